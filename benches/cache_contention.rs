@@ -0,0 +1,78 @@
+//! Concurrent `CacheStore::get`/`set` from several tasks on a multi-threaded
+//! runtime, mostly reads over a small set of hot keys.
+//!
+//! Baseline (1 vCPU Linux VM, rustc 1.95, `cargo bench --bench cache_contention`):
+//!
+//! | tasks | time per 1000 operations per task |
+//! |-------|-----------------------------------|
+//! | 1     | 394 µs                            |
+//! | 4     | 1.2 ms                            |
+//! | 16    | 4.5 ms                            |
+//!
+//! With one vCPU this measures locking overhead rather than parallel
+//! speedup; compare runs on the same machine only.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use phantom_frame::cache::{CacheHandle, CacheStore, CachedResponse};
+
+const KEYS: usize = 64;
+const OPERATIONS: usize = 1000;
+
+fn entry() -> CachedResponse {
+    CachedResponse {
+        body: vec![b'x'; 4096].into(),
+        headers: vec![("content-type".to_string(), "text/html".to_string())],
+        status: 200,
+        content_encoding: None,
+        origin: None,
+        content_digest: None,
+        stored_at: None,
+    }
+}
+
+fn cache_contention(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let store = CacheStore::new(CacheHandle::new(), 0);
+    runtime.block_on(async {
+        for key in 0..KEYS {
+            store.set(format!("GET:/page/{}", key), entry()).await;
+        }
+    });
+
+    let mut group = c.benchmark_group("cache_contention");
+    for tasks in [1, 4, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(tasks), &tasks, |b, &tasks| {
+            b.to_async(&runtime).iter(|| {
+                let store = store.clone();
+                async move {
+                    let workers: Vec<_> = (0..tasks)
+                        .map(|task| {
+                            let store = store.clone();
+                            tokio::spawn(async move {
+                                for op in 0..OPERATIONS {
+                                    let key = format!("GET:/page/{}", (task * 7 + op) % KEYS);
+                                    // One write for every nine reads.
+                                    if op % 10 == 0 {
+                                        store.set(key, entry()).await;
+                                    } else {
+                                        assert!(store.get(&key).await.is_some());
+                                    }
+                                }
+                            })
+                        })
+                        .collect();
+                    for worker in workers {
+                        worker.await.unwrap();
+                    }
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cache_contention);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+//! Deciding whether a request is cacheable against 10 and 100
+//! `include_paths`/`exclude_paths` patterns, with the per-call
+//! `should_cache_path` and the precompiled `PathFilter` the proxy uses.
+//!
+//! Baseline (1 vCPU Linux VM, rustc 1.95, `cargo bench --bench path_matching`):
+//!
+//! | patterns | should_cache_path | PathFilter |
+//! |----------|-------------------|------------|
+//! | 10       | 800 ns            | 443 ns     |
+//! | 100      | 8.2 µs            | 3.5 µs     |
+//!
+//! The request matches no pattern, so every one of them is tried.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use phantom_frame::path_matcher::{should_cache_path, PathFilter};
+
+/// `count` patterns mixing plain prefixes, inner wildcards and methods.
+fn patterns(count: usize) -> (Vec<String>, Vec<String>) {
+    let include = (0..count)
+        .map(|i| match i % 3 {
+            0 => format!("/section-{}/*", i),
+            1 => format!("GET /api/v{}/*/items", i),
+            _ => format!("/*/static-{}/*.css", i),
+        })
+        .collect();
+    let exclude = (0..count / 10)
+        .map(|i| format!("POST /section-{}/*", i))
+        .collect();
+    (include, exclude)
+}
+
+fn path_matching(c: &mut Criterion) {
+    let method = "GET";
+    let path = "/blog/2024/05/an-article-that-matches-nothing";
+
+    let mut group = c.benchmark_group("path_matching");
+    for count in [10, 100] {
+        let (include, exclude) = patterns(count);
+        let filter = PathFilter::new(&include, &exclude);
+
+        group.bench_with_input(
+            BenchmarkId::new("should_cache_path", count),
+            &count,
+            |b, _| b.iter(|| should_cache_path(method, black_box(path), "", &include, &exclude)),
+        );
+        group.bench_with_input(BenchmarkId::new("PathFilter", count), &count, |b, _| {
+            b.iter(|| filter.should_cache(method, black_box(path), ""))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, path_matching);
+criterion_main!(benches);
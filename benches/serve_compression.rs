@@ -0,0 +1,94 @@
+//! Serving a cached 100 KiB HTML page to a gzip-only client, with and
+//! without `compress_on_serve`.
+//!
+//! Baseline (1 vCPU Linux VM, rustc 1.95, `cargo bench --bench
+//! serve_compression`):
+//!
+//! | case                          | time/hit | sent    |
+//! |-------------------------------|----------|---------|
+//! | stored br, served decoded     | 324 µs   | 100 KiB |
+//! | on serve, variant kept        | 4.8 µs   | 9 KiB   |
+//! | on serve, gzip every hit      | 1.6 ms   | 9 KiB   |
+//!
+//! Without `compress_on_serve` every hit decodes the Brotli body. A kept
+//! variant costs its compressed size in memory, here 9 KiB, once per entry
+//! and encoding, bounded by `compress_variants_max_bytes`, and is then
+//! served as cheaply as the stored body. Without it every hit pays for
+//! compression.
+
+use axum::body::Body;
+use axum::http::Request;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use phantom_frame::{create_proxy_handle, CompressStrategy, CreateProxyConfig};
+use std::collections::HashMap;
+use tower::ServiceExt;
+
+/// Prerendered-looking HTML of about `size` bytes.
+fn page(size: usize) -> Vec<u8> {
+    let mut html = String::from("<!doctype html><html><body><ul>");
+    let mut n = 0u64;
+    while html.len() < size {
+        n += 1;
+        html.push_str(&format!(
+            "<li class=\"item\"><a href=\"/items/{}\">Item {}</a><span>{} in stock</span></li>",
+            n,
+            n * 7919 % 1000,
+            n * 104_729 % 97
+        ));
+    }
+    html.push_str("</ul></body></html>");
+    html.into_bytes()
+}
+
+fn serve_compression(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let body = page(100 * 1024);
+    let base = || {
+        // Nothing listens here: every request below must be answered from cache.
+        CreateProxyConfig::new("http://127.0.0.1:9".to_string())
+    };
+    let cases = [
+        ("stored_br_served_decoded", base()),
+        ("on_serve_variant_kept", base().with_compress_on_serve(true)),
+        (
+            "on_serve_gzip_every_hit",
+            base()
+                .with_compress_on_serve(true)
+                .with_compress_variants_max_bytes(0),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("serve_compression");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    for (name, config) in cases {
+        let (app, handle) = runtime.block_on(async {
+            create_proxy_handle(config.compression_strategy(CompressStrategy::Brotli))
+        });
+        let headers = HashMap::from([("content-type".to_string(), "text/html".to_string())]);
+        runtime
+            .block_on(handle.seed("GET:/page", 200, headers, &body))
+            .unwrap();
+
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                let request = Request::builder()
+                    .uri("/page")
+                    .header("accept-encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.clone().oneshot(request).await.unwrap();
+                assert!(response.status().is_success());
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serve_compression);
+criterion_main!(benches);
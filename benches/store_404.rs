@@ -0,0 +1,83 @@
+//! Storing 404 entries in a full 404 store of 10k entries: a new key, which
+//! evicts the oldest, and a key already there, which moves to the back.
+//!
+//! Baseline (1 vCPU Linux VM, rustc 1.95, `cargo bench --bench store_404`):
+//!
+//! | case         | time/insert |
+//! |--------------|-------------|
+//! | insert_evict | 1.0 µs      |
+//! | reinsert     | 1.05 µs     |
+//!
+//! Re-inserting used to search the eviction queue for the key's old
+//! position, about 10 µs at this capacity; both cases should stay near
+//! each other. The store is now split into shards of insertion-ordered
+//! maps, where a removal shifts the keys after it; on a second machine
+//! that measured 1.9 µs and 1.7 µs for the previous layout, it takes
+//! 2.7 µs and 2.0 µs.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use phantom_frame::cache::{CacheHandle, CacheStore, CachedResponse};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const CAPACITY: usize = 10_000;
+
+fn not_found() -> CachedResponse {
+    CachedResponse {
+        body: vec![0; 64].into(),
+        headers: Vec::new(),
+        status: 404,
+        content_encoding: None,
+        origin: None,
+        content_digest: None,
+        stored_at: None,
+    }
+}
+
+fn key(n: usize) -> String {
+    format!("GET:/some/fairly/long/missing/path/{:08}", n)
+}
+
+/// Fibonacci hashing, to spread consecutive steps across the keys.
+fn scatter(step: usize) -> usize {
+    ((step as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize
+}
+
+fn store_404(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let store = CacheStore::new(CacheHandle::new(), CAPACITY);
+    runtime.block_on(async {
+        for n in 0..CAPACITY {
+            store.set_404(key(n), not_found()).await;
+        }
+    });
+
+    let mut group = c.benchmark_group("store_404");
+    let next = AtomicUsize::new(CAPACITY);
+    group.bench_function("insert_evict", |b| {
+        b.to_async(&runtime).iter(|| {
+            let n = next.fetch_add(1, Ordering::Relaxed);
+            store.set_404(key(n), not_found())
+        });
+    });
+    // The store now holds the CAPACITY keys below `next`. Pick among them
+    // by a hash of the step: walking them in a fixed cycle would always hit
+    // the oldest key, which is the cheapest to find.
+    let oldest = next.load(Ordering::Relaxed) - CAPACITY;
+    let step = AtomicUsize::new(0);
+    group.bench_function("reinsert", |b| {
+        b.to_async(&runtime).iter(|| {
+            let n = oldest + scatter(step.fetch_add(1, Ordering::Relaxed)) % CAPACITY;
+            store.set_404(key(n), not_found())
+        });
+    });
+    group.finish();
+
+    let size = runtime.block_on(store.size_404());
+    assert_eq!(size, CAPACITY);
+}
+
+criterion_group!(benches, store_404);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+//! Skip the cache for signed-in visitors.
+//!
+//! Pages are cached as usual, except that requests carrying a `session`
+//! cookie always reach the backend, so nobody is served a page rendered for
+//! someone else. Requests from a prerender service (`X-Prerender: 1`) are
+//! cached even under `/account/*`, which the patterns exclude.
+//!
+//! Run with `cargo run --example cookie_bypass` and a backend on port 8080.
+
+use phantom_frame::{create_proxy_handle, CacheDecision, CreateProxyConfig, RequestInfo};
+
+/// Whether the request carries a `session` cookie.
+fn has_session(req: &RequestInfo) -> bool {
+    req.headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .any(|cookie| cookie.trim_start().starts_with("session="))
+}
+
+#[tokio::main]
+async fn main() {
+    let config = CreateProxyConfig::new("http://localhost:8080".to_string())
+        .with_exclude_paths(vec!["/account/*".to_string()])
+        .with_should_cache_fn(|req| {
+            if has_session(req) {
+                CacheDecision::Bypass
+            } else if req.headers.get("x-prerender").is_some_and(|v| v == "1") {
+                CacheDecision::Cache
+            } else {
+                // Fall through to `exclude_paths`.
+                CacheDecision::Default
+            }
+        });
+    let (app, _handle) = create_proxy_handle(config);
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("Proxy listening on http://0.0.0.0:3000");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
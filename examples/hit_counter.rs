@@ -0,0 +1,74 @@
+//! Count cache hits per top-level path prefix.
+//!
+//! `ProxyEvents` methods run while requests are handled, so the counter only
+//! bumps an atomic; a separate task prints the totals every ten seconds.
+//! `/blog/post-1` and `/blog/post-2` both count towards `/blog`.
+//!
+//! Run with `cargo run --example hit_counter` and a backend on port 8080.
+
+use phantom_frame::events::ProxyEvents;
+use phantom_frame::{create_proxy_handle, CreateProxyConfig};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Default)]
+struct HitCounter {
+    hits: RwLock<HashMap<String, AtomicU64>>,
+}
+
+impl HitCounter {
+    /// `/blog` for a `GET:/blog/post-1?page=2` key.
+    fn prefix(key: &str) -> &str {
+        let path = key.split_once(':').map_or(key, |(_, path)| path);
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        match path[1.min(path.len())..].find('/') {
+            Some(end) => &path[..end + 1],
+            None => path,
+        }
+    }
+}
+
+impl ProxyEvents for HitCounter {
+    fn on_hit(&self, key: &str, _age: Option<Duration>) {
+        let prefix = Self::prefix(key);
+        if let Some(count) = self.hits.read().unwrap().get(prefix) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.hits
+            .write()
+            .unwrap()
+            .entry(prefix.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let counter = Arc::new(HitCounter::default());
+    let config =
+        CreateProxyConfig::new("http://localhost:8080".to_string()).with_events(counter.clone());
+    let (app, _handle) = create_proxy_handle(config);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            for (prefix, count) in counter.hits.read().unwrap().iter() {
+                println!("{}: {} hits", prefix, count.load(Ordering::Relaxed));
+            }
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("Proxy listening on http://0.0.0.0:3000");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
@@ -1,7 +1,7 @@
-use axum::Router;
 use phantom_frame::{
-    cache::CacheHandle, create_proxy, CacheStrategy, CompressStrategy, CreateProxyConfig, ProxyMode,
+    create_proxy_handle, CacheStrategy, CompressStrategy, CreateProxyConfig, ProxyMode,
 };
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[tokio::main]
@@ -30,10 +30,23 @@ async fn main() {
         .with_websocket_enabled(true); // Enable WebSocket support (default: true)
 
     // Create proxy - proxy_url is the backend server to proxy requests to
-    let (proxy_app, handle): (Router, CacheHandle) = create_proxy(proxy_config);
+    let (proxy_app, proxy) = create_proxy_handle(proxy_config);
 
-    // You can clone and use the handle in your code
-    let handle_clone = handle.clone();
+    // Seed a prerendered page before serving. The proxy answers matching
+    // requests from this entry (when caching is enabled).
+    proxy
+        .seed(
+            "GET:/public/landing",
+            200,
+            HashMap::from([("content-type".to_string(), "text/html".to_string())]),
+            b"<h1>Welcome</h1>",
+        )
+        .await
+        .unwrap();
+    println!("Cache entries: {}", proxy.cache().size().await);
+
+    // You can clone and use the invalidation handle in your code
+    let handle_clone = proxy.cache_handle().clone();
 
     // Example: Trigger cache invalidation from another part of your application
     tokio::spawn(async move {
@@ -56,7 +69,8 @@ async fn main() {
     //         paths: vec!["/".to_string(), "/about".to_string(), "/book/1".to_string()],
     //         fallthrough: false, // return 404 on cache miss (default)
     //     });
-    // let (ssg_app, ssg_handle) = create_proxy(ssg_config);
+    // let (ssg_app, ssg_proxy) = create_proxy_handle(ssg_config);
+    // let ssg_handle = ssg_proxy.cache_handle();
     // // At runtime, manage snapshots:
     // ssg_handle.add_snapshot("/book/2").await.unwrap();
     // ssg_handle.refresh_snapshot("/book/1").await.unwrap();
@@ -76,5 +90,10 @@ async fn main() {
     println!("Note: Cache reads and writes are disabled in this example");
     println!("WebSocket support: enabled");
 
-    axum::serve(listener, proxy_app).await.unwrap();
+    axum::serve(
+        listener,
+        proxy_app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -0,0 +1,43 @@
+//! Cache only part of an existing axum app.
+//!
+//! The app serves its own routes and middleware, and everything under `/app`
+//! goes through the caching proxy: `/app/page` fetches
+//! `http://localhost:8080/page` and is cached as `GET:/page`. `Router::nest`
+//! strips the prefix before the proxy sees the request, and redirects from
+//! the backend get it back with `rewrite_location`.
+//!
+//! Run with `cargo run --example nested_app` and a backend on port 8080.
+
+use axum::{routing::get, Router};
+use phantom_frame::{create_proxy_handle, CreateProxyConfig};
+
+#[tokio::main]
+async fn main() {
+    let config =
+        CreateProxyConfig::new("http://localhost:8080".to_string()).with_rewrite_location(true);
+    let (proxy, handle) = create_proxy_handle(config);
+
+    let app = Router::new()
+        .route("/", get(|| async { "Served by the app itself" }))
+        .route(
+            "/purge",
+            get(move || {
+                let handle = handle.clone();
+                async move {
+                    handle.cache_handle().invalidate_all();
+                    "Cache purged"
+                }
+            }),
+        )
+        .nest("/app", proxy)
+        .layer(tower_http::trace::TraceLayer::new_for_http());
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("App listening on http://0.0.0.0:3000, proxy under /app");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
@@ -0,0 +1,387 @@
+//! Hot-swappable upstream endpoints.
+//!
+//! Each proxy reads its upstream URLs and HTTP clients through a
+//! [`BackendSlot`]. Replacing the backends installs fresh clients for new
+//! requests, while requests already in flight keep the [`BackendLease`] they
+//! started with and finish on the old connection pool. The old pool is
+//! dropped once the last of those requests completes.
+//!
+//! With several backends, each request picks one by [`LoadBalancing`]. A
+//! backend that leaves a request without a response (connection refused,
+//! reset, or timed out) is skipped for a cooldown, unless every backend is
+//! cooling down. Error statuses do not count as failures.
+
+use crate::health::BackendHealth;
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long a failed backend is skipped when none is configured.
+pub const DEFAULT_BACKEND_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How requests are spread over several backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// Each backend in turn (default).
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest requests in flight, in turn on a tie.
+    LeastConnections,
+}
+
+/// An upstream URL together with the client used to reach it.
+pub struct Backend {
+    url: String,
+    client: reqwest::Client,
+    generation: u64,
+    in_flight: AtomicUsize,
+    /// Set after a failed request; the backend is skipped until then.
+    cooling_until: Mutex<Option<Instant>>,
+    /// The last active health check, when they are enabled.
+    health: Mutex<Option<BackendHealth>>,
+}
+
+impl Backend {
+    /// The upstream base URL requests are forwarded to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Number of times the backend has been replaced since startup.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Requests currently forwarded to it, upgrade tunnels included.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Whether it is skipped after a failed request.
+    pub fn cooling_down(&self) -> bool {
+        self.cooling_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// The last active health check, or `None` before the first one.
+    pub fn health(&self) -> Option<BackendHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Whether the last active health check passed; true before the first.
+    pub fn is_up(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|health| health.up)
+    }
+
+    pub(crate) fn set_health(&self, health: BackendHealth) {
+        *self.health.lock().unwrap() = Some(health);
+    }
+
+    pub(crate) fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    fn build(url: String, pool: PoolOptions, generation: u64) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: ascii_url(url),
+            client: crate::proxy::build_upstream_client(pool)?,
+            generation,
+            in_flight: AtomicUsize::new(0),
+            cooling_until: Mutex::new(None),
+            health: Mutex::new(None),
+        })
+    }
+}
+
+/// A backend chosen for one request or tunnel, counted in
+/// [`Backend::in_flight`] until dropped.
+pub struct BackendLease(Arc<Backend>);
+
+impl BackendLease {
+    fn new(backend: Arc<Backend>) -> Self {
+        backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(backend)
+    }
+}
+
+impl Deref for BackendLease {
+    type Target = Backend;
+
+    fn deref(&self) -> &Backend {
+        &self.0
+    }
+}
+
+impl Drop for BackendLease {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Rewrite an internationalized host to its punycode form so DNS, SNI, and the
+/// `Host` header all see the same ASCII name. Unparseable URLs are returned
+/// unchanged; a trailing `/` is only kept if the input had one.
+fn ascii_url(url: String) -> String {
+    let Ok(parsed) = reqwest::Url::parse(&url) else {
+        return url;
+    };
+    let ascii = parsed.as_str();
+    if url.ends_with('/') {
+        ascii.to_string()
+    } else {
+        ascii.trim_end_matches('/').to_string()
+    }
+}
+
+/// Connection pool, timeout, and redirect settings, applied to the client of
+/// every backend a slot installs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PoolOptions {
+    pub(crate) max_idle_per_host: usize,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+    pub(crate) follow_redirects: bool,
+}
+
+impl PoolOptions {
+    pub(crate) fn from_config(config: &crate::CreateProxyConfig) -> Self {
+        Self {
+            max_idle_per_host: config.pool_max_idle_per_host,
+            connect_timeout: config.connect_timeout,
+            request_timeout: config.request_timeout,
+            follow_redirects: config.follow_redirects,
+        }
+    }
+}
+
+/// Shared, swappable reference to the active [`Backend`]s.
+#[derive(Clone)]
+pub struct BackendSlot {
+    current: Arc<RwLock<Arc<[Arc<Backend>]>>>,
+    pool: PoolOptions,
+    balancing: LoadBalancing,
+    cooldown: Duration,
+    /// Where the next pick starts, for round robin and tie-breaking.
+    next: Arc<AtomicUsize>,
+}
+
+impl BackendSlot {
+    /// The backends `config` names, with its pool and balancing settings.
+    pub fn from_config(config: &crate::CreateProxyConfig) -> anyhow::Result<Self> {
+        Ok(
+            Self::new(config.backend_urls(), PoolOptions::from_config(config))?
+                .with_balancing(config.load_balancing, config.backend_cooldown),
+        )
+    }
+
+    pub(crate) fn new(urls: Vec<String>, pool: PoolOptions) -> anyhow::Result<Self> {
+        if urls.is_empty() {
+            anyhow::bail!("at least one backend URL is required");
+        }
+        let backends = urls
+            .into_iter()
+            .map(|url| Backend::build(url, pool, 0).map(Arc::new))
+            .collect::<anyhow::Result<Arc<[_]>>>()?;
+        Ok(Self {
+            current: Arc::new(RwLock::new(backends)),
+            pool,
+            balancing: LoadBalancing::default(),
+            cooldown: DEFAULT_BACKEND_COOLDOWN,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub(crate) fn with_balancing(mut self, balancing: LoadBalancing, cooldown: Duration) -> Self {
+        self.balancing = balancing;
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// The first backend, which is the only one unless several are
+    /// configured. Requests are spread with [`BackendSlot::pick`].
+    pub fn current(&self) -> Arc<Backend> {
+        self.current.read().unwrap()[0].clone()
+    }
+
+    /// Every backend, in configured order.
+    pub fn all(&self) -> Vec<Arc<Backend>> {
+        self.current.read().unwrap().to_vec()
+    }
+
+    /// Choose the backend for a new request, skipping those cooling down
+    /// unless all of them are. Backends that are down are only returned
+    /// when every backend is; callers check [`Backend::is_up`].
+    pub fn pick(&self) -> BackendLease {
+        let backends = self.current.read().unwrap().clone();
+        if backends.len() == 1 {
+            return BackendLease::new(backends[0].clone());
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let choose = |usable: fn(&Backend) -> bool| {
+            let mut in_turn = (0..backends.len())
+                .map(|i| &backends[(start + i) % backends.len()])
+                .filter(|backend| usable(backend));
+            match self.balancing {
+                LoadBalancing::RoundRobin => in_turn.next(),
+                LoadBalancing::LeastConnections => {
+                    in_turn.min_by_key(|backend| backend.in_flight())
+                }
+            }
+        };
+        let chosen = choose(|backend| backend.is_up() && !backend.cooling_down())
+            .or_else(|| choose(Backend::is_up))
+            .unwrap_or(&backends[start % backends.len()]);
+        BackendLease::new(chosen.clone())
+    }
+
+    /// Skip `backend` for the cooldown after a request to it failed. A
+    /// single backend is never skipped, as there is nothing to fall back to.
+    pub(crate) fn report_failure(&self, backend: &Backend) {
+        if self.current.read().unwrap().len() == 1 {
+            return;
+        }
+        *backend.cooling_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+        tracing::warn!(
+            "Backend '{}' failed, skipping it for {}s",
+            backend.url(),
+            self.cooldown.as_secs()
+        );
+    }
+
+    /// Point new requests at `url` with a freshly built client.
+    ///
+    /// Returns the new backend. Fails when `url` is not an absolute http(s)
+    /// URL or the client cannot be built; the active backend is unchanged.
+    pub(crate) fn replace(&self, url: String) -> anyhow::Result<Arc<Backend>> {
+        Ok(self.replace_all(vec![url])?.remove(0))
+    }
+
+    /// Like [`BackendSlot::replace`], for a whole list of backends.
+    pub(crate) fn replace_all(&self, urls: Vec<String>) -> anyhow::Result<Vec<Arc<Backend>>> {
+        if urls.is_empty() {
+            anyhow::bail!("at least one backend URL is required");
+        }
+        for url in &urls {
+            let parsed = reqwest::Url::parse(url)?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                anyhow::bail!("unsupported scheme in proxy_url '{}'", url);
+            }
+        }
+        let mut current = self.current.write().unwrap();
+        let generation = current[0].generation + 1;
+        let next = urls
+            .into_iter()
+            .map(|url| Backend::build(url, self.pool, generation).map(Arc::new))
+            .collect::<anyhow::Result<Arc<[_]>>>()?;
+        *current = next.clone();
+        Ok(next.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idn_backend_is_punycoded_for_requests() {
+        let config = crate::CreateProxyConfig::new("http://café.example:8080".to_string());
+        let slot = BackendSlot::new(
+            vec![config.proxy_url.clone()],
+            PoolOptions::from_config(&config),
+        )
+        .unwrap();
+        let backend = slot.current();
+        assert_eq!(backend.url(), "http://xn--caf-dma.example:8080");
+
+        let request = backend
+            .client()
+            .get(format!("{}{}", backend.url(), "/caf%C3%A9"))
+            .build()
+            .unwrap();
+        assert_eq!(request.url().host_str(), Some("xn--caf-dma.example"));
+        assert_eq!(request.url().path(), "/caf%C3%A9");
+
+        assert_eq!(
+            ascii_url("http://localhost:8080".to_string()),
+            "http://localhost:8080"
+        );
+        assert_eq!(
+            ascii_url("http://bücher.example/base/".to_string()),
+            "http://xn--bcher-kva.example/base/"
+        );
+    }
+
+    fn slot(urls: &[&str], balancing: LoadBalancing) -> BackendSlot {
+        let config = crate::CreateProxyConfig::new(urls[0].to_string());
+        BackendSlot::new(
+            urls.iter().map(|url| url.to_string()).collect(),
+            PoolOptions::from_config(&config),
+        )
+        .unwrap()
+        .with_balancing(balancing, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_round_robin_skips_backends_cooling_down() {
+        let slot = slot(
+            &["http://a:1", "http://b:1", "http://c:1"],
+            LoadBalancing::RoundRobin,
+        );
+        let picks: Vec<String> = (0..4).map(|_| slot.pick().url().to_string()).collect();
+        assert_eq!(
+            picks,
+            ["http://a:1", "http://b:1", "http://c:1", "http://a:1"]
+        );
+
+        slot.report_failure(&slot.all()[1]);
+        let picks: Vec<String> = (0..3).map(|_| slot.pick().url().to_string()).collect();
+        assert_eq!(picks, ["http://c:1", "http://c:1", "http://a:1"]);
+
+        // With every backend cooling down, requests still go somewhere.
+        for backend in slot.all() {
+            slot.report_failure(&backend);
+        }
+        assert!(slot.all().iter().all(|backend| backend.cooling_down()));
+        let _ = slot.pick();
+    }
+
+    #[test]
+    fn test_least_connections_counts_leases() {
+        let slot = slot(
+            &["http://a:1", "http://b:1"],
+            LoadBalancing::LeastConnections,
+        );
+        let first = slot.pick();
+        let second = slot.pick();
+        assert_ne!(first.url(), second.url());
+        assert_eq!((first.in_flight(), second.in_flight()), (1, 1));
+
+        let a_again = slot.pick();
+        assert_eq!(a_again.url(), "http://a:1");
+        drop(first);
+        drop(a_again);
+        assert_eq!(slot.pick().url(), "http://a:1");
+        assert_eq!(slot.all()[0].in_flight(), 0);
+        assert_eq!(slot.all()[1].in_flight(), 1);
+    }
+
+    #[test]
+    fn test_single_backend_never_cools_down() {
+        let slot = slot(&["http://a:1"], LoadBalancing::RoundRobin);
+        slot.report_failure(&slot.current());
+        assert!(!slot.current().cooling_down());
+        assert!(slot.replace_all(vec![]).is_err());
+        let replaced = slot.replace_all(vec!["http://b:1".into(), "http://c:1".into()]);
+        assert_eq!(replaced.unwrap()[1].generation(), 1);
+        assert_eq!(slot.current().url(), "http://b:1");
+    }
+}
@@ -0,0 +1,153 @@
+//! Caching directives a backend sends per response in `X-Phantom-*` headers.
+//!
+//! The backend often knows best how long a page stays valid. It can say so
+//! on each response:
+//!
+//! - `X-Phantom-TTL: 300` keeps the entry for 300 seconds. It takes
+//!   precedence over the TTL of a matching cache rule or body rule and, with
+//!   `respect_cache_control`, over the response's `Cache-Control` altogether.
+//!   `0` keeps the response out of the cache. A value that is not a whole
+//!   number of seconds is ignored, and one above 2^31 is capped there, as
+//!   for `Cache-Control`.
+//! - `X-Phantom-No-Cache: 1` (or `true`) keeps the response out of the cache,
+//!   whatever `X-Phantom-TTL` says.
+//! - `X-Phantom-Tags: a,b,c` names tags for the entry. They are read so
+//!   backends can send them now, but nothing invalidates by tag yet.
+//!
+//! The TTL applies to main-cache entries; 404 and error entries keep their
+//! own lifetimes. All three headers are removed from a response as soon as
+//! it arrives, so they are neither stored nor sent to clients, whether the
+//! response is cached, passed through, or streamed. Pre-generated snapshots
+//! follow the same rules.
+
+use crate::cache_control::MAX_DELTA_SECONDS;
+use axum::http::HeaderMap;
+use std::time::Duration;
+
+/// Sets the lifetime of the entry, in seconds.
+pub const TTL_HEADER: &str = "x-phantom-ttl";
+
+/// `1` or `true` keeps the response out of the cache.
+pub const NO_CACHE_HEADER: &str = "x-phantom-no-cache";
+
+/// Comma-separated tags for the entry.
+pub const TAGS_HEADER: &str = "x-phantom-tags";
+
+/// The directives of one backend response.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendDirectives {
+    /// From `X-Phantom-TTL`.
+    pub ttl: Option<Duration>,
+    /// From `X-Phantom-No-Cache`.
+    pub no_cache: bool,
+    /// From `X-Phantom-Tags`, trimmed, without empty tags.
+    pub tags: Vec<String>,
+}
+
+impl BackendDirectives {
+    /// Read the directives from `headers` and remove their headers.
+    pub fn take(headers: &mut HeaderMap) -> Self {
+        let mut values = |name: &str| -> Vec<String> {
+            let values = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(str::to_string)
+                .collect();
+            headers.remove(name);
+            values
+        };
+        let ttl = values(TTL_HEADER)
+            .first()
+            .map(|value| value.trim())
+            .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .map(|digits| {
+                digits
+                    .parse()
+                    .map_or(MAX_DELTA_SECONDS, |s: u64| s.min(MAX_DELTA_SECONDS))
+            })
+            .map(Duration::from_secs);
+        let no_cache = values(NO_CACHE_HEADER).iter().any(|value| {
+            let value = value.trim();
+            value == "1" || value.eq_ignore_ascii_case("true")
+        });
+        let tags = values(TAGS_HEADER)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self {
+            ttl,
+            no_cache,
+            tags,
+        }
+    }
+
+    /// Whether the backend allows storing the response.
+    pub fn allows_store(&self) -> bool {
+        !self.no_cache && self.ttl != Some(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_take_reads_and_strips() {
+        let mut map = headers(&[
+            ("content-type", "text/html"),
+            ("x-phantom-ttl", " 300 "),
+            ("x-phantom-no-cache", "0"),
+            ("x-phantom-tags", "products, , home"),
+            ("x-phantom-tags", "sale"),
+        ]);
+        let directives = BackendDirectives::take(&mut map);
+        assert_eq!(
+            directives,
+            BackendDirectives {
+                ttl: Some(Duration::from_secs(300)),
+                no_cache: false,
+                tags: vec!["products".into(), "home".into(), "sale".into()],
+            }
+        );
+        assert!(directives.allows_store());
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key("content-type"));
+    }
+
+    #[test]
+    fn test_store_decisions() {
+        fn take(pairs: &[(&'static str, &'static str)]) -> BackendDirectives {
+            BackendDirectives::take(&mut headers(pairs))
+        }
+        assert!(!take(&[("x-phantom-no-cache", "1")]).allows_store());
+        assert!(!take(&[("x-phantom-no-cache", "TRUE")]).allows_store());
+        assert!(!take(&[("x-phantom-ttl", "300"), ("x-phantom-no-cache", "1")]).allows_store());
+        assert!(!take(&[("x-phantom-ttl", "0")]).allows_store());
+        // A malformed TTL is ignored.
+        for value in ["-5", "1.5", "soon", ""] {
+            let directives = take(&[("x-phantom-ttl", value)]);
+            assert_eq!(directives.ttl, None, "{value:?}");
+            assert!(directives.allows_store(), "{value:?}");
+        }
+        // Huge values are capped instead of overflowing an `Instant`.
+        for value in ["18446744073709551615", "99999999999999999999999"] {
+            let ttl = take(&[("x-phantom-ttl", value)]).ttl.unwrap();
+            assert_eq!(ttl, Duration::from_secs(MAX_DELTA_SECONDS), "{value:?}");
+            assert!(tokio::time::Instant::now().checked_add(ttl).is_some());
+        }
+        assert_eq!(take(&[]), BackendDirectives::default());
+    }
+}
@@ -0,0 +1,100 @@
+//! A proxy's long-running tasks: the invalidation listener, the PreGenerate
+//! snapshot worker, and open upgrade tunnels.
+//!
+//! They all stop when the proxy's shutdown token is cancelled, either through
+//! [`ProxyHandle::shutdown`] or when the last router and handle sharing the
+//! proxy are dropped, so a proxy that is thrown away leaves no task behind.
+//!
+//! [`ProxyHandle::shutdown`]: crate::ProxyHandle::shutdown
+
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+pub(crate) struct BackgroundTasks {
+    token: CancellationToken,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl BackgroundTasks {
+    pub(crate) fn new() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Cancelled when the proxy shuts down.
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Run `task` until it finishes or the proxy shuts down.
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let token = self.token.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task => {}
+            }
+        });
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(handle);
+    }
+
+    /// Cancel the token and wait for every spawned task to exit.
+    pub(crate) async fn shutdown(&self) {
+        self.token.cancel();
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            if let Err(e) = task.await {
+                tracing::error!("Background task failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    /// A task that never finishes on its own, and the receiver that closes
+    /// once it has been dropped.
+    fn endless_task() -> (impl Future<Output = ()>, oneshot::Receiver<()>) {
+        let (alive, stopped) = oneshot::channel::<()>();
+        let task = async move {
+            let _alive = alive;
+            std::future::pending::<()>().await
+        };
+        (task, stopped)
+    }
+
+    #[tokio::test]
+    async fn test_tasks_exit_on_shutdown_and_drop() {
+        let tasks = BackgroundTasks::new();
+        let (task, mut stopped) = endless_task();
+        tasks.spawn(task);
+        tasks.shutdown().await;
+        assert_eq!(
+            stopped.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        );
+
+        let tasks = BackgroundTasks::new();
+        let (task, stopped) = endless_task();
+        tasks.spawn(task);
+        drop(tasks);
+        let closed = tokio::time::timeout(Duration::from_secs(1), stopped).await;
+        assert!(matches!(closed, Ok(Err(_))), "the task outlived its owner");
+    }
+}
@@ -0,0 +1,237 @@
+//! Opt-in caching of requests whose answer depends on their body.
+//!
+//! A GraphQL API serves every query as `POST /graphql`, so method and path
+//! alone cannot tell two queries apart. A [`BodyCacheRule`] names the
+//! requests whose body should become part of the cache key. Matching requests
+//! have their body read up front, reduced to a key component, and their
+//! response is cached for the rule's TTL only. Requests a rule refuses — too
+//! large, unparsable, or matching `skip_if`, such as mutations — are
+//! forwarded without touching the cache.
+
+use crate::cache::content_digest;
+use crate::path_matcher::{PathPattern, WildcardSyntax};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How the key component is derived from the body.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyKey {
+    /// SHA-256 of the raw body.
+    #[default]
+    Hash,
+    /// A GraphQL request: `operationName`, plus a hash of `query` and
+    /// `variables`. Formatting of the JSON envelope does not matter.
+    Graphql,
+}
+
+/// A substring test on the body, or on the JSON value at `pointer`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BodyMatch {
+    /// JSON pointer (RFC 6901), e.g. `/query`. Absent means the raw body.
+    #[serde(default)]
+    pub pointer: Option<String>,
+    pub contains: String,
+}
+
+impl BodyMatch {
+    pub fn new(pointer: Option<&str>, contains: impl Into<String>) -> Self {
+        Self {
+            pointer: pointer.map(str::to_string),
+            contains: contains.into(),
+        }
+    }
+
+    fn matches(&self, body: &[u8], json: Option<&serde_json::Value>) -> bool {
+        let Some(pointer) = &self.pointer else {
+            return contains(body, self.contains.as_bytes());
+        };
+        match json.and_then(|json| json.pointer(pointer)) {
+            Some(serde_json::Value::String(value)) => value.contains(&self.contains),
+            Some(value) => value.to_string().contains(&self.contains),
+            None => false,
+        }
+    }
+}
+
+/// Requests to cache by body, as written in configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BodyCacheRule {
+    /// Request pattern, same syntax as `include_paths`, e.g. `"POST /graphql"`.
+    pub pattern: String,
+    #[serde(default)]
+    pub key: BodyKey,
+    /// How long a response stays cached (default: 10).
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Larger bodies are forwarded uncached (default: 64 KiB).
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Bodies matching any of these are forwarded uncached.
+    #[serde(default)]
+    pub skip_if: Vec<BodyMatch>,
+}
+
+fn default_ttl_secs() -> u64 {
+    10
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+impl BodyCacheRule {
+    pub fn new(pattern: impl Into<String>, key: BodyKey) -> Self {
+        Self {
+            pattern: pattern.into(),
+            key,
+            ttl_secs: default_ttl_secs(),
+            max_body_bytes: default_max_body_bytes(),
+            skip_if: vec![],
+        }
+    }
+
+    /// A rule for GraphQL at `pattern` that leaves mutations uncached.
+    pub fn graphql(pattern: impl Into<String>) -> Self {
+        Self::new(pattern, BodyKey::Graphql)
+            .with_skip_if(BodyMatch::new(Some("/query"), "mutation"))
+    }
+
+    pub fn with_ttl_secs(mut self, ttl_secs: u64) -> Self {
+        self.ttl_secs = ttl_secs;
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    pub fn with_skip_if(mut self, skip_if: BodyMatch) -> Self {
+        self.skip_if.push(skip_if);
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    /// The cache key component for `body`, or why the request must bypass
+    /// the cache.
+    pub fn key_component(&self, body: &[u8]) -> Result<String, &'static str> {
+        if body.len() > self.max_body_bytes {
+            return Err("body larger than max_body_bytes");
+        }
+        let needs_json =
+            self.key == BodyKey::Graphql || self.skip_if.iter().any(|skip| skip.pointer.is_some());
+        let json = needs_json
+            .then(|| serde_json::from_slice::<serde_json::Value>(body))
+            .transpose()
+            .map_err(|_| "body is not JSON")?;
+        if self
+            .skip_if
+            .iter()
+            .any(|skip| skip.matches(body, json.as_ref()))
+        {
+            return Err("body matches skip_if");
+        }
+
+        match (self.key, json) {
+            (BodyKey::Graphql, Some(json)) => {
+                let query = json
+                    .get("query")
+                    .and_then(|query| query.as_str())
+                    .ok_or("GraphQL body has no query")?;
+                let operation = json
+                    .get("operationName")
+                    .and_then(|name| name.as_str())
+                    .unwrap_or("");
+                // serde_json sorts object keys, so variable order is irrelevant.
+                let variables = json.get("variables").map(|v| v.to_string());
+                let mut input = query.as_bytes().to_vec();
+                input.push(0);
+                input.extend_from_slice(variables.as_deref().unwrap_or("null").as_bytes());
+                Ok(format!("gql:{}:{}", operation, content_digest(&input)))
+            }
+            _ => Ok(format!("sha256:{}", content_digest(body))),
+        }
+    }
+}
+
+/// [`BodyCacheRule`]s with their patterns parsed once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BodyCacheRules(Vec<(PathPattern, BodyCacheRule)>);
+
+impl BodyCacheRules {
+    pub(crate) fn new(rules: &[BodyCacheRule], syntax: WildcardSyntax) -> Self {
+        Self(
+            rules
+                .iter()
+                .map(|rule| {
+                    (
+                        PathPattern::with_syntax(&rule.pattern, syntax),
+                        rule.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// The first rule matching the request.
+    pub(crate) fn find(&self, method: &str, path: &str, query: &str) -> Option<&BodyCacheRule> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.matches_request(Some(method), path, query))
+            .map(|(_, rule)| rule)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphql_key_ignores_envelope_formatting() {
+        let rule = BodyCacheRule::graphql("POST /graphql");
+        let a = rule
+            .key_component(br#"{"query":"{ user(id: $id) { name } }","operationName":"User","variables":{"id":1,"x":2}}"#)
+            .unwrap();
+        let b = rule
+            .key_component(br#"{ "variables": {"x": 2, "id": 1}, "operationName": "User", "query": "{ user(id: $id) { name } }" }"#)
+            .unwrap();
+        let c = rule
+            .key_component(br#"{"query":"{ user(id: $id) { name } }","operationName":"User","variables":{"id":2,"x":2}}"#)
+            .unwrap();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.starts_with("gql:User:"), "{}", a);
+    }
+
+    #[test]
+    fn test_rule_refusals() {
+        let rule = BodyCacheRule::graphql("POST /graphql").with_max_body_bytes(64);
+
+        assert_eq!(
+            rule.key_component(br#"{"query":"mutation { logout }"}"#),
+            Err("body matches skip_if")
+        );
+        assert_eq!(rule.key_component(b"query=1"), Err("body is not JSON"));
+        assert_eq!(
+            rule.key_component(&[b' '; 65]),
+            Err("body larger than max_body_bytes")
+        );
+
+        let raw = BodyCacheRule::new("POST /search", BodyKey::Hash)
+            .with_skip_if(BodyMatch::new(None, "nocache"));
+        assert!(raw.key_component(b"q=rust").unwrap().starts_with("sha256:"));
+        assert!(raw.key_component(b"q=rust&nocache").is_err());
+    }
+}
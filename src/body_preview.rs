@@ -0,0 +1,362 @@
+//! Bounded views of cached bodies for control endpoints.
+//!
+//! A cached body can be megabytes, so control endpoints never return it
+//! whole. The caller picks a view with query parameters:
+//!
+//! - `?body=preview&max_bytes=4096` — the first `max_bytes` bytes (the default
+//!   view, 4096 bytes).
+//! - `?body=range&start=0&end=1024` — bytes `start..end`.
+//! - `?body=hash` — a SHA-256 digest, for comparing entries without moving
+//!   the body.
+//! - `?body=none` — no body.
+//! - `?body=full` — the whole body, sent as the response itself rather than
+//!   inside JSON (see [`full_body`]).
+//!
+//! Views are taken from the decoded body unless `raw=true` asks for the bytes
+//! as stored (for example brotli-compressed). No JSON view returns more than
+//! [`MAX_VIEW_BYTES`].
+
+use crate::cache::{content_digest, CachedResponse};
+use crate::compression::{decompress_body_async, ContentEncoding};
+use base64::Engine;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Bytes returned by `body=preview` when `max_bytes` is not given.
+pub const DEFAULT_PREVIEW_BYTES: usize = 4096;
+
+/// Upper bound on the bytes any view returns.
+pub const MAX_VIEW_BYTES: usize = 1024 * 1024;
+
+/// Body-view query parameters, extracted alongside an endpoint's own query.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BodyViewQuery {
+    /// `preview` (default), `range`, `hash`, `none`, or `full`.
+    pub body: Option<String>,
+    pub max_bytes: Option<usize>,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    /// Use the stored bytes instead of decoding them first.
+    #[serde(default)]
+    pub raw: bool,
+}
+
+/// Which part of a body to return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodySelection {
+    None,
+    Preview {
+        max_bytes: usize,
+    },
+    /// `end` is exclusive and `None` means the end of the body.
+    Range {
+        start: usize,
+        end: Option<usize>,
+    },
+    Hash,
+    /// The whole body, for endpoints that can send it as is.
+    Full,
+}
+
+impl BodyViewQuery {
+    pub fn selection(&self) -> Result<BodySelection, String> {
+        match self.body.as_deref().unwrap_or("preview") {
+            "none" => Ok(BodySelection::None),
+            "preview" => Ok(BodySelection::Preview {
+                max_bytes: self.max_bytes.unwrap_or(DEFAULT_PREVIEW_BYTES),
+            }),
+            "range" => {
+                let start = self.start.unwrap_or(0);
+                if self.end.is_some_and(|end| end < start) {
+                    return Err("'end' must not be less than 'start'".to_string());
+                }
+                Ok(BodySelection::Range {
+                    start,
+                    end: self.end,
+                })
+            }
+            "hash" => Ok(BodySelection::Hash),
+            "full" => Ok(BodySelection::Full),
+            other => Err(format!(
+                "unknown body mode '{}'; expected preview, range, hash, none, or full",
+                other
+            )),
+        }
+    }
+}
+
+/// A bounded view of a body, serialized into endpoint responses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum BodyView {
+    /// `preview` and `range` views.
+    Bytes {
+        /// Whether a compressed body was decoded before slicing.
+        decoded: bool,
+        total_bytes: usize,
+        offset: usize,
+        length: usize,
+        /// More of the body exists past `offset + length`.
+        truncated: bool,
+        /// `utf8` when `content` is the text itself, `base64` otherwise.
+        encoding: &'static str,
+        content: String,
+    },
+    Hash {
+        decoded: bool,
+        total_bytes: usize,
+        algorithm: &'static str,
+        digest: String,
+    },
+}
+
+/// Render `selection` from a cached entry, decoding its body first unless
+/// `raw` is set. Returns `None` for [`BodySelection::None`].
+pub async fn view_cached(
+    cached: &CachedResponse,
+    selection: BodySelection,
+    raw: bool,
+) -> anyhow::Result<Option<BodyView>> {
+    if selection == BodySelection::None {
+        return Ok(None);
+    }
+    let (body, decoded) = match cached.content_encoding {
+        Some(encoding) if !raw => (
+            Cow::Owned(decompress_body_async(cached.body.clone(), encoding).await?),
+            true,
+        ),
+        _ => (Cow::Borrowed(&cached.body[..]), false),
+    };
+    Ok(view(&body, selection, decoded))
+}
+
+/// The whole body of a cached entry, decoded unless `raw` is set, with the
+/// `Content-Encoding` it is still in.
+pub async fn full_body(
+    cached: &CachedResponse,
+    raw: bool,
+) -> anyhow::Result<(Bytes, Option<ContentEncoding>)> {
+    match cached.content_encoding {
+        Some(encoding) if !raw => Ok((
+            decompress_body_async(cached.body.clone(), encoding)
+                .await?
+                .into(),
+            None,
+        )),
+        encoding => Ok((cached.body.clone(), encoding)),
+    }
+}
+
+/// Render `selection` from `body`. `decoded` is reported back as-is.
+pub fn view(body: &[u8], selection: BodySelection, decoded: bool) -> Option<BodyView> {
+    let (start, end) = match selection {
+        BodySelection::None => return None,
+        BodySelection::Hash => {
+            return Some(BodyView::Hash {
+                decoded,
+                total_bytes: body.len(),
+                algorithm: "sha256",
+                digest: content_digest(body),
+            });
+        }
+        BodySelection::Preview { max_bytes } => (0, max_bytes),
+        BodySelection::Range { start, end } => (start, end.unwrap_or(usize::MAX)),
+        // Bounded like any view when it has to go into JSON.
+        BodySelection::Full => (0, usize::MAX),
+    };
+
+    let start = start.min(body.len());
+    let end = end
+        .min(body.len())
+        .min(start.saturating_add(MAX_VIEW_BYTES));
+    let slice = &body[start..end];
+    let (encoding, content, length) = match std::str::from_utf8(slice) {
+        Ok(text) => ("utf8", text.to_string(), slice.len()),
+        // A cut through a multi-byte character at the end of the window:
+        // stop before it rather than falling back to base64.
+        Err(error) if error.error_len().is_none() && error.valid_up_to() > 0 => {
+            let valid = &slice[..error.valid_up_to()];
+            (
+                "utf8",
+                String::from_utf8_lossy(valid).into_owned(),
+                valid.len(),
+            )
+        }
+        Err(_) => (
+            "base64",
+            base64::engine::general_purpose::STANDARD.encode(slice),
+            slice.len(),
+        ),
+    };
+    Some(BodyView::Bytes {
+        decoded,
+        total_bytes: body.len(),
+        offset: start,
+        length,
+        truncated: start + length < body.len(),
+        encoding,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{compress_body, ContentEncoding};
+
+    fn bytes_view(view: Option<BodyView>) -> (usize, usize, bool, &'static str, String) {
+        match view.unwrap() {
+            BodyView::Bytes {
+                offset,
+                length,
+                truncated,
+                encoding,
+                content,
+                ..
+            } => (offset, length, truncated, encoding, content),
+            other => panic!("expected a bytes view, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_preview_truncates_large_bodies() {
+        let body = "a".repeat(10_000);
+        let (offset, length, truncated, encoding, content) = bytes_view(view(
+            body.as_bytes(),
+            BodySelection::Preview { max_bytes: 4096 },
+            false,
+        ));
+        assert_eq!(
+            (offset, length, truncated, encoding),
+            (0, 4096, true, "utf8")
+        );
+        assert_eq!(content.len(), 4096);
+
+        let (_, length, truncated, _, _) = bytes_view(view(
+            b"short",
+            BodySelection::Preview { max_bytes: 4096 },
+            false,
+        ));
+        assert_eq!((length, truncated), (5, false));
+
+        // Even a huge max_bytes is capped.
+        let big = vec![b'x'; MAX_VIEW_BYTES + 10];
+        let (_, length, truncated, _, _) = bytes_view(view(
+            &big,
+            BodySelection::Preview {
+                max_bytes: usize::MAX,
+            },
+            false,
+        ));
+        assert_eq!((length, truncated), (MAX_VIEW_BYTES, true));
+    }
+
+    #[test]
+    fn test_range_and_encoding_choice() {
+        let (offset, length, truncated, encoding, content) = bytes_view(view(
+            b"0123456789",
+            BodySelection::Range {
+                start: 2,
+                end: Some(5),
+            },
+            false,
+        ));
+        assert_eq!((offset, length, truncated), (2, 3, true));
+        assert_eq!((encoding, content.as_str()), ("utf8", "234"));
+
+        // A window ending inside `é` stops before it.
+        let (_, length, _, encoding, content) = bytes_view(view(
+            "café".as_bytes(),
+            BodySelection::Range {
+                start: 0,
+                end: Some(4),
+            },
+            false,
+        ));
+        assert_eq!((length, encoding, content.as_str()), (3, "utf8", "caf"));
+
+        let (_, _, _, encoding, content) = bytes_view(view(
+            &[0xff, 0x00, 0x10],
+            BodySelection::Range {
+                start: 0,
+                end: None,
+            },
+            false,
+        ));
+        assert_eq!((encoding, content.as_str()), ("base64", "/wAQ"));
+
+        let query = BodyViewQuery {
+            body: Some("range".to_string()),
+            start: Some(5),
+            end: Some(1),
+            ..Default::default()
+        };
+        assert!(query.selection().is_err());
+    }
+
+    #[test]
+    fn test_hash_digest() {
+        let view = view(b"abc", BodySelection::Hash, false).unwrap();
+        assert_eq!(
+            view,
+            BodyView::Hash {
+                decoded: false,
+                total_bytes: 3,
+                algorithm: "sha256",
+                digest: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_bodies_are_decoded_unless_raw() {
+        let text = "<html>".to_string() + &"hello ".repeat(2000) + "</html>";
+        let cached = CachedResponse {
+            body: compress_body(text.as_bytes(), ContentEncoding::Brotli)
+                .unwrap()
+                .into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: Some(ContentEncoding::Brotli),
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        let preview = BodySelection::Preview { max_bytes: 6 };
+
+        match view_cached(&cached, preview, false).await.unwrap().unwrap() {
+            BodyView::Bytes {
+                decoded,
+                total_bytes,
+                content,
+                ..
+            } => {
+                assert!(decoded);
+                assert_eq!(total_bytes, text.len());
+                assert_eq!(content, "<html>");
+            }
+            other => panic!("unexpected view {:?}", other),
+        }
+        match view_cached(&cached, BodySelection::Hash, true)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            BodyView::Hash {
+                decoded,
+                total_bytes,
+                ..
+            } => {
+                assert!(!decoded);
+                assert_eq!(total_bytes, cached.body.len());
+            }
+            other => panic!("unexpected view {:?}", other),
+        }
+        assert!(view_cached(&cached, BodySelection::None, false)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
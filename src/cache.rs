@@ -1,14 +1,21 @@
-use dashmap::DashMap;
-use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
-use std::hash::{Hash, Hasher};
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry as DashEntry, DashMap};
+use indexmap::IndexMap;
+use std::collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
 use std::path::PathBuf;
 use std::process;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
-use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::Instant;
 
 use crate::compression::ContentEncoding;
+use crate::decision_memo::DecisionMemo;
+use crate::path_matcher::{Wildcard, WildcardSyntax};
+use crate::persistence::{from_unix_millis, unix_millis, CacheSnapshot, PersistedEntry};
 pub use crate::CacheStorageMode;
 
 static BODY_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -20,6 +27,28 @@ pub enum InvalidationMessage {
     All,
     /// Invalidate cache entries whose key matches a pattern (supports wildcards).
     Pattern(String),
+    /// Invalidate the entry stored under exactly this key, and its `Vary`
+    /// variants. A `*` in the key is taken literally.
+    Key(String),
+}
+
+/// An invalidation as carried on a [`CacheHandle`]'s broadcast channel.
+#[derive(Clone, Debug)]
+pub struct InvalidationEvent {
+    /// Set when sent through a [`ScopedCacheHandle`]. Unscoped events reach
+    /// every listener; scoped ones only proxies configured with that scope.
+    pub scope: Option<String>,
+    pub message: InvalidationMessage,
+}
+
+impl InvalidationEvent {
+    /// Whether a listener configured with `scope` acts on this event.
+    pub fn applies_to(&self, scope: Option<&str>) -> bool {
+        match &self.scope {
+            None => true,
+            Some(event_scope) => scope == Some(event_scope.as_str()),
+        }
+    }
 }
 
 /// An operation sent to the snapshot worker for runtime SSG management.
@@ -44,7 +73,7 @@ pub(crate) enum SnapshotOp {
 /// PreGenerate mode) managing the list of pre-generated SSG snapshots at runtime.
 #[derive(Clone)]
 pub struct CacheHandle {
-    sender: broadcast::Sender<InvalidationMessage>,
+    sender: broadcast::Sender<InvalidationEvent>,
     /// Present only when the proxy is in `ProxyMode::PreGenerate`.
     snapshot_tx: Option<mpsc::Sender<SnapshotRequest>>,
 }
@@ -70,15 +99,35 @@ impl CacheHandle {
 
     /// Invalidate all cache entries.
     pub fn invalidate_all(&self) {
-        let _ = self.sender.send(InvalidationMessage::All);
+        send_invalidation(&self.sender, None, InvalidationMessage::All);
     }
 
     /// Invalidate cache entries whose key matches `pattern`.
     /// Supports wildcards: `"/api/*"`, `"GET:/api/*"`, etc.
     pub fn invalidate(&self, pattern: &str) {
-        let _ = self
-            .sender
-            .send(InvalidationMessage::Pattern(pattern.to_string()));
+        send_invalidation(
+            &self.sender,
+            None,
+            InvalidationMessage::Pattern(pattern.to_string()),
+        );
+    }
+
+    /// Invalidate the entry under exactly `key`, such as `"GET:/products/42"`.
+    pub fn invalidate_key(&self, key: &str) {
+        send_invalidation(
+            &self.sender,
+            None,
+            InvalidationMessage::Key(key.to_string()),
+        );
+    }
+
+    /// A handle on the same channel whose invalidations only reach proxies
+    /// configured with `scope` (see `CreateProxyConfig::with_invalidation_scope`).
+    pub fn scoped(&self, scope: impl Into<String>) -> ScopedCacheHandle {
+        ScopedCacheHandle {
+            sender: self.sender.clone(),
+            scope: scope.into(),
+        }
     }
 
     /// Returns `true` when this handle is connected to a snapshot worker
@@ -87,8 +136,9 @@ impl CacheHandle {
         self.snapshot_tx.is_some()
     }
 
-    /// Subscribe to invalidation events.
-    pub fn subscribe(&self) -> broadcast::Receiver<InvalidationMessage> {
+    /// Subscribe to every invalidation event on this channel, whatever its
+    /// scope.
+    pub fn subscribe(&self) -> broadcast::Receiver<InvalidationEvent> {
         self.sender.subscribe()
     }
 
@@ -134,50 +184,64 @@ impl CacheHandle {
     }
 }
 
-/// Helper function to check if a key matches a pattern with wildcard support
-fn matches_pattern(key: &str, pattern: &str) -> bool {
-    // Handle exact match
-    if key == pattern {
-        return true;
-    }
-
-    // Split pattern by '*' and check if all parts exist in order
-    let parts: Vec<&str> = pattern.split('*').collect();
+/// A [`CacheHandle`] whose invalidations carry a scope, so proxies sharing
+/// one channel can be purged independently. Obtained from
+/// [`CacheHandle::scoped`].
+#[derive(Clone)]
+pub struct ScopedCacheHandle {
+    sender: broadcast::Sender<InvalidationEvent>,
+    scope: String,
+}
 
-    if parts.len() == 1 {
-        // No wildcard, exact match already checked above
-        return false;
+impl ScopedCacheHandle {
+    pub fn scope(&self) -> &str {
+        &self.scope
     }
 
-    let mut current_pos = 0;
+    /// Invalidate all entries of the proxies in this scope.
+    pub fn invalidate_all(&self) {
+        send_invalidation(
+            &self.sender,
+            Some(self.scope.clone()),
+            InvalidationMessage::All,
+        );
+    }
 
-    for (i, part) in parts.iter().enumerate() {
-        if part.is_empty() {
-            continue;
-        }
+    /// Invalidate entries matching `pattern` in the proxies of this scope.
+    pub fn invalidate(&self, pattern: &str) {
+        send_invalidation(
+            &self.sender,
+            Some(self.scope.clone()),
+            InvalidationMessage::Pattern(pattern.to_string()),
+        );
+    }
 
-        // First part must match from the beginning
-        if i == 0 {
-            if !key.starts_with(part) {
-                return false;
-            }
-            current_pos = part.len();
-        }
-        // Last part must match to the end
-        else if i == parts.len() - 1 {
-            if !key[current_pos..].ends_with(part) {
-                return false;
-            }
-        }
-        // Middle parts must exist in order
-        else if let Some(pos) = key[current_pos..].find(part) {
-            current_pos += pos + part.len();
-        } else {
-            return false;
-        }
+    /// Invalidate the entry under exactly `key` in the proxies of this scope.
+    pub fn invalidate_key(&self, key: &str) {
+        send_invalidation(
+            &self.sender,
+            Some(self.scope.clone()),
+            InvalidationMessage::Key(key.to_string()),
+        );
     }
+}
+
+fn send_invalidation(
+    sender: &broadcast::Sender<InvalidationEvent>,
+    scope: Option<String>,
+    message: InvalidationMessage,
+) {
+    let _ = sender.send(InvalidationEvent { scope, message });
+}
 
-    true
+/// Whether a purge for `pattern` removes the entry under `key`: the key or,
+/// for a variant, the key it varies from matches. Request header values in
+/// the key are ignored too, so `GET:/api/*` also purges
+/// `GET:/api/users#headers=accept-language=en`.
+pub(crate) fn purge_matches(key: &str, pattern: &Wildcard) -> bool {
+    pattern.matches(key)
+        || pattern.matches(crate::vary::base_key(key))
+        || pattern.matches(crate::cache_rules::base_key(key))
 }
 
 /// Cache storage for prerendered content
@@ -185,39 +249,277 @@ fn matches_pattern(key: &str, pattern: &str) -> bool {
 pub struct CacheStore {
     store: Arc<DashMap<String, StoredCachedResponse>>,
     // 404-specific store with bounded capacity and FIFO eviction
-    store_404: Arc<DashMap<String, StoredCachedResponse>>,
-    keys_404: Arc<RwLock<VecDeque<String>>>,
+    store_404: Arc<AlternateStore>,
     cache_404_capacity: usize,
+    // 5xx responses, only when error caching is enabled; short TTL
+    store_error: Arc<AlternateStore>,
+    cache_error_capacity: usize,
     handle: CacheHandle,
     body_store: CacheBodyStore,
+    /// Keys recently found not cacheable; cleared together with the cache.
+    decision_memo: Arc<DecisionMemo>,
+    /// How long main-cache entries are kept past their TTL so they can be
+    /// served stale; `None` drops them once it lapses.
+    stale_retention: Option<Duration>,
+    /// Approximate bytes held by the main cache, kept up to date on every
+    /// insert and removal.
+    store_bytes: Arc<AtomicUsize>,
+    /// Byte budget for the main cache; 0 means unlimited.
+    max_bytes: usize,
+    /// Recency order of main-cache keys, only kept when `max_bytes` is set.
+    lru: Arc<Mutex<LruIndex>>,
+    /// Entries dropped to stay within `max_bytes`.
+    budget_evictions: Arc<AtomicU64>,
+    /// The last [`RECENT_PURGES`] calls to `clear` and `clear_by_pattern`,
+    /// newest last.
+    purges: Arc<Mutex<VecDeque<Purge>>>,
+    /// Request headers the responses stored under each key vary on, from the
+    /// last one stored. Keys whose responses do not vary are absent.
+    vary: Arc<DashMap<String, Arc<[String]>>>,
+    /// The variant keys stored under each base key in any store, so
+    /// `clear_key` finds them without scanning. Kept in step with the stores
+    /// on every insert, removal, eviction, and expiry.
+    variants: Arc<DashMap<String, HashSet<String>>>,
+    /// How `*` in purge patterns treats `/`.
+    wildcard_syntax: WildcardSyntax,
+    /// Shared main cache; when set, `store` stays empty.
+    #[cfg(feature = "redis-backend")]
+    redis: Option<crate::redis_cache::RedisCache>,
+}
+
+/// Purges kept for [`CacheStore::recent_purges`].
+pub const RECENT_PURGES: usize = 20;
+
+/// A call to [`CacheStore::clear`] or [`CacheStore::clear_by_pattern`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Purge {
+    pub at: SystemTime,
+    /// The pattern or exact key purged; `None` when the whole cache was
+    /// cleared.
+    pub pattern: Option<String>,
+    /// Entries removed from the main, 404, and error stores.
+    pub removed: usize,
+}
+
+/// Main-cache keys from least to most recently stored or served.
+#[derive(Default)]
+struct LruIndex {
+    next_seq: u64,
+    seqs: HashMap<Arc<str>, u64>,
+    order: BTreeMap<u64, Arc<str>>,
+}
+
+impl LruIndex {
+    /// Mark `key` as the most recently used, adding it if needed.
+    fn touch(&mut self, key: &str) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        match self.seqs.get_key_value(key) {
+            Some((key, &old)) => {
+                let key = key.clone();
+                self.order.remove(&old);
+                self.order.insert(seq, key.clone());
+                self.seqs.insert(key, seq);
+            }
+            None => {
+                let key: Arc<str> = Arc::from(key);
+                self.order.insert(seq, key.clone());
+                self.seqs.insert(key, seq);
+            }
+        }
+    }
+
+    /// Mark `key` as used if it is tracked.
+    fn refresh(&mut self, key: &str) {
+        if self.seqs.contains_key(key) {
+            self.touch(key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(seq) = self.seqs.remove(key) {
+            self.order.remove(&seq);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<Arc<str>> {
+        let (_, key) = self.order.pop_first()?;
+        self.seqs.remove(&key);
+        Some(key)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct CachedResponse {
-    pub body: Vec<u8>,
-    pub headers: HashMap<String, String>,
+    /// Shared with the stored entry: serving a hit does not copy it.
+    pub body: Bytes,
+    /// Header fields as lowercase name/value pairs in the order the backend
+    /// sent them. A name appears once per field line, so repeated headers
+    /// such as `Set-Cookie` are kept.
+    pub headers: Vec<(String, String)>,
     pub status: u16,
     pub content_encoding: Option<ContentEncoding>,
+    /// The request this entry was stored for. `None` for entries seeded
+    /// under an explicit key, which are served to any request for that key.
+    pub origin: Option<CacheOrigin>,
+    /// [`content_digest`] of the decoded body, recorded for entries that went
+    /// through HTML normalization so identical pages can be recognized.
+    pub content_digest: Option<String>,
+    /// When the entry was stored. Set on entries read from a [`CacheStore`];
+    /// ignored when storing one.
+    pub stored_at: Option<SystemTime>,
+}
+
+impl CachedResponse {
+    /// Time since the entry was stored, if it came from a store.
+    pub fn age(&self) -> Option<Duration> {
+        self.stored_at.map(|at| at.elapsed().unwrap_or_default())
+    }
+
+    /// Whether `status` is a valid HTTP status code (100–999). Stores
+    /// refuse entries with any other status.
+    pub fn has_valid_status(&self) -> bool {
+        axum::http::StatusCode::from_u16(self.status).is_ok()
+    }
+
+    /// The value of the first `name` header field.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Whether `response` has a valid status, warning when it does not. An entry
+/// with an invalid one could not be served.
+fn status_is_storable(key: &str, response: &CachedResponse) -> bool {
+    if response.has_valid_status() {
+        return true;
+    }
+    tracing::warn!(
+        "Not caching '{}': invalid status code {}",
+        key,
+        response.status
+    );
+    false
+}
+
+/// Hex-encoded SHA-256 of `body`.
+pub fn content_digest(body: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, body)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Method and normalized path of the request a cache entry was stored for.
+///
+/// A hit for a different method or path means `cache_key_fn` maps two
+/// requests to one key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheOrigin {
+    pub method: String,
+    pub path: String,
+}
+
+impl CacheOrigin {
+    pub fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    /// Whether a request for `method` and `path` is the one this entry was
+    /// stored for.
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        self.method == method && self.path == path
+    }
+}
+
+/// A cache body that could not be read or written. Only filesystem storage
+/// and Redis fail this way.
+#[derive(Debug)]
+pub enum CacheError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[cfg(feature = "redis-backend")]
+    Redis(redis::RedisError),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { path, source } => {
+                write!(
+                    f,
+                    "failed to read cached body '{}': {}",
+                    path.display(),
+                    source
+                )
+            }
+            Self::Write { path, source } => {
+                write!(
+                    f,
+                    "failed to write cached body '{}': {}",
+                    path.display(),
+                    source
+                )
+            }
+            #[cfg(feature = "redis-backend")]
+            Self::Redis(source) => write!(f, "Redis cache request failed: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read { source, .. } | Self::Write { source, .. } => Some(source),
+            #[cfg(feature = "redis-backend")]
+            Self::Redis(source) => Some(source),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct StoredCachedResponse {
     body: StoredBody,
-    headers: HashMap<String, String>,
+    /// Length of the body as stored, known without reading a file back.
+    body_len: usize,
+    headers: Vec<(String, String)>,
     status: u16,
     content_encoding: Option<ContentEncoding>,
+    origin: Option<CacheOrigin>,
+    content_digest: Option<String>,
+    stored_at: SystemTime,
+    /// Set for main-cache entries stored with [`CacheStore::set_with_ttl`].
+    expires: Option<Instant>,
+    /// Requests served from this entry; shared by clones so a lookup can
+    /// count without a write lock.
+    hits: Arc<AtomicU64>,
 }
 
 #[derive(Clone, Debug)]
 enum StoredBody {
-    Memory(Vec<u8>),
+    Memory(Bytes),
     File(PathBuf),
 }
 
+/// The store an entry lives in.
 #[derive(Clone, Copy, Debug)]
-enum CacheBucket {
+pub(crate) enum CacheBucket {
     Standard,
     NotFound,
+    Error,
 }
 
 impl CacheBucket {
@@ -225,6 +527,7 @@ impl CacheBucket {
         match self {
             Self::Standard => "responses",
             Self::NotFound => "responses-404",
+            Self::Error => "responses-error",
         }
     }
 }
@@ -249,43 +552,92 @@ impl CacheBodyStore {
         Self { mode, root_dir }
     }
 
-    async fn store(&self, key: &str, body: Vec<u8>, bucket: CacheBucket) -> StoredBody {
+    /// Store `body`, keeping it in memory when it cannot be written.
+    async fn store(&self, key: &str, body: Bytes, bucket: CacheBucket) -> StoredBody {
+        match self.try_store(key, body.clone(), bucket).await {
+            Ok(stored) => stored,
+            Err(error) => {
+                tracing::warn!(
+                    "Failed to persist cache body for '{}' to filesystem storage: {}",
+                    key,
+                    error
+                );
+                StoredBody::Memory(body)
+            }
+        }
+    }
+
+    async fn try_store(
+        &self,
+        key: &str,
+        body: Bytes,
+        bucket: CacheBucket,
+    ) -> Result<StoredBody, CacheError> {
         match self.mode {
-            CacheStorageMode::Memory => StoredBody::Memory(body),
-            CacheStorageMode::Filesystem => match self.write_body(key, &body, bucket).await {
-                Ok(path) => StoredBody::File(path),
-                Err(error) => {
-                    tracing::warn!(
-                        "Failed to persist cache body for '{}' to filesystem storage: {}",
-                        key,
-                        error
-                    );
-                    StoredBody::Memory(body)
-                }
-            },
+            CacheStorageMode::Memory => Ok(StoredBody::Memory(body)),
+            CacheStorageMode::Filesystem => self
+                .write_body(key, &body, bucket)
+                .await
+                .map(StoredBody::File),
         }
     }
 
-    async fn load(&self, body: &StoredBody) -> Option<Vec<u8>> {
+    async fn load(&self, body: &StoredBody) -> Result<Bytes, CacheError> {
         match body {
-            StoredBody::Memory(bytes) => Some(bytes.clone()),
-            StoredBody::File(path) => match tokio::fs::read(path).await {
-                Ok(bytes) => Some(bytes),
-                Err(error) => {
+            StoredBody::Memory(bytes) => Ok(bytes.clone()),
+            StoredBody::File(path) => {
+                tokio::fs::read(path)
+                    .await
+                    .map(Bytes::from)
+                    .map_err(|source| CacheError::Read {
+                        path: path.clone(),
+                        source,
+                    })
+            }
+        }
+    }
+
+    async fn remove(&self, body: StoredBody) {
+        if let StoredBody::File(path) = body {
+            if let Err(error) = tokio::fs::remove_file(&path).await {
+                if error.kind() != std::io::ErrorKind::NotFound {
                     tracing::warn!(
-                        "Failed to read cached response body from '{}': {}",
+                        "Failed to delete cached response body '{}': {}",
                         path.display(),
                         error
                     );
-                    None
                 }
-            },
+            }
         }
     }
 
-    async fn remove(&self, body: StoredBody) {
+    /// Like [`store`](Self::store), writing with blocking calls; for
+    /// restoring a snapshot before the proxy serves requests.
+    fn store_blocking(&self, key: &str, body: Bytes, bucket: CacheBucket) -> StoredBody {
+        if let CacheStorageMode::Memory = self.mode {
+            return StoredBody::Memory(body);
+        }
+        let (bucket_dir, tmp_path, final_path) = self.body_paths(key, bucket);
+        let written = std::fs::create_dir_all(&bucket_dir)
+            .and_then(|()| std::fs::write(&tmp_path, &body))
+            .and_then(|()| std::fs::rename(&tmp_path, &final_path));
+        match written {
+            Ok(()) => StoredBody::File(final_path),
+            Err(error) => {
+                tracing::warn!(
+                    "Failed to persist cache body for '{}' to filesystem storage: {}",
+                    key,
+                    error
+                );
+                StoredBody::Memory(body)
+            }
+        }
+    }
+
+    /// Like [`remove`](Self::remove), with blocking calls.
+    fn remove_blocking(&self, body: StoredBody) {
         if let StoredBody::File(path) = body {
-            if let Err(error) = tokio::fs::remove_file(&path).await {
+            if let Err(error) = std::fs::remove_file(&path) {
                 if error.kind() != std::io::ErrorKind::NotFound {
                     tracing::warn!(
                         "Failed to delete cached response body '{}': {}",
@@ -297,49 +649,333 @@ impl CacheBodyStore {
         }
     }
 
-    async fn write_body(
-        &self,
-        key: &str,
-        body: &[u8],
-        bucket: CacheBucket,
-    ) -> std::io::Result<PathBuf> {
+    /// The bucket directory, temporary path, and final path for a new body
+    /// file for `key`.
+    fn body_paths(&self, key: &str, bucket: CacheBucket) -> (PathBuf, PathBuf, PathBuf) {
         let root_dir = self
             .root_dir
             .as_ref()
             .expect("filesystem cache storage requires a root directory");
         let bucket_dir = root_dir.join(bucket.directory_name());
-        tokio::fs::create_dir_all(&bucket_dir).await?;
-
         let stem = cache_file_stem(key);
         let tmp_path = bucket_dir.join(format!("{}.tmp", stem));
         let final_path = bucket_dir.join(format!("{}.bin", stem));
+        (bucket_dir, tmp_path, final_path)
+    }
 
-        tokio::fs::write(&tmp_path, body).await?;
-        tokio::fs::rename(&tmp_path, &final_path).await?;
+    async fn write_body(
+        &self,
+        key: &str,
+        body: &[u8],
+        bucket: CacheBucket,
+    ) -> Result<PathBuf, CacheError> {
+        let (bucket_dir, tmp_path, final_path) = self.body_paths(key, bucket);
 
-        Ok(final_path)
+        let written = async {
+            tokio::fs::create_dir_all(&bucket_dir).await?;
+            tokio::fs::write(&tmp_path, body).await?;
+            tokio::fs::rename(&tmp_path, &final_path).await
+        }
+        .await;
+        match written {
+            Ok(()) => Ok(final_path),
+            Err(source) => Err(CacheError::Write {
+                path: final_path,
+                source,
+            }),
+        }
     }
 }
 
 impl StoredCachedResponse {
-    async fn materialize(self, body_store: &CacheBodyStore) -> Option<CachedResponse> {
+    /// Approximate heap and inline bytes held for this entry under `key`.
+    /// File-backed bodies count only their path.
+    fn approximate_size(&self, key: &str) -> usize {
+        let body = match &self.body {
+            StoredBody::Memory(bytes) => bytes.len(),
+            StoredBody::File(path) => path.as_os_str().len(),
+        };
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        let origin = self
+            .origin
+            .as_ref()
+            .map_or(0, |origin| origin.method.len() + origin.path.len());
+        let digest = self.content_digest.as_ref().map_or(0, String::len);
+        // The variant index holds a second copy of a variant's key.
+        let key = if crate::vary::base_key(key).len() < key.len() {
+            2 * key.len()
+        } else {
+            key.len()
+        };
+        key + body + headers + origin + digest + std::mem::size_of::<Self>()
+    }
+
+    fn key_info(&self, key: &str, store: &'static str) -> CacheKeyInfo {
+        CacheKeyInfo {
+            key: key.to_string(),
+            store,
+            status: self.status,
+            stored_at: self.stored_at,
+            body_bytes: self.body_len,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= Instant::now())
+    }
+
+    /// Whether the TTL lapsed more than `retention` ago, or at all without
+    /// one.
+    fn is_past_retention(&self, retention: Option<Duration>) -> bool {
+        let Some(expires) = self.expires else {
+            return false;
+        };
+        expires
+            .checked_add(retention.unwrap_or_default())
+            .is_some_and(|until| until <= Instant::now())
+    }
+
+    async fn materialize(self, body_store: &CacheBodyStore) -> Result<CachedResponse, CacheError> {
         let body = body_store.load(&self.body).await?;
 
-        Some(CachedResponse {
+        Ok(CachedResponse {
             body,
             headers: self.headers,
             status: self.status,
             content_encoding: self.content_encoding,
+            origin: self.origin,
+            content_digest: self.content_digest,
+            stored_at: Some(self.stored_at),
         })
     }
 }
 
+/// Entries per shard an [`AlternateStore`] must be able to hold before it is
+/// split; smaller stores use a single shard. Removing a key shifts the ones
+/// after it, so this also bounds the cost of an eviction.
+const ALTERNATE_SHARD_CAPACITY: usize = 64;
+
+/// Most shards an [`AlternateStore`] is split into.
+const MAX_ALTERNATE_SHARDS: usize = 256;
+
+/// Bounded side store for 404 and error responses, evicting the oldest
+/// entries beyond its capacity; re-inserting a key moves it to the back.
+///
+/// Keys are spread over shards by hash, each an insertion-ordered map with
+/// its own lock and share of the capacity, so lookups and stores of
+/// different keys rarely wait on each other. Eviction order is kept per
+/// shard, so only a store too small to split evicts strictly oldest first.
+///
+/// With a TTL, entries stop being served once it lapses and are dropped by
+/// capacity eviction or a purge.
+struct AlternateStore {
+    shards: Box<[RwLock<AlternateShard>]>,
+    hasher: RandomState,
+    ttl: Option<Duration>,
+    /// Entries dropped because the store was at capacity.
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct AlternateShard {
+    /// Boxed, so shifting entries after a removal moves pointers only.
+    entries: IndexMap<String, Box<AlternateEntry>>,
+    capacity: usize,
+    bytes: usize,
+}
+
+struct AlternateEntry {
+    response: StoredCachedResponse,
+    expires: Option<Instant>,
+}
+
+impl AlternateShard {
+    fn remove(&mut self, key: &str) -> Option<StoredBody> {
+        let old = self.entries.shift_remove(key)?;
+        self.bytes -= old.response.approximate_size(key);
+        Some(old.response.body)
+    }
+}
+
+impl AlternateStore {
+    fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        let count = (capacity / ALTERNATE_SHARD_CAPACITY).clamp(1, MAX_ALTERNATE_SHARDS);
+        let shards = (0..count)
+            .map(|index| {
+                RwLock::new(AlternateShard {
+                    capacity: capacity / count + usize::from(index < capacity % count),
+                    ..AlternateShard::default()
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            ttl,
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<AlternateShard> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    fn get(&self, key: &str) -> Option<StoredCachedResponse> {
+        let shard = self.shard(key).read().unwrap();
+        let entry = shard.entries.get(key)?;
+        if entry
+            .expires
+            .is_some_and(|expires| expires <= Instant::now())
+        {
+            return None;
+        }
+        Some(entry.response.clone())
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.shard(key).read().unwrap().entries.contains_key(key)
+    }
+
+    /// Insert `response` and evict the oldest entries beyond the shard's
+    /// capacity. Returns the keys and bodies of replaced and evicted entries.
+    fn insert(&self, key: String, response: StoredCachedResponse) -> Vec<(String, StoredBody)> {
+        let mut shard = self.shard(&key).write().unwrap();
+        let mut removed = Vec::new();
+        // Removed rather than overwritten, so the key moves to the back.
+        if let Some(old) = shard.remove(&key) {
+            removed.push((key.clone(), old));
+        }
+
+        shard.bytes += response.approximate_size(&key);
+        let entry = Box::new(AlternateEntry {
+            response,
+            expires: self.ttl.map(|ttl| Instant::now() + ttl),
+        });
+        shard.entries.insert(key, entry);
+
+        while shard.entries.len() > shard.capacity {
+            let Some((key, old)) = shard.entries.shift_remove_index(0) else {
+                break;
+            };
+            shard.bytes -= old.response.approximate_size(&key);
+            removed.push((key, old.response.body));
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Remove every entry whose key satisfies `predicate`.
+    fn remove_where(&self, predicate: impl Fn(&str) -> bool) -> Vec<StoredBody> {
+        let mut removed = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let keys: Vec<String> = shard
+                .entries
+                .keys()
+                .filter(|key| predicate(key))
+                .cloned()
+                .collect();
+            removed.extend(keys.iter().filter_map(|key| shard.remove(key)));
+        }
+        removed
+    }
+
+    /// Remove the entries under `keys`.
+    fn remove_keys(&self, keys: &[String]) -> Vec<StoredBody> {
+        keys.iter().filter_map(|key| self.remove(key)).collect()
+    }
+
+    /// Remove every entry, keeping the eviction count.
+    fn drain(&self) -> Vec<StoredBody> {
+        let mut drained = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            shard.bytes = 0;
+            drained.extend(
+                shard
+                    .entries
+                    .drain(..)
+                    .map(|(_, entry)| entry.response.body),
+            );
+        }
+        drained
+    }
+
+    fn remove(&self, key: &str) -> Option<StoredBody> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().entries.len())
+            .sum()
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Describe the entries whose key satisfies `predicate`, including any
+    /// whose TTL has lapsed.
+    fn list(&self, store: &'static str, predicate: &impl Fn(&str) -> bool) -> Vec<CacheKeyInfo> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            keys.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|(key, _)| predicate(key))
+                    .map(|(key, entry)| entry.response.key_info(key, store)),
+            );
+        }
+        keys
+    }
+
+    /// The entries still being served, oldest first.
+    fn live_entries(&self) -> Vec<(String, StoredCachedResponse)> {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.expires.is_none_or(|expires| expires > now))
+                    .map(|(key, entry)| (key.clone(), entry.response.clone())),
+            );
+        }
+        entries.sort_by_key(|(_, entry)| entry.stored_at);
+        entries
+    }
+
+    /// Approximate bytes held by the entries.
+    fn memory_usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().bytes)
+            .sum()
+    }
+}
+
 fn default_cache_directory() -> PathBuf {
     std::env::temp_dir().join("phantom-frame-cache")
 }
 
 fn cleanup_orphaned_cache_files(root_dir: &std::path::Path) {
-    for bucket in [CacheBucket::Standard, CacheBucket::NotFound] {
+    for bucket in [
+        CacheBucket::Standard,
+        CacheBucket::NotFound,
+        CacheBucket::Error,
+    ] {
         let bucket_dir = root_dir.join(bucket.directory_name());
         cleanup_bucket_directory(&bucket_dir);
     }
@@ -411,12 +1047,23 @@ fn cache_file_stem(key: &str) -> String {
     format!("{:016x}-{:x}-{:016x}", hash, process::id(), counter)
 }
 
+fn log_read_error(error: CacheError) -> Option<CachedResponse> {
+    tracing::warn!("{}", error);
+    None
+}
+
 fn into_stored_response(body: StoredBody, response: CachedResponse) -> StoredCachedResponse {
     StoredCachedResponse {
         body,
+        body_len: response.body.len(),
         headers: response.headers,
         status: response.status,
         content_encoding: response.content_encoding,
+        origin: response.origin,
+        content_digest: response.content_digest,
+        stored_at: SystemTime::now(),
+        expires: None,
+        hits: Arc::default(),
     }
 }
 
@@ -433,159 +1080,817 @@ impl CacheStore {
     ) -> Self {
         Self {
             store: Arc::new(DashMap::new()),
-            store_404: Arc::new(DashMap::new()),
-            keys_404: Arc::new(RwLock::new(VecDeque::new())),
+            store_404: Arc::new(AlternateStore::new(cache_404_capacity, None)),
             cache_404_capacity,
+            store_error: Arc::new(AlternateStore::new(0, None)),
+            cache_error_capacity: 0,
             handle,
             body_store: CacheBodyStore::new(storage_mode, cache_directory),
+            decision_memo: Arc::new(DecisionMemo::new(std::time::Duration::ZERO)),
+            stale_retention: None,
+            store_bytes: Arc::new(AtomicUsize::new(0)),
+            max_bytes: 0,
+            lru: Arc::new(Mutex::new(LruIndex::default())),
+            budget_evictions: Arc::new(AtomicU64::new(0)),
+            purges: Arc::default(),
+            vary: Arc::default(),
+            variants: Arc::default(),
+            wildcard_syntax: WildcardSyntax::Legacy,
+            #[cfg(feature = "redis-backend")]
+            redis: None,
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
-        let cached = self.store.get(key).map(|entry| entry.clone())?;
+    /// Cap the main cache at roughly `max_bytes` (0 = unlimited), counted
+    /// like [`memory_usage`](Self::memory_usage). Least recently used
+    /// entries are evicted to make room, and a response larger than the
+    /// whole budget is not stored at all.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Match purge patterns with `syntax`, e.g. so `GET:/api/*` clears
+    /// `GET:/api/users` but not `GET:/api/users/1` with
+    /// [`WildcardSyntax::Segments`].
+    pub fn with_wildcard_syntax(mut self, syntax: WildcardSyntax) -> Self {
+        self.wildcard_syntax = syntax;
+        self
+    }
+
+    /// Remember "not cacheable" verdicts for `ttl` (zero disables the memo).
+    pub fn with_decision_memo(mut self, ttl: std::time::Duration) -> Self {
+        self.decision_memo = Arc::new(DecisionMemo::new(ttl));
+        self
+    }
+
+    /// Keep up to `capacity` error (5xx) responses, each served for at most
+    /// `ttl`. A zero capacity or TTL leaves error caching disabled.
+    pub fn with_error_store(mut self, capacity: usize, ttl: Duration) -> Self {
+        if !ttl.is_zero() {
+            self.store_error = Arc::new(AlternateStore::new(capacity, Some(ttl)));
+            self.cache_error_capacity = capacity;
+        }
+        self
+    }
+
+    /// Keep entries stored with [`set_with_ttl`](Self::set_with_ttl) for up
+    /// to `retention` after their TTL lapses instead of dropping them on
+    /// lookup. They are no longer returned by [`get`](Self::get), but remain
+    /// available as stale copies until then, or until replaced or purged.
+    /// `None` drops them as soon as the TTL lapses.
+    pub fn with_stale_retention(mut self, retention: Option<Duration>) -> Self {
+        self.stale_retention = retention;
+        self
+    }
+
+    /// Keep the main cache in Redis instead of this process, sharing it with
+    /// every proxy using the same server and key prefix. Entries then count
+    /// towards neither [`size`](Self::size) nor `max_bytes`; Redis's own
+    /// memory policy applies.
+    #[cfg(feature = "redis-backend")]
+    pub fn with_redis(mut self, redis: crate::redis_cache::RedisCache) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    /// Whether error responses may be cached at all.
+    pub fn error_store_enabled(&self) -> bool {
+        self.cache_error_capacity > 0
+    }
 
-        cached.materialize(&self.body_store).await
+    pub fn decision_memo(&self) -> &DecisionMemo {
+        &self.decision_memo
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.try_get(key).await.unwrap_or_else(log_read_error)
     }
 
     /// Get a 404 cached response (if present)
     pub async fn get_404(&self, key: &str) -> Option<CachedResponse> {
-        let cached = self.store_404.get(key).map(|entry| entry.clone())?;
+        self.try_get_404(key).await.unwrap_or_else(log_read_error)
+    }
 
-        cached.materialize(&self.body_store).await
+    /// Get a cached error response, if present and its TTL has not lapsed.
+    pub async fn get_error(&self, key: &str) -> Option<CachedResponse> {
+        self.try_get_error(key).await.unwrap_or_else(log_read_error)
     }
 
-    pub async fn set(&self, key: String, response: CachedResponse) {
-        let body = self
-            .body_store
-            .store(&key, response.body.clone(), CacheBucket::Standard)
-            .await;
-        let stored = into_stored_response(body, response);
+    /// Like [`get`](Self::get), but reports a body that cannot be read
+    /// instead of treating it as a miss.
+    pub(crate) async fn try_get(&self, key: &str) -> Result<Option<CachedResponse>, CacheError> {
+        #[cfg(feature = "redis-backend")]
+        if let Some(redis) = &self.redis {
+            return redis.get(key, false).await.map_err(CacheError::Redis);
+        }
+        let Some(cached) = self.store.get(key).map(|entry| entry.clone()) else {
+            return Ok(None);
+        };
+        if cached.is_expired() {
+            self.drop_past_retention(key).await;
+            return Ok(None);
+        }
 
-        let replaced = self.store.insert(key, stored);
+        if self.max_bytes > 0 {
+            self.lru.lock().unwrap().refresh(key);
+        }
+        cached.materialize(&self.body_store).await.map(Some)
+    }
 
-        if let Some(old) = replaced {
+    /// Remove the main-cache entry for `key` if it is expired and no longer
+    /// kept as a stale copy.
+    async fn drop_past_retention(&self, key: &str) {
+        let retention = self.stale_retention;
+        let expired = self
+            .store
+            .remove_if(key, |_, entry| entry.is_past_retention(retention));
+        if let Some((key, old)) = expired {
+            self.forget(&key, &old);
             self.body_store.remove(old.body).await;
         }
     }
 
-    /// Set a 404 cached response. Bounded by `cache_404_capacity` and evict the oldest entries when limit reached.
-    pub async fn set_404(&self, key: String, response: CachedResponse) {
-        if self.cache_404_capacity == 0 {
-            // 404 caching disabled
-            return;
+    /// The main-cache entry for `key`, whether or not its TTL has lapsed, as
+    /// long as it is still kept as a stale copy.
+    pub(crate) async fn try_get_stale(
+        &self,
+        key: &str,
+    ) -> Result<Option<CachedResponse>, CacheError> {
+        #[cfg(feature = "redis-backend")]
+        if let Some(redis) = &self.redis {
+            return redis.get(key, true).await.map_err(CacheError::Redis);
+        }
+        match self.store.get(key).map(|entry| entry.clone()) {
+            Some(cached) if cached.is_past_retention(self.stale_retention) => {
+                self.drop_past_retention(key).await;
+                Ok(None)
+            }
+            Some(cached) => cached.materialize(&self.body_store).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn try_get_404(
+        &self,
+        key: &str,
+    ) -> Result<Option<CachedResponse>, CacheError> {
+        let cached = self.store_404.get(key);
+        match cached {
+            Some(cached) => cached.materialize(&self.body_store).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn try_get_error(
+        &self,
+        key: &str,
+    ) -> Result<Option<CachedResponse>, CacheError> {
+        let cached = self.store_error.get(key);
+        match cached {
+            Some(cached) => cached.materialize(&self.body_store).await.map(Some),
+            None => Ok(None),
         }
+    }
+
+    pub async fn set(&self, key: String, response: CachedResponse) {
+        self.set_in(CacheBucket::Standard, key, response, None)
+            .await;
+    }
+
+    /// Like [`set`](Self::set), but the entry is no longer served once `ttl`
+    /// has passed.
+    pub async fn set_with_ttl(&self, key: String, response: CachedResponse, ttl: Duration) {
+        self.set_in(CacheBucket::Standard, key, response, Some(ttl))
+            .await;
+    }
 
+    /// Set a 404 cached response. Bounded by `cache_404_capacity` and evict the oldest entries when limit reached.
+    pub async fn set_404(&self, key: String, response: CachedResponse) {
+        self.set_in(CacheBucket::NotFound, key, response, None)
+            .await;
+    }
+
+    /// Set an error cached response. Bounded like the 404 store, and served
+    /// only until the configured error TTL lapses.
+    pub async fn set_error(&self, key: String, response: CachedResponse) {
+        self.set_in(CacheBucket::Error, key, response, None).await;
+    }
+
+    /// Store into `bucket`; a body that cannot be written to the filesystem
+    /// is kept in memory.
+    async fn set_in(
+        &self,
+        bucket: CacheBucket,
+        key: String,
+        response: CachedResponse,
+        ttl: Option<Duration>,
+    ) {
+        if !self.accepts(bucket) || !status_is_storable(&key, &response) {
+            return;
+        }
+        #[cfg(feature = "redis-backend")]
+        if let (Some(redis), CacheBucket::Standard) = (&self.redis, bucket) {
+            if let Err(error) = redis.set(&key, &response, ttl, self.stale_retention).await {
+                tracing::warn!("Failed to store '{}' in Redis: {}", key, error);
+            }
+            return;
+        }
         let body = self
             .body_store
-            .store(&key, response.body.clone(), CacheBucket::NotFound)
+            .store(&key, response.body.clone(), bucket)
             .await;
-        let stored = into_stored_response(body, response);
+        self.insert(bucket, key, body, response, ttl).await;
+    }
 
-        let removed_bodies = {
-            let mut keys = self.keys_404.write().await;
-            let mut removed = Vec::new();
+    /// Store into `bucket`, reporting a body that cannot be written instead
+    /// of keeping it in memory. Nothing is stored on error.
+    pub(crate) async fn try_set_in(
+        &self,
+        bucket: CacheBucket,
+        key: String,
+        response: CachedResponse,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        if !self.accepts(bucket) || !status_is_storable(&key, &response) {
+            return Ok(());
+        }
+        #[cfg(feature = "redis-backend")]
+        if let (Some(redis), CacheBucket::Standard) = (&self.redis, bucket) {
+            return redis
+                .set(&key, &response, ttl, self.stale_retention)
+                .await
+                .map_err(CacheError::Redis);
+        }
+        let body = self
+            .body_store
+            .try_store(&key, response.body.clone(), bucket)
+            .await?;
+        self.insert(bucket, key, body, response, ttl).await;
+        Ok(())
+    }
 
-            if self.store_404.contains_key(&key) {
-                if let Some(pos) = keys.iter().position(|existing_key| existing_key == &key) {
-                    keys.remove(pos);
+    /// Whether `bucket` stores anything; the 404 and error stores can be
+    /// disabled.
+    fn accepts(&self, bucket: CacheBucket) -> bool {
+        match bucket {
+            CacheBucket::Standard => true,
+            CacheBucket::NotFound => self.cache_404_capacity > 0,
+            CacheBucket::Error => self.error_store_enabled(),
+        }
+    }
+
+    async fn insert(
+        &self,
+        bucket: CacheBucket,
+        key: String,
+        body: StoredBody,
+        response: CachedResponse,
+        ttl: Option<Duration>,
+    ) {
+        let mut stored = into_stored_response(body, response);
+        // A TTL too long to represent never expires.
+        stored.expires = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+
+        let (evicted, removed_bodies): (Vec<String>, Vec<StoredBody>) = match bucket {
+            CacheBucket::Standard => (Vec::new(), self.insert_standard(key.clone(), stored)),
+            CacheBucket::NotFound => self
+                .store_404
+                .insert(key.clone(), stored)
+                .into_iter()
+                .unzip(),
+            CacheBucket::Error => self
+                .store_error
+                .insert(key.clone(), stored)
+                .into_iter()
+                .unzip(),
+        };
+        self.sync_variant(&key);
+        for key in &evicted {
+            self.sync_variant(key);
+        }
+
+        for body in removed_bodies {
+            self.body_store.remove(body).await;
+        }
+    }
+
+    /// Bring the variant index in line with whether any store holds `key`,
+    /// when `key` names a `Vary` variant. The check runs under the index
+    /// lock, so concurrent inserts and removals of one key cannot leave it
+    /// stale.
+    fn sync_variant(&self, key: &str) {
+        let base = crate::vary::base_key(key);
+        if base.len() == key.len() {
+            return;
+        }
+        match self.variants.entry(base.to_string()) {
+            DashEntry::Occupied(mut variants) => {
+                if self.holds(key) {
+                    variants.get_mut().insert(key.to_string());
+                } else {
+                    variants.get_mut().remove(key);
+                    if variants.get().is_empty() {
+                        variants.remove();
+                    }
+                }
+            }
+            DashEntry::Vacant(slot) => {
+                if self.holds(key) {
+                    slot.insert(HashSet::from([key.to_string()]));
                 }
             }
+        }
+    }
+
+    /// Whether any store holds an entry under `key`.
+    fn holds(&self, key: &str) -> bool {
+        self.store.contains_key(key)
+            || self.store_404.contains(key)
+            || self.store_error.contains(key)
+    }
 
-            if let Some(old) = self.store_404.insert(key.clone(), stored) {
+    /// Insert into the main cache, then evict least recently used entries
+    /// until it fits `max_bytes` again. Returns the bodies to delete.
+    fn insert_standard(&self, key: String, stored: StoredCachedResponse) -> Vec<StoredBody> {
+        let size = stored.approximate_size(&key);
+        if self.max_bytes > 0 && size > self.max_bytes {
+            tracing::debug!(
+                "Not caching '{}': {} bytes exceeds the {} byte cache budget",
+                key,
+                size,
+                self.max_bytes
+            );
+            let mut removed = vec![stored.body];
+            removed.extend(self.remove_standard(&key));
+            return removed;
+        }
+
+        self.store_bytes.fetch_add(size, Ordering::Relaxed);
+        let mut removed = Vec::new();
+        if let Some(old) = self.store.insert(key.clone(), stored) {
+            self.store_bytes
+                .fetch_sub(old.approximate_size(&key), Ordering::Relaxed);
+            removed.push(old.body);
+        }
+        if self.max_bytes == 0 {
+            return removed;
+        }
+
+        let mut evicted = Vec::new();
+        let mut lru = self.lru.lock().unwrap();
+        lru.touch(&key);
+        while self.store_bytes.load(Ordering::Relaxed) > self.max_bytes {
+            let Some(victim) = lru.pop_oldest() else {
+                break;
+            };
+            if *victim == *key {
+                // Only the new entry is left; it fits on its own.
+                lru.touch(&key);
+                break;
+            }
+            if let Some((victim, old)) = self.store.remove(&*victim) {
+                self.store_bytes
+                    .fetch_sub(old.approximate_size(&victim), Ordering::Relaxed);
+                self.budget_evictions.fetch_add(1, Ordering::Relaxed);
                 removed.push(old.body);
+                evicted.push(victim);
             }
-            keys.push_back(key);
+        }
+        drop(lru);
+        for key in &evicted {
+            self.sync_variant(key);
+        }
+        removed
+    }
 
-            while keys.len() > self.cache_404_capacity {
-                if let Some(old_key) = keys.pop_front() {
-                    if let Some((_, old)) = self.store_404.remove(&old_key) {
-                        removed.push(old.body);
-                    }
-                }
-            }
+    /// Remove `key` from the main cache only.
+    fn remove_standard(&self, key: &str) -> Option<StoredBody> {
+        let (key, old) = self.store.remove(key)?;
+        self.forget(&key, &old);
+        Some(old.body)
+    }
 
-            removed
-        };
+    /// Account for `old` having been removed from the main cache.
+    fn forget(&self, key: &str, old: &StoredCachedResponse) {
+        self.store_bytes
+            .fetch_sub(old.approximate_size(key), Ordering::Relaxed);
+        if self.max_bytes > 0 {
+            self.lru.lock().unwrap().remove(key);
+        }
+        self.sync_variant(key);
+    }
 
-        for body in removed_bodies {
+    /// The key under which the variant of `key` selected by
+    /// `request_headers` is stored, following the `Vary` of the last
+    /// response stored for `key`.
+    pub(crate) fn variant_key(&self, key: &str, request_headers: &axum::http::HeaderMap) -> String {
+        match self.vary.get(key) {
+            Some(names) => crate::vary::variant_key(key, &names, request_headers),
+            None => key.to_string(),
+        }
+    }
+
+    /// Record the request headers responses for `key` vary on.
+    pub(crate) fn set_vary(&self, key: &str, names: Vec<String>) {
+        if names.is_empty() {
+            self.vary.remove(key);
+        } else if self.vary.get(key).is_none_or(|known| **known != names[..]) {
+            self.vary.insert(key.to_string(), names.into());
+        }
+    }
+
+    /// Remove `key` from the main, 404, and error stores.
+    pub async fn remove(&self, key: &str) {
+        let mut removed: Vec<StoredBody> = self.remove_standard(key).into_iter().collect();
+        removed.extend(self.store_404.remove(key));
+        removed.extend(self.store_error.remove(key));
+        self.sync_variant(key);
+        #[cfg(feature = "redis-backend")]
+        if let Some(redis) = &self.redis {
+            if let Err(error) = redis.remove(key).await {
+                tracing::warn!("Failed to remove '{}' from Redis: {}", key, error);
+            }
+        }
+
+        for body in removed {
             self.body_store.remove(body).await;
         }
     }
 
-    pub async fn clear(&self) {
+    /// Clear every entry. Returns how many were removed.
+    pub async fn clear(&self) -> usize {
         let standard_keys: Vec<String> =
             self.store.iter().map(|entry| entry.key().clone()).collect();
-        let not_found_keys: Vec<String> = self
-            .store_404
-            .iter()
-            .map(|entry| entry.key().clone())
-            .collect();
 
         let removed_bodies = {
             let mut removed = Vec::new();
 
-            for key in standard_keys {
-                if let Some((_, response)) = self.store.remove(&key) {
-                    removed.push(response.body);
-                }
-            }
-
-            for key in not_found_keys {
-                if let Some((_, response)) = self.store_404.remove(&key) {
-                    removed.push(response.body);
-                }
-            }
+            removed.extend(
+                standard_keys
+                    .iter()
+                    .filter_map(|key| self.remove_standard(key)),
+            );
 
-            let mut keys = self.keys_404.write().await;
-            keys.clear();
+            removed.extend(self.store_404.drain());
+            removed.extend(self.store_error.drain());
 
             removed
         };
+        self.decision_memo.clear();
+        self.vary.clear();
+        self.variants.clear();
+        let removed = removed_bodies.len() + self.purge_redis(None).await;
+        self.record_purge(None, removed);
+
+        for body in removed_bodies {
+            self.body_store.remove(body).await;
+        }
+        removed
+    }
+
+    /// Clear cache entries matching a pattern (supports wildcards). A
+    /// pattern matching a key also clears the variants stored for it.
+    /// Returns how many entries were removed.
+    pub async fn clear_by_pattern(&self, pattern: &str) -> usize {
+        // Cache keys are built from normalized paths.
+        let pattern = &*crate::normalize::normalize_percent_encoding(pattern);
+        let wildcard = Wildcard::with_syntax(pattern, self.wildcard_syntax);
+        let removed_bodies = self.remove_where(|key| purge_matches(key, &wildcard));
+        self.decision_memo.clear_wildcard(&wildcard);
+        self.vary.retain(|key, _| !wildcard.matches(key));
+        self.variants.retain(|_, variants| {
+            variants.retain(|key| self.holds(key));
+            !variants.is_empty()
+        });
+        let removed = removed_bodies.len() + self.purge_redis(Some(pattern)).await;
+        self.record_purge(Some(pattern), removed);
 
         for body in removed_bodies {
             self.body_store.remove(body).await;
         }
+        removed
     }
 
-    /// Clear cache entries matching a pattern (supports wildcards)
-    pub async fn clear_by_pattern(&self, pattern: &str) {
+    /// Remove the entry stored under exactly `key` from every store, along
+    /// with its `Vary` variants. Unlike [`clear_by_pattern`](Self::clear_by_pattern),
+    /// a `*` in `key` matches only itself. In memory no other key is looked
+    /// at, so the cost does not grow with the size of the cache; Redis is
+    /// still scanned for variants. Returns how many entries were removed.
+    pub async fn clear_key(&self, key: &str) -> usize {
+        let key = &*crate::normalize::normalize_percent_encoding(key);
+        let mut keys = vec![key.to_string()];
+        if let Some((_, variants)) = self.variants.remove(key) {
+            keys.extend(variants);
+        }
+        let removed_bodies = self.remove_keys(&keys);
+        for key in &keys {
+            self.sync_variant(key);
+        }
+        self.decision_memo.clear_key(key);
+        self.vary.remove(key);
+        let removed = removed_bodies.len() + self.purge_redis_key(key).await;
+        self.record_purge(Some(key), removed);
+
+        for body in removed_bodies {
+            self.body_store.remove(body).await;
+        }
+        removed
+    }
+
+    /// Remove `keys` from the main, 404, and error stores, returning their
+    /// bodies.
+    fn remove_keys(&self, keys: &[String]) -> Vec<StoredBody> {
+        let mut removed: Vec<StoredBody> = keys
+            .iter()
+            .filter_map(|key| self.remove_standard(key))
+            .collect();
+        removed.extend(self.store_404.remove_keys(keys));
+        removed.extend(self.store_error.remove_keys(keys));
+        removed
+    }
+
+    /// Remove the entries whose key satisfies `predicate` from the main, 404,
+    /// and error stores, returning their bodies.
+    fn remove_where(&self, predicate: impl Fn(&str) -> bool) -> Vec<StoredBody> {
         let keys_to_remove: Vec<String> = self
             .store
             .iter()
-            .filter(|entry| matches_pattern(entry.key(), pattern))
+            .filter(|entry| predicate(entry.key()))
             .map(|entry| entry.key().clone())
             .collect();
-        let keys_to_remove_404: Vec<String> = self
-            .store_404
+
+        let mut removed: Vec<StoredBody> = keys_to_remove
             .iter()
-            .filter(|entry| matches_pattern(entry.key(), pattern))
-            .map(|entry| entry.key().clone())
+            .filter_map(|key| self.remove_standard(key))
             .collect();
+        removed.extend(self.store_404.remove_where(&predicate));
+        removed.extend(self.store_error.remove_where(&predicate));
+        removed
+    }
 
-        let removed_bodies = {
-            let mut removed = Vec::new();
+    /// Remove the Redis entries a purge covers, returning how many there
+    /// were. Failures are logged; the purge still counts as done.
+    #[cfg(feature = "redis-backend")]
+    async fn purge_redis(&self, pattern: Option<&str>) -> usize {
+        let Some(redis) = &self.redis else {
+            return 0;
+        };
+        redis
+            .remove_matching(pattern, self.wildcard_syntax)
+            .await
+            .unwrap_or_else(|error| {
+                tracing::warn!("Failed to purge the Redis cache: {}", error);
+                0
+            })
+    }
+
+    #[cfg(not(feature = "redis-backend"))]
+    async fn purge_redis(&self, _pattern: Option<&str>) -> usize {
+        0
+    }
+
+    /// Like `purge_redis`, for the entry under exactly `key`.
+    #[cfg(feature = "redis-backend")]
+    async fn purge_redis_key(&self, key: &str) -> usize {
+        let Some(redis) = &self.redis else {
+            return 0;
+        };
+        redis.remove_key(key).await.unwrap_or_else(|error| {
+            tracing::warn!("Failed to purge the Redis cache: {}", error);
+            0
+        })
+    }
+
+    #[cfg(not(feature = "redis-backend"))]
+    async fn purge_redis_key(&self, _key: &str) -> usize {
+        0
+    }
 
-            for key in keys_to_remove {
-                if let Some((_, old)) = self.store.remove(&key) {
-                    removed.push(old.body);
+    /// The main-cache and 404 entries and the learnt `Vary` names, for a
+    /// persistence snapshot. Entries past their TTL are left out unless still
+    /// kept as stale copies, and so are entries whose body cannot be read.
+    pub(crate) async fn snapshot(&self) -> CacheSnapshot {
+        let mut entries: Vec<(String, StoredCachedResponse)> = self
+            .store
+            .iter()
+            .filter(|entry| !entry.is_past_retention(self.stale_retention))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        // Restored oldest first, a smaller budget keeps the newest.
+        entries.sort_by_key(|(_, entry)| entry.stored_at);
+        let not_found = self.store_404.live_entries();
+
+        CacheSnapshot {
+            entries: self.persisted_entries(entries).await,
+            not_found: self.persisted_entries(not_found).await,
+            vary: self
+                .vary
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().to_vec()))
+                .collect(),
+            written_at: Some(unix_millis(SystemTime::now())),
+        }
+    }
+
+    async fn persisted_entries(
+        &self,
+        entries: Vec<(String, StoredCachedResponse)>,
+    ) -> Vec<PersistedEntry> {
+        let mut persisted = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let body = match self.body_store.load(&entry.body).await {
+                Ok(body) => body,
+                Err(error) => {
+                    tracing::warn!("Leaving '{}' out of the cache snapshot: {}", key, error);
+                    continue;
                 }
-            }
+            };
+            let expires_at = entry.expires.map(|expires| {
+                SystemTime::now() + expires.saturating_duration_since(Instant::now())
+            });
+            persisted.push(PersistedEntry {
+                key,
+                status: entry.status,
+                headers: entry.headers,
+                content_encoding: entry
+                    .content_encoding
+                    .map(|encoding| encoding.as_header_value().to_string()),
+                origin: entry.origin.map(|origin| (origin.method, origin.path)),
+                content_digest: entry.content_digest,
+                stored_at: unix_millis(entry.stored_at),
+                expires_at: expires_at.map(unix_millis),
+                body: body.to_vec(),
+            });
+        }
+        persisted
+    }
 
-            for key in &keys_to_remove_404 {
-                if let Some((_, old)) = self.store_404.remove(key) {
-                    removed.push(old.body);
+    /// Load a persistence snapshot, applying the capacity limits as if its
+    /// entries were stored in order. Keys already present keep their entry.
+    /// Returns how many main-cache and 404 entries the store then holds.
+    pub(crate) fn restore(&self, snapshot: CacheSnapshot) -> (usize, usize) {
+        let mut removed = Vec::new();
+        #[cfg(feature = "redis-backend")]
+        let entries = if self.redis.is_some() {
+            // The main cache lives in Redis already.
+            Vec::new()
+        } else {
+            snapshot.entries
+        };
+        #[cfg(not(feature = "redis-backend"))]
+        let entries = snapshot.entries;
+
+        for entry in entries {
+            if self.store.contains_key(&entry.key) {
+                continue;
+            }
+            if let Some((key, stored)) = self.restored_entry(entry, CacheBucket::Standard) {
+                removed.extend(self.insert_standard(key.clone(), stored));
+                self.sync_variant(&key);
+            }
+        }
+        if self.accepts(CacheBucket::NotFound) {
+            for entry in snapshot.not_found {
+                if self.store_404.get(&entry.key).is_some() {
+                    continue;
+                }
+                if let Some((key, stored)) = self.restored_entry(entry, CacheBucket::NotFound) {
+                    let evicted = self.store_404.insert(key.clone(), stored);
+                    self.sync_variant(&key);
+                    for (key, body) in evicted {
+                        self.sync_variant(&key);
+                        removed.push(body);
+                    }
                 }
             }
+        }
+        for (key, names) in snapshot.vary {
+            self.vary.entry(key).or_insert_with(|| names.into());
+        }
 
-            let mut keys = self.keys_404.write().await;
-            keys.retain(|key| !matches_pattern(key, pattern));
+        for body in removed {
+            self.body_store.remove_blocking(body);
+        }
+        (self.store.len(), self.store_404.len())
+    }
 
-            removed
+    /// `entry` as stored in `bucket`, or `None` when its TTL lapsed longer ago
+    /// than stale copies are kept.
+    fn restored_entry(
+        &self,
+        entry: PersistedEntry,
+        bucket: CacheBucket,
+    ) -> Option<(String, StoredCachedResponse)> {
+        let expires = match entry.expires_at.map(from_unix_millis) {
+            None => None,
+            Some(at) => match at.duration_since(SystemTime::now()) {
+                Ok(remaining) => Some(Instant::now() + remaining),
+                Err(lapsed) => {
+                    let lapsed = lapsed.duration();
+                    if self
+                        .stale_retention
+                        .is_none_or(|retention| lapsed >= retention)
+                    {
+                        return None;
+                    }
+                    Some(
+                        Instant::now()
+                            .checked_sub(lapsed)
+                            .unwrap_or_else(Instant::now),
+                    )
+                }
+            },
+        };
+        let body_len = entry.body.len();
+        let body = self
+            .body_store
+            .store_blocking(&entry.key, entry.body.into(), bucket);
+        let stored = StoredCachedResponse {
+            body,
+            body_len,
+            headers: entry.headers,
+            status: entry.status,
+            content_encoding: entry
+                .content_encoding
+                .as_deref()
+                .and_then(ContentEncoding::from_header_value),
+            origin: entry
+                .origin
+                .map(|(method, path)| CacheOrigin::new(&method, &path)),
+            content_digest: entry.content_digest,
+            stored_at: from_unix_millis(entry.stored_at),
+            expires,
+            hits: Arc::default(),
         };
+        Some((entry.key, stored))
+    }
 
-        for body in removed_bodies {
-            self.body_store.remove(body).await;
+    fn record_purge(&self, pattern: Option<&str>, removed: usize) {
+        let mut purges = self.purges.lock().unwrap();
+        if purges.len() == RECENT_PURGES {
+            purges.pop_front();
         }
+        purges.push_back(Purge {
+            at: SystemTime::now(),
+            pattern: pattern.map(str::to_string),
+            removed,
+        });
+    }
+
+    /// The most recent purges, newest first.
+    pub fn recent_purges(&self) -> Vec<Purge> {
+        self.purges.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Count a request served from the main-cache entry for `key`.
+    pub fn record_hit(&self, key: &str) {
+        if let Some(entry) = self.store.get(key) {
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Up to `limit` main-cache keys with the most hits since they were
+    /// stored, most hit first. Keys without hits are left out.
+    pub fn top_keys(&self, limit: usize) -> Vec<(String, u64)> {
+        let mut keys: Vec<(String, u64)> = self
+            .store
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.hits.load(Ordering::Relaxed)))
+            .filter(|(_, hits)| *hits > 0)
+            .collect();
+        keys.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(limit);
+        keys
+    }
+
+    /// Every stored entry, sorted by key. The stores are copied under their
+    /// read locks, so listing does not hold up inserts for long. With a
+    /// Redis main cache, only the local 404 and error entries are listed.
+    pub fn keys(&self) -> Vec<CacheKeyInfo> {
+        self.keys_where(|_| true)
+    }
+
+    /// The entries a purge for `pattern` would remove, sorted by key.
+    pub fn keys_matching(&self, pattern: &str) -> Vec<CacheKeyInfo> {
+        let pattern = Wildcard::with_syntax(
+            &crate::normalize::normalize_percent_encoding(pattern),
+            self.wildcard_syntax,
+        );
+        self.keys_where(|key| purge_matches(key, &pattern))
+    }
+
+    fn keys_where(&self, predicate: impl Fn(&str) -> bool) -> Vec<CacheKeyInfo> {
+        let mut keys: Vec<CacheKeyInfo> = self
+            .store
+            .iter()
+            .filter(|entry| predicate(entry.key()))
+            .map(|entry| entry.value().key_info(entry.key(), "main"))
+            .collect();
+        keys.extend(self.store_404.list("404", &predicate));
+        keys.extend(self.store_error.list("error", &predicate));
+        keys.sort_by(|a, b| a.key.cmp(&b.key).then_with(|| a.store.cmp(b.store)));
+        keys
     }
 
     pub fn handle(&self) -> &CacheHandle {
@@ -601,6 +1906,89 @@ impl CacheStore {
     pub async fn size_404(&self) -> usize {
         self.store_404.len()
     }
+
+    /// Approximate bytes held by the main cache: keys, headers, and in-memory
+    /// bodies. File-backed bodies count only their path.
+    pub async fn memory_usage(&self) -> usize {
+        self.store_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Main-cache entries evicted to stay within the
+    /// [`with_max_bytes`](Self::with_max_bytes) budget.
+    pub fn budget_evictions(&self) -> u64 {
+        self.budget_evictions.load(Ordering::Relaxed)
+    }
+
+    /// Approximate bytes held by the 404 cache.
+    pub async fn memory_usage_404(&self) -> usize {
+        self.store_404.memory_usage()
+    }
+
+    /// Size of the error cache (including entries whose TTL has lapsed but
+    /// which have not been evicted yet)
+    pub async fn size_error(&self) -> usize {
+        self.store_error.len()
+    }
+
+    /// Approximate bytes held by the error cache.
+    pub async fn memory_usage_error(&self) -> usize {
+        self.store_error.memory_usage()
+    }
+
+    /// Sizes and eviction counts of every store at once.
+    pub async fn stats(&self) -> CacheStats {
+        let (not_found_entries, not_found_bytes, not_found_evictions) = {
+            let store = &self.store_404;
+            (store.len(), store.memory_usage(), store.evictions())
+        };
+        let (error_entries, error_bytes, error_evictions) = {
+            let store = &self.store_error;
+            (store.len(), store.memory_usage(), store.evictions())
+        };
+        CacheStats {
+            entries: self.size().await,
+            not_found_entries,
+            error_entries,
+            bytes: self.memory_usage().await,
+            not_found_bytes,
+            error_bytes,
+            budget_evictions: self.budget_evictions(),
+            not_found_evictions,
+            error_evictions,
+        }
+    }
+}
+
+/// A stored entry, as listed by [`CacheStore::keys`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheKeyInfo {
+    pub key: String,
+    /// `main`, `404`, or `error`.
+    pub store: &'static str,
+    pub status: u16,
+    pub stored_at: SystemTime,
+    /// Size of the body as stored, so compressed when the entry is.
+    pub body_bytes: usize,
+}
+
+/// Point-in-time sizes of a [`CacheStore`]'s stores, from
+/// [`CacheStore::stats`]. Request outcomes are counted per proxy in
+/// [`ProxyStats`](crate::stats::ProxyStats).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub not_found_entries: usize,
+    pub error_entries: usize,
+    /// Approximate bytes, counted like [`CacheStore::memory_usage`].
+    pub bytes: usize,
+    pub not_found_bytes: usize,
+    pub error_bytes: usize,
+    /// Main-cache entries evicted to stay within `cache_max_bytes`.
+    pub budget_evictions: u64,
+    /// 404 entries evicted at `cache_404_capacity`.
+    pub not_found_evictions: u64,
+    /// Error entries evicted at `cache_errors_capacity`.
+    pub error_evictions: u64,
 }
 
 impl Default for CacheHandle {
@@ -613,6 +2001,10 @@ impl Default for CacheHandle {
 mod tests {
     use super::*;
 
+    fn matches_pattern(key: &str, pattern: &str) -> bool {
+        Wildcard::new(pattern).matches(key)
+    }
+
     fn unique_test_directory(name: &str) -> PathBuf {
         std::env::temp_dir().join(format!(
             "phantom-frame-test-{}-{:x}-{:016x}",
@@ -656,6 +2048,159 @@ mod tests {
         assert!(matches_pattern("POST:/anything", "*"));
     }
 
+    #[test]
+    fn test_segment_patterns_respect_path_boundaries() {
+        let matches = |key: &str, pattern: &str| {
+            purge_matches(
+                key,
+                &Wildcard::with_syntax(pattern, WildcardSyntax::Segments),
+            )
+        };
+        assert!(matches("GET:/api/users", "GET:/api/*"));
+        assert!(matches("GET:/api/users?page=2", "GET:/api/*"));
+        assert!(matches(
+            "GET:/api/users#vary=0123456789abcdef",
+            "GET:/api/*"
+        ));
+        assert!(matches(
+            "GET:/api/users#headers=accept=text/html#vary=0123456789abcdef",
+            "GET:/api/*"
+        ));
+        assert!(!matches("GET:/api/users/123/orders/456", "GET:/api/*"));
+        assert!(matches("GET:/api/users/123/orders/456", "GET:/api/**"));
+        assert!(matches("GET:/api/v1/users", "*:/api/*/users"));
+        assert!(!matches("GET:/api/v1/beta/users", "*:/api/*/users"));
+        assert!(matches("GET:/api/v1/beta/users", "*:/api/**/users"));
+    }
+
+    #[tokio::test]
+    async fn test_entries_with_invalid_status_are_not_stored() {
+        let store =
+            CacheStore::new(CacheHandle::new(), 10).with_error_store(10, Duration::from_secs(60));
+        let response = |status| CachedResponse {
+            body: vec![1].into(),
+            headers: Vec::new(),
+            status,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        for status in [0, 99, 1000] {
+            store.set("GET:/main".to_string(), response(status)).await;
+            store
+                .set_404("GET:/missing".to_string(), response(status))
+                .await;
+            store
+                .set_error("GET:/error".to_string(), response(status))
+                .await;
+            let stored = store
+                .try_set_in(
+                    CacheBucket::Standard,
+                    "GET:/try".to_string(),
+                    response(status),
+                    None,
+                )
+                .await;
+            assert!(stored.is_ok());
+        }
+        assert_eq!(store.size().await, 0);
+        assert_eq!(store.size_404().await, 0);
+        assert!(store.get_error("GET:/error").await.is_none());
+
+        store.set("GET:/main".to_string(), response(999)).await;
+        assert_eq!(store.get("GET:/main").await.unwrap().status, 999);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_gets_sets_and_purges_keep_the_stores_consistent() {
+        const CAPACITY_404: usize = 8;
+        const MAX_BYTES: usize = 16 * 1024;
+        let store = CacheStore::new(CacheHandle::new(), CAPACITY_404).with_max_bytes(MAX_BYTES);
+        let response = |size: usize| CachedResponse {
+            body: vec![b'x'; size].into(),
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for task in 0..200 {
+            let store = store.clone();
+            tasks.spawn(async move {
+                for op in 0..200usize {
+                    let key = format!("GET:/{}/{}", ["a", "b", "c"][(task + op) % 3], op % 32);
+                    match (task * 7 + op) % 10 {
+                        0..=4 => {
+                            store.get(&key).await;
+                        }
+                        5 | 6 => store.set(key, response(64 + op * 4)).await,
+                        7 => store.set_404(key, response(16)).await,
+                        8 => {
+                            store.get_404(&key).await;
+                        }
+                        _ if op % 50 == 9 => {
+                            store
+                                .clear_by_pattern(&format!("GET:/{}/*", ["a", "b"][task % 2]))
+                                .await;
+                        }
+                        _ => {
+                            store.clear_key(&key).await;
+                        }
+                    }
+                }
+            });
+        }
+        tasks.join_all().await;
+
+        let keys = store.keys();
+        let count = |name: &str| keys.iter().filter(|key| key.store == name).count();
+        assert_eq!(store.size().await, count("main"));
+        assert_eq!(store.size_404().await, count("404"));
+        assert!(store.size_404().await <= CAPACITY_404);
+        assert!(store.memory_usage().await <= MAX_BYTES);
+
+        store.clear().await;
+        assert_eq!(store.size().await, 0);
+        assert_eq!(store.size_404().await, 0);
+        assert_eq!(store.memory_usage().await, 0);
+        assert_eq!(store.memory_usage_404().await, 0);
+        assert!(store.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clear_by_pattern_with_segment_syntax() {
+        let store =
+            CacheStore::new(CacheHandle::new(), 10).with_wildcard_syntax(WildcardSyntax::Segments);
+        let response = CachedResponse {
+            body: vec![1].into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        for key in [
+            "GET:/api/users",
+            "GET:/api/users/1",
+            "GET:/api/users/1/orders",
+        ] {
+            store.set(key.to_string(), response.clone()).await;
+        }
+
+        assert_eq!(store.keys_matching("GET:/api/*").len(), 1);
+        assert_eq!(store.clear_by_pattern("GET:/api/*").await, 1);
+        assert!(store.get("GET:/api/users").await.is_none());
+        assert!(store.get("GET:/api/users/1").await.is_some());
+
+        assert_eq!(store.clear_by_pattern("GET:/api/**").await, 2);
+        assert_eq!(store.size().await, 0);
+    }
+
     #[tokio::test]
     async fn test_404_cache_set_get_and_eviction() {
         let trigger = CacheHandle::new();
@@ -663,22 +2208,31 @@ mod tests {
         let store = CacheStore::new(trigger, 2);
 
         let resp1 = CachedResponse {
-            body: vec![1],
-            headers: HashMap::new(),
+            body: vec![1].into(),
+            headers: Vec::new(),
             status: 404,
             content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
         };
         let resp2 = CachedResponse {
-            body: vec![2],
-            headers: HashMap::new(),
+            body: vec![2].into(),
+            headers: Vec::new(),
             status: 404,
             content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
         };
         let resp3 = CachedResponse {
-            body: vec![3],
-            headers: HashMap::new(),
+            body: vec![3].into(),
+            headers: Vec::new(),
             status: 404,
             content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
         };
 
         // Set two 404 entries
@@ -700,6 +2254,113 @@ mod tests {
         assert!(store.get_404("GET:/notfound1").await.is_none());
         assert_eq!(store.get_404("GET:/notfound2").await.unwrap().body, vec![2]);
         assert_eq!(store.get_404("GET:/notfound3").await.unwrap().body, vec![3]);
+
+        let stats = store.stats().await;
+        assert_eq!((stats.not_found_entries, stats.not_found_evictions), (2, 1));
+        // Purging empties the store but keeps the count.
+        store.clear().await;
+        assert_eq!(store.stats().await.not_found_evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_404_reinsert_moves_to_back_and_tracks_bytes() {
+        let store = CacheStore::new(CacheHandle::new(), 2);
+        let response = |byte: u8| CachedResponse {
+            body: vec![byte; 100].into(),
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            status: 404,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        store.set_404("GET:/a".to_string(), response(1)).await;
+        store.set_404("GET:/b".to_string(), response(2)).await;
+        let two_entries = store.memory_usage_404().await;
+        assert!(two_entries > 200);
+
+        // Re-inserting /a makes /b the oldest, so /c evicts /b.
+        store.set_404("GET:/a".to_string(), response(3)).await;
+        store.set_404("GET:/c".to_string(), response(4)).await;
+        assert!(store.get_404("GET:/b").await.is_none());
+        assert_eq!(store.get_404("GET:/a").await.unwrap().body[0], 3);
+
+        // Re-inserting under churn keeps the usage steady.
+        let settled = store.memory_usage_404().await;
+        for _ in 0..100 {
+            store.set_404("GET:/a".to_string(), response(3)).await;
+        }
+        assert_eq!(store.memory_usage_404().await, settled);
+
+        let before = store.memory_usage_404().await;
+        store.clear_by_pattern("GET:/a").await;
+        assert!(store.memory_usage_404().await < before);
+        store.clear().await;
+        assert_eq!(store.memory_usage_404().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_404_store_stays_within_capacity() {
+        let capacity = ALTERNATE_SHARD_CAPACITY * 4 + 3;
+        let store = CacheStore::new(CacheHandle::new(), capacity);
+        assert_eq!(store.store_404.shards.len(), 4);
+        let response = CachedResponse {
+            body: vec![0; 16].into(),
+            headers: Vec::new(),
+            status: 404,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        for index in 0..capacity * 3 {
+            store
+                .set_404(format!("GET:/missing/{index}"), response.clone())
+                .await;
+        }
+        // Every shard is full by now, and never over its share.
+        assert_eq!(store.size_404().await, capacity);
+        assert_eq!(
+            store.stats().await.not_found_evictions,
+            (capacity * 2) as u64
+        );
+        // The newest key is always kept.
+        let newest = format!("GET:/missing/{}", capacity * 3 - 1);
+        assert!(store.get_404(&newest).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_error_store_expires_and_is_purged() {
+        let store =
+            CacheStore::new(CacheHandle::new(), 10).with_error_store(10, Duration::from_secs(5));
+        let response = CachedResponse {
+            body: b"down".to_vec().into(),
+            headers: Vec::new(),
+            status: 503,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        store
+            .set_error("GET:/a".to_string(), response.clone())
+            .await;
+        store.set_error("GET:/b".to_string(), response).await;
+        assert_eq!(store.get_error("GET:/a").await.unwrap().status, 503);
+        assert!(store.get("GET:/a").await.is_none());
+
+        store.clear_by_pattern("GET:/b").await;
+        assert!(store.get_error("GET:/b").await.is_none());
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(store.get_error("GET:/a").await.is_none());
+
+        store.clear().await;
+        assert_eq!(store.size_error().await, 0);
+        assert!(store.error_store_enabled());
     }
 
     #[tokio::test]
@@ -708,10 +2369,13 @@ mod tests {
         let store = CacheStore::new(trigger, 10);
 
         let resp = CachedResponse {
-            body: vec![1],
-            headers: HashMap::new(),
+            body: vec![1].into(),
+            headers: Vec::new(),
             status: 404,
             content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
         };
         store
             .set_404("GET:/api/notfound".to_string(), resp.clone())
@@ -725,6 +2389,177 @@ mod tests {
         assert_eq!(store.size_404().await, 0);
     }
 
+    #[tokio::test]
+    async fn test_clear_key_is_exact_and_takes_variants() {
+        let store = CacheStore::new(CacheHandle::new(), 10);
+        let response = CachedResponse {
+            body: vec![1].into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        for key in [
+            "GET:/api/*",
+            "GET:/api/*#vary=0123456789abcdef",
+            "GET:/api/a",
+            "GET:/api/*?page=2",
+        ] {
+            store.set(key.to_string(), response.clone()).await;
+        }
+        store
+            .set_404(
+                "GET:/api/*".to_string(),
+                CachedResponse {
+                    status: 404,
+                    ..response
+                },
+            )
+            .await;
+
+        store.clear_key("GET:/api/*").await;
+        assert_eq!(store.size_404().await, 0);
+        assert!(store.get("GET:/api/*").await.is_none());
+        assert!(store
+            .get("GET:/api/*#vary=0123456789abcdef")
+            .await
+            .is_none());
+        assert!(store.get("GET:/api/a").await.is_some());
+        assert!(store.get("GET:/api/*?page=2").await.is_some());
+        assert_eq!(store.recent_purges()[0].removed, 3);
+    }
+
+    #[tokio::test]
+    async fn test_clear_key_finds_variants_in_every_store() {
+        let store =
+            CacheStore::new(CacheHandle::new(), 10).with_error_store(10, Duration::from_secs(60));
+        let response = |status| CachedResponse {
+            body: vec![1].into(),
+            headers: Vec::new(),
+            status,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        store
+            .set("GET:/a#vary=0000000000000000".to_string(), response(200))
+            .await;
+        store
+            .set_404("GET:/a#vary=1111111111111111".to_string(), response(404))
+            .await;
+        store
+            .set_error("GET:/a#vary=2222222222222222".to_string(), response(503))
+            .await;
+        store
+            .set("GET:/ab#vary=3333333333333333".to_string(), response(200))
+            .await;
+
+        assert_eq!(store.clear_key("GET:/a").await, 3);
+        assert_eq!(store.size_404().await, 0);
+        assert_eq!(store.size_error().await, 0);
+        assert_eq!(store.size().await, 1);
+        // A purge forgets the variants it removed.
+        assert_eq!(store.clear_key("GET:/a").await, 0);
+        store.clear_by_pattern("GET:/ab").await;
+        assert!(store.variants.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_variant_index_shrinks_on_eviction_and_expiry() {
+        let response = |status| CachedResponse {
+            body: vec![0; 100].into(),
+            headers: Vec::new(),
+            status,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        let variant = |i: usize| format!("GET:/a#vary={i:016x}");
+        let indexed = |store: &CacheStore| store.variants.get("GET:/a").map_or(0, |set| set.len());
+
+        // Budget eviction in the main cache.
+        let probe = CacheStore::new(CacheHandle::new(), 0);
+        probe.set(variant(0), response(200)).await;
+        let entry_bytes = probe.memory_usage().await;
+        let store = CacheStore::new(CacheHandle::new(), 2).with_max_bytes(entry_bytes * 3);
+        for i in 0..50 {
+            store.set(variant(i), response(200)).await;
+        }
+        assert_eq!(store.size().await, 3);
+        assert_eq!(indexed(&store), 3);
+
+        // FIFO eviction in the 404 store.
+        for i in 0..50 {
+            store.set_404(variant(100 + i), response(404)).await;
+        }
+        assert_eq!(store.size_404().await, 2);
+        assert_eq!(indexed(&store), 5);
+
+        // Expiry of a main-cache entry.
+        store
+            .set_with_ttl(variant(200), response(200), Duration::from_millis(10))
+            .await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(store.get(&variant(200)).await.is_none());
+        assert_eq!(store.size().await, 2);
+        assert_eq!(indexed(&store), 4);
+
+        // A variant held by two stores is indexed once and leaves with both.
+        store.set_404(variant(49), response(404)).await;
+        assert_eq!(indexed(&store), 3);
+        store.remove(&variant(48)).await;
+        assert_eq!(indexed(&store), 2);
+        store.clear_by_pattern("GET:/a#vary=0000000000000031").await;
+        assert_eq!(indexed(&store), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes_evicts_least_recently_used() {
+        let response = |fill: u8| CachedResponse {
+            body: vec![fill; 1000].into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        let probe = CacheStore::new(CacheHandle::new(), 0);
+        probe.set("GET:/a".to_string(), response(0)).await;
+        let entry_bytes = probe.memory_usage().await;
+
+        let store = CacheStore::new(CacheHandle::new(), 0).with_max_bytes(entry_bytes * 2 + 10);
+        store.set("GET:/a".to_string(), response(1)).await;
+        store.set("GET:/b".to_string(), response(2)).await;
+        // Serving /a makes /b the least recently used.
+        assert!(store.get("GET:/a").await.is_some());
+        store.set("GET:/c".to_string(), response(3)).await;
+
+        assert!(store.get("GET:/b").await.is_none());
+        assert!(store.get("GET:/a").await.is_some());
+        assert!(store.get("GET:/c").await.is_some());
+        assert_eq!(store.memory_usage().await, entry_bytes * 2);
+        assert_eq!(store.budget_evictions(), 1);
+
+        // Larger than the whole budget: not stored, and the old entry is gone.
+        let mut huge = response(4);
+        huge.body = vec![4; entry_bytes * 3].into();
+        store.set("GET:/c".to_string(), huge).await;
+        assert!(store.get("GET:/c").await.is_none());
+        assert_eq!(store.size().await, 1);
+        assert_eq!(store.memory_usage().await, entry_bytes);
+
+        store.clear_by_pattern("GET:/a").await;
+        assert_eq!(store.memory_usage().await, 0);
+        store.set("GET:/d".to_string(), response(5)).await;
+        store.clear().await;
+        assert_eq!(store.memory_usage().await, 0);
+    }
+
     #[tokio::test]
     async fn test_filesystem_cache_round_trip() {
         let cache_dir = unique_test_directory("round-trip");
@@ -733,10 +2568,13 @@ mod tests {
             CacheStore::with_storage(trigger, 10, CacheStorageMode::Filesystem, Some(cache_dir));
 
         let response = CachedResponse {
-            body: vec![1, 2, 3, 4],
-            headers: HashMap::from([("content-type".to_string(), "text/plain".to_string())]),
+            body: vec![1, 2, 3, 4].into(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
             status: 200,
             content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
         };
 
         store
@@ -759,6 +2597,67 @@ mod tests {
         assert!(tokio::fs::metadata(&stored_path).await.is_err());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_set_with_ttl_expires_entry() {
+        let store = CacheStore::new(CacheHandle::new(), 0);
+        let response = CachedResponse {
+            body: b"answer".to_vec().into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        store
+            .set_with_ttl(
+                "POST:/graphql#body=a".to_string(),
+                response.clone(),
+                Duration::from_secs(5),
+            )
+            .await;
+        store.set("GET:/page".to_string(), response).await;
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(store.get("POST:/graphql#body=a").await.is_some());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(store.get("POST:/graphql#body=a").await.is_none());
+        assert!(store.get("GET:/page").await.is_some());
+        assert_eq!(store.size().await, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_retention_is_bounded() {
+        let store = CacheStore::new(CacheHandle::new(), 0)
+            .with_stale_retention(Some(Duration::from_secs(10)));
+        let response = CachedResponse {
+            body: b"answer".to_vec().into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        for key in ["GET:/read", "GET:/stale"] {
+            store
+                .set_with_ttl(key.to_string(), response.clone(), Duration::from_secs(5))
+                .await;
+        }
+
+        // Expired but retained: no longer a hit, still a stale copy.
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(store.get("GET:/read").await.is_none());
+        assert!(store.try_get_stale("GET:/stale").await.unwrap().is_some());
+        assert_eq!(store.size().await, 2);
+
+        // Past the retention too: dropped on either lookup.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(store.get("GET:/read").await.is_none());
+        assert!(store.try_get_stale("GET:/stale").await.unwrap().is_none());
+        assert_eq!(store.size().await, 0);
+    }
+
     #[tokio::test]
     async fn test_filesystem_404_eviction_removes_body_file() {
         let cache_dir = unique_test_directory("eviction");
@@ -771,10 +2670,13 @@ mod tests {
                 .set_404(
                     format!("GET:/missing{}", index),
                     CachedResponse {
-                        body: vec![index as u8],
-                        headers: HashMap::new(),
+                        body: vec![index as u8].into(),
+                        headers: Vec::new(),
                         status: 404,
                         content_encoding: None,
+                        origin: None,
+                        content_digest: None,
+                        stored_at: None,
                     },
                 )
                 .await;
@@ -791,10 +2693,13 @@ mod tests {
             .set_404(
                 "GET:/missing3".to_string(),
                 CachedResponse {
-                    body: vec![3],
-                    headers: HashMap::new(),
+                    body: vec![3].into(),
+                    headers: Vec::new(),
                     status: 404,
                     content_encoding: None,
+                    origin: None,
+                    content_digest: None,
+                    stored_at: None,
                 },
             )
             .await;
@@ -814,10 +2719,13 @@ mod tests {
             .set(
                 "GET:/api/one".to_string(),
                 CachedResponse {
-                    body: vec![1],
-                    headers: HashMap::new(),
+                    body: vec![1].into(),
+                    headers: Vec::new(),
                     status: 200,
                     content_encoding: None,
+                    origin: None,
+                    content_digest: None,
+                    stored_at: None,
                 },
             )
             .await;
@@ -825,10 +2733,13 @@ mod tests {
             .set(
                 "GET:/other/two".to_string(),
                 CachedResponse {
-                    body: vec![2],
-                    headers: HashMap::new(),
+                    body: vec![2].into(),
+                    headers: Vec::new(),
                     status: 200,
                     content_encoding: None,
+                    origin: None,
+                    content_digest: None,
+                    stored_at: None,
                 },
             )
             .await;
@@ -0,0 +1,163 @@
+//! Backend `Cache-Control` directives (RFC 9111 §5.2.2), read the way a
+//! shared cache reads them.
+//!
+//! Only the directives that decide whether and for how long a response may be
+//! stored are kept. Unknown directives are ignored. The field-name lists of
+//! qualified `private="…"` and `no-cache="…"` are not honoured per field: the
+//! directive applies to the whole response, which is the cautious reading.
+
+use std::time::Duration;
+
+/// Delta-seconds values too large to represent are capped here (RFC 9111 §1.2.2).
+pub(crate) const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+/// The storage-related directives of a response.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    /// Parse every `Cache-Control` field line of a response.
+    ///
+    /// A `max-age` or `s-maxage` whose value is not a number, or that is
+    /// given twice with different values, counts as `0`: the response is
+    /// treated as stale, as RFC 9111 §4.2.1 recommends.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut directives = Self::default();
+        for value in values {
+            for directive in split_directives(value) {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => (name.trim(), Some(unquote(argument.trim()))),
+                    None => (directive, None),
+                };
+                if name.eq_ignore_ascii_case("no-store") {
+                    directives.no_store = true;
+                } else if name.eq_ignore_ascii_case("no-cache") {
+                    directives.no_cache = true;
+                } else if name.eq_ignore_ascii_case("private") {
+                    directives.private = true;
+                } else if name.eq_ignore_ascii_case("max-age") {
+                    merge_seconds(&mut directives.max_age, argument);
+                } else if name.eq_ignore_ascii_case("s-maxage") {
+                    merge_seconds(&mut directives.s_maxage, argument);
+                }
+            }
+        }
+        directives
+    }
+
+    /// Whether a shared cache may store the response at all.
+    pub fn allows_store(&self) -> bool {
+        !self.no_store && !self.private
+    }
+
+    /// How long a stored response may be served without going back to the
+    /// backend, when the directives say. `no-cache` means not at all;
+    /// otherwise `s-maxage` takes precedence over `max-age`.
+    pub fn freshness(&self) -> Option<Duration> {
+        if self.no_cache {
+            return Some(Duration::ZERO);
+        }
+        self.s_maxage.or(self.max_age).map(Duration::from_secs)
+    }
+}
+
+/// Split a field value on commas outside quoted strings, dropping empty
+/// elements.
+fn split_directives(value: &str) -> impl Iterator<Item = &str> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    value
+        .split(move |c: char| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_quotes => escaped = true,
+                '"' => in_quotes = !in_quotes,
+                ',' if !in_quotes => return true,
+                _ => {}
+            }
+            false
+        })
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+}
+
+fn unquote(argument: &str) -> &str {
+    argument
+        .strip_prefix('"')
+        .and_then(|argument| argument.strip_suffix('"'))
+        .unwrap_or(argument)
+}
+
+fn merge_seconds(slot: &mut Option<u64>, argument: Option<&str>) {
+    let seconds = argument
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+        .map(|digits| {
+            digits
+                .parse()
+                .map_or(MAX_DELTA_SECONDS, |s: u64| s.min(MAX_DELTA_SECONDS))
+        })
+        .unwrap_or(0);
+    *slot = match *slot {
+        Some(previous) if previous != seconds => Some(0),
+        _ => Some(seconds),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> CacheControl {
+        CacheControl::parse([value])
+    }
+
+    #[test]
+    fn test_combined_directives() {
+        let directives = parse("public, max-age=60");
+        assert!(directives.allows_store());
+        assert_eq!(directives.freshness(), Some(Duration::from_secs(60)));
+
+        let directives = parse("Max-Age=60 , S-MAXAGE=\"300\", must-revalidate");
+        assert_eq!(directives.freshness(), Some(Duration::from_secs(300)));
+
+        assert!(!parse("public, no-store").allows_store());
+        assert!(!parse("private, max-age=60").allows_store());
+
+        let directives = parse("no-cache, max-age=60");
+        assert!(directives.allows_store());
+        assert_eq!(directives.freshness(), Some(Duration::ZERO));
+
+        // Directives may be spread over several field lines.
+        let directives = CacheControl::parse(["public", "max-age=5", "no-store"]);
+        assert!(!directives.allows_store());
+        assert_eq!(directives.max_age, Some(5));
+
+        assert_eq!(parse("public").freshness(), None);
+        assert_eq!(parse(""), CacheControl::default());
+    }
+
+    #[test]
+    fn test_malformed_values() {
+        assert_eq!(parse("max-age=abc").max_age, Some(0));
+        assert_eq!(parse("max-age").max_age, Some(0));
+        assert_eq!(parse("max-age=-5").max_age, Some(0));
+        assert_eq!(parse("max-age=1.5").max_age, Some(0));
+        assert_eq!(parse("max-age=60, max-age=120").max_age, Some(0));
+        assert_eq!(parse("max-age=60, max-age=60").max_age, Some(60));
+        assert_eq!(
+            parse("max-age=99999999999999999999999").max_age,
+            Some(MAX_DELTA_SECONDS)
+        );
+        // Commas inside a quoted field list do not split directives.
+        let directives = parse("private=\"set-cookie, x-user\", max-age=10");
+        assert!(directives.private);
+        assert_eq!(directives.max_age, Some(10));
+        assert_eq!(parse(",, ,max-age=7,").max_age, Some(7));
+    }
+}
@@ -0,0 +1,224 @@
+//! Per-path cache rules: whether matching requests are cached, for how
+//! long, and which request headers split their cache entries.
+//!
+//! Rules are tried in order and the first whose pattern matches decides.
+//! Requests no rule matches fall back to `include_paths` / `exclude_paths`.
+//! A rule's TTL caps how long an entry is served; a shorter `max-age` from
+//! the backend still wins when `respect_cache_control` is on.
+//!
+//! Header values join the cache key after [`KEY_MARKER`], from the server's
+//! `cache_key_headers` first and then from the matching rule's
+//! `key_headers`: `GET:/page#headers=accept-language=en&x-tenant=acme`.
+
+use crate::path_matcher::{PathPattern, WildcardSyntax};
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Separates a cache key from the request header values added to it by
+/// `cache_key_headers` or a rule's `key_headers`.
+pub const KEY_MARKER: &str = "#headers=";
+
+/// Bytes of each header value kept in a cache key; longer values are cut,
+/// so a client cannot grow keys without bound.
+pub const MAX_KEY_HEADER_VALUE_LEN: usize = 256;
+
+/// One entry of `cache_rules`, as written in configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CacheRule {
+    /// Request pattern, same syntax as `include_paths`, e.g. `"/api/*"`.
+    pub pattern: String,
+    /// How long entries stored under this rule are served. Absent means
+    /// until they are purged or evicted, as without a rule.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Whether matching requests are cached at all (default: true).
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+    /// Request headers whose values become part of the cache key, so e.g.
+    /// each `X-Tenant` gets its own entry.
+    #[serde(default)]
+    pub key_headers: Vec<String>,
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+impl CacheRule {
+    /// A rule caching requests matching `pattern` without a TTL.
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            ttl_secs: None,
+            cache: true,
+            key_headers: vec![],
+        }
+    }
+
+    /// A rule keeping requests matching `pattern` out of the cache.
+    pub fn never(pattern: impl Into<String>) -> Self {
+        Self {
+            cache: false,
+            ..Self::new(pattern)
+        }
+    }
+
+    pub fn with_ttl_secs(mut self, ttl_secs: u64) -> Self {
+        self.ttl_secs = Some(ttl_secs);
+        self
+    }
+
+    pub fn with_key_headers(mut self, headers: Vec<String>) -> Self {
+        self.key_headers = headers;
+        self
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl_secs.map(Duration::from_secs)
+    }
+
+    /// The cache key component for `key_headers`, or `None` without any.
+    /// A missing header counts as empty.
+    pub fn key_component(&self, headers: &HeaderMap) -> Option<String> {
+        headers_key_component(&self.key_headers, headers)
+    }
+}
+
+/// `name=value` pairs for `names`, joined with `&`, or `None` without any
+/// names. Names are lowercased, a missing header counts as empty, several
+/// field lines are joined with `,`, and each value is cut to
+/// [`MAX_KEY_HEADER_VALUE_LEN`] bytes.
+pub fn headers_key_component(names: &[String], headers: &HeaderMap) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    let values: Vec<String> = names
+        .iter()
+        .map(|name| {
+            let mut value = headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            if value.len() > MAX_KEY_HEADER_VALUE_LEN {
+                // `to_str` only accepts visible ASCII, so any byte is a
+                // character boundary.
+                value.truncate(MAX_KEY_HEADER_VALUE_LEN);
+            }
+            format!("{}={}", name.to_ascii_lowercase(), value)
+        })
+        .collect();
+    Some(values.join("&"))
+}
+
+/// Append a [`headers_key_component`] to `key`, after the header values
+/// already in it if there are any.
+pub(crate) fn append_key_component(key: &mut String, component: &str) {
+    key.push_str(if key.contains(KEY_MARKER) {
+        "&"
+    } else {
+        KEY_MARKER
+    });
+    key.push_str(component);
+}
+
+/// `key` without the request header values, if it has any.
+pub fn base_key(key: &str) -> &str {
+    key.split_once(KEY_MARKER).map_or(key, |(base, _)| base)
+}
+
+/// [`CacheRule`]s with their patterns parsed once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CacheRules(Vec<(PathPattern, CacheRule)>);
+
+impl CacheRules {
+    pub(crate) fn new(rules: &[CacheRule], syntax: WildcardSyntax) -> Self {
+        Self(
+            rules
+                .iter()
+                .map(|rule| {
+                    (
+                        PathPattern::with_syntax(&rule.pattern, syntax),
+                        rule.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// The first rule matching the request.
+    pub(crate) fn find(&self, method: &str, path: &str, query: &str) -> Option<&CacheRule> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.matches_request(Some(method), path, query))
+            .map(|(_, rule)| rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let rules = CacheRules::new(
+            &[
+                CacheRule::never("/api/private/*"),
+                CacheRule::new("/api/*").with_ttl_secs(30),
+                CacheRule::new("GET /static/*").with_ttl_secs(86_400),
+                CacheRule::new("/api/*").with_ttl_secs(5),
+            ],
+            WildcardSyntax::Legacy,
+        );
+
+        let private = rules.find("GET", "/api/private/me", "").unwrap();
+        assert!(!private.cache);
+        let api = rules.find("GET", "/api/users", "").unwrap();
+        assert_eq!(
+            (api.cache, api.ttl()),
+            (true, Some(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            rules.find("GET", "/static/app.js", "").unwrap().ttl_secs,
+            Some(86_400)
+        );
+        assert!(rules.find("POST", "/static/app.js", "").is_none());
+        assert!(rules.find("GET", "/about", "").is_none());
+    }
+
+    #[test]
+    fn test_key_component_from_headers() {
+        let rule = CacheRule::new("/*")
+            .with_key_headers(vec!["X-Tenant".to_string(), "Accept-Language".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", HeaderValue::from_static("acme"));
+
+        assert_eq!(
+            rule.key_component(&headers).as_deref(),
+            Some("x-tenant=acme&accept-language=")
+        );
+        assert_eq!(CacheRule::new("/*").key_component(&headers), None);
+    }
+
+    #[test]
+    fn test_key_component_values_are_capped() {
+        let mut headers = HeaderMap::new();
+        let long = "a".repeat(MAX_KEY_HEADER_VALUE_LEN + 10);
+        headers.insert("x-tenant", HeaderValue::from_str(&long).unwrap());
+        let component = headers_key_component(&["x-tenant".to_string()], &headers).unwrap();
+        assert_eq!(
+            component.len(),
+            "x-tenant=".len() + MAX_KEY_HEADER_VALUE_LEN
+        );
+
+        let mut key = "GET:/page".to_string();
+        append_key_component(&mut key, "accept-language=en");
+        append_key_component(&mut key, "x-tenant=acme");
+        assert_eq!(key, "GET:/page#headers=accept-language=en&x-tenant=acme");
+        assert_eq!(base_key(&key), "GET:/page");
+        assert_eq!(base_key("GET:/page?q=1"), "GET:/page?q=1");
+    }
+}
@@ -0,0 +1,393 @@
+//! Recorded backend interactions for offline replay (`cassette` feature).
+//!
+//! A cassette is a JSON Lines file with one request/response pair per line.
+//! In record mode the proxy forwards requests as usual and appends every
+//! backend exchange to the file. In replay mode it never contacts the
+//! backend: responses come from the cassette, and a request with no recorded
+//! interaction fails with `502 Bad Gateway`. This makes it possible to
+//! capture real traffic once and check a configuration (cache key function,
+//! include/exclude rules, normalization) against it in tests.
+//!
+//! Recorded interactions go through the proxy's [`Redactor`]: sensitive
+//! query parameters and header values are stored as `[redacted]`. Replay
+//! matches on method and redacted target, so a request still finds its
+//! recording when only a redacted value differs.
+//!
+//! Requests sent on the cache-miss path are covered. Snapshot warm-up in
+//! PreGenerate mode and upgrade tunnels always go to the network.
+
+use crate::redact::{Redactor, REDACTED};
+use anyhow::{Context, Result};
+use axum::http::HeaderMap;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+/// How a cassette-enabled server uses its cassette file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CassetteMode {
+    /// Forward to the backend and append each exchange to the file.
+    Record,
+    /// Answer from the file without contacting the backend.
+    Replay,
+}
+
+/// A `[server.NAME.cassette]` block.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    pub path: PathBuf,
+}
+
+/// One recorded request and the backend's response to it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path and query as sent to the backend.
+    pub target: String,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// The body exactly as received, still in its `content-encoding`.
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+/// A body stored as text when it is UTF-8 and as base64 otherwise.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RecordedBody {
+    #[serde(default)]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "BodyEncoding::is_utf8")]
+    pub body_encoding: BodyEncoding,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl BodyEncoding {
+    fn is_utf8(&self) -> bool {
+        *self == BodyEncoding::Utf8
+    }
+}
+
+impl RecordedBody {
+    pub fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self {
+                body: text.to_string(),
+                body_encoding: BodyEncoding::Utf8,
+            },
+            Err(_) => Self {
+                body: base64::engine::general_purpose::STANDARD.encode(bytes),
+                body_encoding: BodyEncoding::Base64,
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        match self.body_encoding {
+            BodyEncoding::Utf8 => Ok(self.body.clone().into_bytes()),
+            BodyEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(&self.body)
+                .context("invalid base64 body in cassette"),
+        }
+    }
+}
+
+/// A cassette opened for recording or replay. Cheap to clone.
+#[derive(Clone)]
+pub struct Cassette {
+    inner: Arc<Inner>,
+}
+
+enum Inner {
+    Record {
+        path: PathBuf,
+        file: tokio::sync::Mutex<tokio::fs::File>,
+    },
+    Replay {
+        path: PathBuf,
+        /// Interactions grouped by `method target`, in recorded order.
+        interactions: HashMap<String, Vec<RecordedResponse>>,
+        /// How many times each key has been replayed.
+        served: Mutex<HashMap<String, usize>>,
+    },
+}
+
+impl Cassette {
+    /// Open `path` for recording, creating it if needed. New interactions
+    /// are appended to what is already there.
+    pub async fn record(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to open cassette '{}'", path.display()))?;
+        Ok(Self {
+            inner: Arc::new(Inner::Record {
+                path,
+                file: tokio::sync::Mutex::new(file),
+            }),
+        })
+    }
+
+    /// Load `path` for replay.
+    pub fn replay(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read cassette '{}'", path.display()))?;
+        let interactions =
+            parse(&content).with_context(|| format!("invalid cassette '{}'", path.display()))?;
+        Ok(Self::from_interactions(path, interactions))
+    }
+
+    fn from_interactions(path: PathBuf, interactions: Vec<Interaction>) -> Self {
+        let mut grouped: HashMap<String, Vec<RecordedResponse>> = HashMap::new();
+        for interaction in interactions {
+            grouped
+                .entry(replay_key(
+                    &interaction.request.method,
+                    &interaction.request.target,
+                ))
+                .or_default()
+                .push(interaction.response);
+        }
+        Self {
+            inner: Arc::new(Inner::Replay {
+                path,
+                interactions: grouped,
+                served: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Open a cassette as described by a config block.
+    pub async fn open(config: &CassetteConfig) -> Result<Self> {
+        match config.mode {
+            CassetteMode::Record => Self::record(&config.path).await,
+            CassetteMode::Replay => Self::replay(&config.path),
+        }
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        match *self.inner {
+            Inner::Record { .. } => CassetteMode::Record,
+            Inner::Replay { .. } => CassetteMode::Replay,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match &*self.inner {
+            Inner::Record { path, .. } | Inner::Replay { path, .. } => path,
+        }
+    }
+
+    /// The recorded response for `method target` (already redacted), or
+    /// `None` when there is none. Repeated requests get the recorded
+    /// responses in order; once they run out the last one is repeated.
+    pub(crate) fn replay_response(&self, method: &str, target: &str) -> Option<RecordedResponse> {
+        let Inner::Replay {
+            interactions,
+            served,
+            ..
+        } = &*self.inner
+        else {
+            return None;
+        };
+        let key = replay_key(method, target);
+        let responses = interactions.get(&key)?;
+        let mut served = served.lock().unwrap();
+        let count = served.entry(key).or_insert(0);
+        let response = responses[(*count).min(responses.len() - 1)].clone();
+        *count += 1;
+        Some(response)
+    }
+
+    /// Append `interaction` when recording; a no-op in replay mode.
+    pub(crate) async fn record_interaction(&self, interaction: &Interaction) -> Result<()> {
+        let Inner::Record { file, .. } = &*self.inner else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(interaction)?;
+        line.push(b'\n');
+        let mut file = file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+fn replay_key(method: &str, target: &str) -> String {
+    format!("{} {}", method, target)
+}
+
+/// Parse a cassette file: one [`Interaction`] per non-empty line.
+pub fn parse(content: &str) -> Result<Vec<Interaction>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).with_context(|| format!("line {}", index + 1))
+        })
+        .collect()
+}
+
+/// `headers` as name/value pairs with sensitive values replaced.
+pub(crate) fn redact_headers(
+    redactor: &Redactor,
+    headers: impl IntoIterator<Item = (String, String)>,
+) -> Vec<(String, String)> {
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            if redactor.is_sensitive_header(&name) {
+                (name, REDACTED.to_string())
+            } else {
+                (name, value)
+            }
+        })
+        .collect()
+}
+
+/// Name/value pairs of a header map, skipping non-UTF-8 values.
+pub(crate) fn header_pairs<'a>(
+    headers: impl IntoIterator<Item = (&'a axum::http::HeaderName, &'a axum::http::HeaderValue)>,
+) -> Vec<(String, String)> {
+    headers
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// A recorded header list as a header map, skipping invalid entries.
+pub(crate) fn to_header_map(headers: &[(String, String)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            axum::http::HeaderName::from_bytes(name.as_bytes()),
+            axum::http::HeaderValue::from_str(value),
+        ) {
+            map.append(name, value);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interaction(target: &str, body: &[u8]) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: "GET".to_string(),
+                target: target.to_string(),
+                headers: vec![],
+                body: RecordedBody::default(),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/html".to_string())],
+                body: RecordedBody::new(body),
+            },
+        }
+    }
+
+    #[test]
+    fn test_bodies_round_trip_as_text_or_base64() {
+        let text = RecordedBody::new(b"<p>hi</p>");
+        assert_eq!(text.body_encoding, BodyEncoding::Utf8);
+        assert_eq!(text.to_bytes().unwrap(), b"<p>hi</p>");
+
+        let binary = RecordedBody::new(&[0x1f, 0x8b, 0xff]);
+        assert_eq!(binary.body_encoding, BodyEncoding::Base64);
+        assert_eq!(binary.to_bytes().unwrap(), vec![0x1f, 0x8b, 0xff]);
+
+        let line = serde_json::to_string(&interaction("/a", b"ok")).unwrap();
+        assert!(!line.contains("body_encoding"), "{}", line);
+        assert_eq!(parse(&line).unwrap(), vec![interaction("/a", b"ok")]);
+    }
+
+    #[test]
+    fn test_replay_serves_in_order_then_repeats_last() {
+        let cassette = Cassette::from_interactions(
+            PathBuf::from("memory"),
+            vec![
+                interaction("/a", b"first"),
+                interaction("/b", b"other"),
+                interaction("/a", b"second"),
+            ],
+        );
+        let body = |target: &str| {
+            cassette
+                .replay_response("GET", target)
+                .map(|response| response.body.to_bytes().unwrap())
+        };
+        assert_eq!(body("/a").unwrap(), b"first");
+        assert_eq!(body("/a").unwrap(), b"second");
+        assert_eq!(body("/a").unwrap(), b"second");
+        assert_eq!(body("/b").unwrap(), b"other");
+        assert!(body("/missing").is_none());
+        assert!(cassette.replay_response("POST", "/a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_record_appends_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "phantom-frame-cassette-{}.jsonl",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+
+        let cassette = Cassette::record(&path).await.unwrap();
+        assert_eq!(cassette.mode(), CassetteMode::Record);
+        cassette
+            .record_interaction(&interaction("/a", b"one"))
+            .await
+            .unwrap();
+        cassette
+            .record_interaction(&interaction("/b", &[0xff]))
+            .await
+            .unwrap();
+
+        let replay = Cassette::replay(&path).unwrap();
+        assert_eq!(replay.mode(), CassetteMode::Replay);
+        assert_eq!(
+            replay
+                .replay_response("GET", "/b")
+                .unwrap()
+                .body
+                .to_bytes()
+                .unwrap(),
+            vec![0xff]
+        );
+        std::fs::remove_file(&path).ok();
+    }
+}
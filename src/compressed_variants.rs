@@ -0,0 +1,199 @@
+//! Bodies compressed at serve time, kept for the next client asking for the
+//! same encoding.
+//!
+//! With `compress_on_serve`, a client that does not accept a cached entry's
+//! stored encoding gets the body compressed into one it prefers. Compressing
+//! costs far more than serving, so the result is kept here, keyed by cache
+//! key and encoding, up to a byte budget; the oldest are dropped first. Each
+//! one remembers the entry it was made from and is only reused for that
+//! entry, so a replaced or refreshed entry is compressed afresh.
+
+use crate::cache::CachedResponse;
+use crate::compression::ContentEncoding;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Default for `compress_variants_max_bytes`: 64 MiB.
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Identifies the cached entry a variant was compressed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Source {
+    stored_at: Option<SystemTime>,
+    etag: Option<String>,
+    len: usize,
+    encoding: Option<ContentEncoding>,
+}
+
+impl Source {
+    fn of(cached: &CachedResponse) -> Self {
+        Self {
+            stored_at: cached.stored_at,
+            etag: cached.header("etag").map(str::to_string),
+            len: cached.body.len(),
+            encoding: cached.content_encoding,
+        }
+    }
+}
+
+struct Variant {
+    source: Source,
+    body: Bytes,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct Variants {
+    entries: HashMap<(String, ContentEncoding), Variant>,
+    /// Insertion order, for eviction. Entries replaced since are skipped by
+    /// their generation.
+    order: VecDeque<((String, ContentEncoding), u64)>,
+    bytes: usize,
+    generation: u64,
+}
+
+/// Compressed bodies, at most `max_bytes` of them.
+pub struct CompressedVariants {
+    max_bytes: usize,
+    variants: Mutex<Variants>,
+}
+
+impl CompressedVariants {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            variants: Mutex::new(Variants::default()),
+        }
+    }
+
+    /// The body of `cached`, stored under `key`, compressed with `encoding`,
+    /// if it was kept.
+    pub fn get(
+        &self,
+        key: &str,
+        encoding: ContentEncoding,
+        cached: &CachedResponse,
+    ) -> Option<Bytes> {
+        let variants = self.variants.lock().unwrap();
+        let variant = variants.entries.get(&(key.to_string(), encoding))?;
+        (variant.source == Source::of(cached)).then(|| variant.body.clone())
+    }
+
+    /// Keep `body`, the body of `cached` compressed with `encoding`. A body
+    /// larger than the whole budget is not kept.
+    pub fn insert(
+        &self,
+        key: &str,
+        encoding: ContentEncoding,
+        cached: &CachedResponse,
+        body: Bytes,
+    ) {
+        if body.len() > self.max_bytes {
+            return;
+        }
+        let mut variants = self.variants.lock().unwrap();
+        variants.generation += 1;
+        let generation = variants.generation;
+        let id = (key.to_string(), encoding);
+        variants.bytes += body.len();
+        let variant = Variant {
+            source: Source::of(cached),
+            body,
+            generation,
+        };
+        if let Some(replaced) = variants.entries.insert(id.clone(), variant) {
+            variants.bytes -= replaced.body.len();
+        }
+        variants.order.push_back((id, generation));
+        if variants.order.len() > 2 * variants.entries.len() + 16 {
+            let Variants { entries, order, .. } = &mut *variants;
+            order.retain(|(id, generation)| {
+                entries
+                    .get(id)
+                    .is_some_and(|variant| variant.generation == *generation)
+            });
+        }
+
+        while variants.bytes > self.max_bytes {
+            let Some((id, generation)) = variants.order.pop_front() else {
+                break;
+            };
+            if variants
+                .entries
+                .get(&id)
+                .is_some_and(|variant| variant.generation == generation)
+            {
+                let evicted = variants.entries.remove(&id).unwrap();
+                variants.bytes -= evicted.body.len();
+            }
+        }
+    }
+
+    /// Bytes held.
+    pub fn size_bytes(&self) -> usize {
+        self.variants.lock().unwrap().bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str) -> CachedResponse {
+        CachedResponse {
+            body: body.as_bytes().to_vec().into(),
+            headers: vec![("etag".to_string(), format!("\"{}\"", body))],
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: Some(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    #[test]
+    fn test_variants_are_reused_only_for_their_entry() {
+        let variants = CompressedVariants::new(1024);
+        let page = entry("page");
+        variants.insert("GET:/", ContentEncoding::Gzip, &page, Bytes::from("gz"));
+
+        assert_eq!(
+            variants.get("GET:/", ContentEncoding::Gzip, &page),
+            Some(Bytes::from("gz"))
+        );
+        assert_eq!(variants.get("GET:/", ContentEncoding::Brotli, &page), None);
+        assert_eq!(
+            variants.get("GET:/other", ContentEncoding::Gzip, &page),
+            None
+        );
+        assert_eq!(
+            variants.get("GET:/", ContentEncoding::Gzip, &entry("new page")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_oldest_variants_are_dropped_past_the_budget() {
+        let variants = CompressedVariants::new(10);
+        let page = entry("page");
+        for key in ["a", "b", "c"] {
+            variants.insert(key, ContentEncoding::Gzip, &page, Bytes::from("1234"));
+        }
+        assert_eq!(variants.size_bytes(), 8);
+        assert_eq!(variants.get("a", ContentEncoding::Gzip, &page), None);
+        assert!(variants.get("c", ContentEncoding::Gzip, &page).is_some());
+
+        // Replacing a variant does not count it twice.
+        variants.insert("c", ContentEncoding::Gzip, &page, Bytes::from("12"));
+        assert_eq!(variants.size_bytes(), 6);
+        variants.insert(
+            "big",
+            ContentEncoding::Gzip,
+            &page,
+            Bytes::from("12345678901"),
+        );
+        assert_eq!(variants.size_bytes(), 6);
+    }
+}
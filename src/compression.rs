@@ -10,7 +10,7 @@ use flate2::{
 use std::io::{Read, Write};
 use tokio::task;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ContentEncoding {
     Brotli,
     Gzip,
@@ -36,6 +36,64 @@ impl ContentEncoding {
     }
 }
 
+/// Content types compressed by default: text, and the structured formats
+/// that compress like it. Images other than SVG, video, and archives are
+/// compressed already.
+pub const DEFAULT_COMPRESS_CONTENT_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/manifest+json",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// [`DEFAULT_COMPRESS_CONTENT_TYPES`] as owned strings, for config defaults.
+pub fn default_compress_content_types() -> Vec<String> {
+    DEFAULT_COMPRESS_CONTENT_TYPES
+        .iter()
+        .map(|content_type| content_type.to_string())
+        .collect()
+}
+
+/// Which bodies are compressed, at store time and, with `compress_on_serve`,
+/// at serve time.
+#[derive(Clone, Debug)]
+pub(crate) struct CompressionPolicy {
+    strategy: CompressStrategy,
+    content_types: Vec<String>,
+    min_bytes: usize,
+}
+
+impl CompressionPolicy {
+    pub(crate) fn from_config(config: &crate::CreateProxyConfig) -> Self {
+        Self {
+            strategy: config.compress_strategy.clone(),
+            content_types: config.compress_content_types.clone(),
+            min_bytes: config.compress_min_bytes,
+        }
+    }
+
+    /// Whether a body of `len` bytes and `content_type` is worth compressing.
+    pub(crate) fn compressible(&self, content_type: Option<&str>, len: usize) -> bool {
+        len >= self.min_bytes
+            && crate::transform::content_type_matches(&self.content_types, content_type)
+    }
+
+    /// The encoding to store a decoded body in, if any.
+    pub(crate) fn store_encoding(
+        &self,
+        content_type: Option<&str>,
+        len: usize,
+    ) -> Option<ContentEncoding> {
+        configured_encoding(&self.strategy).filter(|_| self.compressible(content_type, len))
+    }
+}
+
 pub fn configured_encoding(strategy: &CompressStrategy) -> Option<ContentEncoding> {
     match strategy {
         CompressStrategy::None => None,
@@ -96,8 +154,11 @@ pub fn decompress_body(body: &[u8], encoding: ContentEncoding) -> Result<Vec<u8>
     Ok(output)
 }
 
-pub async fn decompress_body_async(body: Vec<u8>, encoding: ContentEncoding) -> Result<Vec<u8>> {
-    task::spawn_blocking(move || decompress_body(&body, encoding))
+pub async fn decompress_body_async(
+    body: impl AsRef<[u8]> + Send + 'static,
+    encoding: ContentEncoding,
+) -> Result<Vec<u8>> {
+    task::spawn_blocking(move || decompress_body(body.as_ref(), encoding))
         .await
         .map_err(|error| anyhow!("decompression task failed: {}", error))?
 }
@@ -167,6 +228,44 @@ pub fn identity_acceptable(headers: &HeaderMap) -> bool {
     }
 }
 
+/// The encoding the client prefers among those the proxy can produce, or
+/// `None` when it accepts none of them. Ties go to `br`, then `gzip`.
+pub fn preferred_encoding(headers: &HeaderMap) -> Option<ContentEncoding> {
+    let value = headers
+        .get(axum::http::header::ACCEPT_ENCODING)?
+        .to_str()
+        .ok()?;
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for encoding in [
+        ContentEncoding::Brotli,
+        ContentEncoding::Gzip,
+        ContentEncoding::Deflate,
+    ] {
+        let quality = encoding_quality(value, encoding.as_header_value());
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// The part of an `Accept-Encoding` value the proxy can decode: `br`,
+/// `gzip`, `deflate`, and `identity` entries, quality values kept. A backend
+/// asked with it never answers in an encoding that would keep the response
+/// out of the cache. `None` when nothing is left, which asks for `identity`.
+pub fn decodable_accept_encoding(value: &str) -> Option<String> {
+    let kept: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| {
+            let token = item.split(';').next().unwrap_or_default().trim();
+            token.eq_ignore_ascii_case("identity")
+                || ContentEncoding::from_header_value(token).is_some()
+        })
+        .collect();
+    (!kept.is_empty()).then(|| kept.join(", "))
+}
+
 fn encoding_quality(value: &str, encoding: &str) -> f32 {
     if let Some(quality) = token_quality(value, encoding) {
         return quality;
@@ -243,6 +342,57 @@ mod tests {
         assert_eq!(decode_upstream_body(body, Some("identity")).unwrap(), body);
     }
 
+    #[test]
+    fn test_preferred_encoding() {
+        let preferred = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                axum::http::header::ACCEPT_ENCODING,
+                HeaderValue::from_str(value).unwrap(),
+            );
+            preferred_encoding(&headers)
+        };
+        assert_eq!(
+            preferred("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(preferred("gzip, br;q=0.5"), Some(ContentEncoding::Gzip));
+        assert_eq!(preferred("deflate"), Some(ContentEncoding::Deflate));
+        assert_eq!(preferred("*;q=0.2"), Some(ContentEncoding::Brotli));
+        assert_eq!(preferred("zstd, identity"), None);
+        assert_eq!(preferred("gzip;q=0"), None);
+        assert_eq!(preferred_encoding(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_compression_policy_limits_types_and_sizes() {
+        let config = crate::CreateProxyConfig::new("http://localhost".to_string())
+            .with_compress_min_bytes(100);
+        let policy = CompressionPolicy::from_config(&config);
+        assert_eq!(
+            policy.store_encoding(Some("text/html; charset=utf-8"), 100),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(policy.store_encoding(Some("text/html"), 99), None);
+        assert_eq!(policy.store_encoding(Some("image/png"), 10_000), None);
+        assert_eq!(policy.store_encoding(None, 10_000), None);
+        assert!(policy.compressible(Some("image/svg+xml"), 100));
+    }
+
+    #[test]
+    fn test_decodable_accept_encoding() {
+        assert_eq!(
+            decodable_accept_encoding("gzip, deflate, br, zstd").as_deref(),
+            Some("gzip, deflate, br")
+        );
+        assert_eq!(
+            decodable_accept_encoding("zstd;q=1.0, br;q=0.9, identity;q=0").as_deref(),
+            Some("br;q=0.9, identity;q=0")
+        );
+        assert_eq!(decodable_accept_encoding("zstd, *;q=0.1"), None);
+        assert_eq!(decodable_accept_encoding(""), None);
+    }
+
     #[test]
     fn test_client_accepts_encoding_with_q_values() {
         let mut headers = HeaderMap::new();
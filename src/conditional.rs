@@ -0,0 +1,277 @@
+//! Entity tags and `If-None-Match` evaluation (RFC 9110 §8.8.3, §13.1.2),
+//! and `If-Modified-Since` (§13.1.3).
+//!
+//! Clients holding several variants, or a validator a CDN weakened, send
+//! lists such as `W/"abc", "def"`, so comparing header strings misses
+//! legitimate matches. `If-None-Match` always uses the weak comparison: the
+//! `W/` prefix is ignored on both sides and only the opaque tags must be
+//! equal. The same parser reads the `ETag` a backend returns, so a stored
+//! validator can be compared against a client's header or a backend's `304`.
+//!
+//! Malformed values never panic. An `ETag` that does not parse is treated as
+//! absent, and an `If-None-Match` that does not parse is ignored, so the
+//! request gets a full response rather than a wrong `304`. The same goes for
+//! an `If-Modified-Since` that is not an HTTP-date.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// A parsed entity tag: `"xyzzy"` or `W/"xyzzy"`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EntityTag {
+    pub weak: bool,
+    /// The opaque tag without its quotes.
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    /// Parse a single entity tag, e.g. an `ETag` header value. Surrounding
+    /// whitespace is allowed; anything else around the tag is not.
+    pub fn parse(value: &str) -> Option<Self> {
+        match parse_entity_tag(value.trim_start())? {
+            (etag, rest) if rest.trim().is_empty() => Some(etag),
+            _ => None,
+        }
+    }
+
+    /// Strong comparison: neither tag is weak and the opaque tags are equal.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the opaque tags are equal, whatever their weakness.
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// A parsed `If-None-Match` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    /// `*`: any current representation.
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl IfNoneMatch {
+    /// Parse an `If-None-Match` value. Several header lines may be joined
+    /// with `,` beforehand. Empty list elements are skipped; any other
+    /// malformed element makes the whole header invalid.
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.trim() == "*" {
+            return Some(Self::Any);
+        }
+        let mut tags = Vec::new();
+        let mut rest = value;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || is_ows(c));
+            if rest.is_empty() {
+                break;
+            }
+            let (etag, after) = parse_entity_tag(rest)?;
+            tags.push(etag);
+            rest = after.trim_start_matches(is_ows);
+            if !rest.is_empty() && !rest.starts_with(',') {
+                return None;
+            }
+        }
+        (!tags.is_empty()).then_some(Self::Tags(tags))
+    }
+
+    /// Whether the client already has `current`, i.e. whether a `GET` or
+    /// `HEAD` should be answered with `304`. `*` matches any representation
+    /// the caller has, with or without a tag.
+    pub fn matches(&self, current: Option<&EntityTag>) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Tags(tags) => {
+                current.is_some_and(|current| tags.iter().any(|tag| tag.weak_eq(current)))
+            }
+        }
+    }
+}
+
+/// Evaluate raw `If-None-Match` and `ETag` header values. `false` when the
+/// header is missing or malformed.
+pub fn if_none_match(header: Option<&str>, etag: Option<&str>) -> bool {
+    let Some(condition) = header.and_then(IfNoneMatch::parse) else {
+        return false;
+    };
+    condition.matches(etag.and_then(EntityTag::parse).as_ref())
+}
+
+/// Evaluate a raw `If-Modified-Since` value against the time the
+/// representation last changed. `true` means not modified since; `false`
+/// when the header is missing or not an HTTP-date. HTTP-dates have whole
+/// seconds, so `last_modified` is compared without its fraction.
+pub fn not_modified_since(header: Option<&str>, last_modified: SystemTime) -> bool {
+    let Some(since) = header.and_then(|value| httpdate::parse_http_date(value.trim()).ok()) else {
+        return false;
+    };
+    let whole_seconds = last_modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(Duration::ZERO, |elapsed| {
+            Duration::from_secs(elapsed.as_secs())
+        });
+    SystemTime::UNIX_EPOCH + whole_seconds <= since
+}
+
+/// `entity-tag = [ "W/" ] DQUOTE *etagc DQUOTE`. Returns the tag and the
+/// unparsed remainder.
+fn parse_entity_tag(value: &str) -> Option<(EntityTag, &str)> {
+    let (weak, quoted) = match value.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let body = quoted.strip_prefix('"')?;
+    let end = body.find('"')?;
+    let tag = &body[..end];
+    if !tag.bytes().all(is_etagc) {
+        return None;
+    }
+    Some((
+        EntityTag {
+            weak,
+            tag: tag.to_string(),
+        },
+        &body[end + 1..],
+    ))
+}
+
+/// `etagc = %x21 / %x23-7E / obs-text`
+fn is_etagc(b: u8) -> bool {
+    b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80
+}
+
+fn is_ows(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc_comparison_table() {
+        // RFC 9110 §8.8.3.2.
+        let cases = [
+            (r#"W/"1""#, r#"W/"1""#, false, true),
+            (r#"W/"1""#, r#"W/"2""#, false, false),
+            (r#"W/"1""#, r#""1""#, false, true),
+            (r#""1""#, r#""1""#, true, true),
+        ];
+        for (a, b, strong, weak) in cases {
+            let (a, b) = (EntityTag::parse(a).unwrap(), EntityTag::parse(b).unwrap());
+            assert_eq!(a.strong_eq(&b), strong, "{a} strong {b}");
+            assert_eq!(a.weak_eq(&b), weak, "{a} weak {b}");
+        }
+    }
+
+    #[test]
+    fn test_if_none_match_table() {
+        let cases: &[(&str, Option<&str>, bool)] = &[
+            // RFC 9110 §13.1.2 examples.
+            (r#""xyzzy""#, Some(r#""xyzzy""#), true),
+            (r#"W/"xyzzy""#, Some(r#""xyzzy""#), true),
+            (
+                r#""xyzzy", "r2d2xxxx", "c3piozzzz""#,
+                Some(r#""c3piozzzz""#),
+                true,
+            ),
+            (
+                r#"W/"xyzzy", W/"r2d2xxxx", W/"c3piozzzz""#,
+                Some(r#""r2d2xxxx""#),
+                true,
+            ),
+            ("*", Some(r#""anything""#), true),
+            ("*", None, true),
+            // Weak comparison in both directions, lists, and empty elements.
+            (r#""xyzzy""#, Some(r#"W/"xyzzy""#), true),
+            (r#"W/"abc", "def""#, Some(r#""def""#), true),
+            (r#"W/"abc", "def""#, Some(r#""abcd""#), false),
+            (r#" , "a",, "b" ,"#, Some(r#""b""#), true),
+            (r#""a,b""#, Some(r#""a,b""#), true),
+            (r#""""#, Some(r#""""#), true),
+            (r#""xyzzy""#, None, false),
+            (r#""xyzzy""#, Some("unquoted"), false),
+            // Case matters: tags are opaque, and `w/` is not a weak prefix.
+            (r#""ABC""#, Some(r#""abc""#), false),
+            (r#"w/"abc""#, Some(r#""abc""#), false),
+            // Malformed headers are ignored.
+            (r#""abc"#, Some(r#""abc""#), false),
+            (r#"W/"abc"#, Some(r#""abc""#), false),
+            ("abc", Some(r#""abc""#), false),
+            ("W/abc", Some(r#""abc""#), false),
+            (r#""a"b", "c""#, Some(r#""c""#), false),
+            (r#""a" "b""#, Some(r#""b""#), false),
+            (r#"*, "a""#, Some(r#""a""#), false),
+            ("W/", Some(r#""a""#), false),
+            ("\"a\tb\"", Some("\"a\tb\""), false),
+            ("", Some(r#""a""#), false),
+            (",", Some(r#""a""#), false),
+            ("\"", Some("\""), false),
+        ];
+        for &(header, etag, expected) in cases {
+            assert_eq!(
+                if_none_match(Some(header), etag),
+                expected,
+                "If-None-Match: {header:?} vs ETag: {etag:?}"
+            );
+        }
+        assert!(!if_none_match(None, Some(r#""a""#)));
+    }
+
+    #[test]
+    fn test_not_modified_since() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let header = |value| not_modified_since(Some(value), modified);
+        // 784111777 is Sun, 06 Nov 1994 08:49:37 GMT.
+        assert!(header("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(header("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(!header("Sun, 06 Nov 1994 08:49:36 GMT"));
+        // Obsolete formats are still HTTP-dates.
+        assert!(header("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert!(header("Sun Nov  6 08:49:37 1994"));
+        assert!(!header("yesterday"));
+        assert!(!header(""));
+        assert!(!not_modified_since(None, modified));
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(
+            IfNoneMatch::parse("W/\"abc\", \"d\u{e9}f\""),
+            Some(IfNoneMatch::Tags(vec![
+                EntityTag::weak("abc"),
+                EntityTag::strong("d\u{e9}f")
+            ]))
+        );
+        assert_eq!(IfNoneMatch::parse(" * "), Some(IfNoneMatch::Any));
+        assert_eq!(EntityTag::parse(r#"  W/"x"  "#), Some(EntityTag::weak("x")));
+        assert_eq!(EntityTag::weak("x").to_string(), r#"W/"x""#);
+        assert_eq!(EntityTag::strong("x").to_string(), r#""x""#);
+    }
+}
@@ -1,4 +1,12 @@
-use crate::{CacheStorageMode, CacheStrategy, CompressStrategy, WebhookConfig};
+use crate::backend::LoadBalancing;
+use crate::body_cache::BodyCacheRule;
+use crate::cache_rules::CacheRule;
+use crate::html_normalize::HtmlNormalizeRule;
+use crate::path_matcher::WildcardSyntax;
+use crate::{
+    AcceptMismatch, BackendErrorPolicy, CacheErrorPolicy, CacheStorageMode, CacheStrategy,
+    ClientDisconnect, CompressStrategy, FormatMismatchPolicy, UpgradeFallback, WebhookConfig,
+};
 use anyhow::{bail, Result};
 use serde::{
     de::{self, Visitor},
@@ -102,18 +110,35 @@ pub struct Config {
     pub https_port: Option<u16>,
 
     /// Path to the TLS certificate file (PEM). Required when `https_port` is set.
+    #[serde(alias = "tls_cert_path")]
     pub cert_path: Option<PathBuf>,
 
     /// Path to the TLS private key file (PEM). Required when `https_port` is set.
+    #[serde(alias = "tls_key_path")]
     pub key_path: Option<PathBuf>,
 
     /// Control-plane listen port (default: 17809).
     #[serde(default = "default_control_port")]
     pub control_port: u16,
 
+    /// Certificate and key (PEM) to serve the control port over TLS. Unset,
+    /// it stays plaintext.
+    pub control_cert_path: Option<PathBuf>,
+    pub control_key_path: Option<PathBuf>,
+
     /// Optional bearer token required to call `/refresh-cache`.
     pub control_auth: Option<String>,
 
+    /// Serve `GET /metrics` on the control port without `control_auth`
+    /// (default: false), for Prometheus scrapers without the token.
+    #[serde(default)]
+    pub public_metrics: bool,
+
+    /// Seconds the binary waits on SIGINT / SIGTERM for in-flight requests
+    /// and upgrade tunnels to finish before closing them (default: 30).
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+
     /// Named server entries, each mapping to a `[server.NAME]` TOML block.
     pub server: HashMap<String, ServerConfig>,
 
@@ -147,6 +172,20 @@ pub struct ServerConfig {
     #[serde(default = "default_proxy_url")]
     pub proxy_url: String,
 
+    /// Several backend URLs to spread requests over, used instead of
+    /// `proxy_url` when set (default: empty).
+    #[serde(default)]
+    pub backends: Vec<String>,
+
+    /// `"round_robin"` (default) or `"least_connections"`.
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Seconds one of several `backends` is skipped after a request to it
+    /// gets no response (default: 10).
+    #[serde(default = "default_backend_cooldown_secs")]
+    pub backend_cooldown_secs: u64,
+
     /// Paths to include in caching (empty means include all).
     /// Supports wildcards: `["/api/*", "/*/users"]`
     #[serde(default)]
@@ -158,6 +197,20 @@ pub struct ServerConfig {
     #[serde(default)]
     pub exclude_paths: Vec<String>,
 
+    /// Ordered cache rules replacing `include_paths` and `exclude_paths`,
+    /// e.g. `["/api/*", "!/api/internal/*", "/api/internal/public/*"]`.
+    /// An entry starting with `!` keeps what it matches out of the cache,
+    /// the last matching entry decides, and paths none matches are not
+    /// cached. Cannot be combined with `include_paths` or `exclude_paths`.
+    #[serde(default)]
+    pub path_rules: Vec<String>,
+
+    /// `"legacy"` (default), where `*` in path and purge patterns also
+    /// matches `/`, or `"segments"`, where `*` stays within one path segment
+    /// and `**` crosses segments.
+    #[serde(default)]
+    pub wildcard_syntax: WildcardSyntax,
+
     /// Enable WebSocket / protocol-upgrade support (default: `true`).
     ///
     /// When `true`, upgrade requests bypass the cache and establish a direct
@@ -177,18 +230,155 @@ pub struct ServerConfig {
     #[serde(default = "default_cache_404_capacity")]
     pub cache_404_capacity: usize,
 
+    /// Seconds 5xx responses are cached in a separate error store
+    /// (default: 0 = error responses get no special treatment).
+    #[serde(default)]
+    pub cache_errors_ttl_secs: u64,
+
+    /// Capacity for the error store (default: 100).
+    #[serde(default = "default_cache_errors_capacity")]
+    pub cache_errors_capacity: usize,
+
+    /// Byte budget for the main cache; least recently used entries are
+    /// evicted beyond it (default: 0 = unlimited).
+    #[serde(default)]
+    pub cache_max_bytes: usize,
+
+    /// Largest request body accepted; longer ones get `413` (default:
+    /// 10 MiB; 0 = unlimited).
+    #[serde(default = "default_max_request_body_bytes")]
+    pub max_request_body_bytes: usize,
+
+    /// Largest response buffered for the cache; longer ones are streamed
+    /// uncached (default: 20 MiB; 0 = unlimited).
+    #[serde(default = "default_max_cacheable_response_bytes")]
+    pub max_cacheable_response_bytes: usize,
+
     /// Detect 404 pages via `<meta name="phantom-404">` in addition to HTTP status.
     #[serde(default = "default_use_404_meta")]
     pub use_404_meta: bool,
 
+    /// Bytes at the start of an HTML body searched for the meta tag
+    /// (default: 16384).
+    #[serde(default = "default_meta_404_scan_bytes")]
+    pub meta_404_scan_bytes: usize,
+
+    /// Follow the backend's `Cache-Control` when storing responses (default:
+    /// `false`).
+    #[serde(default)]
+    pub respect_cache_control: bool,
+
+    /// Do not cache responses that set a cookie (default: `true`).
+    #[serde(default = "default_skip_set_cookie_responses")]
+    pub skip_set_cookie_responses: bool,
+
+    /// Bypass the cache for requests with an `Authorization` header or a
+    /// cookie named in `bypass_cookie_names` (default: `true`).
+    #[serde(default = "default_bypass_credentialed_requests")]
+    pub bypass_credentialed_requests: bool,
+
+    /// Cookie names, `*` wildcards allowed, that count as credentials
+    /// (default: common session cookie names).
+    #[serde(default = "crate::credentials::default_cookie_names")]
+    pub bypass_cookie_names: Vec<String>,
+
+    /// Refresh the cached entry for requests sent with `Cache-Control:
+    /// no-cache` or `Pragma: no-cache` (default: `false`).
+    #[serde(default)]
+    pub respect_client_no_cache: bool,
+
+    /// Secret that forces a refresh when sent in `X-Phantom-Revalidate`.
+    #[serde(default)]
+    pub revalidate_token: Option<String>,
+
+    /// Request header that, set to `1` or `true`, bypasses the cache for that
+    /// request (default: none).
+    #[serde(default)]
+    pub bypass_header: Option<String>,
+
+    /// Request header that purges the request's entry when it carries
+    /// `purge_token`, e.g. `x-phantom-purge` (default: none). Needs a
+    /// `purge_token`.
+    #[serde(default)]
+    pub purge_header: Option<String>,
+
+    /// Secret required by `purge_header` (default: none).
+    #[serde(default)]
+    pub purge_token: Option<String>,
+
+    /// Answer `HEAD` requests from the `GET` entry for the same URL
+    /// (default: `true`).
+    #[serde(default = "default_serve_head_from_get")]
+    pub serve_head_from_get: bool,
+
+    /// Cache one entry per request-header combination named by the
+    /// backend's `Vary` (default: `true`).
+    #[serde(default = "default_respect_vary")]
+    pub respect_vary: bool,
+
+    /// Keep client-sent `X-Forwarded-*` headers instead of replacing them
+    /// (default: `false`). Enable only behind a proxy that sets them.
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+
+    /// Forward the client's `Host` header instead of the backend's host
+    /// (default: `false`).
+    #[serde(default)]
+    pub preserve_host: bool,
+
+    /// Follow backend redirects instead of passing them to the client
+    /// (default: `false`).
+    #[serde(default)]
+    pub follow_redirects: bool,
+
+    /// Rewrite `Location` headers pointing at `proxy_url` to point at the
+    /// proxy (default: `false`).
+    #[serde(default)]
+    pub rewrite_location: bool,
+
+    /// Public base URL for rewritten `Location` headers, e.g.
+    /// `"https://www.example.com"`. Unset, they become relative paths.
+    #[serde(default)]
+    pub location_base_url: Option<String>,
+
+    /// Path prefix stripped from requests before they are matched, cached,
+    /// and forwarded, e.g. `"/app"` when a load balancer routes `/app/*`
+    /// here. Requests outside it get `404`.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+
     /// Controls which response types should be cached.
     #[serde(default)]
     pub cache_strategy: CacheStrategy,
 
+    /// Content type prefixes streamed through uncached (default:
+    /// `text/event-stream`).
+    #[serde(default = "crate::proxy::default_streaming_content_types")]
+    pub streaming_content_types: Vec<String>,
+
     /// Controls how cached responses are compressed in memory.
     #[serde(default)]
     pub compress_strategy: CompressStrategy,
 
+    /// Content type prefixes compressed when stored and served (default:
+    /// text, JSON, JavaScript, XML, SVG, and WebAssembly).
+    #[serde(default = "crate::compression::default_compress_content_types")]
+    pub compress_content_types: Vec<String>,
+
+    /// Smallest body compressed, in bytes (default: 0 = any size).
+    #[serde(default)]
+    pub compress_min_bytes: usize,
+
+    /// Compress cached responses for clients that do not accept the stored
+    /// encoding (default: `false`).
+    #[serde(default)]
+    pub compress_on_serve: bool,
+
+    /// Bytes kept of bodies compressed by `compress_on_serve` (default:
+    /// 64 MiB; 0 compresses on every hit).
+    #[serde(default = "default_compress_variants_max_bytes")]
+    pub compress_variants_max_bytes: usize,
+
     /// Controls where cached response bodies are stored.
     #[serde(default)]
     pub cache_storage_mode: CacheStorageMode,
@@ -229,10 +419,227 @@ pub struct ServerConfig {
     /// Blocking webhooks gate access; notify webhooks are fire-and-forget.
     #[serde(default)]
     pub webhooks: Vec<WebhookConfig>,
+
+    /// Patterns whose cache misses are queued for an external renderer.
+    /// Workers claim jobs via `GET /render-queue` and submit via `PUT /cache/entry`.
+    #[serde(default)]
+    pub render_queue_patterns: Vec<String>,
+
+    /// Maximum number of pending render jobs (default: 1000).
+    #[serde(default = "default_render_queue_capacity")]
+    pub render_queue_capacity: usize,
+
+    /// Optional file used to persist pending render jobs across restarts.
+    #[serde(default)]
+    pub render_queue_path: Option<PathBuf>,
+
+    /// Paths `POST /cache/warm` fetches at once (default: 4).
+    #[serde(default = "default_warm_concurrency")]
+    pub warm_concurrency: usize,
+
+    /// Snapshot file the cache is saved to and restored from on startup.
+    #[serde(default)]
+    pub persistence_path: Option<PathBuf>,
+
+    /// Seconds between cache snapshots (default: 60). With 0 the snapshot is
+    /// only written on shutdown and through `POST /persistence/compact`.
+    #[serde(default = "default_persistence_interval_secs")]
+    pub persistence_interval_secs: u64,
+
+    /// A snapshot or Redis entry from an earlier format version: `"migrate"`
+    /// (default) converts it, `"discard"` ignores it.
+    #[serde(default)]
+    pub format_mismatch_policy: FormatMismatchPolicy,
+
+    /// Maximum cache inserts per second for this server (default: 0 = unlimited).
+    /// Responses beyond the ceiling are served but not stored.
+    #[serde(default)]
+    pub max_cache_writes_per_sec: u32,
+
+    /// Pending cache writes queued to be stored after the client is answered
+    /// (default: 0 = store before answering). Writes beyond it are dropped.
+    #[serde(default)]
+    pub cache_write_queue_capacity: usize,
+
+    /// Clear the cache when `proxy_url` changes on reload (default: `true`).
+    #[serde(default = "default_purge_cache_on_backend_change")]
+    pub purge_cache_on_backend_change: bool,
+
+    /// Idle backend connections kept per host (default: unlimited; 0 disables
+    /// connection reuse).
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Milliseconds allowed to connect to the backend (default: 5000).
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+
+    /// Milliseconds allowed for a whole backend request before it is
+    /// answered with 504 (default: 30000).
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+
+    /// Milliseconds allowed for an upgrade request's backend handshake
+    /// (default: 10000). Established tunnels have no deadline.
+    #[serde(default = "default_upgrade_handshake_timeout_ms")]
+    pub upgrade_handshake_timeout_ms: u64,
+
+    /// Backend path `GET /readyz` sends a `HEAD` request to. Unset (default)
+    /// skips the backend check.
+    #[serde(default)]
+    pub health_check_path: Option<String>,
+
+    /// Milliseconds allowed for the backend health check (default: 2000).
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+
+    /// Seconds between active checks of every backend at
+    /// `health_check_path`; backends that fail are not sent requests until
+    /// they pass again. `0` (default) disables active checks.
+    #[serde(default)]
+    pub health_check_interval_secs: u64,
+
+    /// Minutes of history kept for windowed hit-rate stats (default: 60).
+    #[serde(default = "default_stats_window_minutes")]
+    pub stats_window_minutes: usize,
+
+    /// Seconds to remember that a key is not cacheable (default: 30, 0 disables).
+    #[serde(default = "default_decision_memo_ttl_secs")]
+    pub decision_memo_ttl_secs: u64,
+
+    /// Handling of upgrade requests when upgrades are unavailable:
+    /// `"reject"` (default) or `"ignore_header"`.
+    #[serde(default)]
+    pub upgrade_fallback: UpgradeFallback,
+
+    /// Paths where `upgrade_fallback = "ignore_header"` applies (empty = all).
+    #[serde(default)]
+    pub upgrade_fallback_paths: Vec<String>,
+
+    /// Status code for rejected upgrade requests (default: 501).
+    #[serde(default = "default_upgrade_reject_status")]
+    pub upgrade_reject_status: u16,
+
+    /// Cache hits whose `Content-Type` the request's `Accept` rules out:
+    /// `"ignore"` (default), `"bypass"` to ask the backend, or `"reject"` for 406.
+    #[serde(default)]
+    pub accept_mismatch: AcceptMismatch,
+
+    /// A cache body that cannot be read or written: `"bypass"` (default) to
+    /// carry on without the cache, or `"fail"` to answer 503.
+    #[serde(default)]
+    pub cache_error_policy: CacheErrorPolicy,
+
+    /// A backend that fails a cacheable request: `"fail"` (default) passes
+    /// the failure on, `"stale_if_available"` serves the cached entry even
+    /// past its TTL.
+    #[serde(default)]
+    pub backend_error_policy: BackendErrorPolicy,
+
+    /// Entries stored longer ago than this are not served stale, and
+    /// expired entries are kept at most this long past their TTL (default:
+    /// 86400). Must be non-zero.
+    #[serde(default = "default_stale_max_age_secs")]
+    pub stale_max_age_secs: u64,
+
+    /// Serve stale entries for backend 500, 502, 503, and 504 answers too,
+    /// not only for transport errors (default: `false`).
+    #[serde(default)]
+    pub stale_on_error_status: bool,
+
+    /// A client that disconnects before its response is ready: `"abort"`
+    /// (default) cancels the backend request, `"complete_cacheable"` lets
+    /// cacheable misses finish and be stored.
+    #[serde(default)]
+    pub client_disconnect: ClientDisconnect,
+
+    /// Response header set to `HIT`, `MISS`, `BYPASS`, or `STALE` (default:
+    /// `"X-Cache"`). An empty string disables it.
+    #[serde(default = "default_cache_status_header")]
+    pub cache_status_header: String,
+
+    /// Concurrent misses for the same cache key share one backend fetch
+    /// (default: `true`).
+    #[serde(default = "default_coalesce_misses")]
+    pub coalesce_misses: bool,
+
+    /// Query parameters whose values are logged as `[redacted]`. Replaces the
+    /// default list (tokens, keys, secrets, passwords, signatures, email).
+    #[serde(default = "crate::redact::default_redact_query_params")]
+    pub redact_query_params: Vec<String>,
+
+    /// Headers whose values are logged as `[redacted]`. Replaces the default
+    /// list (authorization, cookie, set-cookie, ...).
+    #[serde(default = "crate::redact::default_redact_headers")]
+    pub redact_headers: Vec<String>,
+
+    /// Check on every cache hit that the entry was stored for the same method
+    /// and path, evicting it otherwise (default: `true`).
+    #[serde(default = "default_verify_cache_origin")]
+    pub verify_cache_origin: bool,
+
+    /// Regex rewrites applied to HTML bodies before they are cached, e.g. to
+    /// blank out CSRF tokens. Changes the content clients are served.
+    #[serde(default)]
+    pub html_normalize_rules: Vec<HtmlNormalizeRule>,
+
+    /// Paths whose HTML is normalized (empty = all cached paths).
+    #[serde(default)]
+    pub html_normalize_paths: Vec<String>,
+
+    /// Per-path rules, tried in order: whether matching requests are cached,
+    /// their TTL, and headers added to their key. Written as
+    /// `[[server.NAME.rules]]`; paths no rule matches follow `include_paths`
+    /// and `exclude_paths`.
+    #[serde(default, alias = "rules")]
+    pub cache_rules: Vec<CacheRule>,
+
+    /// Request headers whose values are appended to every cache key, e.g.
+    /// `["accept-language", "x-tenant-id"]`, so each combination gets its
+    /// own entry. Values longer than 256 bytes are cut.
+    #[serde(default)]
+    pub cache_key_headers: Vec<String>,
+
+    /// Query parameters left out of cache keys, e.g. `["utm_source",
+    /// "gclid", "fbclid"]`. The backend still receives them.
+    #[serde(default)]
+    pub ignored_query_params: Vec<String>,
+
+    /// When non-empty, the only query parameters kept in cache keys.
+    #[serde(default)]
+    pub allowed_query_params: Vec<String>,
+
+    /// Requests cached by their body, e.g. GraphQL queries, each with its
+    /// own TTL. Only requests matching a rule are affected.
+    #[serde(default)]
+    pub body_cache_rules: Vec<BodyCacheRule>,
+
+    /// Credentials added to every backend request, e.g. an OAuth2
+    /// client-credentials token refreshed before it expires.
+    #[serde(default)]
+    pub upstream_auth: Option<crate::upstream_auth::UpstreamAuthConfig>,
+
+    /// Record backend traffic to a file, or replay it without a backend.
+    #[cfg(feature = "cassette")]
+    #[serde(default)]
+    pub cassette: Option<crate::cassette::CassetteConfig>,
+
+    /// Keep the main cache in the Redis server at this URL, e.g.
+    /// `"redis://127.0.0.1:6379/0"`, shared by every replica pointing at it.
+    /// Requires the `redis-backend` feature.
+    #[serde(default)]
+    pub redis_url: Option<String>,
+
+    /// Prefix of this server's Redis keys (default: `"phantom-frame:NAME:"`).
+    #[serde(default)]
+    pub redis_key_prefix: Option<String>,
 }
 
 // ── defaults ────────────────────────────────────────────────────────────────
 
+/// Upper bound for `stats_window_minutes`: one week of per-minute buckets.
+const MAX_STATS_WINDOW_MINUTES: usize = 7 * 24 * 60;
+
 fn default_http_port() -> u16 {
     3000
 }
@@ -241,6 +648,10 @@ fn default_control_port() -> u16 {
     17809
 }
 
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
 fn default_bind_to() -> String {
     "*".to_string()
 }
@@ -249,6 +660,10 @@ fn default_proxy_url() -> String {
     "http://localhost:8080".to_string()
 }
 
+fn default_backend_cooldown_secs() -> u64 {
+    crate::backend::DEFAULT_BACKEND_COOLDOWN.as_secs()
+}
+
 fn default_enable_websocket() -> bool {
     true
 }
@@ -261,14 +676,110 @@ fn default_cache_404_capacity() -> usize {
     100
 }
 
+fn default_cache_errors_capacity() -> usize {
+    100
+}
+
 fn default_use_404_meta() -> bool {
     false
 }
 
+fn default_meta_404_scan_bytes() -> usize {
+    crate::not_found_meta::DEFAULT_SCAN_BYTES
+}
+
 fn default_pre_generate_fallthrough() -> bool {
     false
 }
 
+fn default_max_request_body_bytes() -> usize {
+    crate::proxy::DEFAULT_MAX_REQUEST_BODY_BYTES
+}
+
+fn default_max_cacheable_response_bytes() -> usize {
+    crate::proxy::DEFAULT_MAX_CACHEABLE_RESPONSE_BYTES
+}
+
+fn default_compress_variants_max_bytes() -> usize {
+    crate::compressed_variants::DEFAULT_MAX_BYTES
+}
+
+fn default_render_queue_capacity() -> usize {
+    1000
+}
+
+fn default_warm_concurrency() -> usize {
+    crate::warm::DEFAULT_WARM_CONCURRENCY
+}
+
+fn default_purge_cache_on_backend_change() -> bool {
+    true
+}
+
+fn default_cache_status_header() -> String {
+    "X-Cache".to_string()
+}
+
+fn default_coalesce_misses() -> bool {
+    true
+}
+
+fn default_stale_max_age_secs() -> u64 {
+    86_400
+}
+
+fn default_skip_set_cookie_responses() -> bool {
+    true
+}
+
+fn default_bypass_credentialed_requests() -> bool {
+    true
+}
+
+fn default_serve_head_from_get() -> bool {
+    true
+}
+
+fn default_respect_vary() -> bool {
+    true
+}
+
+fn default_verify_cache_origin() -> bool {
+    true
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_request_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_upgrade_handshake_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_stats_window_minutes() -> usize {
+    60
+}
+
+fn default_persistence_interval_secs() -> u64 {
+    60
+}
+
+fn default_decision_memo_ttl_secs() -> u64 {
+    30
+}
+
+fn default_upgrade_reject_status() -> u16 {
+    501
+}
+
 // ── Config impl ──────────────────────────────────────────────────────────────
 
 /// Recursively walk a `toml::Value` tree, resolving `$env:VAR` references.
@@ -298,7 +809,98 @@ fn resolve_env_vars(value: toml::Value) -> Option<toml::Value> {
     }
 }
 
+/// Environment variables that override config values, taking precedence over
+/// both the file and `$env:` references.
+pub const ENV_OVERRIDES: &[&str] = &[
+    "PHANTOM_PROXY_URL",
+    "PHANTOM_PROXY_PORT",
+    "PHANTOM_CONTROL_PORT",
+    "PHANTOM_CONTROL_AUTH",
+    "PHANTOM_INCLUDE_PATHS",
+];
+
+fn parse_env_port(name: &str, value: &str) -> Result<toml::Value> {
+    let port: u16 = value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("`{name}` must be a port number (0-65535), got '{value}'"))?;
+    Ok(toml::Value::Integer(port.into()))
+}
+
+/// Apply the [`ENV_OVERRIDES`] that `var` returns a value for to a raw config
+/// table. `PHANTOM_PROXY_URL` and `PHANTOM_INCLUDE_PATHS` set the only
+/// `[server.NAME]` block, creating `[server.default]` when there is none.
+fn apply_env_overrides(raw: &mut toml::Value, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+    let Some(root) = raw.as_table_mut() else {
+        bail!("the config root must be a table");
+    };
+    if let Some(value) = var("PHANTOM_PROXY_PORT") {
+        let port = parse_env_port("PHANTOM_PROXY_PORT", &value)?;
+        root.insert("http_port".to_string(), port);
+    }
+    if let Some(value) = var("PHANTOM_CONTROL_PORT") {
+        let port = parse_env_port("PHANTOM_CONTROL_PORT", &value)?;
+        root.insert("control_port".to_string(), port);
+    }
+    if let Some(value) = var("PHANTOM_CONTROL_AUTH") {
+        root.insert("control_auth".to_string(), toml::Value::String(value));
+    }
+
+    let mut server_values = Vec::new();
+    if let Some(value) = var("PHANTOM_PROXY_URL") {
+        server_values.push(("PHANTOM_PROXY_URL", "proxy_url", toml::Value::String(value)));
+    }
+    if let Some(value) = var("PHANTOM_INCLUDE_PATHS") {
+        let paths = value
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(|path| toml::Value::String(path.to_string()))
+            .collect();
+        server_values.push((
+            "PHANTOM_INCLUDE_PATHS",
+            "include_paths",
+            toml::Value::Array(paths),
+        ));
+    }
+    let Some((first_var, _, _)) = server_values.first() else {
+        return Ok(());
+    };
+    let servers = root
+        .entry("server")
+        .or_insert_with(|| toml::Value::Table(toml::map::Map::new()));
+    let Some(servers) = servers.as_table_mut() else {
+        bail!("`server` must be a table of `[server.NAME]` blocks");
+    };
+    if servers.is_empty() {
+        servers.insert(
+            "default".to_string(),
+            toml::Value::Table(toml::map::Map::new()),
+        );
+    }
+    if servers.len() > 1 {
+        bail!(
+            "`{}` cannot be applied: {} `[server.NAME]` blocks are configured, set the value in the file instead",
+            first_var,
+            servers.len()
+        );
+    }
+    let Some(server) = servers
+        .iter_mut()
+        .next()
+        .and_then(|(_, server)| server.as_table_mut())
+    else {
+        bail!("`[server.NAME]` entries must be tables");
+    };
+    for (_, key, value) in server_values {
+        server.insert(key.to_string(), value);
+    }
+    Ok(())
+}
+
 impl Config {
+    /// Load the config file at `path`, resolve `$env:` references, and apply
+    /// [`ENV_OVERRIDES`].
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
 
@@ -330,25 +932,338 @@ impl Config {
 
         // Walk the full TOML tree and resolve all $env: references.
         raw = resolve_env_vars(raw).unwrap_or_else(|| toml::Value::Table(toml::map::Map::new()));
+        apply_env_overrides(&mut raw, |name| std::env::var(name).ok())?;
+
+        let config: Config = raw.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Build a config from [`ENV_OVERRIDES`] alone, for running without a
+    /// config file. `PHANTOM_PROXY_URL` is required; everything else keeps
+    /// its default.
+    pub fn from_env() -> Result<Self> {
+        Self::from_env_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_env_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        if var("PHANTOM_PROXY_URL").is_none() {
+            bail!("no config file given and `PHANTOM_PROXY_URL` is not set");
+        }
+        let mut raw = toml::Value::Table(toml::map::Map::new());
+        apply_env_overrides(&mut raw, var)?;
 
         let config: Config = raw.try_into()?;
         config.validate()?;
         Ok(config)
     }
 
-    fn validate(&self) -> Result<()> {
+    /// Check the config for values that would only fail later, e.g. as bind
+    /// errors or 502s, and report every problem found in one error.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut ports = vec![("http_port", self.http_port)];
+        ports.extend(self.https_port.map(|port| ("https_port", port)));
+        ports.push(("control_port", self.control_port));
+        for (index, (field, port)) in ports.iter().enumerate() {
+            if let Some((other, _)) = ports[..index].iter().find(|(_, other)| other == port) {
+                problems.push(format!("`{}` and `{}` are both {}", other, field, port));
+            }
+        }
         if self.https_port.is_some() {
             if self.cert_path.is_none() {
-                bail!("`cert_path` is required when `https_port` is set");
+                problems.push("`cert_path` is required when `https_port` is set".to_string());
             }
             if self.key_path.is_none() {
-                bail!("`key_path` is required when `https_port` is set");
+                problems.push("`key_path` is required when `https_port` is set".to_string());
             }
         }
+        if self.control_cert_path.is_some() != self.control_key_path.is_some() {
+            problems.push(
+                "`control_cert_path` and `control_key_path` must be set together".to_string(),
+            );
+        }
         if self.server.is_empty() {
-            bail!("at least one `[server.NAME]` block is required");
+            problems.push("at least one `[server.NAME]` block is required".to_string());
+        }
+        let mut servers: Vec<_> = self.server.iter().collect();
+        servers.sort_by_key(|(name, _)| *name);
+        for (name, server) in servers {
+            let urls: Vec<(String, &String)> = if server.backends.is_empty() {
+                vec![("proxy_url".to_string(), &server.proxy_url)]
+            } else {
+                (server.backends.iter().enumerate())
+                    .map(|(index, url)| (format!("backends[{}]", index), url))
+                    .collect()
+            };
+            for (field, url) in urls {
+                let parsed = reqwest::Url::parse(url);
+                let is_http_url = parsed.as_ref().is_ok_and(|url| {
+                    matches!(url.scheme(), "http" | "https") && url.host_str().is_some()
+                });
+                if !is_http_url {
+                    let hint = if url.contains("://") {
+                        String::new()
+                    } else {
+                        format!(" (did you mean 'http://{}'?)", url)
+                    };
+                    problems.push(format!(
+                        "server '{}': `{}` '{}' is not an http(s) URL with a host{}",
+                        name, field, url, hint
+                    ));
+                }
+            }
+            let patterns = [
+                ("include_paths", &server.include_paths),
+                ("exclude_paths", &server.exclude_paths),
+                ("render_queue_patterns", &server.render_queue_patterns),
+                ("html_normalize_paths", &server.html_normalize_paths),
+                ("upgrade_fallback_paths", &server.upgrade_fallback_paths),
+            ];
+            for (field, patterns) in patterns {
+                for (index, pattern) in patterns.iter().enumerate() {
+                    if let Err(reason) = crate::path_matcher::validate_pattern(pattern) {
+                        problems.push(format!(
+                            "server '{}': {}[{}] {:?}: {}",
+                            name, field, index, pattern, reason
+                        ));
+                    }
+                }
+            }
+            for (index, rule) in server.path_rules.iter().enumerate() {
+                if let Err(reason) = crate::path_matcher::validate_rule(rule) {
+                    problems.push(format!(
+                        "server '{}': path_rules[{}] {:?}: {}",
+                        name, index, rule, reason
+                    ));
+                }
+            }
+            if !server.path_rules.is_empty()
+                && (!server.include_paths.is_empty() || !server.exclude_paths.is_empty())
+            {
+                problems.push(format!(
+                    "server '{}': `path_rules` cannot be combined with `include_paths` or `exclude_paths`",
+                    name
+                ));
+            }
+            if server.cache_errors_ttl_secs > 0 && server.cache_errors_capacity == 0 {
+                problems.push(format!(
+                    "server '{}': `cache_errors_capacity` must be non-zero when `cache_errors_ttl_secs` is set",
+                    name
+                ));
+            }
+            if !server.render_queue_patterns.is_empty() && server.render_queue_capacity == 0 {
+                problems.push(format!(
+                    "server '{}': `render_queue_capacity` must be non-zero when `render_queue_patterns` is set",
+                    name
+                ));
+            }
+            if !(1..=MAX_STATS_WINDOW_MINUTES).contains(&server.stats_window_minutes) {
+                problems.push(format!(
+                    "server '{}': `stats_window_minutes` must be between 1 and {}, got {}",
+                    name, MAX_STATS_WINDOW_MINUTES, server.stats_window_minutes
+                ));
+            }
+            if !(400..=599).contains(&server.upgrade_reject_status) {
+                problems.push(format!(
+                    "server '{}': `upgrade_reject_status` must be a 4xx or 5xx code, got {}",
+                    name, server.upgrade_reject_status
+                ));
+            }
+            if server.connect_timeout_ms == 0 {
+                problems.push(format!(
+                    "server '{}': `connect_timeout_ms` must be non-zero",
+                    name
+                ));
+            }
+            if server.request_timeout_ms == 0 {
+                problems.push(format!(
+                    "server '{}': `request_timeout_ms` must be non-zero",
+                    name
+                ));
+            }
+            if server.warm_concurrency == 0 {
+                problems.push(format!(
+                    "server '{}': `warm_concurrency` must be non-zero",
+                    name
+                ));
+            }
+            if server.stale_max_age_secs == 0 {
+                problems.push(format!(
+                    "server '{}': `stale_max_age_secs` must be non-zero",
+                    name
+                ));
+            }
+            if server.upgrade_handshake_timeout_ms == 0 {
+                problems.push(format!(
+                    "server '{}': `upgrade_handshake_timeout_ms` must be non-zero",
+                    name
+                ));
+            }
+            if server
+                .health_check_path
+                .as_ref()
+                .is_some_and(|path| !path.starts_with('/'))
+            {
+                problems.push(format!(
+                    "server '{}': `health_check_path` must start with '/'",
+                    name
+                ));
+            }
+            if server.health_check_timeout_ms == 0 {
+                problems.push(format!(
+                    "server '{}': `health_check_timeout_ms` must be non-zero",
+                    name
+                ));
+            }
+            if server.health_check_interval_secs > 0 && server.health_check_path.is_none() {
+                problems.push(format!(
+                    "server '{}': `health_check_interval_secs` needs a `health_check_path`",
+                    name
+                ));
+            }
+            if !server.cache_status_header.is_empty()
+                && axum::http::HeaderName::from_bytes(server.cache_status_header.as_bytes())
+                    .is_err()
+            {
+                problems.push(format!(
+                    "server '{}': `cache_status_header` '{}' is not a valid header name",
+                    name, server.cache_status_header
+                ));
+            }
+            for (option, header) in [
+                ("bypass_header", &server.bypass_header),
+                ("purge_header", &server.purge_header),
+            ] {
+                if let Some(header) = header {
+                    if axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        problems.push(format!(
+                            "server '{}': `{}` '{}' is not a valid header name",
+                            name, option, header
+                        ));
+                    }
+                }
+            }
+            if server.purge_header.is_some() && server.purge_token.is_none() {
+                problems.push(format!(
+                    "server '{}': `purge_header` needs a `purge_token`",
+                    name
+                ));
+            }
+            if server.use_404_meta && server.meta_404_scan_bytes == 0 {
+                problems.push(format!(
+                    "server '{}': `meta_404_scan_bytes` must be non-zero",
+                    name
+                ));
+            }
+            if server.redis_url.is_some() && !cfg!(feature = "redis-backend") {
+                problems.push(format!(
+                    "server '{}': `redis_url` needs phantom-frame built with the `redis-backend` feature",
+                    name
+                ));
+            }
+            if let Some(base) = &server.location_base_url {
+                let is_http_url = reqwest::Url::parse(base)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+                if !is_http_url {
+                    problems.push(format!(
+                        "server '{}': `location_base_url` '{}' is not an absolute http(s) URL",
+                        name, base
+                    ));
+                }
+            }
+            if let Some(prefix) = &server.path_prefix {
+                if !prefix.starts_with('/') {
+                    problems.push(format!(
+                        "server '{}': `path_prefix` '{}' must start with '/'",
+                        name, prefix
+                    ));
+                }
+            }
+            if let Some(path) = &server.persistence_path {
+                // Only the later name reports a clash, so each pair is listed once.
+                let shared = self.server.iter().find(|(other, config)| {
+                    *other < name && config.persistence_path.as_ref() == Some(path)
+                });
+                if let Some((other, _)) = shared {
+                    problems.push(format!(
+                        "servers '{}' and '{}' use the same `persistence_path`",
+                        other, name
+                    ));
+                }
+            }
+            if let Err(e) = crate::html_normalize::HtmlNormalizer::new(&server.html_normalize_rules)
+            {
+                problems.push(format!("server '{}': {:#}", name, e));
+            }
+            for (index, rule) in server.cache_rules.iter().enumerate() {
+                if let Err(reason) = crate::path_matcher::validate_pattern(&rule.pattern) {
+                    problems.push(format!(
+                        "server '{}': rules[{}] pattern {:?}: {}",
+                        name, index, rule.pattern, reason
+                    ));
+                }
+                if rule.ttl_secs == Some(0) {
+                    problems.push(format!(
+                        "server '{}': rules[{}] has `ttl_secs = 0`; use `cache = false` instead",
+                        name, index
+                    ));
+                }
+                for header in &rule.key_headers {
+                    if axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        problems.push(format!(
+                            "server '{}': rules[{}] has an invalid key header '{}'",
+                            name, index, header
+                        ));
+                    }
+                }
+            }
+            for header in &server.cache_key_headers {
+                if axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    problems.push(format!(
+                        "server '{}': `cache_key_headers` has an invalid header '{}'",
+                        name, header
+                    ));
+                }
+            }
+            for rule in &server.body_cache_rules {
+                if rule.ttl_secs == 0 {
+                    problems.push(format!(
+                        "server '{}': body cache rule '{}' needs a non-zero `ttl_secs`",
+                        name, rule.pattern
+                    ));
+                }
+                let pointers = rule.skip_if.iter().filter_map(|skip| skip.pointer.as_ref());
+                for pointer in pointers {
+                    if !pointer.is_empty() && !pointer.starts_with('/') {
+                        problems.push(format!(
+                            "server '{}': `skip_if` pointer '{}' must be empty or start with '/'",
+                            name, pointer
+                        ));
+                    }
+                }
+            }
+        }
+        match problems.as_slice() {
+            [] => Ok(()),
+            [problem] => bail!("{}", problem),
+            _ => bail!(
+                "{} problems in the configuration:\n  - {}",
+                problems.len(),
+                problems.join("\n  - ")
+            ),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// `backends`, or just `proxy_url` when none are set.
+    pub fn backend_urls(&self) -> Vec<String> {
+        if self.backends.is_empty() {
+            vec![self.proxy_url.clone()]
+        } else {
+            self.backends.clone()
         }
-        Ok(())
     }
 }
 
@@ -357,14 +1272,47 @@ impl Default for ServerConfig {
         Self {
             bind_to: default_bind_to(),
             proxy_url: default_proxy_url(),
+            backends: vec![],
+            load_balancing: LoadBalancing::default(),
+            backend_cooldown_secs: default_backend_cooldown_secs(),
             include_paths: vec![],
             exclude_paths: vec![],
+            path_rules: vec![],
+            wildcard_syntax: WildcardSyntax::default(),
             enable_websocket: default_enable_websocket(),
             forward_get_only: default_forward_get_only(),
             cache_404_capacity: default_cache_404_capacity(),
+            cache_errors_ttl_secs: 0,
+            cache_errors_capacity: default_cache_errors_capacity(),
+            cache_max_bytes: 0,
+            max_request_body_bytes: default_max_request_body_bytes(),
+            max_cacheable_response_bytes: default_max_cacheable_response_bytes(),
             use_404_meta: default_use_404_meta(),
+            meta_404_scan_bytes: default_meta_404_scan_bytes(),
+            respect_cache_control: false,
+            skip_set_cookie_responses: default_skip_set_cookie_responses(),
+            bypass_credentialed_requests: default_bypass_credentialed_requests(),
+            bypass_cookie_names: crate::credentials::default_cookie_names(),
+            respect_client_no_cache: false,
+            revalidate_token: None,
+            bypass_header: None,
+            purge_header: None,
+            purge_token: None,
+            serve_head_from_get: default_serve_head_from_get(),
+            respect_vary: default_respect_vary(),
+            trust_forwarded_headers: false,
+            preserve_host: false,
+            follow_redirects: false,
+            rewrite_location: false,
+            location_base_url: None,
+            path_prefix: None,
             cache_strategy: CacheStrategy::default(),
+            streaming_content_types: crate::proxy::default_streaming_content_types(),
             compress_strategy: CompressStrategy::default(),
+            compress_content_types: crate::compression::default_compress_content_types(),
+            compress_min_bytes: 0,
+            compress_on_serve: false,
+            compress_variants_max_bytes: default_compress_variants_max_bytes(),
             cache_storage_mode: CacheStorageMode::default(),
             cache_directory: None,
             proxy_mode: ProxyModeConfig::default(),
@@ -373,6 +1321,51 @@ impl Default for ServerConfig {
             execute: None,
             execute_dir: None,
             webhooks: vec![],
+            render_queue_patterns: vec![],
+            render_queue_capacity: default_render_queue_capacity(),
+            render_queue_path: None,
+            warm_concurrency: default_warm_concurrency(),
+            persistence_path: None,
+            persistence_interval_secs: default_persistence_interval_secs(),
+            format_mismatch_policy: FormatMismatchPolicy::default(),
+            max_cache_writes_per_sec: 0,
+            cache_write_queue_capacity: 0,
+            purge_cache_on_backend_change: default_purge_cache_on_backend_change(),
+            pool_max_idle_per_host: None,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            request_timeout_ms: default_request_timeout_ms(),
+            upgrade_handshake_timeout_ms: default_upgrade_handshake_timeout_ms(),
+            health_check_path: None,
+            health_check_timeout_ms: default_health_check_timeout_ms(),
+            health_check_interval_secs: 0,
+            stats_window_minutes: default_stats_window_minutes(),
+            decision_memo_ttl_secs: default_decision_memo_ttl_secs(),
+            upgrade_fallback: UpgradeFallback::default(),
+            upgrade_fallback_paths: vec![],
+            upgrade_reject_status: default_upgrade_reject_status(),
+            accept_mismatch: AcceptMismatch::default(),
+            cache_error_policy: CacheErrorPolicy::default(),
+            backend_error_policy: BackendErrorPolicy::default(),
+            stale_max_age_secs: default_stale_max_age_secs(),
+            stale_on_error_status: false,
+            client_disconnect: ClientDisconnect::default(),
+            cache_status_header: default_cache_status_header(),
+            coalesce_misses: default_coalesce_misses(),
+            redact_query_params: crate::redact::default_redact_query_params(),
+            redact_headers: crate::redact::default_redact_headers(),
+            verify_cache_origin: default_verify_cache_origin(),
+            html_normalize_rules: vec![],
+            html_normalize_paths: vec![],
+            cache_rules: vec![],
+            cache_key_headers: vec![],
+            ignored_query_params: vec![],
+            allowed_query_params: vec![],
+            body_cache_rules: vec![],
+            upstream_auth: None,
+            #[cfg(feature = "cassette")]
+            cassette: None,
+            redis_url: None,
+            redis_key_prefix: None,
         }
     }
 }
@@ -398,6 +1391,101 @@ mod tests {
         assert_eq!(s.cache_directory, None);
     }
 
+    #[test]
+    fn test_config_defaults_match_builder_defaults() {
+        use std::time::Duration;
+
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        let s = config.server.get("default").unwrap();
+        let b = crate::CreateProxyConfig::new(s.proxy_url.clone());
+        assert_eq!(s.enable_websocket, b.enable_websocket);
+        assert_eq!(s.forward_get_only, b.forward_get_only);
+        assert_eq!(s.cache_404_capacity, b.cache_404_capacity);
+        assert_eq!(s.use_404_meta, b.use_404_meta);
+        assert_eq!(s.meta_404_scan_bytes, b.meta_404_scan_bytes);
+        assert_eq!(s.cache_errors_capacity, b.cache_errors_capacity);
+        assert_eq!(s.cache_errors_ttl_secs, b.cache_errors_ttl_secs);
+        assert_eq!(s.cache_max_bytes, b.cache_max_bytes);
+        assert_eq!(s.max_request_body_bytes, b.max_request_body_bytes);
+        assert_eq!(
+            s.max_cacheable_response_bytes,
+            b.max_cacheable_response_bytes
+        );
+        assert_eq!(s.respect_cache_control, b.respect_cache_control);
+        assert_eq!(s.skip_set_cookie_responses, b.skip_set_cookie_responses);
+        assert_eq!(
+            s.bypass_credentialed_requests,
+            b.bypass_credentialed_requests
+        );
+        assert_eq!(s.bypass_cookie_names, b.bypass_cookie_names);
+        assert_eq!(s.respect_client_no_cache, b.respect_client_no_cache);
+        assert_eq!(s.revalidate_token, b.revalidate_token);
+        assert_eq!(s.bypass_header, b.bypass_header);
+        assert_eq!(s.purge_header, b.purge_header);
+        assert_eq!(s.purge_token, b.purge_token);
+        assert_eq!(s.serve_head_from_get, b.serve_head_from_get);
+        assert_eq!(s.respect_vary, b.respect_vary);
+        assert_eq!(s.trust_forwarded_headers, b.trust_forwarded_headers);
+        assert_eq!(s.preserve_host, b.preserve_host);
+        assert_eq!(s.follow_redirects, b.follow_redirects);
+        assert_eq!(s.load_balancing, b.load_balancing);
+        assert_eq!(s.wildcard_syntax, b.wildcard_syntax);
+        assert_eq!(s.path_rules, b.path_rules);
+        assert_eq!(s.cache_key_headers, b.cache_key_headers);
+        assert_eq!(s.ignored_query_params, b.ignored_query_params);
+        assert_eq!(s.allowed_query_params, b.allowed_query_params);
+        assert_eq!(
+            Duration::from_secs(s.backend_cooldown_secs),
+            b.backend_cooldown
+        );
+        assert_eq!(s.rewrite_location, b.rewrite_location);
+        assert_eq!(s.render_queue_capacity, b.render_queue_capacity);
+        assert_eq!(s.warm_concurrency, b.warm_concurrency);
+        assert_eq!(s.max_cache_writes_per_sec, b.max_cache_writes_per_sec);
+        assert_eq!(s.cache_write_queue_capacity, b.cache_write_queue_capacity);
+        assert_eq!(s.compress_content_types, b.compress_content_types);
+        assert_eq!(s.streaming_content_types, b.streaming_content_types);
+        assert_eq!(s.compress_min_bytes, b.compress_min_bytes);
+        assert_eq!(s.compress_on_serve, b.compress_on_serve);
+        assert_eq!(s.compress_variants_max_bytes, b.compress_variants_max_bytes);
+        assert_eq!(
+            s.purge_cache_on_backend_change,
+            b.purge_cache_on_backend_change
+        );
+        assert_eq!(s.stats_window_minutes, b.stats_window_minutes);
+        assert_eq!(s.decision_memo_ttl_secs, b.decision_memo_ttl_secs);
+        assert_eq!(
+            Duration::from_millis(s.connect_timeout_ms),
+            b.connect_timeout
+        );
+        assert_eq!(
+            Duration::from_millis(s.request_timeout_ms),
+            b.request_timeout
+        );
+        assert_eq!(
+            Duration::from_millis(s.upgrade_handshake_timeout_ms),
+            b.upgrade_handshake_timeout
+        );
+        assert_eq!(
+            Duration::from_millis(s.health_check_timeout_ms),
+            b.health_check_timeout
+        );
+    }
+
+    #[test]
+    fn test_config_parses_request_handling_flags() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "enable_websocket = false\nforward_get_only = true\ncache_404_capacity = 5\nuse_404_meta = true\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let s = config.server.get("default").unwrap();
+        assert!(!s.enable_websocket);
+        assert!(s.forward_get_only);
+        assert_eq!(s.cache_404_capacity, 5);
+        assert!(s.use_404_meta);
+    }
+
     #[test]
     fn test_config_parses_cache_strategy() {
         let config: Config =
@@ -412,6 +1500,19 @@ mod tests {
             toml::from_str(&single_server_toml("compress_strategy = \"gzip\"\n")).unwrap();
         let s = config.server.get("default").unwrap();
         assert_eq!(s.compress_strategy, CompressStrategy::Gzip);
+        assert!(!s.compress_on_serve);
+        assert!(s.compress_content_types.contains(&"text/".to_string()));
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "compress_strategy = \"none\"\ncompress_on_serve = true\n\
+             compress_content_types = [\"text/html\"]\ncompress_min_bytes = 1024\n",
+        ))
+        .unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.compress_strategy, CompressStrategy::None);
+        assert!(s.compress_on_serve);
+        assert_eq!(s.compress_content_types, vec!["text/html".to_string()]);
+        assert_eq!(s.compress_min_bytes, 1024);
     }
 
     #[test]
@@ -425,6 +1526,329 @@ mod tests {
         assert_eq!(s.cache_directory, Some(PathBuf::from("cache-bodies")));
     }
 
+    #[test]
+    fn test_config_parses_render_queue() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "render_queue_patterns = [\"GET /blog/*\"]\nrender_queue_capacity = 5\n",
+        ))
+        .unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.render_queue_patterns, vec!["GET /blog/*".to_string()]);
+        assert_eq!(s.render_queue_capacity, 5);
+        assert_eq!(s.render_queue_path, None);
+    }
+
+    #[test]
+    fn test_config_upgrade_fallback_and_reject_status() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "upgrade_fallback = \"ignore_header\"\nupgrade_reject_status = 426\n",
+        ))
+        .unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.upgrade_fallback, UpgradeFallback::IgnoreHeader);
+        assert_eq!(s.upgrade_reject_status, 426);
+        assert!(config.validate().is_ok());
+
+        let config: Config =
+            toml::from_str(&single_server_toml("upgrade_reject_status = 200\n")).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_accept_mismatch() {
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.accept_mismatch, AcceptMismatch::Ignore);
+
+        let config: Config =
+            toml::from_str(&single_server_toml("accept_mismatch = \"bypass\"\n")).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.accept_mismatch, AcceptMismatch::Bypass);
+    }
+
+    #[test]
+    fn test_config_error_policies() {
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.cache_error_policy, CacheErrorPolicy::Bypass);
+        assert_eq!(s.backend_error_policy, BackendErrorPolicy::Fail);
+        assert!(!s.stale_on_error_status);
+        assert_eq!(s.stale_max_age_secs, 86_400);
+        assert_eq!(s.client_disconnect, ClientDisconnect::Abort);
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "cache_error_policy = \"fail\"\nbackend_error_policy = \"stale_if_available\"\n\
+             stale_on_error_status = true\nclient_disconnect = \"complete_cacheable\"\n",
+        ))
+        .unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.cache_error_policy, CacheErrorPolicy::Fail);
+        assert_eq!(s.backend_error_policy, BackendErrorPolicy::StaleIfAvailable);
+        assert!(s.stale_on_error_status);
+        config.validate().unwrap();
+
+        // Expired entries would be kept forever.
+        let config: Config = toml::from_str(&single_server_toml(
+            "backend_error_policy = \"stale_if_available\"\nstale_max_age_secs = 0\n",
+        ))
+        .unwrap();
+        assert!(config.validate().is_err());
+        assert_eq!(s.client_disconnect, ClientDisconnect::CompleteCacheable);
+        assert!(toml::from_str::<Config>(&single_server_toml(
+            "backend_error_policy = \"stale\"\n"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_config_upstream_pool() {
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.pool_max_idle_per_host, None);
+        assert_eq!(s.connect_timeout_ms, 5000);
+        assert_eq!(s.request_timeout_ms, 30_000);
+        assert_eq!(s.upgrade_handshake_timeout_ms, 10_000);
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "pool_max_idle_per_host = 8\nconnect_timeout_ms = 250\nrequest_timeout_ms = 1500\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.pool_max_idle_per_host, Some(8));
+        assert_eq!(s.connect_timeout_ms, 250);
+        assert_eq!(s.request_timeout_ms, 1500);
+
+        for invalid in [
+            "connect_timeout_ms = 0\n",
+            "request_timeout_ms = 0\n",
+            "upgrade_handshake_timeout_ms = 0\n",
+            "health_check_timeout_ms = 0\n",
+            "health_check_path = \"up\"\n",
+            "health_check_interval_secs = 5\n",
+        ] {
+            let config: Config = toml::from_str(&single_server_toml(invalid)).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_config_bypass_and_purge_headers() {
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        assert_eq!(config.server["default"].purge_header, None);
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "bypass_header = \"x-phantom-bypass\"\npurge_header = \"x-phantom-purge\"\n\
+             purge_token = \"secret\"\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+
+        for invalid in [
+            "bypass_header = \"x phantom bypass\"\n",
+            "purge_header = \"x-phantom-purge:\"\npurge_token = \"secret\"\n",
+            "purge_header = \"x-phantom-purge\"\n",
+        ] {
+            let config: Config = toml::from_str(&single_server_toml(invalid)).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_config_location_base_url() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "rewrite_location = true\nlocation_base_url = \"https://www.example.com\"\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "location_base_url = \"www.example.com\"\n",
+        ))
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_path_prefix() {
+        let config: Config =
+            toml::from_str(&single_server_toml("path_prefix = \"/app\"\n")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.server["default"].path_prefix.as_deref(),
+            Some("/app")
+        );
+
+        let config: Config =
+            toml::from_str(&single_server_toml("path_prefix = \"app\"\n")).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_persistence_paths_are_not_shared() {
+        let config: Config = toml::from_str(
+            "[server.a]\npersistence_path = \"a.snapshot\"\n\
+             [server.b]\npersistence_path = \"b.snapshot\"\npersistence_interval_secs = 0\n\
+             format_mismatch_policy = \"discard\"\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.server["a"].persistence_interval_secs, 60);
+        assert_eq!(config.server["b"].persistence_interval_secs, 0);
+        assert_eq!(
+            config.server["a"].format_mismatch_policy,
+            FormatMismatchPolicy::Migrate
+        );
+        assert_eq!(
+            config.server["b"].format_mismatch_policy,
+            FormatMismatchPolicy::Discard
+        );
+
+        let config: Config = toml::from_str(
+            "[server.a]\npersistence_path = \"cache.snapshot\"\n\
+             [server.b]\npersistence_path = \"cache.snapshot\"\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_redaction_lists() {
+        let config: Config = toml::from_str(&single_server_toml("")).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert!(s.redact_query_params.contains(&"token".to_string()));
+        assert!(s.redact_headers.contains(&"authorization".to_string()));
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "redact_query_params = [\"session\"]\nredact_headers = []\n",
+        ))
+        .unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.redact_query_params, vec!["session".to_string()]);
+        assert!(s.redact_headers.is_empty());
+    }
+
+    #[test]
+    fn test_config_html_normalize_rules() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "html_normalize_paths = [\"/blog/*\"]\n\
+             html_normalize_rules = [\n\
+               { pattern = '<!-- rendered at [^>]* -->' },\n\
+               { pattern = 'content=\"[^\"]*\"', replace = 'content=\"\"' },\n\
+             ]\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(s.html_normalize_paths, vec!["/blog/*".to_string()]);
+        assert_eq!(
+            s.html_normalize_rules,
+            vec![
+                HtmlNormalizeRule::new("<!-- rendered at [^>]* -->", ""),
+                HtmlNormalizeRule::new("content=\"[^\"]*\"", "content=\"\""),
+            ]
+        );
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "html_normalize_rules = [{ pattern = '(' }]\n",
+        ))
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_cache_rules() {
+        let config: Config = toml::from_str(&single_server_toml(
+            "[[server.default.rules]]\n\
+             pattern = \"/account/*\"\n\
+             cache = false\n\
+             [[server.default.rules]]\n\
+             pattern = \"/api/*\"\n\
+             ttl_secs = 30\n\
+             key_headers = [\"X-Tenant\"]\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.server["default"].cache_rules,
+            vec![
+                CacheRule::never("/account/*"),
+                CacheRule::new("/api/*")
+                    .with_ttl_secs(30)
+                    .with_key_headers(vec!["X-Tenant".to_string()]),
+            ]
+        );
+
+        for invalid in [
+            "cache_rules = [{ pattern = \"api/*\" }]\n",
+            "cache_rules = [{ pattern = \"/api/*\", ttl_secs = 0 }]\n",
+            "cache_rules = [{ pattern = \"/api/*\", key_headers = [\"bad header\"] }]\n",
+            "cache_key_headers = [\"bad header\"]\n",
+        ] {
+            let config: Config = toml::from_str(&single_server_toml(invalid)).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_config_body_cache_rules() {
+        use crate::body_cache::{BodyKey, BodyMatch};
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "body_cache_rules = [\n\
+               { pattern = \"POST /graphql\", key = \"graphql\", ttl_secs = 5, skip_if = [\n\
+                 { pointer = \"/query\", contains = \"mutation\" },\n\
+               ] },\n\
+               { pattern = \"POST /search\" },\n\
+             ]\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(
+            s.body_cache_rules,
+            vec![
+                BodyCacheRule::new("POST /graphql", BodyKey::Graphql)
+                    .with_ttl_secs(5)
+                    .with_skip_if(BodyMatch::new(Some("/query"), "mutation")),
+                BodyCacheRule::new("POST /search", BodyKey::Hash),
+            ]
+        );
+
+        let config: Config = toml::from_str(&single_server_toml(
+            "body_cache_rules = [{ pattern = \"POST /q\", ttl_secs = 0 }]\n",
+        ))
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_upstream_auth_client_credentials() {
+        use crate::upstream_auth::UpstreamAuthConfig;
+
+        let toml = single_server_toml("")
+            + "[server.default.upstream_auth]\n\
+               type = \"client_credentials\"\n\
+               token_url = \"https://auth.example.com/oauth/token\"\n\
+               client_id = \"phantom\"\n\
+               client_secret = \"s3cret\"\n\
+               scope = \"api.read\"\n";
+        let config: Config = toml::from_str(&toml).unwrap();
+        let s = config.server.get("default").unwrap();
+        assert_eq!(
+            s.upstream_auth,
+            Some(UpstreamAuthConfig::ClientCredentials {
+                token_url: "https://auth.example.com/oauth/token".to_string(),
+                client_id: "phantom".to_string(),
+                client_secret: "s3cret".to_string(),
+                scope: Some("api.read".to_string()),
+                refresh_before_expiry_secs: 60,
+            })
+        );
+        // The secret stays out of debug output, e.g. startup logs.
+        assert!(!format!("{:?}", s).contains("s3cret"));
+    }
+
     #[test]
     fn test_config_top_level_ports() {
         let toml = "http_port = 8080\ncontrol_port = 9000\n".to_string() + &single_server_toml("");
@@ -432,6 +1856,176 @@ mod tests {
         assert_eq!(config.http_port, 8080);
         assert_eq!(config.control_port, 9000);
         assert_eq!(config.https_port, None);
+        assert_eq!(config.drain_timeout_secs, 30);
+
+        let toml = "drain_timeout_secs = 5\n".to_string() + &single_server_toml("");
+        let config: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(config.drain_timeout_secs, 5);
+    }
+
+    fn validation_error(toml: &str) -> String {
+        let config: Config = toml::from_str(toml).unwrap();
+        format!("{:#}", config.validate().unwrap_err())
+    }
+
+    #[test]
+    fn test_validation_rejects_unusable_proxy_urls() {
+        for proxy_url in [
+            "localhost:8080",
+            "ftp://example.com",
+            "http://",
+            "/relative",
+        ] {
+            let err = validation_error(&format!(
+                "[server.default]\nproxy_url = \"{}\"\n",
+                proxy_url
+            ));
+            assert!(err.contains("`proxy_url`"), "{proxy_url}: {err}");
+        }
+        let err = validation_error("[server.default]\nproxy_url = \"localhost:8080\"\n");
+        assert!(
+            err.contains("did you mean 'http://localhost:8080'?"),
+            "{err}"
+        );
+
+        for proxy_url in ["http://localhost:8080", "https://[::1]:8443/base"] {
+            let toml = format!("[server.default]\nproxy_url = \"{}\"\n", proxy_url);
+            let config: Config = toml::from_str(&toml).unwrap();
+            config.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validation_rejects_invalid_regex_patterns() {
+        let err = validation_error(&single_server_toml(
+            "include_paths = [\"GET re:^/products/\\\\d+$\", \"re:^/blog/(\"]\n\
+             render_queue_patterns = [\"re:[\"]\n",
+        ));
+        assert!(err.contains("2 problems"), "{err}");
+        assert!(
+            err.contains("include_paths[1] \"re:^/blog/(\": invalid regex"),
+            "{err}"
+        );
+        assert!(
+            err.contains("render_queue_patterns[0] \"re:[\": invalid regex"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_path_rules_are_validated_and_exclusive() {
+        let config: Config =
+            toml::from_str("[server.default]\npath_rules = [\"/api/*\", \"!/api/internal/*\"]\n")
+                .unwrap();
+        config.validate().unwrap();
+
+        let err = validation_error(&single_server_toml(
+            "path_rules = [\"/api/*\", \"!api/internal/*\"]\ninclude_paths = [\"/api/*\"]\n",
+        ));
+        assert!(
+            err.contains("path_rules[1] \"!api/internal/*\": path must start with '/'"),
+            "{err}"
+        );
+        assert!(err.contains("`path_rules` cannot be combined"), "{err}");
+    }
+
+    #[test]
+    fn test_backends_replace_proxy_url_and_are_validated() {
+        let toml = "[server.default]\nbackends = [\"http://a:1\", \"http://b:1\"]\n\
+                    load_balancing = \"least_connections\"\n";
+        let config: Config = toml::from_str(toml).unwrap();
+        config.validate().unwrap();
+        let server = &config.server["default"];
+        assert_eq!(server.backend_urls(), ["http://a:1", "http://b:1"]);
+        assert_eq!(server.load_balancing, LoadBalancing::LeastConnections);
+
+        let err = validation_error("[server.default]\nbackends = [\"http://a:1\", \"b:1\"]\n");
+        assert!(
+            err.contains("`backends[1]` 'b:1' is not an http(s) URL"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_validation_rejects_clashing_ports() {
+        let err = validation_error(&format!(
+            "http_port = 8080\ncontrol_port = 8080\n{}",
+            single_server_toml("")
+        ));
+        assert!(
+            err.contains("`http_port` and `control_port` are both 8080"),
+            "{err}"
+        );
+
+        let err = validation_error(&format!(
+            "https_port = 3000\ncert_path = \"c.pem\"\nkey_path = \"k.pem\"\n{}",
+            single_server_toml("")
+        ));
+        assert!(
+            err.contains("`http_port` and `https_port` are both 3000"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_validation_rejects_malformed_path_patterns() {
+        let err = validation_error(&single_server_toml("include_paths = [\"/blog/*\", \"\"]\n"));
+        assert!(
+            err.contains("include_paths[1] \"\": pattern is empty"),
+            "{err}"
+        );
+
+        let err = validation_error(&single_server_toml("exclude_paths = [\"admin/*\"]\n"));
+        assert!(err.contains("exclude_paths[0] \"admin/*\""), "{err}");
+    }
+
+    #[test]
+    fn test_validation_rejects_out_of_range_numbers() {
+        for (extra, field) in [
+            (
+                "cache_errors_ttl_secs = 10\ncache_errors_capacity = 0\n",
+                "cache_errors_capacity",
+            ),
+            (
+                "render_queue_patterns = [\"/*\"]\nrender_queue_capacity = 0\n",
+                "render_queue_capacity",
+            ),
+            ("stats_window_minutes = 0\n", "stats_window_minutes"),
+            ("stats_window_minutes = 20000\n", "stats_window_minutes"),
+        ] {
+            let err = validation_error(&single_server_toml(extra));
+            assert!(err.contains(&format!("`{}`", field)), "{extra}: {err}");
+        }
+        // A zero capacity is fine where the store is unused or disabled.
+        let config: Config = toml::from_str(&single_server_toml(
+            "cache_404_capacity = 0\ncache_errors_capacity = 0\nrender_queue_capacity = 0\n",
+        ))
+        .unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validation_lists_every_problem() {
+        let err = validation_error(
+            "http_port = 9000\ncontrol_port = 9000\n\
+             [server.b]\nbind_to = \"/b\"\nproxy_url = \"localhost:1\"\nconnect_timeout_ms = 0\n\
+             [server.a]\nproxy_url = \"http://localhost:2\"\ninclude_paths = [\"\"]\n",
+        );
+        assert!(
+            err.starts_with("4 problems in the configuration:\n"),
+            "{err}"
+        );
+        let lines: Vec<&str> = err.lines().skip(1).collect();
+        assert!(lines[0].contains("`http_port` and `control_port`"), "{err}");
+        assert!(
+            lines[1].starts_with("  - server 'a': include_paths[0]"),
+            "{err}"
+        );
+        assert!(lines[2].starts_with("  - server 'b': `proxy_url`"), "{err}");
+        assert!(
+            lines[3].starts_with("  - server 'b': `connect_timeout_ms`"),
+            "{err}"
+        );
     }
 
     #[test]
@@ -441,6 +2035,24 @@ mod tests {
         assert!(config.validate().is_err());
     }
 
+    #[test]
+    fn test_tls_paths_accept_aliases_and_control_needs_both() {
+        let toml = "https_port = 443\ntls_cert_path = \"c.pem\"\ntls_key_path = \"k.pem\"\n\
+                    control_cert_path = \"cc.pem\"\ncontrol_key_path = \"ck.pem\"\n"
+            .to_string()
+            + &single_server_toml("");
+        let config: Config = toml::from_str(&toml).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.cert_path, Some(PathBuf::from("c.pem")));
+        assert_eq!(config.key_path, Some(PathBuf::from("k.pem")));
+
+        let err = validation_error(&format!(
+            "control_cert_path = \"cc.pem\"\n{}",
+            single_server_toml("")
+        ));
+        assert!(err.contains("`control_key_path`"), "{err}");
+    }
+
     #[test]
     fn test_multiple_servers() {
         let toml = "[server.frontend]\nbind_to = \"*\"\nproxy_url = \"http://localhost:5173\"\n\
@@ -503,6 +2115,76 @@ mod tests {
         std::env::remove_var("_PF_TEST_HTTP_PORT");
     }
 
+    // ── PHANTOM_* override tests ─────────────────────────────────────────────
+
+    fn env_vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_env_overrides_take_precedence_over_file_values() {
+        let toml = format!(
+            "http_port = 8080
+control_auth = \"from-file\"\n{}",
+            single_server_toml("include_paths = [\"/blog/*\"]\n")
+        );
+        let mut raw: toml::Value = toml::from_str(&toml).unwrap();
+        apply_env_overrides(
+            &mut raw,
+            env_vars(&[
+                ("PHANTOM_PROXY_URL", "http://app:5173"),
+                ("PHANTOM_PROXY_PORT", "80"),
+                ("PHANTOM_CONTROL_AUTH", "from-env"),
+                ("PHANTOM_INCLUDE_PATHS", "/, /static/* ,"),
+            ]),
+        )
+        .unwrap();
+        let config: Config = raw.try_into().unwrap();
+
+        assert_eq!(config.http_port, 80);
+        assert_eq!(config.control_port, 17809);
+        assert_eq!(config.control_auth.as_deref(), Some("from-env"));
+        let s = &config.server["default"];
+        assert_eq!(s.proxy_url, "http://app:5173");
+        assert_eq!(s.include_paths, vec!["/", "/static/*"]);
+    }
+
+    #[test]
+    fn test_env_overrides_reject_bad_ports_and_ambiguous_servers() {
+        let mut raw: toml::Value = toml::from_str(&single_server_toml("")).unwrap();
+        let err = apply_env_overrides(&mut raw, env_vars(&[("PHANTOM_CONTROL_PORT", "abc")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("`PHANTOM_CONTROL_PORT`"), "{err}");
+
+        let toml = "[server.a]\nbind_to = \"/a\"\n[server.b]\n";
+        let mut raw: toml::Value = toml::from_str(toml).unwrap();
+        let err =
+            apply_env_overrides(&mut raw, env_vars(&[("PHANTOM_INCLUDE_PATHS", "/")])).unwrap_err();
+        assert!(err.to_string().contains("`PHANTOM_INCLUDE_PATHS`"), "{err}");
+        // Global overrides still work with several servers.
+        apply_env_overrides(&mut raw, env_vars(&[("PHANTOM_PROXY_PORT", "8080")])).unwrap();
+    }
+
+    #[test]
+    fn test_config_from_env_vars_without_a_file() {
+        assert!(Config::from_env_vars(env_vars(&[("PHANTOM_CONTROL_PORT", "9000")])).is_err());
+
+        let config = Config::from_env_vars(env_vars(&[
+            ("PHANTOM_PROXY_URL", "http://app:3000"),
+            ("PHANTOM_CONTROL_PORT", "9000"),
+        ]))
+        .unwrap();
+        assert_eq!(config.http_port, 3000);
+        assert_eq!(config.control_port, 9000);
+        assert_eq!(config.server.len(), 1);
+        assert_eq!(config.server["default"].proxy_url, "http://app:3000");
+        assert_eq!(config.server["default"].bind_to, "*");
+    }
+
     // ── dotenv config deserialization tests ──────────────────────────────────
 
     #[test]
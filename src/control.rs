@@ -1,55 +1,111 @@
-use crate::cache::CacheHandle;
+use crate::body_preview::{full_body, view_cached, BodySelection, BodyView, BodyViewQuery};
+use crate::cache::{CacheHandle, CacheStats};
+use crate::path_matcher::PathPatterns;
+use crate::persistence::PersistenceStats;
+use crate::redact::REDACTED;
+use crate::render_queue::{CompleteOutcome, RenderLease, DEFAULT_LEASE};
+#[cfg(feature = "metrics")]
+use crate::stats::{render_prometheus, ServerMetrics};
+use crate::stats::{LatencySummary, OutcomeCounts, PolicyCounts};
+use crate::status_page::{self, ServerStatus};
+use crate::warm::{WarmOutcome, WarmResult};
+use crate::ProxyHandle;
 use axum::{
-    extract::State,
+    extract::{Query, State},
     http::{header, HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::post,
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::task::JoinHandle;
 
 #[derive(Clone)]
 pub struct ControlState {
     /// Named server handles — (server_name, handle) pairs.
-    handles: Vec<(String, CacheHandle)>,
+    handles: Vec<(String, ProxyHandle)>,
     auth_token: Option<String>,
+    /// Serve `GET /metrics` without the token.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    public_metrics: bool,
+    /// Set while the proxy listener accepts requests; `None` counts as set.
+    listener_ready: Option<Arc<AtomicBool>>,
 }
 
 impl ControlState {
-    pub fn new(handles: Vec<(String, CacheHandle)>, auth_token: Option<String>) -> Self {
+    pub fn new(handles: Vec<(String, ProxyHandle)>, auth_token: Option<String>) -> Self {
         Self {
             handles,
             auth_token,
+            public_metrics: false,
+            listener_ready: None,
         }
     }
 
+    /// Let `GET /metrics` answer without the bearer token, for scrapers that
+    /// cannot send one. Every other route still requires it.
+    pub fn with_public_metrics(mut self, enabled: bool) -> Self {
+        self.public_metrics = enabled;
+        self
+    }
+
+    /// Report `GET /readyz` as not ready while `ready` is unset, e.g. until
+    /// the proxy listener is bound and again once shutdown begins.
+    pub fn with_listener_ready(mut self, ready: Arc<AtomicBool>) -> Self {
+        self.listener_ready = Some(ready);
+        self
+    }
+
+    /// Return proxies matching `server` (if provided) or all proxies.
+    /// Returns `Err` when a name was given but no server matched.
+    fn resolve_proxies(
+        &self,
+        server: Option<&str>,
+    ) -> Result<Vec<(&str, &ProxyHandle)>, (StatusCode, String)> {
+        let matched: Vec<(&str, &ProxyHandle)> = self
+            .handles
+            .iter()
+            .filter(|(n, _)| server.is_none_or(|name| n == name))
+            .map(|(n, h)| (n.as_str(), h))
+            .collect();
+
+        match server {
+            Some(name) if matched.is_empty() => Err((
+                StatusCode::NOT_FOUND,
+                format!("No server named '{}' found", name),
+            )),
+            _ => Ok(matched),
+        }
+    }
+
+    /// Redact `target` for a log line about the servers matching `server`.
+    /// Every matching server's list applies, so a value any of them hides
+    /// stays hidden.
+    fn redact(&self, server: Option<&str>, target: &str) -> String {
+        self.handles
+            .iter()
+            .filter(|(n, _)| server.is_none_or(|name| n == name))
+            .fold(target.to_string(), |target, (_, handle)| {
+                handle.redactor().target(&target).into_owned()
+            })
+    }
+
     /// Return handles matching `server` (if provided) or all handles.
     /// Returns `Err` when a name was given but no server matched.
     fn resolve_handles(
         &self,
         server: Option<&str>,
     ) -> Result<Vec<&CacheHandle>, (StatusCode, String)> {
-        match server {
-            None => Ok(self.handles.iter().map(|(_, h)| h).collect()),
-            Some(name) => {
-                let matched: Vec<&CacheHandle> = self
-                    .handles
-                    .iter()
-                    .filter(|(n, _)| n == name)
-                    .map(|(_, h)| h)
-                    .collect();
-                if matched.is_empty() {
-                    Err((
-                        StatusCode::NOT_FOUND,
-                        format!("No server named '{}' found", name),
-                    ))
-                } else {
-                    Ok(matched)
-                }
-            }
-        }
+        Ok(self
+            .resolve_proxies(server)?
+            .into_iter()
+            .map(|(_, h)| h.cache_handle())
+            .collect())
     }
 
     /// Like `resolve_handles`, but for snapshot operations:
@@ -66,8 +122,8 @@ impl ControlState {
                 let handles: Vec<&CacheHandle> = self
                     .handles
                     .iter()
-                    .filter(|(_, h)| h.is_snapshot_capable())
-                    .map(|(_, h)| h)
+                    .map(|(_, h)| h.cache_handle())
+                    .filter(|h| h.is_snapshot_capable())
                     .collect();
                 if handles.is_empty() {
                     return Err((
@@ -77,22 +133,7 @@ impl ControlState {
                 }
                 Ok(handles)
             }
-            Some(name) => {
-                let matched: Vec<&CacheHandle> = self
-                    .handles
-                    .iter()
-                    .filter(|(n, _)| n == name)
-                    .map(|(_, h)| h)
-                    .collect();
-                if matched.is_empty() {
-                    Err((
-                        StatusCode::NOT_FOUND,
-                        format!("No server named '{}' found", name),
-                    ))
-                } else {
-                    Ok(matched)
-                }
-            }
+            Some(name) => self.resolve_handles(Some(name)),
         }
     }
 }
@@ -104,6 +145,13 @@ struct PatternBody {
     server: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct KeyBody {
+    key: String,
+    /// Optional: only invalidate this named server's cache.
+    server: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct PathBody {
     path: String,
@@ -132,6 +180,9 @@ struct BulkOperationItemResult {
     item: String,
     success: bool,
     error: Option<String>,
+    /// Entries removed, for invalidations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purged: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -141,9 +192,19 @@ struct BulkOperationResponse {
     requested: usize,
     succeeded: usize,
     failed: usize,
+    /// Total entries removed, for invalidations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purged: Option<usize>,
     results: Vec<BulkOperationItemResult>,
 }
 
+/// Response of the single invalidation routes.
+#[derive(Serialize)]
+struct PurgeResult {
+    /// Entries removed across the servers purged.
+    purged: usize,
+}
+
 #[derive(Clone, Copy)]
 enum BulkSnapshotAction {
     Add,
@@ -154,11 +215,11 @@ enum BulkSnapshotAction {
 /// Returns `Err(UNAUTHORIZED)` when the request lacks a valid Bearer token.
 fn check_auth(state: &ControlState, headers: &HeaderMap) -> Result<(), StatusCode> {
     if let Some(required_token) = &state.auth_token {
-        let auth_header = headers
+        let token = headers
             .get(header::AUTHORIZATION)
-            .and_then(|h| h.to_str().ok());
-        let expected = format!("Bearer {}", required_token);
-        if auth_header != Some(expected.as_str()) {
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| tokens_match(token, required_token)) {
             tracing::warn!("Unauthorized control endpoint attempt");
             return Err(StatusCode::UNAUTHORIZED);
         }
@@ -166,6 +227,56 @@ fn check_auth(state: &ControlState, headers: &HeaderMap) -> Result<(), StatusCod
     Ok(())
 }
 
+/// Like [`check_auth`], for pages opened in a browser, which cannot attach a
+/// bearer token: the token is also accepted as `?token=` or as the password
+/// of Basic auth (any user name).
+fn check_page_auth(
+    state: &ControlState,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<(), StatusCode> {
+    let Some(required_token) = &state.auth_token else {
+        return Ok(());
+    };
+    let basic_password = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|encoded| {
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+        })
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(_, password)| password.to_string())
+        });
+    if [query_token, basic_password.as_deref()]
+        .into_iter()
+        .flatten()
+        .any(|token| tokens_match(token, required_token))
+    {
+        return Ok(());
+    }
+    check_auth(state, headers)
+}
+
+/// Compare tokens in time independent of where they differ, so response
+/// timing cannot be used to guess the token byte by byte. Hashing first also
+/// hides its length.
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = ring::digest::digest(&ring::digest::SHA256, given.as_bytes());
+    let expected = ring::digest::digest(&ring::digest::SHA256, expected.as_bytes());
+    given
+        .as_ref()
+        .iter()
+        .zip(expected.as_ref())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
 fn validate_bulk_items<T>(items: &[T], field_name: &str) -> Result<(), (StatusCode, String)> {
     if items.is_empty() {
         return Err((
@@ -176,6 +287,18 @@ fn validate_bulk_items<T>(items: &[T], field_name: &str) -> Result<(), (StatusCo
     Ok(())
 }
 
+/// Reject an empty or blank pattern or key, which would purge nothing (or,
+/// for a pattern, nothing the caller meant).
+fn validate_target(value: &str, field_name: &str) -> Result<(), (StatusCode, String)> {
+    if value.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("'{}' must not be empty", field_name),
+        ));
+    }
+    Ok(())
+}
+
 fn bulk_response(
     operation: &'static str,
     server: Option<String>,
@@ -184,6 +307,10 @@ fn bulk_response(
     let requested = results.len();
     let succeeded = results.iter().filter(|result| result.success).count();
     let failed = requested - succeeded;
+    let purged = results
+        .iter()
+        .filter_map(|result| result.purged)
+        .reduce(|total, purged| total + purged);
 
     (
         StatusCode::OK,
@@ -193,6 +320,7 @@ fn bulk_response(
             requested,
             succeeded,
             failed,
+            purged,
             results,
         }),
     )
@@ -216,6 +344,7 @@ async fn run_bulk_snapshot_operation(
                     item: path,
                     success: error.is_none(),
                     error,
+                    purged: None,
                 }
             })
         })
@@ -232,6 +361,7 @@ async fn run_bulk_snapshot_operation(
                     item: "<unknown>".to_string(),
                     success: false,
                     error: Some("bulk snapshot task failed".to_string()),
+                    purged: None,
                 });
             }
         }
@@ -261,20 +391,93 @@ async fn run_snapshot_operation_for_path(
 }
 
 /// POST /invalidate_all — invalidate every cached entry across all servers.
+///
+/// Purges run before the response is sent, so it reports what they removed:
+/// `{ "purged": 128 }`. Each is also broadcast on the server's `CacheHandle`,
+/// so proxies sharing it and its subscribers see it too. The same holds for
+/// the other invalidation routes.
 async fn invalidate_all_handler(
     State(state): State<Arc<ControlState>>,
     headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
     check_auth(&state, &headers)?;
 
+    let mut purged = 0;
     for (_, handle) in &state.handles {
-        handle.invalidate_all();
+        purged += handle.cache().clear().await;
+        handle.cache_handle().invalidate_all();
     }
     tracing::info!(
-        "invalidate_all triggered via control endpoint ({} server(s))",
+        "invalidate_all via control endpoint purged {} entries ({} server(s))",
+        purged,
         state.handles.len()
     );
-    Ok((StatusCode::OK, "Cache invalidated"))
+    Ok(Json(PurgeResult { purged }))
+}
+
+#[derive(Deserialize)]
+struct ServerBody {
+    /// Optional: only apply to this named server.
+    server: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PauseResult {
+    paused: bool,
+    /// The servers whose state changed.
+    changed: Vec<String>,
+}
+
+/// Pause or resume caching on the servers `body` names, or on all of them.
+fn set_caching_paused(
+    state: &ControlState,
+    body: Option<Json<ServerBody>>,
+    paused: bool,
+) -> Result<Json<PauseResult>, (StatusCode, String)> {
+    let server = body.and_then(|Json(body)| body.server);
+    let mut changed = Vec::new();
+    for (name, proxy) in state.resolve_proxies(server.as_deref())? {
+        let was_paused = if paused {
+            proxy.pause_caching()
+        } else {
+            proxy.resume_caching()
+        };
+        if was_paused != paused {
+            changed.push(name.to_string());
+        }
+    }
+    tracing::warn!(
+        "caching {} via control endpoint (server={:?}, changed={:?})",
+        if paused { "paused" } else { "resumed" },
+        server,
+        changed
+    );
+    Ok(Json(PauseResult { paused, changed }))
+}
+
+/// POST /cache/pause — proxy every request straight to the backend, without
+/// cache lookups or stores, until `POST /cache/resume`. Entries are kept.
+///
+/// Optional body: `{ "server": "frontend" }`
+async fn pause_cache_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    body: Option<Json<ServerBody>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    set_caching_paused(&state, body, true)
+}
+
+/// POST /cache/resume — use the cache again after `POST /cache/pause`.
+///
+/// Optional body: `{ "server": "frontend" }`
+async fn resume_cache_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    body: Option<Json<ServerBody>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    set_caching_paused(&state, body, false)
 }
 
 /// POST /invalidate — invalidate entries matching a wildcard pattern.
@@ -286,17 +489,46 @@ async fn invalidate_handler(
     Json(body): Json<PatternBody>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    validate_target(&body.pattern, "pattern")?;
 
-    let handles = state.resolve_handles(body.server.as_deref())?;
-    for handle in handles {
-        handle.invalidate(&body.pattern);
+    let mut purged = 0;
+    for (_, proxy) in state.resolve_proxies(body.server.as_deref())? {
+        purged += proxy.cache().clear_by_pattern(&body.pattern).await;
+        proxy.cache_handle().invalidate(&body.pattern);
+    }
+    tracing::info!(
+        "invalidate('{}') via control endpoint purged {} entries (server={:?})",
+        state.redact(body.server.as_deref(), &body.pattern),
+        purged,
+        body.server
+    );
+    Ok(Json(PurgeResult { purged }))
+}
+
+/// DELETE /cache/key — invalidate the entry under exactly one cache key and
+/// its `Vary` variants; a `*` in the key is not a wildcard.
+///
+/// Body: `{ "key": "GET:/products/42" }` or `{ "key": "GET:/products/42", "server": "frontend" }`
+async fn delete_cache_key_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Json(body): Json<KeyBody>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    validate_target(&body.key, "key")?;
+
+    let mut purged = 0;
+    for (_, proxy) in state.resolve_proxies(body.server.as_deref())? {
+        purged += proxy.cache().clear_key(&body.key).await;
+        proxy.cache_handle().invalidate_key(&body.key);
     }
     tracing::info!(
-        "invalidate('{}') triggered via control endpoint (server={:?})",
-        body.pattern,
+        "invalidate_key('{}') via control endpoint purged {} entries (server={:?})",
+        state.redact(body.server.as_deref(), &body.key),
+        purged,
         body.server
     );
-    Ok((StatusCode::OK, "Pattern invalidation triggered".to_string()))
+    Ok(Json(PurgeResult { purged }))
 }
 
 /// POST /bulk_invalidate — invalidate entries matching multiple wildcard patterns.
@@ -310,19 +542,25 @@ async fn bulk_invalidate_handler(
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
     validate_bulk_items(&body.patterns, "patterns")?;
+    for pattern in &body.patterns {
+        validate_target(pattern, "patterns")?;
+    }
 
-    let handles = state.resolve_handles(body.server.as_deref())?;
+    let proxies = state.resolve_proxies(body.server.as_deref())?;
     let mut results = Vec::with_capacity(body.patterns.len());
 
     for pattern in &body.patterns {
-        for handle in &handles {
-            handle.invalidate(pattern);
+        let mut purged = 0;
+        for (_, proxy) in &proxies {
+            purged += proxy.cache().clear_by_pattern(pattern).await;
+            proxy.cache_handle().invalidate(pattern);
         }
 
         results.push(BulkOperationItemResult {
             item: pattern.clone(),
             success: true,
             error: None,
+            purged: Some(purged),
         });
     }
 
@@ -355,7 +593,7 @@ async fn add_snapshot_handler(
     }
     tracing::info!(
         "add_snapshot('{}') triggered via control endpoint (server={:?})",
-        body.path,
+        state.redact(body.server.as_deref(), &body.path),
         body.server
     );
     Ok((StatusCode::OK, "Snapshot added".to_string()))
@@ -406,7 +644,7 @@ async fn refresh_snapshot_handler(
     }
     tracing::info!(
         "refresh_snapshot('{}') triggered via control endpoint (server={:?})",
-        body.path,
+        state.redact(body.server.as_deref(), &body.path),
         body.server
     );
     Ok((StatusCode::OK, "Snapshot refreshed".to_string()))
@@ -458,7 +696,7 @@ async fn remove_snapshot_handler(
     }
     tracing::info!(
         "remove_snapshot('{}') triggered via control endpoint (server={:?})",
-        body.path,
+        state.redact(body.server.as_deref(), &body.path),
         body.server
     );
     Ok((StatusCode::OK, "Snapshot removed".to_string()))
@@ -521,31 +759,2054 @@ async fn refresh_all_snapshots_handler(
     Ok((StatusCode::OK, "All snapshots refreshed".to_string()))
 }
 
-/// Create the control server router.
+#[derive(Deserialize)]
+struct RenderQueueQuery {
+    /// Maximum number of jobs to claim (default: 10).
+    limit: Option<usize>,
+    /// Lease duration in seconds (default: 60).
+    lease_secs: Option<u64>,
+    /// Optional: only claim jobs from this named server.
+    server: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ClaimedRender {
+    server: String,
+    #[serde(flatten)]
+    lease: RenderLease,
+}
+
+#[derive(Deserialize)]
+struct CacheEntryBody {
+    key: String,
+    #[serde(default = "default_entry_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: String,
+    /// Lease returned by `GET /render-queue`; when given it must still be active.
+    lease_id: Option<String>,
+    /// Optional: only store into this named server's cache.
+    server: Option<String>,
+}
+
+fn default_entry_status() -> u16 {
+    200
+}
+
+#[derive(Serialize)]
+struct CacheEntryResponse {
+    stored: usize,
+    render_completed: bool,
+}
+
+/// GET /render-queue — claim pending render jobs for an external renderer.
 ///
-/// `handles` contains one `(server_name, CacheHandle)` pair per named proxy server.
-pub fn create_control_router(
-    handles: Vec<(String, CacheHandle)>,
-    auth_token: Option<String>,
-) -> Router {
-    let state = Arc::new(ControlState::new(handles, auth_token));
+/// Query: `?limit=10&lease_secs=60&server=frontend`. Claimed jobs are hidden
+/// from other workers until the lease expires or the result is submitted via
+/// `PUT /cache/entry`.
+async fn render_queue_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<RenderQueueQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
 
-    Router::new()
-        .route("/invalidate_all", post(invalidate_all_handler))
-        .route("/invalidate", post(invalidate_handler))
-        .route("/bulk_invalidate", post(bulk_invalidate_handler))
-        .route("/add_snapshot", post(add_snapshot_handler))
-        .route("/bulk_add_snapshot", post(bulk_add_snapshot_handler))
-        .route("/refresh_snapshot", post(refresh_snapshot_handler))
-        .route(
-            "/bulk_refresh_snapshot",
-            post(bulk_refresh_snapshot_handler),
-        )
-        .route("/remove_snapshot", post(remove_snapshot_handler))
-        .route("/bulk_remove_snapshot", post(bulk_remove_snapshot_handler))
-        .route(
-            "/refresh_all_snapshots",
-            post(refresh_all_snapshots_handler),
+    let limit = query.limit.unwrap_or(10);
+    let lease = query
+        .lease_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_LEASE);
+    let mut items = Vec::new();
+
+    for (name, proxy) in state.resolve_proxies(query.server.as_deref())? {
+        let Some(queue) = proxy.render_queue() else {
+            continue;
+        };
+        let remaining = limit.saturating_sub(items.len());
+        if remaining == 0 {
+            break;
+        }
+        items.extend(
+            queue
+                .claim(remaining, lease)
+                .into_iter()
+                .map(|lease| ClaimedRender {
+                    server: name.to_string(),
+                    lease,
+                }),
+        );
+    }
+
+    tracing::debug!("render-queue claimed {} job(s)", items.len());
+    Ok(Json(serde_json::json!({ "items": items })))
+}
+
+/// PUT /cache/entry — store a response under an explicit cache key.
+///
+/// Body: `{ "key": "GET:/about", "status": 200, "headers": {"content-type": "text/html"},
+/// "body": "<html>…</html>", "lease_id": "…", "server": "frontend" }`.
+/// Completes the matching render-queue job when one is pending.
+async fn put_cache_entry_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Json(body): Json<CacheEntryBody>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    if body.key.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'key' must not be empty".to_string(),
+        ));
+    }
+    if StatusCode::from_u16(body.status).is_err() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("'status' {} is not a valid HTTP status code", body.status),
+        ));
+    }
+
+    let proxies = state.resolve_proxies(body.server.as_deref())?;
+    let lease_conflict = |name: &str| {
+        (
+            StatusCode::CONFLICT,
+            format!(
+                "Render job '{}' on server '{}' is leased to another worker",
+                body.key, name
+            ),
         )
-        .with_state(state)
+    };
+
+    // Every lease is checked before any proxy is seeded, and jobs are only
+    // completed once every proxy has its entry, so a conflict or a failed
+    // seed leaves the queues as they were.
+    for (name, proxy) in &proxies {
+        if let Some(queue) = proxy.render_queue() {
+            if queue.check(&body.key, body.lease_id.as_deref()) == CompleteOutcome::LeaseMismatch {
+                return Err(lease_conflict(name));
+            }
+        }
+    }
+    for (_, proxy) in &proxies {
+        proxy
+            .seed(
+                body.key.clone(),
+                body.status,
+                body.headers.clone(),
+                body.body.as_bytes(),
+            )
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    let mut render_completed = false;
+    for (name, proxy) in &proxies {
+        if let Some(queue) = proxy.render_queue() {
+            match queue.complete(&body.key, body.lease_id.as_deref()).await {
+                CompleteOutcome::Completed => render_completed = true,
+                CompleteOutcome::NotQueued => {}
+                // Claimed by another worker since the check.
+                CompleteOutcome::LeaseMismatch => return Err(lease_conflict(name)),
+            }
+        }
+    }
+
+    tracing::info!(
+        "cache entry '{}' stored via control endpoint (server={:?})",
+        state.redact(body.server.as_deref(), &body.key),
+        body.server
+    );
+    Ok(Json(CacheEntryResponse {
+        stored: proxies.len(),
+        render_completed,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CacheEntryQuery {
+    key: String,
+    /// Required when more than one server is configured.
+    server: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CacheEntryView {
+    server: String,
+    key: String,
+    /// `main`, `404`, or `error`.
+    store: &'static str,
+    status: u16,
+    /// Size of the body as stored, so compressed when the entry is.
+    stored_bytes: usize,
+    /// Seconds since the entry was stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_secs: Option<u64>,
+    /// Values of a repeated header are joined with `, `.
+    headers: HashMap<String, String>,
+    /// Digest of the normalized body, for entries that went through HTML
+    /// normalization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<BodyView>,
+}
+
+/// GET /cache/entry — inspect a stored entry without fetching its whole body.
+///
+/// Query: `key=GET:/about`, `server` when more than one server is configured,
+/// and the body view parameters described in [`crate::body_preview`]
+/// (`body=preview|range|hash|none|full`, `max_bytes`, `start`, `end`, `raw`).
+/// Values of redacted headers are masked.
+///
+/// With `body=full` the response is the body itself, under the entry's
+/// `Content-Type`, rather than JSON.
+async fn get_cache_entry_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<CacheEntryQuery>,
+    Query(view): Query<BodyViewQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    let selection = view
+        .selection()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let proxies = state.resolve_proxies(query.server.as_deref())?;
+    let [(name, proxy)] = proxies.as_slice() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'server' is required when more than one server is configured".to_string(),
+        ));
+    };
+
+    let cache = proxy.cache();
+    let found = match cache.get(&query.key).await {
+        Some(cached) => Some((cached, "main")),
+        None => match cache.get_404(&query.key).await {
+            Some(cached) => Some((cached, "404")),
+            None => cache
+                .get_error(&query.key)
+                .await
+                .map(|cached| (cached, "error")),
+        },
+    };
+    let Some((cached, store)) = found else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No cache entry for key '{}'", query.key),
+        ));
+    };
+
+    if selection == BodySelection::Full {
+        let (body, encoding) = full_body(&cached, view.raw)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let mut response = Response::builder().header(
+            header::CONTENT_TYPE,
+            cached
+                .header("content-type")
+                .unwrap_or("application/octet-stream"),
+        );
+        if let Some(encoding) = encoding {
+            response = response.header(header::CONTENT_ENCODING, encoding.as_header_value());
+        }
+        return response
+            .body(axum::body::Body::from(body))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+
+    let body = view_cached(&cached, selection, view.raw)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let age_secs = cached.age().map(|age| age.as_secs());
+    let redactor = proxy.redactor();
+    let mut headers = HashMap::<String, String>::new();
+    for (name, value) in cached.headers {
+        let value = if redactor.is_sensitive_header(&name) {
+            REDACTED.to_string()
+        } else {
+            value
+        };
+        headers
+            .entry(name)
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    Ok(Json(CacheEntryView {
+        server: name.to_string(),
+        key: query.key,
+        store,
+        status: cached.status,
+        stored_bytes: cached.body.len(),
+        age_secs,
+        headers,
+        content_digest: cached.content_digest.clone(),
+        body,
+    })
+    .into_response())
+}
+
+/// Keys listed by `GET /cache/keys` when `limit` is not given, and the most
+/// it may ask for.
+const DEFAULT_KEYS_LIMIT: usize = 100;
+const MAX_KEYS_LIMIT: usize = 10_000;
+
+#[derive(Deserialize)]
+struct CacheKeysQuery {
+    pattern: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    /// Required when more than one server is configured.
+    server: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CacheKeyView {
+    key: String,
+    store: &'static str,
+    status: u16,
+    /// Unix seconds.
+    stored_at: u64,
+    body_bytes: usize,
+}
+
+#[derive(Serialize)]
+struct CacheKeysResponse {
+    server: String,
+    /// Matching entries before `offset` and `limit` apply.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    keys: Vec<CacheKeyView>,
+}
+
+/// GET /cache/keys — list stored entries, sorted by key.
+///
+/// Query: `pattern=GET:/api/*` to list what a purge for it would remove,
+/// `limit` (default 100, at most 10000) and `offset` to page through the
+/// result, and `server` when more than one server is configured.
+async fn get_cache_keys_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<CacheKeysQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let proxies = state.resolve_proxies(query.server.as_deref())?;
+    let [(name, proxy)] = proxies.as_slice() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'server' is required when more than one server is configured".to_string(),
+        ));
+    };
+
+    let keys = match query.pattern.as_deref() {
+        Some(pattern) => proxy.cache().keys_matching(pattern),
+        None => proxy.cache().keys(),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_KEYS_LIMIT)
+        .min(MAX_KEYS_LIMIT);
+    let total = keys.len();
+    let keys = keys
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|info| CacheKeyView {
+            key: info.key,
+            store: info.store,
+            status: info.status,
+            stored_at: info
+                .stored_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            body_bytes: info.body_bytes,
+        })
+        .collect();
+
+    Ok(Json(CacheKeysResponse {
+        server: name.to_string(),
+        total,
+        offset: query.offset,
+        limit,
+        keys,
+    }))
+}
+
+#[derive(Deserialize)]
+struct WarmBody {
+    /// Paths to fetch, each optionally prefixed with a method (`"HEAD /about"`).
+    paths: Vec<String>,
+    /// Optional: only warm this named server's cache.
+    server: Option<String>,
+    /// Override the server's `warm_concurrency`.
+    concurrency: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct WarmItemResult {
+    server: String,
+    #[serde(flatten)]
+    result: WarmResult,
+}
+
+#[derive(Serialize)]
+struct WarmResponse {
+    requested: usize,
+    stored: usize,
+    failed: usize,
+    results: Vec<WarmItemResult>,
+}
+
+/// POST /cache/warm — fetch paths through the proxy so they are cached
+/// before the first visitor asks. Answers once every path is done.
+///
+/// Body: `{ "paths": ["/", "/about", "HEAD /pricing"], "server": "frontend", "concurrency": 8 }`
+async fn warm_cache_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Json(body): Json<WarmBody>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+    validate_bulk_items(&body.paths, "paths")?;
+    for path in &body.paths {
+        validate_target(path, "paths")?;
+    }
+
+    let mut results = Vec::new();
+    for (name, proxy) in state.resolve_proxies(body.server.as_deref())? {
+        let warmed = match body.concurrency {
+            Some(concurrency) => {
+                proxy
+                    .warm_with_concurrency(body.paths.clone(), concurrency)
+                    .await
+            }
+            None => proxy.warm(body.paths.clone()).await,
+        };
+        results.extend(warmed.into_iter().map(|result| WarmItemResult {
+            server: name.to_string(),
+            result,
+        }));
+    }
+
+    let count = |outcome| {
+        results
+            .iter()
+            .filter(|item| item.result.outcome == outcome)
+            .count()
+    };
+    let (stored, failed) = (count(WarmOutcome::Stored), count(WarmOutcome::Failed));
+    tracing::info!(
+        "warm({} paths) via control endpoint stored {} entries, {} failed (server={:?})",
+        body.paths.len(),
+        stored,
+        failed,
+        body.server
+    );
+    Ok(Json(WarmResponse {
+        requested: results.len(),
+        stored,
+        failed,
+        results,
+    }))
+}
+
+#[derive(Deserialize)]
+struct BackendBody {
+    proxy_url: Option<String>,
+    /// Several backends to spread requests over, instead of `proxy_url`.
+    #[serde(default)]
+    backends: Vec<String>,
+    /// Target server. Required when more than one server is configured.
+    server: Option<String>,
+    /// Override the server's `purge_cache_on_backend_change` setting.
+    purge_cache: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct BackendResponse {
+    server: String,
+    /// The first of `backends`.
+    backend: String,
+    backends: Vec<String>,
+    generation: u64,
+    cache_purged: bool,
+}
+
+/// PATCH /backend — point a server at a new upstream without a restart.
+///
+/// Body: `{ "proxy_url": "http://localhost:8081", "server": "api", "purge_cache": false }`,
+/// or `"backends": [...]` in place of `proxy_url` to spread requests over
+/// several. In-flight requests finish on the previous backend.
+async fn patch_backend_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Json(body): Json<BackendBody>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let proxies = state.resolve_proxies(body.server.as_deref())?;
+    let [(name, proxy)] = proxies.as_slice() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'server' is required when more than one server is configured".to_string(),
+        ));
+    };
+
+    let urls = match (body.proxy_url, body.backends.is_empty()) {
+        (Some(url), true) => vec![url],
+        (None, false) => body.backends,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "exactly one of 'proxy_url' and 'backends' is required".to_string(),
+            ))
+        }
+    };
+    let purge = body
+        .purge_cache
+        .unwrap_or(proxy.config().purge_cache_on_backend_change);
+    let backends = proxy
+        .set_backends(urls, purge)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    Ok(Json(BackendResponse {
+        server: name.to_string(),
+        backend: backends[0].url().to_string(),
+        backends: backends.iter().map(|b| b.url().to_string()).collect(),
+        generation: backends[0].generation(),
+        cache_purged: purge,
+    }))
+}
+
+#[derive(Serialize)]
+struct UpstreamStatus {
+    url: String,
+    /// `up`, `down`, or `unchecked` without active health checks or before
+    /// the first one.
+    health: &'static str,
+    /// What the last active check got back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checked_secs_ago: Option<u64>,
+    in_flight: usize,
+    cooling_down: bool,
+}
+
+#[derive(Serialize)]
+struct ServerUpstreams {
+    server: String,
+    generation: u64,
+    backends: Vec<UpstreamStatus>,
+}
+
+/// GET /upstreams — each server's backends with their health, in-flight
+/// requests, and cooldowns, optionally for `?server=` only.
+async fn upstreams_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<PathsQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let servers: Vec<ServerUpstreams> = state
+        .resolve_proxies(query.server.as_deref())?
+        .into_iter()
+        .map(|(name, proxy)| ServerUpstreams {
+            server: name.to_string(),
+            generation: proxy.backend().generation(),
+            backends: (proxy.backends().iter())
+                .map(|backend| {
+                    let health = backend.health();
+                    UpstreamStatus {
+                        url: backend.url().to_string(),
+                        health: match &health {
+                            Some(health) if health.up => "up",
+                            Some(_) => "down",
+                            None => "unchecked",
+                        },
+                        status: health.as_ref().and_then(|health| health.status),
+                        error: health.as_ref().and_then(|health| health.error.clone()),
+                        checked_secs_ago: (health.as_ref())
+                            .map(|health| health.checked_at.elapsed().as_secs()),
+                        in_flight: backend.in_flight(),
+                        cooling_down: backend.cooling_down(),
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "servers": servers })))
+}
+
+#[derive(Deserialize)]
+struct PathsQuery {
+    server: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PathsBody {
+    #[serde(flatten)]
+    patterns: PathPatterns,
+    /// Target server. Required when more than one server is configured.
+    server: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ServerPaths {
+    server: String,
+    #[serde(flatten)]
+    patterns: PathPatterns,
+}
+
+/// GET /config/paths — the `include_paths` and `exclude_paths`, or the
+/// `path_rules` (as `rules`), each server currently uses, optionally for `?server=` only.
+async fn get_config_paths_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<PathsQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let servers: Vec<ServerPaths> = state
+        .resolve_proxies(query.server.as_deref())?
+        .into_iter()
+        .map(|(name, proxy)| ServerPaths {
+            server: name.to_string(),
+            patterns: proxy.path_patterns(),
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "servers": servers })))
+}
+
+/// PUT /config/paths — replace a server's `include_paths`, `exclude_paths`,
+/// and `path_rules` at once, for requests from now on.
+///
+/// Body: `{ "include": ["/blog/*"], "exclude": ["/blog/drafts/*"], "server": "frontend" }`
+/// or `{ "rules": ["/blog/*", "!/blog/drafts/*"] }`. Lists left out are
+/// empty; an empty `include` without `rules` caches every path. Answers
+/// `400` naming each pattern that could never match, or when `rules` is
+/// combined with either list, and changes nothing.
+async fn put_config_paths_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Json(body): Json<PathsBody>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let proxies = state.resolve_proxies(body.server.as_deref())?;
+    let [(name, proxy)] = proxies.as_slice() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'server' is required when more than one server is configured".to_string(),
+        ));
+    };
+
+    proxy
+        .set_path_patterns(body.patterns)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let patterns = proxy.path_patterns();
+    tracing::info!(
+        "server '{}': cache path patterns replaced via control endpoint (include={:?}, exclude={:?}, rules={:?})",
+        name,
+        patterns.include,
+        patterns.exclude,
+        patterns.rules
+    );
+    Ok(Json(ServerPaths {
+        server: name.to_string(),
+        patterns,
+    }))
+}
+
+#[derive(Serialize)]
+struct BackendStats {
+    url: String,
+    in_flight: usize,
+    /// Skipped after a failed request until its cooldown ends.
+    cooling_down: bool,
+}
+
+#[derive(Serialize)]
+struct ServerStats {
+    server: String,
+    backend: String,
+    backend_generation: u64,
+    /// Every backend requests are spread over, `backend` first.
+    backends: Vec<BackendStats>,
+    uptime_secs: u64,
+    cache_entries: usize,
+    cache_404_entries: usize,
+    /// Approximate bytes held by the main and 404 caches.
+    cache_bytes: usize,
+    cache_404_bytes: usize,
+    /// Main-cache entries evicted to stay within `cache_max_bytes`.
+    cache_budget_evictions: u64,
+    cache_error_entries: usize,
+    cache_error_bytes: usize,
+    cache_writes_throttled: u64,
+    cache_writes_dropped: u64,
+    callback_panics: u64,
+    cache_origin_mismatches: u64,
+    decision_memo_hits: u64,
+    requests: OutcomeCounts,
+    last_1m: OutcomeCounts,
+    last_5m: OutcomeCounts,
+    last_1h: OutcomeCounts,
+    /// Time until backend response headers arrived.
+    backend_ttfb: LatencySummary,
+    /// Time spent reading backend bodies after the headers.
+    backend_body: LatencySummary,
+    upstream_auth_failures: u64,
+    /// Upgrade tunnels currently open.
+    active_tunnels: u64,
+    /// Clients that disconnected while their backend fetch was running.
+    client_disconnects: u64,
+    /// Misses answered from another request's concurrent fetch.
+    coalesced_misses: u64,
+    /// How often `cache_error_policy` and `backend_error_policy` took effect.
+    policy_activations: PolicyCounts,
+    /// Errors the proxy answered with itself, such as 502 for an unreachable
+    /// backend, by status.
+    error_responses: std::collections::BTreeMap<u16, u64>,
+}
+
+/// GET /stats — per-server backend and cache counters.
+async fn stats_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_auth(&state, &headers)?;
+
+    let mut servers = Vec::with_capacity(state.handles.len());
+    for (name, proxy) in &state.handles {
+        let backend = proxy.backend();
+        servers.push(ServerStats {
+            server: name.clone(),
+            backend: backend.url().to_string(),
+            backend_generation: backend.generation(),
+            backends: (proxy.backends().iter())
+                .map(|backend| BackendStats {
+                    url: backend.url().to_string(),
+                    in_flight: backend.in_flight(),
+                    cooling_down: backend.cooling_down(),
+                })
+                .collect(),
+            uptime_secs: proxy.stats().uptime().as_secs(),
+            cache_entries: proxy.cache().size().await,
+            cache_404_entries: proxy.cache().size_404().await,
+            cache_bytes: proxy.cache().memory_usage().await,
+            cache_budget_evictions: proxy.cache().budget_evictions(),
+            cache_404_bytes: proxy.cache().memory_usage_404().await,
+            cache_error_entries: proxy.cache().size_error().await,
+            cache_error_bytes: proxy.cache().memory_usage_error().await,
+            cache_writes_throttled: proxy.cache_writes_throttled(),
+            cache_writes_dropped: proxy.cache_writes_dropped(),
+            callback_panics: proxy.callback_panics(),
+            cache_origin_mismatches: proxy.cache_origin_mismatches(),
+            decision_memo_hits: proxy.cache().decision_memo().hits(),
+            requests: proxy.stats().totals(),
+            last_1m: proxy.stats().last(1),
+            last_5m: proxy.stats().last(5),
+            last_1h: proxy.stats().last(60),
+            backend_ttfb: LatencySummary::from(&proxy.stats().backend_ttfb()),
+            backend_body: LatencySummary::from(&proxy.stats().backend_body()),
+            upstream_auth_failures: proxy.stats().upstream_auth_failures(),
+            active_tunnels: proxy.stats().active_tunnels(),
+            client_disconnects: proxy.stats().client_disconnects(),
+            coalesced_misses: proxy.stats().coalesced_misses(),
+            policy_activations: proxy.stats().policy_activations(),
+            error_responses: proxy.stats().error_responses(),
+        });
+    }
+
+    Ok(Json(serde_json::json!({ "servers": servers })))
+}
+
+#[derive(Serialize)]
+struct ServerCacheStats {
+    server: String,
+    uptime_secs: u64,
+    /// Set between `POST /cache/pause` and `POST /cache/resume`.
+    caching_paused: bool,
+    #[serde(flatten)]
+    cache: CacheStats,
+    /// Request outcomes since startup, with their `hit_rate`.
+    #[serde(flatten)]
+    requests: OutcomeCounts,
+}
+
+/// GET /cache/stats — store sizes, evictions, and hit ratio of each server.
+async fn cache_stats_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_auth(&state, &headers)?;
+
+    let mut servers = Vec::with_capacity(state.handles.len());
+    for (name, proxy) in &state.handles {
+        servers.push(ServerCacheStats {
+            server: name.clone(),
+            uptime_secs: proxy.stats().uptime().as_secs(),
+            caching_paused: proxy.caching_paused(),
+            cache: proxy.cache().stats().await,
+            requests: proxy.stats().totals(),
+        });
+    }
+
+    Ok(Json(serde_json::json!({ "servers": servers })))
+}
+
+#[derive(Serialize)]
+struct ServerPersistence {
+    server: String,
+    #[serde(flatten)]
+    stats: PersistenceStats,
+}
+
+/// GET /persistence/stats — snapshot file state of each server with a
+/// `persistence_path`.
+async fn persistence_stats_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_auth(&state, &headers)?;
+
+    let servers: Vec<ServerPersistence> = state
+        .handles
+        .iter()
+        .filter_map(|(name, proxy)| {
+            Some(ServerPersistence {
+                server: name.clone(),
+                stats: proxy.persistence()?.stats(),
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "servers": servers })))
+}
+
+#[derive(Serialize)]
+struct CompactResult {
+    server: String,
+    entries: Option<usize>,
+    error: Option<String>,
+}
+
+/// POST /persistence/compact — write a fresh cache snapshot now for every
+/// server with a `persistence_path`, dropping purged and expired entries
+/// from the file without waiting for the next scheduled write.
+async fn persistence_compact_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_auth(&state, &headers).map_err(|s| (s, String::new()))?;
+
+    let mut results = Vec::new();
+    for (name, proxy) in &state.handles {
+        let Some(persistence) = proxy.persistence() else {
+            continue;
+        };
+        let saved = persistence.save().await;
+        if let Err(e) = &saved {
+            tracing::error!("server '{}': {:#}", name, e);
+        }
+        results.push(CompactResult {
+            server: name.clone(),
+            entries: saved.as_ref().ok().copied(),
+            error: saved.err().map(|e| format!("{:#}", e)),
+        });
+    }
+    if results.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "No servers have a `persistence_path` configured".to_string(),
+        ));
+    }
+    Ok(Json(serde_json::json!({ "servers": results })))
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    token: Option<String>,
+}
+
+/// GET /status — the counters of `/stats` as a self-refreshing HTML page.
+async fn status_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+    Query(query): Query<StatusQuery>,
+) -> Response {
+    if let Err(status) = check_page_auth(&state, &headers, query.token.as_deref()) {
+        // Lets a browser prompt for the token.
+        return (
+            status,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"phantom-frame\"")],
+        )
+            .into_response();
+    }
+
+    let mut servers = Vec::with_capacity(state.handles.len());
+    for (name, proxy) in &state.handles {
+        servers.push(ServerStatus::collect(name, proxy).await);
+    }
+    Html(status_page::render(&servers)).into_response()
+}
+
+/// GET /healthz — liveness: `200` whenever the process can answer.
+/// Never requires the token.
+async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize)]
+struct ServerReadiness {
+    server: String,
+    /// `reachable`, `unreachable`, or `unchecked` without a `health_check_path`.
+    backend: &'static str,
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    listening: bool,
+    servers: Vec<ServerReadiness>,
+}
+
+/// GET /readyz — readiness: `200` once the proxy listener is bound and every
+/// backend with a `health_check_path` is reachable, `503` otherwise. Never
+/// requires the token.
+async fn readyz_handler(State(state): State<Arc<ControlState>>) -> impl IntoResponse {
+    let listening = state
+        .listener_ready
+        .as_ref()
+        .is_none_or(|ready| ready.load(Ordering::SeqCst));
+
+    let mut servers = Vec::with_capacity(state.handles.len());
+    for (name, proxy) in &state.handles {
+        let backend = match proxy.check_backend().await {
+            Some(true) => "reachable",
+            Some(false) => "unreachable",
+            None => "unchecked",
+        };
+        servers.push(ServerReadiness {
+            server: name.clone(),
+            backend,
+        });
+    }
+
+    let ready = listening && servers.iter().all(|s| s.backend != "unreachable");
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            listening,
+            servers,
+        }),
+    )
+}
+
+/// GET /metrics — request, cache, and backend counters in the Prometheus
+/// text format.
+#[cfg(feature = "metrics")]
+async fn metrics_handler(
+    State(state): State<Arc<ControlState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !state.public_metrics {
+        check_auth(&state, &headers)?;
+    }
+
+    let mut servers = Vec::with_capacity(state.handles.len());
+    for (name, proxy) in &state.handles {
+        servers.push(ServerMetrics {
+            name,
+            stats: proxy.stats(),
+            cache: proxy.cache().stats().await,
+        });
+    }
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_prometheus(&servers),
+    ))
+}
+
+/// Create the control server router.
+///
+/// `handles` contains one `(server_name, ProxyHandle)` pair per named proxy server.
+pub fn create_control_router(
+    handles: Vec<(String, ProxyHandle)>,
+    auth_token: Option<String>,
+) -> Router {
+    control_router(ControlState::new(handles, auth_token))
+}
+
+/// Like [`create_control_router`], for a [`ControlState`] built with options.
+pub fn control_router(state: ControlState) -> Router {
+    let state = Arc::new(state);
+
+    let router = Router::new()
+        .route("/invalidate_all", post(invalidate_all_handler))
+        .route("/invalidate", post(invalidate_handler))
+        .route("/bulk_invalidate", post(bulk_invalidate_handler))
+        .route("/cache/key", delete(delete_cache_key_handler))
+        .route("/cache/keys", get(get_cache_keys_handler))
+        .route("/cache/warm", post(warm_cache_handler))
+        .route("/cache/pause", post(pause_cache_handler))
+        .route("/cache/resume", post(resume_cache_handler))
+        .route("/add_snapshot", post(add_snapshot_handler))
+        .route("/bulk_add_snapshot", post(bulk_add_snapshot_handler))
+        .route("/refresh_snapshot", post(refresh_snapshot_handler))
+        .route(
+            "/bulk_refresh_snapshot",
+            post(bulk_refresh_snapshot_handler),
+        )
+        .route("/remove_snapshot", post(remove_snapshot_handler))
+        .route("/bulk_remove_snapshot", post(bulk_remove_snapshot_handler))
+        .route(
+            "/refresh_all_snapshots",
+            post(refresh_all_snapshots_handler),
+        )
+        .route("/render-queue", get(render_queue_handler))
+        .route(
+            "/cache/entry",
+            put(put_cache_entry_handler).get(get_cache_entry_handler),
+        )
+        .route("/backend", patch(patch_backend_handler))
+        .route(
+            "/config/paths",
+            get(get_config_paths_handler).put(put_config_paths_handler),
+        )
+        .route("/stats", get(stats_handler))
+        .route("/upstreams", get(upstreams_handler))
+        .route("/cache/stats", get(cache_stats_handler))
+        .route("/persistence/stats", get(persistence_stats_handler))
+        .route("/persistence/compact", post(persistence_compact_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/status", get(status_handler));
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(metrics_handler));
+    router.with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, get, send, spawn_backend};
+    use crate::{create_proxy_handle, CreateProxyConfig};
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_control_auth_requires_exact_bearer_token() {
+        let (_proxy, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:9".to_string()));
+        let control =
+            create_control_router(vec![("default".to_string(), handle)], Some("s3cret".into()));
+
+        for (authorization, expected) in [
+            (Some("Bearer s3cret"), StatusCode::OK),
+            (Some("Bearer s3cre"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer s3cret!"), StatusCode::UNAUTHORIZED),
+            (Some("s3cret"), StatusCode::UNAUTHORIZED),
+            (None, StatusCode::UNAUTHORIZED),
+        ] {
+            let mut request = Request::builder().uri("/stats");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let response = send(&control, request.body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), expected, "{:?}", authorization);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_public_metrics_report_cache_and_proxy_errors() {
+        let backend = spawn_backend(Router::new().fallback(|| async { "page" })).await;
+        let (proxy, handle) = create_proxy_handle(CreateProxyConfig::new(backend));
+        let (unreachable, unreachable_handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:9".to_string()));
+        let control = control_router(
+            ControlState::new(
+                vec![
+                    ("web".to_string(), handle),
+                    ("down".to_string(), unreachable_handle),
+                ],
+                Some("s3cret".into()),
+            )
+            .with_public_metrics(true),
+        );
+
+        send(&proxy, get("/page")).await;
+        let response = send(&unreachable, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = send(&control, get("/metrics")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = body_string(response).await;
+        assert!(text.contains("phantom_frame_cache_entries{server=\"web\",store=\"main\"} 1"));
+        assert!(
+            text.contains("phantom_frame_error_responses_total{server=\"down\",status=\"502\"} 1")
+        );
+        assert!(!text.contains("phantom_frame_error_responses_total{server=\"web\""));
+
+        // Only /metrics is opened up.
+        let response = send(&control, get("/stats")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_purge_by_exact_key_and_reject_empty_targets() {
+        let (_proxy, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:9".to_string()));
+        let cache = handle.cache().clone();
+        let mut events = handle.cache_handle().subscribe();
+        for key in ["GET:/products/42", "GET:/products/420"] {
+            handle.seed(key, 200, Vec::new(), b"product").await.unwrap();
+        }
+        let control = create_control_router(
+            vec![("default".to_string(), handle.clone())],
+            Some("s3cret".into()),
+        );
+        let request = |method: &str, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer s3cret")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        for (method, uri, body) in [
+            ("DELETE", "/cache/key", r#"{"key": " "}"#),
+            ("POST", "/invalidate", r#"{"pattern": ""}"#),
+            (
+                "POST",
+                "/bulk_invalidate",
+                r#"{"patterns": ["GET:/a", ""]}"#,
+            ),
+        ] {
+            let response = send(&control, request(method, uri, body)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri} {body}");
+        }
+
+        let mut unauthenticated = request("DELETE", "/cache/key", r#"{"key": "GET:/products/42"}"#);
+        unauthenticated.headers_mut().remove(header::AUTHORIZATION);
+        let response = send(&control, unauthenticated).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let purged = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            body
+        };
+        let body = purged(
+            send(
+                &control,
+                request("DELETE", "/cache/key", r#"{"key": "GET:/products/42"}"#),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(body["purged"], 1);
+        // The purge is done by the time the response arrives.
+        assert!(cache.get("GET:/products/42").await.is_none());
+        assert!(cache.get("GET:/products/420").await.is_some());
+
+        let body = purged(
+            send(
+                &control,
+                request("POST", "/invalidate", r#"{"pattern": "GET:/products/42"}"#),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(body["purged"], 0);
+
+        let body = purged(
+            send(
+                &control,
+                request(
+                    "POST",
+                    "/bulk_invalidate",
+                    r#"{"patterns": ["GET:/products/*", "GET:/other"]}"#,
+                ),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(body["purged"], 1);
+        assert_eq!(body["results"][0]["purged"], 1);
+        assert_eq!(body["results"][1]["purged"], 0);
+
+        handle
+            .seed("GET:/page", 200, Vec::new(), b"page")
+            .await
+            .unwrap();
+        let body = purged(send(&control, request("POST", "/invalidate_all", "")).await).await;
+        assert_eq!(body["purged"], 1);
+
+        // Every purge still reaches the handle's other listeners.
+        let mut broadcast = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            broadcast.push(format!("{:?}", event.message));
+        }
+        assert_eq!(
+            broadcast,
+            [
+                r#"Key("GET:/products/42")"#,
+                r#"Pattern("GET:/products/42")"#,
+                r#"Pattern("GET:/products/*")"#,
+                r#"Pattern("GET:/other")"#,
+                "All",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_keys_filter_and_paginate() {
+        let (_proxy, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:9".to_string()));
+        for key in ["GET:/api/b", "GET:/api/a", "GET:/about", "GET:/api/c"] {
+            handle.seed(key, 200, Vec::new(), b"12345").await.unwrap();
+        }
+        handle
+            .cache()
+            .set_404(
+                "GET:/api/missing".to_string(),
+                crate::cache::CachedResponse {
+                    body: Vec::new().into(),
+                    headers: Vec::new(),
+                    status: 404,
+                    content_encoding: None,
+                    origin: None,
+                    content_digest: None,
+                    stored_at: None,
+                },
+            )
+            .await;
+        let control = create_control_router(vec![("web".to_string(), handle)], None);
+        let list = |uri: &'static str| {
+            let control = control.clone();
+            async move {
+                let response = send(&control, get(uri)).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap()
+            }
+        };
+
+        let body = list("/cache/keys?pattern=GET:/api/*&limit=2&offset=1").await;
+        assert_eq!(body["total"], 4);
+        let keys: Vec<(&str, &str, u64)> = body["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["key"].as_str().unwrap(),
+                    entry["store"].as_str().unwrap(),
+                    entry["status"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [("GET:/api/b", "main", 200), ("GET:/api/c", "main", 200)]
+        );
+        assert!(body["keys"][0]["stored_at"].as_u64().unwrap() > 0);
+        assert!(body["keys"][0]["body_bytes"].as_u64().unwrap() > 0);
+
+        let body = list("/cache/keys?offset=4").await;
+        assert_eq!(
+            (body["total"].as_u64(), body["limit"].as_u64()),
+            (Some(5), Some(100))
+        );
+        assert_eq!(body["keys"][0]["key"], "GET:/api/missing");
+        assert_eq!(body["keys"][0]["store"], "404");
+    }
+
+    #[tokio::test]
+    async fn test_health_probes_skip_auth_and_report_readiness() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { "page" }
+        }))
+        .await;
+        let (proxy, up) =
+            create_proxy_handle(CreateProxyConfig::new(backend).with_health_check_path("/up"));
+        let response = send(&proxy, get(crate::health::HEALTH_PATH)).await;
+        assert_eq!(body_string(response).await, "ok");
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+
+        let (_down_proxy, down) = create_proxy_handle(
+            CreateProxyConfig::new("http://127.0.0.1:9".to_string()).with_health_check_path("/up"),
+        );
+        let (_unchecked_proxy, unchecked) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:9".to_string()));
+        let listening = Arc::new(AtomicBool::new(false));
+        let readiness = |handles: Vec<(String, ProxyHandle)>| {
+            control_router(
+                ControlState::new(handles, Some("s3cret".into()))
+                    .with_listener_ready(listening.clone()),
+            )
+        };
+        let healthy = readiness(vec![
+            ("up".to_string(), up),
+            ("unchecked".to_string(), unchecked),
+        ]);
+
+        assert_eq!(
+            send(&healthy, get("/healthz")).await.status(),
+            StatusCode::OK
+        );
+        let response = send(&healthy, get("/readyz")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["listening"], false);
+
+        listening.store(true, Ordering::SeqCst);
+        let response = send(&healthy, get("/readyz")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["servers"][0]["backend"], "reachable");
+        assert_eq!(body["servers"][1]["backend"], "unchecked");
+        send(&healthy, get("/readyz")).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        let unhealthy = readiness(vec![("down".to_string(), down)]);
+        let response = send(&unhealthy, get("/readyz")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["servers"][0]["backend"], "unreachable");
+    }
+
+    #[tokio::test]
+    async fn test_active_health_checks_route_around_down_backends() {
+        // A backend whose health path fails while its flag is set.
+        async fn flaky(name: &'static str) -> (String, Arc<AtomicBool>) {
+            let sick = Arc::new(AtomicBool::new(false));
+            let flag = sick.clone();
+            let url = spawn_backend(
+                Router::new()
+                    .route(
+                        "/up",
+                        axum::routing::head(move || {
+                            let sick = flag.load(Ordering::SeqCst);
+                            async move {
+                                if sick {
+                                    StatusCode::SERVICE_UNAVAILABLE
+                                } else {
+                                    StatusCode::OK
+                                }
+                            }
+                        }),
+                    )
+                    .fallback(move || async move { name }),
+            )
+            .await;
+            (url, sick)
+        }
+        let ((a, a_sick), (b, b_sick)) = (flaky("a").await, flaky("b").await);
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(String::new())
+                .with_backends(vec![a, b])
+                .with_cache_strategy(crate::CacheStrategy::None)
+                .with_health_check_path("/up")
+                .with_health_check_interval(Duration::from_millis(20)),
+        );
+        let control = create_control_router(vec![("default".to_string(), handle.clone())], None);
+        let wait_for = |up: [bool; 2]| {
+            let handle = handle.clone();
+            async move {
+                for _ in 0..200 {
+                    let health: Vec<_> = handle.backends().iter().map(|b| b.health()).collect();
+                    if health
+                        .iter()
+                        .zip(up)
+                        .all(|(h, up)| h.as_ref().is_some_and(|h| h.up == up))
+                    {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("backends never became {:?}", up);
+            }
+        };
+
+        b_sick.store(true, Ordering::SeqCst);
+        wait_for([true, false]).await;
+        for _ in 0..4 {
+            assert_eq!(body_string(send(&proxy, get("/")).await).await, "a");
+        }
+        let upstreams: serde_json::Value =
+            serde_json::from_str(&body_string(send(&control, get("/upstreams")).await).await)
+                .unwrap();
+        let backends = &upstreams["servers"][0]["backends"];
+        assert_eq!(backends[0]["health"], "up");
+        assert_eq!(backends[1]["health"], "down");
+        assert_eq!(backends[1]["status"], 503);
+
+        a_sick.store(true, Ordering::SeqCst);
+        wait_for([false, false]).await;
+        let response = send(&proxy, get("/")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(handle.check_backend().await, Some(false));
+
+        b_sick.store(false, Ordering::SeqCst);
+        wait_for([false, true]).await;
+        assert_eq!(body_string(send(&proxy, get("/")).await).await, "b");
+    }
+
+    #[tokio::test]
+    async fn test_pause_bypasses_cache_and_resume_keeps_entries() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { "page" }
+        }))
+        .await;
+        let (proxy, handle) = create_proxy_handle(CreateProxyConfig::new(backend));
+        let cache = handle.cache().clone();
+        let control = create_control_router(vec![("web".to_string(), handle)], None);
+        let post = |uri: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let x_cache = |response: &axum::response::Response| {
+            response.headers()["x-cache"].to_str().unwrap().to_string()
+        };
+
+        send(&proxy, get("/page")).await;
+        let response = send(&control, post("/cache/pause")).await;
+        assert_eq!(
+            body_string(response).await,
+            r#"{"paused":true,"changed":["web"]}"#
+        );
+
+        for path in ["/page", "/other"] {
+            let response = send(&proxy, get(path)).await;
+            assert_eq!(x_cache(&response), "PAUSED");
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.size().await, 1);
+        let response = send(&control, get("/cache/stats")).await;
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["servers"][0]["caching_paused"], true);
+
+        let response = send(&control, post("/cache/resume")).await;
+        assert_eq!(
+            body_string(response).await,
+            r#"{"paused":false,"changed":["web"]}"#
+        );
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(x_cache(&response), "HIT");
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_replace_path_patterns_at_runtime() {
+        let backend = spawn_backend(Router::new().fallback(|| async { "page" })).await;
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(backend).with_include_paths(vec!["/blog/*".to_string()]),
+        );
+        let control = create_control_router(vec![("web".to_string(), handle)], None);
+        let put = |body: &'static str| {
+            Request::builder()
+                .method("PUT")
+                .uri("/config/paths")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let x_cache = |path: &'static str| {
+            let proxy = proxy.clone();
+            async move {
+                let response = send(&proxy, get(path)).await;
+                response.headers()["x-cache"].to_str().unwrap().to_string()
+            }
+        };
+        let current = || {
+            let control = control.clone();
+            async move {
+                let response = send(&control, get("/config/paths")).await;
+                serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap()
+            }
+        };
+        let initial = serde_json::json!({
+            "servers": [{ "server": "web", "include": ["/blog/*"], "exclude": [] }]
+        });
+
+        assert_eq!(current().await, initial);
+        assert_eq!(x_cache("/about").await, "BYPASS");
+
+        let response = send(
+            &control,
+            put(r#"{"include": ["/ok", "api/*"], "exclude": ["FETCH /x"]}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let message = body_string(response).await;
+        assert!(message.contains(r#"include[1] "api/*""#), "{message}");
+        assert!(message.contains(r#"exclude[0] "FETCH /x""#), "{message}");
+        assert_eq!(current().await, initial);
+
+        let response = send(
+            &control,
+            put(r#"{"include": [], "exclude": ["/private/*"]}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(response).await,
+            r#"{"server":"web","include":[],"exclude":["/private/*"]}"#
+        );
+        assert_eq!(x_cache("/about").await, "MISS");
+        assert_eq!(x_cache("/about").await, "HIT");
+        assert_eq!(x_cache("/private/notes").await, "BYPASS");
+
+        let response = send(
+            &control,
+            put(r#"{"rules": ["*", "!/private/*"], "include": ["/a"]}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(
+            &control,
+            put(r#"{"rules": ["/private/*", "!/private/*", "/private/shared/*"]}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(response).await,
+            r#"{"server":"web","include":[],"exclude":[],"rules":["/private/*","!/private/*","/private/shared/*"]}"#
+        );
+        assert_eq!(x_cache("/private/notes").await, "BYPASS");
+        assert_eq!(x_cache("/private/shared/doc").await, "MISS");
+        assert_eq!(x_cache("/private/shared/doc").await, "HIT");
+        assert_eq!(x_cache("/about").await, "BYPASS");
+    }
+
+    #[tokio::test]
+    async fn test_warm_fetches_each_path_once_and_skips_excluded() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                "page"
+            }
+        }))
+        .await;
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(backend).with_exclude_paths(vec!["/admin/*".to_string()]),
+        );
+        let control = create_control_router(vec![("web".to_string(), handle.clone())], None);
+        let request = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/cache/warm")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(
+            &control,
+            request(r#"{"paths": ["/", "/about", "/admin/users", "about"], "concurrency": 2}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            (&body["requested"], &body["stored"], &body["failed"]),
+            (&4.into(), &2.into(), &1.into())
+        );
+        let outcomes: Vec<(&str, &str)> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| {
+                (
+                    item["path"].as_str().unwrap(),
+                    item["outcome"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("/", "stored"),
+                ("/about", "stored"),
+                ("/admin/users", "excluded"),
+                ("about", "failed"),
+            ]
+        );
+        assert_eq!(body["results"][0]["server"], "web");
+        assert_eq!(body["results"][0]["status"], 200);
+        assert!(body["results"][3]["error"].is_string());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        let results = handle.warm(vec!["/about".to_string()]).await;
+        assert_eq!(results[0].outcome, WarmOutcome::AlreadyCached);
+        let response = send(&proxy, get("/about")).await;
+        assert_eq!(body_string(response).await, "page");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        let response = send(&control, request(r#"{"paths": []}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats_report_stores_and_hit_ratio() {
+        let backend = spawn_backend(Router::new().fallback(|| async { "page" })).await;
+        let (proxy, handle) = create_proxy_handle(CreateProxyConfig::new(backend));
+        let control = create_control_router(vec![("web".to_string(), handle)], None);
+
+        for _ in 0..4 {
+            send(&proxy, get("/page")).await;
+        }
+
+        let response = send(&control, get("/cache/stats")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        let server = &body["servers"][0];
+        assert_eq!(server["server"], "web");
+        assert_eq!(server["entries"], 1);
+        assert_eq!(server["not_found_entries"], 0);
+        assert!(server["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(
+            (server["hits"].as_u64(), server["misses"].as_u64()),
+            (Some(3), Some(1))
+        );
+        assert_eq!(server["hit_rate"], 0.75);
+        assert!(server["uptime_secs"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_status_page_accepts_browser_credentials_and_shows_stats() {
+        let backend =
+            spawn_backend(Router::new().fallback(|| async {
+                ([(header::CONTENT_TYPE, "text/html")], "<html>page</html>")
+            }))
+            .await;
+        let (proxy, handle) = create_proxy_handle(CreateProxyConfig::new(backend));
+        let cache = handle.cache().clone();
+        let control =
+            create_control_router(vec![("default".to_string(), handle)], Some("s3cret".into()));
+
+        for _ in 0..3 {
+            send(&proxy, get("/popular")).await;
+        }
+        send(&proxy, get("/other")).await;
+        cache.clear_by_pattern("GET:/other").await;
+
+        let basic = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode("ops:s3cret")
+        );
+        for (uri, authorization, expected) in [
+            ("/status", Some("Bearer s3cret"), StatusCode::OK),
+            ("/status?token=s3cret", None, StatusCode::OK),
+            ("/status", Some(basic.as_str()), StatusCode::OK),
+            ("/status?token=wrong", None, StatusCode::UNAUTHORIZED),
+            (
+                "/status",
+                Some("Basic b3BzOndyb25n"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            ("/status", None, StatusCode::UNAUTHORIZED),
+        ] {
+            let mut request = Request::builder().uri(uri);
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let response = send(&control, request.body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), expected, "{uri} {authorization:?}");
+            if expected == StatusCode::UNAUTHORIZED {
+                assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+            }
+        }
+        // The token only unlocks the page, not the JSON endpoints.
+        let response = send(&control, get("/stats?token=s3cret")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let page = body_string(send(&control, get("/status?token=s3cret")).await).await;
+        assert!(page.contains("<h2>default</h2>"), "{page}");
+        // Two of the three requests for /popular were hits.
+        assert!(page.contains("<code>GET:/popular</code></td><td class=\"num\">2<"));
+        assert!(page.contains("<code>GET:/other</code></td><td class=\"num\">1<"));
+        assert!(page.contains("value=\"0.500\"></meter> 50.0%"));
+    }
+
+    #[tokio::test]
+    async fn test_render_worker_claims_and_replaces_interim_entry() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let backend_hits = hits.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            let hits = backend_hits.clone();
+            async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                ([(header::CONTENT_TYPE, "text/html")], "<html>raw</html>")
+            }
+        }))
+        .await;
+
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_render_queue_patterns(vec!["GET /blog/*".to_string()]),
+        );
+        let control = create_control_router(vec![("default".to_string(), handle)], None);
+
+        let response = send(&proxy, get("/blog/1")).await;
+        assert_eq!(body_string(response).await, "<html>raw</html>");
+
+        let claimed = send(&control, get("/render-queue?lease_secs=30")).await;
+        let claimed: serde_json::Value = serde_json::from_str(&body_string(claimed).await).unwrap();
+        let items = claimed["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["key"], "GET:/blog/1");
+        assert_eq!(items[0]["path"], "/blog/1");
+
+        // A second worker must not receive the leased job.
+        let second = send(&control, get("/render-queue")).await;
+        let second: serde_json::Value = serde_json::from_str(&body_string(second).await).unwrap();
+        assert!(second["items"].as_array().unwrap().is_empty());
+
+        let submit = Request::builder()
+            .method("PUT")
+            .uri("/cache/entry")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({
+                    "key": "GET:/blog/1",
+                    "headers": { "content-type": "text/html" },
+                    "body": "<html>rendered</html>",
+                    "lease_id": items[0]["lease_id"],
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let submitted = send(&control, submit).await;
+        assert_eq!(submitted.status(), StatusCode::OK);
+        let submitted: serde_json::Value =
+            serde_json::from_str(&body_string(submitted).await).unwrap();
+        assert_eq!(submitted["render_completed"], true);
+
+        let response = send(&proxy, get("/blog/1")).await;
+        assert_eq!(body_string(response).await, "<html>rendered</html>");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let invalid = Request::builder()
+            .method("PUT")
+            .uri("/cache/entry")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "key": "GET:/blog/2", "status": 1000, "body": "" }).to_string(),
+            ))
+            .unwrap();
+        assert_eq!(
+            send(&control, invalid).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_submission_checks_every_lease_before_seeding() {
+        let backend =
+            spawn_backend(Router::new().fallback(|| async {
+                ([(header::CONTENT_TYPE, "text/html")], "<html>raw</html>")
+            }))
+            .await;
+        let mut proxies = Vec::new();
+        let mut handles = Vec::new();
+        for name in ["a", "b"] {
+            let (proxy, handle) = create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_render_queue_patterns(vec!["GET /blog/*".to_string()]),
+            );
+            send(&proxy, get("/blog/1")).await;
+            proxies.push(proxy);
+            handles.push((name.to_string(), handle));
+        }
+        let control = create_control_router(handles, None);
+        let claimed = send(&control, get("/render-queue?server=b&lease_secs=30")).await;
+        let claimed: serde_json::Value = serde_json::from_str(&body_string(claimed).await).unwrap();
+        assert_eq!(claimed["items"][0]["server"], "b", "{claimed}");
+        let lease_id = claimed["items"][0]["lease_id"].clone();
+        let submit = |lease_id: serde_json::Value| {
+            Request::builder()
+                .method("PUT")
+                .uri("/cache/entry")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "key": "GET:/blog/1",
+                        "body": "<html>rendered</html>",
+                        "lease_id": lease_id,
+                    })
+                    .to_string(),
+                ))
+                .unwrap()
+        };
+        let bodies = || async {
+            let mut bodies = Vec::new();
+            for proxy in &proxies {
+                bodies.push(body_string(send(proxy, get("/blog/1")).await).await);
+            }
+            bodies
+        };
+
+        // Without b's lease id nothing changes, not even on a.
+        let response = send(&control, submit(serde_json::Value::Null)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(bodies().await, ["<html>raw</html>", "<html>raw</html>"]);
+        let pending = send(&control, get("/render-queue?server=a&lease_secs=1")).await;
+        let pending: serde_json::Value = serde_json::from_str(&body_string(pending).await).unwrap();
+        assert_eq!(pending["items"].as_array().unwrap().len(), 1);
+
+        // b's lease id does not hold a's live lease either.
+        let response = send(&control, submit(lease_id.clone())).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(bodies().await, ["<html>raw</html>", "<html>raw</html>"]);
+
+        // Once a's lease lapses, b's lease id completes both jobs.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = send(&control, submit(lease_id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(response["render_completed"], true);
+        assert_eq!(
+            bodies().await,
+            ["<html>rendered</html>", "<html>rendered</html>"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_swap_mid_traffic_drops_no_requests() {
+        // The old backend answers slowly so requests are still in flight when
+        // the swap happens.
+        let old = spawn_backend(Router::new().fallback(|| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "old"
+        }))
+        .await;
+        let new = spawn_backend(Router::new().fallback(|| async { "new" })).await;
+
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(old).with_cache_strategy(crate::CacheStrategy::None),
+        );
+        let control = create_control_router(vec![("default".to_string(), handle)], None);
+
+        let traffic = tokio::spawn({
+            let proxy = proxy.clone();
+            async move {
+                let mut requests = tokio::task::JoinSet::new();
+                for i in 0..40 {
+                    let proxy = proxy.clone();
+                    requests.spawn(async move {
+                        let response = send(&proxy, get(&format!("/item/{}", i))).await;
+                        (response.status(), body_string(response).await)
+                    });
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                requests.join_all().await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let swap = Request::builder()
+            .method("PATCH")
+            .uri("/backend")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "proxy_url": new, "purge_cache": false }).to_string(),
+            ))
+            .unwrap();
+        assert_eq!(send(&control, swap).await.status(), StatusCode::OK);
+
+        let results = traffic.await.unwrap();
+        assert!(results.iter().all(|(status, _)| *status == StatusCode::OK));
+        assert!(results.iter().any(|(_, body)| body == "old"));
+        assert!(results.iter().any(|(_, body)| body == "new"));
+
+        let stats = send(&control, get("/stats")).await;
+        let stats: serde_json::Value = serde_json::from_str(&body_string(stats).await).unwrap();
+        assert_eq!(stats["servers"][0]["backend"], new);
+        assert_eq!(stats["servers"][0]["backend_generation"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_cache_entry_body_views() {
+        let page = format!("<html>{}</html>", "x".repeat(10_000));
+        let backend_page = page.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            let page = backend_page.clone();
+            async move {
+                (
+                    [
+                        (header::CONTENT_TYPE, "text/html"),
+                        (header::SET_COOKIE, "session=abc"),
+                    ],
+                    page,
+                )
+            }
+        }))
+        .await;
+        // Stored anyway, to check that the cookie is redacted in the view.
+        let (proxy, handle) = create_proxy_handle(
+            CreateProxyConfig::new(backend).with_skip_set_cookie_responses(false),
+        );
+        let control = create_control_router(vec![("default".to_string(), handle)], None);
+        send(&proxy, get("/big")).await;
+
+        let entry = |query: &str| {
+            let control = control.clone();
+            let uri = format!("/cache/entry?key=GET:/big&{}", query);
+            async move {
+                let response = send(&control, get(&uri)).await;
+                let status = response.status();
+                let body = body_string(response).await;
+                (
+                    status,
+                    serde_json::from_str(&body).unwrap_or(serde_json::Value::Null),
+                )
+            }
+        };
+
+        // Default: a decoded 4 KiB preview of the brotli-stored body.
+        let (status, json) = entry("").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["store"], "main");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["headers"]["set-cookie"], "[redacted]");
+        assert_eq!(json["body"]["mode"], "bytes");
+        assert_eq!(json["body"]["decoded"], true);
+        assert_eq!(json["body"]["total_bytes"], page.len());
+        assert_eq!(json["body"]["length"], 4096);
+        assert_eq!(json["body"]["truncated"], true);
+        assert_eq!(json["body"]["content"], &page[..4096]);
+
+        let (_, json) = entry("body=preview&max_bytes=6").await;
+        assert_eq!(json["body"]["content"], "<html>");
+
+        let (_, json) = entry("body=range&start=10005&end=20000").await;
+        assert_eq!(json["body"]["offset"], 10005);
+        assert_eq!(json["body"]["content"], "x</html>");
+        assert_eq!(json["body"]["truncated"], false);
+
+        let (_, decoded) = entry("body=hash").await;
+        let (_, raw) = entry("body=hash&raw=true").await;
+        assert_eq!(decoded["body"]["algorithm"], "sha256");
+        assert_eq!(decoded["body"]["digest"].as_str().unwrap().len(), 64);
+        assert_eq!(raw["body"]["decoded"], false);
+        assert_ne!(decoded["body"]["digest"], raw["body"]["digest"]);
+        assert!(raw["body"]["total_bytes"].as_u64().unwrap() < page.len() as u64);
+
+        let (_, json) = entry("body=none").await;
+        assert!(json.get("body").is_none());
+        assert_eq!(json["age_secs"], 0);
+        assert!(json["stored_bytes"].as_u64().unwrap() < page.len() as u64);
+
+        // The whole body, as the response itself.
+        let response = send(&control, get("/cache/entry?key=GET:/big&body=full")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_string(response).await, page);
+        let response = send(
+            &control,
+            get("/cache/entry?key=GET:/big&body=full&raw=true"),
+        )
+        .await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+
+        let (status, _) = entry("body=everything").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let response = send(&control, get("/cache/entry?key=GET:/missing")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_persisted_cache_survives_restart() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { ([(header::CONTENT_TYPE, "text/html")], "<p>kept</p>") }
+        }))
+        .await;
+        let path = std::env::temp_dir().join(format!(
+            "phantom-frame-control-persistence-{:x}.snapshot",
+            std::process::id()
+        ));
+        let config = || {
+            CreateProxyConfig::new(backend.clone())
+                .with_persistence_path(path.clone())
+                .with_persistence_interval(Duration::ZERO)
+        };
+
+        let (proxy, handle) = create_proxy_handle(config());
+        let control = create_control_router(vec![("default".to_string(), handle)], None);
+        send(&proxy, get("/page")).await;
+        let compact = Request::builder()
+            .method("POST")
+            .uri("/persistence/compact")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&control, compact).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(json["servers"][0]["entries"], 1);
+
+        let stats = send(&control, get("/persistence/stats")).await;
+        let stats: serde_json::Value = serde_json::from_str(&body_string(stats).await).unwrap();
+        assert_eq!(stats["servers"][0]["server"], "default");
+        assert_eq!(stats["servers"][0]["snapshot_entries"], 1);
+        assert_eq!(
+            stats["servers"][0]["last_restore"]["outcome"],
+            "no_snapshot"
+        );
+        assert!(stats["servers"][0]["file_bytes"].as_u64().unwrap() > 0);
+
+        // A new proxy on the same file serves the page without the backend.
+        let (proxy, handle) = create_proxy_handle(config());
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(body_string(response).await, "<p>kept</p>");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let control = create_control_router(vec![("default".to_string(), handle)], None);
+        let stats = send(&control, get("/persistence/stats")).await;
+        let stats: serde_json::Value = serde_json::from_str(&body_string(stats).await).unwrap();
+        assert_eq!(stats["servers"][0]["last_restore"]["outcome"], "restored");
+        assert_eq!(stats["servers"][0]["last_restore"]["entries"], 1);
+
+        std::fs::remove_file(&path).ok();
+    }
 }
@@ -0,0 +1,148 @@
+//! Requests that carry credentials, which `bypass_credentialed_requests`
+//! keeps away from the cache.
+//!
+//! A page rendered for a signed-in visitor must neither be stored nor be
+//! answered by an entry stored for someone else. A request counts as
+//! credentialed when it has an `Authorization` header or a cookie whose name
+//! matches one of `bypass_cookie_names`, compared ignoring case with `*`
+//! matching any run of characters.
+
+use crate::path_matcher::Wildcard;
+use axum::http::HeaderMap;
+
+/// Cookie names treated as credentials by default.
+pub const DEFAULT_COOKIE_NAMES: &[&str] = &[
+    "session*",
+    "*_session",
+    "*sessid",
+    "connect.sid",
+    "auth_token",
+    "remember_*",
+    "wordpress_logged_in_*",
+];
+
+/// [`DEFAULT_COOKIE_NAMES`] as owned strings, for config defaults.
+pub fn default_cookie_names() -> Vec<String> {
+    DEFAULT_COOKIE_NAMES
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Which credential made a request bypass the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Credential {
+    Authorization,
+    Cookie,
+}
+
+impl Credential {
+    /// Short name used in logs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Authorization => "authorization",
+            Self::Cookie => "session_cookie",
+        }
+    }
+}
+
+/// `bypass_credentialed_requests` and `bypass_cookie_names`, parsed once.
+#[derive(Clone)]
+pub struct CredentialFilter {
+    enabled: bool,
+    cookie_names: Vec<Wildcard>,
+}
+
+impl CredentialFilter {
+    pub fn new(enabled: bool, cookie_names: &[String]) -> Self {
+        Self {
+            enabled,
+            cookie_names: cookie_names
+                .iter()
+                .map(|name| Wildcard::new(&name.to_ascii_lowercase()))
+                .collect(),
+        }
+    }
+
+    /// The credential `headers` carry, if any and if the filter is enabled.
+    pub fn find(&self, headers: &HeaderMap) -> Option<Credential> {
+        if !self.enabled {
+            return None;
+        }
+        if headers.contains_key(axum::http::header::AUTHORIZATION) {
+            return Some(Credential::Authorization);
+        }
+        let session_cookie = headers
+            .get_all(axum::http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.split_once('=').map(|(name, _)| name.trim()))
+            .any(|name| {
+                let name = name.to_ascii_lowercase();
+                self.cookie_names
+                    .iter()
+                    .any(|pattern| pattern.matches(&name))
+            });
+        session_cookie.then_some(Credential::Cookie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_finds_authorization_and_session_cookies() {
+        let filter = CredentialFilter::new(true, &default_cookie_names());
+        assert_eq!(
+            filter.find(&headers(&[("authorization", "Bearer abc")])),
+            Some(Credential::Authorization)
+        );
+        for cookie in [
+            "sessionid=1",
+            "theme=dark; laravel_session=1",
+            "PHPSESSID=1",
+            "Auth_Token=1",
+        ] {
+            let found = filter.find(&headers(&[("cookie", "lang=en"), ("cookie", cookie)]));
+            assert_eq!(found, Some(Credential::Cookie), "{}", cookie);
+        }
+        assert_eq!(
+            filter.find(&headers(&[("cookie", "theme=dark; lang=en; session")])),
+            None
+        );
+        assert_eq!(filter.find(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_disabled_filter_finds_nothing() {
+        let filter = CredentialFilter::new(false, &default_cookie_names());
+        assert_eq!(
+            filter.find(&headers(&[
+                ("authorization", "Basic eDp5"),
+                ("cookie", "session=1")
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_custom_cookie_names() {
+        let filter = CredentialFilter::new(true, &["member".to_string()]);
+        assert_eq!(
+            filter.find(&headers(&[("cookie", "member=1")])),
+            Some(Credential::Cookie)
+        );
+        assert_eq!(filter.find(&headers(&[("cookie", "session=1")])), None);
+    }
+}
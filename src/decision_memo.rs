@@ -0,0 +1,151 @@
+//! Short-lived memo of cache keys whose responses were rejected for caching.
+//!
+//! Deciding that a response is not cacheable can be expensive: the body may be
+//! decompressed and scanned for the 404 meta tag before the content type rules
+//! it out. Remembering the verdict per key for a few seconds lets repeat
+//! requests skip straight to pass-through. Any purge clears the memo.
+
+use crate::path_matcher::Wildcard;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Upper bound on remembered keys; new verdicts are dropped once full.
+const CAPACITY: usize = 4096;
+
+/// Why a response was not cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotCacheable {
+    /// The content type is excluded by the cache strategy.
+    ContentType,
+    /// The upstream body used an encoding the proxy cannot decode.
+    UndecodableBody,
+}
+
+/// Bounded, TTL-based map of cache key to "not cacheable" verdict.
+pub struct DecisionMemo {
+    entries: DashMap<String, (NotCacheable, Instant)>,
+    ttl: Duration,
+    hits: AtomicU64,
+}
+
+impl DecisionMemo {
+    /// Remember verdicts for `ttl`. A zero TTL disables the memo.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: DashMap::new(),
+            ttl,
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the remembered verdict for `key`, if still fresh.
+    pub fn lookup(&self, key: &str) -> Option<NotCacheable> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let now = Instant::now();
+        let reason = {
+            let entry = self.entries.get(key)?;
+            let (reason, expires) = *entry;
+            (expires > now).then_some(reason)
+        };
+        match reason {
+            Some(reason) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(reason)
+            }
+            None => {
+                self.entries
+                    .remove_if(key, |_, (_, expires)| *expires <= now);
+                None
+            }
+        }
+    }
+
+    /// Whether a fresh verdict is remembered for `key`, without counting a hit.
+    pub fn contains(&self, key: &str) -> bool {
+        !self.ttl.is_zero()
+            && self
+                .entries
+                .get(key)
+                .is_some_and(|entry| entry.1 > Instant::now())
+    }
+
+    /// Record that the response for `key` is not cacheable.
+    pub fn remember(&self, key: &str, reason: NotCacheable) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= CAPACITY {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+            if self.entries.len() >= CAPACITY {
+                return;
+            }
+        }
+        self.entries
+            .insert(key.to_string(), (reason, now + self.ttl));
+    }
+
+    /// Forget every verdict.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Forget verdicts whose key matches `pattern`.
+    pub fn clear_matching(&self, pattern: &str) {
+        self.clear_wildcard(&Wildcard::new(pattern));
+    }
+
+    /// Like [`clear_matching`](Self::clear_matching), for a compiled pattern.
+    pub fn clear_wildcard(&self, pattern: &Wildcard) {
+        self.entries.retain(|key, _| !pattern.matches(key));
+    }
+
+    /// Forget the verdicts for `key` and its `Vary` variants.
+    pub fn clear_key(&self, key: &str) {
+        self.entries
+            .retain(|stored, _| crate::vary::base_key(stored) != key);
+    }
+
+    /// Number of requests that skipped inspection thanks to the memo.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of verdicts currently remembered (including expired ones not yet swept).
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` when nothing is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_verdicts_expire_and_clear_by_pattern() {
+        let memo = DecisionMemo::new(Duration::from_secs(10));
+        memo.remember("GET:/img/a.png", NotCacheable::ContentType);
+        memo.remember("GET:/api/b", NotCacheable::UndecodableBody);
+
+        assert_eq!(
+            memo.lookup("GET:/img/a.png"),
+            Some(NotCacheable::ContentType)
+        );
+        memo.clear_matching("GET:/api/*");
+        assert_eq!(memo.lookup("GET:/api/b"), None);
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(memo.lookup("GET:/img/a.png"), None);
+        assert!(memo.is_empty());
+        assert_eq!(memo.hits(), 1);
+    }
+}
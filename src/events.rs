@@ -0,0 +1,73 @@
+//! Callbacks for feeding cache activity into your own metrics or logs.
+//!
+//! Implement [`ProxyEvents`], overriding only the methods you need, and pass
+//! it to [`crate::CreateProxyConfig::with_events`]. Every method is called
+//! inline while the request is handled, so it must return quickly: bump a
+//! counter, or hand the event to a bounded channel with `try_send` and do the
+//! slow work elsewhere. A panicking method is caught and counted in
+//! `callback_panics`; the request carries on.
+
+use std::time::Duration;
+
+/// Why a request was proxied without the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BypassReason {
+    /// Excluded by path patterns, cache rules, `should_cache_fn`, or a body
+    /// rule.
+    Filtered,
+    /// The cache key function returned `None`.
+    NoCacheKey,
+    /// Caching is paused.
+    Paused,
+    /// The cache strategy is `none`.
+    CacheDisabled,
+    /// The request carries an `Authorization` header or a session cookie.
+    Credentials,
+    /// The request carries the configured `bypass_header`.
+    Requested,
+    /// A cached entry exists but the client's `Accept` rules it out.
+    AcceptMismatch,
+    /// A `Range` request that the cache cannot answer: there is no entry to
+    /// cut the range from, or the header holds several ranges.
+    Range,
+    /// A `HEAD` request with no cached `GET` to answer it from. It is
+    /// forwarded as a `HEAD` and its empty response is not stored.
+    Head,
+}
+
+/// Cache events, each a no-op unless overridden.
+pub trait ProxyEvents: Send + Sync {
+    /// A request was answered from the cache. `age` is how long ago the entry
+    /// was stored, when known.
+    fn on_hit(&self, key: &str, age: Option<Duration>) {
+        let _ = (key, age);
+    }
+
+    /// A cacheable request was not in the cache and goes to the backend.
+    fn on_miss(&self, key: &str) {
+        let _ = key;
+    }
+
+    /// A backend response was stored; `size` is the stored body in bytes,
+    /// after compression.
+    fn on_store(&self, key: &str, size: usize, status: u16) {
+        let _ = (key, size, status);
+    }
+
+    /// A request was proxied without reading or writing the cache.
+    fn on_bypass(&self, path: &str, reason: BypassReason) {
+        let _ = (path, reason);
+    }
+
+    /// The backend could not be reached or gave no response for `path`.
+    fn on_backend_error(&self, path: &str, error: &(dyn std::error::Error + 'static)) {
+        let _ = (path, error);
+    }
+}
+
+/// Events that do nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopEvents;
+
+impl ProxyEvents for NoopEvents {}
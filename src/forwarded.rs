@@ -0,0 +1,165 @@
+//! The `X-Forwarded-For`, `X-Forwarded-Proto`, and `X-Forwarded-Host`
+//! headers the backend gets, so it can see the client's address and the URL
+//! the client asked for rather than the proxy's.
+//!
+//! Unless the proxy is told to trust them, the values a client sent itself
+//! are dropped first: anyone can claim any address in a request header.
+
+use axum::extract::ConnectInfo;
+use axum::http::{header, uri::Scheme, HeaderMap, HeaderName, HeaderValue, Request};
+use std::net::{IpAddr, SocketAddr};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Request extension marking a request that reached the proxy over TLS, so
+/// that it is forwarded with `X-Forwarded-Proto: https`. The HTTPS listener
+/// of the binary adds it; applications that terminate TLS themselves can add
+/// it with `axum::Extension(TlsConnection)`.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsConnection;
+
+/// Add the forwarding headers to `req`.
+///
+/// The client address comes from `ConnectInfo<SocketAddr>`, which is only
+/// present when the router is served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`; without it
+/// `X-Forwarded-For` is left as the client sent it (or absent).
+pub fn apply<B>(req: &mut Request<B>, trust_incoming: bool) {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let tls = req.extensions().get::<TlsConnection>().is_some()
+        || req.uri().scheme() == Some(&Scheme::HTTPS);
+    let host = original_host(req);
+    let proto = if tls { "https" } else { "http" };
+    set_headers(req.headers_mut(), client, proto, host, trust_incoming);
+}
+
+/// The host the client asked for: its `Host` header, or for HTTP/2 requests,
+/// which carry it in `:authority` instead, the URI's authority.
+pub fn original_host<B>(req: &Request<B>) -> Option<HeaderValue> {
+    req.headers().get(header::HOST).cloned().or_else(|| {
+        req.uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    })
+}
+
+fn set_headers(
+    headers: &mut HeaderMap,
+    client: Option<IpAddr>,
+    proto: &'static str,
+    host: Option<HeaderValue>,
+    trust_incoming: bool,
+) {
+    if !trust_incoming {
+        headers.remove(X_FORWARDED_FOR);
+        headers.remove(X_FORWARDED_PROTO);
+        headers.remove(X_FORWARDED_HOST);
+    }
+
+    if let Some(client) = client {
+        // Several field lines are one list; they are folded into one line.
+        let mut chain: Vec<String> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect();
+        chain.push(client.to_string());
+        if let Ok(value) = HeaderValue::from_str(&chain.join(", ")) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+    }
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+    if let Some(host) = host {
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().uri("/page");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 50000))));
+        req
+    }
+
+    fn forwarded(req: &Request<Body>) -> [Option<&str>; 3] {
+        [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST]
+            .map(|name| req.headers().get(name).and_then(|v| v.to_str().ok()))
+    }
+
+    #[test]
+    fn test_headers_are_set_from_the_connection() {
+        let mut req = request(&[("host", "example.com")]);
+        apply(&mut req, false);
+        assert_eq!(
+            forwarded(&req),
+            [Some("203.0.113.7"), Some("http"), Some("example.com")]
+        );
+
+        let mut req = request(&[("host", "example.com")]);
+        req.extensions_mut().insert(TlsConnection);
+        apply(&mut req, false);
+        assert_eq!(forwarded(&req)[1], Some("https"));
+    }
+
+    #[test]
+    fn test_client_values_are_stripped_unless_trusted() {
+        let spoofed = [
+            ("host", "example.com"),
+            ("x-forwarded-for", "10.0.0.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.test"),
+        ];
+
+        let mut req = request(&spoofed);
+        apply(&mut req, false);
+        assert_eq!(
+            forwarded(&req),
+            [Some("203.0.113.7"), Some("http"), Some("example.com")]
+        );
+
+        let mut req = request(&spoofed);
+        apply(&mut req, true);
+        assert_eq!(
+            forwarded(&req),
+            [
+                Some("10.0.0.1, 10.0.0.2, 203.0.113.7"),
+                Some("https"),
+                Some("evil.test")
+            ]
+        );
+        assert_eq!(req.headers().get_all(X_FORWARDED_FOR).iter().count(), 1);
+    }
+
+    #[test]
+    fn test_without_connect_info() {
+        let mut req = Request::builder()
+            .uri("https://example.com/page")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        apply(&mut req, false);
+        assert_eq!(forwarded(&req), [None, Some("https"), Some("example.com")]);
+    }
+}
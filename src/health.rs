@@ -0,0 +1,221 @@
+//! Liveness and readiness probes.
+//!
+//! Every proxy router answers [`HEALTH_PATH`] itself, without touching the
+//! backend or the cache, so a load balancer can tell the process is serving.
+//! The control server's `GET /readyz` additionally probes each backend with
+//! a `HEAD` request to `health_check_path`, whose result is reused for
+//! [`PROBE_CACHE_TTL`] so frequent probes reach the origin at most once per
+//! interval.
+//!
+//! With `health_check_interval` set as well, a background task probes every
+//! backend on that interval and marks it up or down. Requests are not sent to
+//! a backend that is down; when all of them are, they are answered from stale
+//! cache entries or with `503`.
+
+use crate::backend::{Backend, BackendSlot};
+use crate::proxy::ProxyState;
+use axum::http::StatusCode;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+/// Path the proxy router answers `200 ok` on without forwarding.
+pub const HEALTH_PATH: &str = "/__phantom/health";
+
+/// How long a backend probe result is reused.
+pub const PROBE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Timeout of a backend probe when none is configured.
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Answers [`HEALTH_PATH`].
+pub async fn health_handler() -> &'static str {
+    "ok"
+}
+
+/// The cached result of probing a backend with `HEAD health_check_path`.
+pub(crate) struct BackendProbe {
+    path: Option<String>,
+    timeout: Duration,
+    /// When the last probe finished and whether the backend answered. Held
+    /// during a probe so concurrent checks wait for it instead of sending
+    /// their own.
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl BackendProbe {
+    pub(crate) fn new(path: Option<String>, timeout: Duration) -> Self {
+        Self {
+            path,
+            timeout,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Whether `backend` answered the probe with a status below 500, or
+    /// `None` when no `health_check_path` is configured. Any other answer
+    /// (a `401` from an origin behind auth, say) still proves it reachable.
+    pub(crate) async fn check(&self, backend: &Backend) -> Option<bool> {
+        let path = self.path.as_deref()?;
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, reachable)) = *last {
+            if checked_at.elapsed() < PROBE_CACHE_TTL {
+                return Some(reachable);
+            }
+        }
+
+        let url = format!("{}{}", backend.url(), path);
+        let reachable = match probe(backend, path, self.timeout).await {
+            Ok(status) if status.is_server_error() => {
+                tracing::warn!("Backend health check {} answered {}", url, status);
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!("Backend health check {} failed: {}", url, e);
+                false
+            }
+        };
+        *last = Some((Instant::now(), reachable));
+        Some(reachable)
+    }
+}
+
+/// Send `HEAD path` to `backend`.
+async fn probe(
+    backend: &Backend,
+    path: &str,
+    timeout: Duration,
+) -> Result<StatusCode, reqwest::Error> {
+    let url = format!("{}{}", backend.url(), path);
+    let response = backend.client().head(&url).timeout(timeout).send().await?;
+    Ok(response.status())
+}
+
+/// What the last active check of a backend found.
+#[derive(Clone, Debug)]
+pub struct BackendHealth {
+    /// Whether it answered with a status below 500.
+    pub up: bool,
+    /// The status it answered with; `None` when no response came.
+    pub status: Option<u16>,
+    /// Why no response came.
+    pub error: Option<String>,
+    pub checked_at: Instant,
+}
+
+/// The task probing every backend of `state` each `health_check_interval`,
+/// or `None` unless both that and `health_check_path` are configured.
+pub(crate) fn active_checks(state: &ProxyState) -> Option<impl Future<Output = ()>> {
+    let config = state.config();
+    let path = config.health_check_path.clone()?;
+    let interval = config.health_check_interval?;
+    let (slot, timeout) = (state.backend().clone(), config.health_check_timeout);
+    Some(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            check_all(&slot, &path, timeout).await;
+        }
+    })
+}
+
+/// Probe every backend at once and record the results.
+async fn check_all(slot: &BackendSlot, path: &str, timeout: Duration) {
+    let mut checks = JoinSet::new();
+    for backend in slot.all() {
+        let path = path.to_string();
+        checks.spawn(async move {
+            let result = probe(&backend, &path, timeout).await;
+            (backend, result)
+        });
+    }
+    while let Some(Ok((backend, result))) = checks.join_next().await {
+        record(&backend, result);
+    }
+}
+
+fn record(backend: &Arc<Backend>, result: Result<StatusCode, reqwest::Error>) {
+    let health = match result {
+        Ok(status) => BackendHealth {
+            up: !status.is_server_error(),
+            status: Some(status.as_u16()),
+            error: None,
+            checked_at: Instant::now(),
+        },
+        Err(e) => BackendHealth {
+            up: false,
+            status: None,
+            error: Some(e.without_url().to_string()),
+            checked_at: Instant::now(),
+        },
+    };
+    let was_up = backend.is_up();
+    match (was_up, &health) {
+        (
+            true,
+            BackendHealth {
+                up: false,
+                status: Some(status),
+                ..
+            },
+        ) => {
+            tracing::info!("Backend '{}' is down: answered {}", backend.url(), status)
+        }
+        (
+            true,
+            BackendHealth {
+                up: false,
+                error: Some(error),
+                ..
+            },
+        ) => {
+            tracing::info!("Backend '{}' is down: {}", backend.url(), error)
+        }
+        (false, BackendHealth { up: true, .. }) => {
+            tracing::info!("Backend '{}' is up again", backend.url())
+        }
+        _ => {}
+    }
+    backend.set_health(health);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendSlot, PoolOptions};
+    use crate::test_support::spawn_backend;
+    use axum::http::StatusCode;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_probe_result_is_reused_within_ttl() {
+        let probes = Arc::new(AtomicUsize::new(0));
+        let counter = probes.clone();
+        let url = spawn_backend(Router::new().fallback(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { StatusCode::SERVICE_UNAVAILABLE }
+        }))
+        .await;
+        let config = crate::CreateProxyConfig::new(url.clone());
+        let backend = BackendSlot::new(vec![url], PoolOptions::from_config(&config))
+            .unwrap()
+            .current();
+
+        assert_eq!(
+            BackendProbe::new(None, DEFAULT_HEALTH_CHECK_TIMEOUT)
+                .check(&backend)
+                .await,
+            None
+        );
+        let probe = BackendProbe::new(Some("/up".to_string()), DEFAULT_HEALTH_CHECK_TIMEOUT);
+        assert_eq!(probe.check(&backend).await, Some(false));
+        assert_eq!(probe.check(&backend).await, Some(false));
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,99 @@
+//! Opt-in rewriting of HTML bodies before they are cached.
+//!
+//! Pages that are identical except for per-request noise — a CSRF token, a
+//! "rendered at" comment — make every stored copy unique. Normalization rules
+//! are regular expressions applied to the decoded body of `text/html`
+//! responses just before they are stored; each match is replaced with the
+//! rule's replacement (empty by default, which deletes the match).
+//!
+//! This changes what clients are served: the normalized body is what gets
+//! cached and answered from. Only use it for content the page does not need,
+//! or replace it with something harmless.
+
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// One normalization rule, as written in configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HtmlNormalizeRule {
+    /// Regular expression matched against the body (`regex` crate syntax).
+    pub pattern: String,
+    /// Replacement text; `$1`, `${name}` refer to capture groups.
+    #[serde(default)]
+    pub replace: String,
+}
+
+impl HtmlNormalizeRule {
+    pub fn new(pattern: impl Into<String>, replace: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            replace: replace.into(),
+        }
+    }
+}
+
+/// Compiled normalization rules, applied in order.
+#[derive(Clone, Debug)]
+pub struct HtmlNormalizer {
+    rules: Vec<(Regex, String)>,
+}
+
+impl HtmlNormalizer {
+    /// Compile `rules`. Fails on the first invalid pattern.
+    pub fn new(rules: &[HtmlNormalizeRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern).with_context(|| {
+                    format!("invalid HTML normalization pattern '{}'", rule.pattern)
+                })?;
+                Ok((regex, rule.replace.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Apply every rule to `body`, borrowing it when nothing matched.
+    pub fn apply<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut body = Cow::Borrowed(body);
+        for (regex, replace) in &self.rules {
+            if let Cow::Owned(replaced) = regex.replace_all(&body, replace.as_bytes()) {
+                body = Cow::Owned(replaced);
+            }
+        }
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_apply_in_order_with_captures() {
+        let normalizer = HtmlNormalizer::new(&[
+            HtmlNormalizeRule::new(r"<!-- rendered at [^>]* -->", ""),
+            HtmlNormalizeRule::new(r#"(<meta name="csrf-token" content=")[^"]*(")"#, "${1}${2}"),
+        ])
+        .unwrap();
+
+        let body =
+            br#"<html><!-- rendered at 12:00:01 --><meta name="csrf-token" content="k9x"></html>"#;
+        assert_eq!(
+            normalizer.apply(body).as_ref(),
+            br#"<html><meta name="csrf-token" content=""></html>"#
+        );
+        assert!(matches!(
+            normalizer.apply(b"<html>plain</html>"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_rejected() {
+        let error = HtmlNormalizer::new(&[HtmlNormalizeRule::new("(unclosed", "")]).unwrap_err();
+        assert!(error.to_string().contains("(unclosed"), "{}", error);
+    }
+}
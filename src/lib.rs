@@ -1,18 +1,60 @@
 #[cfg(all(feature = "native-tls", feature = "rustls"))]
 compile_error!("Features `native-tls` and `rustls` are mutually exclusive — enable only one.");
 
+pub mod backend;
+pub mod backend_headers;
+mod background;
+pub mod body_cache;
+pub mod body_preview;
 pub mod cache;
+pub mod cache_control;
+pub mod cache_rules;
+#[cfg(feature = "cassette")]
+pub mod cassette;
+pub mod compressed_variants;
 pub mod compression;
+pub mod conditional;
 pub mod config;
 pub mod control;
+pub mod credentials;
+pub mod decision_memo;
+pub mod events;
+pub mod forwarded;
+pub mod health;
+pub mod html_normalize;
+pub mod location;
+pub mod negotiation;
+pub mod normalize;
+pub mod not_found_meta;
+pub mod outbound;
 pub mod path_matcher;
+pub mod persistence;
 pub mod proxy;
+pub mod query_params;
+pub mod range;
+pub mod redact;
+#[cfg(feature = "redis-backend")]
+pub mod redis_cache;
+pub mod render_queue;
+pub mod stats;
+pub mod status_page;
+pub mod transform;
+pub mod upstream_auth;
+pub mod vary;
+pub mod warm;
+pub mod write_limiter;
 
-use axum::{extract::Extension, Router};
+#[cfg(test)]
+mod test_support;
+
+use axum::{routing::get, Router};
+use backend::BackendSlot;
 use cache::{CacheHandle, CacheStore};
 use proxy::ProxyState;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 
@@ -183,8 +225,9 @@ pub enum ProxyMode {
     /// - `fallthrough = false` (default): return 404 immediately.
     /// - `fallthrough = true`: fall through to the upstream backend.
     ///
-    /// Use the [`CacheHandle`] returned by [`create_proxy`] to manage snapshots
-    /// at runtime via `add_snapshot`, `refresh_snapshot`, `remove_snapshot`, and
+    /// Use the [`CacheHandle`] from the [`ProxyHandle`] returned by
+    /// [`create_proxy_handle`] to manage snapshots at runtime via
+    /// `add_snapshot`, `refresh_snapshot`, `remove_snapshot`, and
     /// `refresh_all_snapshots`.
     PreGenerate {
         /// The paths to pre-generate at startup (e.g. `"/book/1"`).
@@ -195,7 +238,88 @@ pub enum ProxyMode {
     },
 }
 
-/// Information about an incoming request for cache key generation
+/// What to do with an upgrade request when upgrades are unavailable
+/// (`enable_websocket = false`, or a pure SSG server).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeFallback {
+    /// Answer with `upgrade_reject_status` (default).
+    #[default]
+    Reject,
+    /// Drop the `Upgrade` / `Connection: upgrade` headers and handle the
+    /// request like any other through the cache and proxy path.
+    IgnoreHeader,
+}
+
+/// What to do with a cache hit whose `Content-Type` the request's `Accept`
+/// header rules out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AcceptMismatch {
+    /// Serve the cached entry regardless of `Accept` (default).
+    #[default]
+    Ignore,
+    /// Skip the cache and forward the request to the backend, which may be
+    /// able to negotiate. The backend's answer is not stored.
+    Bypass,
+    /// Answer `406 Not Acceptable`.
+    Reject,
+}
+
+/// What a request does when the cache cannot be read or written. Only
+/// filesystem storage fails this way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheErrorPolicy {
+    /// Carry on without the cache (default): a failed lookup is treated as a
+    /// miss, and a response that cannot be stored is served uncached.
+    #[default]
+    Bypass,
+    /// Answer `503 Service Unavailable`.
+    Fail,
+}
+
+/// What a cacheable request does when the backend fails: it cannot be
+/// reached, its body cannot be read, or it answers 500, 502, 503, or 504.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendErrorPolicy {
+    /// Serve the cached entry for the request even if its TTL has lapsed.
+    /// Without one, the failure is passed on.
+    StaleIfAvailable,
+    /// Pass the failure on (default).
+    #[default]
+    Fail,
+}
+
+/// What a proxy does with a cache snapshot or Redis entry written in an
+/// earlier [`persistence::CACHE_FORMAT_VERSION`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatMismatchPolicy {
+    /// Convert it to the current format (default). Versions this build
+    /// cannot convert are still discarded.
+    #[default]
+    Migrate,
+    /// Ignore it, starting with a cold cache.
+    Discard,
+}
+
+/// What happens to a backend fetch when the client disconnects before the
+/// response is ready.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientDisconnect {
+    /// Cancel the backend request (default).
+    #[default]
+    Abort,
+    /// Let cache misses on cacheable paths finish so the response is still
+    /// stored for the next client. Other requests are cancelled.
+    CompleteCacheable,
+}
+
+/// Information about an incoming request for cache key generation and
+/// `should_cache_fn`
 #[derive(Clone, Debug)]
 pub struct RequestInfo<'a> {
     /// HTTP method (e.g., "GET", "POST", "PUT", "DELETE")
@@ -208,12 +332,63 @@ pub struct RequestInfo<'a> {
     pub headers: &'a axum::http::HeaderMap,
 }
 
+/// What a [`CreateProxyConfig::with_should_cache_fn`] closure decides for a
+/// request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheDecision {
+    /// Cache the request, even if the path patterns or cache rules exclude it.
+    Cache,
+    /// Forward the request without caching, even if the patterns include it.
+    Bypass,
+    /// Leave it to the path patterns and cache rules.
+    #[default]
+    Default,
+}
+
+/// Key function set with [`CreateProxyConfig::with_cache_key_fn`] or
+/// [`CreateProxyConfig::with_cache_key_opt_fn`].
+pub type CacheKeyFn = Arc<dyn Fn(&RequestInfo) -> Option<String> + Send + Sync>;
+
+/// Future returned by an [`AsyncCacheKeyFn`].
+pub type CacheKeyFuture<'a> = Pin<Box<dyn Future<Output = Option<String>> + Send + 'a>>;
+
+/// Key function set with [`CreateProxyConfig::with_async_cache_key_fn`].
+pub type AsyncCacheKeyFn =
+    Arc<dyn for<'a> Fn(&'a RequestInfo<'a>) -> CacheKeyFuture<'a> + Send + Sync>;
+
+/// Closure set with [`CreateProxyConfig::with_should_cache_fn`].
+pub type ShouldCacheFn = Arc<dyn Fn(&RequestInfo) -> CacheDecision + Send + Sync>;
+
+/// Predicate set with [`CreateProxyConfig::with_body_cache_skip_fn`].
+pub type BodyCacheSkipFn = Arc<dyn Fn(&RequestInfo, &[u8]) -> bool + Send + Sync>;
+
+/// Hook set with [`CreateProxyConfig::with_transform_response`] or
+/// [`CreateProxyConfig::with_transform_on_serve`]. It receives the response
+/// with its body decoded and returns the one to use instead.
+pub type TransformResponseFn =
+    Arc<dyn Fn(&RequestInfo, cache::CachedResponse) -> cache::CachedResponse + Send + Sync>;
+
+/// Hook set with [`CreateProxyConfig::with_transform_request`].
+pub type TransformRequestFn = Arc<dyn Fn(&mut outbound::OutboundRequest) + Send + Sync>;
+
 /// Configuration for creating a proxy
 #[derive(Clone)]
 pub struct CreateProxyConfig {
     /// The backend URL to proxy requests to
     pub proxy_url: String,
 
+    /// Backends requests are spread over (default: empty, meaning only
+    /// `proxy_url`). Set with [`CreateProxyConfig::with_backends`], which
+    /// also makes the first one `proxy_url`.
+    pub backends: Vec<String>,
+
+    /// How requests are spread over `backends` (default: round robin).
+    pub load_balancing: backend::LoadBalancing,
+
+    /// How long one of several `backends` is skipped after a request to it
+    /// gets no response (default: 10 seconds).
+    pub backend_cooldown: std::time::Duration,
+
     /// Paths to include in caching (empty means include all)
     /// Supports wildcards and method prefixes: "/api/*", "POST /api/*", "GET /*/users", etc.
     pub include_paths: Vec<String>,
@@ -223,6 +398,17 @@ pub struct CreateProxyConfig {
     /// Exclude overrides include
     pub exclude_paths: Vec<String>,
 
+    /// Ordered cache rules used instead of `include_paths` and
+    /// `exclude_paths` when non-empty (default: empty). Entries starting
+    /// with `!` keep matching requests out of the cache; the last matching
+    /// entry decides, and requests none matches are not cached.
+    pub path_rules: Vec<String>,
+
+    /// Whether `*` in path patterns and purge patterns crosses `/`
+    /// (default: legacy, it does). With [`path_matcher::WildcardSyntax::Segments`],
+    /// `*` stays within one segment and `**` crosses segments.
+    pub wildcard_syntax: path_matcher::WildcardSyntax,
+
     /// Enable WebSocket and protocol upgrade support (default: true)
     /// When enabled, requests with Connection: Upgrade headers will bypass
     /// the cache and establish a direct bidirectional TCP tunnel
@@ -234,22 +420,185 @@ pub struct CreateProxyConfig {
     pub forward_get_only: bool,
 
     /// Custom cache key generator
-    /// Takes request info and returns a cache key
+    /// Takes request info and returns a cache key, or `None` to forward the
+    /// request without touching the cache
     /// Default: method + path + query string
-    pub cache_key_fn: Arc<dyn Fn(&RequestInfo) -> String + Send + Sync>,
+    pub cache_key_fn: CacheKeyFn,
+
+    /// Awaited instead of `cache_key_fn` when set, for keys that need I/O
+    /// such as a session lookup. It runs before every cache lookup, so its
+    /// latency is added to every request, hits included.
+    pub async_cache_key_fn: Option<AsyncCacheKeyFn>,
+
+    /// Lowercase names of request headers whose values are appended to every
+    /// key the key function returns, as
+    /// `GET:/page#headers=accept-language=en&x-tenant-id=acme`. A missing
+    /// header counts as empty and values are cut to
+    /// [`cache_rules::MAX_KEY_HEADER_VALUE_LEN`] bytes. Empty by default.
+    pub cache_key_headers: Vec<String>,
+
+    /// Query parameters removed from the query the cache key is built from,
+    /// e.g. `utm_source` or `gclid`, so URLs differing only in them share an
+    /// entry. The backend still receives the full query. Empty by default.
+    pub ignored_query_params: Vec<String>,
+
+    /// When non-empty, the only query parameters kept for the cache key;
+    /// `ignored_query_params` still applies to them. Empty by default.
+    pub allowed_query_params: Vec<String>,
+
+    /// Called for every request after the path patterns and cache rules; a
+    /// decision other than [`CacheDecision::Default`] overrides them. Use it
+    /// for decisions based on headers such as cookies.
+    pub should_cache_fn: Option<ShouldCacheFn>,
     /// Capacity for special 404 cache. When 0, 404 caching is disabled.
     pub cache_404_capacity: usize,
 
-    /// When true, treat a response containing the meta tag `<meta name="phantom-404" content="true">` as a 404
+    /// Seconds a 5xx response may be served from cache (default: 0). When
+    /// non-zero, 5xx responses go to a separate bounded error store instead of
+    /// the main cache and are fetched again once this lapses.
+    pub cache_errors_ttl_secs: u64,
+
+    /// Capacity of the error store used when `cache_errors_ttl_secs` is set
+    /// (default: 100).
+    pub cache_errors_capacity: usize,
+
+    /// Approximate byte budget for the main cache (default: 0 = unlimited).
+    /// Least recently used entries are evicted to stay within it, and a
+    /// response larger than the whole budget is not cached.
+    pub cache_max_bytes: usize,
+
+    /// Largest request body read, in bytes; longer uploads are refused with
+    /// `413` (default: 10 MiB; 0 = unlimited).
+    pub max_request_body_bytes: usize,
+
+    /// Largest backend response buffered for the cache, in bytes. Longer
+    /// responses are streamed to the client and not cached (default: 20 MiB;
+    /// 0 = unlimited).
+    pub max_cacheable_response_bytes: usize,
+
+    /// When true, treat an HTML response containing the meta tag `<meta name="phantom-404" content="true">` as a 404
     /// This is an optional performance-affecting fallback to detect framework-generated 404 pages.
     pub use_404_meta: bool,
 
+    /// Follow the backend's `Cache-Control` (default: false). `no-store` and
+    /// `private` responses are not cached, `no-cache` ones are stored but
+    /// fetched again on the next request, and `s-maxage`/`max-age` limit how
+    /// long a main-cache entry is served.
+    pub respect_cache_control: bool,
+
+    /// Never store a response that sets a cookie (default: true). Such
+    /// responses are usually specific to one visitor; they are still
+    /// forwarded unchanged.
+    pub skip_set_cookie_responses: bool,
+
+    /// Neither look up nor store requests carrying an `Authorization` header
+    /// or a cookie named in `bypass_cookie_names` (default: true). Pages
+    /// rendered for a signed-in visitor are then never served to others.
+    /// `should_cache_fn` still has the last word.
+    pub bypass_credentialed_requests: bool,
+
+    /// Cookie names that make a request credentialed, ignoring case, with
+    /// `*` matching any run of characters (default: `session*`, `*_session`,
+    /// `*sessid`, `connect.sid`, `auth_token`, `remember_*`, and
+    /// `wordpress_logged_in_*`).
+    pub bypass_cookie_names: Vec<String>,
+
+    /// Let a request with `Cache-Control: no-cache` or `Pragma: no-cache`
+    /// skip the cache lookup and replace the stored entry with a fresh one
+    /// (default: false). Anyone can then make the proxy fetch from the
+    /// backend at will.
+    pub respect_client_no_cache: bool,
+
+    /// A secret that forces the same refresh when sent as
+    /// `X-Phantom-Revalidate: <token>`, whatever `respect_client_no_cache`
+    /// says (default: none).
+    pub revalidate_token: Option<String>,
+
+    /// A request header, e.g. `x-phantom-bypass`, that sent as `1` or `true`
+    /// makes the request skip the cache entirely (default: none).
+    pub bypass_header: Option<String>,
+
+    /// A request header, e.g. `x-phantom-purge`, whose value, when it equals
+    /// `purge_token`, removes the request's cache entry before it is handled
+    /// (default: none).
+    pub purge_header: Option<String>,
+
+    /// The secret `purge_header` must carry (default: none; every purge is
+    /// refused with `403`).
+    pub purge_token: Option<String>,
+
+    /// Answer a `HEAD` request from the `GET` entry for the same URL, with
+    /// its status and headers but no body, and never store `HEAD` responses
+    /// (default: true). Turn off for backends that answer `HEAD` differently.
+    pub serve_head_from_get: bool,
+
+    /// Store a response whose `Vary` names request headers once per
+    /// combination of their values, and never store `Vary: *` responses
+    /// (default: true).
+    pub respect_vary: bool,
+
+    /// Keep the `X-Forwarded-For`, `X-Forwarded-Proto`, and
+    /// `X-Forwarded-Host` a client sent, adding to them, instead of replacing
+    /// them (default: false). Only safe behind another proxy that sets them.
+    pub trust_forwarded_headers: bool,
+
+    /// Send the client's `Host` to the backend instead of the host of
+    /// `proxy_url` (default: false). Applies to upgrade requests too.
+    pub preserve_host: bool,
+
+    /// Follow backend redirects and answer with their target instead of
+    /// passing the `3xx` to the client (default: false).
+    pub follow_redirects: bool,
+
+    /// Rewrite a `Location` header pointing at `proxy_url` so that it points
+    /// at the proxy (default: false). Other locations are left alone.
+    pub rewrite_location: bool,
+
+    /// Base URL rewritten locations are made absolute against, such as
+    /// `https://www.example.com`. When unset they become relative paths.
+    pub location_base_url: Option<String>,
+
+    /// Path prefix the proxy is mounted under, such as `/app`, stripped
+    /// before path matching, cache keys, and the backend request, so
+    /// `/app/page` fetches `{proxy_url}/page`. Requests outside it get
+    /// `404`. Not needed under `Router::nest`, which strips the prefix
+    /// itself. `None` (default) serves every path as is.
+    pub path_prefix: Option<String>,
+
+    /// Bytes at the start of an HTML body searched for the phantom-404 meta
+    /// tag (default: 16 KiB).
+    pub meta_404_scan_bytes: usize,
+
     /// Controls which responses should be cached after the backend responds.
     pub cache_strategy: CacheStrategy,
 
+    /// Content type prefixes of responses that never end on their own, such
+    /// as Server-Sent Events. They are streamed to the client as they arrive
+    /// and never cached (default: `text/event-stream`).
+    pub streaming_content_types: Vec<String>,
+
     /// Controls how cached bodies are stored in memory.
     pub compress_strategy: CompressStrategy,
 
+    /// Content type prefixes compressed, when stored and when served
+    /// (default: text, JSON, JavaScript, XML, SVG, and WebAssembly). Other
+    /// bodies are stored and served as the backend sent them, decoded.
+    pub compress_content_types: Vec<String>,
+
+    /// Smallest body compressed, in bytes (default: 0 = any size).
+    pub compress_min_bytes: usize,
+
+    /// Compress cached responses for clients that do not accept the stored
+    /// encoding, e.g. gzip for a client without Brotli, or anything for
+    /// entries stored with `CompressStrategy::None` (default: false, such
+    /// clients get the decoded body).
+    pub compress_on_serve: bool,
+
+    /// Memory kept for bodies compressed by `compress_on_serve`, so each one
+    /// is compressed once rather than on every hit (default: 64 MiB). The
+    /// oldest are dropped first; 0 compresses on every hit.
+    pub compress_variants_max_bytes: usize,
+
     /// Controls where cached response bodies are stored.
     pub cache_storage_mode: CacheStorageMode,
 
@@ -262,6 +611,217 @@ pub struct CreateProxyConfig {
     /// Webhooks called for every request before cache reads.
     /// Blocking webhooks gate access; notify webhooks are fire-and-forget.
     pub webhooks: Vec<WebhookConfig>,
+
+    /// Patterns whose cache misses are queued for an external renderer.
+    /// Same syntax as `include_paths`. Empty (default) disables the render queue.
+    pub render_queue_patterns: Vec<String>,
+
+    /// Maximum number of pending render jobs (default: 1000).
+    pub render_queue_capacity: usize,
+
+    /// Optional file used to persist pending render jobs across restarts.
+    pub render_queue_path: Option<PathBuf>,
+
+    /// Paths [`ProxyHandle::warm`] fetches at once (default: 4).
+    pub warm_concurrency: usize,
+
+    /// Snapshot file the cache is saved to and restored from on startup.
+    /// `None` (default) keeps the cache in memory only.
+    pub persistence_path: Option<PathBuf>,
+
+    /// How often the cache snapshot is rewritten (default: 60s). Zero
+    /// writes it only when asked to, e.g. on shutdown.
+    pub persistence_interval: std::time::Duration,
+
+    /// What to do with a snapshot or Redis entry from an earlier format
+    /// version (default: migrate it).
+    pub format_mismatch_policy: FormatMismatchPolicy,
+
+    /// Maximum cache inserts per second across all clients. Responses beyond
+    /// the ceiling are served but not stored. When 0 (default), writes are unlimited.
+    pub max_cache_writes_per_sec: u32,
+
+    /// Store cached responses after answering the client, through a queue of
+    /// this many pending writes. Writes that find the queue full are dropped
+    /// and counted. When 0 (default), each miss is stored before it is
+    /// answered, so the next request for the key is a hit.
+    pub cache_write_queue_capacity: usize,
+
+    /// Clear the cache when the backend URL is swapped at runtime (default: true).
+    /// Disable when the new backend serves the same content as the old one.
+    pub purge_cache_on_backend_change: bool,
+
+    /// Idle connections kept open to the backend host (default: unlimited).
+    /// Idle connections are closed after 90 seconds either way.
+    pub pool_max_idle_per_host: usize,
+
+    /// Time allowed to establish a backend connection, including the TLS
+    /// handshake (default: 5 seconds).
+    pub connect_timeout: std::time::Duration,
+
+    /// Time allowed for a whole backend request, from connecting until the
+    /// body is read (default: 30 seconds). Requests that run out of time are
+    /// answered with 504.
+    pub request_timeout: std::time::Duration,
+
+    /// Time allowed for an upgrade request to connect to the backend and
+    /// get its `101` (default: 10 seconds). An established tunnel has no
+    /// deadline.
+    pub upgrade_handshake_timeout: std::time::Duration,
+
+    /// Backend path `ProxyHandle::check_backend` sends a `HEAD` request to.
+    /// `None` (default) skips the backend check.
+    pub health_check_path: Option<String>,
+
+    /// Time allowed for the backend health check (default: 2 seconds).
+    pub health_check_timeout: std::time::Duration,
+
+    /// Probe every backend at `health_check_path` on this interval and stop
+    /// routing requests to those that fail (default: `None`, no active
+    /// checks).
+    pub health_check_interval: Option<std::time::Duration>,
+
+    /// Minutes of per-minute hit/miss history kept for windowed stats (default: 60).
+    pub stats_window_minutes: usize,
+
+    /// Seconds to remember that a key's response was not cacheable, so repeat
+    /// requests skip body inspection (default: 30). When 0, the memo is disabled.
+    pub decision_memo_ttl_secs: u64,
+
+    /// How upgrade requests are handled when upgrades are unavailable.
+    pub upgrade_fallback: UpgradeFallback,
+
+    /// Limit `UpgradeFallback::IgnoreHeader` to these patterns (same syntax as
+    /// `include_paths`). Empty means every path falls back.
+    pub upgrade_fallback_paths: Vec<String>,
+
+    /// Status returned for rejected upgrade requests (default: 501).
+    pub upgrade_reject_status: axum::http::StatusCode,
+
+    /// How cache hits are handled when the cached `Content-Type` does not
+    /// satisfy the request's `Accept` header (default: ignore).
+    pub accept_mismatch: AcceptMismatch,
+
+    /// What to do when a cache lookup or store fails (default: bypass).
+    pub cache_error_policy: CacheErrorPolicy,
+
+    /// What to do when the backend fails a cacheable request (default:
+    /// pass the failure on). Entries whose TTL lapsed are only kept for this
+    /// when it is `StaleIfAvailable`.
+    pub backend_error_policy: BackendErrorPolicy,
+
+    /// Oldest entry, in seconds since it was stored, that
+    /// `backend_error_policy` may serve stale (default: 86400). Expired
+    /// entries are kept at most this long past their TTL.
+    pub stale_max_age_secs: u64,
+
+    /// Let a backend 500, 502, 503, or 504 answer be replaced by a stale
+    /// entry, not only a backend that cannot be reached (default: false).
+    pub stale_on_error_status: bool,
+
+    /// What happens to a backend fetch whose client has gone away (default:
+    /// abort it).
+    pub client_disconnect: ClientDisconnect,
+
+    /// Response header reporting whether the cache answered: `HIT`, `MISS`,
+    /// `BYPASS`, or `STALE` (default: `X-Cache`). Hits and stale entries also
+    /// carry `Age`. `None`
+    /// leaves responses as the backend or cache produced them.
+    pub cache_status_header: Option<String>,
+
+    /// Let concurrent misses for the same cache key share one backend fetch
+    /// (default: true). Requests that arrive while the first is fetching wait
+    /// for it and are answered from what it stored; when nothing was stored
+    /// they fetch for themselves.
+    pub coalesce_misses: bool,
+
+    /// Query parameters whose values are replaced with `[redacted]` in logs
+    /// (default: [`redact::DEFAULT_REDACT_QUERY_PARAMS`]). Forwarded requests
+    /// and cache keys are unaffected.
+    pub redact_query_params: Vec<String>,
+
+    /// Headers whose values are replaced with `[redacted]` in logs
+    /// (default: [`redact::DEFAULT_REDACT_HEADERS`]).
+    pub redact_headers: Vec<String>,
+
+    /// Store each entry's method and path and check them on every hit
+    /// (default: true). A hit stored for a different request is evicted and
+    /// treated as a miss, guarding against colliding `cache_key_fn` keys.
+    pub verify_cache_origin: bool,
+
+    /// Rewrites applied to `text/html` bodies before they are stored. The
+    /// normalized body is what clients are served from the cache. `None`
+    /// (default) stores bodies as received.
+    pub html_normalizer: Option<html_normalize::HtmlNormalizer>,
+
+    /// Limit `html_normalizer` to these patterns (same syntax as
+    /// `include_paths`). Empty means every cached HTML page is normalized.
+    pub html_normalize_paths: Vec<String>,
+
+    /// Scope this proxy's invalidation listener answers to. It acts on
+    /// unscoped invalidations and on those sent through
+    /// `CacheHandle::scoped` with the same scope. `None` (default) acts on
+    /// unscoped invalidations only.
+    pub invalidation_scope: Option<String>,
+
+    /// Per-path rules deciding whether a request is cached, for how long,
+    /// and which headers are part of its key. The first matching rule
+    /// decides; requests no rule matches follow `include_paths` and
+    /// `exclude_paths`. Empty by default.
+    pub cache_rules: Vec<cache_rules::CacheRule>,
+
+    /// Requests cached by their body, e.g. GraphQL queries. Matching
+    /// requests get a key component derived from the body and a short TTL;
+    /// those a rule refuses bypass the cache. Empty (default) leaves bodies
+    /// out of caching entirely.
+    pub body_cache_rules: Vec<body_cache::BodyCacheRule>,
+
+    /// Called for requests matched by a `body_cache_rules` entry; returning
+    /// `true` forwards the request uncached. Use it for checks `skip_if`
+    /// cannot express.
+    pub body_cache_skip_fn: Option<BodyCacheSkipFn>,
+
+    /// Rewrites a response once, before it is stored; the cache keeps the
+    /// result. Responses that are not stored are not passed to it.
+    pub transform_response: Option<TransformResponseFn>,
+
+    /// Rewrites every response as it is served, from the cache or not.
+    pub transform_on_serve: Option<TransformResponseFn>,
+
+    /// `Content-Type` prefixes the transform hooks run for
+    /// (default: [`transform::DEFAULT_CONTENT_TYPES`]).
+    pub transform_content_types: Vec<String>,
+
+    /// Receives cache hits, misses, stores, bypasses, and backend errors.
+    pub events: Option<Arc<dyn events::ProxyEvents>>,
+
+    /// Changes each backend request right before it is sent, after the
+    /// cache lookup, e.g. to add an internal header.
+    pub transform_request: Option<TransformRequestFn>,
+
+    /// Provides the credential header sent with every backend request,
+    /// replacing the client's header of the same name. When it fails the
+    /// request is answered with `502` and not forwarded.
+    pub upstream_auth: Option<Arc<dyn upstream_auth::UpstreamAuth>>,
+
+    /// Record backend exchanges to, or replay them from, a cassette file.
+    #[cfg(feature = "cassette")]
+    pub cassette: Option<cassette::Cassette>,
+
+    /// Keep the main cache in Redis, shared with other proxies using the
+    /// same server and key prefix.
+    #[cfg(feature = "redis-backend")]
+    pub redis_cache: Option<redis_cache::RedisCache>,
+}
+
+/// Reject a `re:` pattern whose regex does not compile when it is set,
+/// rather than letting it silently match nothing.
+fn assert_regexes_compile(field: &str, patterns: &[String]) {
+    for (index, pattern) in patterns.iter().enumerate() {
+        if let Some(reason) = path_matcher::regex_error(pattern) {
+            panic!("{}[{}] {:?}: {}", field, index, pattern, reason);
+        }
+    }
 }
 
 impl CreateProxyConfig {
@@ -269,40 +829,159 @@ impl CreateProxyConfig {
     pub fn new(proxy_url: String) -> Self {
         Self {
             proxy_url,
+            backends: vec![],
+            load_balancing: backend::LoadBalancing::default(),
+            backend_cooldown: backend::DEFAULT_BACKEND_COOLDOWN,
             include_paths: vec![],
             exclude_paths: vec![],
+            path_rules: vec![],
+            wildcard_syntax: path_matcher::WildcardSyntax::default(),
             enable_websocket: true,
             forward_get_only: false,
             cache_key_fn: Arc::new(|req_info| {
-                if req_info.query.is_empty() {
+                Some(if req_info.query.is_empty() {
                     format!("{}:{}", req_info.method, req_info.path)
                 } else {
                     format!("{}:{}?{}", req_info.method, req_info.path, req_info.query)
-                }
+                })
             }),
             cache_404_capacity: 100,
+            cache_errors_ttl_secs: 0,
+            cache_errors_capacity: 100,
+            cache_max_bytes: 0,
+            max_request_body_bytes: proxy::DEFAULT_MAX_REQUEST_BODY_BYTES,
+            max_cacheable_response_bytes: proxy::DEFAULT_MAX_CACHEABLE_RESPONSE_BYTES,
             use_404_meta: false,
+            meta_404_scan_bytes: not_found_meta::DEFAULT_SCAN_BYTES,
+            respect_cache_control: false,
+            skip_set_cookie_responses: true,
+            bypass_credentialed_requests: true,
+            bypass_cookie_names: credentials::default_cookie_names(),
+            respect_client_no_cache: false,
+            revalidate_token: None,
+            bypass_header: None,
+            purge_header: None,
+            purge_token: None,
+            serve_head_from_get: true,
+            respect_vary: true,
+            trust_forwarded_headers: false,
+            preserve_host: false,
+            follow_redirects: false,
+            rewrite_location: false,
+            location_base_url: None,
+            path_prefix: None,
             cache_strategy: CacheStrategy::All,
+            streaming_content_types: proxy::default_streaming_content_types(),
             compress_strategy: CompressStrategy::Brotli,
+            compress_content_types: compression::default_compress_content_types(),
+            compress_min_bytes: 0,
+            compress_on_serve: false,
+            compress_variants_max_bytes: compressed_variants::DEFAULT_MAX_BYTES,
             cache_storage_mode: CacheStorageMode::Memory,
             cache_directory: None,
             proxy_mode: ProxyMode::Dynamic,
             webhooks: vec![],
+            render_queue_patterns: vec![],
+            render_queue_capacity: 1000,
+            render_queue_path: None,
+            warm_concurrency: warm::DEFAULT_WARM_CONCURRENCY,
+            persistence_path: None,
+            persistence_interval: std::time::Duration::from_secs(60),
+            format_mismatch_policy: FormatMismatchPolicy::Migrate,
+            max_cache_writes_per_sec: 0,
+            cache_write_queue_capacity: 0,
+            purge_cache_on_backend_change: true,
+            pool_max_idle_per_host: usize::MAX,
+            connect_timeout: std::time::Duration::from_secs(5),
+            request_timeout: std::time::Duration::from_secs(30),
+            upgrade_handshake_timeout: std::time::Duration::from_secs(10),
+            health_check_path: None,
+            health_check_timeout: health::DEFAULT_HEALTH_CHECK_TIMEOUT,
+            health_check_interval: None,
+            stats_window_minutes: 60,
+            decision_memo_ttl_secs: 30,
+            upgrade_fallback: UpgradeFallback::Reject,
+            upgrade_fallback_paths: vec![],
+            upgrade_reject_status: axum::http::StatusCode::NOT_IMPLEMENTED,
+            accept_mismatch: AcceptMismatch::Ignore,
+            cache_error_policy: CacheErrorPolicy::Bypass,
+            backend_error_policy: BackendErrorPolicy::Fail,
+            stale_max_age_secs: 86_400,
+            stale_on_error_status: false,
+            client_disconnect: ClientDisconnect::Abort,
+            cache_status_header: Some("X-Cache".to_string()),
+            coalesce_misses: true,
+            redact_query_params: redact::default_redact_query_params(),
+            redact_headers: redact::default_redact_headers(),
+            verify_cache_origin: true,
+            html_normalizer: None,
+            html_normalize_paths: vec![],
+            invalidation_scope: None,
+            cache_rules: vec![],
+            body_cache_rules: vec![],
+            body_cache_skip_fn: None,
+            transform_response: None,
+            transform_on_serve: None,
+            transform_content_types: transform::default_content_types(),
+            events: None,
+            transform_request: None,
+            should_cache_fn: None,
+            async_cache_key_fn: None,
+            cache_key_headers: vec![],
+            ignored_query_params: vec![],
+            allowed_query_params: vec![],
+            upstream_auth: None,
+            #[cfg(feature = "cassette")]
+            cassette: None,
+            #[cfg(feature = "redis-backend")]
+            redis_cache: None,
         }
     }
 
     /// Set include paths
+    ///
+    /// # Panics
+    ///
+    /// If a `re:` pattern is not a valid regular expression.
     pub fn with_include_paths(mut self, paths: Vec<String>) -> Self {
+        assert_regexes_compile("include_paths", &paths);
         self.include_paths = paths;
         self
     }
 
     /// Set exclude paths
+    ///
+    /// # Panics
+    ///
+    /// If a `re:` pattern is not a valid regular expression.
     pub fn with_exclude_paths(mut self, paths: Vec<String>) -> Self {
+        assert_regexes_compile("exclude_paths", &paths);
         self.exclude_paths = paths;
         self
     }
 
+    /// Decide what is cached with ordered rules instead of include and
+    /// exclude lists, e.g. `["/api/*", "!/api/internal/*"]`
+    ///
+    /// # Panics
+    ///
+    /// If a `re:` pattern is not a valid regular expression.
+    pub fn with_path_rules(mut self, rules: Vec<String>) -> Self {
+        for (index, rule) in rules.iter().enumerate() {
+            if let Some(reason) = path_matcher::rule_regex_error(rule) {
+                panic!("path_rules[{}] {:?}: {}", index, rule, reason);
+            }
+        }
+        self.path_rules = rules;
+        self
+    }
+
+    /// Set whether `*` in path and purge patterns crosses `/`
+    pub fn with_wildcard_syntax(mut self, syntax: path_matcher::WildcardSyntax) -> Self {
+        self.wildcard_syntax = syntax;
+        self
+    }
+
     /// Enable or disable WebSocket and protocol upgrade support
     pub fn with_websocket_enabled(mut self, enabled: bool) -> Self {
         self.enable_websocket = enabled;
@@ -316,26 +995,258 @@ impl CreateProxyConfig {
     }
 
     /// Set custom cache key function
-    pub fn with_cache_key_fn<F>(mut self, f: F) -> Self
+    pub fn with_cache_key_fn<F>(self, f: F) -> Self
     where
         F: Fn(&RequestInfo) -> String + Send + Sync + 'static,
+    {
+        self.with_cache_key_opt_fn(move |req_info| Some(f(req_info)))
+    }
+
+    /// Set a cache key function that may return `None` to forward a request
+    /// without reading or writing the cache, e.g. when it carries an
+    /// `Authorization` header
+    pub fn with_cache_key_opt_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestInfo) -> Option<String> + Send + Sync + 'static,
     {
         self.cache_key_fn = Arc::new(f);
         self
     }
 
+    /// Set a cache key function that is awaited, replacing `cache_key_fn`.
+    /// Like [`CreateProxyConfig::with_cache_key_opt_fn`], a `None` key
+    /// bypasses the cache. Every request waits for it, so keep it fast.
+    pub fn with_async_cache_key_fn<F>(mut self, f: F) -> Self
+    where
+        F: for<'a> Fn(&'a RequestInfo<'a>) -> CacheKeyFuture<'a> + Send + Sync + 'static,
+    {
+        self.async_cache_key_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Split cache entries on these request headers, e.g.
+    /// `["accept-language", "x-tenant-id"]`, without a custom key function.
+    /// Panics on a name that is not a valid header name.
+    pub fn with_cache_key_headers(mut self, headers: Vec<String>) -> Self {
+        for header in &headers {
+            if let Err(e) = axum::http::HeaderName::from_bytes(header.as_bytes()) {
+                panic!("cache_key_headers {:?}: {}", header, e);
+            }
+        }
+        self.cache_key_headers = headers
+            .into_iter()
+            .map(|header| header.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Leave these query parameters out of cache keys, e.g.
+    /// `["utm_source", "gclid", "fbclid"]`. Names match case-insensitively.
+    pub fn with_ignored_query_params(mut self, params: Vec<String>) -> Self {
+        self.ignored_query_params = params;
+        self
+    }
+
+    /// Keep only these query parameters in cache keys, for APIs where any
+    /// other parameter must not create a new entry.
+    pub fn with_allowed_query_params(mut self, params: Vec<String>) -> Self {
+        self.allowed_query_params = params;
+        self
+    }
+
     /// Set 404 cache capacity. When 0, 404 caching is disabled.
     pub fn with_cache_404_capacity(mut self, capacity: usize) -> Self {
         self.cache_404_capacity = capacity;
         self
     }
 
+    /// Cache 5xx responses for `secs` in a separate short-lived store.
+    /// When 0 (default), error responses get no special treatment.
+    pub fn with_cache_errors_ttl_secs(mut self, secs: u64) -> Self {
+        self.cache_errors_ttl_secs = secs;
+        self
+    }
+
+    /// Set how many error responses the error store keeps.
+    pub fn with_cache_errors_capacity(mut self, capacity: usize) -> Self {
+        self.cache_errors_capacity = capacity;
+        self
+    }
+
+    /// Cap the main cache at roughly `max_bytes`. 0 (default) is unlimited.
+    pub fn with_cache_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.cache_max_bytes = max_bytes;
+        self
+    }
+
+    /// Refuse request bodies over `max_bytes` with `413`. 0 is unlimited.
+    pub fn with_max_request_body_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_request_body_bytes = max_bytes;
+        self
+    }
+
+    /// Stream responses over `max_bytes` without caching them. 0 is
+    /// unlimited.
+    pub fn with_max_cacheable_response_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_cacheable_response_bytes = max_bytes;
+        self
+    }
+
     /// Treat pages that include the special meta tag as 404 pages
     pub fn with_use_404_meta(mut self, enabled: bool) -> Self {
         self.use_404_meta = enabled;
         self
     }
 
+    /// Honour `Cache-Control` on backend responses when deciding what to store
+    pub fn with_respect_cache_control(mut self, enabled: bool) -> Self {
+        self.respect_cache_control = enabled;
+        self
+    }
+
+    /// Set whether responses carrying `Set-Cookie` are kept out of the cache
+    pub fn with_skip_set_cookie_responses(mut self, enabled: bool) -> Self {
+        self.skip_set_cookie_responses = enabled;
+        self
+    }
+
+    /// Set whether requests carrying credentials bypass the cache
+    pub fn with_bypass_credentialed_requests(mut self, enabled: bool) -> Self {
+        self.bypass_credentialed_requests = enabled;
+        self
+    }
+
+    /// Set the cookie names that make a request bypass the cache
+    pub fn with_bypass_cookie_names(mut self, names: Vec<String>) -> Self {
+        self.bypass_cookie_names = names;
+        self
+    }
+
+    /// Set whether `Cache-Control: no-cache` on a request forces a refresh
+    pub fn with_respect_client_no_cache(mut self, enabled: bool) -> Self {
+        self.respect_client_no_cache = enabled;
+        self
+    }
+
+    /// Set the `X-Phantom-Revalidate` token that forces a refresh
+    pub fn with_revalidate_token(mut self, token: impl Into<String>) -> Self {
+        self.revalidate_token = Some(token.into());
+        self
+    }
+
+    /// Set the request header that makes a request bypass the cache
+    pub fn with_bypass_header(mut self, name: impl Into<String>) -> Self {
+        self.bypass_header = Some(name.into());
+        self
+    }
+
+    /// Set the request header that purges the request's cache entry
+    pub fn with_purge_header(mut self, name: impl Into<String>) -> Self {
+        self.purge_header = Some(name.into());
+        self
+    }
+
+    /// Set the token the purge header must carry
+    pub fn with_purge_token(mut self, token: impl Into<String>) -> Self {
+        self.purge_token = Some(token.into());
+        self
+    }
+
+    /// Set whether `HEAD` requests are answered from the `GET` cache
+    pub fn with_serve_head_from_get(mut self, enabled: bool) -> Self {
+        self.serve_head_from_get = enabled;
+        self
+    }
+
+    /// Set whether the backend's `Vary` selects separate cache entries
+    pub fn with_respect_vary(mut self, enabled: bool) -> Self {
+        self.respect_vary = enabled;
+        self
+    }
+
+    /// Set whether `X-Forwarded-*` headers sent by clients are kept
+    pub fn with_trust_forwarded_headers(mut self, enabled: bool) -> Self {
+        self.trust_forwarded_headers = enabled;
+        self
+    }
+
+    /// Set whether the client's `Host` header is forwarded to the backend
+    pub fn with_preserve_host(mut self, enabled: bool) -> Self {
+        self.preserve_host = enabled;
+        self
+    }
+
+    /// Spread requests over several backends; the first becomes `proxy_url`
+    pub fn with_backends(mut self, urls: Vec<String>) -> Self {
+        if let Some(first) = urls.first() {
+            self.proxy_url = first.clone();
+        }
+        self.backends = urls;
+        self
+    }
+
+    /// Set how requests are spread over `backends`
+    pub fn with_load_balancing(mut self, balancing: backend::LoadBalancing) -> Self {
+        self.load_balancing = balancing;
+        self
+    }
+
+    /// Set how long a failing backend is skipped when there are several
+    pub fn with_backend_cooldown(mut self, cooldown: std::time::Duration) -> Self {
+        self.backend_cooldown = cooldown;
+        self
+    }
+
+    /// `backends`, or just `proxy_url` when none are set.
+    pub(crate) fn backend_urls(&self) -> Vec<String> {
+        if self.backends.is_empty() {
+            vec![self.proxy_url.clone()]
+        } else {
+            self.backends.clone()
+        }
+    }
+
+    /// Set whether backend redirects are followed instead of passed through
+    pub fn with_follow_redirects(mut self, enabled: bool) -> Self {
+        self.follow_redirects = enabled;
+        self
+    }
+
+    /// Set whether `Location` headers pointing at the backend are rewritten
+    pub fn with_rewrite_location(mut self, enabled: bool) -> Self {
+        self.rewrite_location = enabled;
+        self
+    }
+
+    /// Set the public base URL rewritten `Location` headers point at
+    pub fn with_location_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.location_base_url = Some(base_url.into());
+        self
+    }
+
+    /// Strip `prefix`, e.g. `/app`, from request paths, for a proxy mounted
+    /// with `Router::fallback_service` or behind a path-routing load
+    /// balancer. A trailing `/` is ignored. Panics unless `prefix` starts
+    /// with `/`.
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        assert!(
+            prefix.starts_with('/'),
+            "path_prefix {:?} must start with '/'",
+            prefix
+        );
+        let prefix = prefix.trim_end_matches('/');
+        self.path_prefix = (!prefix.is_empty()).then(|| prefix.to_string());
+        self
+    }
+
+    /// Set how many bytes at the start of an HTML body are searched for the
+    /// phantom-404 meta tag
+    pub fn with_meta_404_scan_bytes(mut self, bytes: usize) -> Self {
+        self.meta_404_scan_bytes = bytes;
+        self
+    }
+
     /// Set the cache strategy used to decide which response types are stored.
     pub fn with_cache_strategy(mut self, strategy: CacheStrategy) -> Self {
         self.cache_strategy = strategy;
@@ -347,6 +1258,13 @@ impl CreateProxyConfig {
         self.with_cache_strategy(strategy)
     }
 
+    /// Stream responses whose `Content-Type` starts with one of
+    /// `content_types`, ignoring case, without caching them.
+    pub fn with_streaming_content_types(mut self, content_types: Vec<String>) -> Self {
+        self.streaming_content_types = content_types;
+        self
+    }
+
     /// Set the compression strategy used for stored cache entries.
     pub fn with_compress_strategy(mut self, strategy: CompressStrategy) -> Self {
         self.compress_strategy = strategy;
@@ -358,6 +1276,32 @@ impl CreateProxyConfig {
         self.with_compress_strategy(strategy)
     }
 
+    /// Compress only bodies whose `Content-Type` starts with one of
+    /// `content_types`, ignoring case.
+    pub fn with_compress_content_types(mut self, content_types: Vec<String>) -> Self {
+        self.compress_content_types = content_types;
+        self
+    }
+
+    /// Leave bodies smaller than `min_bytes` uncompressed.
+    pub fn with_compress_min_bytes(mut self, min_bytes: usize) -> Self {
+        self.compress_min_bytes = min_bytes;
+        self
+    }
+
+    /// Compress cached responses for clients that do not accept the stored
+    /// encoding.
+    pub fn with_compress_on_serve(mut self, enabled: bool) -> Self {
+        self.compress_on_serve = enabled;
+        self
+    }
+
+    /// Cap the memory kept for bodies compressed at serve time.
+    pub fn with_compress_variants_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.compress_variants_max_bytes = max_bytes;
+        self
+    }
+
     /// Set the backing store for cached response bodies.
     pub fn with_cache_storage_mode(mut self, mode: CacheStorageMode) -> Self {
         self.cache_storage_mode = mode;
@@ -383,121 +1327,799 @@ impl CreateProxyConfig {
         self.webhooks = webhooks;
         self
     }
-}
 
-/// The main library interface for using phantom-frame as a library
-/// Returns a proxy handler function and a cache handle
-pub fn create_proxy(config: CreateProxyConfig) -> (Router, CacheHandle) {
-    let upstream_client =
-        proxy::build_upstream_client().expect("failed to build shared upstream HTTP client");
-    let webhook_client =
-        proxy::build_webhook_client().expect("failed to build shared webhook HTTP client");
+    /// Queue cache misses matching these patterns for an external renderer.
+    /// Render workers claim jobs via `GET /render-queue` and submit results via
+    /// `PUT /cache/entry` on the control server.
+    ///
+    /// # Panics
+    ///
+    /// If a `re:` pattern is not a valid regular expression.
+    pub fn with_render_queue_patterns(mut self, patterns: Vec<String>) -> Self {
+        assert_regexes_compile("render_queue_patterns", &patterns);
+        self.render_queue_patterns = patterns;
+        self
+    }
 
-    // In PreGenerate mode, create a channel for the snapshot worker
-    let (handle, snapshot_rx) = if let ProxyMode::PreGenerate { .. } = &config.proxy_mode {
-        let (tx, rx) = mpsc::channel(32);
-        (CacheHandle::new_with_snapshots(tx), Some(rx))
-    } else {
-        (CacheHandle::new(), None)
-    };
+    /// Set the maximum number of pending render jobs.
+    pub fn with_render_queue_capacity(mut self, capacity: usize) -> Self {
+        self.render_queue_capacity = capacity;
+        self
+    }
 
-    let cache = CacheStore::with_storage(
-        handle.clone(),
-        config.cache_404_capacity,
-        config.cache_storage_mode.clone(),
-        config.cache_directory.clone(),
-    );
+    /// Persist pending render jobs to `path` so they survive restarts.
+    pub fn with_render_queue_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.render_queue_path = Some(path.into());
+        self
+    }
 
-    // Spawn background task to listen for invalidation events
-    spawn_invalidation_listener(cache.clone());
+    /// Set how many paths [`ProxyHandle::warm`] fetches at once. `0` is
+    /// treated as 1.
+    pub fn with_warm_concurrency(mut self, concurrency: usize) -> Self {
+        self.warm_concurrency = concurrency;
+        self
+    }
 
-    // Spawn snapshot worker (warm-up + runtime snapshot management) in PreGenerate mode
-    if let (Some(rx), ProxyMode::PreGenerate { paths, .. }) = (snapshot_rx, &config.proxy_mode) {
-        let worker = SnapshotWorker {
-            rx,
-            cache: cache.clone(),
-            upstream_client: upstream_client.clone(),
-            proxy_url: config.proxy_url.clone(),
-            compress_strategy: config.compress_strategy.clone(),
-            cache_key_fn: config.cache_key_fn.clone(),
-            snapshots: paths.clone(),
-        };
-        tokio::spawn(worker.run());
+    /// Save the cache to a snapshot at `path` and restore it from there on
+    /// startup. See [`persistence`].
+    pub fn with_persistence_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.persistence_path = Some(path.into());
+        self
     }
 
-    let proxy_state = Arc::new(ProxyState::new(
-        cache,
-        config,
-        upstream_client,
-        webhook_client,
-    ));
+    /// Set how often the cache snapshot is rewritten; zero only writes it
+    /// through [`ProxyHandle::persistence`].
+    pub fn with_persistence_interval(mut self, interval: std::time::Duration) -> Self {
+        self.persistence_interval = interval;
+        self
+    }
 
-    let app = Router::new()
-        .fallback(proxy::proxy_handler)
-        .layer(Extension(proxy_state));
+    /// Set whether a snapshot or Redis entry from an earlier format version
+    /// is migrated or discarded.
+    pub fn with_format_mismatch_policy(mut self, policy: FormatMismatchPolicy) -> Self {
+        self.format_mismatch_policy = policy;
+        self
+    }
 
-    (app, handle)
-}
+    /// Cap cache inserts per second. Set to 0 to disable the limit.
+    pub fn with_max_cache_writes_per_sec(mut self, max_per_sec: u32) -> Self {
+        self.max_cache_writes_per_sec = max_per_sec;
+        self
+    }
 
-/// Create a proxy handler with an existing cache handle.
-/// Useful for sharing a single handle across multiple proxy instances so that
-/// invalidation propagates to all caches simultaneously.
-///
-/// Note: snapshot operations (PreGenerate mode warm-up) are not available
-/// through this variant — use [`create_proxy`] for full PreGenerate support.
-pub fn create_proxy_with_handle(config: CreateProxyConfig, handle: CacheHandle) -> Router {
-    let upstream_client =
-        proxy::build_upstream_client().expect("failed to build shared upstream HTTP client");
-    let webhook_client =
-        proxy::build_webhook_client().expect("failed to build shared webhook HTTP client");
+    /// Store responses in the background through a queue of `capacity`
+    /// writes. Set to 0 to store them before answering.
+    pub fn with_cache_write_queue_capacity(mut self, capacity: usize) -> Self {
+        self.cache_write_queue_capacity = capacity;
+        self
+    }
 
-    let cache = CacheStore::with_storage(
-        handle,
-        config.cache_404_capacity,
-        config.cache_storage_mode.clone(),
-        config.cache_directory.clone(),
-    );
+    /// Choose whether swapping the backend at runtime clears the cache.
+    pub fn with_purge_cache_on_backend_change(mut self, enabled: bool) -> Self {
+        self.purge_cache_on_backend_change = enabled;
+        self
+    }
+
+    /// Set how many idle backend connections are kept per host (0 = none)
+    pub fn with_pool_max_idle_per_host(mut self, max_idle: usize) -> Self {
+        self.pool_max_idle_per_host = max_idle;
+        self
+    }
+
+    /// Set the deadline for connecting to a backend, TLS handshake included
+    pub fn with_connect_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set the deadline for a whole backend request
+    pub fn with_request_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Set the deadline for an upgrade request's backend handshake
+    pub fn with_upgrade_handshake_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.upgrade_handshake_timeout = timeout;
+        self
+    }
+
+    /// Check backend reachability for readiness with `HEAD path`.
+    pub fn with_health_check_path(mut self, path: impl Into<String>) -> Self {
+        self.health_check_path = Some(path.into());
+        self
+    }
+
+    /// Set the timeout of the backend health check
+    pub fn with_health_check_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.health_check_timeout = timeout;
+        self
+    }
+
+    /// Probe backends at `health_check_path` on `interval`, skipping failed ones
+    pub fn with_health_check_interval(mut self, interval: std::time::Duration) -> Self {
+        self.health_check_interval = Some(interval);
+        self
+    }
+
+    /// Set how many minutes of history back the windowed hit-rate stats.
+    pub fn with_stats_window_minutes(mut self, minutes: usize) -> Self {
+        self.stats_window_minutes = minutes;
+        self
+    }
+
+    /// Set how long "not cacheable" verdicts are remembered per key. Set to 0 to disable.
+    pub fn with_decision_memo_ttl_secs(mut self, secs: u64) -> Self {
+        self.decision_memo_ttl_secs = secs;
+        self
+    }
+
+    /// Choose how upgrade requests are handled when upgrades are unavailable.
+    pub fn with_upgrade_fallback(mut self, fallback: UpgradeFallback) -> Self {
+        self.upgrade_fallback = fallback;
+        self
+    }
+
+    /// Restrict the upgrade fallback to paths matching these patterns.
+    pub fn with_upgrade_fallback_paths(mut self, paths: Vec<String>) -> Self {
+        self.upgrade_fallback_paths = paths;
+        self
+    }
+
+    /// Set the status returned for rejected upgrade requests
+    /// (e.g. `426 Upgrade Required`).
+    pub fn with_upgrade_reject_status(mut self, status: axum::http::StatusCode) -> Self {
+        self.upgrade_reject_status = status;
+        self
+    }
+
+    /// Choose how cache hits are handled when the request's `Accept` header
+    /// rules out the cached content type.
+    pub fn with_accept_mismatch(mut self, mode: AcceptMismatch) -> Self {
+        self.accept_mismatch = mode;
+        self
+    }
+
+    /// Set what happens when the cache cannot be read or written
+    pub fn with_cache_error_policy(mut self, policy: CacheErrorPolicy) -> Self {
+        self.cache_error_policy = policy;
+        self
+    }
+
+    /// Set what happens when the backend fails a cacheable request
+    pub fn with_backend_error_policy(mut self, policy: BackendErrorPolicy) -> Self {
+        self.backend_error_policy = policy;
+        self
+    }
+
+    /// Set the oldest entry, in seconds since it was stored, served stale
+    /// when the backend fails. Panics if `secs` is 0.
+    pub fn with_stale_max_age_secs(mut self, secs: u64) -> Self {
+        assert!(secs > 0, "stale_max_age_secs must be non-zero");
+        self.stale_max_age_secs = secs;
+        self
+    }
+
+    /// Set whether backend 5xx answers, not only unreachable backends, fall
+    /// back to a stale entry
+    pub fn with_stale_on_error_status(mut self, enabled: bool) -> Self {
+        self.stale_on_error_status = enabled;
+        self
+    }
+
+    /// Set what happens to a backend fetch when its client disconnects
+    pub fn with_client_disconnect(mut self, mode: ClientDisconnect) -> Self {
+        self.client_disconnect = mode;
+        self
+    }
+
+    /// Set the cache status response header, or pass `None` to send none
+    pub fn with_cache_status_header(mut self, header: Option<String>) -> Self {
+        self.cache_status_header = header;
+        self
+    }
+
+    /// Enable or disable sharing one backend fetch between concurrent misses
+    /// for the same key
+    pub fn with_coalesce_misses(mut self, enabled: bool) -> Self {
+        self.coalesce_misses = enabled;
+        self
+    }
+
+    /// Replace the query parameters redacted in logs. Pass an empty list to
+    /// log query strings in full.
+    pub fn with_redact_query_params(mut self, params: Vec<String>) -> Self {
+        self.redact_query_params = params;
+        self
+    }
+
+    /// Replace the headers redacted in logs.
+    pub fn with_redact_headers(mut self, headers: Vec<String>) -> Self {
+        self.redact_headers = headers;
+        self
+    }
+
+    /// Enable or disable the per-hit check that an entry was stored for the
+    /// same method and path.
+    pub fn with_verify_cache_origin(mut self, enabled: bool) -> Self {
+        self.verify_cache_origin = enabled;
+        self
+    }
+
+    /// Normalize HTML bodies with `normalizer` before storing them.
+    pub fn with_html_normalizer(mut self, normalizer: html_normalize::HtmlNormalizer) -> Self {
+        self.html_normalizer = Some(normalizer);
+        self
+    }
+
+    /// Set the patterns whose HTML responses are normalized.
+    pub fn with_html_normalize_paths(mut self, paths: Vec<String>) -> Self {
+        self.html_normalize_paths = paths;
+        self
+    }
+
+    /// Set the scope of `CacheHandle::scoped` invalidations this proxy acts on.
+    /// Useful when several proxies share one handle via
+    /// [`create_proxy_with_handle`].
+    pub fn with_invalidation_scope(mut self, scope: impl Into<String>) -> Self {
+        self.invalidation_scope = Some(scope.into());
+        self
+    }
+
+    /// Decide per path whether and for how long requests are cached; the
+    /// first matching rule wins over `include_paths` and `exclude_paths`.
+    pub fn with_cache_rules(mut self, rules: Vec<cache_rules::CacheRule>) -> Self {
+        self.cache_rules = rules;
+        self
+    }
+
+    /// Decide per request whether it is cached, overriding the path patterns
+    /// and cache rules unless `f` returns [`CacheDecision::Default`]. A
+    /// panicking `f` counts as [`CacheDecision::Bypass`].
+    pub fn with_should_cache_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestInfo) -> CacheDecision + Send + Sync + 'static,
+    {
+        self.should_cache_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Cache requests matching `rules` by their body.
+    pub fn with_body_cache_rules(mut self, rules: Vec<body_cache::BodyCacheRule>) -> Self {
+        self.body_cache_rules = rules;
+        self
+    }
+
+    /// Forward requests matched by `body_cache_rules` uncached whenever `f`
+    /// returns `true` for the request and its body.
+    pub fn with_body_cache_skip_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestInfo, &[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.body_cache_skip_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Rewrite responses once before they are stored, e.g. to inject a
+    /// `<script>` tag. `f` gets the decoded body; the entry is compressed and
+    /// its `content-length` set afterwards. A panicking `f` leaves the
+    /// response unstored.
+    pub fn with_transform_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestInfo, cache::CachedResponse) -> cache::CachedResponse + Send + Sync + 'static,
+    {
+        self.transform_response = Some(Arc::new(f));
+        self
+    }
+
+    /// Rewrite every response as it is served, hits included. It runs on
+    /// each request, so keep it cheap. A panicking `f` leaves the response
+    /// as it was.
+    pub fn with_transform_on_serve<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestInfo, cache::CachedResponse) -> cache::CachedResponse + Send + Sync + 'static,
+    {
+        self.transform_on_serve = Some(Arc::new(f));
+        self
+    }
+
+    /// Set the `Content-Type` prefixes the transform hooks run for, such as
+    /// `["text/html", "application/json"]`.
+    pub fn with_transform_content_types(mut self, content_types: Vec<String>) -> Self {
+        self.transform_content_types = content_types;
+        self
+    }
+
+    /// Report cache activity to `events`. Its methods are called while
+    /// requests are handled, so they must not block.
+    pub fn with_events(mut self, events: Arc<dyn events::ProxyEvents>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Change every request to the backend, including upgrade handshakes and
+    /// snapshot fetches, right before it is sent. Cache keys are computed
+    /// from the client's request first. A panicking `f` answers the request
+    /// with `502` instead of sending it.
+    pub fn with_transform_request<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut outbound::OutboundRequest) + Send + Sync + 'static,
+    {
+        self.transform_request = Some(Arc::new(f));
+        self
+    }
+
+    /// Authenticate backend requests with `auth`, e.g. an OAuth2
+    /// [`upstream_auth::ClientCredentials`] provider.
+    pub fn with_upstream_auth(mut self, auth: Arc<dyn upstream_auth::UpstreamAuth>) -> Self {
+        self.upstream_auth = Some(auth);
+        self
+    }
+
+    /// Record backend exchanges to `cassette`, or answer from it without
+    /// contacting the backend, depending on how it was opened.
+    #[cfg(feature = "cassette")]
+    pub fn with_cassette(mut self, cassette: cassette::Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Keep the main cache in `redis` instead of this process
+    #[cfg(feature = "redis-backend")]
+    pub fn with_redis_cache(mut self, redis: redis_cache::RedisCache) -> Self {
+        self.redis_cache = Some(redis);
+        self
+    }
+}
+
+/// Runtime handle to a single proxy instance.
+///
+/// Unlike [`CacheHandle`], which only broadcasts invalidations, this gives
+/// direct access to the cache store and per-proxy state. The control server
+/// uses it for operations that need a synchronous answer.
+#[derive(Clone)]
+pub struct ProxyHandle {
+    cache_handle: CacheHandle,
+    state: Arc<ProxyState>,
+}
+
+impl ProxyHandle {
+    /// The state [`proxy::proxy_handler`] runs on, for mounting it on your
+    /// own router with `Router::with_state`.
+    pub fn proxy_state(&self) -> Arc<ProxyState> {
+        self.state.clone()
+    }
+
+    /// The invalidation / snapshot handle for this proxy.
+    pub fn cache_handle(&self) -> &CacheHandle {
+        &self.cache_handle
+    }
+
+    /// The cache store backing this proxy.
+    pub fn cache(&self) -> &CacheStore {
+        self.state.cache()
+    }
+
+    /// The configuration this proxy was created with.
+    ///
+    /// `proxy_url`, `include_paths`, and `exclude_paths` reflect the startup
+    /// values; use [`ProxyHandle::backend`] and [`ProxyHandle::path_patterns`]
+    /// for the ones currently in effect.
+    pub fn config(&self) -> &CreateProxyConfig {
+        self.state.config()
+    }
+
+    /// The backend currently receiving new requests; the first one when
+    /// there are several.
+    pub fn backend(&self) -> Arc<backend::Backend> {
+        self.state.backend().current()
+    }
+
+    /// Every backend requests are spread over, with their in-flight counts
+    /// and cooldowns.
+    pub fn backends(&self) -> Vec<Arc<backend::Backend>> {
+        self.state.backend().all()
+    }
+
+    /// Route new requests to `proxy_url` without dropping in-flight ones.
+    ///
+    /// Requests already being forwarded finish on the previous backend, and
+    /// established upgrade tunnels stay open until either side closes them.
+    /// When `purge_cache` is true the cache is cleared after the swap.
+    pub async fn set_backend(
+        &self,
+        proxy_url: impl Into<String>,
+        purge_cache: bool,
+    ) -> anyhow::Result<Arc<backend::Backend>> {
+        let backend = self.state.backend().replace(proxy_url.into())?;
+        self.after_backend_change(purge_cache).await;
+        tracing::info!(
+            "Backend switched to '{}' (generation {}, cache purged: {})",
+            backend.url(),
+            backend.generation(),
+            purge_cache
+        );
+        Ok(backend)
+    }
+
+    /// Like [`ProxyHandle::set_backend`], spreading new requests over
+    /// several backends.
+    pub async fn set_backends(
+        &self,
+        urls: Vec<String>,
+        purge_cache: bool,
+    ) -> anyhow::Result<Vec<Arc<backend::Backend>>> {
+        let backends = self.state.backend().replace_all(urls)?;
+        self.after_backend_change(purge_cache).await;
+        tracing::info!(
+            "Backends switched to {:?} (generation {}, cache purged: {})",
+            backends.iter().map(|b| b.url()).collect::<Vec<_>>(),
+            backends[0].generation(),
+            purge_cache
+        );
+        Ok(backends)
+    }
+
+    async fn after_backend_change(&self, purge_cache: bool) {
+        if purge_cache {
+            self.state.cache().clear().await;
+        } else {
+            // Verdicts were made against the old backend's responses.
+            self.state.cache().decision_memo().clear();
+        }
+    }
+
+    /// The pending-render queue, when `render_queue_patterns` is configured.
+    pub fn render_queue(&self) -> Option<&render_queue::RenderQueue> {
+        self.state.render_queue()
+    }
+
+    /// The cache snapshot, when `persistence_path` is configured. Call
+    /// [`save`](persistence::Persistence::save) on it before shutting down
+    /// so the next start has the latest entries.
+    pub fn persistence(&self) -> Option<&persistence::Persistence> {
+        self.state.persistence()
+    }
+
+    /// Hit/miss/bypass/store counters and backend latencies for this proxy.
+    pub fn stats(&self) -> &stats::ProxyStats {
+        self.state.stats()
+    }
+
+    /// Whether the backend answers `HEAD health_check_path` with a status
+    /// below 500, or `None` when no path is configured. Results are reused
+    /// for [`health::PROBE_CACHE_TTL`]. With `health_check_interval`, the
+    /// active checks decide instead: true while any backend is up.
+    pub async fn check_backend(&self) -> Option<bool> {
+        let config = self.state.config();
+        if config.health_check_path.is_some() && config.health_check_interval.is_some() {
+            return Some(self.backends().iter().any(|backend| backend.is_up()));
+        }
+        self.state
+            .backend_probe()
+            .check(&self.state.backend().current())
+            .await
+    }
+
+    /// Redacts request targets and headers in this proxy's log output.
+    pub(crate) fn redactor(&self) -> &redact::Redactor {
+        self.state.redactor()
+    }
+
+    /// Number of cache hits rejected because the entry was stored for a
+    /// different method or path, i.e. `cache_key_fn` collisions.
+    pub fn cache_origin_mismatches(&self) -> u64 {
+        self.state.cache_origin_mismatches()
+    }
+
+    /// The `include_paths` and `exclude_paths`, or `path_rules`, currently
+    /// in effect.
+    pub fn path_patterns(&self) -> path_matcher::PathPatterns {
+        self.state.path_filter().patterns()
+    }
+
+    /// Replace `include_paths`, `exclude_paths`, and `path_rules` for new
+    /// requests, all at once. Fails, changing nothing, when a pattern could
+    /// never match or rules are combined with include or exclude patterns;
+    /// the error names each such entry. Stored entries are kept, but those
+    /// under newly excluded paths are no longer served.
+    pub fn set_path_patterns(&self, patterns: path_matcher::PathPatterns) -> anyhow::Result<()> {
+        let errors = patterns.errors();
+        if !errors.is_empty() {
+            anyhow::bail!("invalid patterns: {}", errors.join("; "));
+        }
+        self.state
+            .set_path_filter(path_matcher::PathFilter::from_patterns(
+                &patterns,
+                self.state.config().wildcard_syntax,
+            ));
+        Ok(())
+    }
+
+    /// Stop using the cache: until [`ProxyHandle::resume_caching`], requests
+    /// skip cache lookups and stores and go straight to the backend. Stored
+    /// entries are kept. Returns whether caching was already paused.
+    pub fn pause_caching(&self) -> bool {
+        self.state.set_caching_paused(true)
+    }
+
+    /// Use the cache again after [`ProxyHandle::pause_caching`]. Returns
+    /// whether caching was paused.
+    pub fn resume_caching(&self) -> bool {
+        self.state.set_caching_paused(false)
+    }
+
+    /// Whether caching is paused.
+    pub fn caching_paused(&self) -> bool {
+        self.state.caching_paused()
+    }
+
+    /// Stop this proxy's background tasks (the invalidation listener, the
+    /// PreGenerate snapshot worker) and close its open upgrade tunnels, then
+    /// wait for the tasks to exit. The router keeps answering requests, but
+    /// invalidations no longer reach its cache. Dropping the last router and
+    /// handle of a proxy stops the tasks too, without waiting.
+    pub async fn shutdown(&self) {
+        self.state.background().shutdown().await;
+    }
+
+    /// A token cancelled when this proxy shuts down. Cancelling it has the
+    /// same effect as [`ProxyHandle::shutdown`], without waiting.
+    pub fn shutdown_token(&self) -> tokio_util::sync::CancellationToken {
+        self.state.background().token().clone()
+    }
+
+    /// Number of panics caught from user callbacks such as `cache_key_fn`.
+    /// Each one made its request bypass the cache.
+    pub fn callback_panics(&self) -> u64 {
+        self.state.callback_panics()
+    }
+
+    /// Number of cache writes dropped because the `cache_write_queue_capacity`
+    /// queue was full.
+    pub fn cache_writes_dropped(&self) -> u64 {
+        self.state.cache_writes_dropped()
+    }
+
+    /// Number of cache writes skipped because `max_cache_writes_per_sec` was reached.
+    pub fn cache_writes_throttled(&self) -> u64 {
+        self.state.cache_writes_throttled()
+    }
+
+    /// Store an externally produced response under `key`, compressed with the
+    /// proxy's configured strategy. Replaces any existing entry. `headers`
+    /// may be a map or, to repeat a name, a list of pairs.
+    pub async fn seed(
+        &self,
+        key: impl Into<String>,
+        status: u16,
+        headers: impl IntoIterator<Item = (String, String)>,
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        self.state
+            .seed_entry(key.into(), status, headers, body)
+            .await
+    }
+
+    /// Fetch `paths` through the proxy so they are cached before the first
+    /// visitor asks, with at most `warm_concurrency` requests in flight.
+    /// A path may carry a method prefix (`"HEAD /about"`); the default is
+    /// `GET`. Returns one result per path, in order. See [`warm`].
+    pub async fn warm(&self, paths: Vec<String>) -> Vec<warm::WarmResult> {
+        let concurrency = self.state.config().warm_concurrency;
+        self.warm_with_concurrency(paths, concurrency).await
+    }
+
+    /// Like [`ProxyHandle::warm`], with an explicit concurrency limit.
+    pub async fn warm_with_concurrency(
+        &self,
+        paths: Vec<String>,
+        concurrency: usize,
+    ) -> Vec<warm::WarmResult> {
+        warm::warm(self.state.clone(), paths, concurrency).await
+    }
+}
+
+/// The main library interface for using phantom-frame as a library
+/// Returns a proxy handler function and a cache handle
+#[deprecated(
+    note = "use `create_proxy_handle`; its `ProxyHandle` also exposes the cache store and stats, and `cache_handle()` returns this `CacheHandle`"
+)]
+pub fn create_proxy(config: CreateProxyConfig) -> (Router, CacheHandle) {
+    let (router, handle) = create_proxy_handle(config);
+    (router, handle.cache_handle)
+}
+
+/// The main library interface: a proxy router and a [`ProxyHandle`] with
+/// the cache store, its [`CacheHandle`], and stats. Pass the handle to
+/// [`control::create_control_router`].
+pub fn create_proxy_handle(config: CreateProxyConfig) -> (Router, ProxyHandle) {
+    // In PreGenerate mode, create a channel for the snapshot worker
+    let (handle, snapshot_rx) = if let ProxyMode::PreGenerate { .. } = &config.proxy_mode {
+        let (tx, rx) = mpsc::channel(32);
+        (CacheHandle::new_with_snapshots(tx), Some(rx))
+    } else {
+        (CacheHandle::new(), None)
+    };
+    let cache = cache_store_for(&config, handle);
+    build_proxy(config, cache, snapshot_rx)
+}
+
+/// Create a proxy handler with an existing cache handle.
+/// Useful for sharing a single handle across multiple proxy instances so that
+/// invalidation propagates to all caches simultaneously.
+///
+/// Note: snapshot operations (PreGenerate mode warm-up) are not available
+/// through this variant — use [`create_proxy_handle`] for full PreGenerate
+/// support.
+pub fn create_proxy_with_handle(config: CreateProxyConfig, handle: CacheHandle) -> Router {
+    let cache = cache_store_for(&config, handle);
+    build_proxy(config, cache, None).0
+}
+
+/// Create a proxy serving from an existing `cache`, so several routers can
+/// share one store. The store keeps the sizing it was built with; the cache
+/// sizing fields of `config` are not applied to it.
+///
+/// Like [`create_proxy_with_handle`], snapshot operations are not available.
+pub fn create_proxy_with_cache(
+    config: CreateProxyConfig,
+    cache: CacheStore,
+) -> (Router, ProxyHandle) {
+    build_proxy(config, cache, None)
+}
 
-    // Spawn background task to listen for invalidation events
-    spawn_invalidation_listener(cache.clone());
+/// The cache store `config` describes, reporting to `handle`.
+fn cache_store_for(config: &CreateProxyConfig, handle: CacheHandle) -> CacheStore {
+    let cache = CacheStore::with_storage(
+        handle,
+        config.cache_404_capacity,
+        config.cache_storage_mode.clone(),
+        config.cache_directory.clone(),
+    )
+    .with_decision_memo(std::time::Duration::from_secs(
+        config.decision_memo_ttl_secs,
+    ))
+    .with_error_store(
+        config.cache_errors_capacity,
+        std::time::Duration::from_secs(config.cache_errors_ttl_secs),
+    )
+    .with_stale_retention(
+        (config.backend_error_policy == BackendErrorPolicy::StaleIfAvailable)
+            .then(|| std::time::Duration::from_secs(config.stale_max_age_secs)),
+    )
+    .with_max_bytes(config.cache_max_bytes)
+    .with_wildcard_syntax(config.wildcard_syntax);
+    #[cfg(feature = "redis-backend")]
+    let cache = match &config.redis_cache {
+        Some(redis) => cache.with_redis(
+            redis
+                .clone()
+                .with_format_mismatch_policy(config.format_mismatch_policy),
+        ),
+        None => cache,
+    };
+    cache
+}
 
-    let proxy_state = Arc::new(ProxyState::new(
-        cache,
-        config,
-        upstream_client,
-        webhook_client,
-    ));
+/// Build the proxy router and its background tasks around `cache`. The
+/// snapshot worker runs only when `snapshot_rx` is given.
+fn build_proxy(
+    config: CreateProxyConfig,
+    cache: CacheStore,
+    snapshot_rx: Option<mpsc::Receiver<cache::SnapshotRequest>>,
+) -> (Router, ProxyHandle) {
+    let backend =
+        BackendSlot::from_config(&config).expect("failed to build shared upstream HTTP client");
+    let webhook_client =
+        proxy::build_webhook_client().expect("failed to build shared webhook HTTP client");
 
+    let redactor = redact::Redactor::new(&config.redact_query_params, &config.redact_headers);
+    let listener = invalidation_listener(
+        cache.clone(),
+        redactor.clone(),
+        config.invalidation_scope.clone(),
+    );
+    // Snapshot worker (warm-up + runtime snapshot management) in PreGenerate mode
+    let worker = match (snapshot_rx, &config.proxy_mode) {
+        (Some(rx), ProxyMode::PreGenerate { paths, .. }) => Some(SnapshotWorker {
+            rx,
+            cache: cache.clone(),
+            backend: backend.clone(),
+            compression: compression::CompressionPolicy::from_config(&config),
+            cache_key: proxy::CacheKeyFns::new(&config),
+            redactor: redactor.clone(),
+            upstream_auth: config.upstream_auth.clone(),
+            transform_request: config.transform_request.clone(),
+            snapshots: paths.clone(),
+        }),
+        _ => None,
+    };
+
+    let cache_handle = cache.handle().clone();
+    let proxy_state = Arc::new(ProxyState::new(cache, config, backend, webhook_client));
+    proxy_state.background().spawn(listener);
+    if let Some(checks) = health::active_checks(&proxy_state) {
+        proxy_state.background().spawn(checks);
+    }
+    if let Some(worker) = worker {
+        proxy_state.background().spawn(worker.run());
+    }
+
+    (
+        proxy_router(proxy_state.clone()),
+        ProxyHandle {
+            cache_handle,
+            state: proxy_state,
+        },
+    )
+}
+
+/// The router [`create_proxy_handle`] returns: the health endpoint, and
+/// [`proxy::proxy_handler`] for everything else.
+pub fn proxy_router(state: Arc<ProxyState>) -> Router {
     Router::new()
+        .route(health::HEALTH_PATH, get(health::health_handler))
         .fallback(proxy::proxy_handler)
-        .layer(Extension(proxy_state))
+        .with_state(state)
 }
 
-/// Spawn a background task to listen for cache invalidation events.
-fn spawn_invalidation_listener(cache: CacheStore) {
+/// Listen for cache invalidation events until the channel closes. Events
+/// sent to another scope are ignored. A listener that falls behind and
+/// misses events clears its whole cache, since it cannot tell what they
+/// named.
+fn invalidation_listener(
+    cache: CacheStore,
+    redactor: redact::Redactor,
+    scope: Option<String>,
+) -> impl std::future::Future<Output = ()> + Send + 'static {
+    // Subscribe now, so events sent before the task first runs are not lost.
     let mut receiver = cache.handle().subscribe();
 
-    tokio::spawn(async move {
+    async move {
         loop {
-            match receiver.recv().await {
-                Ok(cache::InvalidationMessage::All) => {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(
+                        "Missed {} cache invalidation events; clearing the whole cache",
+                        missed
+                    );
+                    cache.clear().await;
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            };
+            if !event.applies_to(scope.as_deref()) {
+                tracing::trace!(
+                    event_scope = ?event.scope,
+                    own_scope = ?scope,
+                    "ignoring invalidation sent to another scope"
+                );
+                continue;
+            }
+            match event.message {
+                cache::InvalidationMessage::All => {
                     tracing::debug!("Cache invalidation triggered: clearing all entries");
                     cache.clear().await;
                 }
-                Ok(cache::InvalidationMessage::Pattern(pattern)) => {
+                cache::InvalidationMessage::Pattern(pattern) => {
                     tracing::debug!(
                         "Cache invalidation triggered: clearing entries matching pattern '{}'",
-                        pattern
+                        redactor.target(&pattern)
                     );
                     cache.clear_by_pattern(&pattern).await;
                 }
-                Err(e) => {
-                    tracing::error!("Invalidation channel error: {}", e);
-                    break;
+                cache::InvalidationMessage::Key(key) => {
+                    tracing::debug!(
+                        "Cache invalidation triggered: clearing entry '{}'",
+                        redactor.target(&key)
+                    );
+                    cache.clear_key(&key).await;
                 }
             }
         }
-    });
+    }
 }
 
 /// Background worker that handles snapshot warm-up and runtime snapshot operations
@@ -505,10 +2127,13 @@ fn spawn_invalidation_listener(cache: CacheStore) {
 struct SnapshotWorker {
     rx: mpsc::Receiver<cache::SnapshotRequest>,
     cache: CacheStore,
-    upstream_client: reqwest::Client,
-    proxy_url: String,
-    compress_strategy: CompressStrategy,
-    cache_key_fn: Arc<dyn Fn(&RequestInfo) -> String + Send + Sync>,
+    /// Shared with the proxy so snapshots follow backend swaps.
+    backend: BackendSlot,
+    compression: compression::CompressionPolicy,
+    cache_key: proxy::CacheKeyFns,
+    redactor: redact::Redactor,
+    upstream_auth: Option<Arc<dyn upstream_auth::UpstreamAuth>>,
+    transform_request: Option<TransformRequestFn>,
     /// Current snapshot list — grows/shrinks via add/remove operations.
     snapshots: Vec<String>,
 }
@@ -519,7 +2144,11 @@ impl SnapshotWorker {
         let initial = self.snapshots.clone();
         for path in &initial {
             if let Err(e) = self.fetch_and_store(path).await {
-                tracing::warn!("Failed to pre-generate snapshot '{}': {}", path, e);
+                tracing::warn!(
+                    "Failed to pre-generate snapshot '{}': {}",
+                    self.redactor.target(path),
+                    e
+                );
             }
         }
 
@@ -528,30 +2157,52 @@ impl SnapshotWorker {
             match req.op {
                 cache::SnapshotOp::Add(path) => match self.fetch_and_store(&path).await {
                     Ok(()) => self.snapshots.push(path),
-                    Err(e) => tracing::warn!("add_snapshot '{}' failed: {}", path, e),
+                    Err(e) => tracing::warn!(
+                        "add_snapshot '{}' failed: {}",
+                        self.redactor.target(&path),
+                        e
+                    ),
                 },
                 cache::SnapshotOp::Refresh(path) => {
                     if let Err(e) = self.fetch_and_store(&path).await {
-                        tracing::warn!("refresh_snapshot '{}' failed: {}", path, e);
+                        tracing::warn!(
+                            "refresh_snapshot '{}' failed: {}",
+                            self.redactor.target(&path),
+                            e
+                        );
                     }
                 }
                 cache::SnapshotOp::Remove(path) => {
                     let empty_headers = axum::http::HeaderMap::new();
                     let req_info = RequestInfo {
                         method: "GET",
-                        path: &path,
+                        path: &normalize::normalize_percent_encoding(&path),
                         query: "",
                         headers: &empty_headers,
                     };
-                    let key = (self.cache_key_fn)(&req_info);
-                    self.cache.clear_by_pattern(&key).await;
-                    self.snapshots.retain(|s| s != &path);
+                    match self.cache_key.key(&req_info).await {
+                        Ok(key) => {
+                            if let Some(key) = key {
+                                self.cache.clear_key(&key).await;
+                            }
+                            self.snapshots.retain(|s| s != &path);
+                        }
+                        Err(e) => tracing::warn!(
+                            "remove_snapshot '{}' failed: cache_key_fn panicked: {}",
+                            self.redactor.target(&path),
+                            e
+                        ),
+                    }
                 }
                 cache::SnapshotOp::RefreshAll => {
                     let paths: Vec<String> = self.snapshots.clone();
                     for path in &paths {
                         if let Err(e) = self.fetch_and_store(path).await {
-                            tracing::warn!("refresh_all_snapshots '{}' failed: {}", path, e);
+                            tracing::warn!(
+                                "refresh_all_snapshots '{}' failed: {}",
+                                self.redactor.target(path),
+                                e
+                            );
                         }
                     }
                 }
@@ -564,11 +2215,15 @@ impl SnapshotWorker {
     async fn fetch_and_store(&self, path: &str) -> anyhow::Result<()> {
         proxy::fetch_and_cache_snapshot(
             path,
-            &self.upstream_client,
-            &self.proxy_url,
+            &self.backend.current(),
             &self.cache,
-            &self.compress_strategy,
-            &self.cache_key_fn,
+            &self.compression,
+            &self.cache_key,
+            &self.redactor,
+            proxy::SnapshotHooks {
+                upstream_auth: self.upstream_auth.as_deref(),
+                transform_request: self.transform_request.as_deref(),
+            },
         )
         .await
     }
@@ -594,6 +2249,14 @@ mod tests {
         assert!(!CacheStrategy::OnlyAssets.allows_content_type(None));
     }
 
+    #[test]
+    #[should_panic(expected = "exclude_paths[1] \"re:^/(\": invalid regex")]
+    fn test_builder_rejects_invalid_regex_pattern() {
+        CreateProxyConfig::new("http://localhost:8080".to_string())
+            .with_include_paths(vec![r"GET re:^/products/\d+$".to_string()])
+            .with_exclude_paths(vec!["/admin/*".to_string(), "re:^/(".to_string()]);
+    }
+
     #[test]
     fn test_compress_strategy_display() {
         assert_eq!(CompressStrategy::default().to_string(), "brotli");
@@ -603,6 +2266,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(deprecated)]
     async fn test_create_proxy() {
         let config = CreateProxyConfig::new("http://localhost:8080".to_string());
         assert_eq!(config.compress_strategy, CompressStrategy::Brotli);
@@ -611,4 +2275,213 @@ mod tests {
         handle.invalidate("GET:/api/*");
         // Just ensure it compiles and runs without panic
     }
+
+    #[tokio::test]
+    async fn test_proxy_handle_cache_serves_requests() {
+        use crate::test_support::{body_string, get, send};
+
+        // Nothing listens on the backend, so only the seeded entry can answer.
+        let (app, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:1".to_string()));
+        assert_eq!(handle.cache().size().await, 0);
+
+        handle
+            .seed(
+                "GET:/page",
+                200,
+                vec![("content-type".to_string(), "text/html".to_string())],
+                b"prerendered",
+            )
+            .await
+            .unwrap();
+        assert_eq!(handle.cache().size().await, 1);
+
+        let response = send(&app, get("/page")).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(body_string(response).await, "prerendered");
+        assert_eq!(handle.stats().totals().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_every_constructor_applies_cache_404_capacity() {
+        use crate::test_support::{get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(Router::new().fallback(move || {
+            backend_calls.fetch_add(1, Ordering::SeqCst);
+            async { axum::http::StatusCode::NOT_FOUND }
+        }))
+        .await;
+        // With room for one 404, `/b` evicts `/a`, which is fetched again.
+        let backend_calls_for = |app: Router| {
+            let calls = calls.clone();
+            async move {
+                calls.store(0, Ordering::SeqCst);
+                for path in ["/a", "/b", "/a"] {
+                    send(&app, get(path)).await;
+                }
+                calls.load(Ordering::SeqCst)
+            }
+        };
+        let config =
+            |capacity| CreateProxyConfig::new(backend.clone()).with_cache_404_capacity(capacity);
+
+        for capacity in [1, 2] {
+            let expected = if capacity == 1 { 3 } else { 2 };
+            let (app, _handle) = create_proxy_handle(config(capacity));
+            assert_eq!(backend_calls_for(app).await, expected);
+            let app = create_proxy_with_handle(config(capacity), CacheHandle::new());
+            assert_eq!(backend_calls_for(app).await, expected);
+            let (app, _handle) = create_proxy_with_cache(
+                config(capacity),
+                cache_store_for(&config(capacity), CacheHandle::new()),
+            );
+            assert_eq!(backend_calls_for(app).await, expected);
+        }
+
+        // Routers built on one store share its entries.
+        let cache = cache_store_for(&config(2), CacheHandle::new());
+        let (first, _handle) = create_proxy_with_cache(config(2), cache.clone());
+        let (second, handle) = create_proxy_with_cache(config(2), cache);
+        calls.store(0, Ordering::SeqCst);
+        send(&first, get("/a")).await;
+        send(&second, get("/a")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(handle.cache().size_404().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_background_tasks_stop_on_shutdown_and_when_dropped() {
+        let (_app, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:1".to_string()));
+        handle
+            .seed("GET:/page", 200, vec![], b"page")
+            .await
+            .unwrap();
+        handle.shutdown().await;
+        assert!(handle.shutdown_token().is_cancelled());
+        // The invalidation listener is gone, so nothing clears the entry.
+        handle.cache_handle().invalidate_all();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(handle.cache().size().await, 1);
+
+        let (app, handle) =
+            create_proxy_handle(CreateProxyConfig::new("http://127.0.0.1:1".to_string()));
+        let token = handle.shutdown_token();
+        drop(handle);
+        assert!(!token.is_cancelled(), "the router still uses the proxy");
+        drop(app);
+        assert!(token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_scoped_invalidations_only_reach_their_scope() {
+        use cache::{CachedResponse, InvalidationMessage};
+
+        let handle = CacheHandle::new();
+        let mut audit = handle.subscribe();
+        let entry = CachedResponse {
+            body: b"page".to_vec().into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        let mut stores = Vec::new();
+        for scope in [Some("a"), Some("b"), None] {
+            let store = CacheStore::new(handle.clone(), 10);
+            store.set("GET:/page".to_string(), entry.clone()).await;
+            tokio::spawn(invalidation_listener(
+                store.clone(),
+                redact::Redactor::default(),
+                scope.map(str::to_string),
+            ));
+            stores.push(store);
+        }
+        let sizes = || async {
+            let mut sizes = Vec::new();
+            for store in &stores {
+                sizes.push(store.size().await);
+            }
+            sizes
+        };
+        let wait_for = |expected: Vec<usize>| async move {
+            for _ in 0..100 {
+                if sizes().await == expected {
+                    return;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            panic!("expected sizes {:?}, got {:?}", expected, sizes().await);
+        };
+
+        handle.scoped("a").invalidate("GET:/*");
+        wait_for(vec![0, 1, 1]).await;
+
+        // Unscoped invalidations still reach every listener.
+        for store in &stores {
+            store.set("GET:/page".to_string(), entry.clone()).await;
+        }
+        handle.invalidate_all();
+        wait_for(vec![0, 0, 0]).await;
+
+        // Raw subscribers see every event with its scope.
+        let event = audit.recv().await.unwrap();
+        assert_eq!(event.scope.as_deref(), Some("a"));
+        assert!(matches!(event.message, InvalidationMessage::Pattern(p) if p == "GET:/*"));
+        let event = audit.recv().await.unwrap();
+        assert!(event.scope.is_none());
+        assert!(matches!(event.message, InvalidationMessage::All));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_invalidation_listener_clears_and_keeps_listening() {
+        let handle = CacheHandle::new();
+        let entry = cache::CachedResponse {
+            body: b"page".to_vec().into(),
+            headers: Vec::new(),
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+        let store = CacheStore::new(handle.clone(), 10);
+        store.set("GET:/page".to_string(), entry.clone()).await;
+        store.set("GET:/other".to_string(), entry.clone()).await;
+        tokio::spawn(invalidation_listener(
+            store.clone(),
+            redact::Redactor::default(),
+            Some("a".to_string()),
+        ));
+        let wait_for = |expected: usize| {
+            let store = store.clone();
+            async move {
+                for _ in 0..100 {
+                    if store.size().await == expected {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+                panic!("expected {} entries, got {}", expected, store.size().await);
+            }
+        };
+
+        // A burst past the channel's capacity, sent before the listener runs.
+        for i in 0..40 {
+            handle.scoped("b").invalidate_key(&format!("GET:/{i}"));
+        }
+        wait_for(0).await;
+
+        // Later invalidations still apply.
+        store.set("GET:/page".to_string(), entry.clone()).await;
+        store.set("GET:/other".to_string(), entry).await;
+        handle.scoped("a").invalidate_key("GET:/page");
+        wait_for(1).await;
+        assert!(store.get("GET:/other").await.is_some());
+    }
 }
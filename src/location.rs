@@ -0,0 +1,143 @@
+//! Rewriting `Location` headers that point at the backend.
+//!
+//! A backend that builds absolute redirect URLs from its own address sends
+//! clients to, say, `http://localhost:8080/next`, which they cannot reach.
+//! With `rewrite_location`, such a value is rewritten to a path on the
+//! proxy, or to the same path under a configured public base URL. Values
+//! that are relative already, or that name any other origin, are left alone,
+//! except that a proxy mounted under a path prefix puts the prefix back in
+//! front of absolute paths.
+
+use reqwest::Url;
+
+/// The rewritten `Location`, or `None` when `location` does not point at
+/// `backend_url`.
+///
+/// Origins are compared the way browsers do: scheme and host without regard
+/// to case, and a port left out stands for the scheme's default. When the
+/// backend URL has a path, only locations under it match, and the rewritten
+/// path drops it, mirroring how requests are forwarded to the backend.
+pub fn rewrite(location: &str, backend_url: &str, public_base: Option<&str>) -> Option<String> {
+    let backend = Url::parse(backend_url).ok()?;
+    let target = if location.starts_with("//") {
+        // Scheme-relative: it takes the scheme of the response's URL.
+        Url::parse(&format!("{}:{}", backend.scheme(), location)).ok()?
+    } else {
+        Url::parse(location).ok()?
+    };
+
+    let same_origin = target.scheme() == backend.scheme()
+        && target.host_str() == backend.host_str()
+        && target.port_or_known_default() == backend.port_or_known_default();
+    if !same_origin {
+        return None;
+    }
+
+    let prefix = backend.path().trim_end_matches('/');
+    let path = target.path().strip_prefix(prefix)?;
+    let path = match path {
+        "" => "/",
+        path if path.starts_with('/') => path,
+        // `/app` must not match `/application`.
+        _ => return None,
+    };
+
+    let mut rewritten = public_base
+        .map(|base| base.trim_end_matches('/').to_string())
+        .unwrap_or_default();
+    rewritten.push_str(path);
+    if let Some(query) = target.query() {
+        rewritten.push('?');
+        rewritten.push_str(query);
+    }
+    if let Some(fragment) = target.fragment() {
+        rewritten.push('#');
+        rewritten.push_str(fragment);
+    }
+    Some(rewritten)
+}
+
+/// `location` under the path `prefix` the proxy is mounted at, or `None`
+/// when it is not an absolute path on the proxy's own origin.
+pub fn add_mount_prefix(location: &str, prefix: &str) -> Option<String> {
+    if !location.starts_with('/') || location.starts_with("//") {
+        return None;
+    }
+    Some(format!("{}{}", prefix.trim_end_matches('/'), location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "http://localhost:8080";
+
+    #[test]
+    fn test_backend_locations_become_relative() {
+        let rewrite = |location| rewrite(location, BACKEND, None);
+        assert_eq!(
+            rewrite("http://localhost:8080/next?page=2#top").as_deref(),
+            Some("/next?page=2#top")
+        );
+        assert_eq!(rewrite("http://LOCALHOST:8080").as_deref(), Some("/"));
+        assert_eq!(rewrite("//localhost:8080/next").as_deref(), Some("/next"));
+
+        // Relative already, or another origin.
+        assert_eq!(rewrite("/next"), None);
+        assert_eq!(rewrite("next"), None);
+        assert_eq!(rewrite("https://localhost:8080/next"), None);
+        assert_eq!(rewrite("http://localhost:9090/next"), None);
+        assert_eq!(rewrite("http://example.com/next"), None);
+    }
+
+    #[test]
+    fn test_default_ports_and_public_base() {
+        assert_eq!(
+            rewrite("https://app.internal:443/a", "https://app.internal", None).as_deref(),
+            Some("/a")
+        );
+        assert_eq!(
+            rewrite("http://app.internal/a", "http://app.internal:80/", None).as_deref(),
+            Some("/a")
+        );
+        assert_eq!(
+            rewrite(
+                "http://localhost:8080/a?b=c",
+                BACKEND,
+                Some("https://www.example.com/")
+            )
+            .as_deref(),
+            Some("https://www.example.com/a?b=c")
+        );
+    }
+
+    #[test]
+    fn test_backend_path_prefix() {
+        let backend = "http://localhost:8080/app";
+        assert_eq!(
+            rewrite("http://localhost:8080/app/next", backend, None).as_deref(),
+            Some("/next")
+        );
+        assert_eq!(
+            rewrite("http://localhost:8080/app", backend, None).as_deref(),
+            Some("/")
+        );
+        assert_eq!(
+            rewrite("http://localhost:8080/application", backend, None),
+            None
+        );
+        assert_eq!(rewrite("http://localhost:8080/other", backend, None), None);
+    }
+
+    #[test]
+    fn test_mount_prefix() {
+        assert_eq!(
+            add_mount_prefix("/next?a=b", "/app").as_deref(),
+            Some("/app/next?a=b")
+        );
+        assert_eq!(add_mount_prefix("/", "/app/").as_deref(), Some("/app/"));
+        assert_eq!(add_mount_prefix("//cdn.example.com/x", "/app"), None);
+        assert_eq!(add_mount_prefix("https://example.com/x", "/app"), None);
+        assert_eq!(add_mount_prefix("next", "/app"), None);
+    }
+}
@@ -1,25 +1,50 @@
+use anyhow::Context;
 use axum::Router;
 use phantom_frame::{
-    cache::CacheHandle,
-    config::{Config, ProxyModeConfig},
-    control, CreateProxyConfig, ProxyMode,
+    config::{self, Config, ProxyModeConfig},
+    control, CreateProxyConfig, ProxyHandle, ProxyMode,
 };
-use std::{env, path::PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
+    // reqwest compiles rustls with ring and axum-server with aws-lc-rs; with
+    // both present rustls cannot pick a provider on its own.
+    #[cfg(feature = "rustls")]
+    let _ = rustls::crypto::ring::default_provider().install_default();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <config-file.toml>", args[0]);
-        eprintln!("Example: {} ./config.toml", args[0]);
-        std::process::exit(1);
-    }
-
-    let config = Config::from_file(&args[1])?;
+    let config_path = args.get(1).cloned();
+    let config = match &config_path {
+        Some(path) => {
+            let config = Config::from_file(path)?;
+            tracing::info!("Loaded configuration from: {}", path);
+            config
+        }
+        None if env::var_os("PHANTOM_PROXY_URL").is_some() => {
+            let config = Config::from_env()?;
+            tracing::info!("Loaded configuration from PHANTOM_* environment variables");
+            config
+        }
+        None => {
+            eprintln!("Usage: {} <config-file.toml>", args[0]);
+            eprintln!("Example: {} ./config.toml", args[0]);
+            eprintln!(
+                "Without a file, set PHANTOM_PROXY_URL (and optionally {})",
+                config::ENV_OVERRIDES[1..].join(", ")
+            );
+            std::process::exit(1);
+        }
+    };
 
-    tracing::info!("Loaded configuration from: {}", args[1]);
     tracing::info!("HTTP port: {}", config.http_port);
     if let Some(p) = config.https_port {
         tracing::info!("HTTPS port: {}", p);
@@ -27,6 +52,19 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Control port: {}", config.control_port);
     tracing::info!("Server entries: {}", config.server.len());
 
+    // ── TLS key material ─────────────────────────────────────────────────────
+    // Loaded before anything starts, so an unreadable file fails startup.
+    let https_cert = match (config.https_port, &config.cert_path, &config.key_path) {
+        (Some(_), Some(cert), Some(key)) => {
+            Some(TlsCert::load("HTTPS", cert.clone(), key.clone()).await?)
+        }
+        _ => None,
+    };
+    let control_cert = match (&config.control_cert_path, &config.control_key_path) {
+        (Some(cert), Some(key)) => Some(TlsCert::load("control", cert.clone(), key.clone()).await?),
+        _ => None,
+    };
+
     // ── Spawn execute commands and wait for their ports ──────────────────────
     // Collect servers that have an `execute` command.
     // Spawn all processes first so they boot concurrently, then wait for each.
@@ -35,7 +73,7 @@ async fn main() -> anyhow::Result<()> {
 
     for (name, server_cfg) in &config.server {
         if let Some(ref cmd) = server_cfg.execute {
-            let (host, port) = extract_host_port(&server_cfg.proxy_url)?;
+            let (host, port) = extract_host_port(&server_cfg.backend_urls()[0])?;
 
             tracing::info!("server '{}': spawning command: {}", name, cmd);
 
@@ -51,18 +89,45 @@ async fn main() -> anyhow::Result<()> {
 
     // ── Build per-server routers ────────────────────────────────────────────
     // Collect (name, bind_to, router, handle) tuples.
-    let mut entries: Vec<(String, String, Router, CacheHandle)> = Vec::new();
+    let mut entries: Vec<(String, String, Router, ProxyHandle)> = Vec::new();
 
     for (name, server_cfg) in &config.server {
         let mut proxy_config = CreateProxyConfig::new(server_cfg.proxy_url.clone())
+            .with_backends(server_cfg.backends.clone())
+            .with_load_balancing(server_cfg.load_balancing)
+            .with_backend_cooldown(Duration::from_secs(server_cfg.backend_cooldown_secs))
             .with_include_paths(server_cfg.include_paths.clone())
             .with_exclude_paths(server_cfg.exclude_paths.clone())
+            .with_path_rules(server_cfg.path_rules.clone())
+            .with_wildcard_syntax(server_cfg.wildcard_syntax)
             .with_websocket_enabled(server_cfg.enable_websocket)
             .with_forward_get_only(server_cfg.forward_get_only)
             .with_cache_404_capacity(server_cfg.cache_404_capacity)
+            .with_cache_errors_ttl_secs(server_cfg.cache_errors_ttl_secs)
+            .with_cache_errors_capacity(server_cfg.cache_errors_capacity)
+            .with_cache_max_bytes(server_cfg.cache_max_bytes)
+            .with_max_request_body_bytes(server_cfg.max_request_body_bytes)
+            .with_max_cacheable_response_bytes(server_cfg.max_cacheable_response_bytes)
             .with_use_404_meta(server_cfg.use_404_meta)
+            .with_meta_404_scan_bytes(server_cfg.meta_404_scan_bytes)
+            .with_respect_cache_control(server_cfg.respect_cache_control)
+            .with_skip_set_cookie_responses(server_cfg.skip_set_cookie_responses)
+            .with_bypass_credentialed_requests(server_cfg.bypass_credentialed_requests)
+            .with_bypass_cookie_names(server_cfg.bypass_cookie_names.clone())
+            .with_respect_client_no_cache(server_cfg.respect_client_no_cache)
+            .with_serve_head_from_get(server_cfg.serve_head_from_get)
+            .with_respect_vary(server_cfg.respect_vary)
+            .with_trust_forwarded_headers(server_cfg.trust_forwarded_headers)
+            .with_preserve_host(server_cfg.preserve_host)
+            .with_follow_redirects(server_cfg.follow_redirects)
+            .with_rewrite_location(server_cfg.rewrite_location)
             .with_cache_strategy(server_cfg.cache_strategy.clone())
+            .with_streaming_content_types(server_cfg.streaming_content_types.clone())
             .with_compress_strategy(server_cfg.compress_strategy.clone())
+            .with_compress_content_types(server_cfg.compress_content_types.clone())
+            .with_compress_min_bytes(server_cfg.compress_min_bytes)
+            .with_compress_on_serve(server_cfg.compress_on_serve)
+            .with_compress_variants_max_bytes(server_cfg.compress_variants_max_bytes)
             .with_cache_storage_mode(server_cfg.cache_storage_mode.clone());
 
         if let Some(ref dir) = server_cfg.cache_directory {
@@ -78,17 +143,152 @@ async fn main() -> anyhow::Result<()> {
         };
         proxy_config = proxy_config.with_proxy_mode(proxy_mode);
 
-        proxy_config = proxy_config.with_webhooks(server_cfg.webhooks.clone());
+        proxy_config = proxy_config
+            .with_webhooks(server_cfg.webhooks.clone())
+            .with_render_queue_patterns(server_cfg.render_queue_patterns.clone())
+            .with_render_queue_capacity(server_cfg.render_queue_capacity)
+            .with_warm_concurrency(server_cfg.warm_concurrency)
+            .with_max_cache_writes_per_sec(server_cfg.max_cache_writes_per_sec)
+            .with_cache_write_queue_capacity(server_cfg.cache_write_queue_capacity)
+            .with_purge_cache_on_backend_change(server_cfg.purge_cache_on_backend_change)
+            .with_connect_timeout(std::time::Duration::from_millis(
+                server_cfg.connect_timeout_ms,
+            ))
+            .with_request_timeout(std::time::Duration::from_millis(
+                server_cfg.request_timeout_ms,
+            ))
+            .with_upgrade_handshake_timeout(std::time::Duration::from_millis(
+                server_cfg.upgrade_handshake_timeout_ms,
+            ))
+            .with_health_check_timeout(std::time::Duration::from_millis(
+                server_cfg.health_check_timeout_ms,
+            ))
+            .with_stats_window_minutes(server_cfg.stats_window_minutes)
+            .with_decision_memo_ttl_secs(server_cfg.decision_memo_ttl_secs)
+            .with_upgrade_fallback(server_cfg.upgrade_fallback.clone())
+            .with_upgrade_fallback_paths(server_cfg.upgrade_fallback_paths.clone())
+            .with_upgrade_reject_status(axum::http::StatusCode::from_u16(
+                server_cfg.upgrade_reject_status,
+            )?)
+            .with_accept_mismatch(server_cfg.accept_mismatch.clone())
+            .with_cache_error_policy(server_cfg.cache_error_policy)
+            .with_backend_error_policy(server_cfg.backend_error_policy)
+            .with_format_mismatch_policy(server_cfg.format_mismatch_policy)
+            .with_stale_max_age_secs(server_cfg.stale_max_age_secs)
+            .with_stale_on_error_status(server_cfg.stale_on_error_status)
+            .with_client_disconnect(server_cfg.client_disconnect)
+            .with_cache_status_header(
+                Some(server_cfg.cache_status_header.clone()).filter(|name| !name.is_empty()),
+            )
+            .with_coalesce_misses(server_cfg.coalesce_misses)
+            .with_redact_query_params(server_cfg.redact_query_params.clone())
+            .with_redact_headers(server_cfg.redact_headers.clone())
+            .with_verify_cache_origin(server_cfg.verify_cache_origin)
+            .with_html_normalize_paths(server_cfg.html_normalize_paths.clone())
+            .with_cache_rules(server_cfg.cache_rules.clone())
+            .with_cache_key_headers(server_cfg.cache_key_headers.clone())
+            .with_ignored_query_params(server_cfg.ignored_query_params.clone())
+            .with_allowed_query_params(server_cfg.allowed_query_params.clone())
+            .with_body_cache_rules(server_cfg.body_cache_rules.clone());
+
+        if !server_cfg.html_normalize_rules.is_empty() {
+            proxy_config = proxy_config.with_html_normalizer(
+                phantom_frame::html_normalize::HtmlNormalizer::new(
+                    &server_cfg.html_normalize_rules,
+                )?,
+            );
+        }
+
+        if let Some(ref auth) = server_cfg.upstream_auth {
+            proxy_config = proxy_config.with_upstream_auth(std::sync::Arc::new(auth.build()?));
+        }
+
+        if let Some(max_idle) = server_cfg.pool_max_idle_per_host {
+            proxy_config = proxy_config.with_pool_max_idle_per_host(max_idle);
+        }
+
+        if let Some(ref base_url) = server_cfg.location_base_url {
+            proxy_config = proxy_config.with_location_base_url(base_url.clone());
+        }
+
+        if let Some(ref prefix) = server_cfg.path_prefix {
+            proxy_config = proxy_config.with_path_prefix(prefix.clone());
+        }
 
-        let (router, handle) = phantom_frame::create_proxy(proxy_config);
+        if let Some(ref token) = server_cfg.revalidate_token {
+            proxy_config = proxy_config.with_revalidate_token(token.clone());
+        }
+
+        if let Some(ref name) = server_cfg.bypass_header {
+            proxy_config = proxy_config.with_bypass_header(name.clone());
+        }
+        if let Some(ref name) = server_cfg.purge_header {
+            proxy_config = proxy_config.with_purge_header(name.clone());
+        }
+
+        if let Some(ref token) = server_cfg.purge_token {
+            proxy_config = proxy_config.with_purge_token(token.clone());
+        }
+
+        #[cfg(feature = "redis-backend")]
+        if let Some(ref url) = server_cfg.redis_url {
+            let prefix = server_cfg
+                .redis_key_prefix
+                .clone()
+                .unwrap_or_else(|| format!("phantom-frame:{}:", name));
+            proxy_config = proxy_config
+                .with_redis_cache(phantom_frame::redis_cache::RedisCache::new(url, prefix)?);
+        }
+
+        if let Some(ref path) = server_cfg.health_check_path {
+            proxy_config = proxy_config.with_health_check_path(path.clone());
+        }
+        if server_cfg.health_check_interval_secs > 0 {
+            proxy_config = proxy_config.with_health_check_interval(Duration::from_secs(
+                server_cfg.health_check_interval_secs,
+            ));
+        }
+        if let Some(ref path) = server_cfg.render_queue_path {
+            proxy_config = proxy_config.with_render_queue_path(path.clone());
+        }
+
+        if let Some(ref path) = server_cfg.persistence_path {
+            proxy_config = proxy_config
+                .with_persistence_path(path.clone())
+                .with_persistence_interval(std::time::Duration::from_secs(
+                    server_cfg.persistence_interval_secs,
+                ));
+        }
+
+        #[cfg(feature = "cassette")]
+        if let Some(ref cassette) = server_cfg.cassette {
+            proxy_config = proxy_config
+                .with_cassette(phantom_frame::cassette::Cassette::open(cassette).await?);
+            tracing::info!(
+                "  server '{}': cassette {:?} at '{}'",
+                name,
+                cassette.mode,
+                cassette.path.display()
+            );
+        }
+
+        let (router, handle) = phantom_frame::create_proxy_handle(proxy_config);
 
         tracing::info!(
-            "  server '{}': bind_to='{}', proxy_url='{}', mode={:?}",
+            "  server '{}': bind_to='{}', backends={:?}, mode={:?}",
             name,
             server_cfg.bind_to,
-            server_cfg.proxy_url,
+            server_cfg.backend_urls(),
             server_cfg.proxy_mode,
         );
+        tracing::info!(
+            "  server '{}': enable_websocket={}, forward_get_only={}, cache_404_capacity={}, use_404_meta={}",
+            name,
+            server_cfg.enable_websocket,
+            server_cfg.forward_get_only,
+            server_cfg.cache_404_capacity,
+            server_cfg.use_404_meta,
+        );
 
         entries.push((name.clone(), server_cfg.bind_to.clone(), router, handle));
     }
@@ -107,7 +307,7 @@ async fn main() -> anyhow::Result<()> {
     // ── Compose top-level router ─────────────────────────────────────────────
     let mut app = Router::new();
     let mut star_router: Option<Router> = None;
-    let mut handles: Vec<(String, CacheHandle)> = Vec::new();
+    let mut handles: Vec<(String, ProxyHandle)> = Vec::new();
 
     for (name, bind_to, server_router, handle) in entries {
         handles.push((name, handle));
@@ -118,109 +318,358 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    // The health route answers at the root even without a `*` server.
+    app = app.route(
+        phantom_frame::health::HEALTH_PATH,
+        axum::routing::get(phantom_frame::health::health_handler),
+    );
+
     // Catch-all fallback (bind_to = "*") goes on last.
     if let Some(star) = star_router {
         app = app.fallback_service(star);
     }
 
+    // ── Config reload ────────────────────────────────────────────────────────
+    // Without a file there is nothing to re-read.
+    #[cfg(unix)]
+    if let Some(path) = config_path.clone() {
+        let certs = https_cert.iter().chain(&control_cert).cloned().collect();
+        spawn_reload_on_sighup(path, handles.clone(), certs)?;
+    }
+
     // ── Control server ───────────────────────────────────────────────────────
-    let control_app = control::create_control_router(handles, config.control_auth.clone());
+    let listener_ready = Arc::new(AtomicBool::new(false));
+    let control_app = control::control_router(
+        control::ControlState::new(handles.clone(), config.control_auth.clone())
+            .with_public_metrics(config.public_metrics)
+            .with_listener_ready(listener_ready.clone()),
+    );
 
     // ── HTTP listener ────────────────────────────────────────────────────────
     let http_addr = format!("0.0.0.0:{}", config.http_port);
     let http_listener = tokio::net::TcpListener::bind(&http_addr).await?;
     tracing::info!("HTTP proxy listening on {}", http_addr);
+    listener_ready.store(true, Ordering::SeqCst);
 
+    // Cancelled on SIGINT / SIGTERM: the proxy listeners stop accepting and
+    // drain their in-flight requests.
+    let draining = CancellationToken::new();
     let http_app = app.clone();
-    let http_server = tokio::spawn(async move {
-        axum::serve(http_listener, http_app)
+    let mut http_server = tokio::spawn({
+        let draining = draining.clone();
+        async move {
+            axum::serve(
+                http_listener,
+                http_app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .with_graceful_shutdown(draining.cancelled_owned())
             .await
             .expect("HTTP proxy server failed");
+        }
     });
 
     // ── Optional HTTPS listener ──────────────────────────────────────────────
-    let https_port = config.https_port;
-    let cert_path = config.cert_path.clone();
-    let key_path = config.key_path.clone();
-    let https_app = app.clone();
-
-    let https_task = tokio::spawn(async move {
-        if let Some(port) = https_port {
-            let cert = cert_path.unwrap();
-            let key = key_path.unwrap();
-            if let Err(e) = run_https_server(port, cert, key, https_app).await {
-                tracing::error!("HTTPS server error: {}", e);
+    let https_app = app
+        .clone()
+        .layer(axum::Extension(phantom_frame::forwarded::TlsConnection));
+
+    let tls_handle = axum_server::Handle::new();
+    let mut https_task = tokio::spawn({
+        let tls_handle = tls_handle.clone();
+        let https = config.https_port.zip(https_cert.clone());
+        async move {
+            if let Some((port, cert)) = https {
+                if let Err(e) = serve_tls(port, cert.config, https_app, tls_handle).await {
+                    tracing::error!("HTTPS server error: {}", e);
+                }
             }
-        } else {
-            // No HTTPS configured — park this task indefinitely so the
-            // select! below never fires on it spuriously.
-            std::future::pending::<()>().await;
         }
     });
 
     // ── Control listener ─────────────────────────────────────────────────────
     let control_addr = format!("0.0.0.0:{}", config.control_port);
-    let control_listener = tokio::net::TcpListener::bind(&control_addr).await?;
-    tracing::info!("Control server listening on {}", control_addr);
-
-    let control_server = tokio::spawn(async move {
-        axum::serve(control_listener, control_app)
-            .await
-            .expect("Control server failed");
-    });
+    let control_server = match control_cert {
+        Some(cert) => {
+            let port = config.control_port;
+            tracing::info!("Control server listening on {} (TLS)", control_addr);
+            tokio::spawn(async move {
+                if let Err(e) =
+                    serve_tls(port, cert.config, control_app, axum_server::Handle::new()).await
+                {
+                    tracing::error!("Control server error: {}", e);
+                }
+            })
+        }
+        None => {
+            let control_listener = tokio::net::TcpListener::bind(&control_addr).await?;
+            tracing::info!("Control server listening on {}", control_addr);
+            tokio::spawn(async move {
+                axum::serve(control_listener, control_app)
+                    .await
+                    .expect("Control server failed");
+            })
+        }
+    };
 
-    tokio::select! {
-        _ = http_server => {
+    let signalled = tokio::select! {
+        _ = &mut http_server => {
             tracing::error!("HTTP proxy server stopped unexpectedly");
+            false
         }
-        _ = https_task => {
+        _ = &mut https_task, if config.https_port.is_some() => {
             tracing::error!("HTTPS proxy server stopped unexpectedly");
+            false
         }
         _ = control_server => {
             tracing::error!("Control server stopped unexpectedly");
+            false
+        }
+        _ = shutdown_signal() => true,
+    };
+
+    // Fail readiness first so load balancers drain this instance. The
+    // control server keeps answering until the process exits.
+    listener_ready.store(false, Ordering::SeqCst);
+    if signalled {
+        let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+        tracing::info!(
+            "Shutting down, draining for up to {}s",
+            config.drain_timeout_secs
+        );
+        draining.cancel();
+        tls_handle.graceful_shutdown(Some(drain_timeout));
+        let drained = tokio::time::timeout(drain_timeout, async {
+            let _ = tokio::join!(http_server, https_task);
+            // Upgraded connections outlive the server's own drain.
+            while handles
+                .iter()
+                .any(|(_, handle)| handle.stats().active_tunnels() > 0)
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!("Drain timeout elapsed, closing remaining connections");
         }
     }
+    for (_, handle) in &handles {
+        handle.shutdown().await;
+    }
+    save_snapshots(&handles).await;
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::warn!("Cannot listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Write the cache snapshot of every server that has `persistence_path`,
+/// and any render queue changes still waiting to be written.
+async fn save_snapshots(handles: &[(String, ProxyHandle)]) {
+    for (_, handle) in handles {
+        if let Some(queue) = handle.render_queue() {
+            queue.flush().await;
+        }
+    }
+    for (name, handle) in handles {
+        let Some(persistence) = handle.persistence() else {
+            continue;
+        };
+        match persistence.save().await {
+            Ok(entries) => tracing::info!(
+                "server '{}': saved {} cache entries to '{}'",
+                name,
+                entries,
+                persistence.path().display()
+            ),
+            Err(e) => tracing::error!("server '{}': {:#}", name, e),
+        }
+    }
+}
 
+// ── Reload helpers ───────────────────────────────────────────────────────────
+
+/// Re-read the config file on SIGHUP, hot-swap any changed backends, and
+/// reload the TLS certificates from their files.
+///
+/// Only backend URLs and certificates are applied live; other settings,
+/// including the certificate paths, still need a restart.
+#[cfg(unix)]
+fn spawn_reload_on_sighup(
+    config_path: String,
+    handles: Vec<(String, ProxyHandle)>,
+    certs: Vec<TlsCert>,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            tracing::info!("SIGHUP received, reloading {}", config_path);
+            match Config::from_file(&config_path) {
+                Ok(config) => apply_backend_changes(&config, &handles).await,
+                Err(e) => tracing::error!("Config reload failed, keeping current backends: {}", e),
+            }
+            for cert in &certs {
+                cert.reload().await;
+            }
+        }
+    });
     Ok(())
 }
 
+#[cfg(unix)]
+async fn apply_backend_changes(config: &Config, handles: &[(String, ProxyHandle)]) {
+    for (name, handle) in handles {
+        let Some(server_cfg) = config.server.get(name) else {
+            tracing::warn!(
+                "server '{}' missing from reloaded config, left unchanged",
+                name
+            );
+            continue;
+        };
+        let urls = server_cfg.backend_urls();
+        let current: Vec<_> = handle
+            .backends()
+            .iter()
+            .map(|b| b.url().to_string())
+            .collect();
+        if current == urls {
+            continue;
+        }
+        if let Err(e) = handle
+            .set_backends(urls, server_cfg.purge_cache_on_backend_change)
+            .await
+        {
+            tracing::error!("server '{}': failed to switch backend: {}", name, e);
+        }
+    }
+}
+
 // ── TLS helpers ──────────────────────────────────────────────────────────────
 
-async fn run_https_server(
-    port: u16,
+#[cfg(feature = "rustls")]
+type TlsConfig = axum_server::tls_rustls::RustlsConfig;
+#[cfg(feature = "native-tls")]
+type TlsConfig = axum_server::tls_openssl::OpenSSLConfig;
+
+/// A certificate and key loaded for a listener, kept with their paths so
+/// SIGHUP can re-read them after a renewal.
+#[derive(Clone)]
+struct TlsCert {
+    /// Which listener it serves, for log messages.
+    listener: &'static str,
     cert_path: PathBuf,
     key_path: PathBuf,
-    app: Router,
-) -> anyhow::Result<()> {
-    let addr: std::net::SocketAddr = format!("0.0.0.0:{}", port).parse()?;
-    start_tls(addr, cert_path, key_path, app).await
+    config: TlsConfig,
+}
+
+impl TlsCert {
+    /// Load the key material, failing with both paths named so a bad file
+    /// stops startup instead of leaving the listener down.
+    async fn load(
+        listener: &'static str,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let config = load_tls_config(&cert_path, &key_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to load the {} TLS certificate '{}' and key '{}'",
+                    listener,
+                    cert_path.display(),
+                    key_path.display()
+                )
+            })?;
+        Ok(Self {
+            listener,
+            cert_path,
+            key_path,
+            config,
+        })
+    }
+
+    /// Re-read the files into the running listener. On failure the previous
+    /// certificate stays in use.
+    async fn reload(&self) {
+        match reload_tls_config(&self.config, &self.cert_path, &self.key_path).await {
+            Ok(()) => tracing::info!(
+                "Reloaded the {} TLS certificate from '{}'",
+                self.listener,
+                self.cert_path.display()
+            ),
+            Err(e) => tracing::error!(
+                "Failed to reload the {} TLS certificate, keeping the current one: {:#}",
+                self.listener,
+                e
+            ),
+        }
+    }
 }
 
 #[cfg(feature = "rustls")]
-async fn start_tls(
-    addr: std::net::SocketAddr,
-    cert_path: PathBuf,
-    key_path: PathBuf,
-    app: Router,
+async fn load_tls_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsConfig> {
+    Ok(TlsConfig::from_pem_file(cert_path, key_path).await?)
+}
+
+#[cfg(feature = "native-tls")]
+async fn load_tls_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsConfig> {
+    Ok(TlsConfig::from_pem_file(cert_path, key_path)?)
+}
+
+#[cfg(feature = "rustls")]
+async fn reload_tls_config(
+    config: &TlsConfig,
+    cert_path: &Path,
+    key_path: &Path,
 ) -> anyhow::Result<()> {
-    let tls_config =
-        axum_server::tls_rustls::RustlsConfig::from_pem_file(cert_path, key_path).await?;
-    axum_server::bind_rustls(addr, tls_config)
-        .serve(app.into_make_service())
-        .await
-        .map_err(Into::into)
+    Ok(config.reload_from_pem_file(cert_path, key_path).await?)
 }
 
 #[cfg(feature = "native-tls")]
-async fn start_tls(
-    addr: std::net::SocketAddr,
-    cert_path: PathBuf,
-    key_path: PathBuf,
+async fn reload_tls_config(
+    config: &TlsConfig,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<()> {
+    Ok(config.reload_from_pem_file(cert_path, key_path)?)
+}
+
+async fn serve_tls(
+    port: u16,
+    config: TlsConfig,
     app: Router,
+    handle: axum_server::Handle,
 ) -> anyhow::Result<()> {
-    let tls_config = axum_server::tls_openssl::OpenSSLConfig::from_pem_file(cert_path, key_path)?;
-    axum_server::bind_openssl(addr, tls_config)
-        .serve(app.into_make_service())
+    let addr: std::net::SocketAddr = format!("0.0.0.0:{}", port).parse()?;
+    #[cfg(feature = "rustls")]
+    let mut server = axum_server::bind_rustls(addr, config).handle(handle);
+    #[cfg(feature = "native-tls")]
+    let mut server = axum_server::bind_openssl(addr, config).handle(handle);
+    // Advertise RFC 8441 so h2 clients can open WebSockets on the same connection.
+    server.http_builder().http2().enable_connect_protocol();
+    server
+        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .await
         .map_err(Into::into)
 }
@@ -0,0 +1,287 @@
+//! `Accept` header parsing and media-type matching (RFC 9110 §12.5.1).
+//!
+//! Qualities are kept as integers in thousandths (`q=0.5` is `500`) so they
+//! compare exactly. When several media ranges match a type, the most specific
+//! one decides its quality: `text/html;level=1` beats `text/html`, which beats
+//! `text/*`, which beats `*/*`.
+
+/// One media range from an `Accept` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaRange {
+    /// Lowercased type, or `*`.
+    pub type_: String,
+    /// Lowercased subtype, or `*`.
+    pub subtype: String,
+    /// Parameters before `q`, with lowercased names and unquoted values.
+    pub params: Vec<(String, String)>,
+    /// Quality in thousandths, `0..=1000`.
+    pub quality: u16,
+}
+
+impl MediaRange {
+    /// Higher is more specific; only meaningful between ranges that match.
+    fn specificity(&self) -> usize {
+        match (self.type_.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2 + self.params.len(),
+        }
+    }
+
+    fn matches(&self, media: &MediaType) -> bool {
+        (self.type_ == "*" || self.type_ == media.type_)
+            && (self.subtype == "*" || self.subtype == media.subtype)
+            && self.params.iter().all(|(name, value)| {
+                media.params.iter().any(|(n, v)| {
+                    n == name
+                        && if name == "charset" {
+                            v.eq_ignore_ascii_case(value)
+                        } else {
+                            v == value
+                        }
+                })
+            })
+    }
+}
+
+/// A concrete media type such as a response `Content-Type`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaType {
+    pub type_: String,
+    pub subtype: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// Parse `type/subtype;param=value`. Returns `None` for malformed values
+    /// or wildcards.
+    pub fn parse(value: &str) -> Option<Self> {
+        parse_media(value).filter(|media| media.type_ != "*" && media.subtype != "*")
+    }
+}
+
+/// Parse an `Accept` header value. Malformed ranges, and ranges with an
+/// invalid `q`, are skipped.
+pub fn parse_accept(value: &str) -> Vec<MediaRange> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let item = item.trim();
+            if item.is_empty() {
+                return None;
+            }
+            let MediaType {
+                type_,
+                subtype,
+                params: raw_params,
+            } = parse_media(item)?;
+            if type_ == "*" && subtype != "*" {
+                return None;
+            }
+            // Parameters after `q` are accept extensions, not part of the range.
+            let mut params = Vec::new();
+            let mut quality = 1000;
+            for (name, value) in raw_params {
+                if name == "q" {
+                    quality = parse_quality(&value)?;
+                    break;
+                }
+                params.push((name, value));
+            }
+            Some(MediaRange {
+                type_,
+                subtype,
+                params,
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Quality the client assigns to `media`: the quality of the most specific
+/// matching range, or 0 when nothing matches.
+pub fn quality(ranges: &[MediaRange], media: &MediaType) -> u16 {
+    ranges
+        .iter()
+        .filter(|range| range.matches(media))
+        .max_by_key(|range| range.specificity())
+        .map_or(0, |range| range.quality)
+}
+
+/// Whether a response of type `content_type` satisfies an `Accept` header.
+///
+/// A missing or empty `Accept` accepts anything, as does a `content_type`
+/// that cannot be parsed (there is nothing to judge it by).
+pub fn is_acceptable(accept: Option<&str>, content_type: &str) -> bool {
+    let Some(accept) = accept else {
+        return true;
+    };
+    let ranges = parse_accept(accept);
+    if ranges.is_empty() {
+        return true;
+    }
+    match MediaType::parse(content_type) {
+        Some(media) => quality(&ranges, &media) > 0,
+        None => true,
+    }
+}
+
+/// Parse `type/subtype;params`, allowing wildcards.
+fn parse_media(value: &str) -> Option<MediaType> {
+    let mut parts = value.split(';');
+    let (type_, subtype) = parts.next()?.trim().split_once('/')?;
+    let (type_, subtype) = (type_.trim(), subtype.trim());
+    if !is_token(type_) || !is_token(subtype) {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    for param in parts {
+        let param = param.trim();
+        if param.is_empty() {
+            continue;
+        }
+        let (name, value) = param.split_once('=')?;
+        let name = name.trim();
+        if !is_token(name) {
+            return None;
+        }
+        let value = value.trim();
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => quoted.strip_suffix('"')?.replace("\\\"", "\""),
+            None if is_token(value) => value.to_string(),
+            None => return None,
+        };
+        params.push((name.to_ascii_lowercase(), value));
+    }
+
+    Some(MediaType {
+        type_: type_.to_ascii_lowercase(),
+        subtype: subtype.to_ascii_lowercase(),
+        params,
+    })
+}
+
+/// `qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] )`
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(3)
+        .fold(0u16, |acc, digit| acc * 10 + u16::from(digit - b'0'));
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q(accept: &str, content_type: &str) -> u16 {
+        quality(
+            &parse_accept(accept),
+            &MediaType::parse(content_type).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_parse_quality_values() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.05"), Some(50));
+        assert_eq!(parse_quality("0.001"), Some(1));
+        assert_eq!(parse_quality("1.001"), None);
+        assert_eq!(parse_quality("0.0001"), None);
+        assert_eq!(parse_quality("2"), None);
+        assert_eq!(parse_quality(".5"), None);
+        assert_eq!(parse_quality("0.a"), None);
+    }
+
+    #[test]
+    fn test_parse_accept_ranges() {
+        let ranges = parse_accept(
+            "Text/HTML;Level=1, application/json;q=0.8;ext=1, */*;q=0.1, bad, */json, x/y;q=7",
+        );
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].type_, "text");
+        assert_eq!(ranges[0].subtype, "html");
+        assert_eq!(
+            ranges[0].params,
+            vec![("level".to_string(), "1".to_string())]
+        );
+        assert_eq!(ranges[0].quality, 1000);
+        // Parameters after q are extensions and are dropped.
+        assert!(ranges[1].params.is_empty());
+        assert_eq!(ranges[1].quality, 800);
+        assert_eq!(ranges[2].quality, 100);
+    }
+
+    #[test]
+    fn test_most_specific_range_wins() {
+        let accept = "text/*;q=0.3, text/html;q=0.7, text/html;level=1, \
+                      text/html;level=2;q=0.4, */*;q=0.5";
+        // Example from RFC 9110 §12.5.1.
+        assert_eq!(q(accept, "text/html;level=1"), 1000);
+        assert_eq!(q(accept, "text/html"), 700);
+        assert_eq!(q(accept, "text/plain"), 300);
+        assert_eq!(q(accept, "image/jpeg"), 500);
+        assert_eq!(q(accept, "text/html;level=2"), 400);
+        assert_eq!(q(accept, "text/html;level=3"), 700);
+    }
+
+    #[test]
+    fn test_q_zero_excludes_even_under_wildcard() {
+        assert_eq!(q("*/*, text/html;q=0", "text/html; charset=utf-8"), 0);
+        assert_eq!(q("*/*, text/html;q=0", "text/plain"), 1000);
+        assert_eq!(q("application/*;q=0", "application/json"), 0);
+    }
+
+    #[test]
+    fn test_params_and_charset_matching() {
+        assert_eq!(
+            q("text/html;charset=UTF-8", "text/html; charset=utf-8"),
+            1000
+        );
+        assert_eq!(
+            q("text/html;charset=\"utf-8\"", "text/html;charset=utf-8"),
+            1000
+        );
+        assert_eq!(q("text/html;charset=utf-8", "text/html"), 0);
+        assert_eq!(q("text/html;level=1", "text/html;level=2"), 0);
+    }
+
+    #[test]
+    fn test_is_acceptable() {
+        assert!(is_acceptable(None, "text/html"));
+        assert!(is_acceptable(Some(""), "text/html"));
+        assert!(is_acceptable(Some("application/json"), "not a type"));
+        assert!(is_acceptable(
+            Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+            "application/json"
+        ));
+        assert!(!is_acceptable(
+            Some("application/json"),
+            "text/html; charset=utf-8"
+        ));
+        assert!(is_acceptable(
+            Some("application/json, text/*;q=0.1"),
+            "text/html"
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+//! Canonical percent-encoding for request paths, queries, and patterns.
+//!
+//! Clients spell the same URL differently: `/café`, `/caf%c3%a9`, and
+//! `/caf%C3%A9` all name one resource. Normalizing before pattern matching and
+//! cache-key generation makes them share a cache entry, and the result is
+//! plain ASCII that can be forwarded to the backend as-is.
+//!
+//! The normal form follows RFC 3986 §6.2.2: percent escapes use uppercase hex,
+//! escapes of unreserved characters are decoded, and raw non-ASCII bytes are
+//! escaped. Reserved characters keep their encoded or unencoded spelling,
+//! since `/a%2Fb` and `/a/b` differ. A `%` not followed by two hex digits is
+//! escaped as `%25`.
+
+use std::borrow::Cow;
+
+/// Normalize the percent-encoding of a path, query, or pattern.
+///
+/// Returns the input unchanged (borrowed) when it is already normal.
+pub fn normalize_percent_encoding(input: &str) -> Cow<'_, str> {
+    let bytes = input.as_bytes();
+    if !bytes.iter().any(|&b| b == b'%' || !b.is_ascii()) {
+        return Cow::Borrowed(input);
+    }
+
+    let mut out = String::with_capacity(input.len() + 8);
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte == b'%' {
+            match (bytes.get(i + 1), bytes.get(i + 2)) {
+                (Some(&hi), Some(&lo)) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                    let decoded = hex_value(hi) << 4 | hex_value(lo);
+                    if is_unreserved(decoded) {
+                        out.push(decoded as char);
+                    } else {
+                        push_escaped(&mut out, decoded);
+                    }
+                    i += 3;
+                    continue;
+                }
+                _ => push_escaped(&mut out, b'%'),
+            }
+        } else if byte.is_ascii() {
+            out.push(byte as char);
+        } else {
+            push_escaped(&mut out, byte);
+        }
+        i += 1;
+    }
+
+    if out == input {
+        Cow::Borrowed(input)
+    } else {
+        Cow::Owned(out)
+    }
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+fn push_escaped(out: &mut String, byte: u8) {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    out.push('%');
+    out.push(HEX[usize::from(byte >> 4)] as char);
+    out.push(HEX[usize::from(byte & 0xF)] as char);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spellings_of_one_path_normalize_identically() {
+        for spelling in [
+            "/café",
+            "/caf%c3%a9",
+            "/caf%C3%A9",
+            "/caf%C3%a9",
+            "/%63af%C3%A9",
+        ] {
+            assert_eq!(normalize_percent_encoding(spelling), "/caf%C3%A9");
+        }
+    }
+
+    #[test]
+    fn test_reserved_escapes_are_kept() {
+        assert_eq!(normalize_percent_encoding("/a%2fb"), "/a%2Fb");
+        assert_eq!(normalize_percent_encoding("/a/b"), "/a/b");
+        assert_eq!(normalize_percent_encoding("q=a%26b&c=d"), "q=a%26b&c=d");
+        assert_eq!(normalize_percent_encoding("/%2A"), "/%2A");
+    }
+
+    #[test]
+    fn test_plain_ascii_is_borrowed() {
+        assert!(matches!(
+            normalize_percent_encoding("/api/users?id=1"),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            normalize_percent_encoding("/caf%C3%A9"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_stray_percent_and_mixed_input() {
+        assert_eq!(normalize_percent_encoding("/100%"), "/100%25");
+        assert_eq!(normalize_percent_encoding("/50%zz"), "/50%25zz");
+        assert_eq!(
+            normalize_percent_encoding("/straße/%e6%97%a5本"),
+            "/stra%C3%9Fe/%E6%97%A5%E6%9C%AC"
+        );
+        assert_eq!(normalize_percent_encoding("/café/*"), "/caf%C3%A9/*");
+    }
+}
@@ -0,0 +1,158 @@
+//! Detection of `<meta name="phantom-404" content="true">` in HTML pages.
+//!
+//! Frameworks that render their "not found" page with status 200 can mark it
+//! with this tag; with `use_404_meta` the proxy then treats the page as a
+//! 404. Only the start of the body is scanned, since the tag belongs in the
+//! `<head>`. Tag and attribute names and values are matched without regard
+//! to case, attributes may come in any order, and values may be double-,
+//! single-, or unquoted.
+
+/// Bytes of the body scanned for the tag unless configured otherwise.
+pub const DEFAULT_SCAN_BYTES: usize = 16 * 1024;
+
+/// Whether `content_type` is an HTML media type.
+pub fn is_html(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .is_some_and(|value| {
+            value.eq_ignore_ascii_case("text/html")
+                || value.eq_ignore_ascii_case("application/xhtml+xml")
+        })
+}
+
+/// Whether the first `scan_bytes` bytes of `body` contain the phantom-404
+/// meta tag. A tag cut off by the end of the window does not count.
+pub fn contains_404_meta(body: &[u8], scan_bytes: usize) -> bool {
+    let window = body[..body.len().min(scan_bytes)].to_ascii_lowercase();
+    let mut rest = &window[..];
+    while let Some(start) = find(rest, b"<meta") {
+        rest = &rest[start + b"<meta".len()..];
+        // `<metadata>` and the like are other elements.
+        if !rest
+            .first()
+            .is_some_and(|&b| b.is_ascii_whitespace() || b == b'/')
+        {
+            continue;
+        }
+        let Some((attributes, len)) = attributes(rest) else {
+            return false;
+        };
+        let has = |name: &[u8], value: &[u8]| attributes.contains(&(name, value));
+        if has(b"name", b"phantom-404") && has(b"content", b"true") {
+            return true;
+        }
+        rest = &rest[len..];
+    }
+    false
+}
+
+/// An attribute name and its value, both lowercased.
+type Attribute<'a> = (&'a [u8], &'a [u8]);
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The attributes of a tag whose name ends where `input` starts, and the
+/// length of `input` up to and including the closing `>`. `None` when the
+/// tag is not closed.
+fn attributes(input: &[u8]) -> Option<(Vec<Attribute<'_>>, usize)> {
+    let skip_while = |mut i: usize, pred: &dyn Fn(u8) -> bool| {
+        while i < input.len() && pred(input[i]) {
+            i += 1;
+        }
+        i
+    };
+    let mut attributes = Vec::new();
+    let mut i = 0;
+    loop {
+        i = skip_while(i, &|b| b.is_ascii_whitespace() || b == b'/');
+        match input.get(i) {
+            None => return None,
+            Some(b'>') => return Some((attributes, i + 1)),
+            Some(_) => {}
+        }
+
+        let name_start = i;
+        i = skip_while(i, &|b| {
+            !b.is_ascii_whitespace() && !matches!(b, b'=' | b'>' | b'/')
+        });
+        let name = &input[name_start..i];
+        i = skip_while(i, &|b| b.is_ascii_whitespace());
+        if input.get(i) != Some(&b'=') {
+            attributes.push((name, &[][..]));
+            continue;
+        }
+        i = skip_while(i + 1, &|b| b.is_ascii_whitespace());
+
+        let value = match input.get(i) {
+            Some(&quote @ (b'"' | b'\'')) => {
+                let start = i + 1;
+                let len = input[start..].iter().position(|&b| b == quote)?;
+                i = start + len + 1;
+                &input[start..start + len]
+            }
+            _ => {
+                let start = i;
+                i = skip_while(i, &|b| !b.is_ascii_whitespace() && b != b'>');
+                &input[start..i]
+            }
+        };
+        attributes.push((name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detects(html: &str) -> bool {
+        contains_404_meta(html.as_bytes(), DEFAULT_SCAN_BYTES)
+    }
+
+    #[test]
+    fn test_quoting_order_and_case() {
+        assert!(detects(r#"<meta name="phantom-404" content="true">"#));
+        assert!(detects("<meta name='phantom-404' content='true'>"));
+        assert!(detects("<meta name=phantom-404 content=true>"));
+        assert!(detects(r#"<META CONTENT="True" Name='Phantom-404'/>"#));
+        assert!(detects(
+            "<head>\n<meta charset=\"utf-8\">\n<meta\n  content = \"true\"\n  name=\"phantom-404\" >"
+        ));
+    }
+
+    #[test]
+    fn test_non_matching_markup() {
+        assert!(!detects(r#"<meta name="phantom-404" content="false">"#));
+        // Both attributes must be on the same tag.
+        assert!(!detects(
+            r#"<meta name="phantom-404"><meta name="robots" content="true">"#
+        ));
+        assert!(!detects(r#"<metadata name="phantom-404" content="true">"#));
+        assert!(!detects(r#"<meta name="x" content="name='phantom-404'">"#));
+        assert!(!detects(r#"<p>name="phantom-404" content="true"</p>"#));
+        assert!(!detects(r#"<meta name="phantom-404" content="true""#));
+    }
+
+    #[test]
+    fn test_scan_window() {
+        let tag = r#"<meta name="phantom-404" content="true">"#;
+        let late = format!("{}{}", " ".repeat(100), tag);
+        assert!(contains_404_meta(late.as_bytes(), 100 + tag.len()));
+        // Past the window, or cut off by it.
+        assert!(!contains_404_meta(late.as_bytes(), 100));
+        assert!(!contains_404_meta(late.as_bytes(), 100 + tag.len() - 1));
+    }
+
+    #[test]
+    fn test_is_html() {
+        assert!(is_html(Some("text/html")));
+        assert!(is_html(Some("Text/HTML; charset=utf-8")));
+        assert!(is_html(Some("application/xhtml+xml")));
+        assert!(!is_html(Some("text/plain")));
+        assert!(!is_html(None));
+    }
+}
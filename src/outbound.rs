@@ -0,0 +1,205 @@
+//! The request about to be sent to the backend, as seen by the
+//! `transform_request` hook.
+//!
+//! The hook runs after the cache lookup and right before the request is sent,
+//! so cache keys stay based on what the client asked for. It can change the
+//! method, path and query, and headers. Requests keep going to the backend
+//! the proxy picked: a new path must start with `/`, so it cannot smuggle in
+//! another host, and sending the request elsewhere takes an explicit
+//! [`OutboundRequest::replace_origin`].
+
+use axum::http::{HeaderMap, Method};
+
+/// A backend request that a `transform_request` hook may change.
+#[derive(Debug)]
+pub struct OutboundRequest {
+    method: Method,
+    path_and_query: String,
+    headers: HeaderMap,
+    origin: String,
+    origin_replaced: bool,
+}
+
+impl OutboundRequest {
+    pub(crate) fn new(
+        method: Method,
+        path_and_query: impl Into<String>,
+        headers: HeaderMap,
+        origin: impl Into<String>,
+    ) -> Self {
+        Self {
+            method,
+            path_and_query: path_and_query.into(),
+            headers,
+            origin: origin.into(),
+            origin_replaced: false,
+        }
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn method_mut(&mut self) -> &mut Method {
+        &mut self.method
+    }
+
+    /// Path and query sent to the backend, e.g. `/api/users?page=2`.
+    pub fn path_and_query(&self) -> &str {
+        &self.path_and_query
+    }
+
+    /// Send `path_and_query` to the backend instead. Fails, leaving the
+    /// request unchanged, if it does not start with `/`.
+    pub fn set_path_and_query(
+        &mut self,
+        path_and_query: impl Into<String>,
+    ) -> Result<(), OutboundError> {
+        let path_and_query = path_and_query.into();
+        if !path_and_query.starts_with('/') {
+            return Err(OutboundError::InvalidPath(path_and_query));
+        }
+        self.path_and_query = path_and_query;
+        Ok(())
+    }
+
+    /// Headers sent to the backend, hop-by-hop headers already removed and
+    /// the `upstream_auth` credential already added. Upgrade handshakes keep
+    /// their `Connection` and `Upgrade` headers.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// Origin the request goes to, such as `http://localhost:8080`.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Send the request to `origin` instead of the backend the proxy picked.
+    /// Failures there do not count against that backend. Upgrade requests
+    /// cannot be moved and are answered with `502`.
+    ///
+    /// Fails, leaving the request unchanged, unless `origin` is an `http` or
+    /// `https` URL with a host and nothing after it but an optional base path.
+    pub fn replace_origin(&mut self, origin: &str) -> Result<(), OutboundError> {
+        let valid = reqwest::Url::parse(origin).is_ok_and(|url| {
+            matches!(url.scheme(), "http" | "https")
+                && url.has_host()
+                && url.query().is_none()
+                && url.fragment().is_none()
+        });
+        if !valid {
+            return Err(OutboundError::InvalidOrigin(origin.to_string()));
+        }
+        self.origin = origin.trim_end_matches('/').to_string();
+        self.origin_replaced = true;
+        Ok(())
+    }
+
+    /// Whether the hook called [`OutboundRequest::replace_origin`].
+    pub fn origin_replaced(&self) -> bool {
+        self.origin_replaced
+    }
+
+    /// Full URL the request is sent to.
+    pub fn url(&self) -> String {
+        format!("{}{}", self.origin, self.path_and_query)
+    }
+
+    pub(crate) fn into_parts(self) -> (Method, String, HeaderMap) {
+        (self.method, self.path_and_query, self.headers)
+    }
+}
+
+/// A change an [`OutboundRequest`] refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundError {
+    /// A path and query that does not start with `/`.
+    InvalidPath(String),
+    /// An origin that is not an `http` or `https` URL with a host and no
+    /// query or fragment.
+    InvalidOrigin(String),
+}
+
+impl std::fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPath(path) => write!(f, "outbound path must start with '/': {}", path),
+            Self::InvalidOrigin(origin) => write!(
+                f,
+                "outbound origin must be an http(s) URL without query or fragment: {}",
+                origin
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OutboundError {}
+
+/// Run `hook` on `request`. Returns `false` if it panicked, in which case the
+/// request must not be sent.
+pub(crate) fn call_hook(
+    hook: &(dyn Fn(&mut OutboundRequest) + Send + Sync),
+    request: &mut OutboundRequest,
+) -> bool {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| hook(request))).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> OutboundRequest {
+        OutboundRequest::new(
+            Method::GET,
+            "/page?id=1",
+            HeaderMap::new(),
+            "http://localhost:8080",
+        )
+    }
+
+    #[test]
+    fn test_paths_cannot_leave_the_backend() {
+        let mut outbound = request();
+        outbound.set_path_and_query("/internal/page?id=1").unwrap();
+        assert_eq!(outbound.url(), "http://localhost:8080/internal/page?id=1");
+        assert!(!outbound.origin_replaced());
+
+        for path in ["@evil.example/", ".evil.example/", ""] {
+            let mut outbound = request();
+            assert_eq!(
+                outbound.set_path_and_query(path),
+                Err(OutboundError::InvalidPath(path.to_string()))
+            );
+            assert_eq!(outbound.url(), "http://localhost:8080/page?id=1");
+        }
+    }
+
+    #[test]
+    fn test_replace_origin() {
+        let mut outbound = request();
+        outbound
+            .replace_origin("https://internal.example/base/")
+            .unwrap();
+        assert_eq!(outbound.url(), "https://internal.example/base/page?id=1");
+        assert!(outbound.origin_replaced());
+
+        for origin in [
+            "ftp://internal.example",
+            "internal.example",
+            "http://x/?a=1",
+        ] {
+            let mut outbound = request();
+            assert_eq!(
+                outbound.replace_origin(origin),
+                Err(OutboundError::InvalidOrigin(origin.to_string()))
+            );
+            assert_eq!(outbound.origin(), "http://localhost:8080");
+            assert!(!outbound.origin_replaced());
+        }
+    }
+}
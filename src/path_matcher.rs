@@ -1,8 +1,22 @@
+use crate::normalize::normalize_percent_encoding;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+/// Marks a pattern's path as a regular expression, e.g. `"re:^/products/\d+$"`
+/// or `"GET re:^/blog/.*"`. The regex is matched against the normalized
+/// request path and is not anchored unless it says so.
+pub const REGEX_PREFIX: &str = "re:";
+
 /// Path matching module with wildcard support
 ///
 /// Supports wildcard patterns where * can appear anywhere in the pattern
 /// Example patterns: "/api/*", "/*/users", "/api/*/data"
 /// Also supports method prefixes: "POST /api/*", "GET *", "PUT /hello"
+/// and regexes after [`REGEX_PREFIX`]: "GET re:^/products/\d+$"
+/// The method is only recognized at the very start, so a `?` query section
+/// stays part of path_pattern; see [`split_query`].
 /// Returns (method, path_pattern)
 /// Examples:
 ///   "POST /api/*" -> (Some("POST"), "/api/*")
@@ -29,6 +43,17 @@ fn parse_pattern(pattern: &str) -> (Option<&str>, &str) {
     (None, pattern)
 }
 
+/// Split a wildcard path pattern at its first `?` into the part matched
+/// against the path and the part matched against the query string, e.g.
+/// `"/search?*preview=true*"`. Without a `?`, any query matches. `re:`
+/// patterns are not split: `?` is part of the regex there.
+fn split_query(path_pattern: &str) -> (&str, Option<&str>) {
+    match path_pattern.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_pattern, None),
+    }
+}
+
 /// Check if a path matches a wildcard pattern
 /// * can appear anywhere and matches any sequence of characters
 ///   If method is provided, pattern can optionally specify a method prefix like "POST /api/*"
@@ -43,69 +68,386 @@ pub fn matches_pattern(path: &str, pattern: &str) -> bool {
 ///   matches_pattern_with_method(Some("GET"), "/api/users", "POST /api/*") -> false
 ///   matches_pattern_with_method(Some("GET"), "/api/users", "/api/*") -> true (no method constraint)
 pub fn matches_pattern_with_method(method: Option<&str>, path: &str, pattern: &str) -> bool {
+    matches_request(method, path, "", pattern)
+}
+
+/// Like [`matches_pattern_with_method`], also matching a pattern's `?`
+/// section against the request's `query` (empty without one):
+///   matches_request(Some("GET"), "/search", "q=a&preview=true", "/search?*preview=true*") -> true
+///   matches_request(Some("GET"), "/search", "", "/search?") -> true (only without a query)
+pub fn matches_request(method: Option<&str>, path: &str, query: &str, pattern: &str) -> bool {
     let (pattern_method, path_pattern) = parse_pattern(pattern);
+    if !method_matches(pattern_method, method) {
+        return false;
+    }
+    if let Some(source) = path_pattern.strip_prefix(REGEX_PREFIX) {
+        return cached_regex(source).is_some_and(|regex| regex.is_match(path));
+    }
+    // Patterns may be written with raw UTF-8 or any escape spelling;
+    // request paths and queries arrive normalized.
+    let (path_pattern, query_pattern) = split_query(path_pattern);
+    Wildcard::new(&normalize_percent_encoding(path_pattern)).matches(path)
+        && query_pattern.is_none_or(|pattern| {
+            Wildcard::new(&normalize_percent_encoding(pattern)).matches(query)
+        })
+}
 
-    // If pattern specifies a method, it must match
-    if let Some(required_method) = pattern_method {
-        if let Some(actual_method) = method {
-            if required_method != actual_method {
-                return false;
-            }
-        } else {
-            // Pattern requires a method but none was provided
-            return false;
-        }
+/// The compiled regex for `source`, built on first use and kept for the
+/// patterns that are matched as strings on every request. Invalid regexes
+/// are remembered too, as `None`, and never match.
+fn cached_regex(source: &str) -> Option<Regex> {
+    static CACHE: OnceLock<RwLock<HashMap<String, Option<Regex>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(regex) = cache.read().unwrap().get(source) {
+        return regex.clone();
     }
+    let regex = Regex::new(source).ok();
+    cache
+        .write()
+        .unwrap()
+        .insert(source.to_string(), regex.clone());
+    regex
+}
 
-    // Now match the path part using the existing logic
-    matches_path_pattern(path, path_pattern)
+/// If the pattern specifies a method, the request must have that method.
+fn method_matches(required: Option<&str>, actual: Option<&str>) -> bool {
+    match required {
+        Some(required) => actual == Some(required),
+        None => true,
+    }
 }
 
-/// Internal function to match just the path against a pattern
-fn matches_path_pattern(path: &str, pattern: &str) -> bool {
-    // Split pattern by * to get segments
-    let segments: Vec<&str> = pattern.split('*').collect();
+/// What `*` in a wildcard pattern may match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WildcardSyntax {
+    /// `*` matches any sequence of characters, `/` included, so `/api/*`
+    /// also covers `/api/users/123/orders`.
+    #[default]
+    Legacy,
+    /// Glob style: `*` matches within one path segment and `**` across
+    /// segments. `/api/*` covers `/api/users` but not `/api/users/123`,
+    /// `/api/**` covers both, and `/api/**/orders` also covers `/api/orders`.
+    Segments,
+}
 
-    if segments.len() == 1 {
-        // No wildcards, exact match
-        return path == pattern;
+/// A wildcard pattern compiled once, for matching many paths or cache keys.
+/// How `*` treats `/` depends on the [`WildcardSyntax`] it was built with.
+#[derive(Clone, Debug)]
+pub struct Wildcard(Glob);
+
+#[derive(Clone, Debug)]
+enum Glob {
+    /// [`WildcardSyntax::Legacy`], split into its literal segments.
+    Legacy {
+        /// The text before the first `*`, or the whole pattern without any.
+        prefix: String,
+        /// The non-empty segments between the first and last `*`.
+        middle: Vec<String>,
+        /// The text after the last `*`; `None` without any `*`.
+        suffix: Option<String>,
+    },
+    /// [`WildcardSyntax::Segments`], as an anchored regex.
+    Segments(Regex),
+}
+
+impl Wildcard {
+    /// Compile `pattern` with [`WildcardSyntax::Legacy`].
+    pub fn new(pattern: &str) -> Self {
+        Self::with_syntax(pattern, WildcardSyntax::Legacy)
     }
 
-    let mut current_pos = 0;
+    pub fn with_syntax(pattern: &str, syntax: WildcardSyntax) -> Self {
+        match syntax {
+            WildcardSyntax::Legacy => Self(legacy_glob(pattern)),
+            WildcardSyntax::Segments => Self(Glob::Segments(
+                Regex::new(&segments_regex(pattern)).expect("literal text is escaped"),
+            )),
+        }
+    }
 
-    for (i, segment) in segments.iter().enumerate() {
-        if i == 0 {
-            // First segment must match at the start
-            if !segment.is_empty() && !path.starts_with(segment) {
-                return false;
+    /// Does not allocate: it runs for every pattern on every request.
+    pub fn matches(&self, text: &str) -> bool {
+        let (prefix, middle, suffix) = match &self.0 {
+            Glob::Legacy {
+                prefix,
+                middle,
+                suffix,
+            } => (prefix, middle, suffix),
+            Glob::Segments(regex) => return regex.is_match(text),
+        };
+        let Some(suffix) = suffix else {
+            return text == prefix;
+        };
+
+        // First segment must match at the start, last segment at the end,
+        // without overlapping
+        if text.len() < prefix.len() + suffix.len()
+            || !text.starts_with(prefix.as_str())
+            || !text.ends_with(suffix.as_str())
+        {
+            return false;
+        }
+
+        // Middle segments must appear in order in what is left
+        let mut remaining = &text[prefix.len()..text.len() - suffix.len()];
+        for segment in middle {
+            match remaining.find(segment.as_str()) {
+                Some(pos) => remaining = &remaining[pos + segment.len()..],
+                None => return false,
             }
-            current_pos = segment.len();
-        } else if i == segments.len() - 1 {
-            // Last segment must match at the end
-            if !segment.is_empty() && !path.ends_with(segment) {
-                return false;
+        }
+
+        true
+    }
+}
+
+fn legacy_glob(pattern: &str) -> Glob {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return Glob::Legacy {
+            prefix: pattern.to_string(),
+            middle: vec![],
+            suffix: None,
+        };
+    };
+    let (middle, suffix) = rest.rsplit_once('*').unwrap_or(("", rest));
+    Glob::Legacy {
+        prefix: prefix.to_string(),
+        middle: middle
+            .split('*')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect(),
+        suffix: Some(suffix.to_string()),
+    }
+}
+
+/// The anchored regex for a [`WildcardSyntax::Segments`] pattern. Runs of
+/// two or more `*` count as `**`; a `/**/` may also stand for a single `/`.
+fn segments_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut rest = pattern;
+    while let Some(star) = rest.find('*') {
+        regex.push_str(&regex::escape(&rest[..star]));
+        let stars = rest[star..].len() - rest[star..].trim_start_matches('*').len();
+        let after = &rest[star + stars..];
+        rest = match stars {
+            1 => {
+                regex.push_str("[^/]*");
+                after
             }
-            // Also ensure that the last segment appears after current_pos
-            if !segment.is_empty() {
-                if let Some(pos) = path[current_pos..].find(segment) {
-                    if current_pos + pos + segment.len() != path.len() {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
+            _ if rest[..star].ends_with('/') && after.starts_with('/') => {
+                regex.push_str("(?:.*/)?");
+                &after[1..]
             }
-        } else {
-            // Middle segments must appear in order
-            if let Some(pos) = path[current_pos..].find(segment) {
-                current_pos += pos + segment.len();
-            } else {
-                return false;
+            _ => {
+                regex.push_str(".*");
+                after
             }
+        };
+    }
+    regex.push_str(&regex::escape(rest));
+    regex.push('$');
+    regex
+}
+
+#[derive(Clone, Debug)]
+enum PathMatcher {
+    /// A normalized wildcard pattern.
+    Wildcard(Wildcard),
+    Regex(Regex),
+    /// A regex that does not compile; matches nothing.
+    Invalid,
+}
+
+/// A pattern parsed and normalized once, for matching many requests.
+///
+/// Same syntax and semantics as [`matches_pattern_with_method`].
+#[derive(Clone, Debug)]
+pub struct PathPattern {
+    method: Option<String>,
+    path: PathMatcher,
+    /// The `?` section, matched against the query string.
+    query: Option<Wildcard>,
+    /// The pattern as written.
+    source: String,
+}
+
+impl PathPattern {
+    /// Parse `pattern` with [`WildcardSyntax::Legacy`]. A `re:` path that is
+    /// not a valid regex matches nothing; [`validate_pattern`] reports it.
+    pub fn new(pattern: &str) -> Self {
+        Self::with_syntax(pattern, WildcardSyntax::Legacy)
+    }
+
+    pub fn with_syntax(pattern: &str, syntax: WildcardSyntax) -> Self {
+        let (method, path) = parse_pattern(pattern);
+        let compile =
+            |pattern: &str| Wildcard::with_syntax(&normalize_percent_encoding(pattern), syntax);
+        let (path, query) = match path.strip_prefix(REGEX_PREFIX) {
+            Some(source) => (
+                Regex::new(source).map_or(PathMatcher::Invalid, PathMatcher::Regex),
+                None,
+            ),
+            None => {
+                let (path, query) = split_query(path);
+                (PathMatcher::Wildcard(compile(path)), query.map(compile))
+            }
+        };
+        Self {
+            method: method.map(str::to_string),
+            path,
+            query,
+            source: pattern.to_string(),
         }
     }
 
-    true
+    /// The pattern as written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the request matches, as one without a query string.
+    pub fn matches(&self, method: Option<&str>, path: &str) -> bool {
+        self.matches_request(method, path, "")
+    }
+
+    /// Whether the request matches; `query` is empty without one.
+    pub fn matches_request(&self, method: Option<&str>, path: &str, query: &str) -> bool {
+        method_matches(self.method.as_deref(), method)
+            && match &self.path {
+                PathMatcher::Wildcard(pattern) => pattern.matches(path),
+                PathMatcher::Regex(regex) => regex.is_match(path),
+                PathMatcher::Invalid => false,
+            }
+            && self
+                .query
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(query))
+    }
+}
+
+/// A list of [`PathPattern`]s parsed once, such as `render_queue_patterns`.
+#[derive(Clone, Debug, Default)]
+pub struct PatternList(Vec<PathPattern>);
+
+impl PatternList {
+    pub fn new(patterns: &[String], syntax: WildcardSyntax) -> Self {
+        Self(
+            patterns
+                .iter()
+                .map(|pattern| PathPattern::with_syntax(pattern, syntax))
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any pattern matches the request.
+    pub fn matches(&self, method: &str, path: &str, query: &str) -> bool {
+        self.0
+            .iter()
+            .any(|pattern| pattern.matches_request(Some(method), path, query))
+    }
+}
+
+/// Why a `re:` pattern's regex does not compile, if it does not.
+pub(crate) fn regex_error(pattern: &str) -> Option<String> {
+    let (_, path) = parse_pattern(pattern);
+    let source = path.strip_prefix(REGEX_PREFIX)?;
+    Regex::new(source)
+        .err()
+        .map(|e| format!("invalid regex: {}", e))
+}
+
+/// Why `pattern` can never match a request, if it cannot: it is blank, its
+/// path neither starts with `/` nor is a wildcard, it has an unknown
+/// method prefix (which leaves a space in the path), or its `re:` regex
+/// does not compile.
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    let (_, path) = parse_pattern(pattern);
+    if path.is_empty() {
+        return Err("pattern is empty".to_string());
+    }
+    if path.starts_with(REGEX_PREFIX) {
+        return regex_error(pattern).map_or(Ok(()), Err);
+    }
+    if path.contains(char::is_whitespace) {
+        return Err("unknown method or whitespace in path".to_string());
+    }
+    if !path.starts_with('/') && !path.starts_with('*') {
+        return Err("path must start with '/' or '*'".to_string());
+    }
+    Ok(())
+}
+
+/// Marks an entry of `path_rules` as keeping matching requests out of the
+/// cache, e.g. `"!/api/internal/*"` or `"!POST *"`.
+pub const NEGATION_PREFIX: char = '!';
+
+/// Split a `path_rules` entry into whether it caches and its pattern.
+fn parse_rule(rule: &str) -> (bool, &str) {
+    let rule = rule.trim();
+    match rule.strip_prefix(NEGATION_PREFIX) {
+        Some(pattern) => (false, pattern.trim_start()),
+        None => (true, rule),
+    }
+}
+
+/// [`validate_pattern`] for a `path_rules` entry, which may start with
+/// [`NEGATION_PREFIX`].
+pub fn validate_rule(rule: &str) -> Result<(), String> {
+    validate_pattern(parse_rule(rule).1)
+}
+
+/// Why a `path_rules` entry's regex does not compile, if it does not.
+pub(crate) fn rule_regex_error(rule: &str) -> Option<String> {
+    regex_error(parse_rule(rule).1)
+}
+
+/// `include_paths` / `exclude_paths` and `path_rules`, as written.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathPatterns {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Ordered rules replacing `include` and `exclude`; see
+    /// [`should_cache_path_ordered`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+}
+
+impl PathPatterns {
+    /// Every entry [`validate_pattern`] or [`validate_rule`] rejects, as
+    /// `include[1] "api/*": reason`, and `rules` set together with either
+    /// list.
+    pub fn errors(&self) -> Vec<String> {
+        let list = |name: &'static str, patterns: &'_ [String]| {
+            patterns
+                .iter()
+                .enumerate()
+                .filter_map(move |(index, pattern)| {
+                    validate_pattern(pattern)
+                        .err()
+                        .map(|reason| format!("{}[{}] {:?}: {}", name, index, pattern, reason))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut errors = list("include", &self.include);
+        errors.extend(list("exclude", &self.exclude));
+        errors.extend(self.rules.iter().enumerate().filter_map(|(index, rule)| {
+            validate_rule(rule)
+                .err()
+                .map(|reason| format!("rules[{}] {:?}: {}", index, rule, reason))
+        }));
+        if !self.rules.is_empty() && (!self.include.is_empty() || !self.exclude.is_empty()) {
+            errors.push("rules cannot be combined with include or exclude".to_string());
+        }
+        errors
+    }
 }
 
 /// Check if a request should be cached based on include and exclude patterns
@@ -113,16 +455,22 @@ fn matches_path_pattern(path: &str, pattern: &str) -> bool {
 /// - If exclude_paths is empty, no paths are excluded
 /// - exclude_paths overrides include_paths
 /// - Patterns can include method prefixes: "POST /api/*", "GET *", etc.
+/// - Patterns with a `?` section also match `query` (empty without one):
+///   "/search?*preview=true*"
+///
+/// Every pattern is parsed again on each call; build a [`PathFilter`] once to
+/// check many requests against the same lists.
 pub fn should_cache_path(
     method: &str,
     path: &str,
+    query: &str,
     include_paths: &[String],
     exclude_paths: &[String],
 ) -> bool {
     // Check exclude patterns first (they override includes)
     if !exclude_paths.is_empty() {
         for pattern in exclude_paths {
-            if matches_pattern_with_method(Some(method), path, pattern) {
+            if matches_request(Some(method), path, query, pattern) {
                 return false;
             }
         }
@@ -135,7 +483,7 @@ pub fn should_cache_path(
 
     // Check if path matches any include pattern
     for pattern in include_paths {
-        if matches_pattern_with_method(Some(method), path, pattern) {
+        if matches_request(Some(method), path, query, pattern) {
             return true;
         }
     }
@@ -143,6 +491,123 @@ pub fn should_cache_path(
     false
 }
 
+/// Check a request against `path_rules`: each entry is a pattern that
+/// caches what it matches, or with a leading `!` keeps it out of the cache.
+/// Rules are read top to bottom and the last one matching decides, so a
+/// later rule can re-include part of what an earlier one excluded. With
+/// `["/api/*", "!/api/internal/*", "/api/internal/public/*"]`, `/api/users`
+/// and `/api/internal/public/docs` are cached but `/api/internal/stats` is
+/// not.
+///
+/// A request no rule matches is not cached; start the list with `"*"` to
+/// cache everything not excluded later.
+pub fn should_cache_path_ordered(method: &str, path: &str, query: &str, rules: &[String]) -> bool {
+    rules
+        .iter()
+        .rev()
+        .map(|rule| parse_rule(rule))
+        .find(|(_, pattern)| matches_request(Some(method), path, query, pattern))
+        .is_some_and(|(cache, _)| cache)
+}
+
+/// Precompiled include and exclude lists, with the semantics of
+/// [`should_cache_path`], or ordered rules with those of
+/// [`should_cache_path_ordered`].
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    include: Vec<PathPattern>,
+    exclude: Vec<PathPattern>,
+    /// `path_rules`, each with whether it caches; when non-empty, they
+    /// decide and `include` and `exclude` are empty.
+    rules: Vec<(bool, PathPattern)>,
+}
+
+impl PathFilter {
+    pub fn new(include_paths: &[String], exclude_paths: &[String]) -> Self {
+        Self::with_syntax(include_paths, exclude_paths, WildcardSyntax::Legacy)
+    }
+
+    pub fn with_syntax(
+        include_paths: &[String],
+        exclude_paths: &[String],
+        syntax: WildcardSyntax,
+    ) -> Self {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| PathPattern::with_syntax(p, syntax))
+                .collect()
+        };
+        Self {
+            include: compile(include_paths),
+            exclude: compile(exclude_paths),
+            rules: vec![],
+        }
+    }
+
+    /// A filter for ordered `path_rules`.
+    pub fn ordered(rules: &[String], syntax: WildcardSyntax) -> Self {
+        Self {
+            rules: rules
+                .iter()
+                .map(|rule| {
+                    let (cache, pattern) = parse_rule(rule);
+                    (cache, PathPattern::with_syntax(pattern, syntax))
+                })
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// The filter for `patterns`: ordered when it has `rules`.
+    pub fn from_patterns(patterns: &PathPatterns, syntax: WildcardSyntax) -> Self {
+        if patterns.rules.is_empty() {
+            Self::with_syntax(&patterns.include, &patterns.exclude, syntax)
+        } else {
+            Self::ordered(&patterns.rules, syntax)
+        }
+    }
+
+    pub fn should_cache(&self, method: &str, path: &str, query: &str) -> bool {
+        let matches = |pattern: &PathPattern| pattern.matches_request(Some(method), path, query);
+        if !self.rules.is_empty() {
+            return self
+                .rules
+                .iter()
+                .rev()
+                .find(|(_, pattern)| matches(pattern))
+                .is_some_and(|(cache, _)| *cache);
+        }
+        !self.exclude.iter().any(matches)
+            && (self.include.is_empty() || self.include.iter().any(matches))
+    }
+
+    /// The patterns this filter was built from.
+    pub fn patterns(&self) -> PathPatterns {
+        let sources = |patterns: &[PathPattern]| {
+            patterns
+                .iter()
+                .map(|pattern| pattern.as_str().to_string())
+                .collect()
+        };
+        PathPatterns {
+            include: sources(&self.include),
+            exclude: sources(&self.exclude),
+            rules: self
+                .rules
+                .iter()
+                .map(|(cache, pattern)| {
+                    if *cache {
+                        pattern.as_str().to_string()
+                    } else {
+                        format!("{}{}", NEGATION_PREFIX, pattern.as_str())
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,17 +646,157 @@ mod tests {
         assert!(!matches_pattern("/api/v1/posts/123", "/api/*/users/*"));
     }
 
+    #[test]
+    fn test_wildcard_suffix_repeated_in_path() {
+        assert!(matches_pattern("/users/users", "*/users"));
+        assert!(matches_pattern("/api/a/users/b/users", "/api/*/users"));
+        assert!(!matches_pattern("/api", "/api*/"));
+    }
+
     #[test]
     fn test_wildcard_only() {
         assert!(matches_pattern("/anything", "*"));
         assert!(matches_pattern("/api/users/123", "*"));
     }
 
+    #[test]
+    fn test_segment_wildcards_stop_at_slashes() {
+        let matches = |text: &str, pattern: &str| {
+            Wildcard::with_syntax(pattern, WildcardSyntax::Segments).matches(text)
+        };
+        assert!(matches("/api/users", "/api/*"));
+        assert!(matches("/api/", "/api/*"));
+        assert!(!matches("/api/users/123/orders/456", "/api/*"));
+        assert!(!matches("/api", "/api/*"));
+
+        assert!(matches("/api/users/123/orders/456", "/api/**"));
+        assert!(matches("/api/users", "/api/**"));
+        assert!(!matches("/apiv2/users", "/api/**"));
+
+        assert!(matches("/api/v1/users", "/api/*/users"));
+        assert!(!matches("/api/v1/beta/users", "/api/*/users"));
+        assert!(matches("/api/v1/beta/users", "/api/**/users"));
+        assert!(matches("/api/users", "/api/**/users"));
+        assert!(!matches("/api/allusers", "/api/**/users"));
+
+        assert!(matches("/assets/app.min.js", "/assets/*.js"));
+        assert!(!matches("/assets/js/app.js", "/assets/*.js"));
+        assert!(matches("/a.b/(x)+", "/a.b/(x)+"));
+        assert!(!matches("/aXb/(x)+", "/a.b/(x)+"));
+
+        // Cache keys: `*` stops at the first `/` of the path, not at `:`.
+        assert!(matches("GET:/api/users", "GET:/api/*"));
+        assert!(matches("GET:/api/users?page=2", "GET:/api/*"));
+        assert!(!matches("GET:/api/users/1", "GET:/api/*"));
+        assert!(matches("POST:/api/users/1", "*:/api/**"));
+    }
+
+    #[test]
+    fn test_path_filter_with_segment_syntax() {
+        let include = vec!["GET /api/*".to_string(), "/docs/**".to_string()];
+        let exclude = vec!["/api/*/private".to_string()];
+        let legacy = PathFilter::new(&include, &exclude);
+        let segments = PathFilter::with_syntax(&include, &exclude, WildcardSyntax::Segments);
+
+        assert!(legacy.should_cache("GET", "/api/users/123", ""));
+        assert!(!segments.should_cache("GET", "/api/users/123", ""));
+        assert!(segments.should_cache("GET", "/api/users", ""));
+        assert!(!segments.should_cache("POST", "/api/users", ""));
+        assert!(segments.should_cache("GET", "/docs/guide/intro", ""));
+
+        assert!(!legacy.should_cache("GET", "/api/a/b/private", ""));
+        let exclude_segments = PathFilter::with_syntax(&[], &exclude, WildcardSyntax::Segments);
+        assert!(!exclude_segments.should_cache("GET", "/api/a/private", ""));
+        assert!(exclude_segments.should_cache("GET", "/api/a/b/private", ""));
+    }
+
+    /// The uncompiled matcher cache purges used before [`Wildcard`]: split on
+    /// every call, kept to show the compiled one agrees with it.
+    fn split_on_every_call(key: &str, pattern: &str) -> bool {
+        if key == pattern {
+            return true;
+        }
+        let parts: Vec<&str> = pattern.split('*').collect();
+        if parts.len() == 1 {
+            return false;
+        }
+        let mut current_pos = 0;
+        for (i, part) in parts.iter().enumerate() {
+            if part.is_empty() {
+                continue;
+            }
+            if i == 0 {
+                if !key.starts_with(part) {
+                    return false;
+                }
+                current_pos = part.len();
+            } else if i == parts.len() - 1 {
+                if !key[current_pos..].ends_with(part) {
+                    return false;
+                }
+            } else if let Some(pos) = key[current_pos..].find(part) {
+                current_pos += pos + part.len();
+            } else {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn test_compiled_wildcard_agrees_with_uncompiled_matching() {
+        let patterns = [
+            "/api/users",
+            "/api/*",
+            "*/users",
+            "/api/*/users",
+            "/api/*/users/*",
+            "/api*/",
+            "*",
+            "**",
+            "*a*a*",
+            "GET:/api/*",
+            "GET:*/users/*",
+            "",
+        ];
+        let texts = [
+            "/api/users",
+            "/api/posts",
+            "/apiv2/users",
+            "/api/users/123",
+            "/v1/api/users",
+            "/users/users",
+            "/api/a/users/b/users",
+            "/api",
+            "/",
+            "a",
+            "aa",
+            "GET:/api/users",
+            "POST:/v2/admin/users/456",
+            "",
+        ];
+        for pattern in patterns {
+            let wildcard = Wildcard::new(pattern);
+            for text in texts {
+                assert_eq!(
+                    wildcard.matches(text),
+                    split_on_every_call(text, pattern),
+                    "{text:?} against {pattern:?}"
+                );
+                assert_eq!(
+                    PathPattern::new(pattern).matches(None, text),
+                    matches_pattern(text, pattern),
+                    "{text:?} against {pattern:?}"
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_should_cache_path_empty_filters() {
         // Empty include and exclude should cache everything
-        assert!(should_cache_path("GET", "/api/users", &[], &[]));
-        assert!(should_cache_path("POST", "/anything", &[], &[]));
+        assert!(should_cache_path("GET", "/api/users", "", &[], &[]));
+        assert!(should_cache_path("POST", "/anything", "", &[], &[]));
     }
 
     #[test]
@@ -199,16 +804,24 @@ mod tests {
         let include = vec!["/api/*".to_string(), "/public/*".to_string()];
         let exclude = vec![];
 
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         assert!(should_cache_path(
             "GET",
             "/public/index.html",
+            "",
             &include,
             &exclude
         ));
         assert!(!should_cache_path(
             "GET",
             "/private/data",
+            "",
             &include,
             &exclude
         ));
@@ -219,16 +832,24 @@ mod tests {
         let include = vec![];
         let exclude = vec!["/admin/*".to_string(), "/private/*".to_string()];
 
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         assert!(!should_cache_path(
             "GET",
             "/admin/dashboard",
+            "",
             &include,
             &exclude
         ));
         assert!(!should_cache_path(
             "GET",
             "/private/data",
+            "",
             &include,
             &exclude
         ));
@@ -239,10 +860,17 @@ mod tests {
         let include = vec!["/api/*".to_string()];
         let exclude = vec!["/api/admin/*".to_string()];
 
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         assert!(!should_cache_path(
             "GET",
             "/api/admin/users",
+            "",
             &include,
             &exclude
         ));
@@ -320,15 +948,34 @@ mod tests {
         let exclude = vec!["POST /api/*".to_string(), "PUT /api/*".to_string()];
 
         // GET should be cached
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         // POST should not be cached (excluded)
-        assert!(!should_cache_path("POST", "/api/users", &include, &exclude));
+        assert!(!should_cache_path(
+            "POST",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         // PUT should not be cached (excluded)
-        assert!(!should_cache_path("PUT", "/api/users", &include, &exclude));
+        assert!(!should_cache_path(
+            "PUT",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
         // DELETE should be cached (not excluded)
         assert!(should_cache_path(
             "DELETE",
             "/api/users",
+            "",
             &include,
             &exclude
         ));
@@ -340,12 +987,104 @@ mod tests {
         let exclude = vec!["POST *".to_string()];
 
         // All POST requests should be excluded
-        assert!(!should_cache_path("POST", "/api/users", &include, &exclude));
-        assert!(!should_cache_path("POST", "/anything", &include, &exclude));
+        assert!(!should_cache_path(
+            "POST",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+        assert!(!should_cache_path(
+            "POST",
+            "/anything",
+            "",
+            &include,
+            &exclude
+        ));
 
         // Other methods should be cached
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
-        assert!(should_cache_path("PUT", "/api/users", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+        assert!(should_cache_path(
+            "PUT",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+    }
+
+    #[test]
+    fn test_path_filter_agrees_with_should_cache_path() {
+        let include = vec!["GET /api/*".to_string(), "/caf%C3%A9/*".to_string()];
+        let exclude = vec!["/api/*/private".to_string()];
+        let filter = PathFilter::new(&include, &exclude);
+
+        for (method, path) in [
+            ("GET", "/api/users"),
+            ("POST", "/api/users"),
+            ("GET", "/api/users/private"),
+            ("GET", "/café/menu"),
+            ("GET", "/other"),
+        ] {
+            assert_eq!(
+                filter.should_cache(method, path, ""),
+                should_cache_path(method, path, "", &include, &exclude),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_should_cache_path_with_regex_and_wildcard_patterns() {
+        let include = vec![
+            r"re:^/products/\d+$".to_string(),
+            "GET re:^/blog/.*".to_string(),
+            "/static/*".to_string(),
+        ];
+        let exclude = vec!["re:/drafts/".to_string(), "*.map".to_string()];
+        let filter = PathFilter::new(&include, &exclude);
+
+        for (method, path, expected) in [
+            ("GET", "/products/42", true),
+            ("GET", "/products/new", false),
+            ("GET", "/products/42/reviews", false),
+            ("GET", "/blog/hello", true),
+            ("POST", "/blog/hello", false),
+            ("GET", "/blog/drafts/next", false),
+            ("GET", "/static/app.js", true),
+            ("GET", "/static/app.js.map", false),
+            ("GET", "/about", false),
+        ] {
+            assert_eq!(
+                should_cache_path(method, path, "", &include, &exclude),
+                expected,
+                "{} {}",
+                method,
+                path
+            );
+            assert_eq!(
+                filter.should_cache(method, path, ""),
+                expected,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected_and_never_matches() {
+        assert_eq!(validate_pattern(r"GET re:^/a/\d+$"), Ok(()));
+        let err = validate_pattern("re:^/products/(").unwrap_err();
+        assert!(err.starts_with("invalid regex:"), "{err}");
+        assert!(!matches_pattern("/products/(", "re:^/products/("));
+        assert!(!PathPattern::new("re:^/products/(").matches(None, "/products/("));
     }
 
     #[test]
@@ -354,11 +1093,194 @@ mod tests {
         let exclude = vec![];
 
         // Only GET requests should be included
-        assert!(should_cache_path("GET", "/api/users", &include, &exclude));
-        assert!(should_cache_path("GET", "/anything", &include, &exclude));
+        assert!(should_cache_path(
+            "GET",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+        assert!(should_cache_path(
+            "GET",
+            "/anything",
+            "",
+            &include,
+            &exclude
+        ));
 
         // Other methods should not be cached
-        assert!(!should_cache_path("POST", "/api/users", &include, &exclude));
-        assert!(!should_cache_path("PUT", "/api/users", &include, &exclude));
+        assert!(!should_cache_path(
+            "POST",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+        assert!(!should_cache_path(
+            "PUT",
+            "/api/users",
+            "",
+            &include,
+            &exclude
+        ));
+    }
+
+    #[test]
+    fn test_ordered_rules_last_match_wins() {
+        let rules: Vec<String> = [
+            "/api/*",
+            "!/api/internal/*",
+            "/api/internal/public/*",
+            "!POST *",
+            "! /api/internal/public/secret",
+        ]
+        .map(String::from)
+        .to_vec();
+        let filter = PathFilter::ordered(&rules, WildcardSyntax::Legacy);
+
+        for (method, path, expected) in [
+            ("GET", "/api/users", true),
+            ("GET", "/api/internal/stats", false),
+            ("GET", "/api/internal/public/docs", true),
+            ("GET", "/api/internal/public/secret", false),
+            ("POST", "/api/users", false),
+            ("POST", "/api/internal/public/docs", false),
+            // Matched by no rule.
+            ("GET", "/about", false),
+        ] {
+            assert_eq!(
+                should_cache_path_ordered(method, path, "", &rules),
+                expected,
+                "{method} {path}"
+            );
+            assert_eq!(
+                filter.should_cache(method, path, ""),
+                expected,
+                "{method} {path}"
+            );
+        }
+
+        let everything_but_admin = ["*".to_string(), "!/admin/*".to_string()];
+        assert!(should_cache_path_ordered(
+            "GET",
+            "/about",
+            "",
+            &everything_but_admin
+        ));
+        assert!(!should_cache_path_ordered(
+            "GET",
+            "/admin/users",
+            "",
+            &everything_but_admin
+        ));
+        assert!(!should_cache_path_ordered("GET", "/about", "", &[]));
+
+        let patterns = PathPatterns {
+            rules: rules.clone(),
+            ..PathPatterns::default()
+        };
+        assert_eq!(
+            PathFilter::from_patterns(&patterns, WildcardSyntax::Legacy)
+                .patterns()
+                .rules[4],
+            "!/api/internal/public/secret"
+        );
+    }
+
+    #[test]
+    fn test_query_sections_match_the_query_string() {
+        let preview = "/search?*preview=true*";
+        assert!(matches_request(
+            Some("GET"),
+            "/search",
+            "q=a&preview=true",
+            preview
+        ));
+        assert!(!matches_request(Some("GET"), "/search", "q=a", preview));
+        assert!(!matches_request(
+            Some("GET"),
+            "/other",
+            "preview=true",
+            preview
+        ));
+        // Without a `?` section the query does not matter; with an empty
+        // one, only requests without a query match.
+        assert!(matches_request(None, "/search", "page=2", "/search"));
+        assert!(matches_request(None, "/search", "", "/search?"));
+        assert!(!matches_request(None, "/search", "page=2", "/search?"));
+        // The method prefix is read before the query section.
+        assert!(matches_request(
+            Some("POST"),
+            "/a",
+            "x=GET b",
+            "POST /a?x=GET*"
+        ));
+        assert!(!matches_request(
+            Some("GET"),
+            "/a",
+            "x=GET b",
+            "POST /a?x=GET*"
+        ));
+        // `?` in a regex is a quantifier, not a query section.
+        assert!(matches_request(None, "/product", "", "re:^/products?$"));
+        // Queries are matched in their normalized spelling.
+        assert!(matches_request(None, "/s", "q=caf%C3%A9", "/s?q=café"));
+
+        let pattern = PathPattern::with_syntax("GET /search?*page=*", WildcardSyntax::Segments);
+        assert!(pattern.matches_request(Some("GET"), "/search", "q=a&page=2"));
+        assert!(!pattern.matches_request(Some("GET"), "/search", "q=a"));
+        assert!(!pattern.matches(Some("GET"), "/search"));
+
+        let exclude = vec!["/search?*preview=true*".to_string()];
+        let filter = PathFilter::new(&[], &exclude);
+        for (query, expected) in [("q=a", true), ("q=a&preview=true", false), ("", true)] {
+            assert_eq!(
+                should_cache_path("GET", "/search", query, &[], &exclude),
+                expected
+            );
+            assert_eq!(filter.should_cache("GET", "/search", query), expected);
+        }
+    }
+
+    #[test]
+    fn test_rule_errors_and_conflicts() {
+        assert_eq!(validate_rule("!GET /api/*"), Ok(()));
+        assert_eq!(validate_rule(r"!re:^/a/\d+$"), Ok(()));
+        let patterns = PathPatterns {
+            include: vec!["/ok".to_string()],
+            exclude: vec![],
+            rules: vec!["/api/*".to_string(), "!api/*".to_string(), "!".to_string()],
+        };
+        assert_eq!(
+            patterns.errors(),
+            [
+                "rules[1] \"!api/*\": path must start with '/' or '*'",
+                "rules[2] \"!\": pattern is empty",
+                "rules cannot be combined with include or exclude",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pattern_errors_name_each_bad_entry() {
+        for pattern in ["/api/*", "*", "GET *", "POST /graphql", "*.css"] {
+            assert_eq!(validate_pattern(pattern), Ok(()), "{pattern}");
+        }
+        let patterns = PathPatterns {
+            include: vec!["/ok".to_string(), "api/*".to_string()],
+            exclude: vec!["FETCH /x".to_string(), " ".to_string()],
+            rules: vec![],
+        };
+        assert_eq!(
+            patterns.errors(),
+            [
+                "include[1] \"api/*\": path must start with '/' or '*'",
+                "exclude[0] \"FETCH /x\": unknown method or whitespace in path",
+                "exclude[1] \" \": pattern is empty",
+            ]
+        );
+
+        let filter = PathFilter::new(&patterns.include, &patterns.exclude);
+        assert_eq!(filter.patterns(), patterns);
     }
 }
@@ -0,0 +1,569 @@
+//! Saving the cache to a snapshot file and loading it back on startup, so a
+//! restart does not begin with a cold cache.
+//!
+//! A snapshot holds the main-cache and 404 entries, bodies included, and the
+//! `Vary` names learnt per key. The file starts with [`MAGIC`] and the
+//! [`CACHE_FORMAT_VERSION`] it was written with; the rest is bincode. A file
+//! from an earlier version is migrated or ignored, as the
+//! [`FormatMismatchPolicy`] says. One from a version this build cannot
+//! migrate, or one that cannot be decoded, is ignored with a warning and the
+//! proxy starts empty.
+//!
+//! Every snapshot is written from the live entries to a temporary file that
+//! is then renamed over the previous one, so it never contains purged or
+//! expired entries, and a crash mid-write leaves the last good snapshot in
+//! place.
+
+use crate::cache::CacheStore;
+use crate::FormatMismatchPolicy;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+/// Version of the layout of persisted cache entries. Raise it whenever
+/// [`PersistedEntry`] or [`CacheSnapshot`] change shape, so older files are
+/// migrated or discarded instead of being decoded into the wrong fields.
+///
+/// - 1: the first layout.
+/// - 2: snapshots record when they were written.
+pub const CACHE_FORMAT_VERSION: u32 = 2;
+
+/// The earlier version this build can migrate from.
+pub const PREVIOUS_FORMAT_VERSION: u32 = 1;
+
+/// First bytes of every snapshot file.
+pub const MAGIC: &[u8; 8] = b"PFCACHE\0";
+
+/// Everything a snapshot holds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct CacheSnapshot {
+    /// Main-cache entries, oldest first.
+    pub(crate) entries: Vec<PersistedEntry>,
+    /// 404 entries, oldest first.
+    pub(crate) not_found: Vec<PersistedEntry>,
+    /// The request headers responses under each key vary on.
+    pub(crate) vary: Vec<(String, Vec<String>)>,
+    /// Milliseconds since the Unix epoch; `None` for a migrated snapshot.
+    pub(crate) written_at: Option<u64>,
+}
+
+/// The layout of format version 1, kept to migrate its snapshots.
+mod v1 {
+    use super::PersistedEntry;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(super) struct CacheSnapshot {
+        entries: Vec<PersistedEntry>,
+        not_found: Vec<PersistedEntry>,
+        vary: Vec<(String, Vec<String>)>,
+    }
+
+    impl From<CacheSnapshot> for super::CacheSnapshot {
+        fn from(snapshot: CacheSnapshot) -> Self {
+            Self {
+                entries: snapshot.entries,
+                not_found: snapshot.not_found,
+                vary: snapshot.vary,
+                written_at: None,
+            }
+        }
+    }
+}
+
+/// A cache entry as written to a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PersistedEntry {
+    pub(crate) key: String,
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) content_encoding: Option<String>,
+    pub(crate) origin: Option<(String, String)>,
+    pub(crate) content_digest: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub(crate) stored_at: u64,
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// What happened to the snapshot file when the proxy started.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum RestoreOutcome {
+    /// There was no snapshot file yet.
+    NoSnapshot,
+    /// Entries were loaded. Counts are those kept after capacity limits.
+    Restored {
+        entries: usize,
+        not_found_entries: usize,
+        /// The format version the file was migrated from, if it was.
+        migrated_from: Option<u32>,
+        /// Unix seconds when the snapshot was written, if it says.
+        written_at: Option<u64>,
+    },
+    /// The file was written with another [`CACHE_FORMAT_VERSION`] and was
+    /// not migrated.
+    VersionMismatch { found: u32 },
+    /// The file could not be read or decoded.
+    Unreadable { error: String },
+}
+
+/// Snapshot file state, as reported by `GET /persistence/stats`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PersistenceStats {
+    pub path: PathBuf,
+    /// Size of the snapshot file; `None` when there is none.
+    pub file_bytes: Option<u64>,
+    /// Entries in the last snapshot written by this process.
+    pub snapshot_entries: usize,
+    /// Unix seconds when that snapshot was written.
+    pub last_snapshot_at: Option<u64>,
+    pub last_restore: RestoreOutcome,
+}
+
+/// Writes a proxy's cache to a snapshot file and restores it on startup.
+pub struct Persistence {
+    path: PathBuf,
+    cache: CacheStore,
+    format_policy: FormatMismatchPolicy,
+    /// Held while a snapshot is written, so two never share the temp file.
+    write_lock: tokio::sync::Mutex<()>,
+    snapshot_entries: Mutex<(usize, Option<u64>)>,
+    last_restore: Mutex<RestoreOutcome>,
+}
+
+impl Persistence {
+    /// Restore `cache` from the snapshot at `path`, then write a fresh
+    /// snapshot every `interval` (zero: only when [`save`](Self::save) is
+    /// called) for as long as the returned value is alive. A snapshot from
+    /// an earlier format version is handled as `format_policy` says.
+    pub(crate) fn start(
+        path: PathBuf,
+        cache: CacheStore,
+        interval: Duration,
+        format_policy: FormatMismatchPolicy,
+    ) -> Arc<Self> {
+        let persistence = Arc::new(Self {
+            path,
+            cache,
+            format_policy,
+            write_lock: tokio::sync::Mutex::new(()),
+            snapshot_entries: Mutex::new((0, None)),
+            last_restore: Mutex::new(RestoreOutcome::NoSnapshot),
+        });
+        persistence.restore();
+
+        if !interval.is_zero() {
+            let weak = Arc::downgrade(&persistence);
+            tokio::spawn(async move {
+                let mut ticks =
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                loop {
+                    ticks.tick().await;
+                    let Some(persistence) = weak.upgrade() else {
+                        break;
+                    };
+                    if let Err(error) = persistence.save().await {
+                        tracing::warn!("{:#}", error);
+                    }
+                }
+            });
+        }
+        persistence
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the cache's current entries to the snapshot file, replacing the
+    /// previous snapshot only once the new one is complete. Returns how many
+    /// entries were written.
+    pub async fn save(&self) -> anyhow::Result<usize> {
+        let _guard = self.write_lock.lock().await;
+        let snapshot = self.cache.snapshot().await;
+        let entries = snapshot.entries.len() + snapshot.not_found.len();
+        let payload = encode(&snapshot)?;
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let written = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(&payload).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        }
+        .await;
+        if let Err(error) = written {
+            return Err(anyhow::anyhow!(
+                "Failed to write cache snapshot '{}': {}",
+                self.path.display(),
+                error
+            ));
+        }
+
+        *self.snapshot_entries.lock().unwrap() =
+            (entries, Some(unix_millis(SystemTime::now()) / 1000));
+        tracing::debug!(
+            "Wrote {} cache entries to '{}'",
+            entries,
+            self.path.display()
+        );
+        Ok(entries)
+    }
+
+    pub fn stats(&self) -> PersistenceStats {
+        let (snapshot_entries, last_snapshot_at) = *self.snapshot_entries.lock().unwrap();
+        PersistenceStats {
+            path: self.path.clone(),
+            file_bytes: std::fs::metadata(&self.path).ok().map(|meta| meta.len()),
+            snapshot_entries,
+            last_snapshot_at,
+            last_restore: self.last_restore.lock().unwrap().clone(),
+        }
+    }
+
+    fn restore(&self) {
+        let outcome = match std::fs::read(&self.path) {
+            Ok(bytes) => match decode(&bytes, self.format_policy) {
+                Ok((snapshot, migrated_from)) => {
+                    let written_at = snapshot.written_at.map(|millis| millis / 1000);
+                    let (entries, not_found_entries) = self.cache.restore(snapshot);
+                    tracing::info!(
+                        "Restored {} cache entries and {} 404 entries from '{}'",
+                        entries,
+                        not_found_entries,
+                        self.path.display()
+                    );
+                    if let Some(found) = migrated_from {
+                        tracing::info!(
+                            "Migrated cache snapshot '{}' from format version {} to {}",
+                            self.path.display(),
+                            found,
+                            CACHE_FORMAT_VERSION
+                        );
+                    }
+                    RestoreOutcome::Restored {
+                        entries,
+                        not_found_entries,
+                        migrated_from,
+                        written_at,
+                    }
+                }
+                Err(outcome) => outcome,
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                RestoreOutcome::NoSnapshot
+            }
+            Err(error) => RestoreOutcome::Unreadable {
+                error: error.to_string(),
+            },
+        };
+
+        match &outcome {
+            RestoreOutcome::VersionMismatch { found }
+                if readable_format(*found, FormatMismatchPolicy::Migrate) =>
+            {
+                tracing::warn!(
+                    "Ignoring cache snapshot '{}' in format version {}: format_mismatch_policy is discard",
+                    self.path.display(),
+                    found
+                )
+            }
+            RestoreOutcome::VersionMismatch { found } => tracing::warn!(
+                "Ignoring cache snapshot '{}': written with format version {}, this build reads {}",
+                self.path.display(),
+                found,
+                CACHE_FORMAT_VERSION
+            ),
+            RestoreOutcome::Unreadable { error } => tracing::warn!(
+                "Ignoring unreadable cache snapshot '{}': {}",
+                self.path.display(),
+                error
+            ),
+            RestoreOutcome::NoSnapshot | RestoreOutcome::Restored { .. } => {}
+        }
+        *self.last_restore.lock().unwrap() = outcome;
+    }
+}
+
+impl std::fmt::Debug for Persistence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persistence")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+fn encode(snapshot: &CacheSnapshot) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::from(&MAGIC[..]);
+    bytes.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, snapshot)?;
+    Ok(bytes)
+}
+
+/// Whether data written in format `version` is read under `policy`.
+pub(crate) fn readable_format(version: u32, policy: FormatMismatchPolicy) -> bool {
+    version == CACHE_FORMAT_VERSION
+        || (version == PREVIOUS_FORMAT_VERSION && policy == FormatMismatchPolicy::Migrate)
+}
+
+/// The snapshot in `bytes`, and the version it was migrated from if it was.
+fn decode(
+    bytes: &[u8],
+    policy: FormatMismatchPolicy,
+) -> Result<(CacheSnapshot, Option<u32>), RestoreOutcome> {
+    let unreadable = |error: &str| RestoreOutcome::Unreadable {
+        error: error.to_string(),
+    };
+    let payload = bytes
+        .strip_prefix(&MAGIC[..])
+        .ok_or_else(|| unreadable("not a phantom-frame cache snapshot"))?;
+    let (version, payload) = payload
+        .split_first_chunk::<4>()
+        .ok_or_else(|| unreadable("truncated header"))?;
+    let version = u32::from_le_bytes(*version);
+    if !readable_format(version, policy) {
+        return Err(RestoreOutcome::VersionMismatch { found: version });
+    }
+    let snapshot = if version == CACHE_FORMAT_VERSION {
+        bincode::deserialize(payload).map(|snapshot| (snapshot, None))
+    } else {
+        bincode::deserialize::<v1::CacheSnapshot>(payload)
+            .map(|snapshot| (snapshot.into(), Some(version)))
+    };
+    snapshot.map_err(|error| unreadable(&error.to_string()))
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(crate) fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheHandle, CachedResponse};
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "phantom-frame-persistence-{}-{:x}.snapshot",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn page(body: &str) -> CachedResponse {
+        CachedResponse {
+            body: body.to_string().into_bytes().into(),
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        }
+    }
+
+    fn no_interval(path: &Path, cache: CacheStore) -> Arc<Persistence> {
+        Persistence::start(
+            path.to_path_buf(),
+            cache,
+            Duration::ZERO,
+            FormatMismatchPolicy::Migrate,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip_respects_capacity() {
+        let path = test_path("round-trip");
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        cache.set("GET:/a".to_string(), page("a")).await;
+        cache
+            .set_with_ttl("GET:/b".to_string(), page("b"), Duration::from_secs(60))
+            .await;
+        for n in 0..3 {
+            cache
+                .set_404(format!("GET:/missing/{}", n), page("gone"))
+                .await;
+        }
+        cache.set_vary("GET:/a", vec!["accept-language".to_string()]);
+        let persistence = no_interval(&path, cache);
+        assert_eq!(persistence.stats().last_restore, RestoreOutcome::NoSnapshot);
+        assert_eq!(persistence.save().await.unwrap(), 5);
+
+        // A 404 store of two keeps the two newest entries.
+        let restored = CacheStore::new(CacheHandle::new(), 2);
+        let persistence = no_interval(&path, restored.clone());
+        assert!(matches!(
+            persistence.stats().last_restore,
+            RestoreOutcome::Restored {
+                entries: 2,
+                not_found_entries: 2,
+                migrated_from: None,
+                written_at: Some(_),
+            }
+        ));
+        assert_eq!(restored.get("GET:/a").await.unwrap().body, "a");
+        assert_eq!(restored.get("GET:/b").await.unwrap().body, "b");
+        assert!(restored.get_404("GET:/missing/0").await.is_none());
+        assert!(restored.get_404("GET:/missing/2").await.is_some());
+        let headers = axum::http::HeaderMap::new();
+        assert_ne!(restored.variant_key("GET:/a", &headers), "GET:/a");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_foreign_and_corrupt_snapshots_are_ignored() {
+        let path = test_path("foreign");
+        let mut header = Vec::from(&MAGIC[..]);
+        header.extend_from_slice(&(CACHE_FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &header).unwrap();
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        let persistence = no_interval(&path, cache.clone());
+        assert_eq!(
+            persistence.stats().last_restore,
+            RestoreOutcome::VersionMismatch {
+                found: CACHE_FORMAT_VERSION + 1
+            }
+        );
+
+        cache.set("GET:/a".to_string(), page("a")).await;
+        persistence.save().await.unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        std::fs::write(&path, &bytes).unwrap();
+        let restored = CacheStore::new(CacheHandle::new(), 10);
+        let persistence = no_interval(&path, restored.clone());
+        assert!(matches!(
+            persistence.stats().last_restore,
+            RestoreOutcome::Unreadable { .. }
+        ));
+        assert_eq!(restored.size().await, 0);
+
+        std::fs::write(&path, b"{\"entries\": []}").unwrap();
+        let persistence = no_interval(&path, CacheStore::new(CacheHandle::new(), 10));
+        assert!(matches!(
+            persistence.stats().last_restore,
+            RestoreOutcome::Unreadable { .. }
+        ));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_previous_format_snapshot_is_migrated_or_discarded() {
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cache-snapshot-v1.bin");
+        let bytes = std::fs::read(fixture).unwrap();
+        assert_eq!(bytes[8..12], PREVIOUS_FORMAT_VERSION.to_le_bytes());
+        let path = test_path("previous-format");
+
+        std::fs::write(&path, &bytes).unwrap();
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        let persistence = Persistence::start(
+            path.clone(),
+            cache.clone(),
+            Duration::ZERO,
+            FormatMismatchPolicy::Discard,
+        );
+        assert_eq!(
+            persistence.stats().last_restore,
+            RestoreOutcome::VersionMismatch {
+                found: PREVIOUS_FORMAT_VERSION
+            }
+        );
+        assert_eq!(cache.size().await, 0);
+
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        let persistence = no_interval(&path, cache.clone());
+        assert_eq!(
+            persistence.stats().last_restore,
+            RestoreOutcome::Restored {
+                entries: 2,
+                not_found_entries: 1,
+                migrated_from: Some(PREVIOUS_FORMAT_VERSION),
+                written_at: None,
+            }
+        );
+        let about = cache.get("GET:/about").await.unwrap();
+        assert_eq!(about.body, "<h1>About</h1>");
+        let cookies: Vec<_> = about
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "set-cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(about.origin.unwrap().path, "/about");
+        assert!(cache.get_404("GET:/missing").await.is_some());
+        let headers = axum::http::HeaderMap::new();
+        assert_ne!(cache.variant_key("GET:/news", &headers), "GET:/news");
+
+        // The next snapshot is written in the current format.
+        persistence.save().await.unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[8..12], CACHE_FORMAT_VERSION.to_le_bytes());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_write_keeps_previous_snapshot() {
+        let path = test_path("interrupted");
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        cache.set("GET:/a".to_string(), page("a")).await;
+        let persistence = no_interval(&path, cache.clone());
+        persistence.save().await.unwrap();
+
+        // A crash mid-write leaves a partial temp file behind.
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, &MAGIC[..4]).unwrap();
+
+        let restored = CacheStore::new(CacheHandle::new(), 10);
+        no_interval(&path, restored.clone());
+        assert_eq!(restored.get("GET:/a").await.unwrap().body, "a");
+
+        std::fs::remove_file(&tmp_path).ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_drop_purged_and_expired_entries() {
+        let path = test_path("compact");
+        let cache = CacheStore::new(CacheHandle::new(), 10);
+        for n in 0..20 {
+            cache
+                .set(format!("GET:/page/{}", n), page(&"x".repeat(1000)))
+                .await;
+        }
+        cache
+            .set_with_ttl(
+                "GET:/brief".to_string(),
+                page("brief"),
+                Duration::from_millis(1),
+            )
+            .await;
+        let persistence = no_interval(&path, cache.clone());
+        persistence.save().await.unwrap();
+        let full = persistence.stats().file_bytes.unwrap();
+
+        cache.clear_by_pattern("GET:/page/1*").await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(persistence.save().await.unwrap(), 9);
+        assert!(persistence.stats().file_bytes.unwrap() < full);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,49 +1,406 @@
-use crate::cache::{CacheStore, CachedResponse};
+use crate::backend::{Backend, BackendLease, BackendSlot, PoolOptions};
+use crate::backend_headers::BackendDirectives;
+use crate::background::BackgroundTasks;
+use crate::body_cache::BodyCacheRules;
+use crate::cache::{content_digest, CacheError, CacheOrigin, CacheStore, CachedResponse};
+use crate::cache_rules::{CacheRule, CacheRules};
+use crate::compressed_variants::CompressedVariants;
 use crate::compression::{
-    client_accepts_encoding, compress_body_async, configured_encoding, decode_upstream_body_async,
-    decompress_body_async, identity_acceptable,
+    client_accepts_encoding, compress_body_async, decode_upstream_body_async,
+    decompress_body_async, identity_acceptable, preferred_encoding, CompressionPolicy,
 };
-use crate::path_matcher::should_cache_path;
-use crate::{CompressStrategy, CreateProxyConfig, ProxyMode, WebhookType};
+use crate::conditional;
+use crate::credentials::CredentialFilter;
+use crate::events::ProxyEvents;
+use crate::health::BackendProbe;
+use crate::negotiation::is_acceptable;
+use crate::normalize::normalize_percent_encoding;
+use crate::outbound::OutboundRequest;
+use crate::path_matcher::{PathFilter, PatternList};
+use crate::persistence::Persistence;
+use crate::query_params::QueryParamFilter;
+use crate::redact::Redactor;
+use crate::render_queue::RenderQueue;
+use crate::stats::{Outcome, PolicyActivation, ProxyStats};
+use crate::upstream_auth::UpstreamAuth;
+use crate::write_limiter::CacheWriteLimiter;
+use crate::{AcceptMismatch, CacheErrorPolicy, CreateProxyConfig, UpgradeFallback};
 use axum::{
     body::Body,
-    extract::Extension,
+    extract::{Extension, State},
     http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode},
 };
+use bytes::Bytes;
 use hyper_util::rt::TokioIo;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+mod coalesce;
+mod lookup;
+mod pipeline;
+mod write_queue;
 
 #[derive(Clone)]
 pub struct ProxyState {
     cache: CacheStore,
     config: CreateProxyConfig,
-    upstream_client: reqwest::Client,
+    backend: BackendSlot,
     webhook_client: reqwest::Client,
+    /// Present when `render_queue_patterns` is non-empty.
+    render_queue: Option<Arc<RenderQueue>>,
+    /// Present when `max_cache_writes_per_sec` is non-zero.
+    write_limiter: Option<Arc<CacheWriteLimiter>>,
+    /// Present when `cache_write_queue_capacity` is non-zero.
+    write_queue: Option<Arc<write_queue::CacheWriteQueue>>,
+    compression: CompressionPolicy,
+    /// Present when `compress_on_serve` is set and
+    /// `compress_variants_max_bytes` is non-zero.
+    compressed_variants: Option<Arc<CompressedVariants>>,
+    /// Present when `persistence_path` is set.
+    persistence: Option<Arc<Persistence>>,
+    stats: Arc<ProxyStats>,
+    /// Panics caught from user callbacks such as `cache_key_fn`.
+    callback_panics: Arc<AtomicU64>,
+    /// While set, requests skip cache lookups and stores.
+    caching_paused: Arc<AtomicBool>,
+    /// Cache hits rejected because the entry was stored for another request.
+    cache_origin_mismatches: Arc<AtomicU64>,
+    redactor: Redactor,
+    /// `include_paths` and `exclude_paths` or `path_rules`, parsed once and
+    /// replaced whole by `PUT /config/paths`.
+    path_filter: Arc<RwLock<Arc<PathFilter>>>,
+    /// `cache_rules`, parsed once.
+    cache_rules: CacheRules,
+    cache_key: CacheKeyFns,
+    /// `render_queue_patterns`, `html_normalize_paths`, and
+    /// `upgrade_fallback_paths`, parsed once.
+    render_queue_patterns: PatternList,
+    html_normalize_paths: PatternList,
+    upgrade_fallback_paths: PatternList,
+    body_cache_rules: BodyCacheRules,
+    /// `bypass_credentialed_requests` and `bypass_cookie_names`, parsed once.
+    credentials: CredentialFilter,
+    /// Cache keys with a backend fetch in progress, for `coalesce_misses`.
+    in_flight: Arc<coalesce::InFlight>,
+    /// `cache_status_header`, parsed once.
+    cache_status_header: Option<HeaderName>,
+    backend_probe: Arc<BackendProbe>,
+    /// Stops the proxy's background tasks when the last clone is dropped.
+    background: Arc<BackgroundTasks>,
 }
 
 impl ProxyState {
+    /// Build the state [`proxy_handler`] runs on. Background tasks, such as
+    /// the invalidation listener and health checks, are not started; prefer
+    /// [`crate::ProxyHandle::proxy_state`] from [`crate::create_proxy_handle`],
+    /// which has them running. The cache writer for
+    /// `cache_write_queue_capacity` is the exception: it is spawned here, so
+    /// with that set this must be called within a Tokio runtime.
     pub fn new(
         cache: CacheStore,
         config: CreateProxyConfig,
-        upstream_client: reqwest::Client,
+        backend: BackendSlot,
         webhook_client: reqwest::Client,
     ) -> Self {
+        let render_queue = (!config.render_queue_patterns.is_empty()).then(|| {
+            Arc::new(RenderQueue::new(
+                config.render_queue_capacity,
+                config.render_queue_path.clone(),
+            ))
+        });
+        let compression = CompressionPolicy::from_config(&config);
+        let compressed_variants = (config.compress_on_serve
+            && config.compress_variants_max_bytes > 0)
+            .then(|| Arc::new(CompressedVariants::new(config.compress_variants_max_bytes)));
+        let write_limiter = (config.max_cache_writes_per_sec > 0)
+            .then(|| Arc::new(CacheWriteLimiter::new(config.max_cache_writes_per_sec)));
+        let persistence = config.persistence_path.clone().map(|path| {
+            Persistence::start(
+                path,
+                cache.clone(),
+                config.persistence_interval,
+                config.format_mismatch_policy,
+            )
+        });
+
+        let stats = Arc::new(ProxyStats::new(config.stats_window_minutes));
+        let redactor = Redactor::new(&config.redact_query_params, &config.redact_headers);
+        let syntax = config.wildcard_syntax;
+        let path_filter = Arc::new(RwLock::new(Arc::new(if config.path_rules.is_empty() {
+            PathFilter::with_syntax(&config.include_paths, &config.exclude_paths, syntax)
+        } else {
+            PathFilter::ordered(&config.path_rules, syntax)
+        })));
+        let cache_rules = CacheRules::new(&config.cache_rules, syntax);
+        let cache_key = CacheKeyFns::new(&config);
+        let render_queue_patterns = PatternList::new(&config.render_queue_patterns, syntax);
+        let html_normalize_paths = PatternList::new(&config.html_normalize_paths, syntax);
+        let upgrade_fallback_paths = PatternList::new(&config.upgrade_fallback_paths, syntax);
+        let body_cache_rules = BodyCacheRules::new(&config.body_cache_rules, syntax);
+        let credentials = CredentialFilter::new(
+            config.bypass_credentialed_requests,
+            &config.bypass_cookie_names,
+        );
+        let cache_status_header = config.cache_status_header.as_deref().and_then(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .inspect_err(|_| {
+                    tracing::warn!("Invalid cache_status_header '{}', not sending it", name)
+                })
+                .ok()
+        });
+
+        let backend_probe = Arc::new(BackendProbe::new(
+            config.health_check_path.clone(),
+            config.health_check_timeout,
+        ));
+
+        // Unlike the tasks above, the writer is part of the miss path, so it
+        // starts with the state.
+        let background = Arc::new(BackgroundTasks::new());
+        let write_queue = (config.cache_write_queue_capacity > 0).then(|| {
+            let (queue, receiver) =
+                write_queue::CacheWriteQueue::new(config.cache_write_queue_capacity);
+            let queue = Arc::new(queue);
+            background.spawn(queue.clone().run(receiver, cache.clone(), stats.clone()));
+            queue
+        });
+
         Self {
             cache,
             config,
-            upstream_client,
+            backend,
             webhook_client,
+            render_queue,
+            write_limiter,
+            write_queue,
+            compression,
+            compressed_variants,
+            persistence,
+            stats,
+            callback_panics: Arc::new(AtomicU64::new(0)),
+            caching_paused: Arc::new(AtomicBool::new(false)),
+            cache_origin_mismatches: Arc::new(AtomicU64::new(0)),
+            redactor,
+            path_filter,
+            cache_rules,
+            cache_key,
+            render_queue_patterns,
+            html_normalize_paths,
+            upgrade_fallback_paths,
+            body_cache_rules,
+            credentials,
+            in_flight: Arc::default(),
+            cache_status_header,
+            backend_probe,
+            background,
+        }
+    }
+
+    pub(crate) fn background(&self) -> &BackgroundTasks {
+        &self.background
+    }
+
+    pub(crate) fn cache(&self) -> &CacheStore {
+        &self.cache
+    }
+
+    pub(crate) fn backend_probe(&self) -> &BackendProbe {
+        &self.backend_probe
+    }
+
+    pub(crate) fn path_filter(&self) -> Arc<PathFilter> {
+        self.path_filter.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_path_filter(&self, filter: PathFilter) {
+        *self.path_filter.write().unwrap() = Arc::new(filter);
+    }
+
+    /// The first `cache_rules` entry matching the request.
+    pub(crate) fn cache_rule(&self, method: &str, path: &str, query: &str) -> Option<&CacheRule> {
+        self.cache_rules.find(method, path, query)
+    }
+
+    /// Whether the request may be cached: the matching cache rule decides,
+    /// or without one `path_rules` or `include_paths` and `exclude_paths`.
+    pub(crate) fn should_cache(&self, method: &str, path: &str, query: &str) -> bool {
+        match self.cache_rule(method, path, query) {
+            Some(rule) => rule.cache,
+            None => self.path_filter().should_cache(method, path, query),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &CreateProxyConfig {
+        &self.config
+    }
+
+    pub(crate) fn backend(&self) -> &BackendSlot {
+        &self.backend
+    }
+
+    /// Report `outcome` in the cache status header, with `Age` on hits.
+    /// Responses already marked stale keep that status.
+    fn mark_cache_status(
+        &self,
+        response: &mut Response<Body>,
+        outcome: Outcome,
+        age: Option<Duration>,
+    ) {
+        response.extensions_mut().insert(outcome);
+        if response.extensions().get::<ServedStale>().is_some() {
+            return;
+        }
+        let value = match outcome {
+            Outcome::Hit => "HIT",
+            Outcome::Miss => "MISS",
+            Outcome::Bypass if self.caching_paused() => "PAUSED",
+            Outcome::Bypass | Outcome::Store => "BYPASS",
+        };
+        self.set_cache_status(response, value, age.filter(|_| outcome == Outcome::Hit));
+    }
+
+    /// Mark a cached entry served because the backend failed: `STALE` in the
+    /// cache status header and `Warning: 111` (RFC 7234 §5.5.2).
+    fn mark_stale(&self, response: &mut Response<Body>, age: Option<Duration>) {
+        response.headers_mut().insert(
+            axum::http::header::WARNING,
+            HeaderValue::from_static("111 - \"Revalidation Failed\""),
+        );
+        response.extensions_mut().insert(ServedStale);
+        self.set_cache_status(response, "STALE", age);
+    }
+
+    fn set_cache_status(
+        &self,
+        response: &mut Response<Body>,
+        value: &'static str,
+        age: Option<Duration>,
+    ) {
+        let Some(name) = &self.cache_status_header else {
+            return;
+        };
+        let headers = response.headers_mut();
+        headers.insert(name.clone(), HeaderValue::from_static(value));
+        if let Some(age) = age {
+            headers.insert(axum::http::header::AGE, HeaderValue::from(age.as_secs()));
+        }
+    }
+
+    pub(crate) fn render_queue(&self) -> Option<&RenderQueue> {
+        self.render_queue.as_deref()
+    }
+
+    pub(crate) fn persistence(&self) -> Option<&Persistence> {
+        self.persistence.as_deref()
+    }
+
+    pub(crate) fn stats(&self) -> &ProxyStats {
+        &self.stats
+    }
+
+    pub(crate) fn caching_paused(&self) -> bool {
+        self.caching_paused.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume caching, returning whether it was paused before.
+    pub(crate) fn set_caching_paused(&self, paused: bool) -> bool {
+        self.caching_paused.swap(paused, Ordering::Relaxed)
+    }
+
+    /// Pass an event to the `events` callbacks, if set. A panic is counted
+    /// and otherwise ignored.
+    pub(crate) fn emit(&self, event: impl FnOnce(&dyn ProxyEvents)) {
+        let Some(events) = self.config.events.as_deref() else {
+            return;
+        };
+        if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| event(events))).is_err() {
+            tracing::warn!("A ProxyEvents callback panicked");
+            self.callback_panics.fetch_add(1, Ordering::Relaxed);
         }
     }
+
+    pub(crate) fn callback_panics(&self) -> u64 {
+        self.callback_panics.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn cache_origin_mismatches(&self) -> u64 {
+        self.cache_origin_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Redacts request targets and headers in this proxy's log output.
+    pub(crate) fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    pub(crate) fn cache_writes_dropped(&self) -> u64 {
+        self.write_queue.as_ref().map_or(0, |queue| queue.dropped())
+    }
+
+    pub(crate) fn cache_writes_throttled(&self) -> u64 {
+        self.write_limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.throttled())
+    }
+
+    /// Compress and store an externally produced response under `key`,
+    /// replacing any existing entry.
+    pub(crate) async fn seed_entry(
+        &self,
+        key: String,
+        status: u16,
+        headers: impl IntoIterator<Item = (String, String)>,
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .collect();
+        let cached =
+            build_cached_response_from_pairs(status, headers, body, &self.compression).await?;
+        self.cache.set(key, cached).await;
+        Ok(())
+    }
 }
 
-pub(crate) fn build_upstream_client() -> anyhow::Result<reqwest::Client> {
+/// Content types whose responses never end on their own and are streamed
+/// through uncached by default.
+pub const DEFAULT_STREAMING_CONTENT_TYPES: &[&str] = &["text/event-stream"];
+
+/// [`DEFAULT_STREAMING_CONTENT_TYPES`] as owned strings, for config defaults.
+pub fn default_streaming_content_types() -> Vec<String> {
+    DEFAULT_STREAMING_CONTENT_TYPES
+        .iter()
+        .map(|content_type| content_type.to_string())
+        .collect()
+}
+
+/// Default for `max_request_body_bytes`: 10 MiB.
+pub const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// Default for `max_cacheable_response_bytes`: 20 MiB.
+pub const DEFAULT_MAX_CACHEABLE_RESPONSE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Response extension set on stale entries served by `backend_error_policy`.
+#[derive(Clone, Copy)]
+struct ServedStale;
+
+/// Response extension set when a miss was stored in the cache.
+#[derive(Clone, Copy)]
+pub(crate) struct StoredOnMiss;
+
+pub(crate) fn build_upstream_client(pool: PoolOptions) -> anyhow::Result<reqwest::Client> {
     reqwest::Client::builder()
         .pool_idle_timeout(Duration::from_secs(90))
-        .connect_timeout(Duration::from_secs(5))
-        .timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(pool.max_idle_per_host)
+        .connect_timeout(pool.connect_timeout)
+        .timeout(pool.request_timeout)
+        .redirect(if pool.follow_redirects {
+            reqwest::redirect::Policy::default()
+        } else {
+            reqwest::redirect::Policy::none()
+        })
         .tcp_keepalive(Duration::from_secs(30))
         .no_brotli()
         .no_deflate()
@@ -52,7 +409,8 @@ pub(crate) fn build_upstream_client() -> anyhow::Result<reqwest::Client> {
         .map_err(Into::into)
 }
 
-pub(crate) fn build_webhook_client() -> anyhow::Result<reqwest::Client> {
+/// The client blocking webhooks are called with, for [`ProxyState::new`].
+pub fn build_webhook_client() -> anyhow::Result<reqwest::Client> {
     reqwest::Client::builder()
         .pool_idle_timeout(Duration::from_secs(30))
         .connect_timeout(Duration::from_secs(3))
@@ -159,446 +517,219 @@ async fn call_webhook(
 
 /// Main proxy handler that serves prerendered content from cache
 /// or fetches from backend if not cached
+///
+/// Mount it on a router with [`Router::with_state`], e.g. as the fallback
+/// next to your own routes, with the state from
+/// [`crate::ProxyHandle::proxy_state`].
+///
+/// [`Router::with_state`]: axum::Router::with_state
 pub async fn proxy_handler(
-    Extension(state): Extension<Arc<ProxyState>>,
-    req: Request<Body>,
+    State(state): State<Arc<ProxyState>>,
+    mut req: Request<Body>,
 ) -> Result<Response<Body>, StatusCode> {
-    let request_started = Instant::now();
-    // Check for upgrade requests FIRST (before consuming anything from the request)
-    // This is critical for WebSocket to work properly
-    let is_upgrade = is_upgrade_request(req.headers());
-
-    if is_upgrade {
-        let method_str = req.method().as_str();
-        let path = req.uri().path();
-
-        // WebSocket / upgrade tunnelling is only meaningful when there is a live
-        // backend to tunnel to.  Pure SSG servers (PreGenerate with fallthrough
-        // disabled) have no backend reachable at request time, so we always
-        // return 501 for them regardless of the `enable_websocket` flag.
-        let ws_allowed = state.config.enable_websocket
-            && match &state.config.proxy_mode {
-                ProxyMode::Dynamic => true,
-                ProxyMode::PreGenerate { fallthrough, .. } => *fallthrough,
+    let mount_prefix = match state.config.path_prefix.as_deref() {
+        Some(prefix) => {
+            let Some(uri) = strip_path_prefix(req.uri(), prefix) else {
+                return Err(StatusCode::NOT_FOUND);
             };
-
-        if ws_allowed {
-            tracing::debug!(
-                "Upgrade request detected for {} {}, establishing direct proxy tunnel",
-                method_str,
-                path
-            );
-            return handle_upgrade_request(state, req).await;
-        } else {
-            tracing::warn!(
-                "Upgrade request detected for {} {} but WebSocket support is disabled or not available in current proxy mode",
-                method_str,
-                path
-            );
-            return Err(StatusCode::NOT_IMPLEMENTED);
+            *req.uri_mut() = uri;
+            Some(prefix.to_string())
         }
+        None => nested_mount_prefix(&req),
+    };
+    // The hook sees the request as the proxy received it, before
+    // `handle_request` takes it apart.
+    let serve_request = state.config.transform_on_serve.is_some().then(|| {
+        (
+            req.method().clone(),
+            req.uri().path().to_string(),
+            req.uri().query().unwrap_or("").to_string(),
+            req.headers().clone(),
+        )
+    });
+    let mut response = handle_request(state.clone(), req)
+        .await
+        .inspect_err(|status| state.stats.record_error_response(status.as_u16()))?;
+    if state.config.rewrite_location {
+        rewrite_location(&state, &mut response, mount_prefix.as_deref());
     }
-
-    // Extract request details (only after we know it's not an upgrade request)
-    let method = req.method().clone();
-    let method_str = method.as_str();
-    let uri = req.uri().clone();
-    let path = uri.path();
-    let query = uri.query().unwrap_or("");
-    let headers = req.headers().clone();
-    tracing::debug!(
-        method = method_str,
-        path,
-        query,
-        "proxy request entered handler"
-    );
-
-    // Check if only GET requests are allowed
-    if state.config.forward_get_only && method != axum::http::Method::GET {
-        tracing::warn!(
-            "Non-GET request {} {} rejected (forward_get_only is enabled)",
-            method_str,
-            path
-        );
-        return Err(StatusCode::METHOD_NOT_ALLOWED);
-    }
-
-    // ── Webhook dispatch ────────────────────────────────────────────────────
-    // Webhooks fire before cache reads so that access control is enforced even
-    // for requests that would otherwise be served from the cache.
-    let mut cache_key_override: Option<String> = None;
-    if !state.config.webhooks.is_empty() {
-        let payload = build_webhook_payload(method_str, path, query, &headers);
-        let webhook_started = Instant::now();
-
-        for webhook in &state.config.webhooks {
-            match webhook.webhook_type {
-                WebhookType::Notify => {
-                    // Fire-and-forget: spawn without awaiting.
-                    let url = webhook.url.clone();
-                    let payload_clone = payload.clone();
-                    let timeout_ms = webhook.timeout_ms.unwrap_or(5000);
-                    let webhook_client = state.webhook_client.clone();
-                    tokio::spawn(async move {
-                        if let Err(()) =
-                            call_webhook(&webhook_client, &url, &payload_clone, timeout_ms).await
-                        {
-                            tracing::warn!("Notify webhook POST to '{}' failed", url);
-                        }
-                    });
-                }
-                WebhookType::Blocking => {
-                    let timeout_ms = webhook.timeout_ms.unwrap_or(5000);
-                    match call_webhook(&state.webhook_client, &webhook.url, &payload, timeout_ms)
-                        .await
-                    {
-                        Ok(result) if result.status.is_success() => {
-                            tracing::debug!(
-                                "Blocking webhook '{}' allowed {} {}",
-                                webhook.url,
-                                method_str,
-                                path
-                            );
-                        }
-                        Ok(result) if result.status.is_redirection() => {
-                            tracing::debug!(
-                                "Blocking webhook '{}' redirecting {} {} to {}",
-                                webhook.url,
-                                method_str,
-                                path,
-                                result.location.as_deref().unwrap_or("(no location)")
-                            );
-                            let mut builder = Response::builder().status(result.status);
-                            if let Some(loc) = &result.location {
-                                builder =
-                                    builder.header(axum::http::header::LOCATION, loc.as_str());
-                            }
-                            return Ok(builder
-                                .body(Body::empty())
-                                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
-                        }
-                        Ok(result) => {
-                            tracing::warn!(
-                                "Blocking webhook '{}' denied {} {} with status {}",
-                                webhook.url,
-                                method_str,
-                                path,
-                                result.status
-                            );
-                            return Err(result.status);
-                        }
-                        Err(()) => {
-                            tracing::warn!(
-                                "Blocking webhook '{}' timed out or failed for {} {} — denying request",
-                                webhook.url,
-                                method_str,
-                                path
-                            );
-                            return Err(StatusCode::SERVICE_UNAVAILABLE);
-                        }
-                    }
-                }
-                WebhookType::CacheKey => {
-                    let timeout_ms = webhook.timeout_ms.unwrap_or(5000);
-                    match call_webhook(&state.webhook_client, &webhook.url, &payload, timeout_ms)
-                        .await
-                    {
-                        Ok(result) if result.status.is_success() => {
-                            let key = result.body.trim().to_string();
-                            if !key.is_empty() {
-                                tracing::debug!(
-                                    "Cache key webhook '{}' set key '{}' for {} {}",
-                                    webhook.url,
-                                    key,
-                                    method_str,
-                                    path
-                                );
-                                cache_key_override = Some(key);
-                            } else {
-                                tracing::warn!(
-                                    "Cache key webhook '{}' returned empty body for {} {} — using default key",
-                                    webhook.url,
-                                    method_str,
-                                    path
-                                );
-                            }
-                        }
-                        Ok(result) => {
-                            tracing::warn!(
-                                "Cache key webhook '{}' returned non-2xx {} for {} {} — using default key",
-                                webhook.url,
-                                result.status,
-                                method_str,
-                                path
-                            );
-                        }
-                        Err(()) => {
-                            tracing::warn!(
-                                "Cache key webhook '{}' timed out or failed for {} {} — using default key",
-                                webhook.url,
-                                method_str,
-                                path
-                            );
-                        }
-                    }
-                }
-            }
-        }
-
-        tracing::debug!(
-            method = method_str,
-            path,
-            elapsed_ms = webhook_started.elapsed().as_millis(),
-            "proxy request completed webhook phase"
-        );
+    if let (Some(hook), Some((method, path, query, headers))) =
+        (state.config.transform_on_serve.as_deref(), serve_request)
+    {
+        let req_info = crate::RequestInfo {
+            method: method.as_str(),
+            path: &path,
+            query: &query,
+            headers: &headers,
+        };
+        response = crate::transform::on_serve(
+            response,
+            &state.config.transform_content_types,
+            hook,
+            &req_info,
+            || {
+                tracing::warn!("transform_on_serve panicked for {} {}", method, path);
+                state.callback_panics.fetch_add(1, Ordering::Relaxed);
+            },
+        )
+        .await;
     }
+    Ok(response)
+}
 
-    // Check if this path should be cached based on include/exclude patterns
-    let should_cache = should_cache_path(
-        method_str,
-        path,
-        &state.config.include_paths,
-        &state.config.exclude_paths,
-    );
+/// [`proxy_handler`] for routers that provide the state as an
+/// `Extension<Arc<ProxyState>>` layer, as earlier versions did.
+#[deprecated(note = "use `proxy_handler` with `Router::with_state`")]
+pub async fn extension_proxy_handler(
+    Extension(state): Extension<Arc<ProxyState>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    proxy_handler(State(state), req).await
+}
 
-    // Generate cache key using the configured function
-    let req_info = crate::RequestInfo {
-        method: method_str,
-        path,
-        query,
-        headers: &headers,
+/// `uri` with `prefix` removed from its path, or `None` when the path is not
+/// under `prefix`. `/app` itself becomes `/`.
+fn strip_path_prefix(uri: &axum::http::Uri, prefix: &str) -> Option<axum::http::Uri> {
+    let rest = uri.path().strip_prefix(prefix)?;
+    let path = match rest {
+        "" => "/",
+        rest if rest.starts_with('/') => rest,
+        // `/app` must not match `/application`.
+        _ => return None,
     };
-    let cache_key = cache_key_override.unwrap_or_else(|| (state.config.cache_key_fn)(&req_info));
-    let cache_reads_enabled = !matches!(state.config.cache_strategy, crate::CacheStrategy::None);
-
-    // Try to get 404 cache first (available even if should_cache is false)
-    if cache_reads_enabled && state.config.cache_404_capacity > 0 {
-        if let Some(cached) = state.cache.get_404(&cache_key).await {
-            if cached_response_is_allowed(&state.config.cache_strategy, &cached) {
-                tracing::debug!("404 cache hit for: {} {}", method_str, cache_key);
-                let response = build_response_from_cache(cached, &headers).await?;
-                tracing::debug!(
-                    method = method_str,
-                    path,
-                    elapsed_ms = request_started.elapsed().as_millis(),
-                    "proxy request served from 404 cache"
-                );
-                return Ok(response);
-            }
-        }
-    }
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    axum::http::Uri::from_parts(parts).ok()
+}
 
-    // Try to get from cache first (only if caching is enabled for this path)
-    if should_cache && cache_reads_enabled {
-        if let Some(cached) = state.cache.get(&cache_key).await {
-            if cached_response_is_allowed(&state.config.cache_strategy, &cached) {
-                tracing::debug!("Cache hit for: {} {}", method_str, cache_key);
-                let response = build_response_from_cache(cached, &headers).await?;
-                tracing::debug!(
-                    method = method_str,
-                    path,
-                    elapsed_ms = request_started.elapsed().as_millis(),
-                    "proxy request served from main cache"
-                );
-                return Ok(response);
-            }
-        }
-        // PreGenerate mode: serve only from cache, no backend fallthrough on miss
-        if let ProxyMode::PreGenerate { fallthrough, .. } = &state.config.proxy_mode {
-            if !fallthrough {
-                tracing::debug!(
-                    "PreGenerate cache miss for: {} {} — returning 404 (fallthrough disabled)",
-                    method_str,
-                    cache_key
-                );
-                return Err(StatusCode::NOT_FOUND);
-            }
-        }
-        tracing::debug!(
-            "Cache miss for: {} {}, fetching from backend",
-            method_str,
-            cache_key
-        );
-    } else if !cache_reads_enabled {
-        tracing::debug!(
-            "{} {} not cacheable (cache strategy: none), proxying directly",
-            method_str,
-            path
-        );
+/// The prefix `Router::nest` removed from the request's path, if any.
+fn nested_mount_prefix(req: &Request<Body>) -> Option<String> {
+    let original = req.extensions().get::<axum::extract::OriginalUri>()?.path();
+    let path = req.uri().path();
+    let prefix = if path == "/" {
+        original.trim_end_matches('/')
     } else {
-        tracing::debug!(
-            "{} {} not cacheable (filtered), proxying directly",
-            method_str,
-            path
-        );
-    }
+        original.strip_suffix(path)?
+    };
+    (!prefix.is_empty()).then(|| prefix.to_string())
+}
 
-    // Convert body to bytes to forward it
-    let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            tracing::error!("Failed to read request body: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
+/// Point a `Location` aimed at the backend at the proxy instead. Done on the
+/// way out, so cached entries keep the backend's value and follow a backend
+/// switch. Under a mount prefix, paths without a `location_base_url` get the
+/// prefix back, including paths the backend sent as they are.
+fn rewrite_location(state: &ProxyState, response: &mut Response<Body>, mount_prefix: Option<&str>) {
+    let headers = response.headers_mut();
+    let Some(location) = headers
+        .get(axum::http::header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return;
+    };
+    let base_url = state.config.location_base_url.as_deref();
+    let rewritten = state
+        .backend
+        .all()
+        .iter()
+        .find_map(|backend| crate::location::rewrite(location, backend.url(), base_url));
+    let rewritten = match (mount_prefix, base_url) {
+        (Some(prefix), None) => {
+            let path = rewritten.as_deref().unwrap_or(location);
+            crate::location::add_mount_prefix(path, prefix).or(rewritten)
         }
+        _ => rewritten,
     };
+    if let Some(value) = rewritten.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        headers.insert(axum::http::header::LOCATION, value);
+    }
+}
 
-    // Fetch from backend (proxy_url)
-    // Use path+query only — not the full `uri` — because HTTP/2 requests carry an
-    // absolute-form URI (e.g. `https://example.com/path`) which would corrupt the
-    // concatenated URL when appended to proxy_url.
-    let path_and_query = uri
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or_else(|| uri.path());
-    let target_url = format!("{}{}", state.config.proxy_url, path_and_query);
-    let upstream_started = Instant::now();
-
-    let response = match state
-        .upstream_client
-        .request(method.clone(), &target_url)
-        .headers(convert_headers(&headers))
-        .body(body_bytes.to_vec())
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            tracing::error!("Failed to fetch from backend: {}", e);
-            return Err(StatusCode::BAD_GATEWAY);
+pub(crate) async fn handle_request(
+    state: Arc<ProxyState>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    crate::forwarded::apply(&mut req, state.config.trust_forwarded_headers);
+    if state.config.preserve_host && !req.headers().contains_key(axum::http::header::HOST) {
+        if let Some(host) = crate::forwarded::original_host(&req) {
+            req.headers_mut().insert(axum::http::header::HOST, host);
         }
-    };
+    }
+    let ctx = pipeline::RequestContext::new(req);
     tracing::debug!(
-        method = method_str,
-        path,
-        elapsed_ms = upstream_started.elapsed().as_millis(),
-        "proxy request received upstream response headers"
+        method = ctx.method.as_str(),
+        path = ctx.path,
+        query = %state.redactor.query(ctx.query()),
+        "proxy request entered handler"
     );
+    pipeline::run(&state, ctx).await
+}
 
-    // Cache the response (only if caching is enabled for this path)
-    let status = response.status().as_u16();
-    let response_headers = response.headers().clone();
-    let body_bytes = match response.bytes().await {
-        Ok(bytes) => bytes.to_vec(),
-        Err(e) => {
-            tracing::error!("Failed to read response body: {}", e);
-            return Err(StatusCode::BAD_GATEWAY);
-        }
+/// Read a request body of at most `max_request_body_bytes`. Longer bodies
+/// are refused with `413` as soon as the limit is passed, without reading
+/// the rest.
+async fn read_request_body(state: &ProxyState, body: Body) -> Result<Bytes, StatusCode> {
+    let limit = match state.config.max_request_body_bytes {
+        0 => usize::MAX,
+        limit => limit,
     };
-
-    let response_content_type = response_headers
-        .get(axum::http::header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok());
-    let response_is_cacheable = state
-        .config
-        .cache_strategy
-        .allows_content_type(response_content_type);
-    let upstream_content_encoding = response_headers
-        .get(axum::http::header::CONTENT_ENCODING)
-        .and_then(|value| value.to_str().ok());
-    let should_try_cache = cache_reads_enabled
-        && response_is_cacheable
-        && (should_cache || state.config.cache_404_capacity > 0);
-    let normalized_body = if should_try_cache || state.config.use_404_meta {
-        match decode_upstream_body_async(
-            body_bytes.clone(),
-            upstream_content_encoding.map(|value| value.to_string()),
-        )
-        .await
-        {
-            Ok(body) => Some(body),
-            Err(error) => {
-                tracing::warn!(
-                    "Skipping cache compression for {} {} due to unsupported upstream encoding: {}",
-                    method_str,
-                    path,
-                    error
-                );
-                None
-            }
+    axum::body::to_bytes(body, limit).await.map_err(|e| {
+        let too_large = std::error::Error::source(&e)
+            .is_some_and(|source| source.is::<http_body_util::LengthLimitError>());
+        if too_large {
+            tracing::warn!(
+                "Request body exceeds max_request_body_bytes ({}), answering 413",
+                limit
+            );
+            StatusCode::PAYLOAD_TOO_LARGE
+        } else {
+            tracing::error!("Failed to read request body: {}", e);
+            StatusCode::BAD_REQUEST
         }
-    } else {
-        None
-    };
+    })
+}
 
-    // Determine if this should be cached as a 404 (either by status or by meta tag if enabled)
-    let mut is_404 = status == 404;
-    if !is_404 && state.config.use_404_meta {
-        if let Some(body) = normalized_body.as_deref() {
-            is_404 = body_contains_404_meta(body);
-        }
-    }
-
-    let should_store_404 = is_404
-        && state.config.cache_404_capacity > 0
-        && response_is_cacheable
-        && cache_reads_enabled
-        && normalized_body.is_some();
-    let should_store_response = !is_404
-        && should_cache
-        && response_is_cacheable
-        && cache_reads_enabled
-        && normalized_body.is_some();
-
-    if should_store_404 || should_store_response {
-        let cached_response = match build_cached_response(
-            status,
-            &response_headers,
-            normalized_body.as_deref().unwrap(),
-            &state.config.compress_strategy,
-        )
-        .await
-        {
-            Ok(cached_response) => cached_response,
-            Err(error) => {
-                tracing::warn!(
-                    "Failed to prepare cached response for {} {}: {}",
-                    method_str,
-                    path,
-                    error
-                );
-                return Ok(build_response_from_upstream(
-                    status,
-                    &response_headers,
-                    body_bytes,
-                ));
-            }
-        };
+/// Logs and counts a client disconnect when the handler future is dropped
+/// before the miss path finished, which is how hyper reports a closed
+/// connection. Disarmed once the response is ready.
+struct DisconnectGuard<'a> {
+    state: &'a ProxyState,
+    method: &'a str,
+    path: &'a str,
+    /// The miss path runs on its own task and outlives the client.
+    completing: bool,
+    armed: bool,
+}
 
-        if should_store_404 {
-            state
-                .cache
-                .set_404(cache_key.clone(), cached_response.clone())
-                .await;
-            tracing::debug!("Cached 404 response for: {} {}", method_str, cache_key);
-        } else {
-            state
-                .cache
-                .set(cache_key.clone(), cached_response.clone())
-                .await;
-            tracing::debug!("Cached response for: {} {}", method_str, cache_key);
-        }
+impl DisconnectGuard<'_> {
+    /// The response is ready: dropping the guard no longer means the client
+    /// went away.
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
 
-        let response = build_response_from_cache(cached_response, &headers).await?;
+impl Drop for DisconnectGuard<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.state.stats.record_client_disconnect();
+        let action = if self.completing {
+            "completing the backend fetch to populate the cache"
+        } else {
+            "aborting the backend fetch"
+        };
         tracing::debug!(
-            method = method_str,
-            path,
-            elapsed_ms = request_started.elapsed().as_millis(),
-            "proxy request completed after upstream fetch and cache write"
+            "Client disconnected before the response to {} {} was ready, {}",
+            self.method,
+            self.state.redactor.target(self.path),
+            action
         );
-        return Ok(response);
     }
-
-    tracing::debug!(
-        method = method_str,
-        path,
-        elapsed_ms = request_started.elapsed().as_millis(),
-        "proxy request completed without caching"
-    );
-    Ok(build_response_from_upstream(
-        status,
-        &response_headers,
-        body_bytes,
-    ))
 }
 
 /// Handle WebSocket and other upgrade requests by establishing a direct TCP tunnel
@@ -616,74 +747,38 @@ async fn handle_upgrade_request(
     state: Arc<ProxyState>,
     mut req: Request<Body>,
 ) -> Result<Response<Body>, StatusCode> {
-    // Use path+query only for the same reason as in proxy_handler (HTTP/2 absolute-form URI).
-    let req_path_and_query = req
-        .uri()
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or_else(|| req.uri().path());
-    let target_url = format!("{}{}", state.config.proxy_url, req_path_and_query);
-
-    // Parse the backend URL to extract host and port
-    let backend_uri = target_url.parse::<hyper::Uri>().map_err(|e| {
-        tracing::error!("Failed to parse backend URL: {}", e);
-        StatusCode::BAD_GATEWAY
-    })?;
-
-    let host = backend_uri.host().ok_or_else(|| {
-        tracing::error!("No host in backend URL");
-        StatusCode::BAD_GATEWAY
-    })?;
-
-    let port = backend_uri.port_u16().unwrap_or_else(|| {
-        if backend_uri.scheme_str() == Some("https") {
-            443
-        } else {
-            80
-        }
-    });
+    let deadline = tokio::time::Instant::now() + state.config.upgrade_handshake_timeout;
+    if let Err(response) = authorize_upstream(&state, req.headers_mut()).await {
+        return Ok(response);
+    }
+    // The tunnel stays on this backend for its whole lifetime.
+    let backend = pick_upgrade_backend(&state)?;
+    set_upgrade_host(&state, &backend, req.headers_mut());
+    transform_upgrade_request(&state, &backend, &mut req)?;
+    let (mut sender, conn_task) = before_deadline(
+        deadline,
+        connect_backend_for_upgrade(&state, &backend, req.uri()),
+    )
+    .await?;
 
     // IMPORTANT: Set up client upgrade BEFORE processing the request
     // This captures the client's connection for later upgrade
     let client_upgrade = hyper::upgrade::on(&mut req);
 
-    // Connect to backend
-    let backend_stream = tokio::net::TcpStream::connect((host, port))
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to connect to backend {}:{}: {}", host, port, e);
-            StatusCode::BAD_GATEWAY
-        })?;
-
-    let backend_io = TokioIo::new(backend_stream);
-
-    // Build the backend request with upgrade support
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(backend_io)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to handshake with backend: {}", e);
+    // Forward the request to the backend
+    let handshake = async {
+        sender.send_request(req).await.map_err(|e| {
+            tracing::error!("Failed to send request to backend: {}", e);
             StatusCode::BAD_GATEWAY
-        })?;
-
-    // Spawn a task to poll the connection - this will handle the upgrade
-    let conn_task = tokio::spawn(async move {
-        match conn.with_upgrades().await {
-            Ok(parts) => {
-                tracing::debug!("Backend connection upgraded successfully");
-                Ok(parts)
-            }
-            Err(e) => {
-                tracing::error!("Backend connection failed: {}", e);
-                Err(e)
-            }
+        })
+    };
+    let backend_response = match before_deadline(deadline, handshake).await {
+        Ok(response) => response,
+        Err(status) => {
+            conn_task.abort();
+            return Err(status);
         }
-    });
-
-    // Forward the request to the backend
-    let backend_response = sender.send_request(req).await.map_err(|e| {
-        tracing::error!("Failed to send request to backend: {}", e);
-        StatusCode::BAD_GATEWAY
-    })?;
+    };
 
     // Check if backend accepted the upgrade
     let status = backend_response.status();
@@ -700,47 +795,7 @@ async fn handle_upgrade_request(
 
     // Get the upgraded backend connection
     let backend_upgrade = hyper::upgrade::on(backend_response);
-
-    // Spawn a task to handle bidirectional streaming between client and backend
-    tokio::spawn(async move {
-        tracing::debug!("Starting upgrade tunnel establishment");
-
-        // Wait for both upgrades to complete
-        let (client_result, backend_result) = tokio::join!(client_upgrade, backend_upgrade);
-
-        // Drop the connection task as we now have the upgraded streams
-        drop(conn_task);
-
-        match (client_result, backend_result) {
-            (Ok(client_upgraded), Ok(backend_upgraded)) => {
-                tracing::debug!("Both upgrades successful, establishing bidirectional tunnel");
-
-                // Wrap both in TokioIo for AsyncRead + AsyncWrite
-                let mut client_stream = TokioIo::new(client_upgraded);
-                let mut backend_stream = TokioIo::new(backend_upgraded);
-
-                // Create bidirectional tunnel
-                match tokio::io::copy_bidirectional(&mut client_stream, &mut backend_stream).await {
-                    Ok((client_to_backend, backend_to_client)) => {
-                        tracing::debug!(
-                            "Tunnel closed gracefully. Transferred {} bytes client->backend, {} bytes backend->client",
-                            client_to_backend,
-                            backend_to_client
-                        );
-                    }
-                    Err(e) => {
-                        tracing::error!("Tunnel error: {}", e);
-                    }
-                }
-            }
-            (Err(e), _) => {
-                tracing::error!("Client upgrade failed: {}", e);
-            }
-            (_, Err(e)) => {
-                tracing::error!("Backend upgrade failed: {}", e);
-            }
-        }
-    });
+    spawn_upgrade_tunnel(client_upgrade, backend_upgrade, conn_task, backend, &state);
 
     // Build the response to send back to the client with upgrade support
     let mut response = Response::builder()
@@ -772,338 +827,5131 @@ async fn handle_upgrade_request(
     Ok(response)
 }
 
-async fn build_response_from_cache(
-    cached: CachedResponse,
-    request_headers: &HeaderMap,
-) -> Result<Response<Body>, StatusCode> {
-    let mut response_headers = cached.headers;
-    let body = if let Some(content_encoding) = cached.content_encoding {
-        if client_accepts_encoding(request_headers, content_encoding) {
-            upsert_vary_accept_encoding(&mut response_headers);
-            cached.body
-        } else {
-            if !identity_acceptable(request_headers) {
-                tracing::warn!(
-                    "Client does not accept cached encoding '{}' or identity fallback",
-                    content_encoding.as_header_value()
-                );
-                return Err(StatusCode::NOT_ACCEPTABLE);
-            }
+/// Whether an upgrade request that cannot be tunnelled should instead be
+/// served as a plain request under `UpgradeFallback::IgnoreHeader`.
+///
+/// Extended CONNECT requests never fall back: without the tunnel there is no
+/// meaningful plain-request equivalent.
+fn upgrade_falls_back(state: &ProxyState, req: &Request<Body>) -> bool {
+    if state.config.upgrade_fallback != UpgradeFallback::IgnoreHeader
+        || extended_connect_protocol(req).is_some()
+    {
+        return false;
+    }
+    let paths = &state.upgrade_fallback_paths;
+    let uri = req.uri();
+    paths.is_empty()
+        || paths.matches(
+            req.method().as_str(),
+            &normalize_percent_encoding(uri.path()),
+            &normalize_percent_encoding(uri.query().unwrap_or("")),
+        )
+}
 
-            response_headers.remove("content-encoding");
-            upsert_vary_accept_encoding(&mut response_headers);
-            match decompress_body_async(cached.body.clone(), content_encoding).await {
-                Ok(body) => body,
-                Err(error) => {
-                    tracing::error!("Failed to decompress cached response: {}", error);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
-            }
-        }
-    } else {
-        cached.body
-    };
+/// Remove `Upgrade` and the `upgrade` token of `Connection` so the request is
+/// forwarded as a plain HTTP request.
+fn strip_upgrade_headers(headers: &mut HeaderMap) {
+    headers.remove(axum::http::header::UPGRADE);
 
-    response_headers.remove("transfer-encoding");
-    response_headers.insert("content-length".to_string(), body.len().to_string());
+    let remaining: Vec<String> = headers
+        .get_all(axum::http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty() && !token.eq_ignore_ascii_case("upgrade"))
+        .map(str::to_string)
+        .collect();
+    headers.remove(axum::http::header::CONNECTION);
+    if !remaining.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&remaining.join(", ")) {
+            headers.insert(axum::http::header::CONNECTION, value);
+        }
+    }
+}
 
-    Ok(build_response(cached.status, response_headers, body))
+/// Return the `:protocol` of an RFC 8441 extended CONNECT request.
+///
+/// HTTP/2 clients bootstrap WebSockets with `CONNECT` plus a `:protocol`
+/// pseudo-header instead of `Connection: Upgrade`, so these requests are not
+/// caught by [`is_upgrade_request`].
+fn extended_connect_protocol(req: &Request<Body>) -> Option<&str> {
+    if req.method() != axum::http::Method::CONNECT {
+        return None;
+    }
+    req.extensions()
+        .get::<hyper::ext::Protocol>()
+        .map(|protocol| protocol.as_str())
 }
 
-async fn build_cached_response(
-    status: u16,
-    response_headers: &reqwest::header::HeaderMap,
-    normalized_body: &[u8],
-    compress_strategy: &CompressStrategy,
-) -> anyhow::Result<CachedResponse> {
-    let mut headers = convert_headers_to_map(response_headers);
-    headers.remove("content-encoding");
-    headers.remove("content-length");
-    headers.remove("transfer-encoding");
+/// Translate an HTTP/2 extended CONNECT WebSocket request (RFC 8441) into an
+/// HTTP/1.1 `Upgrade: websocket` handshake toward the backend.
+///
+/// The backend's `101 Switching Protocols` becomes a `200 OK` on the client's
+/// h2 stream, after which the stream and the upgraded backend connection are
+/// tunnelled exactly like an HTTP/1.1 upgrade.
+async fn handle_extended_connect(
+    state: Arc<ProxyState>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let deadline = tokio::time::Instant::now() + state.config.upgrade_handshake_timeout;
+    let backend = pick_upgrade_backend(&state)?;
+    let (mut sender, conn_task) = before_deadline(
+        deadline,
+        connect_backend_for_upgrade(&state, &backend, req.uri()),
+    )
+    .await?;
 
-    let content_encoding = configured_encoding(compress_strategy);
-    let body = if let Some(content_encoding) = content_encoding {
-        let compressed = compress_body_async(normalized_body.to_vec(), content_encoding).await?;
-        headers.insert(
-            "content-encoding".to_string(),
-            content_encoding.as_header_value().to_string(),
-        );
-        upsert_vary_accept_encoding(&mut headers);
-        compressed
-    } else {
-        normalized_body.to_vec()
-    };
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let mut backend_req = Request::builder()
+        .method(axum::http::Method::GET)
+        .uri(path_and_query)
+        .body(Body::empty())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    headers.insert("content-length".to_string(), body.len().to_string());
+    // h2 requests carry no hop-by-hop headers, so everything else is safe to
+    // forward. With `preserve_host`, `proxy_handler` has already moved the
+    // authority from `:authority` into `Host`.
+    let backend_headers = backend_req.headers_mut();
+    for (name, value) in req.headers() {
+        if name != axum::http::header::CONTENT_LENGTH {
+            backend_headers.append(name.clone(), value.clone());
+        }
+    }
+    set_upgrade_host(&state, &backend, backend_headers);
+    backend_headers.insert(
+        axum::http::header::CONNECTION,
+        HeaderValue::from_static("Upgrade"),
+    );
+    backend_headers.insert(
+        axum::http::header::UPGRADE,
+        HeaderValue::from_static("websocket"),
+    );
+    backend_headers.insert(
+        axum::http::header::SEC_WEBSOCKET_VERSION,
+        HeaderValue::from_static("13"),
+    );
+    backend_headers.insert(
+        axum::http::header::SEC_WEBSOCKET_KEY,
+        HeaderValue::from_str(&generate_websocket_key()).map_err(|_| StatusCode::BAD_GATEWAY)?,
+    );
+    if let Err(response) = authorize_upstream(&state, backend_headers).await {
+        return Ok(response);
+    }
+    transform_upgrade_request(&state, &backend, &mut backend_req)?;
 
-    Ok(CachedResponse {
-        body,
-        headers,
-        status,
-        content_encoding,
-    })
-}
+    let client_upgrade = hyper::upgrade::on(&mut req);
 
-fn build_response_from_upstream(
-    status: u16,
-    response_headers: &reqwest::header::HeaderMap,
-    body: Vec<u8>,
-) -> Response<Body> {
-    let mut headers = convert_headers_to_map(response_headers);
-    headers.remove("transfer-encoding");
-    headers.insert("content-length".to_string(), body.len().to_string());
-    build_response(status, headers, body)
-}
+    let handshake = async {
+        sender.send_request(backend_req).await.map_err(|e| {
+            tracing::error!("Failed to send WebSocket handshake to backend: {}", e);
+            StatusCode::BAD_GATEWAY
+        })
+    };
+    let backend_response = match before_deadline(deadline, handshake).await {
+        Ok(response) => response,
+        Err(status) => {
+            conn_task.abort();
+            return Err(status);
+        }
+    };
 
-fn build_response(
-    status: u16,
-    response_headers: HashMap<String, String>,
-    body: Vec<u8>,
-) -> Response<Body> {
-    let mut response = Response::builder().status(status);
+    let status = backend_response.status();
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        tracing::warn!(
+            "Backend rejected translated WebSocket handshake, status: {}",
+            status
+        );
+        let (parts, body) = backend_response.into_parts();
+        return Ok(Response::from_parts(parts, Body::new(body)));
+    }
 
-    // Add headers
-    let headers = response.headers_mut().unwrap();
-    for (key, value) in response_headers {
-        if let Ok(header_name) = key.parse::<HeaderName>() {
-            if let Ok(header_value) = HeaderValue::from_str(&value) {
-                headers.insert(header_name, header_value);
-            } else {
-                tracing::warn!(
-                    "Failed to parse header value for key '{}': {:?}",
-                    key,
-                    value
-                );
-            }
-        } else {
-            tracing::warn!("Failed to parse header name: {}", key);
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())
+        .unwrap();
+    for name in [
+        axum::http::header::SEC_WEBSOCKET_PROTOCOL,
+        axum::http::header::SEC_WEBSOCKET_EXTENSIONS,
+    ] {
+        if let Some(value) = backend_response.headers().get(&name) {
+            response.headers_mut().insert(name, value.clone());
         }
     }
 
-    response.body(Body::from(body)).unwrap()
-}
+    let backend_upgrade = hyper::upgrade::on(backend_response);
+    spawn_upgrade_tunnel(client_upgrade, backend_upgrade, conn_task, backend, &state);
 
-fn cached_response_is_allowed(strategy: &crate::CacheStrategy, cached: &CachedResponse) -> bool {
-    strategy.allows_content_type(
-        cached
-            .headers
-            .get("content-type")
-            .map(|value| value.as_str()),
-    )
-}
+    tracing::debug!("Extended CONNECT accepted, WebSocket tunnel task spawned");
 
-fn body_contains_404_meta(body: &[u8]) -> bool {
-    let Ok(body_str) = std::str::from_utf8(body) else {
-        return false;
-    };
+    Ok(response)
+}
 
-    let name_dbl = "name=\"phantom-404\"";
-    let name_sgl = "name='phantom-404'";
-    let content_dbl = "content=\"true\"";
-    let content_sgl = "content='true'";
+/// Random nonce for the `Sec-WebSocket-Key` of a translated handshake.
+///
+/// RFC 8441 drops the key from the h2 leg, so the proxy mints its own for the
+/// HTTP/1.1 leg. It only guards against misbehaving intermediaries, so
+/// `RandomState`'s per-instance seed is random enough.
+fn generate_websocket_key() -> String {
+    use base64::Engine;
+    use std::hash::{BuildHasher, Hasher};
 
-    (body_str.contains(name_dbl) || body_str.contains(name_sgl))
-        && (body_str.contains(content_dbl) || body_str.contains(content_sgl))
+    let mut nonce = [0u8; 16];
+    for chunk in nonce.chunks_mut(8) {
+        let value = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    base64::engine::general_purpose::STANDARD.encode(nonce)
 }
 
-fn upsert_vary_accept_encoding(headers: &mut HashMap<String, String>) {
-    match headers.get_mut("vary") {
-        Some(value) => {
-            let has_accept_encoding = value
-                .split(',')
-                .any(|part| part.trim().eq_ignore_ascii_case("accept-encoding"));
-            if !has_accept_encoding {
-                value.push_str(", Accept-Encoding");
-            }
-        }
-        None => {
-            headers.insert("vary".to_string(), "Accept-Encoding".to_string());
-        }
+type BackendConnTask = tokio::task::JoinHandle<Result<(), hyper::Error>>;
+
+/// The backend an upgrade tunnels to, or `503` when every backend failed its
+/// health check.
+fn pick_upgrade_backend(state: &ProxyState) -> Result<BackendLease, StatusCode> {
+    let backend = state.backend.pick();
+    if !backend.is_up() {
+        tracing::warn!("No healthy backend for upgrade request");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
+    Ok(backend)
 }
 
-fn convert_headers(headers: &HeaderMap) -> reqwest::header::HeaderMap {
-    let mut req_headers = reqwest::header::HeaderMap::new();
-    for (key, value) in headers {
-        // Skip host header as reqwest will set it
-        if key == axum::http::header::HOST {
-            continue;
-        }
-        if let Ok(val) = value.to_str() {
-            if let Ok(header_value) = reqwest::header::HeaderValue::from_str(val) {
-                req_headers.insert(key.clone(), header_value);
-            }
-        }
+/// Point the `Host` of an upgrade request at its backend, as reqwest does
+/// for plain requests, unless `preserve_host` keeps the client's.
+fn set_upgrade_host(state: &ProxyState, backend: &Backend, headers: &mut HeaderMap) {
+    if state.config.preserve_host {
+        return;
+    }
+    let backend_uri = backend.url().parse::<hyper::Uri>();
+    if let Some(host) = backend_uri
+        .ok()
+        .and_then(|uri| HeaderValue::from_str(uri.authority()?.as_str()).ok())
+    {
+        headers.insert(axum::http::header::HOST, host);
     }
-    req_headers
 }
 
-/// Fetch a single path from the upstream server, compress it, and store it in the cache.
-/// Used by the snapshot worker for PreGenerate warm-up and runtime snapshot management.
-pub(crate) async fn fetch_and_cache_snapshot(
-    path: &str,
-    client: &reqwest::Client,
-    proxy_url: &str,
-    cache: &CacheStore,
-    compress_strategy: &CompressStrategy,
-    cache_key_fn: &std::sync::Arc<dyn Fn(&crate::RequestInfo) -> String + Send + Sync>,
-) -> anyhow::Result<()> {
-    let empty_headers = axum::http::HeaderMap::new();
-    let req_info = crate::RequestInfo {
-        method: "GET",
-        path,
-        query: "",
-        headers: &empty_headers,
-    };
-    let cache_key = cache_key_fn(&req_info);
-
-    let url = format!("{}{}", proxy_url, path);
-    let response = client
-        .get(&url)
-        .send()
+/// Run a step of an upgrade handshake, answering 504 if `deadline` passes
+/// first.
+async fn before_deadline<T>(
+    deadline: tokio::time::Instant,
+    step: impl std::future::Future<Output = Result<T, StatusCode>>,
+) -> Result<T, StatusCode> {
+    tokio::time::timeout_at(deadline, step)
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to fetch snapshot '{}': {}", path, e))?;
+        .unwrap_or_else(|_| {
+            tracing::error!("Backend did not complete the upgrade handshake in time");
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        })
+}
 
-    let status = response.status().as_u16();
-    let response_headers = response.headers().clone();
-    let body_bytes = response
-        .bytes()
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to read snapshot response for '{}': {}", path, e))?
-        .to_vec();
+/// Open a raw HTTP/1.1 connection to `backend` for an upgrade.
+///
+/// The connection is driven by a spawned task that keeps polling until the
+/// upgrade completes, so the caller only has to send the handshake request.
+async fn connect_backend_for_upgrade(
+    state: &ProxyState,
+    backend: &Backend,
+    uri: &axum::http::Uri,
+) -> Result<
+    (
+        hyper::client::conn::http1::SendRequest<Body>,
+        BackendConnTask,
+    ),
+    StatusCode,
+> {
+    // Use path+query only for the same reason as in proxy_handler (HTTP/2 absolute-form URI).
+    let req_path_and_query = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or_else(|| uri.path());
+    let target_url = format!("{}{}", backend.url(), req_path_and_query);
 
-    let upstream_encoding = response_headers
-        .get(axum::http::header::CONTENT_ENCODING)
-        .and_then(|v| v.to_str().ok());
-    let normalized =
-        decode_upstream_body_async(body_bytes, upstream_encoding.map(|value| value.to_string()))
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to decode snapshot body for '{}': {}", path, e))?;
+    // Parse the backend URL to extract host and port
+    let backend_uri = target_url.parse::<hyper::Uri>().map_err(|e| {
+        tracing::error!("Failed to parse backend URL: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
 
-    let cached =
-        build_cached_response(status, &response_headers, &normalized, compress_strategy).await?;
-    cache.set(cache_key, cached).await;
-    tracing::debug!("Snapshot pre-generated: {}", path);
-    Ok(())
-}
+    let host = backend_uri.host().ok_or_else(|| {
+        tracing::error!("No host in backend URL");
+        StatusCode::BAD_GATEWAY
+    })?;
 
-fn convert_headers_to_map(
-    headers: &reqwest::header::HeaderMap,
-) -> std::collections::HashMap<String, String> {
-    let mut map = std::collections::HashMap::new();
-    for (key, value) in headers {
-        if let Ok(val) = value.to_str() {
-            map.insert(key.as_str().to_ascii_lowercase(), val.to_string());
+    let port = backend_uri.port_u16().unwrap_or_else(|| {
+        if backend_uri.scheme_str() == Some("https") {
+            443
         } else {
-            // Log when we can't convert a header (might be binary)
-            tracing::debug!("Could not convert header '{}' to string", key);
+            80
         }
-    }
-    map
-}
+    });
+
+    // Connect to backend
+    let backend_stream = tokio::net::TcpStream::connect((host, port))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to connect to backend {}:{}: {}", host, port, e);
+            state.backend.report_failure(backend);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let backend_io = TokioIo::new(backend_stream);
+
+    // Build the backend request with upgrade support
+    let (sender, conn) = hyper::client::conn::http1::handshake(backend_io)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to handshake with backend: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    // Spawn a task to poll the connection - this will handle the upgrade
+    let conn_task = tokio::spawn(async move {
+        match conn.with_upgrades().await {
+            Ok(()) => {
+                tracing::debug!("Backend connection upgraded successfully");
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Backend connection failed: {}", e);
+                Err(e)
+            }
+        }
+    });
+
+    Ok((sender, conn_task))
+}
+
+/// Wait for both sides to finish upgrading, then copy bytes between them until
+/// either side closes.
+fn spawn_upgrade_tunnel(
+    client_upgrade: hyper::upgrade::OnUpgrade,
+    backend_upgrade: hyper::upgrade::OnUpgrade,
+    conn_task: BackendConnTask,
+    backend: BackendLease,
+    state: &ProxyState,
+) {
+    let stats = state.stats.clone();
+    let shutdown = state.background().token().clone();
+    tokio::spawn(async move {
+        tracing::debug!("Starting upgrade tunnel establishment");
+        // Counts the tunnel as in flight on its backend until it closes.
+        let _backend = backend;
+
+        // Wait for both upgrades to complete
+        let (client_result, backend_result) = tokio::join!(client_upgrade, backend_upgrade);
+
+        // Drop the connection task as we now have the upgraded streams
+        drop(conn_task);
+
+        match (client_result, backend_result) {
+            (Ok(client_upgraded), Ok(backend_upgraded)) => {
+                tracing::debug!("Both upgrades successful, establishing bidirectional tunnel");
+                let _tunnel = stats.track_tunnel();
+
+                // Wrap both in TokioIo for AsyncRead + AsyncWrite
+                let (mut client_read, mut client_write) =
+                    tokio::io::split(TokioIo::new(client_upgraded));
+                let (mut backend_read, mut backend_write) =
+                    tokio::io::split(TokioIo::new(backend_upgraded));
+
+                // Copy each direction until its source closes, then pass the
+                // close on. The first direction to finish names the side
+                // that ended the tunnel.
+                let closed_by = std::sync::OnceLock::new();
+                let client_to_backend = async {
+                    let bytes = tokio::io::copy(&mut client_read, &mut backend_write).await?;
+                    let _ = closed_by.set("client");
+                    backend_write.shutdown().await?;
+                    Ok::<_, std::io::Error>(bytes)
+                };
+                let backend_to_client = async {
+                    let bytes = tokio::io::copy(&mut backend_read, &mut client_write).await?;
+                    let _ = closed_by.set("backend");
+                    client_write.shutdown().await?;
+                    Ok::<_, std::io::Error>(bytes)
+                };
+
+                let transfer = async { tokio::try_join!(client_to_backend, backend_to_client) };
+                tokio::select! {
+                    result = transfer => match result {
+                        Ok((client_to_backend, backend_to_client)) => {
+                            tracing::debug!(
+                                "Tunnel closed by the {}. Transferred {} bytes client->backend, {} bytes backend->client",
+                                closed_by.get().copied().unwrap_or("client"),
+                                client_to_backend,
+                                backend_to_client
+                            );
+                        }
+                        Err(e) => {
+                            tracing::error!("Tunnel error: {}", e);
+                        }
+                    },
+                    _ = shutdown.cancelled() => {
+                        // Close both sides rather than dropping them mid-stream.
+                        tracing::debug!("Closing tunnel for shutdown");
+                        let _ = tokio::join!(client_write.shutdown(), backend_write.shutdown());
+                    }
+                }
+            }
+            (Err(e), _) => {
+                tracing::error!("Client upgrade failed: {}", e);
+            }
+            (_, Err(e)) => {
+                tracing::error!("Backend upgrade failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Headers of a `200` that are repeated on a `304` (RFC 9110 §15.4.5).
+const NOT_MODIFIED_HEADERS: [&str; 6] = [
+    "cache-control",
+    "content-location",
+    "date",
+    "etag",
+    "expires",
+    "vary",
+];
+
+/// A `304 Not Modified` when the client's copy of a successful entry is
+/// current. `If-None-Match` is compared with the entry's `ETag`; only without
+/// it is `If-Modified-Since` compared with the entry's `Last-Modified`, or
+/// with when it was stored.
+fn not_modified(
+    method: &str,
+    request_headers: &HeaderMap,
+    cached: &CachedResponse,
+) -> Option<Response<Body>> {
+    if !matches!(method, "GET" | "HEAD") || !(200..300).contains(&cached.status) {
+        return None;
+    }
+    let if_none_match = request_headers
+        .get_all(axum::http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    let matches = if if_none_match.is_empty() {
+        let last_modified = cached
+            .header("last-modified")
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .or(cached.stored_at)?;
+        let since = request_headers
+            .get(axum::http::header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok());
+        conditional::not_modified_since(since, last_modified)
+    } else {
+        conditional::if_none_match(Some(&if_none_match.join(",")), cached.header("etag"))
+    };
+    if !matches {
+        return None;
+    }
+
+    let headers = cached
+        .headers
+        .iter()
+        .filter(|(name, _)| NOT_MODIFIED_HEADERS.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    Some(build_response(304, headers, Body::empty()))
+}
+
+/// Answer a single-range request from a `200` entry: the range cut from the
+/// decoded body as a `206`, or a `416` when the range lies outside it. An
+/// `If-Range` that no longer matches the entry gets the whole entry instead.
+async fn serve_range(
+    state: &ProxyState,
+    key: &str,
+    cached: CachedResponse,
+    request_headers: &HeaderMap,
+    range: crate::range::ByteRange,
+) -> Result<Response<Body>, StatusCode> {
+    let if_range = request_headers
+        .get(axum::http::header::IF_RANGE)
+        .map(|value| value.to_str().unwrap_or(""));
+    if if_range.is_some_and(|header| {
+        !crate::range::if_range_matches(
+            header,
+            cached.header("etag"),
+            cached.header("last-modified"),
+        )
+    }) {
+        return serve_cached(state, key, cached, request_headers).await;
+    }
+
+    // Ranges count bytes of the decoded body, the representation every
+    // client can be sent.
+    let mut response_headers = cached.headers;
+    let body: Bytes = match cached.content_encoding {
+        Some(encoding) => {
+            remove_header(&mut response_headers, "content-encoding");
+            match decompress_body_async(cached.body, encoding).await {
+                Ok(body) => body.into(),
+                Err(error) => {
+                    tracing::error!("Failed to decompress cached response: {}", error);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+        None => cached.body,
+    };
+    remove_header(&mut response_headers, "transfer-encoding");
+    let len = body.len() as u64;
+    let Some(selected) = range.resolve(len) else {
+        let headers = vec![(
+            "content-range".to_string(),
+            crate::range::unsatisfied_range(len),
+        )];
+        return Ok(build_response(416, headers, Body::empty()));
+    };
+    set_header(
+        &mut response_headers,
+        "content-range",
+        crate::range::content_range(&selected, len),
+    );
+    let part = body.slice(*selected.start() as usize..=*selected.end() as usize);
+    set_header(
+        &mut response_headers,
+        "content-length",
+        part.len().to_string(),
+    );
+    Ok(build_response(206, response_headers, part))
+}
+
+/// `response` as the answer to a `HEAD`: the same status and headers,
+/// `Content-Length` included, with the body left out.
+fn without_body(response: Response<Body>) -> Response<Body> {
+    let (parts, _) = response.into_parts();
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Answer from `cached`, stored under `key`. With `compress_on_serve`, a
+/// client that does not accept the stored encoding gets the body compressed
+/// into one it prefers, reusing an earlier compression when one was kept.
+async fn serve_cached(
+    state: &ProxyState,
+    key: &str,
+    mut cached: CachedResponse,
+    request_headers: &HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    // Entries stored compressed were judged worth it when stored.
+    let compressible = state.config.compress_on_serve
+        && state.compression.compressible(
+            cached.header("content-type"),
+            if cached.content_encoding.is_some() {
+                usize::MAX
+            } else {
+                cached.body.len()
+            },
+        );
+    let accepts_stored = cached
+        .content_encoding
+        .is_some_and(|encoding| client_accepts_encoding(request_headers, encoding));
+    if !compressible || accepts_stored {
+        return build_response_from_cache(cached, request_headers).await;
+    }
+    // Clients get different bodies for this entry from here on.
+    upsert_vary_accept_encoding(&mut cached.headers);
+    let Some(encoding) = preferred_encoding(request_headers) else {
+        return build_response_from_cache(cached, request_headers).await;
+    };
+
+    let kept = state
+        .compressed_variants
+        .as_ref()
+        .and_then(|variants| variants.get(key, encoding, &cached));
+    let body = match kept {
+        Some(body) => body,
+        None => {
+            let decoded = match cached.content_encoding {
+                Some(stored) => decompress_body_async(cached.body.clone(), stored).await,
+                None => Ok(cached.body.to_vec()),
+            };
+            let compressed = match decoded {
+                Ok(decoded) => compress_body_async(decoded, encoding).await,
+                Err(error) => Err(error),
+            };
+            let body: Bytes = match compressed {
+                Ok(body) => body.into(),
+                Err(error) => {
+                    tracing::warn!(
+                        "Failed to compress {} for the client: {}",
+                        state.redactor.target(key),
+                        error
+                    );
+                    return build_response_from_cache(cached, request_headers).await;
+                }
+            };
+            if let Some(variants) = &state.compressed_variants {
+                variants.insert(key, encoding, &cached, body.clone());
+            }
+            body
+        }
+    };
+
+    let mut response_headers = cached.headers;
+    remove_header(&mut response_headers, "transfer-encoding");
+    set_header(
+        &mut response_headers,
+        "content-encoding",
+        encoding.as_header_value().to_string(),
+    );
+    set_header(
+        &mut response_headers,
+        "content-length",
+        body.len().to_string(),
+    );
+    Ok(build_response(cached.status, response_headers, body))
+}
+
+async fn build_response_from_cache(
+    cached: CachedResponse,
+    request_headers: &HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let mut response_headers = cached.headers;
+    let body = if let Some(content_encoding) = cached.content_encoding {
+        if client_accepts_encoding(request_headers, content_encoding) {
+            upsert_vary_accept_encoding(&mut response_headers);
+            cached.body
+        } else {
+            if !identity_acceptable(request_headers) {
+                tracing::warn!(
+                    "Client does not accept cached encoding '{}' or identity fallback",
+                    content_encoding.as_header_value()
+                );
+                return Err(StatusCode::NOT_ACCEPTABLE);
+            }
+
+            remove_header(&mut response_headers, "content-encoding");
+            upsert_vary_accept_encoding(&mut response_headers);
+            match decompress_body_async(cached.body, content_encoding).await {
+                Ok(body) => body.into(),
+                Err(error) => {
+                    tracing::error!("Failed to decompress cached response: {}", error);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+    } else {
+        cached.body
+    };
+
+    remove_header(&mut response_headers, "transfer-encoding");
+    set_header(
+        &mut response_headers,
+        "content-length",
+        body.len().to_string(),
+    );
+
+    Ok(build_response(cached.status, response_headers, body))
+}
+
+async fn build_cached_response(
+    status: u16,
+    response_headers: &reqwest::header::HeaderMap,
+    normalized_body: &[u8],
+    compression: &CompressionPolicy,
+) -> anyhow::Result<CachedResponse> {
+    build_cached_response_from_pairs(
+        status,
+        convert_headers_to_pairs(response_headers),
+        normalized_body,
+        compression,
+    )
+    .await
+}
+
+async fn build_cached_response_from_pairs(
+    status: u16,
+    mut headers: Vec<(String, String)>,
+    normalized_body: &[u8],
+    compression: &CompressionPolicy,
+) -> anyhow::Result<CachedResponse> {
+    for name in ["content-encoding", "content-length", "transfer-encoding"] {
+        remove_header(&mut headers, name);
+    }
+    // A backend's own validator is kept; otherwise the body gets one so
+    // repeat visitors can be answered with `304`.
+    if !headers.iter().any(|(name, _)| name == "etag") {
+        let digest = content_digest(normalized_body);
+        headers.push(("etag".to_string(), format!("\"{}\"", &digest[..32])));
+    }
+
+    let content_type = headers
+        .iter()
+        .find(|(name, _)| name == "content-type")
+        .map(|(_, value)| value.as_str());
+    let content_encoding = compression.store_encoding(content_type, normalized_body.len());
+    let body: Bytes = if let Some(content_encoding) = content_encoding {
+        let compressed = compress_body_async(normalized_body.to_vec(), content_encoding).await?;
+        set_header(
+            &mut headers,
+            "content-encoding",
+            content_encoding.as_header_value().to_string(),
+        );
+        upsert_vary_accept_encoding(&mut headers);
+        compressed.into()
+    } else {
+        Bytes::copy_from_slice(normalized_body)
+    };
+
+    set_header(&mut headers, "content-length", body.len().to_string());
+
+    Ok(CachedResponse {
+        body,
+        headers,
+        status,
+        content_encoding,
+        origin: None,
+        content_digest: None,
+        stored_at: None,
+    })
+}
+
+fn build_response_from_upstream(
+    status: u16,
+    response_headers: &reqwest::header::HeaderMap,
+    body: Vec<u8>,
+) -> Response<Body> {
+    let mut headers = convert_headers_to_pairs(response_headers);
+    remove_header(&mut headers, "transfer-encoding");
+    set_header(&mut headers, "content-length", body.len().to_string());
+    build_response(status, headers, body)
+}
+
+fn build_response(
+    status: u16,
+    response_headers: Vec<(String, String)>,
+    body: impl Into<Body>,
+) -> Response<Body> {
+    // Stores refuse invalid statuses, but entries can also come from disk
+    // or Redis.
+    let status = StatusCode::from_u16(status).unwrap_or_else(|_| {
+        tracing::warn!("Invalid cached status code {}, answering 502", status);
+        StatusCode::BAD_GATEWAY
+    });
+    let mut response = Response::builder().status(status);
+
+    // Add headers, keeping every field line of a repeated name
+    let headers = response.headers_mut().unwrap();
+    for (key, value) in response_headers {
+        if let Ok(header_name) = key.parse::<HeaderName>() {
+            if let Ok(header_value) = HeaderValue::from_str(&value) {
+                headers.append(header_name, header_value);
+            } else {
+                // The value is left out: it may be a cookie or credential.
+                tracing::warn!("Failed to parse header value for key '{}'", key);
+            }
+        } else {
+            tracing::warn!("Failed to parse header name: {}", key);
+        }
+    }
+
+    response.body(body.into()).unwrap()
+}
+
+fn cached_response_is_allowed(strategy: &crate::CacheStrategy, cached: &CachedResponse) -> bool {
+    strategy.allows_content_type(cached.header("content-type"))
+}
+
+/// The configured `accept_mismatch` action when `cached` does not satisfy the
+/// request's `Accept` header, or `None` when it may be served.
+fn accept_mismatch(
+    config: &CreateProxyConfig,
+    request_headers: &HeaderMap,
+    cached: &CachedResponse,
+) -> Option<AcceptMismatch> {
+    if config.accept_mismatch == AcceptMismatch::Ignore {
+        return None;
+    }
+    let content_type = cached.header("content-type")?;
+    let accept = request_headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    if accept.is_empty() || is_acceptable(Some(&accept.join(",")), content_type) {
+        return None;
+    }
+    tracing::debug!(
+        "Cached {} does not satisfy Accept: {}",
+        content_type,
+        accept.join(",")
+    );
+    Some(config.accept_mismatch.clone())
+}
+
+/// Whether the client asked for a fresh copy: `Cache-Control: no-cache` or
+/// `Pragma: no-cache` with `respect_client_no_cache`, or the
+/// `revalidate_token` in `X-Phantom-Revalidate`.
+fn client_forces_refresh(config: &CreateProxyConfig, headers: &HeaderMap) -> bool {
+    if let Some(token) = &config.revalidate_token {
+        if headers
+            .get_all("x-phantom-revalidate")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|given| crate::control::tokens_match(given, token))
+        {
+            return true;
+        }
+    }
+    if !config.respect_client_no_cache {
+        return false;
+    }
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+    };
+    crate::cache_control::CacheControl::parse(values(axum::http::header::CACHE_CONTROL)).no_cache
+        || crate::cache_control::CacheControl::parse(values(axum::http::header::PRAGMA)).no_cache
+}
+
+/// Whether a cache hit may be served to this request: true unless origin
+/// checking is on and the entry was stored for a different method or path.
+///
+/// A foreign entry means `cache_key_fn` maps two requests to one key. The
+/// entry is evicted, counted, and logged; the caller treats it as a miss.
+async fn hit_matches_origin(
+    state: &ProxyState,
+    cached: &CachedResponse,
+    method: &str,
+    path: &str,
+    cache_key: &str,
+) -> bool {
+    let Some(origin) = cached.origin.as_ref() else {
+        return true;
+    };
+    if !state.config.verify_cache_origin || origin.matches(method, path) {
+        return true;
+    }
+    tracing::error!(
+        "Cache key collision: '{}' was stored for {} {} but requested by {} {}; \
+         evicting it and fetching from the backend. Check cache_key_fn.",
+        state.redactor.target(cache_key),
+        origin.method,
+        origin.path,
+        method,
+        path
+    );
+    state
+        .cache_origin_mismatches
+        .fetch_add(1, Ordering::Relaxed);
+    state.cache.remove(cache_key).await;
+    false
+}
+
+fn upsert_vary_accept_encoding(headers: &mut Vec<(String, String)>) {
+    let has_accept_encoding = headers
+        .iter()
+        .filter(|(name, _)| name == "vary")
+        .flat_map(|(_, value)| value.split(','))
+        .any(|part| part.trim().eq_ignore_ascii_case("accept-encoding"));
+    if has_accept_encoding {
+        return;
+    }
+    match headers.iter_mut().find(|(name, _)| name == "vary") {
+        Some((_, value)) => value.push_str(", Accept-Encoding"),
+        None => headers.push(("vary".to_string(), "Accept-Encoding".to_string())),
+    }
+}
+
+/// Drop every `name` field from lowercase header pairs.
+fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(field, _)| field != name);
+}
+
+/// Replace every `name` field of lowercase header pairs with one `value`.
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: String) {
+    remove_header(headers, name);
+    headers.push((name.to_string(), value));
+}
+
+/// Request headers that only describe the client's connection to the
+/// proxy. They are never forwarded, and neither is any header the client
+/// names in `Connection`.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Build the headers of a backend request from the client's, without
+/// hop-by-hop headers. Repeated fields are kept; `cookie` fields, which
+/// HTTP/2 clients may split, are joined into one for HTTP/1.1 backends.
+/// A client that accepts trailers gets `te: trailers` sent on its behalf,
+/// since responses with trailers are streamed through with them.
+fn convert_headers(headers: &HeaderMap, preserve_host: bool) -> reqwest::header::HeaderMap {
+    let connection_tokens: Vec<String> = headers
+        .get_all(axum::http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    let accepts_trailers = headers
+        .get_all(axum::http::header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"));
+
+    let mut req_headers = reqwest::header::HeaderMap::new();
+    let mut cookies = Vec::new();
+    for (key, value) in headers {
+        // Unless the client's host is kept, reqwest sets the backend's
+        if key == axum::http::header::HOST && !preserve_host {
+            continue;
+        }
+        if HOP_BY_HOP_HEADERS.contains(&key.as_str())
+            || connection_tokens.iter().any(|token| token == key.as_str())
+        {
+            continue;
+        }
+        if let Ok(val) = value.to_str() {
+            if key == axum::http::header::COOKIE {
+                cookies.push(val);
+            } else if let Ok(header_value) = reqwest::header::HeaderValue::from_str(val) {
+                req_headers.append(key.clone(), header_value);
+            }
+        }
+    }
+    if !cookies.is_empty() {
+        if let Ok(cookie) = reqwest::header::HeaderValue::from_str(&cookies.join("; ")) {
+            req_headers.insert(reqwest::header::COOKIE, cookie);
+        }
+    }
+    if accepts_trailers {
+        req_headers.insert(
+            reqwest::header::TE,
+            reqwest::header::HeaderValue::from_static("trailers"),
+        );
+    }
+    req_headers
+}
+
+/// Call a user-supplied `cache_key_fn`, catching a panic instead of letting it
+/// unwind through the caller. Returns the panic message on failure.
+///
+/// `AssertUnwindSafe` is sound here because the closure only gets shared
+/// access to the request info and its result is discarded on panic; any state
+/// the closure itself mutates is the closure's responsibility.
+pub(crate) fn call_cache_key_fn(
+    cache_key_fn: &(dyn Fn(&crate::RequestInfo) -> Option<String> + Send + Sync),
+    req_info: &crate::RequestInfo,
+) -> Result<Option<String>, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cache_key_fn(req_info)))
+        .map_err(panic_message)
+}
+
+/// How requests map to cache keys: `async_cache_key_fn` when one is
+/// configured, else `cache_key_fn`, given the query without the ignored
+/// parameters and followed by the `cache_key_headers`.
+#[derive(Clone)]
+pub(crate) struct CacheKeyFns {
+    cache_key_fn: crate::CacheKeyFn,
+    async_cache_key_fn: Option<crate::AsyncCacheKeyFn>,
+    key_headers: Arc<[String]>,
+    query_filter: QueryParamFilter,
+}
+
+impl CacheKeyFns {
+    pub(crate) fn new(config: &CreateProxyConfig) -> Self {
+        Self {
+            cache_key_fn: config.cache_key_fn.clone(),
+            async_cache_key_fn: config.async_cache_key_fn.clone(),
+            key_headers: config.cache_key_headers.clone().into(),
+            query_filter: QueryParamFilter::new(
+                &config.ignored_query_params,
+                &config.allowed_query_params,
+            ),
+        }
+    }
+
+    /// The key for `req_info`. The sync function is called without boxing a
+    /// future. A panic, whether while building the future or polling it, is
+    /// returned like [`call_cache_key_fn`] returns it.
+    pub(crate) async fn key(
+        &self,
+        req_info: &crate::RequestInfo<'_>,
+    ) -> Result<Option<String>, String> {
+        let query = self.query_filter.apply(req_info.query);
+        let req_info = &crate::RequestInfo {
+            query: &query,
+            ..req_info.clone()
+        };
+        let key = match &self.async_cache_key_fn {
+            None => call_cache_key_fn(self.cache_key_fn.as_ref(), req_info)?,
+            Some(async_cache_key_fn) => {
+                let future = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    async_cache_key_fn(req_info)
+                }))
+                .map_err(panic_message)?;
+                CatchPanic(future).await?
+            }
+        };
+        Ok(key.map(|mut key| {
+            if let Some(component) =
+                crate::cache_rules::headers_key_component(&self.key_headers, req_info.headers)
+            {
+                crate::cache_rules::append_key_component(&mut key, &component);
+            }
+            key
+        }))
+    }
+}
+
+/// Resolves to the inner future's output, or to the panic message if polling
+/// it panicked.
+struct CatchPanic<F>(F);
+
+impl<F: std::future::Future + Unpin> std::future::Future for CatchPanic<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let inner = std::pin::Pin::new(&mut self.0);
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| inner.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => std::task::Poll::Ready(Err(panic_message(payload))),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string())
+}
+
+/// Add the `upstream_auth` credential to a backend request's headers,
+/// replacing the client's own. On failure, returns the `502` to answer with.
+pub(crate) async fn authorize_upstream(
+    state: &ProxyState,
+    headers: &mut HeaderMap,
+) -> Result<(), Response<Body>> {
+    let Some(auth) = &state.config.upstream_auth else {
+        return Ok(());
+    };
+    match auth.header().await {
+        Ok(value) => {
+            headers.insert(auth.header_name(), value);
+            Ok(())
+        }
+        Err(error) => {
+            tracing::error!("Upstream authentication failed: {:#}", error);
+            state.stats.record_upstream_auth_failure();
+            state
+                .stats
+                .record_error_response(StatusCode::BAD_GATEWAY.as_u16());
+            Err(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .header(axum::http::header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("upstream authentication failed"))
+                .unwrap())
+        }
+    }
+}
+
+/// Run the `transform_request` hook on a backend request. On a panic,
+/// returns the status to answer with instead of sending it.
+pub(crate) fn transform_outbound(
+    state: &ProxyState,
+    outbound: &mut OutboundRequest,
+) -> Result<(), StatusCode> {
+    let Some(hook) = state.config.transform_request.as_deref() else {
+        return Ok(());
+    };
+    if crate::outbound::call_hook(hook, outbound) {
+        return Ok(());
+    }
+    tracing::warn!(
+        "transform_request panicked for {} {} — not forwarding it",
+        outbound.method(),
+        state.redactor.target(outbound.path_and_query())
+    );
+    state.callback_panics.fetch_add(1, Ordering::Relaxed);
+    Err(StatusCode::BAD_GATEWAY)
+}
+
+/// Run the `transform_request` hook on an upgrade handshake in place. The
+/// tunnel is opened to `backend`, so a hook that replaces the origin gets
+/// the request refused.
+fn transform_upgrade_request(
+    state: &ProxyState,
+    backend: &Backend,
+    req: &mut Request<Body>,
+) -> Result<(), StatusCode> {
+    if state.config.transform_request.is_none() {
+        return Ok(());
+    }
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |pq| pq.as_str())
+        .to_string();
+    let mut outbound = OutboundRequest::new(
+        req.method().clone(),
+        path_and_query,
+        std::mem::take(req.headers_mut()),
+        backend.url(),
+    );
+    transform_outbound(state, &mut outbound)?;
+    if outbound.origin_replaced() {
+        tracing::warn!(
+            "transform_request moved an upgrade request to {}, which cannot be tunnelled",
+            outbound.origin()
+        );
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+    let (method, path_and_query, headers) = outbound.into_parts();
+    *req.method_mut() = method;
+    *req.uri_mut() = path_and_query
+        .parse()
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    *req.headers_mut() = headers;
+    Ok(())
+}
+
+/// Apply `cache_error_policy` to a cache `phase` ("lookup" or "store") that
+/// failed: `Ok` to carry on without the cache, or the status to fail with.
+pub(crate) fn apply_cache_error_policy(
+    state: &ProxyState,
+    error: CacheError,
+    phase: &str,
+    cache_key: &str,
+) -> Result<(), StatusCode> {
+    let key = state.redactor.target(cache_key);
+    match state.config.cache_error_policy {
+        CacheErrorPolicy::Bypass => {
+            tracing::warn!(
+                "Cache {} failed for {}: {} — bypassing the cache",
+                phase,
+                key,
+                error
+            );
+            state
+                .stats
+                .record_policy(PolicyActivation::CacheErrorBypass);
+            Ok(())
+        }
+        CacheErrorPolicy::Fail => {
+            tracing::error!(
+                "Cache {} failed for {}: {} — answering 503",
+                phase,
+                key,
+                error
+            );
+            state.stats.record_policy(PolicyActivation::CacheErrorFail);
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+/// Run `body_cache_skip_fn`, treating a panic as "skip" so the request is
+/// still served, uncached.
+/// Run `should_cache_fn`, treating a panic as `Bypass`.
+fn call_should_cache_fn(
+    state: &ProxyState,
+    decide: &(dyn Fn(&crate::RequestInfo) -> crate::CacheDecision + Send + Sync),
+    req_info: &crate::RequestInfo,
+) -> crate::CacheDecision {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| decide(req_info))).unwrap_or_else(
+        |_| {
+            tracing::warn!(
+                "should_cache_fn panicked for {} {} — bypassing the cache",
+                req_info.method,
+                req_info.path
+            );
+            state.callback_panics.fetch_add(1, Ordering::Relaxed);
+            crate::CacheDecision::Bypass
+        },
+    )
+}
+
+fn call_body_cache_skip_fn(
+    state: &ProxyState,
+    skip: &(dyn Fn(&crate::RequestInfo, &[u8]) -> bool + Send + Sync),
+    req_info: &crate::RequestInfo,
+    body: &[u8],
+) -> bool {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| skip(req_info, body))).unwrap_or_else(
+        |_| {
+            tracing::warn!("body_cache_skip_fn panicked — bypassing the cache");
+            state.callback_panics.fetch_add(1, Ordering::Relaxed);
+            true
+        },
+    )
+}
+
+/// Hooks a snapshot fetch applies to its backend request.
+pub(crate) struct SnapshotHooks<'a> {
+    pub upstream_auth: Option<&'a dyn UpstreamAuth>,
+    pub transform_request: Option<&'a (dyn Fn(&mut OutboundRequest) + Send + Sync)>,
+}
+
+/// Fetch a single path from the upstream server, compress it, and store it in the cache.
+/// Used by the snapshot worker for PreGenerate warm-up and runtime snapshot management.
+pub(crate) async fn fetch_and_cache_snapshot(
+    path: &str,
+    backend: &crate::backend::Backend,
+    cache: &CacheStore,
+    compression: &CompressionPolicy,
+    cache_key: &CacheKeyFns,
+    redactor: &Redactor,
+    hooks: SnapshotHooks<'_>,
+) -> anyhow::Result<()> {
+    // Match the key a live request for this path would get.
+    let path = &*normalize_percent_encoding(path);
+    let shown = redactor.target(path);
+    let empty_headers = axum::http::HeaderMap::new();
+    let req_info = crate::RequestInfo {
+        method: "GET",
+        path,
+        query: "",
+        headers: &empty_headers,
+    };
+    let cache_key = cache_key
+        .key(&req_info)
+        .await
+        .map_err(|e| anyhow::anyhow!("cache_key_fn panicked for snapshot '{}': {}", shown, e))?
+        .ok_or_else(|| anyhow::anyhow!("cache_key_fn returned no key for snapshot '{}'", shown))?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(auth) = hooks.upstream_auth {
+        let value = auth.header().await.map_err(|e| {
+            anyhow::anyhow!(
+                "Upstream authentication failed for snapshot '{}': {:#}",
+                shown,
+                e
+            )
+        })?;
+        headers.insert(auth.header_name(), value);
+    }
+    let mut outbound = OutboundRequest::new(axum::http::Method::GET, path, headers, backend.url());
+    if let Some(hook) = hooks.transform_request {
+        if !crate::outbound::call_hook(hook, &mut outbound) {
+            anyhow::bail!("transform_request panicked for snapshot '{}'", shown);
+        }
+    }
+    let url = outbound.url();
+    let (method, _, headers) = outbound.into_parts();
+    let request = backend.client().request(method, &url).headers(headers);
+    let response = request.send().await.map_err(|e| {
+        anyhow::anyhow!("Failed to fetch snapshot '{}': {}", shown, e.without_url())
+    })?;
+
+    let status = response.status().as_u16();
+    let mut response_headers = response.headers().clone();
+    let directives = BackendDirectives::take(&mut response_headers);
+    if !directives.allows_store() {
+        tracing::debug!(
+            "Snapshot '{}' not stored: the backend sent {:?}",
+            shown,
+            directives
+        );
+        return Ok(());
+    }
+    let body_bytes = response
+        .bytes()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to read snapshot response for '{}': {}",
+                shown,
+                e.without_url()
+            )
+        })?
+        .to_vec();
+
+    let upstream_encoding = response_headers
+        .get(axum::http::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+    let normalized =
+        decode_upstream_body_async(body_bytes, upstream_encoding.map(|value| value.to_string()))
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("Failed to decode snapshot body for '{}': {}", shown, e)
+            })?;
+
+    let mut cached =
+        build_cached_response(status, &response_headers, &normalized, compression).await?;
+    cached.origin = Some(CacheOrigin::new("GET", path));
+    match directives.ttl {
+        Some(ttl) => cache.set_with_ttl(cache_key, cached, ttl).await,
+        None => cache.set(cache_key, cached).await,
+    }
+    tracing::debug!("Snapshot pre-generated: {}", shown);
+    Ok(())
+}
+
+/// Lowercase name/value pairs of `headers`, one per field line, in order.
+fn convert_headers_to_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    let mut pairs = Vec::with_capacity(headers.len());
+    for (key, value) in headers {
+        if let Ok(val) = value.to_str() {
+            pairs.push((key.as_str().to_ascii_lowercase(), val.to_string()));
+        } else {
+            // Log when we can't convert a header (might be binary)
+            tracing::debug!("Could not convert header '{}' to string", key);
+        }
+    }
+    pairs
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::compression::ContentEncoding;
+    use crate::events::BypassReason;
+    use crate::CompressStrategy;
     use axum::body::to_bytes;
+    use pipeline::BODY_INSPECTIONS;
+    use std::time::Instant;
+
+    fn response_headers() -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            reqwest::header::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_build_cached_response_uses_selected_encoding() {
+        let cached = build_cached_response(
+            200,
+            &response_headers(),
+            b"<html>compressed</html>",
+            &CompressionPolicy::from_config(
+                &CreateProxyConfig::new("http://localhost".to_string())
+                    .with_compress_strategy(crate::CompressStrategy::Gzip),
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(cached.content_encoding, Some(ContentEncoding::Gzip));
+        assert_eq!(cached.header("content-encoding"), Some("gzip"));
+        assert_eq!(cached.header("vary"), Some("Accept-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_build_response_from_cache_falls_back_to_identity() {
+        let body = b"<html>identity</html>";
+        let compressed = crate::compression::compress_body(body, ContentEncoding::Brotli).unwrap();
+        let cached = CachedResponse {
+            body: compressed.into(),
+            headers: vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("content-encoding".to_string(), "br".to_string()),
+                ("content-length".to_string(), "123".to_string()),
+                ("vary".to_string(), "Accept-Encoding".to_string()),
+            ],
+            status: 200,
+            content_encoding: Some(ContentEncoding::Brotli),
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            axum::http::header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip"),
+        );
+
+        let response = build_response_from_cache(cached, &request_headers)
+            .await
+            .unwrap();
+        assert!(response
+            .headers()
+            .get(axum::http::header::CONTENT_ENCODING)
+            .is_none());
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.as_ref(), b"<html>identity</html>");
+    }
+
+    #[tokio::test]
+    async fn test_build_response_from_cache_keeps_supported_encoding() {
+        let body = b"<html>compressed</html>";
+        let compressed = crate::compression::compress_body(body, ContentEncoding::Brotli).unwrap();
+        let cached = CachedResponse {
+            body: compressed.clone().into(),
+            headers: vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("content-encoding".to_string(), "br".to_string()),
+                ("content-length".to_string(), compressed.len().to_string()),
+                ("vary".to_string(), "Accept-Encoding".to_string()),
+            ],
+            status: 200,
+            content_encoding: Some(ContentEncoding::Brotli),
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            axum::http::header::ACCEPT_ENCODING,
+            HeaderValue::from_static("br, gzip;q=0.5"),
+        );
+
+        let response = build_response_from_cache(cached, &request_headers)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(axum::http::header::CONTENT_ENCODING),
+            Some(&HeaderValue::from_static("br"))
+        );
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.as_ref(), compressed.as_slice());
+    }
+
+    /// Send `path` with `accept_encoding`, check that `Content-Length` and
+    /// `Content-Encoding` describe the body, and return the encoding and the
+    /// decoded body.
+    async fn fetch_encoded(
+        proxy: &axum::Router,
+        path: &str,
+        accept_encoding: Option<&str>,
+    ) -> (Option<String>, String) {
+        let request = match accept_encoding {
+            Some(value) => get_with_encoding(path, value),
+            None => crate::test_support::get(path),
+        };
+        let response = crate::test_support::send(proxy, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let encoding = response
+            .headers()
+            .get(axum::http::header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap().to_string());
+        let length = response.headers()[axum::http::header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            length,
+            body.len(),
+            "content-length for {:?}",
+            accept_encoding
+        );
+        let decoded = match encoding.as_deref() {
+            Some(value) => {
+                let encoding = ContentEncoding::from_header_value(value).unwrap();
+                crate::compression::decompress_body(&body, encoding).unwrap()
+            }
+            None => body.to_vec(),
+        };
+        (encoding, String::from_utf8(decoded).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_clients_get_an_encoding_they_accept_from_a_gzip_backend() {
+        use crate::test_support::spawn_backend;
+        use axum::response::IntoResponse;
+
+        const PAGE: &str = "<html>negotiated</html>";
+        // Gzips for clients that ask for it, like most real servers, and
+        // prefers zstd, which the proxy cannot decode.
+        let backend = spawn_backend(axum::Router::new().fallback(
+            |headers: HeaderMap| async move {
+                let accept_encoding = headers
+                    .get(axum::http::header::ACCEPT_ENCODING)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let gzip = accept_encoding.contains("gzip");
+                if accept_encoding.contains("zstd") {
+                    return (
+                        [
+                            (axum::http::header::CONTENT_TYPE, "text/html"),
+                            (axum::http::header::CONTENT_ENCODING, "zstd"),
+                        ],
+                        "zstd frames",
+                    )
+                        .into_response();
+                }
+                let mut response = Response::new(Body::from(if gzip {
+                    crate::compression::compress_body(PAGE.as_bytes(), ContentEncoding::Gzip)
+                        .unwrap()
+                } else {
+                    PAGE.as_bytes().to_vec()
+                }));
+                let response_headers = response.headers_mut();
+                response_headers.insert(
+                    axum::http::header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/html"),
+                );
+                response_headers.insert(
+                    axum::http::header::VARY,
+                    HeaderValue::from_static("Accept-Encoding"),
+                );
+                if gzip {
+                    response_headers.insert(
+                        axum::http::header::CONTENT_ENCODING,
+                        HeaderValue::from_static("gzip"),
+                    );
+                }
+                response
+            },
+        ))
+        .await;
+
+        // Each client's `Accept-Encoding`, and the encoding it should get.
+        type Clients = &'static [(Option<&'static str>, Option<&'static str>)];
+        let cases: [(CompressStrategy, Clients); 3] = [
+            (
+                CompressStrategy::Brotli,
+                &[
+                    (Some("gzip, zstd"), None),
+                    (Some("br"), Some("br")),
+                    (None, None),
+                    (Some("gzip, deflate, br"), Some("br")),
+                    (Some("br;q=0, gzip"), None),
+                ],
+            ),
+            (
+                CompressStrategy::Gzip,
+                &[
+                    (Some("br"), None),
+                    (Some("gzip, deflate"), Some("gzip")),
+                    (None, None),
+                ],
+            ),
+            (
+                CompressStrategy::None,
+                &[(Some("gzip"), None), (Some("br"), None), (None, None)],
+            ),
+        ];
+        for (strategy, clients) in cases {
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_compress_strategy(strategy.clone())
+                    .with_exclude_paths(vec!["/live".to_string()]),
+            );
+            // The first client's encoding is what the backend sent; it must
+            // not be what later clients get.
+            for (accept_encoding, expected) in clients.iter().copied() {
+                let (encoding, body) = fetch_encoded(&proxy, "/page", accept_encoding).await;
+                assert_eq!(
+                    encoding.as_deref(),
+                    expected,
+                    "{:?} with {:?}",
+                    strategy,
+                    accept_encoding
+                );
+                assert_eq!(body, PAGE);
+            }
+            assert_eq!(handle.cache().size().await, 1);
+
+            // Uncached responses pass through what the backend chose for
+            // this very client.
+            for (accept_encoding, expected) in [(Some("gzip"), Some("gzip")), (None, None)] {
+                let (encoding, body) = fetch_encoded(&proxy, "/live", accept_encoding).await;
+                assert_eq!(encoding.as_deref(), expected);
+                assert_eq!(body, PAGE);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress_on_serve_compresses_once_per_encoding() {
+        use crate::test_support::{send, spawn_backend};
+
+        let page = "<p>prerendered</p>".repeat(100);
+        let served = page.clone();
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/small",
+                    axum::routing::get(|| async {
+                        (
+                            [(axum::http::header::CONTENT_TYPE, "text/html")],
+                            "<p>small</p>",
+                        )
+                    }),
+                )
+                .route(
+                    "/image",
+                    axum::routing::get(|| async {
+                        (
+                            [(axum::http::header::CONTENT_TYPE, "image/png")],
+                            vec![7u8; 4096],
+                        )
+                    }),
+                )
+                .fallback(move || {
+                    let page = served.clone();
+                    async move { ([(axum::http::header::CONTENT_TYPE, "text/html")], page) }
+                }),
+        )
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_compress_strategy(CompressStrategy::None)
+                .with_compress_on_serve(true)
+                .with_compress_min_bytes(1024),
+        );
+        let state = handle.proxy_state();
+        let variants = state.compressed_variants.as_ref().unwrap();
+
+        // Stored once, uncompressed; each client gets what it prefers.
+        for (accept_encoding, expected) in [
+            (None, None),
+            (Some("gzip"), Some("gzip")),
+            (Some("gzip, deflate, br"), Some("br")),
+            (Some("zstd"), None),
+        ] {
+            let (encoding, body) = fetch_encoded(&proxy, "/page", accept_encoding).await;
+            assert_eq!(encoding.as_deref(), expected, "{:?}", accept_encoding);
+            assert_eq!(body, page);
+        }
+        let kept = variants.size_bytes();
+        assert!(kept > 0 && kept < page.len(), "kept {} bytes", kept);
+
+        // Hits reuse the kept variants instead of compressing again.
+        for accept_encoding in ["gzip", "br"] {
+            let (_, body) = fetch_encoded(&proxy, "/page", Some(accept_encoding)).await;
+            assert_eq!(body, page);
+        }
+        assert_eq!(variants.size_bytes(), kept);
+        let response = send(&proxy, get_with_encoding("/page", "gzip")).await;
+        assert_eq!(
+            response.headers()[axum::http::header::VARY],
+            "Accept-Encoding"
+        );
+
+        // Too small, or not a compressible type.
+        for path in ["/small", "/image"] {
+            let (encoding, _) = fetch_encoded(&proxy, path, Some("gzip, br")).await;
+            assert_eq!(encoding, None, "{}", path);
+        }
+        assert_eq!(variants.size_bytes(), kept);
+    }
+
+    fn get_with_encoding(path: &str, accept_encoding: &str) -> Request<Body> {
+        let mut request = crate::test_support::get(path);
+        request.headers_mut().insert(
+            axum::http::header::ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn test_build_response_from_cache_answers_502_for_invalid_status() {
+        for status in [0, 99, 1000, u16::MAX] {
+            let cached = CachedResponse {
+                body: b"page".to_vec().into(),
+                headers: vec![("content-type".to_string(), "text/html".to_string())],
+                status,
+                content_encoding: None,
+                origin: None,
+                content_digest: None,
+                stored_at: None,
+            };
+
+            let response = build_response_from_cache(cached, &HeaderMap::new())
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_GATEWAY,
+                "status {}",
+                status
+            );
+            assert_eq!(response.headers()["content-type"], "text/html");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_write_limit_serves_but_skips_storing_burst() {
+        use crate::test_support::{get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            (
+                [(axum::http::header::CONTENT_TYPE, "text/html")],
+                "<html>ok</html>",
+            )
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_max_cache_writes_per_sec(5),
+        );
+
+        for i in 0..20 {
+            let response = send(&proxy, get(&format!("/random/{}", i))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // The burst may straddle one window boundary, never more.
+        let stored = handle.cache().size().await;
+        assert!((5..=10).contains(&stored), "stored {} entries", stored);
+        assert_eq!(stored as u64 + handle.cache_writes_throttled(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_queued_cache_writes_do_not_delay_the_response() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            (
+                [(axum::http::header::CONTENT_TYPE, "text/html")],
+                "<html>ok</html>",
+            )
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cache_write_queue_capacity(1),
+        );
+        let state = handle.proxy_state();
+        let queue = state.write_queue.as_ref().unwrap();
+        let held = queue.hold.write().await;
+
+        for path in ["/a", "/b", "/c"] {
+            let response = tokio::time::timeout(Duration::from_secs(5), send(&proxy, get(path)))
+                .await
+                .expect("the response waited for the cache write");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "<html>ok</html>");
+        }
+        assert_eq!(handle.cache().size().await, 0);
+        // One write is queued; the writer may or may not have taken another.
+        let dropped = handle.cache_writes_dropped();
+        assert!((1..=2).contains(&dropped), "dropped {} writes", dropped);
+
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.cache().size().await as u64 + dropped < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("queued writes were stored");
+        // The first write always finds the queue empty.
+        assert!(handle.cache().get("GET:/a").await.is_some());
+        assert_eq!(handle.cache_writes_dropped(), dropped);
+    }
+
+    /// HTTP/1.1 backend that accepts any `Upgrade: websocket` handshake and
+    /// echoes bytes back over the upgraded connection.
+    async fn spawn_upgrade_echo_backend() -> String {
+        use hyper::service::service_fn;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let service =
+                        service_fn(|mut req: Request<hyper::body::Incoming>| async move {
+                            assert_eq!(req.headers()["upgrade"], "websocket");
+                            assert!(req.headers().contains_key("sec-websocket-key"));
+                            let on_upgrade = hyper::upgrade::on(&mut req);
+                            tokio::spawn(async move {
+                                let mut io = TokioIo::new(on_upgrade.await.unwrap());
+                                let (mut reader, mut writer) = tokio::io::split(&mut io);
+                                tokio::io::copy(&mut reader, &mut writer).await.ok();
+                            });
+                            Response::builder()
+                                .status(StatusCode::SWITCHING_PROTOCOLS)
+                                .header("upgrade", "websocket")
+                                .header("connection", "Upgrade")
+                                .header("sec-websocket-protocol", "chat")
+                                .body(http_body_util::Empty::<axum::body::Bytes>::new())
+                        });
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .with_upgrades()
+                        .await
+                        .ok();
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Serve `app` over cleartext HTTP/2 with extended CONNECT enabled and
+    /// return an h2 client connected to it.
+    async fn h2_client_for(app: axum::Router) -> hyper::client::conn::http2::SendRequest<Body> {
+        use hyper_util::rt::TokioExecutor;
+        use hyper_util::service::TowerToHyperService;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .enable_connect_protocol()
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app))
+                .await
+                .ok();
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        // Extended CONNECT may only be sent once the server's SETTINGS frame
+        // has arrived; a plain round trip guarantees that.
+        sender.ready().await.unwrap();
+        sender
+            .send_request(
+                Request::builder()
+                    .uri(format!("http://{}/__warmup", addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .ok();
+        sender
+    }
+
+    fn extended_connect(protocol: &'static str) -> Request<Body> {
+        let mut req = Request::builder()
+            .method("CONNECT")
+            .uri("http://proxy.local/ws?room=1")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-protocol", "chat")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(hyper::ext::Protocol::from_static(protocol));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_h2_extended_connect_is_translated_to_http1_upgrade() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let backend = spawn_upgrade_echo_backend().await;
+        let (proxy, _handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let mut client = h2_client_for(proxy).await;
+
+        let mut response = client
+            .send_request(extended_connect("websocket"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["sec-websocket-protocol"], "chat");
+
+        let upgraded = hyper::upgrade::on(&mut response).await.unwrap();
+        let mut io = TokioIo::new(upgraded);
+        io.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        io.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_open_tunnels() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let backend = spawn_upgrade_echo_backend().await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let mut client = h2_client_for(proxy).await;
+        let mut response = client
+            .send_request(extended_connect("websocket"))
+            .await
+            .unwrap();
+        let mut io = TokioIo::new(hyper::upgrade::on(&mut response).await.unwrap());
+        io.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        io.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(handle.stats().active_tunnels(), 1);
+
+        handle.shutdown().await;
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), io.read_to_end(&mut rest))
+            .await
+            .expect("the tunnel was not closed")
+            .unwrap();
+        assert!(rest.is_empty());
+        for _ in 0..100 {
+            if handle.stats().active_tunnels() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("the tunnel is still counted as open");
+    }
+
+    #[tokio::test]
+    async fn test_backends_share_cache_and_skip_failed_ones() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let a = spawn_backend(axum::Router::new().fallback(|| async { "a" })).await;
+        let b = spawn_backend(axum::Router::new().fallback(|| async { "b" })).await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(String::new()).with_backends(vec![a.clone(), b]),
+        );
+        assert_eq!(handle.config().proxy_url, a);
+        let mut bodies = vec![];
+        for path in ["/one", "/two", "/one", "/two"] {
+            bodies.push(body_string(send(&proxy, get(path)).await).await);
+        }
+        // The second round is served from the shared cache.
+        assert_eq!(bodies, ["a", "b", "a", "b"]);
+        assert!(handle
+            .backends()
+            .iter()
+            .all(|backend| backend.in_flight() == 0));
+
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(String::new())
+                .with_backends(vec![dead, a])
+                .with_cache_strategy(crate::CacheStrategy::None),
+        );
+        let first = send(&proxy, get("/")).await;
+        assert_eq!(first.status(), StatusCode::BAD_GATEWAY);
+        assert!(handle.backends()[0].cooling_down());
+        for _ in 0..3 {
+            assert_eq!(body_string(send(&proxy, get("/")).await).await, "a");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replaced_origin_failures_do_not_cool_down_backends() {
+        use crate::test_support::{get, send, spawn_backend};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Announces more body than it sends, then hangs up.
+        let truncating = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let truncating_url = format!("http://{}", truncating.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = truncating.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-type: text/html\r\n\
+                          content-length: 100\r\n\r\npartial",
+                    )
+                    .await;
+            }
+        });
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+
+        let a = spawn_backend(axum::Router::new().fallback(|| async { "a" })).await;
+        let b = spawn_backend(axum::Router::new().fallback(|| async { "b" })).await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(String::new())
+                .with_backends(vec![a, b])
+                .with_transform_request(move |request| {
+                    let origin = if request.path_and_query().contains("refused") {
+                        &dead
+                    } else {
+                        &truncating_url
+                    };
+                    request.replace_origin(origin).unwrap();
+                }),
+        );
+        for path in ["/refused", "/truncated", "/refused", "/truncated"] {
+            let response = send(&proxy, get(path)).await;
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{}", path);
+        }
+        assert!(handle
+            .backends()
+            .iter()
+            .all(|backend| !backend.cooling_down()));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_stays_on_one_backend() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let backends = vec![
+            spawn_upgrade_echo_backend().await,
+            spawn_upgrade_echo_backend().await,
+        ];
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(String::new()).with_backends(backends),
+        );
+        let mut client = h2_client_for(proxy).await;
+        let mut response = client
+            .send_request(extended_connect("websocket"))
+            .await
+            .unwrap();
+        let mut io = TokioIo::new(hyper::upgrade::on(&mut response).await.unwrap());
+        let in_flight = || -> Vec<usize> {
+            handle
+                .backends()
+                .iter()
+                .map(|backend| backend.in_flight())
+                .collect()
+        };
+        let held = in_flight();
+        assert_eq!(held.iter().sum::<usize>(), 1);
+
+        for _ in 0..3 {
+            io.write_all(b"ping").await.unwrap();
+            let mut echoed = [0u8; 4];
+            io.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"ping");
+            assert_eq!(in_flight(), held);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_h2_extended_connect_rejects_unknown_protocol() {
+        let backend = spawn_upgrade_echo_backend().await;
+        let (proxy, _handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let mut client = h2_client_for(proxy).await;
+
+        let response = client
+            .send_request(extended_connect("connect-udp"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_decision_memo_skips_repeat_inspection() {
+        use crate::test_support::{get, send, spawn_backend};
+
+        // An encoding the proxy cannot decode, so the body is not cacheable.
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            (
+                [
+                    (axum::http::header::CONTENT_TYPE, "image/png"),
+                    (axum::http::header::CONTENT_ENCODING, "x-unknown"),
+                ],
+                "png",
+            )
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+
+        for _ in 0..3 {
+            let response = send(&proxy, get("/logo.png")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(BODY_INSPECTIONS.with(|count| count.get()), 1);
+        assert_eq!(handle.cache().decision_memo().hits(), 2);
+
+        // A purge forgets the verdict, so the next request is inspected again.
+        handle.cache().clear_by_pattern("GET:/logo*").await;
+        send(&proxy, get("/logo.png")).await;
+        assert_eq!(BODY_INSPECTIONS.with(|count| count.get()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_accept_mismatch_bypasses_or_rejects_cached_html() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(
+            |headers: HeaderMap| async move {
+                if headers
+                    .get("accept")
+                    .is_some_and(|value| value == "application/json")
+                {
+                    (
+                        [(axum::http::header::CONTENT_TYPE, "application/json")],
+                        "{}",
+                    )
+                } else {
+                    (
+                        [(axum::http::header::CONTENT_TYPE, "text/html")],
+                        "<p>hi</p>",
+                    )
+                }
+            },
+        ))
+        .await;
+        let json_get = || {
+            Request::builder()
+                .uri("/page")
+                .header("accept", "application/json")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone()).with_accept_mismatch(AcceptMismatch::Bypass),
+        );
+        send(&proxy, get("/page")).await;
+        let response = send(&proxy, json_get()).await;
+        assert_eq!(body_string(response).await, "{}");
+        // The negotiated answer does not replace the cached page.
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(body_string(response).await, "<p>hi</p>");
+        assert_eq!(handle.stats().totals().bypasses, 1);
+
+        let (proxy, _) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_accept_mismatch(AcceptMismatch::Reject),
+        );
+        send(&proxy, get("/page")).await;
+        let response = send(&proxy, json_get()).await;
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_cache_key_fn_bypasses_cache() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async { "fresh" })).await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cache_key_fn(|req_info| {
+                if req_info.path == "/boom" {
+                    panic!("unexpected header");
+                }
+                req_info.path.to_string()
+            }),
+        );
+
+        let response = send(&proxy, get("/boom")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "fresh");
+        assert_eq!(handle.callback_panics(), 1);
+        assert_eq!(handle.cache().size().await, 0);
+        assert_eq!(handle.stats().totals().bypasses, 1);
+
+        // Other requests keep caching normally.
+        send(&proxy, get("/ok")).await;
+        assert_eq!(handle.cache().size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_opt_fn_none_never_reaches_the_cache() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            async move { format!("call {call}") }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_cache_404_capacity(10)
+                .with_cache_key_opt_fn(|req_info| {
+                    (!req_info.headers.contains_key("authorization"))
+                        .then(|| format!("{}:{}", req_info.method, req_info.path))
+                }),
+        );
+        let authorized = || {
+            Request::builder()
+                .uri("/account")
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for expected in ["call 0", "call 1"] {
+            let response = send(&proxy, authorized()).await;
+            assert_eq!(response.headers()["x-cache"], "BYPASS");
+            assert_eq!(body_string(response).await, expected);
+        }
+        assert!(handle.cache().keys().is_empty());
+        assert_eq!(handle.callback_panics(), 0);
+
+        // Without the header the same path is keyed and cached.
+        send(&proxy, get("/account")).await;
+        let response = send(&proxy, get("/account")).await;
+        assert_eq!(body_string(response).await, "call 2");
+        let keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        assert_eq!(keys, ["GET:/account"]);
+        let response = send(&proxy, authorized()).await;
+        assert_eq!(body_string(response).await, "call 3");
+    }
+
+    #[tokio::test]
+    async fn test_async_cache_key_fn_replaces_the_sync_one() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            async move { format!("call {call}") }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_cache_key_fn(|_req_info| "unused".to_string())
+                .with_async_cache_key_fn(|req_info| {
+                    Box::pin(async move {
+                        tokio::task::yield_now().await;
+                        match req_info.headers.get("x-tenant") {
+                            Some(tenant) if tenant == "boom" => panic!("lookup failed"),
+                            Some(tenant) => {
+                                Some(format!("{}:{}", tenant.to_str().unwrap(), req_info.path))
+                            }
+                            None => None,
+                        }
+                    })
+                }),
+        );
+        let tenant = |name: &'static str| {
+            Request::builder()
+                .uri("/home")
+                .header("x-tenant", name)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        send(&proxy, tenant("acme")).await;
+        let response = send(&proxy, tenant("acme")).await;
+        assert_eq!(body_string(response).await, "call 0");
+        let keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        assert_eq!(keys, ["acme:/home"]);
+
+        // No key, or a panic after the first poll, bypasses the cache.
+        let response = send(&proxy, get("/home")).await;
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        let response = send(&proxy, tenant("boom")).await;
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert_eq!(body_string(response).await, "call 2");
+        assert_eq!(handle.callback_panics(), 1);
+        assert_eq!(handle.cache().keys().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_colliding_cache_keys_are_caught_on_hit() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(
+            axum::Router::new()
+                .fallback(|uri: axum::http::Uri| async move { uri.path().to_string() }),
+        )
+        .await;
+        let colliding = |config: CreateProxyConfig| {
+            config.with_cache_key_fn(|_req_info| "GET:/same".to_string())
+        };
+
+        let (proxy, handle) =
+            crate::create_proxy_handle(colliding(CreateProxyConfig::new(backend.clone())));
+        assert_eq!(body_string(send(&proxy, get("/a")).await).await, "/a");
+        // Same key, different path: not served /a's page.
+        assert_eq!(body_string(send(&proxy, get("/b")).await).await, "/b");
+        assert_eq!(handle.cache_origin_mismatches(), 1);
+        assert_eq!(handle.cache().size().await, 1);
+        assert_eq!(
+            handle.cache().get("GET:/same").await.unwrap().origin,
+            Some(CacheOrigin::new("GET", "/b"))
+        );
+        // The entry now belongs to /b and is a plain hit for it.
+        assert_eq!(body_string(send(&proxy, get("/b")).await).await, "/b");
+        assert_eq!(handle.cache_origin_mismatches(), 1);
+        assert_eq!(handle.stats().totals().hits, 1);
+
+        // With the check off the collision goes unnoticed.
+        let (proxy, handle) = crate::create_proxy_handle(colliding(
+            CreateProxyConfig::new(backend).with_verify_cache_origin(false),
+        ));
+        send(&proxy, get("/a")).await;
+        assert_eq!(body_string(send(&proxy, get("/b")).await).await, "/a");
+        assert_eq!(handle.cache_origin_mismatches(), 0);
+        assert_eq!(handle.cache().get("GET:/same").await.unwrap().origin, None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_error_stops_serving_after_ttl_once_backend_recovers() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::AtomicBool;
+
+        let healthy = Arc::new(AtomicBool::new(false));
+        let backend_healthy = healthy.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let healthy = backend_healthy.load(Ordering::SeqCst);
+            async move {
+                if healthy {
+                    (StatusCode::OK, "recovered")
+                } else {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "crashed")
+                }
+            }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cache_errors_ttl_secs(1),
+        );
+
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(handle.cache().size().await, 0);
+        assert_eq!(handle.cache().size_error().await, 1);
+
+        // The backend recovers, but the error is still shielded until the TTL lapses.
+        healthy.store(true, Ordering::SeqCst);
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(body_string(response).await, "crashed");
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "recovered");
+        assert_eq!(handle.cache().size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_path_spellings_share_one_cache_entry_and_backend_path() {
+        use crate::test_support::{get, send, spawn_backend};
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let backend_seen = seen.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move |uri: axum::http::Uri| {
+            backend_seen.lock().unwrap().push(uri.to_string());
+            async { "page" }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_include_paths(vec!["/café*".to_string()]),
+        );
+
+        let raw_utf8 = Request::builder()
+            .uri(axum::http::Uri::from_maybe_shared("/café?q=thé".as_bytes()).unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&proxy, raw_utf8).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for spelling in [
+            "/caf%c3%a9?q=th%c3%a9",
+            "/caf%C3%A9?q=th%C3%A9",
+            "/%63af%C3%a9?q=th%C3%A9",
+        ] {
+            let response = send(&proxy, get(spelling)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        assert_eq!(*seen.lock().unwrap(), vec!["/caf%C3%A9?q=th%C3%A9"]);
+        assert_eq!(handle.cache().size().await, 1);
+
+        handle.cache().clear_by_pattern("GET:/café*").await;
+        assert_eq!(handle.cache().size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_should_cache_fn_overrides_patterns_both_ways() {
+        use crate::test_support::{send, spawn_backend};
+        use crate::CacheDecision;
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async { "page" })).await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_exclude_paths(vec!["/private/*".to_string()])
+                .with_should_cache_fn(|req| {
+                    let header = |name: &str| {
+                        req.headers
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or("")
+                    };
+                    if header("x-panic") == "1" {
+                        panic!("boom");
+                    }
+                    if header("cookie").contains("session=") {
+                        CacheDecision::Bypass
+                    } else if header("x-prerender") == "1" {
+                        CacheDecision::Cache
+                    } else {
+                        CacheDecision::Default
+                    }
+                }),
+        );
+        let x_cache = |path: &'static str, header: Option<(&'static str, &'static str)>| {
+            let proxy = proxy.clone();
+            async move {
+                let mut request = Request::builder().uri(path);
+                if let Some((name, value)) = header {
+                    request = request.header(name, value);
+                }
+                let response = send(&proxy, request.body(Body::empty()).unwrap()).await;
+                response.headers()["x-cache"].to_str().unwrap().to_string()
+            }
+        };
+
+        // Default leaves it to the patterns.
+        assert_eq!(x_cache("/page", None).await, "MISS");
+        assert_eq!(x_cache("/page", None).await, "HIT");
+        assert_eq!(x_cache("/private/a", None).await, "BYPASS");
+
+        // A session cookie bypasses even a cached path.
+        let session = Some(("cookie", "theme=dark; session=abc"));
+        assert_eq!(x_cache("/page", session).await, "BYPASS");
+        assert_eq!(x_cache("/other", session).await, "BYPASS");
+
+        // The closure can cache what the patterns exclude.
+        let prerender = Some(("x-prerender", "1"));
+        assert_eq!(x_cache("/private/a", prerender).await, "MISS");
+        assert_eq!(x_cache("/private/a", prerender).await, "HIT");
+
+        assert_eq!(x_cache("/page", Some(("x-panic", "1"))).await, "BYPASS");
+        assert_eq!(handle.callback_panics(), 1);
+        assert_eq!(handle.cache().size().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_sections_decide_caching_for_the_same_path() {
+        use crate::test_support::{get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async { "results" })).await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_path_rules(vec![
+                    "/search".to_string(),
+                    "!/search?*page=*".to_string(),
+                    "!/search?*preview=true*".to_string(),
+                ])
+                .with_cache_rules(vec![crate::cache_rules::CacheRule::never("/search?debug")]),
+        );
+        let x_cache = |target: &'static str| {
+            let proxy = proxy.clone();
+            async move {
+                let response = send(&proxy, get(target)).await;
+                response.headers()["x-cache"].to_str().unwrap().to_string()
+            }
+        };
+
+        for target in ["/search?q=shoes", "/search", "/search?q=caf%C3%A9"] {
+            assert_eq!(x_cache(target).await, "MISS", "{target}");
+            assert_eq!(x_cache(target).await, "HIT", "{target}");
+        }
+        for target in [
+            "/search?q=shoes&page=2",
+            "/search?q=shoes&preview=true",
+            "/search?debug",
+        ] {
+            assert_eq!(x_cache(target).await, "BYPASS", "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logs_redact_query_and_headers_but_backend_gets_real_values() {
+        use crate::test_support::{capture_logs, send, spawn_backend};
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let backend_seen = seen.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(
+            move |uri: axum::http::Uri, headers: HeaderMap| {
+                backend_seen.lock().unwrap().push((
+                    uri.to_string(),
+                    headers["authorization"].to_str().unwrap().to_string(),
+                ));
+                async { "page" }
+            },
+        ))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_bypass_credentialed_requests(false),
+        );
+
+        let (logs, _guard) = capture_logs();
+        let request = Request::builder()
+            .uri("/login?next=/home&token=abc123")
+            .header("authorization", "Bearer s3cret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&proxy, request).await.status(), StatusCode::OK);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                "/login?next=/home&token=abc123".to_string(),
+                "Bearer s3cret".to_string()
+            )]
+        );
+        assert!(handle
+            .cache()
+            .get("GET:/login?next=/home&token=abc123")
+            .await
+            .is_some());
+
+        let logs = logs.contents();
+        assert!(
+            logs.contains("GET:/login?next=/home&token=[redacted]"),
+            "{}",
+            logs
+        );
+        assert!(
+            logs.contains(r#""authorization": "[redacted]""#),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("abc123"), "{}", logs);
+        assert!(!logs.contains("s3cret"), "{}", logs);
+    }
+
+    /// HTTP/1.1 backend whose responses announce an `x-checksum` trailer and
+    /// end with it.
+    async fn spawn_trailer_backend() -> String {
+        use http_body_util::channel::Channel;
+        use hyper::service::service_fn;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let service = service_fn(|_req: Request<hyper::body::Incoming>| async {
+                        let (mut sender, body) = Channel::<axum::body::Bytes>::new(2);
+                        tokio::spawn(async move {
+                            sender.send_data("chunk one ".into()).await.unwrap();
+                            sender.send_data("chunk two".into()).await.unwrap();
+                            let mut trailers = HeaderMap::new();
+                            trailers.insert("x-checksum", HeaderValue::from_static("abc123"));
+                            sender.send_trailers(trailers).await.unwrap();
+                        });
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header("content-type", "text/html")
+                            .header("trailer", "x-checksum")
+                            .body(body)
+                    });
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                        .ok();
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Backend that writes one chunked response with a trailer field but no
+    /// `Trailer` header. hyper's server only sends announced trailers, so the
+    /// response is written by hand.
+    async fn spawn_unannounced_trailer_backend() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      content-type: text/html\r\n\
+                      transfer-encoding: chunked\r\n\
+                      connection: close\r\n\r\n\
+                      a\r\nchunk one \r\n\
+                      9\r\nchunk two\r\n\
+                      0\r\nx-checksum: abc123\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_announced_trailers_are_streamed_through_uncached() {
+        use http_body_util::BodyExt;
+
+        let backend = spawn_trailer_backend().await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+
+        for _ in 0..2 {
+            // `TE: trailers` is passed on and lets the backend send them.
+            let request = Request::builder()
+                .uri("/report")
+                .header("te", "trailers")
+                .body(Body::empty())
+                .unwrap();
+            let response = crate::test_support::send(&proxy, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["trailer"], "x-checksum");
+            assert!(!response.headers().contains_key("content-length"));
+
+            let collected = response.into_body().collect().await.unwrap();
+            assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc123");
+            assert_eq!(collected.to_bytes(), "chunk one chunk two");
+        }
+        assert_eq!(handle.cache().size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unannounced_trailers_are_forwarded_uncached() {
+        use http_body_util::BodyExt;
+
+        let backend = spawn_unannounced_trailer_backend().await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+
+        let response = crate::test_support::send(&proxy, crate::test_support::get("/report")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let collected = response.into_body().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc123");
+        assert_eq!(collected.to_bytes(), "chunk one chunk two");
+        assert_eq!(handle.cache().size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_uncacheable_responses_are_streamed() {
+        use http_body_util::BodyExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const CHUNK: usize = 1024 * 1024;
+        let release = Arc::new(tokio::sync::Notify::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let backend_release = release.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      content-type: application/octet-stream\r\n\
+                      transfer-encoding: chunked\r\n\
+                      connection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let chunk = format!("{:x}\r\n{}\r\n", CHUNK, "x".repeat(CHUNK));
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+            // The rest only follows once the client has seen the first bytes.
+            backend_release.notified().await;
+            for _ in 0..3 {
+                stream.write_all(chunk.as_bytes()).await.unwrap();
+            }
+            stream.write_all(b"0\r\n\r\n").await.unwrap();
+        });
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_exclude_paths(vec!["/download".to_string()]),
+        );
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            crate::test_support::send(&proxy, crate::test_support::get("/download")),
+        )
+        .await
+        .expect("headers are forwarded before the backend finishes");
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let first = tokio::time::timeout(Duration::from_secs(5), body.frame())
+            .await
+            .expect("first bytes are forwarded before the backend finishes")
+            .unwrap()
+            .unwrap()
+            .into_data()
+            .unwrap();
+        assert!(!first.is_empty());
+
+        release.notify_one();
+        let rest = body.collect().await.unwrap().to_bytes();
+        assert_eq!(first.len() + rest.len(), 4 * CHUNK);
+        assert_eq!(handle.cache().size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_html_normalization_makes_noisy_pages_identical() {
+        use crate::html_normalize::{HtmlNormalizeRule, HtmlNormalizer};
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let renders = Arc::new(AtomicUsize::new(0));
+        let backend_renders = renders.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let render = backend_renders.fetch_add(1, Ordering::SeqCst);
+            async move {
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/html")],
+                    format!(
+                        "<html><head><meta name=\"csrf-token\" content=\"tok{render}\"></head>\
+                         <body>same<!-- rendered at 12:00:0{render} --></body></html>"
+                    ),
+                )
+            }
+        }))
+        .await;
+        let normalizer = HtmlNormalizer::new(&[
+            HtmlNormalizeRule::new(r#"(<meta name="csrf-token" content=")[^"]*""#, r#"${1}""#),
+            HtmlNormalizeRule::new(r"<!-- rendered at [^>]* -->", ""),
+        ])
+        .unwrap();
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_html_normalizer(normalizer)
+                .with_html_normalize_paths(vec!["/pages/*".to_string()]),
+        );
+
+        let expected = "<html><head><meta name=\"csrf-token\" content=\"\"></head>\
+                        <body>same</body></html>";
+        for path in ["/pages/a", "/pages/b"] {
+            let response = send(&proxy, crate::test_support::get(path)).await;
+            assert_eq!(body_string(response).await, expected);
+        }
+        let first = handle.cache().get("GET:/pages/a").await.unwrap();
+        let second = handle.cache().get("GET:/pages/b").await.unwrap();
+        assert_eq!(first.body, second.body);
+        assert_eq!(
+            first.content_digest.as_deref(),
+            Some(crate::cache::content_digest(expected.as_bytes()).as_str())
+        );
+        assert_eq!(first.content_digest, second.content_digest);
+
+        // Paths outside `html_normalize_paths` are stored as received.
+        let response = send(&proxy, crate::test_support::get("/other")).await;
+        assert!(body_string(response).await.contains("content=\"tok2\""));
+        let other = handle.cache().get("GET:/other").await.unwrap();
+        assert!(other.content_digest.is_none());
+        assert_eq!(renders.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_queries_are_cached_by_body_and_mutations_are_not() {
+        use crate::body_cache::{BodyCacheRule, BodyKey};
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move |body: String| {
+            let call = backend_calls.fetch_add(1, Ordering::SeqCst);
+            async move { format!("{call}:{body}") }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_body_cache_rules(vec![
+                    BodyCacheRule::graphql("POST /graphql").with_ttl_secs(60),
+                    BodyCacheRule::new("POST /search", BodyKey::Hash),
+                ])
+                .with_body_cache_skip_fn(|_, body| body.starts_with(b"private")),
+        );
+        let post = |uri: &str, body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let users = r#"{"query":"query Users { users { id } }","operationName":"Users"}"#;
+        let users_again =
+            r#"{ "operationName": "Users", "query": "query Users { users { id } }" }"#;
+        let posts = r#"{"query":"query Posts { posts { id } }","operationName":"Posts"}"#;
+        let logout = r#"{"query":"mutation Logout { logout }","operationName":"Logout"}"#;
+
+        // Identical queries share an entry, even when formatted differently.
+        let first = body_string(send(&proxy, post("/graphql", users)).await).await;
+        assert!(first.starts_with("0:"), "{}", first);
+        let again = body_string(send(&proxy, post("/graphql", users_again)).await).await;
+        assert_eq!(again, first);
+
+        // Distinct queries do not.
+        let other = body_string(send(&proxy, post("/graphql", posts)).await).await;
+        assert_eq!(other, format!("1:{posts}"));
+
+        // Mutations always reach the backend and are never stored.
+        for expected in ["2:", "3:"] {
+            let response = body_string(send(&proxy, post("/graphql", logout)).await).await;
+            assert!(response.starts_with(expected), "{}", response);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(handle.cache().size().await, 2);
+
+        // Raw-body rules, and the callback refusing some bodies.
+        send(&proxy, post("/search", "q=rust")).await;
+        send(&proxy, post("/search", "q=rust")).await;
+        send(&proxy, post("/search", "private q=rust")).await;
+        send(&proxy, post("/search", "private q=rust")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 7);
+        assert_eq!(handle.cache().size().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_rules_take_precedence_in_order_with_ttl_and_key_headers() {
+        use crate::cache_rules::CacheRule;
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let call = backend_calls.fetch_add(1, Ordering::SeqCst);
+            async move { call.to_string() }
+        }))
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_include_paths(vec!["/static/*".to_string()])
+                .with_exclude_paths(vec!["/api/*".to_string()])
+                .with_cache_rules(vec![
+                    CacheRule::never("/api/private/*"),
+                    CacheRule::new("/api/*")
+                        .with_ttl_secs(1)
+                        .with_key_headers(vec!["X-Tenant".to_string()]),
+                    CacheRule::new("/api/*").with_ttl_secs(3600),
+                ]),
+        );
+        let fetch = |path: &'static str, tenant: &'static str| {
+            let proxy = proxy.clone();
+            async move {
+                let request = Request::builder()
+                    .uri(path)
+                    .header("x-tenant", tenant)
+                    .body(Body::empty())
+                    .unwrap();
+                body_string(send(&proxy, request).await).await
+            }
+        };
+
+        // The earlier rule keeps private paths out despite the broader one.
+        assert_eq!(fetch("/api/private/me", "a").await, "0");
+        assert_eq!(fetch("/api/private/me", "a").await, "1");
+
+        // A rule overrides `exclude_paths` and keys entries on the tenant.
+        assert_eq!(fetch("/api/users", "a").await, "2");
+        assert_eq!(fetch("/api/users", "a").await, "2");
+        assert_eq!(fetch("/api/users", "b").await, "3");
+
+        // Without a matching rule, `include_paths` still applies.
+        assert_eq!(fetch("/about", "a").await, "4");
+        assert_eq!(fetch("/about", "a").await, "5");
+        assert_eq!(fetch("/static/app.js", "a").await, "6");
+        assert_eq!(fetch("/static/app.js", "a").await, "6");
+
+        // The first matching rule's TTL applies, not the later, longer one.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(fetch("/api/users", "a").await, "7");
+    }
+
+    #[tokio::test]
+    async fn test_nested_router_forwards_the_path_below_its_mount() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/login",
+                    axum::routing::get(|| async { axum::response::Redirect::to("/account") }),
+                )
+                .fallback(|uri: axum::http::Uri| async move { format!("backend {}", uri) }),
+        )
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone()).with_rewrite_location(true),
+        );
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "own" }))
+            .nest("/app", proxy);
+
+        assert_eq!(body_string(send(&app, get("/")).await).await, "own");
+        let response = send(&app, get("/app/page?x=1")).await;
+        assert_eq!(body_string(response).await, "backend /page?x=1");
+        let response = send(&app, get("/app")).await;
+        assert_eq!(body_string(response).await, "backend /");
+        let response = send(&app, get("/app/login")).await;
+        assert_eq!(response.headers()["location"], "/app/account");
+        let mut keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        keys.sort();
+        assert_eq!(keys, ["GET:/", "GET:/login", "GET:/page?x=1"]);
+
+        // Mounted without `nest`, `path_prefix` does the stripping.
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_path_prefix("/app/")
+                .with_rewrite_location(true),
+        );
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "own" }))
+            .fallback_service(proxy);
+
+        let response = send(&app, get("/app/page?x=1")).await;
+        assert_eq!(body_string(response).await, "backend /page?x=1");
+        let response = send(&app, get("/app")).await;
+        assert_eq!(body_string(response).await, "backend /");
+        let response = send(&app, get("/app/login")).await;
+        assert_eq!(response.headers()["location"], "/app/account");
+        assert_eq!(
+            send(&app, get("/application")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(&app, get("/page")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ignored_query_params_share_one_entry_and_reach_the_backend() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move |uri: axum::http::Uri| {
+            backend_calls.fetch_add(1, Ordering::SeqCst);
+            async move { uri.query().unwrap_or("").to_string() }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone())
+                .with_ignored_query_params(vec!["utm_source".to_string(), "gclid".to_string()]),
+        );
+
+        let response = send(&proxy, get("/page?id=1&utm_source=mail")).await;
+        assert_eq!(body_string(response).await, "id=1&utm_source=mail");
+        let response = send(&proxy, get("/page?id=1&gclid=abc")).await;
+        assert_eq!(body_string(response).await, "id=1&utm_source=mail");
+        send(&proxy, get("/page?id=2")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let mut keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        keys.sort();
+        assert_eq!(keys, ["GET:/page?id=1", "GET:/page?id=2"]);
+
+        // An allowlist drops every other parameter from the key.
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_allowed_query_params(vec!["id".to_string()]),
+        );
+        send(&proxy, get("/page?session=x&id=1")).await;
+        send(&proxy, get("/page?id=1&debug")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        let keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        assert_eq!(keys, ["GET:/page?id=1"]);
+    }
+
+    #[tokio::test]
+    async fn test_transform_hooks_rewrite_cold_and_warm_html_responses() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/page",
+                    axum::routing::get(move || {
+                        backend_calls.fetch_add(1, Ordering::SeqCst);
+                        async { axum::response::Html("<body>page</body>") }
+                    }),
+                )
+                .route(
+                    "/plain",
+                    axum::routing::get(|| async { "<body>plain</body>" }),
+                ),
+        )
+        .await;
+        let inject = |marker: &'static str| {
+            move |_: &crate::RequestInfo, mut response: CachedResponse| {
+                let body = String::from_utf8_lossy(&response.body)
+                    .replace("</body>", &format!("{}</body>", marker));
+                response.body = body.into_bytes().into();
+                response
+            }
+        };
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_transform_response(inject("<script>stored</script>"))
+                .with_transform_on_serve(inject("<!--served-->")),
+        );
+
+        let expected = "<body>page<script>stored</script><!--served--></body>";
+        for _ in 0..2 {
+            let response = send(&proxy, get("/page")).await;
+            let content_length = response.headers()[axum::http::header::CONTENT_LENGTH].clone();
+            assert_eq!(content_length, expected.len().to_string().as_str());
+            assert_eq!(body_string(response).await, expected);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let stored = handle.cache().get("GET:/page").await.unwrap();
+        let stored = crate::compression::decode_upstream_body_async(
+            stored.body.to_vec(),
+            stored
+                .content_encoding
+                .map(|e| e.as_header_value().to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stored, b"<body>page<script>stored</script></body>");
+
+        // Other content types are left alone.
+        let response = send(&proxy, get("/plain")).await;
+        assert_eq!(body_string(response).await, "<body>plain</body>");
+    }
+
+    #[tokio::test]
+    async fn test_transform_request_changes_the_backend_request_but_not_the_key() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let echo = |name: &'static str| {
+            axum::Router::new().fallback(
+                move |uri: axum::http::Uri, headers: HeaderMap| async move {
+                    let auth = headers
+                        .get("x-internal-auth")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("none")
+                        .to_string();
+                    format!("{} {} {}", name, uri, auth)
+                },
+            )
+        };
+        let backend = spawn_backend(echo("backend")).await;
+        let other = spawn_backend(echo("other")).await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_transform_request(move |request| {
+                request
+                    .headers_mut()
+                    .insert("x-internal-auth", HeaderValue::from_static("secret"));
+                let path = format!("/internal{}", request.path_and_query());
+                request.set_path_and_query(path).unwrap();
+                if request.path_and_query().contains("elsewhere") {
+                    request.replace_origin(&other).unwrap();
+                }
+                if request.path_and_query().contains("escape") {
+                    let refused = request.set_path_and_query("@evil.example/");
+                    assert!(refused.is_err());
+                }
+                if request.path_and_query().contains("typo") {
+                    request.replace_origin("htp:/other").unwrap();
+                }
+            }),
+        );
+
+        let response = send(&proxy, get("/page?id=1")).await;
+        assert_eq!(
+            body_string(response).await,
+            "backend /internal/page?id=1 secret"
+        );
+        let keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        assert_eq!(keys, ["GET:/page?id=1"]);
+
+        let response = send(&proxy, get("/elsewhere")).await;
+        assert_eq!(
+            body_string(response).await,
+            "other /internal/elsewhere secret"
+        );
+
+        // A refused path leaves the request as it was.
+        let response = send(&proxy, get("/escape")).await;
+        assert_eq!(
+            body_string(response).await,
+            "backend /internal/escape secret"
+        );
+        assert_eq!(handle.callback_panics(), 0);
+
+        // A hook that panics on the error is not forwarded.
+        let response = send(&proxy, get("/typo")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(handle.callback_panics(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_headers_are_not_forwarded() {
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::Mutex;
+
+        let backend = spawn_backend(axum::Router::new().fallback(
+            |headers: HeaderMap| async move {
+                let mut lines: Vec<String> = headers
+                    .iter()
+                    .filter(|(name, _)| {
+                        !matches!(name.as_str(), "host" | "accept" | "accept-encoding")
+                    })
+                    .map(|(name, value)| format!("{}={}", name, value.to_str().unwrap()))
+                    .collect();
+                lines.sort();
+                lines.join("\n")
+            },
+        ))
+        .await;
+        let seen_by_hook = Arc::new(Mutex::new(Vec::new()));
+        let seen = seen_by_hook.clone();
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_transform_request(move |request| {
+                let mut names: Vec<String> = request
+                    .headers()
+                    .keys()
+                    .map(|name| name.to_string())
+                    .collect();
+                names.sort();
+                names.dedup();
+                *seen.lock().unwrap() = names;
+            }),
+        );
+
+        let request = Request::builder()
+            .uri("/page")
+            .header("connection", "keep-alive, x-hop")
+            .header("keep-alive", "timeout=5")
+            .header("x-hop", "1")
+            .header("te", "trailers, deflate")
+            .header("trailer", "x-checksum")
+            .header("proxy-connection", "keep-alive")
+            .header("proxy-authorization", "Basic c2VjcmV0")
+            .header("x-multi", "a")
+            .header("x-multi", "b")
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&proxy, request).await;
+        assert_eq!(
+            body_string(response).await,
+            "cookie=a=1; b=2\nte=trailers\nx-forwarded-proto=http\nx-multi=a\nx-multi=b"
+        );
+        assert_eq!(
+            *seen_by_hook.lock().unwrap(),
+            ["cookie", "te", "x-forwarded-proto", "x-multi"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_report_hits_misses_stores_bypasses_and_backend_errors() {
+        use crate::events::ProxyEvents;
+        use crate::test_support::{get, send, spawn_backend};
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl ProxyEvents for Recorder {
+            fn on_hit(&self, key: &str, _age: Option<Duration>) {
+                self.0.lock().unwrap().push(format!("hit {}", key));
+            }
+            fn on_miss(&self, key: &str) {
+                self.0.lock().unwrap().push(format!("miss {}", key));
+            }
+            fn on_store(&self, key: &str, _size: usize, status: u16) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("store {} {}", key, status));
+            }
+            fn on_bypass(&self, path: &str, reason: BypassReason) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("bypass {} {:?}", path, reason));
+            }
+            fn on_backend_error(&self, path: &str, _error: &(dyn std::error::Error + 'static)) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("backend error {}", path));
+            }
+        }
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async { "page" })).await;
+        let events = Arc::new(Recorder::default());
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_exclude_paths(vec!["/admin/*".to_string()])
+                .with_events(events.clone()),
+        );
+        send(&proxy, get("/page")).await;
+        send(&proxy, get("/page")).await;
+        send(&proxy, get("/admin/users")).await;
+
+        let (unreachable, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string()).with_events(events.clone()),
+        );
+        send(&unreachable, get("/down")).await;
+
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                "miss GET:/page",
+                "store GET:/page 200",
+                "hit GET:/page",
+                "bypass /admin/users Filtered",
+                "miss GET:/down",
+                "backend error /down",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handler_on_a_custom_router_with_user_routes() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+
+        let backend = spawn_backend(
+            axum::Router::new()
+                .fallback(|uri: axum::http::Uri| async move { format!("backend {}", uri) }),
+        )
+        .await;
+        let (_router, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let app = axum::Router::new()
+            .route("/mine", axum::routing::get(|| async { "user route" }))
+            .fallback(proxy_handler)
+            .with_state(handle.proxy_state());
+
+        let response = send(&app, get("/mine")).await;
+        assert_eq!(body_string(response).await, "user route");
+        let response = send(&app, get("/page")).await;
+        assert_eq!(body_string(response).await, "backend /page");
+        assert_eq!(handle.cache().size().await, 1);
+
+        // The old `Extension` wiring still works.
+        #[allow(deprecated)]
+        let app = axum::Router::new()
+            .fallback(extension_proxy_handler)
+            .layer(Extension(handle.proxy_state()));
+        let response = send(&app, get("/page")).await;
+        assert_eq!(body_string(response).await, "backend /page");
+        assert_eq!(handle.stats().totals().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_headers_split_entries_and_still_purge_by_path() {
+        use crate::cache_rules::CacheRule;
+        use crate::path_matcher::WildcardSyntax;
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let call = backend_calls.fetch_add(1, Ordering::SeqCst);
+            async move { call.to_string() }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_wildcard_syntax(WildcardSyntax::Segments)
+                .with_cache_key_headers(vec!["Accept-Language".to_string()])
+                .with_cache_rules(vec![
+                    CacheRule::new("/api/*").with_key_headers(vec!["X-Tenant".to_string()])
+                ]),
+        );
+        let fetch = |path: &'static str, language: Option<&'static str>| {
+            let proxy = proxy.clone();
+            async move {
+                let mut request = Request::builder().uri(path);
+                if let Some(language) = language {
+                    request = request.header("accept-language", language);
+                }
+                let request = request
+                    .header("x-tenant", "acme/eu")
+                    .body(Body::empty())
+                    .unwrap();
+                body_string(send(&proxy, request).await).await
+            }
+        };
+
+        assert_eq!(fetch("/about", Some("en")).await, "0");
+        assert_eq!(fetch("/about", Some("en")).await, "0");
+        assert_eq!(fetch("/about", Some("de")).await, "1");
+        assert_eq!(fetch("/about", None).await, "2");
+        assert_eq!(fetch("/api/users", Some("en")).await, "3");
+
+        let mut keys: Vec<String> = handle.cache().keys().into_iter().map(|k| k.key).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "GET:/about#headers=accept-language=",
+                "GET:/about#headers=accept-language=de",
+                "GET:/about#headers=accept-language=en",
+                "GET:/api/users#headers=accept-language=en&x-tenant=acme/eu",
+            ]
+        );
+
+        // The `/` in the header value does not stop `*` from matching.
+        assert_eq!(handle.cache().clear_by_pattern("GET:/api/*").await, 1);
+        assert_eq!(handle.cache().clear_by_pattern("GET:/about").await, 3);
+        assert!(handle.cache().keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_auth_rotates_tokens_and_keeps_them_out_of_logs_and_cache() {
+        use crate::test_support::{body_string, capture_logs, get, send, spawn_backend};
+        use crate::upstream_auth::ClientCredentials;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // The first token expires immediately, so the second request rotates.
+        let issued = Arc::new(AtomicUsize::new(0));
+        let token_issued = issued.clone();
+        let auth_server = spawn_backend(axum::Router::new().route(
+            "/token",
+            axum::routing::post(move || {
+                let n = token_issued.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    axum::Json(serde_json::json!({
+                        "access_token": format!("tok-{n}"),
+                        "token_type": "bearer",
+                        "expires_in": if n == 0 { 0 } else { 3600 },
+                    }))
+                }
+            }),
+        ))
+        .await;
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let backend_seen = seen.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move |headers: HeaderMap| {
+            let auth = headers["authorization"].to_str().unwrap().to_string();
+            backend_seen.lock().unwrap().push(auth);
+            async { "ok" }
+        }))
+        .await;
+        let auth = ClientCredentials::new(format!("{auth_server}/token"), "id", "secret").unwrap();
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone())
+                .with_upstream_auth(Arc::new(auth))
+                .with_bypass_credentialed_requests(false),
+        );
+
+        let (logs, _guard) = capture_logs();
+        for path in ["/a", "/b", "/c"] {
+            let request = Request::builder()
+                .uri(path)
+                .header("authorization", "Bearer from-the-client")
+                .body(Body::empty())
+                .unwrap();
+            let response = send(&proxy, request).await;
+            assert_eq!(body_string(response).await, "ok");
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["Bearer tok-0", "Bearer tok-1", "Bearer tok-1"]
+        );
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+
+        let cached = handle.cache().get("GET:/a").await.unwrap();
+        assert!(!cached
+            .headers
+            .iter()
+            .any(|(_, value)| value.contains("tok-")));
+        assert!(!logs.contents().contains("tok-"), "{}", logs.contents());
+
+        // A failing token endpoint fails the request with a distinct 502.
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_upstream_auth(Arc::new(
+                ClientCredentials::new(format!("{auth_server}/missing"), "id", "secret").unwrap(),
+            )),
+        );
+        let response = send(&proxy, get("/a")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body_string(response).await,
+            "upstream authentication failed"
+        );
+        assert_eq!(handle.stats().upstream_auth_failures(), 1);
+    }
+
+    #[cfg(feature = "cassette")]
+    #[tokio::test]
+    async fn test_cassette_records_redacted_and_replays_without_backend() {
+        use crate::cassette::Cassette;
+        use crate::test_support::{body_string, send, spawn_backend};
+
+        let path = std::env::temp_dir().join(format!(
+            "phantom-frame-proxy-cassette-{}.jsonl",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            (
+                [
+                    (axum::http::header::CONTENT_TYPE, "text/html"),
+                    (axum::http::header::SET_COOKIE, "session=s3cret"),
+                ],
+                "<html>recorded</html>",
+            )
+        }))
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cassette(Cassette::record(&path).await.unwrap()),
+        );
+        let request = Request::builder()
+            .uri("/page?token=abc123&lang=en")
+            .header("authorization", "Bearer s3cret")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&proxy, request).await;
+        assert_eq!(body_string(response).await, "<html>recorded</html>");
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(recorded.lines().count(), 1);
+        assert!(
+            recorded.contains("/page?token=[redacted]&lang=en"),
+            "{}",
+            recorded
+        );
+        assert!(!recorded.contains("abc123"), "{}", recorded);
+        assert!(!recorded.contains("s3cret"), "{}", recorded);
+
+        // Replay against a backend that does not exist.
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+                .with_cassette(Cassette::replay(&path).unwrap())
+                .with_skip_set_cookie_responses(false),
+        );
+        let response = send(
+            &proxy,
+            crate::test_support::get("/page?token=other&lang=en"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "<html>recorded</html>");
+        assert_eq!(handle.cache().size().await, 1);
+
+        let response = send(&proxy, crate::test_support::get("/page?lang=de")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_endless_streams_are_passed_through_uncached() {
+        use crate::test_support::spawn_backend;
+        use http_body_util::channel::Channel;
+
+        // Sends one chunk, then holds the response open.
+        let endless = |content_type: &'static str, first: &'static str| {
+            let (mut sender, body) = Channel::<axum::body::Bytes>::new(1);
+            tokio::spawn(async move {
+                sender.send_data(first.into()).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                drop(sender);
+            });
+            (
+                [(axum::http::header::CONTENT_TYPE, content_type)],
+                Body::new(body),
+            )
+        };
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/events",
+                    axum::routing::get(move || async move {
+                        endless("text/event-stream", "data: hello\n\n")
+                    }),
+                )
+                .route(
+                    "/log",
+                    axum::routing::get(move || async move { endless("text/plain", "line one\n") }),
+                ),
+        )
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cache_strategy(crate::CacheStrategy::OnlyHtml),
+        );
+        let url = spawn_backend(proxy).await;
+
+        for (path, first) in [("/events", "data: hello\n\n"), ("/log", "line one\n")] {
+            let first_chunk = async {
+                let mut response = reqwest::get(format!("{url}{path}")).await.unwrap();
+                let chunk = response.chunk().await.unwrap();
+                (response, chunk)
+            };
+            let (mut response, chunk) = tokio::time::timeout(Duration::from_secs(2), first_chunk)
+                .await
+                .unwrap_or_else(|_| panic!("{path} was buffered"));
+            assert_eq!(chunk.as_deref(), Some(first.as_bytes()), "{path}");
+            // The stream is still open.
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), response.chunk())
+                    .await
+                    .is_err(),
+                "{path}"
+            );
+            assert!(handle.cache().get(&format!("GET:{path}")).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_and_response_size_limits() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use http_body_util::channel::Channel;
+
+        let chunked = |parts: &'static [&'static str]| {
+            let (mut sender, body) = Channel::<axum::body::Bytes>::new(1);
+            tokio::spawn(async move {
+                for part in parts {
+                    sender.send_data((*part).into()).await.unwrap();
+                }
+            });
+            Body::new(body)
+        };
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/upload",
+                    axum::routing::post(|body: String| async move { body }),
+                )
+                .route("/exact", axum::routing::get(|| async { "0123456789" }))
+                .route("/over", axum::routing::get(|| async { "0123456789!" }))
+                .route(
+                    "/chunked-exact",
+                    axum::routing::get(move || async move { chunked(&["01234", "56789"]) }),
+                )
+                .route(
+                    "/chunked-over",
+                    axum::routing::get(move || async move { chunked(&["012345", "6789!"]) }),
+                ),
+        )
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_exclude_paths(vec!["POST /upload".to_string()])
+                .with_max_request_body_bytes(8)
+                .with_max_cacheable_response_bytes(10),
+        );
+
+        // Uploads are not cached, so each one reaches the backend.
+        let upload = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/upload")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let response = send(&proxy, upload("12345678")).await;
+        assert_eq!(body_string(response).await, "12345678");
+        let response = send(&proxy, upload("123456789")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        for (path, body, cached) in [
+            ("/exact", "0123456789", true),
+            ("/over", "0123456789!", false),
+            ("/chunked-exact", "0123456789", true),
+            ("/chunked-over", "0123456789!", false),
+        ] {
+            let response = send(&proxy, get(path)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(body_string(response).await, body, "{path}");
+            let key = format!("GET:{path}");
+            assert_eq!(handle.cache().get(&key).await.is_some(), cached, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_ttfb_and_body_time_are_recorded_separately() {
+        use crate::test_support::{body_string, send, spawn_backend};
+        use http_body_util::channel::Channel;
+        use std::time::Duration;
+
+        const DELAY: Duration = Duration::from_millis(300);
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/slow-headers",
+                    axum::routing::get(|| async {
+                        tokio::time::sleep(DELAY).await;
+                        "<html>late</html>"
+                    }),
+                )
+                .route(
+                    "/slow-body",
+                    axum::routing::get(|| async {
+                        let (mut sender, body) = Channel::<axum::body::Bytes>::new(1);
+                        tokio::spawn(async move {
+                            sender.send_data("<html>first ".into()).await.unwrap();
+                            tokio::time::sleep(DELAY).await;
+                            sender.send_data("second</html>".into()).await.unwrap();
+                        });
+                        Body::new(body)
+                    }),
+                ),
+        )
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+
+        let response = send(&proxy, crate::test_support::get("/slow-headers")).await;
+        assert_eq!(body_string(response).await, "<html>late</html>");
+        let ttfb = handle.stats().backend_ttfb();
+        let body = handle.stats().backend_body();
+        assert_eq!((ttfb.count, body.count), (1, 1));
+        assert!(ttfb.sum >= DELAY, "{:?}", ttfb);
+        assert!(body.sum < DELAY, "{:?}", body);
+
+        let response = send(&proxy, crate::test_support::get("/slow-body")).await;
+        assert_eq!(body_string(response).await, "<html>first second</html>");
+        let ttfb = handle.stats().backend_ttfb().sum - ttfb.sum;
+        let body = handle.stats().backend_body().sum - body.sum;
+        assert!(ttfb < DELAY, "{:?}", ttfb);
+        assert!(body >= DELAY, "{:?}", body);
+    }
+
+    fn gratuitous_upgrade_get(uri: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header("connection", "keep-alive, Upgrade")
+            .header("upgrade", "websocket")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_fallback_serves_gratuitous_upgrade_from_cache() {
+        use crate::test_support::{body_string, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let backend_hits = hits.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move |headers: HeaderMap| {
+            let hits = backend_hits.clone();
+            async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                assert!(!headers.contains_key("upgrade"));
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/html")],
+                    "<html>page</html>",
+                )
+            }
+        }))
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_websocket_enabled(false)
+                .with_upgrade_fallback(UpgradeFallback::IgnoreHeader)
+                .with_upgrade_fallback_paths(vec!["GET /pages/*".to_string()])
+                .with_upgrade_reject_status(StatusCode::UPGRADE_REQUIRED),
+        );
+
+        for _ in 0..2 {
+            let response = send(&proxy, gratuitous_upgrade_get("/pages/home")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "<html>page</html>");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Outside the fallback scope the configured reject status is used.
+        let response = send(&proxy, gratuitous_upgrade_get("/chat")).await;
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+    }
+
+    #[test]
+    fn test_strip_upgrade_headers_keeps_other_connection_tokens() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "connection",
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        headers.insert("upgrade", HeaderValue::from_static("websocket"));
+        strip_upgrade_headers(&mut headers);
+        assert!(!headers.contains_key("upgrade"));
+        assert_eq!(headers["connection"], "keep-alive");
+    }
+
+    #[tokio::test]
+    async fn test_cache_error_policy_on_lookup_and_store_failures() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use crate::{CacheErrorPolicy, CacheStorageMode};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend_calls = calls.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            backend_calls.fetch_add(1, Ordering::SeqCst);
+            async { "page" }
+        }))
+        .await;
+        let root = std::env::temp_dir().join(format!(
+            "phantom-frame-test-cache-error-policy-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        // A file where the cache directory should be makes every write fail.
+        let blocked = root.join("blocked");
+        std::fs::write(&blocked, b"").unwrap();
+
+        for policy in [CacheErrorPolicy::Bypass, CacheErrorPolicy::Fail] {
+            let cache_dir = root.join(format!("{:?}", policy));
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_cache_storage_mode(CacheStorageMode::Filesystem)
+                    .with_cache_directory(&cache_dir)
+                    .with_cache_error_policy(policy),
+            );
+            calls.store(0, Ordering::SeqCst);
+
+            // Lookup: the stored body disappears.
+            assert_eq!(body_string(send(&proxy, get("/a")).await).await, "page");
+            std::fs::remove_dir_all(&cache_dir).unwrap();
+            let response = send(&proxy, get("/a")).await;
+            match policy {
+                CacheErrorPolicy::Bypass => {
+                    assert_eq!(body_string(response).await, "page");
+                    // Treated as a miss, so the entry is written again.
+                    assert_eq!(body_string(send(&proxy, get("/a")).await).await, "page");
+                    assert_eq!(calls.load(Ordering::SeqCst), 2);
+                }
+                CacheErrorPolicy::Fail => {
+                    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                    assert_eq!(calls.load(Ordering::SeqCst), 1);
+                }
+            }
+
+            // Store: the body cannot be written.
+            let (proxy, blocked_handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_cache_storage_mode(CacheStorageMode::Filesystem)
+                    .with_cache_directory(&blocked)
+                    .with_cache_error_policy(policy),
+            );
+            let response = send(&proxy, get("/b")).await;
+            match policy {
+                CacheErrorPolicy::Bypass => {
+                    assert_eq!(body_string(response).await, "page");
+                }
+                CacheErrorPolicy::Fail => {
+                    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+            assert_eq!(blocked_handle.cache().size().await, 0);
+
+            let counts = handle.stats().policy_activations();
+            let blocked_counts = blocked_handle.stats().policy_activations();
+            let expected = match policy {
+                CacheErrorPolicy::Bypass => (1, 0),
+                CacheErrorPolicy::Fail => (0, 1),
+            };
+            assert_eq!(
+                (counts.cache_error_bypass, counts.cache_error_fail),
+                expected
+            );
+            assert_eq!(
+                (
+                    blocked_counts.cache_error_bypass,
+                    blocked_counts.cache_error_fail
+                ),
+                expected
+            );
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_fallback_is_marked_and_limited() {
+        use crate::test_support::{get, send, spawn_backend};
+        use crate::BackendErrorPolicy;
+        use axum::http::header::WARNING;
+
+        let erroring = spawn_backend(
+            axum::Router::new().fallback(|| async { (StatusCode::BAD_GATEWAY, "down") }),
+        )
+        .await;
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let expired = |config: CreateProxyConfig| async move {
+            let (proxy, handle) = crate::create_proxy_handle(
+                config.with_backend_error_policy(BackendErrorPolicy::StaleIfAvailable),
+            );
+            handle.seed("GET:/page", 200, [], b"stale").await.unwrap();
+            let entry = handle.cache().get("GET:/page").await.unwrap();
+            handle
+                .cache()
+                .set_with_ttl("GET:/page".to_string(), entry, Duration::from_millis(1))
+                .await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            proxy
+        };
+
+        let config =
+            |backend: &String| CreateProxyConfig::new(backend.clone()).with_stale_max_age_secs(60);
+        let proxy = expired(config(&unreachable)).await;
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "STALE");
+        assert_eq!(response.headers()[WARNING], "111 - \"Revalidation Failed\"");
+
+        // Transport errors only by default: the backend's 502 is passed on
+        // unmarked.
+        let proxy = expired(config(&erroring)).await;
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(!response.headers().contains_key(WARNING));
+        let proxy = expired(config(&erroring).with_stale_on_error_status(true)).await;
+        let response = send(&proxy, get("/page")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "STALE");
+
+        // Too old to be served stale.
+        let proxy = expired(CreateProxyConfig::new(unreachable).with_stale_max_age_secs(1)).await;
+        assert_eq!(send(&proxy, get("/page")).await.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            send(&proxy, get("/page")).await.status(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_error_policy_serves_stale_entries() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use crate::BackendErrorPolicy;
+        use std::sync::atomic::AtomicBool;
+
+        let healthy = Arc::new(AtomicBool::new(false));
+        let backend_healthy = healthy.clone();
+        let backend = spawn_backend(axum::Router::new().fallback(move || {
+            let healthy = backend_healthy.load(Ordering::SeqCst);
+            async move {
+                if healthy {
+                    (StatusCode::OK, "fresh")
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, "down")
+                }
+            }
+        }))
+        .await;
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let stale = CachedResponse {
+            body: Bytes::from_static(b"stale"),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            status: 200,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        };
+
+        for policy in [
+            BackendErrorPolicy::StaleIfAvailable,
+            BackendErrorPolicy::Fail,
+        ] {
+            healthy.store(false, Ordering::SeqCst);
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_backend_error_policy(policy)
+                    .with_stale_max_age_secs(60)
+                    .with_stale_on_error_status(true),
+            );
+            let (dead_proxy, dead_handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(unreachable.clone())
+                    .with_backend_error_policy(policy)
+                    .with_stale_max_age_secs(60),
+            );
+            for handle in [&handle, &dead_handle] {
+                handle
+                    .cache()
+                    .set_with_ttl(
+                        "GET:/page".to_string(),
+                        stale.clone(),
+                        Duration::from_millis(1),
+                    )
+                    .await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            let errored = send(&proxy, get("/page")).await;
+            let unreachable = send(&dead_proxy, get("/page")).await;
+            // Without a stale entry the failure is passed on either way.
+            let no_entry = send(&proxy, get("/other")).await;
+            assert_eq!(no_entry.status(), StatusCode::SERVICE_UNAVAILABLE);
+            match policy {
+                BackendErrorPolicy::StaleIfAvailable => {
+                    assert_eq!(body_string(errored).await, "stale");
+                    assert_eq!(body_string(unreachable).await, "stale");
+                    assert_eq!(handle.stats().policy_activations().backend_error_stale, 1);
+                    assert_eq!(handle.stats().policy_activations().backend_error_fail, 1);
+                }
+                BackendErrorPolicy::Fail => {
+                    assert_eq!(errored.status(), StatusCode::SERVICE_UNAVAILABLE);
+                    assert_eq!(unreachable.status(), StatusCode::BAD_GATEWAY);
+                    assert_eq!(handle.stats().policy_activations().backend_error_stale, 0);
+                    assert_eq!(handle.stats().policy_activations().backend_error_fail, 2);
+                }
+            }
+
+            // Once the backend recovers, the expired entry is replaced.
+            healthy.store(true, Ordering::SeqCst);
+            handle.cache().remove("GET:/page").await;
+            handle
+                .cache()
+                .set_with_ttl(
+                    "GET:/page".to_string(),
+                    stale.clone(),
+                    Duration::from_millis(1),
+                )
+                .await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(body_string(send(&proxy, get("/page")).await).await, "fresh");
+            // Stored without a TTL, so it is served again.
+            assert!(handle.cache().get("GET:/page").await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_aborts_or_completes_backend_fetch() {
+        use crate::test_support::spawn_backend;
+        use crate::ClientDisconnect;
+        use std::sync::atomic::AtomicUsize;
+        use tokio::io::AsyncWriteExt;
+        use tokio::sync::Notify;
+
+        /// Counts a backend handler dropped before it answered.
+        struct Cancelled(Arc<AtomicUsize>);
+        impl Drop for Cancelled {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let started = Arc::new(Notify::new());
+        let completed = Arc::new(AtomicUsize::new(0));
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let (started, completed, cancelled) =
+                (started.clone(), completed.clone(), cancelled.clone());
+            move || {
+                let (started, completed) = (started.clone(), completed.clone());
+                let guard = Cancelled(cancelled.clone());
+                async move {
+                    started.notify_one();
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    std::mem::forget(guard);
+                    completed.fetch_add(1, Ordering::SeqCst);
+                    "slow"
+                }
+            }
+        }))
+        .await;
+
+        // A client that waits for its response is not counted.
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        let response = crate::test_support::send(&proxy, crate::test_support::get("/page")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(handle.stats().client_disconnects(), 0);
+        // Take the permit that request left on `started`.
+        started.notified().await;
+
+        for (mode, path, finishes) in [
+            (ClientDisconnect::Abort, "/page", false),
+            (ClientDisconnect::CompleteCacheable, "/page", true),
+            (ClientDisconnect::CompleteCacheable, "/private/page", false),
+        ] {
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_exclude_paths(vec!["/private/*".to_string()])
+                    .with_client_disconnect(mode),
+            );
+            let addr = spawn_backend(proxy).await.replace("http://", "");
+            let (completed_before, cancelled_before) = (
+                completed.load(Ordering::SeqCst),
+                cancelled.load(Ordering::SeqCst),
+            );
+
+            // Send the request headers, then hang up while the backend works.
+            let mut client = tokio::net::TcpStream::connect(&addr).await.unwrap();
+            client
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            started.notified().await;
+            drop(client);
+            tokio::time::sleep(Duration::from_millis(400)).await;
+
+            let case = format!("{mode:?} {path}");
+            assert_eq!(handle.stats().client_disconnects(), 1, "{case}");
+            assert_eq!(
+                completed.load(Ordering::SeqCst) - completed_before,
+                usize::from(finishes),
+                "{case}"
+            );
+            assert_eq!(
+                cancelled.load(Ordering::SeqCst) - cancelled_before,
+                usize::from(!finishes),
+                "{case}"
+            );
+            let key = format!("GET:{path}");
+            assert_eq!(handle.cache().get(&key).await.is_some(), finishes, "{case}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_phantom_404_meta_pages_are_answered_and_cached_as_404() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use axum::extract::Path;
+
+        const TAG: &str = "<meta content='true' name='phantom-404'>";
+        let backend = spawn_backend(axum::Router::new().route(
+            "/{page}",
+            axum::routing::get(|Path(page): Path<String>| async move {
+                let (content_type, body) = match page.as_str() {
+                    "missing" => ("text/html", format!("<head>{TAG}</head>")),
+                    // The tag sits past the default 16 KiB window.
+                    "late" => ("text/html", format!("{}{TAG}", " ".repeat(20 * 1024))),
+                    _ => ("text/plain", TAG.to_string()),
+                };
+                ([(axum::http::header::CONTENT_TYPE, content_type)], body)
+            }),
+        ))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_use_404_meta(true)
+                .with_cache_404_capacity(10),
+        );
+
+        for _ in 0..2 {
+            let response = send(&proxy, get("/missing")).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(body_string(response).await.contains(TAG));
+        }
+        assert_eq!(handle.stats().totals().hits, 1);
+        assert_eq!(handle.cache().size_404().await, 1);
+
+        for path in ["/late", "/plain"] {
+            let response = send(&proxy, get(path)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+        }
+        assert_eq!(handle.cache().size_404().await, 1);
+        assert_eq!(handle.cache().size().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_backend_cache_control_is_respected_when_enabled() {
+        use crate::test_support::{get, send, spawn_backend};
+        use axum::extract::Path;
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().route(
+            "/{directives}",
+            axum::routing::get({
+                let calls = calls.clone();
+                move |Path(directives): Path<String>| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    async move { ([(axum::http::header::CACHE_CONTROL, directives)], "page") }
+                }
+            }),
+        ))
+        .await;
+
+        for (respect, path, backend_calls) in [
+            (true, "/no-store", 2),
+            (true, "/private", 2),
+            (true, "/no-cache", 2),
+            (true, "/max-age=60", 1),
+            (false, "/no-store", 1),
+        ] {
+            let (proxy, _handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone()).with_respect_cache_control(respect),
+            );
+            calls.store(0, Ordering::SeqCst);
+            for _ in 0..2 {
+                assert_eq!(send(&proxy, get(path)).await.status(), StatusCode::OK);
+            }
+            assert_eq!(
+                calls.load(Ordering::SeqCst),
+                backend_calls,
+                "{path} respect={respect}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_phantom_headers_set_ttl_and_no_cache() {
+        use crate::cache_rules::CacheRule;
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use axum::extract::Path;
+        use axum::response::IntoResponse;
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().route(
+            "/{page}",
+            axum::routing::get({
+                let calls = calls.clone();
+                move |Path(page): Path<String>| {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    let mut headers = vec![("cache-control", "max-age=3600")];
+                    headers.extend(match page.as_str() {
+                        "short" => &[("x-phantom-ttl", "1"), ("x-phantom-tags", "a,b")][..],
+                        "no-cache" => &[("x-phantom-ttl", "60"), ("x-phantom-no-cache", "1")],
+                        "zero" => &[("x-phantom-ttl", "0")],
+                        "huge" => &[("x-phantom-ttl", "18446744073709551615")],
+                        _ => &[],
+                    });
+                    let mut response = call.to_string().into_response();
+                    for (name, value) in headers {
+                        response
+                            .headers_mut()
+                            .insert(name, axum::http::HeaderValue::from_static(value));
+                    }
+                    async move { response }
+                }
+            }),
+        ))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_respect_cache_control(true)
+                .with_cache_rules(vec![CacheRule::new("/*").with_ttl_secs(3600)]),
+        );
+        let fetch = |path: &'static str| {
+            let proxy = proxy.clone();
+            async move {
+                let response = send(&proxy, get(path)).await;
+                for name in ["x-phantom-ttl", "x-phantom-no-cache", "x-phantom-tags"] {
+                    assert!(!response.headers().contains_key(name), "{path} {name}");
+                }
+                body_string(response).await
+            }
+        };
 
-    fn response_headers() -> reqwest::header::HeaderMap {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            reqwest::header::CONTENT_TYPE,
-            reqwest::header::HeaderValue::from_static("text/html; charset=utf-8"),
+        // X-Phantom-TTL wins over both the rule TTL and `max-age`.
+        assert_eq!(fetch("/short").await, "0");
+        assert_eq!(fetch("/short").await, "0");
+        assert_eq!(fetch("/plain").await, "1");
+        let cached = handle.cache().get("GET:/short").await.unwrap();
+        assert!(cached
+            .headers
+            .iter()
+            .all(|(name, _)| name != "x-phantom-tags"));
+
+        // No-Cache wins over a TTL, and a TTL of 0 stores nothing.
+        assert_eq!(fetch("/no-cache").await, "2");
+        assert_eq!(fetch("/no-cache").await, "3");
+        assert_eq!(fetch("/zero").await, "4");
+        assert_eq!(fetch("/zero").await, "5");
+
+        // A TTL past what an `Instant` can hold is capped and still stored.
+        assert_eq!(fetch("/huge").await, "6");
+        assert_eq!(fetch("/huge").await, "6");
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(fetch("/short").await, "7");
+        assert_eq!(fetch("/plain").await, "1");
+        assert_eq!(fetch("/huge").await, "6");
+    }
+
+    #[tokio::test]
+    async fn test_responses_setting_cookies_are_not_cached() {
+        use crate::test_support::{get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    (
+                        [(axum::http::header::SET_COOKIE, format!("session={call}"))],
+                        "page",
+                    )
+                }
+            }
+        }))
+        .await;
+
+        for (skip, backend_calls) in [(true, 2), (false, 1)] {
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone()).with_skip_set_cookie_responses(skip),
+            );
+            calls.store(0, Ordering::SeqCst);
+            for call in 0..2 {
+                let response = send(&proxy, get("/login")).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                if skip {
+                    // Each visitor gets their own cookie, passed through as sent.
+                    assert_eq!(
+                        response.headers()[axum::http::header::SET_COOKIE],
+                        format!("session={call}").as_str()
+                    );
+                }
+            }
+            assert_eq!(calls.load(Ordering::SeqCst), backend_calls, "skip={skip}");
+            assert_eq!(handle.cache().size().await, 2 - backend_calls);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credentialed_requests_bypass_the_cache() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            move |headers: HeaderMap| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let signed_in = headers.contains_key(axum::http::header::AUTHORIZATION)
+                    || headers.contains_key(axum::http::header::COOKIE);
+                async move {
+                    if signed_in {
+                        "account"
+                    } else {
+                        "public"
+                    }
+                }
+            }
+        }))
+        .await;
+        let with_header = |name: &'static str, value: &'static str| {
+            Request::builder()
+                .uri("/")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        // A credentialed request neither stores its page...
+        for request in [
+            with_header("authorization", "Bearer abc"),
+            with_header("cookie", "theme=dark; sessionid=1"),
+        ] {
+            let response = send(&proxy, request).await;
+            assert_eq!(response.headers()["x-cache"], "BYPASS");
+            assert_eq!(body_string(response).await, "account");
+        }
+        assert_eq!(handle.cache().size().await, 0);
+
+        // ...nor is answered with an anonymous visitor's.
+        assert_eq!(body_string(send(&proxy, get("/")).await).await, "public");
+        let response = send(&proxy, get("/")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        let response = send(&proxy, with_header("cookie", "PHPSESSID=1")).await;
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert_eq!(body_string(response).await, "account");
+
+        // Cookies that are not session cookies still share the cache.
+        let response = send(&proxy, with_header("cookie", "theme=dark")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Opting out caches credentialed requests like any other.
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_bypass_credentialed_requests(false),
         );
-        headers
+        send(&proxy, with_header("authorization", "Bearer abc")).await;
+        let response = send(&proxy, with_header("authorization", "Bearer abc")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(handle.cache().size().await, 1);
     }
 
     #[tokio::test]
-    async fn test_build_cached_response_uses_selected_encoding() {
-        let cached = build_cached_response(
-            200,
-            &response_headers(),
-            b"<html>compressed</html>",
-            &CompressStrategy::Gzip,
-        )
-        .await
-        .unwrap();
+    async fn test_clients_can_force_a_refresh_of_the_stored_entry() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move { format!("v{call}") }
+            }
+        }))
+        .await;
+        let with_header = |name: &'static str, value: &'static str| {
+            Request::builder()
+                .uri("/")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let stored = |handle: crate::ProxyHandle| async move {
+            let cached = handle.cache().get("GET:/").await.unwrap();
+            let body = match cached.content_encoding {
+                Some(encoding) => decompress_body_async(cached.body, encoding).await.unwrap(),
+                None => cached.body.to_vec(),
+            };
+            String::from_utf8(body).unwrap()
+        };
+
+        // Off by default: no-cache is answered from the cache.
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        send(&proxy, get("/")).await;
+        let response = send(&proxy, with_header("cache-control", "no-cache")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(stored(handle).await, "v0");
+
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone()).with_respect_client_no_cache(true),
+        );
+        calls.store(0, Ordering::SeqCst);
+        send(&proxy, get("/")).await;
+        for (request, body) in [
+            (with_header("cache-control", "max-age=0, no-cache"), "v1"),
+            (with_header("pragma", "no-cache"), "v2"),
+        ] {
+            let response = send(&proxy, request).await;
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            assert_eq!(body_string(response).await, body);
+            assert_eq!(stored(handle.clone()).await, body);
+        }
+        let response = send(&proxy, get("/")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(body_string(response).await, "v2");
+
+        // With only a token, no-cache is ignored and the token refreshes.
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_revalidate_token("let-me-in"),
+        );
+        calls.store(0, Ordering::SeqCst);
+        send(&proxy, get("/")).await;
+        for request in [
+            with_header("cache-control", "no-cache"),
+            with_header("x-phantom-revalidate", "guess"),
+        ] {
+            let response = send(&proxy, request).await;
+            assert_eq!(response.headers()["x-cache"], "HIT");
+        }
+        assert_eq!(stored(handle.clone()).await, "v0");
+        let response = send(&proxy, with_header("x-phantom-revalidate", "let-me-in")).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(body_string(response).await, "v1");
+        assert_eq!(stored(handle).await, "v1");
+    }
+
+    #[tokio::test]
+    async fn test_bypass_and_purge_headers() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let forwarded_purges = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            let forwarded_purges = forwarded_purges.clone();
+            move |headers: HeaderMap| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                assert!(!headers.contains_key("x-phantom-bypass"));
+                if headers.contains_key("x-phantom-purge") {
+                    forwarded_purges.fetch_add(1, Ordering::SeqCst);
+                }
+                async move { format!("v{call}") }
+            }
+        }))
+        .await;
+        let with_header = |name: &'static str, value: &'static str| {
+            Request::builder()
+                .uri("/")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone())
+                .with_bypass_header("x-phantom-bypass")
+                .with_purge_header("x-phantom-purge")
+                .with_purge_token("secret"),
+        );
+        let stored = || async { handle.cache().get("GET:/").await.unwrap().body };
+
+        assert_eq!(body_string(send(&proxy, get("/")).await).await, "v0");
+        let before = stored().await;
+
+        let response = send(&proxy, with_header("x-phantom-bypass", "1")).await;
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert_eq!(body_string(response).await, "v1");
+        assert_eq!(stored().await, before);
+
+        let response = send(&proxy, with_header("x-phantom-purge", "guess")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let response = send(&proxy, with_header("x-phantom-purge", "secret")).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(body_string(response).await, "v2");
+        assert_ne!(stored().await, before);
+        let response = send(&proxy, get("/")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(body_string(response).await, "v2");
+        assert_eq!(forwarded_purges.load(Ordering::SeqCst), 0);
+
+        // Without a purge_token every purge is refused.
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone()).with_purge_header("x-phantom-purge"),
+        );
+        let response = send(&proxy, with_header("x-phantom-purge", "secret")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Without a purge_header the header is an ordinary one: it is
+        // forwarded and purges nothing.
+        let (proxy, _handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let response = send(&proxy, with_header("x-phantom-purge", "secret")).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(body_string(response).await, "v3");
+        assert_eq!(forwarded_purges.load(Ordering::SeqCst), 1);
+        let response = send(&proxy, with_header("x-phantom-purge", "secret")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(body_string(response).await, "v3");
+    }
+
+    #[tokio::test]
+    async fn test_head_is_answered_from_the_get_cache() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use axum::http::Method;
+        use std::sync::Mutex;
+
+        let methods = Arc::new(Mutex::new(Vec::new()));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let methods = methods.clone();
+            move |method: Method| {
+                methods.lock().unwrap().push(method.to_string());
+                async { "hello world" }
+            }
+        }))
+        .await;
+        let head = || {
+            Request::builder()
+                .method(Method::HEAD)
+                .uri("/")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+
+        // Without a GET entry the HEAD is proxied and nothing is stored, so
+        // it counts as a bypass rather than a miss.
+        let response = send(&proxy, head()).await;
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert!(handle.cache().get("GET:/").await.is_none());
+        assert!(handle.cache().get("HEAD:/").await.is_none());
+        let totals = handle.stats().totals();
+        assert_eq!((totals.misses, totals.bypasses), (0, 1));
 
-        assert_eq!(cached.content_encoding, Some(ContentEncoding::Gzip));
         assert_eq!(
-            cached.headers.get("content-encoding"),
-            Some(&"gzip".to_string())
+            body_string(send(&proxy, get("/")).await).await,
+            "hello world"
+        );
+        let response = send(&proxy, head()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(response.headers()[axum::http::header::CONTENT_LENGTH], "11");
+        assert_eq!(body_string(response).await, "");
+        assert_eq!(*methods.lock().unwrap(), ["HEAD", "GET"]);
+
+        // The full length also reaches clients over the wire.
+        let url = spawn_backend(proxy).await;
+        let response = reqwest::Client::new().head(url).send().await.unwrap();
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(response.headers()[reqwest::header::CONTENT_LENGTH], "11");
+        assert_eq!(methods.lock().unwrap().len(), 2);
+
+        // Switched off, HEAD requests are keyed and fetched on their own.
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_serve_head_from_get(false),
         );
+        send(&proxy, get("/")).await;
+        let response = send(&proxy, head()).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(methods.lock().unwrap()[2..], ["GET", "HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use axum::response::IntoResponse;
+        use std::sync::Mutex;
+
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let ranges = ranges.clone();
+            move |headers: HeaderMap| {
+                let range = headers
+                    .get("range")
+                    .map(|v| v.to_str().unwrap().to_string());
+                ranges.lock().unwrap().push(range.clone());
+                async move {
+                    match range {
+                        Some(_) => (
+                            StatusCode::PARTIAL_CONTENT,
+                            [("content-range", "bytes 0-1/10")],
+                            "01",
+                        )
+                            .into_response(),
+                        None => "0123456789".into_response(),
+                    }
+                }
+            }
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let ranged = |range: &str| {
+            Request::builder()
+                .uri("/")
+                .header("range", range)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Nothing cached: the backend's 206 passes through and is not stored.
+        let response = send(&proxy, ranged("bytes=0-1")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert_eq!(body_string(response).await, "01");
+        assert!(handle.cache().get("GET:/").await.is_none());
+
+        send(&proxy, get("/")).await;
+        for (range, part, content_range) in [
+            ("bytes=2-4", "234", "bytes 2-4/10"),
+            ("bytes=-3", "789", "bytes 7-9/10"),
+            ("bytes=8-", "89", "bytes 8-9/10"),
+            ("bytes=-30", "0123456789", "bytes 0-9/10"),
+        ] {
+            let response = send(&proxy, ranged(range)).await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{range}");
+            assert_eq!(response.headers()["x-cache"], "HIT", "{range}");
+            assert_eq!(
+                response.headers()["content-range"],
+                content_range,
+                "{range}"
+            );
+            assert_eq!(
+                response.headers()["content-length"],
+                part.len().to_string(),
+                "{range}"
+            );
+            assert_eq!(body_string(response).await, part, "{range}");
+        }
+        let response = send(&proxy, ranged("bytes=10-")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */10");
+        assert_eq!(ranges.lock().unwrap().len(), 2);
+
+        // A changed If-Range gets the whole entry.
+        let mut request = ranged("bytes=2-4");
+        request
+            .headers_mut()
+            .insert("if-range", HeaderValue::from_static("\"stale\""));
+        let response = send(&proxy, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "0123456789");
+
+        // Several ranges go to the backend, and the entry is left alone.
+        let response = send(&proxy, ranged("bytes=0-1,5-6")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
         assert_eq!(
-            cached.headers.get("vary"),
-            Some(&"Accept-Encoding".to_string())
+            ranges.lock().unwrap().last().unwrap().as_deref(),
+            Some("bytes=0-1,5-6")
         );
+        let response = send(&proxy, get("/")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(body_string(response).await, "0123456789");
     }
 
     #[tokio::test]
-    async fn test_build_response_from_cache_falls_back_to_identity() {
-        let body = b"<html>identity</html>";
-        let compressed = crate::compression::compress_body(body, ContentEncoding::Brotli).unwrap();
-        let cached = CachedResponse {
-            body: compressed,
-            headers: HashMap::from([
-                ("content-type".to_string(), "text/html".to_string()),
-                ("content-encoding".to_string(), "br".to_string()),
-                ("content-length".to_string(), "123".to_string()),
-                ("vary".to_string(), "Accept-Encoding".to_string()),
-            ]),
-            status: 200,
-            content_encoding: Some(ContentEncoding::Brotli),
+    async fn test_repeated_headers_survive_the_cache() {
+        use crate::test_support::{get, send, spawn_backend};
+        use axum::http::header::{SET_COOKIE, VARY};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            let mut response = Response::new(Body::from("page"));
+            let headers = response.headers_mut();
+            for (name, value) in [
+                (SET_COOKIE, "a=1"),
+                (VARY, "Accept-Language"),
+                (SET_COOKIE, "b=2"),
+                (VARY, "Cookie"),
+                (SET_COOKIE, "c=3"),
+            ] {
+                headers.append(name, HeaderValue::from_static(value));
+            }
+            response
+        }))
+        .await;
+        let (proxy, handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_skip_set_cookie_responses(false),
+        );
+
+        // The miss is forwarded from the backend response, the hit rebuilt
+        // from the stored entry.
+        for _ in 0..2 {
+            let response = send(&proxy, get("/")).await;
+            let values = |name| {
+                response
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|value| value.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(values(SET_COOKIE), ["a=1", "b=2", "c=3"]);
+            let vary = values(VARY).join(", ");
+            assert!(vary.starts_with("Accept-Language"), "{vary}");
+            assert!(vary.contains("Cookie"), "{vary}");
+        }
+        assert_eq!(handle.stats().totals().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests_get_304_from_cache() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use axum::http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/tagged",
+                    axum::routing::get(|| async { ([(ETAG, "\"v1\"")], "tagged") }),
+                )
+                .fallback(|| async { "page" }),
+        )
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend));
+        let conditional = |path: &str, name, value: &str| {
+            Request::builder()
+                .uri(path)
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
         };
 
-        let mut request_headers = HeaderMap::new();
-        request_headers.insert(
-            axum::http::header::ACCEPT_ENCODING,
-            HeaderValue::from_static("gzip"),
+        // The first response already carries the ETag generated on store.
+        let response = send(&proxy, get("/page")).await;
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(crate::conditional::EntityTag::parse(&etag).is_some_and(|tag| !tag.weak));
+        let response = send(&proxy, get("/tagged")).await;
+        assert_eq!(response.headers()[ETAG], "\"v1\"");
+
+        let weak = format!("W/{etag}");
+        for (path, name, value) in [
+            ("/page", IF_NONE_MATCH, etag.as_str()),
+            ("/page", IF_NONE_MATCH, weak.as_str()),
+            ("/tagged", IF_NONE_MATCH, "\"v0\", \"v1\""),
+            ("/page", IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT"),
+        ] {
+            let response = send(&proxy, conditional(path, name.clone(), value)).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "{name}: {value}"
+            );
+            assert!(response.headers().contains_key(ETAG));
+            assert_eq!(response.headers()["x-cache"], "HIT");
+            assert_eq!(body_string(response).await, "");
+        }
+
+        for (path, name, value) in [
+            ("/page", IF_NONE_MATCH, "\"other\""),
+            ("/tagged", IF_NONE_MATCH, "v1"),
+            ("/page", IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT"),
+            ("/page", IF_MODIFIED_SINCE, "not a date"),
+        ] {
+            let response = send(&proxy, conditional(path, name.clone(), value)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{name}: {value}");
+            assert!(!body_string(response).await.is_empty());
+        }
+
+        // If-Modified-Since is ignored when If-None-Match is present.
+        let request = Request::builder()
+            .uri("/page")
+            .header(IF_NONE_MATCH, "\"other\"")
+            .header(IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&proxy, request).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_vary_selects_a_cached_variant_per_request_header() {
+        use crate::test_support::{body_string, send, spawn_backend};
+        use axum::http::header::{ACCEPT_LANGUAGE, VARY};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/anything",
+                    axum::routing::get(|| async { ([(VARY, "*")], "uncacheable") }),
+                )
+                .fallback({
+                    let calls = calls.clone();
+                    move |headers: HeaderMap| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        let language = headers
+                            .get(ACCEPT_LANGUAGE)
+                            .map_or("none", |value| value.to_str().unwrap())
+                            .to_string();
+                        async move {
+                            (
+                                [(VARY, "Accept-Language, Accept-Encoding")],
+                                format!("page in {language}"),
+                            )
+                        }
+                    }
+                }),
+        )
+        .await;
+        let request = |path: &str, language: Option<&str>| {
+            let mut request = Request::builder().uri(path);
+            if let Some(language) = language {
+                request = request.header(ACCEPT_LANGUAGE, language);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        for _ in 0..2 {
+            for language in [Some("en"), Some("de"), None] {
+                let response = send(&proxy, request("/page", language)).await;
+                assert_eq!(
+                    body_string(response).await,
+                    format!("page in {}", language.unwrap_or("none"))
+                );
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(handle.cache().size().await, 3);
+        assert_eq!(handle.stats().totals().hits, 3);
+
+        for _ in 0..2 {
+            send(&proxy, request("/anything", Some("en"))).await;
+        }
+        assert_eq!(handle.cache().size().await, 3);
+
+        // Purging the page's key removes all of its variants.
+        handle.cache().clear_by_pattern("GET:/page").await;
+        assert_eq!(handle.cache().size().await, 0);
+
+        // Without `respect_vary` one entry answers every language.
+        let (proxy, handle) =
+            crate::create_proxy_handle(CreateProxyConfig::new(backend).with_respect_vary(false));
+        for language in ["en", "de"] {
+            let response = send(&proxy, request("/page", Some(language))).await;
+            assert_eq!(body_string(response).await, "page in en");
+        }
+        assert_eq!(handle.cache().size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_header_reports_hit_miss_and_bypass() {
+        use crate::test_support::{get, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(|| async { "page" })).await;
+        let config =
+            CreateProxyConfig::new(backend).with_exclude_paths(vec!["/private/*".to_string()]);
+
+        let (proxy, handle) = crate::create_proxy_handle(config.clone());
+        let status = |response: &Response<Body>, name: &str| {
+            response
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        let miss = send(&proxy, get("/page")).await;
+        assert_eq!(status(&miss, "x-cache").as_deref(), Some("MISS"));
+        assert_eq!(status(&miss, "age"), None);
+        let hit = send(&proxy, get("/page")).await;
+        assert_eq!(status(&hit, "x-cache").as_deref(), Some("HIT"));
+        assert_eq!(status(&hit, "age").as_deref(), Some("0"));
+        let bypass = send(&proxy, get("/private/page")).await;
+        assert_eq!(status(&bypass, "x-cache").as_deref(), Some("BYPASS"));
+
+        // Age counts from when the entry was stored.
+        let mut cached = handle.cache().get("GET:/page").await.unwrap();
+        cached.stored_at = Some(std::time::SystemTime::now() - Duration::from_secs(90));
+        assert_eq!(cached.age().unwrap().as_secs(), 90);
+
+        let (proxy, _handle) = crate::create_proxy_handle(
+            config
+                .clone()
+                .with_cache_status_header(Some("CDN-Cache".to_string())),
         );
+        send(&proxy, get("/page")).await;
+        let hit = send(&proxy, get("/page")).await;
+        assert_eq!(status(&hit, "cdn-cache").as_deref(), Some("HIT"));
+        assert_eq!(status(&hit, "x-cache"), None);
 
-        let response = build_response_from_cache(cached, &request_headers)
+        let (proxy, _handle) = crate::create_proxy_handle(config.with_cache_status_header(None));
+        send(&proxy, get("/page")).await;
+        let hit = send(&proxy, get("/page")).await;
+        assert_eq!(status(&hit, "x-cache"), None);
+        assert_eq!(status(&hit, "age"), None);
+    }
+
+    #[tokio::test]
+    async fn test_404_flood_stays_within_404_capacity() {
+        use crate::test_support::{get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { (StatusCode::NOT_FOUND, "missing") }
+            }
+        }))
+        .await;
+
+        for capacity in [5, 0] {
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone()).with_cache_404_capacity(capacity),
+            );
+            for i in 0..50 {
+                let response = send(&proxy, get(&format!("/missing/{i}"))).await;
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }
+            assert_eq!(handle.cache().size_404().await, capacity, "{capacity}");
+            assert_eq!(handle.cache().size().await, 0, "{capacity}");
+
+            // The newest 404 is served from the store; the oldest was evicted.
+            calls.store(0, Ordering::SeqCst);
+            send(&proxy, get("/missing/49")).await;
+            send(&proxy, get("/missing/0")).await;
+            let expected = if capacity > 0 { 1 } else { 2 };
+            assert_eq!(calls.load(Ordering::SeqCst), expected, "{capacity}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_one_backend_fetch() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::AtomicUsize;
+        use tokio::io::AsyncReadExt;
+        use tokio::task::JoinSet;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = spawn_backend(axum::Router::new().fallback({
+            let calls = calls.clone();
+            move |uri: axum::http::Uri| {
+                let calls = calls.clone();
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let mut response = axum::response::IntoResponse::into_response("page");
+                    if uri.path() == "/localized" {
+                        // Stored under a variant key the followers do not know yet.
+                        response.headers_mut().insert(
+                            axum::http::header::VARY,
+                            axum::http::HeaderValue::from_static("accept-language"),
+                        );
+                    }
+                    response
+                }
+            }
+        }))
+        .await;
+
+        // Reads each request, then hangs up without answering.
+        let failing = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failing_backend = format!("http://{}", failing.local_addr().unwrap());
+        let failed_calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let failed_calls = failed_calls.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = failing.accept().await.unwrap();
+                    let failed_calls = failed_calls.clone();
+                    tokio::spawn(async move {
+                        let _ = stream.read(&mut [0; 1024]).await;
+                        failed_calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    });
+                }
+            }
+        });
+
+        // Sends `count` concurrent requests for `path` and returns the
+        // statuses, failing if any waits on a fetch that never finishes.
+        async fn burst(
+            proxy: &axum::Router,
+            path: &str,
+            count: usize,
+        ) -> Vec<(StatusCode, String)> {
+            let mut requests = JoinSet::new();
+            for _ in 0..count {
+                let (proxy, path) = (proxy.clone(), path.to_string());
+                requests.spawn(async move {
+                    let response = send(&proxy, get(&path)).await;
+                    (response.status(), body_string(response).await)
+                });
+            }
+            tokio::time::timeout(Duration::from_secs(5), requests.join_all())
+                .await
+                .expect("a coalesced request hung")
+        }
+
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        let responses = burst(&proxy, "/page", 50).await;
+        assert!(responses
+            .iter()
+            .all(|response| *response == (StatusCode::OK, "page".to_string())));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(handle.stats().coalesced_misses(), 49);
+
+        // Followers find the leader's entry under the key its `Vary` chose.
+        let responses = burst(&proxy, "/localized", 50).await;
+        assert!(responses
+            .iter()
+            .all(|response| *response == (StatusCode::OK, "page".to_string())));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(handle.stats().coalesced_misses(), 98);
+
+        // A failed fetch stores nothing, so each waiter fetches for itself
+        // once the first request has its error.
+        let (proxy, handle) = crate::create_proxy_handle(CreateProxyConfig::new(failing_backend));
+        let responses = burst(&proxy, "/page", 10).await;
+        assert!(responses
+            .iter()
+            .all(|(status, _)| *status == StatusCode::BAD_GATEWAY));
+        assert_eq!(failed_calls.load(Ordering::SeqCst), 10);
+        assert_eq!(handle.stats().coalesced_misses(), 0);
+
+        let (proxy, _handle) =
+            crate::create_proxy_handle(CreateProxyConfig::new(backend).with_coalesce_misses(false));
+        burst(&proxy, "/page", 50).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 50);
+    }
+
+    #[tokio::test]
+    async fn test_pool_options_apply_to_every_backend_client() {
+        use crate::test_support::{body_string, get, send};
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        // Answers with the client's port, which changes with each connection.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let app =
+            axum::Router::new().fallback(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                peer.port().to_string()
+            });
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
             .await
             .unwrap();
-        assert!(response
-            .headers()
-            .get(axum::http::header::CONTENT_ENCODING)
-            .is_none());
+        });
 
-        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        assert_eq!(body.as_ref(), b"<html>identity</html>");
+        for (max_idle, reused) in [(usize::MAX, true), (0, false)] {
+            let (proxy, handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_cache_strategy(crate::CacheStrategy::None)
+                    .with_pool_max_idle_per_host(max_idle),
+            );
+            for _ in 0..2 {
+                let first = body_string(send(&proxy, get("/")).await).await;
+                let second = body_string(send(&proxy, get("/")).await).await;
+                assert_eq!(first == second, reused, "max_idle {max_idle}");
+                // A swapped-in backend gets a client with the same settings.
+                handle.set_backend(backend.clone(), false).await.unwrap();
+            }
+        }
     }
 
     #[tokio::test]
-    async fn test_build_response_from_cache_keeps_supported_encoding() {
-        let body = b"<html>compressed</html>";
-        let compressed = crate::compression::compress_body(body, ContentEncoding::Brotli).unwrap();
-        let cached = CachedResponse {
-            body: compressed.clone(),
-            headers: HashMap::from([
-                ("content-type".to_string(), "text/html".to_string()),
-                ("content-encoding".to_string(), "br".to_string()),
-                ("content-length".to_string(), compressed.len().to_string()),
-                ("vary".to_string(), "Accept-Encoding".to_string()),
-            ]),
-            status: 200,
-            content_encoding: Some(ContentEncoding::Brotli),
-        };
+    async fn test_backend_timeouts_answer_504() {
+        use crate::test_support::{get, send, spawn_backend};
 
-        let mut request_headers = HeaderMap::new();
-        request_headers.insert(
-            axum::http::header::ACCEPT_ENCODING,
-            HeaderValue::from_static("br, gzip;q=0.5"),
+        let backend = spawn_backend(axum::Router::new().fallback(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        }))
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_request_timeout(Duration::from_millis(200)),
         );
+        let started = Instant::now();
+        let response = send(&proxy, get("/slow")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(5));
 
-        let response = build_response_from_cache(cached, &request_headers)
+        // Accepts connections but never answers the handshake.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                // Also bounds the client's warm-up request.
+                .with_request_timeout(Duration::from_millis(200))
+                .with_upgrade_handshake_timeout(Duration::from_millis(200)),
+        );
+        let mut client = h2_client_for(proxy).await;
+        let response = client
+            .send_request(extended_connect("websocket"))
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_backend_receives_forwarding_headers() {
+        use crate::test_support::{body_string, send, spawn_backend};
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        let backend = spawn_backend(axum::Router::new().fallback(
+            |headers: HeaderMap| async move {
+                ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host"]
+                    .map(|name| headers.get(name).map_or("-", |v| v.to_str().unwrap()))
+                    .join(" | ")
+            },
+        ))
+        .await;
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend).with_cache_strategy(crate::CacheStrategy::None),
+        );
+        let mut request = Request::builder()
+            .uri("/page")
+            .header("host", "shop.example")
+            .header("x-forwarded-for", "10.9.9.9")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([198, 51, 100, 4], 40000))));
+
+        let body = body_string(send(&proxy, request).await).await;
+        assert_eq!(body, "198.51.100.4 | http | shop.example");
+    }
+
+    #[tokio::test]
+    async fn test_preserve_host_forwards_the_client_host() {
+        use crate::test_support::{body_string, send, spawn_backend};
+
+        let backend = spawn_backend(axum::Router::new().fallback(
+            |headers: HeaderMap| async move { headers["host"].to_str().unwrap().to_string() },
+        ))
+        .await;
+        let backend_authority = backend.trim_start_matches("http://").to_string();
+        for (preserve, expected) in [
+            (false, backend_authority.as_str()),
+            (true, "tenant.example"),
+        ] {
+            let (proxy, _handle) = crate::create_proxy_handle(
+                CreateProxyConfig::new(backend.clone())
+                    .with_cache_strategy(crate::CacheStrategy::None)
+                    .with_preserve_host(preserve),
+            );
+            let request = Request::builder()
+                .uri("/")
+                .header("host", "tenant.example")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(body_string(send(&proxy, request).await).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_redirects_are_passed_through_and_cached() {
+        use crate::test_support::{body_string, get, send, spawn_backend};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let redirects = Arc::new(AtomicUsize::new(0));
+        let counter = redirects.clone();
+        let backend = spawn_backend(
+            axum::Router::new()
+                .route(
+                    "/old",
+                    axum::routing::get(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async { (StatusCode::FOUND, [("location", "/new")]) }
+                    }),
+                )
+                .route("/new", axum::routing::get(|| async { "new page" })),
+        )
+        .await;
+
+        let (proxy, _handle) = crate::create_proxy_handle(CreateProxyConfig::new(backend.clone()));
+        for _ in 0..2 {
+            let response = send(&proxy, get("/old")).await;
+            assert_eq!(response.status(), StatusCode::FOUND);
+            assert_eq!(response.headers()["location"], "/new");
+        }
+        // The second one came from the cache.
+        assert_eq!(redirects.load(Ordering::SeqCst), 1);
+
+        let (proxy, _handle) =
+            crate::create_proxy_handle(CreateProxyConfig::new(backend).with_follow_redirects(true));
+        let response = send(&proxy, get("/old")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "new page");
+    }
+
+    #[tokio::test]
+    async fn test_location_pointing_at_backend_is_rewritten() {
+        use crate::test_support::{get, send};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let own_url = backend.clone();
+        let app = axum::Router::new()
+            .route(
+                "/old",
+                axum::routing::get(move || async move {
+                    let location = format!("{own_url}/next?from=old");
+                    (StatusCode::FOUND, [("location", location)])
+                }),
+            )
+            .route(
+                "/external",
+                axum::routing::get(|| async {
+                    (StatusCode::FOUND, [("location", "https://example.org/")])
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend.clone()).with_rewrite_location(true),
+        );
+        let location =
+            |response: Response<Body>| response.headers()["location"].to_str().unwrap().to_string();
+
+        // Stored as the backend sent it, rewritten on the miss and the hit.
+        for _ in 0..2 {
+            assert_eq!(location(send(&proxy, get("/old")).await), "/next?from=old");
+        }
         assert_eq!(
-            response.headers().get(axum::http::header::CONTENT_ENCODING),
-            Some(&HeaderValue::from_static("br"))
+            location(send(&proxy, get("/external")).await),
+            "https://example.org/"
         );
 
-        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        assert_eq!(body.as_ref(), compressed.as_slice());
+        let (proxy, _handle) = crate::create_proxy_handle(
+            CreateProxyConfig::new(backend)
+                .with_rewrite_location(true)
+                .with_location_base_url("https://www.example.com/"),
+        );
+        assert_eq!(
+            location(send(&proxy, get("/old")).await),
+            "https://www.example.com/next?from=old"
+        );
     }
 }
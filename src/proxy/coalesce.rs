@@ -0,0 +1,217 @@
+//! One backend fetch shared by concurrent misses for the same cache key.
+//!
+//! The first miss for a key leads: it runs the miss path as usual. Misses for
+//! the same key that arrive while it is running follow: they wait for the
+//! leader and then look the key up again. The leader's entry in [`InFlight`]
+//! is removed however its fetch ends — stored, not cacheable, failed, or
+//! dropped along with its client — so followers never wait longer than the
+//! leader runs. A follower that finds nothing it may serve fetches for itself.
+
+use super::{
+    accept_mismatch, cached_response_is_allowed, hit_matches_origin,
+    pipeline::{RequestContext, Stage, StageFuture},
+    serve_cached, ProxyState,
+};
+use crate::vary;
+use axum::{
+    body::Body,
+    http::{Response, StatusCode},
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Cache keys whose miss path is running, each with a receiver that closes
+/// when it ends.
+#[derive(Default)]
+pub(super) struct InFlight {
+    fetches: DashMap<String, watch::Receiver<()>>,
+}
+
+impl InFlight {
+    fn join(self: &Arc<Self>, key: &str) -> Role {
+        match self.fetches.entry(key.to_string()) {
+            Entry::Occupied(entry) => Role::Follower(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let (done, waiter) = watch::channel(());
+                entry.insert(waiter);
+                Role::Leader(LeaderGuard {
+                    in_flight: self.clone(),
+                    key: key.to_string(),
+                    _done: done,
+                })
+            }
+        }
+    }
+}
+
+enum Role {
+    Leader(LeaderGuard),
+    Follower(watch::Receiver<()>),
+}
+
+/// Held by the leader for as long as its miss path runs, and past that while
+/// its entry waits in the cache write queue.
+pub(super) struct LeaderGuard {
+    in_flight: Arc<InFlight>,
+    key: String,
+    /// Never sent on; dropping it wakes the followers.
+    _done: watch::Sender<()>,
+}
+
+impl Drop for LeaderGuard {
+    fn drop(&mut self) {
+        // Runs before `_done` is dropped, so a woken follower that has to
+        // fetch for itself does not find the finished fetch still listed.
+        self.in_flight.fetches.remove(&self.key);
+    }
+}
+
+/// Lead the fetch for the request's key, or wait for a concurrent miss for
+/// the same key and answer from what it stored.
+pub(super) struct Coalesce;
+
+impl Stage for Coalesce {
+    fn name(&self) -> &'static str {
+        "coalesce"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if !state.config.coalesce_misses
+                || !ctx.should_cache
+                || !ctx.cache_reads_enabled
+                // Known not to be cacheable: waiting would only delay the request.
+                || state.cache.decision_memo().contains(&ctx.cache_key)
+            {
+                return ControlFlow::Continue(());
+            }
+
+            match state.in_flight.join(&ctx.cache_key) {
+                Role::Leader(guard) => ctx.in_flight = Some(guard),
+                Role::Follower(mut done) => {
+                    // Errors once the leader's guard is dropped, which is the signal.
+                    let _ = done.changed().await;
+                    if let Some(response) = stored_answer(state, ctx).await {
+                        state.stats.record_coalesced_miss();
+                        tracing::debug!(
+                            "Answered {} {} from a concurrent fetch",
+                            ctx.method,
+                            state.redactor.target(&ctx.cache_key)
+                        );
+                        return ControlFlow::Break(response);
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// The entry the leader stored for this request's key, if this request may
+/// be served it. The key is chosen again, since the leader may have learnt
+/// the key's `Vary` and stored a variant. Lookup errors are treated as
+/// nothing stored; the follower's own fetch then applies
+/// `cache_error_policy` when it stores.
+async fn stored_answer(
+    state: &ProxyState,
+    ctx: &RequestContext,
+) -> Option<Result<Response<Body>, StatusCode>> {
+    let key = &state
+        .cache
+        .variant_key(vary::base_key(&ctx.cache_key), &ctx.request_headers);
+    let mut cached = state.cache.try_get(key).await.ok().flatten();
+    if cached.is_none() {
+        cached = state.cache.try_get_404(key).await.ok().flatten();
+    }
+    if cached.is_none() {
+        cached = state.cache.try_get_error(key).await.ok().flatten();
+    }
+    let cached = cached?;
+
+    let servable = hit_matches_origin(state, &cached, ctx.method.as_str(), &ctx.path, key).await
+        && cached_response_is_allowed(&state.config.cache_strategy, &cached)
+        && accept_mismatch(&state.config, &ctx.request_headers, &cached).is_none();
+    if !servable {
+        return None;
+    }
+    Some(serve_cached(state, key, cached, &ctx.request_headers).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendSlot, PoolOptions};
+    use crate::cache::{CacheHandle, CacheStore, CachedResponse};
+    use crate::test_support::{body_string, get};
+    use crate::CreateProxyConfig;
+
+    fn state(config: CreateProxyConfig) -> Arc<ProxyState> {
+        let backend =
+            BackendSlot::new(config.backend_urls(), PoolOptions::from_config(&config)).unwrap();
+        Arc::new(ProxyState::new(
+            CacheStore::new(CacheHandle::new(), config.cache_404_capacity),
+            config,
+            backend,
+            super::super::build_webhook_client().unwrap(),
+        ))
+    }
+
+    fn context() -> RequestContext {
+        let mut ctx = RequestContext::new(get("/page"));
+        ctx.cache_key = "GET:/page".to_string();
+        ctx.should_cache = true;
+        ctx.cache_reads_enabled = true;
+        ctx
+    }
+
+    #[tokio::test]
+    async fn test_follower_is_answered_from_leaders_entry() {
+        let state = state(CreateProxyConfig::new("http://127.0.0.1:1".to_string()));
+        let mut leader = context();
+        assert!(Coalesce.run(&state, &mut leader).await.is_continue());
+        assert!(leader.in_flight.is_some());
+
+        let follower = tokio::spawn({
+            let state = state.clone();
+            async move { Coalesce.run(&state, &mut context()).await }
+        });
+        tokio::task::yield_now().await;
+        state
+            .cache
+            .set(
+                "GET:/page".to_string(),
+                CachedResponse {
+                    body: "page".into(),
+                    headers: vec![("content-type".to_string(), "text/html".to_string())],
+                    status: 200,
+                    content_encoding: None,
+                    origin: None,
+                    content_digest: None,
+                    stored_at: None,
+                },
+            )
+            .await;
+        drop(leader);
+
+        let ControlFlow::Break(Ok(response)) = follower.await.unwrap() else {
+            panic!("the follower fetched for itself");
+        };
+        assert_eq!(body_string(response).await, "page");
+    }
+
+    #[tokio::test]
+    async fn test_misses_are_not_coalesced_when_disabled() {
+        let state = state(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string()).with_coalesce_misses(false),
+        );
+        let mut ctx = context();
+        assert!(Coalesce.run(&state, &mut ctx).await.is_continue());
+        assert!(ctx.in_flight.is_none());
+    }
+}
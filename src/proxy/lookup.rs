@@ -0,0 +1,1512 @@
+//! The stages before the miss path: filtering the request, choosing its
+//! cache key, and answering it from the cache when an entry may serve it.
+//!
+//! See the `pipeline` module for where they run and why in this order.
+
+use super::{
+    accept_mismatch, apply_cache_error_policy, build_webhook_payload, cached_response_is_allowed,
+    call_body_cache_skip_fn, call_should_cache_fn, call_webhook, client_forces_refresh,
+    extended_connect_protocol, handle_extended_connect, handle_upgrade_request, hit_matches_origin,
+    is_upgrade_request, not_modified,
+    pipeline::{RequestContext, Stage, StageFlow, StageFuture},
+    read_request_body, serve_cached, serve_range, strip_upgrade_headers, upgrade_falls_back,
+    without_body, ProxyState,
+};
+use crate::cache::CachedResponse;
+use crate::cache_rules::CacheRule;
+use crate::events::BypassReason;
+use crate::stats::Outcome;
+use crate::{AcceptMismatch, ProxyMode, WebhookType};
+use axum::{
+    body::Body,
+    http::{Method, Response, StatusCode},
+};
+use std::ops::ControlFlow;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Take the bypass and purge headers off the request. They are for the proxy
+/// alone and never reach the backend. A purge must carry `purge_token`.
+/// Without a configured header, neither is looked for and the request goes
+/// on untouched.
+pub(super) struct ProxyHeaders;
+
+impl Stage for ProxyHeaders {
+    fn name(&self) -> &'static str {
+        "proxy_headers"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.bypass_requested = state
+                .config
+                .bypass_header
+                .as_deref()
+                .and_then(|name| ctx.request_headers.remove(name))
+                .is_some_and(|value| {
+                    value.as_bytes() == b"1" || value.as_bytes().eq_ignore_ascii_case(b"true")
+                });
+            let purge_header = state.config.purge_header.as_deref();
+            let Some(given) = purge_header.and_then(|name| ctx.request_headers.remove(name)) else {
+                return ControlFlow::Continue(());
+            };
+            let authorized = given.to_str().ok().zip(state.config.purge_token.as_deref());
+            if !authorized.is_some_and(|(given, token)| crate::control::tokens_match(given, token))
+            {
+                tracing::warn!(
+                    "Rejected {} for {}: wrong or unconfigured purge_token",
+                    purge_header.unwrap_or_default(),
+                    ctx.uri.path()
+                );
+                return ControlFlow::Break(Err(StatusCode::FORBIDDEN));
+            }
+            ctx.purge_requested = true;
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Tunnel WebSocket and other upgrade requests to the backend, or refuse
+/// them. Under `upgrade_fallback = ignore_header` they continue as plain
+/// requests instead.
+pub(super) struct Upgrade;
+
+impl Stage for Upgrade {
+    fn name(&self) -> &'static str {
+        "upgrade"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let extended_connect = ctx.method == Method::CONNECT
+                && ctx.extensions.get::<hyper::ext::Protocol>().is_some();
+            if !is_upgrade_request(&ctx.request_headers) && !extended_connect {
+                return ControlFlow::Continue(());
+            }
+            let mut req = ctx.take_request();
+            let method_str = req.method().as_str();
+            let path = req.uri().path();
+
+            // WebSocket / upgrade tunnelling is only meaningful when there is a live
+            // backend to tunnel to.  Pure SSG servers (PreGenerate with fallthrough
+            // disabled) have no backend reachable at request time, so they never
+            // tunnel regardless of the `enable_websocket` flag.
+            let ws_allowed = state.config.enable_websocket
+                && match &state.config.proxy_mode {
+                    ProxyMode::Dynamic => true,
+                    ProxyMode::PreGenerate { fallthrough, .. } => *fallthrough,
+                };
+
+            if ws_allowed {
+                if let Some(protocol) = extended_connect_protocol(&req) {
+                    if !protocol.eq_ignore_ascii_case("websocket") {
+                        tracing::warn!(
+                            "Extended CONNECT for unsupported protocol '{}' on {}",
+                            protocol,
+                            path
+                        );
+                        return ControlFlow::Break(Err(StatusCode::NOT_IMPLEMENTED));
+                    }
+                    tracing::debug!(
+                        "HTTP/2 WebSocket request detected for {}, translating to HTTP/1.1 upgrade",
+                        path
+                    );
+                    return ControlFlow::Break(handle_extended_connect(state.clone(), req).await);
+                }
+                tracing::debug!(
+                    "Upgrade request detected for {} {}, establishing direct proxy tunnel",
+                    method_str,
+                    path
+                );
+                return ControlFlow::Break(handle_upgrade_request(state.clone(), req).await);
+            } else if upgrade_falls_back(state, &req) {
+                tracing::debug!(
+                    "Upgrade request for {} {} handled as a plain request (upgrade_fallback = ignore_header)",
+                    method_str,
+                    path
+                );
+            } else {
+                tracing::warn!(
+                    "Upgrade request detected for {} {} but WebSocket support is disabled or not available in current proxy mode",
+                    method_str,
+                    path
+                );
+                return ControlFlow::Break(Err(state.config.upgrade_reject_status));
+            }
+
+            strip_upgrade_headers(req.headers_mut());
+            ctx.restore_request(req);
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Refuse anything but `GET` under `forward_get_only`.
+pub(super) struct AllowMethod;
+
+impl Stage for AllowMethod {
+    fn name(&self) -> &'static str {
+        "allow_method"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if state.config.forward_get_only && ctx.method != Method::GET {
+                tracing::warn!(
+                    "Non-GET request {} {} rejected (forward_get_only is enabled)",
+                    ctx.method,
+                    ctx.path
+                );
+                return ControlFlow::Break(Err(StatusCode::METHOD_NOT_ALLOWED));
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Call the configured webhooks: notify webhooks in the background, blocking
+/// ones to allow, redirect or deny the request, and `cache_key` ones for the
+/// key [`Key`] uses.
+pub(super) struct Webhooks;
+
+impl Stage for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if state.config.webhooks.is_empty() {
+                return ControlFlow::Continue(());
+            }
+            let method_str = ctx.method.as_str();
+            let path = ctx.path.as_str();
+            let payload =
+                build_webhook_payload(method_str, path, ctx.query(), &ctx.request_headers);
+            let webhook_started = Instant::now();
+
+            for webhook in &state.config.webhooks {
+                let timeout_ms = webhook.timeout_ms.unwrap_or(5000);
+                match webhook.webhook_type {
+                    WebhookType::Notify => {
+                        // Fire-and-forget: spawn without awaiting.
+                        let url = webhook.url.clone();
+                        let payload_clone = payload.clone();
+                        let webhook_client = state.webhook_client.clone();
+                        tokio::spawn(async move {
+                            if let Err(()) =
+                                call_webhook(&webhook_client, &url, &payload_clone, timeout_ms)
+                                    .await
+                            {
+                                tracing::warn!("Notify webhook POST to '{}' failed", url);
+                            }
+                        });
+                    }
+                    WebhookType::Blocking => {
+                        match call_webhook(
+                            &state.webhook_client,
+                            &webhook.url,
+                            &payload,
+                            timeout_ms,
+                        )
+                        .await
+                        {
+                            Ok(result) if result.status.is_success() => {
+                                tracing::debug!(
+                                    "Blocking webhook '{}' allowed {} {}",
+                                    webhook.url,
+                                    method_str,
+                                    path
+                                );
+                            }
+                            Ok(result) if result.status.is_redirection() => {
+                                tracing::debug!(
+                                    "Blocking webhook '{}' redirecting {} {} to {}",
+                                    webhook.url,
+                                    method_str,
+                                    path,
+                                    result.location.as_deref().unwrap_or("(no location)")
+                                );
+                                let mut builder = Response::builder().status(result.status);
+                                if let Some(loc) = &result.location {
+                                    builder =
+                                        builder.header(axum::http::header::LOCATION, loc.as_str());
+                                }
+                                return ControlFlow::Break(
+                                    builder
+                                        .body(Body::empty())
+                                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+                                );
+                            }
+                            Ok(result) => {
+                                tracing::warn!(
+                                    "Blocking webhook '{}' denied {} {} with status {}",
+                                    webhook.url,
+                                    method_str,
+                                    path,
+                                    result.status
+                                );
+                                return ControlFlow::Break(Err(result.status));
+                            }
+                            Err(()) => {
+                                tracing::warn!(
+                                    "Blocking webhook '{}' timed out or failed for {} {} — denying request",
+                                    webhook.url,
+                                    method_str,
+                                    path
+                                );
+                                return ControlFlow::Break(Err(StatusCode::SERVICE_UNAVAILABLE));
+                            }
+                        }
+                    }
+                    WebhookType::CacheKey => {
+                        match call_webhook(
+                            &state.webhook_client,
+                            &webhook.url,
+                            &payload,
+                            timeout_ms,
+                        )
+                        .await
+                        {
+                            Ok(result) if result.status.is_success() => {
+                                let key = result.body.trim().to_string();
+                                if !key.is_empty() {
+                                    tracing::debug!(
+                                        "Cache key webhook '{}' set key '{}' for {} {}",
+                                        webhook.url,
+                                        state.redactor.target(&key),
+                                        method_str,
+                                        path
+                                    );
+                                    ctx.cache_key_override = Some(key);
+                                } else {
+                                    tracing::warn!(
+                                        "Cache key webhook '{}' returned empty body for {} {} — using default key",
+                                        webhook.url,
+                                        method_str,
+                                        path
+                                    );
+                                }
+                            }
+                            Ok(result) => {
+                                tracing::warn!(
+                                    "Cache key webhook '{}' returned non-2xx {} for {} {} — using default key",
+                                    webhook.url,
+                                    result.status,
+                                    method_str,
+                                    path
+                                );
+                            }
+                            Err(()) => {
+                                tracing::warn!(
+                                    "Cache key webhook '{}' timed out or failed for {} {} — using default key",
+                                    webhook.url,
+                                    method_str,
+                                    path
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+
+            tracing::debug!(
+                method = method_str,
+                path,
+                elapsed_ms = webhook_started.elapsed().as_millis(),
+                "proxy request completed webhook phase"
+            );
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// With `serve_head_from_get`, look a `HEAD` up as the `GET` for the same
+/// URL: rules, key functions, and the entry are the `GET`'s.
+pub(super) struct HeadAsGet;
+
+impl Stage for HeadAsGet {
+    fn name(&self) -> &'static str {
+        "head_as_get"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.head_from_get = ctx.method == Method::HEAD && state.config.serve_head_from_get;
+            if ctx.head_from_get {
+                ctx.lookup_method = Method::GET;
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Decide from the cache rules, or `path_rules` or `include_paths` and
+/// `exclude_paths`, whether the request may be cached. While caching is
+/// paused, every request goes straight to the backend.
+pub(super) struct Filter;
+
+impl Stage for Filter {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.paused = state.caching_paused();
+            ctx.should_cache = !ctx.paused
+                && state.should_cache(ctx.lookup_method.as_str(), &ctx.path, ctx.query());
+            ctx.cache_reads_enabled =
+                !ctx.paused && !matches!(state.config.cache_strategy, crate::CacheStrategy::None);
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Keep requests from signed-in visitors out of the cache: their page must
+/// not be stored, nor answered with one stored for someone else.
+pub(super) struct Credentials;
+
+impl Stage for Credentials {
+    fn name(&self) -> &'static str {
+        "credentials"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.credential = state.credentials.find(&ctx.request_headers);
+            ctx.credential_bypass = ctx.credential.filter(|_| ctx.should_cache);
+            ctx.should_cache &= ctx.credential.is_none();
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Let `should_cache_fn` have the last word, either way, then keep a client
+/// that asked to bypass the cache away from it.
+pub(super) struct Decide;
+
+impl Stage for Decide {
+    fn name(&self) -> &'static str {
+        "decide"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if let Some(decide) = state
+                .config
+                .should_cache_fn
+                .as_deref()
+                .filter(|_| !ctx.paused)
+            {
+                let decision = call_should_cache_fn(state, decide, &ctx.request_info());
+                match decision {
+                    crate::CacheDecision::Cache => ctx.should_cache = true,
+                    crate::CacheDecision::Bypass => ctx.should_cache = false,
+                    crate::CacheDecision::Default => {}
+                }
+            }
+            ctx.should_cache &= !ctx.bypass_requested;
+            // Credentials, not the path, keep this request away from the cache.
+            ctx.credential_bypass = ctx
+                .credential_bypass
+                .filter(|_| !ctx.should_cache && !ctx.bypass_requested);
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Choose the cache key: a `cache_key` webhook's, or the key function's,
+/// extended by the matching cache rule, which also caps the entry's
+/// lifetime. Without a key (the key function declined or panicked), the
+/// request bypasses the cache entirely.
+pub(super) struct Key;
+
+impl Stage for Key {
+    fn name(&self) -> &'static str {
+        "key"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let key = match ctx.cache_key_override.take() {
+                Some(key) => Some(key),
+                None => match state.cache_key.key(&ctx.request_info()).await {
+                    Ok(Some(key)) => Some(key),
+                    Ok(None) => {
+                        tracing::debug!(
+                            "cache_key_fn returned no key for {} {} — bypassing the cache",
+                            ctx.method,
+                            ctx.path
+                        );
+                        None
+                    }
+                    Err(message) => {
+                        tracing::warn!(
+                            "cache_key_fn panicked for {} {}: {} — bypassing the cache",
+                            ctx.method,
+                            ctx.path,
+                            message
+                        );
+                        state.callback_panics.fetch_add(1, Ordering::Relaxed);
+                        None
+                    }
+                },
+            };
+            ctx.no_cache_key = key.is_none();
+            ctx.cache_key = key.unwrap_or_default();
+            ctx.should_cache &= !ctx.no_cache_key;
+            ctx.cache_reads_enabled &= !ctx.no_cache_key;
+
+            let cache_rule = state.cache_rule(ctx.lookup_method.as_str(), &ctx.path, ctx.query());
+            ctx.entry_ttl = cache_rule.and_then(CacheRule::ttl);
+            if let Some(component) =
+                cache_rule.and_then(|rule| rule.key_component(&ctx.request_headers))
+            {
+                crate::cache_rules::append_key_component(&mut ctx.cache_key, &component);
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// For requests cached by body, read the body before any lookup and key the
+/// entry on it. Whatever the rule refuses goes to the backend uncached.
+pub(super) struct BodyKey;
+
+impl Stage for BodyKey {
+    fn name(&self) -> &'static str {
+        "body_key"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let body_rule = (ctx.should_cache && !ctx.head_from_get)
+                .then(|| {
+                    state
+                        .body_cache_rules
+                        .find(ctx.method.as_str(), &ctx.path, ctx.query())
+                })
+                .flatten();
+            let Some(rule) = body_rule else {
+                return ControlFlow::Continue(());
+            };
+            let body = match read_request_body(state, ctx.take_body().unwrap_or_default()).await {
+                Ok(body) => body,
+                Err(status) => return ControlFlow::Break(Err(status)),
+            };
+            let component = rule.key_component(&body).and_then(|component| {
+                match &state.config.body_cache_skip_fn {
+                    Some(skip)
+                        if call_body_cache_skip_fn(
+                            state,
+                            skip.as_ref(),
+                            &ctx.request_info(),
+                            &body,
+                        ) =>
+                    {
+                        Err("body_cache_skip_fn returned true")
+                    }
+                    _ => Ok(component),
+                }
+            });
+            match component {
+                Ok(component) => {
+                    ctx.cache_key = format!("{}#body={}", ctx.cache_key, component);
+                    ctx.entry_ttl =
+                        Some(ctx.entry_ttl.map_or(rule.ttl(), |ttl| ttl.min(rule.ttl())));
+                }
+                Err(reason) => {
+                    tracing::debug!(
+                        "{} {} not cached by body ({}), proxying directly",
+                        ctx.method,
+                        ctx.path,
+                        reason
+                    );
+                    ctx.should_cache = false;
+                }
+            }
+            ctx.request_body = body;
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Drop the entry, with its variants, when the request carried the purge
+/// header, so the request continues as a miss.
+pub(super) struct Purge;
+
+impl Stage for Purge {
+    fn name(&self) -> &'static str {
+        "purge"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if ctx.purge_requested && !ctx.no_cache_key {
+                let purged = state.cache.clear_key(&ctx.cache_key).await;
+                tracing::info!(
+                    "Purged {} entries for {} on request",
+                    purged,
+                    state.redactor.target(&ctx.cache_key)
+                );
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// With `respect_vary`, switch to the key of the variant the request's
+/// headers select.
+pub(super) struct Variant;
+
+impl Stage for Variant {
+    fn name(&self) -> &'static str {
+        "variant"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if state.config.respect_vary {
+                ctx.cache_key = state
+                    .cache
+                    .variant_key(&ctx.cache_key, &ctx.request_headers);
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Skip the lookups for a client asking for a fresh copy; the miss path then
+/// fetches it and replaces the stored entry. Without fallthrough there is no
+/// backend to fetch from.
+pub(super) struct Refresh;
+
+impl Stage for Refresh {
+    fn name(&self) -> &'static str {
+        "refresh"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.refresh = ctx.should_cache
+                && ctx.cache_reads_enabled
+                && !matches!(
+                    state.config.proxy_mode,
+                    ProxyMode::PreGenerate {
+                        fallthrough: false,
+                        ..
+                    }
+                )
+                && client_forces_refresh(&state.config, &ctx.request_headers);
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Read the `Range` header. A single byte range is cut from a cached entry;
+/// several ranges, or a range nothing is cached for, go to the backend
+/// without a store.
+pub(super) struct Range;
+
+impl Stage for Range {
+    fn name(&self) -> &'static str {
+        "range"
+    }
+
+    fn run<'a>(
+        &'a self,
+        _state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            ctx.range = ctx
+                .request_headers
+                .get(axum::http::header::RANGE)
+                .map(|value| value.to_str().ok().and_then(crate::range::ByteRange::parse));
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Answer from the 404 store. It is consulted even when the path rules keep
+/// the request out of the main cache, unless credentials or a bypass request
+/// do.
+pub(super) struct Lookup404;
+
+impl Stage for Lookup404 {
+    fn name(&self) -> &'static str {
+        "lookup_404"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let credentials_allowed = ctx.credential.is_none() || ctx.should_cache;
+            if !ctx.cache_reads_enabled
+                || !credentials_allowed
+                || ctx.bypass_requested
+                || multi_range(ctx)
+                || ctx.refresh
+                || state.config.cache_404_capacity == 0
+            {
+                return ControlFlow::Continue(());
+            }
+            let cached = match state.cache.try_get_404(&ctx.cache_key).await {
+                Ok(cached) => cached,
+                Err(error) => {
+                    if let Err(status) =
+                        apply_cache_error_policy(state, error, "lookup", &ctx.cache_key)
+                    {
+                        return ControlFlow::Break(Err(status));
+                    }
+                    None
+                }
+            };
+            let Some(cached) = cached else {
+                return ControlFlow::Continue(());
+            };
+            if !servable(state, ctx, &cached).await? {
+                return ControlFlow::Continue(());
+            }
+
+            tracing::debug!(
+                "404 cache hit for: {} {}",
+                ctx.method,
+                state.redactor.target(&ctx.cache_key)
+            );
+            state.stats.record(Outcome::Hit);
+            let age = cached.age();
+            state.emit(|events| events.on_hit(&ctx.cache_key, age));
+            match serve_cached(state, &ctx.cache_key, cached, &ctx.request_headers).await {
+                Ok(response) => serve_hit(state, ctx, response, age, "404"),
+                Err(status) => ControlFlow::Break(Err(status)),
+            }
+        })
+    }
+}
+
+/// Answer from the main cache or, when it has nothing for the key, the error
+/// store: a live main-cache entry takes precedence over a briefly cached
+/// error. Only requests that may be cached look either up.
+pub(super) struct Lookup;
+
+impl Stage for Lookup {
+    fn name(&self) -> &'static str {
+        "lookup"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if !ctx.should_cache
+                || !ctx.cache_reads_enabled
+                || ctx.accept_bypass
+                || multi_range(ctx)
+                || ctx.refresh
+            {
+                return ControlFlow::Continue(());
+            }
+            let lookup = match state.cache.try_get(&ctx.cache_key).await {
+                Ok(Some(cached)) => Ok(Some((cached, "main"))),
+                Ok(None) => state
+                    .cache
+                    .try_get_error(&ctx.cache_key)
+                    .await
+                    .map(|cached| cached.map(|cached| (cached, "error"))),
+                Err(error) => Err(error),
+            };
+            let cached = match lookup {
+                Ok(cached) => cached,
+                Err(error) => {
+                    if let Err(status) =
+                        apply_cache_error_policy(state, error, "lookup", &ctx.cache_key)
+                    {
+                        return ControlFlow::Break(Err(status));
+                    }
+                    None
+                }
+            };
+            let Some((cached, store)) = cached else {
+                return ControlFlow::Continue(());
+            };
+            if !servable(state, ctx, &cached).await? {
+                return ControlFlow::Continue(());
+            }
+
+            tracing::debug!(
+                "Cache hit for: {} {}",
+                ctx.method,
+                state.redactor.target(&ctx.cache_key)
+            );
+            state.stats.record(Outcome::Hit);
+            if store == "main" {
+                state.cache.record_hit(&ctx.cache_key);
+            }
+            let age = cached.age();
+            state.emit(|events| events.on_hit(&ctx.cache_key, age));
+            let response = match (
+                not_modified(ctx.method.as_str(), &ctx.request_headers, &cached),
+                ctx.range,
+            ) {
+                (Some(response), _) => Ok(response),
+                (None, Some(Some(range))) if cached.status == 200 => {
+                    serve_range(state, &ctx.cache_key, cached, &ctx.request_headers, range).await
+                }
+                (None, _) => {
+                    serve_cached(state, &ctx.cache_key, cached, &ctx.request_headers).await
+                }
+            };
+            match response {
+                Ok(response) => serve_hit(state, ctx, response, age, store),
+                Err(status) => ControlFlow::Break(Err(status)),
+            }
+        })
+    }
+}
+
+/// The request asked for several byte ranges, or for ones that cannot be
+/// parsed.
+fn multi_range(ctx: &RequestContext) -> bool {
+    matches!(ctx.range, Some(None))
+}
+
+/// Whether an entry found for the request may answer it, as if nothing was
+/// found when not. An entry the client's `Accept` rules out ends the request
+/// with `406`, or under `accept_mismatch = bypass` sends it to the backend.
+async fn servable(
+    state: &ProxyState,
+    ctx: &mut RequestContext,
+    cached: &CachedResponse,
+) -> ControlFlow<Result<Response<Body>, StatusCode>, bool> {
+    if !hit_matches_origin(
+        state,
+        cached,
+        ctx.lookup_method.as_str(),
+        &ctx.path,
+        &ctx.cache_key,
+    )
+    .await
+        || !cached_response_is_allowed(&state.config.cache_strategy, cached)
+    {
+        return ControlFlow::Continue(false);
+    }
+    match accept_mismatch(&state.config, &ctx.request_headers, cached) {
+        None => ControlFlow::Continue(true),
+        Some(AcceptMismatch::Reject) => {
+            state.stats.record(Outcome::Bypass);
+            state.emit(|events| events.on_bypass(&ctx.path, BypassReason::AcceptMismatch));
+            ControlFlow::Break(Err(StatusCode::NOT_ACCEPTABLE))
+        }
+        Some(_) => {
+            ctx.accept_bypass = true;
+            ControlFlow::Continue(false)
+        }
+    }
+}
+
+/// End the request with a hit from the `store` cache.
+fn serve_hit(
+    state: &ProxyState,
+    ctx: &RequestContext,
+    mut response: Response<Body>,
+    age: Option<Duration>,
+    store: &str,
+) -> StageFlow {
+    if ctx.head_from_get {
+        response = without_body(response);
+    }
+    state.mark_cache_status(&mut response, Outcome::Hit, age);
+    tracing::debug!(
+        method = ctx.method.as_str(),
+        path = ctx.path,
+        elapsed_ms = ctx.request_started.elapsed().as_millis(),
+        status = response.status().as_u16(),
+        "proxy request served from {} cache",
+        store
+    );
+    ControlFlow::Break(Ok(response))
+}
+
+/// Record why the cache did not answer, read the body, and pick the backend.
+/// A `HEAD` miss goes to the backend as a `HEAD` and is not stored: its empty
+/// body would stand in for the `GET`'s. A range request's `206` streams
+/// through, since a partial body must not stand in for the whole.
+pub(super) struct Miss;
+
+impl Stage for Miss {
+    fn name(&self) -> &'static str {
+        "miss"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if let Err(status) = record_miss(state, ctx) {
+                return ControlFlow::Break(Err(status));
+            }
+            if let Some(body) = ctx.take_body() {
+                match read_request_body(state, body).await {
+                    Ok(body) => ctx.request_body = body,
+                    Err(status) => return ControlFlow::Break(Err(status)),
+                }
+            }
+
+            let passthrough = ctx.accept_bypass || ctx.head_from_get || ctx.range.is_some();
+            ctx.outcome = if ctx.should_cache && ctx.cache_reads_enabled && !passthrough {
+                Outcome::Miss
+            } else {
+                Outcome::Bypass
+            };
+            ctx.should_cache &= !passthrough;
+            ctx.cache_reads_enabled &= !passthrough;
+            ctx.backend = Some(state.backend.pick());
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Count the miss or bypass and report it to `events`. In PreGenerate mode
+/// without fallthrough there is no backend to go to, so the request ends.
+fn record_miss(state: &ProxyState, ctx: &RequestContext) -> Result<(), StatusCode> {
+    let method_str = ctx.method.as_str();
+    let path = ctx.path.as_str();
+    let head_from_get = ctx.head_from_get;
+    if ctx.accept_bypass {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::AcceptMismatch));
+        // Without fallthrough there is no backend to negotiate with.
+        if let ProxyMode::PreGenerate {
+            fallthrough: false, ..
+        } = &state.config.proxy_mode
+        {
+            return Err(StatusCode::NOT_ACCEPTABLE);
+        }
+        tracing::debug!(
+            "Cached entry for {} {} does not satisfy Accept, proxying directly",
+            method_str,
+            state.redactor.target(&ctx.cache_key)
+        );
+    } else if ctx.should_cache && ctx.cache_reads_enabled {
+        if ctx.range.is_some() {
+            state.stats.record(Outcome::Bypass);
+            state.emit(|events| events.on_bypass(path, BypassReason::Range));
+        } else if head_from_get {
+            state.stats.record(Outcome::Bypass);
+            state.emit(|events| events.on_bypass(path, BypassReason::Head));
+        } else {
+            state.stats.record(Outcome::Miss);
+            state.emit(|events| events.on_miss(&ctx.cache_key));
+        }
+        // PreGenerate mode: serve only from cache, no backend fallthrough on miss
+        if let ProxyMode::PreGenerate { fallthrough, .. } = &state.config.proxy_mode {
+            if !fallthrough {
+                tracing::debug!(
+                    "PreGenerate cache miss for: {} {} — returning 404 (fallthrough disabled)",
+                    method_str,
+                    state.redactor.target(&ctx.cache_key)
+                );
+                return Err(StatusCode::NOT_FOUND);
+            }
+        }
+        tracing::debug!(
+            "{} for: {} {}, fetching from backend",
+            if ctx.range.is_some() {
+                "Range request"
+            } else if head_from_get {
+                "HEAD without a cached GET"
+            } else if ctx.refresh {
+                "Refresh requested"
+            } else {
+                "Cache miss"
+            },
+            method_str,
+            state.redactor.target(&ctx.cache_key)
+        );
+    } else if ctx.no_cache_key {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::NoCacheKey));
+    } else if ctx.paused {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::Paused));
+        tracing::debug!(
+            "{} {} proxied directly, caching is paused",
+            method_str,
+            path
+        );
+    } else if !ctx.cache_reads_enabled {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::CacheDisabled));
+        tracing::debug!(
+            "{} {} not cacheable (cache strategy: none), proxying directly",
+            method_str,
+            path
+        );
+    } else if ctx.bypass_requested {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::Requested));
+        tracing::debug!(
+            "{} {} asked to bypass the cache, proxying directly",
+            method_str,
+            path
+        );
+    } else if let Some(credential) = ctx.credential_bypass {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::Credentials));
+        tracing::debug!(
+            bypass_reason = credential.as_str(),
+            "{} {} carries credentials, proxying directly",
+            method_str,
+            path
+        );
+    } else {
+        state.stats.record(Outcome::Bypass);
+        state.emit(|events| events.on_bypass(path, BypassReason::Filtered));
+        tracing::debug!(
+            "{} {} not cacheable (filtered), proxying directly",
+            method_str,
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendSlot, PoolOptions};
+    use crate::body_cache::{BodyCacheRule, BodyKey as BodyKeyKind};
+    use crate::cache::CacheHandle;
+    use crate::credentials::Credential;
+    use crate::test_support::{body_string, get};
+    use crate::{CacheDecision, CreateProxyConfig, UpgradeFallback, WebhookConfig};
+    use axum::http::Request;
+
+    /// Every stage before the lookups.
+    const BEFORE_LOOKUP: [&dyn Stage; 14] = [
+        &ProxyHeaders,
+        &Upgrade,
+        &AllowMethod,
+        &Webhooks,
+        &HeadAsGet,
+        &Filter,
+        &Credentials,
+        &Decide,
+        &Key,
+        &BodyKey,
+        &Purge,
+        &Variant,
+        &Refresh,
+        &Range,
+    ];
+
+    fn config() -> CreateProxyConfig {
+        CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+    }
+
+    fn state(config: CreateProxyConfig) -> Arc<ProxyState> {
+        let backend =
+            BackendSlot::new(config.backend_urls(), PoolOptions::from_config(&config)).unwrap();
+        Arc::new(ProxyState::new(
+            crate::cache_store_for(&config, CacheHandle::new()),
+            config,
+            backend,
+            super::super::build_webhook_client().unwrap(),
+        ))
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn page(status: u16, body: &str) -> CachedResponse {
+        CachedResponse {
+            body: body.to_string().into(),
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            status,
+            content_encoding: None,
+            origin: None,
+            content_digest: None,
+            stored_at: None,
+        }
+    }
+
+    /// Run `stages` in order, returning how the first one to end the request
+    /// ended it.
+    async fn run(
+        state: &Arc<ProxyState>,
+        ctx: &mut RequestContext,
+        stages: &[&dyn Stage],
+    ) -> Option<Result<Response<Body>, StatusCode>> {
+        for stage in stages {
+            if let ControlFlow::Break(result) = stage.run(state, ctx).await {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    /// The context after every stage before the lookups ran for `req`.
+    async fn prepared(state: &Arc<ProxyState>, req: Request<Body>) -> RequestContext {
+        let mut ctx = RequestContext::new(req);
+        assert!(run(state, &mut ctx, &BEFORE_LOOKUP).await.is_none());
+        ctx
+    }
+
+    #[tokio::test]
+    async fn test_proxy_headers_are_taken_off_and_purges_need_the_token() {
+        let state = state(
+            config()
+                .with_bypass_header("x-bypass")
+                .with_purge_header("x-purge")
+                .with_purge_token("secret"),
+        );
+        let mut ctx = RequestContext::new(request(
+            "GET",
+            "/page",
+            &[("x-bypass", "true"), ("x-purge", "secret")],
+        ));
+        assert!(run(&state, &mut ctx, &[&ProxyHeaders]).await.is_none());
+        assert!(ctx.bypass_requested);
+        assert!(ctx.purge_requested);
+        assert!(!ctx.request_headers.contains_key("x-bypass"));
+        assert!(!ctx.request_headers.contains_key("x-purge"));
+
+        let mut ctx = RequestContext::new(request("GET", "/page", &[("x-purge", "wrong")]));
+        assert!(matches!(
+            run(&state, &mut ctx, &[&ProxyHeaders]).await,
+            Some(Err(StatusCode::FORBIDDEN))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_is_refused_or_falls_back() {
+        let upgrade = || {
+            RequestContext::new(request(
+                "GET",
+                "/ws",
+                &[
+                    ("connection", "keep-alive, upgrade"),
+                    ("upgrade", "websocket"),
+                ],
+            ))
+        };
+
+        let refused = state(config().with_websocket_enabled(false));
+        assert!(matches!(
+            run(&refused, &mut upgrade(), &[&Upgrade]).await,
+            Some(Err(StatusCode::NOT_IMPLEMENTED))
+        ));
+
+        let fallback = state(
+            config()
+                .with_websocket_enabled(false)
+                .with_upgrade_fallback(UpgradeFallback::IgnoreHeader),
+        );
+        let mut ctx = upgrade();
+        assert!(run(&fallback, &mut ctx, &[&Upgrade]).await.is_none());
+        assert!(!ctx.request_headers.contains_key("upgrade"));
+        assert_eq!(ctx.request_headers["connection"], "keep-alive");
+        assert!(ctx.take_body().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_allow_method_refuses_non_get() {
+        let state = state(config().with_forward_get_only(true));
+        let mut post = RequestContext::new(request("POST", "/page", &[]));
+        assert!(matches!(
+            run(&state, &mut post, &[&AllowMethod]).await,
+            Some(Err(StatusCode::METHOD_NOT_ALLOWED))
+        ));
+        let mut get = RequestContext::new(get("/page"));
+        assert!(run(&state, &mut get, &[&AllowMethod]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_blocking_webhook_denies() {
+        let webhook = |webhook_type| WebhookConfig {
+            url: "http://127.0.0.1:1/hook".to_string(),
+            webhook_type,
+            timeout_ms: Some(500),
+        };
+        let notify = state(config().with_webhooks(vec![webhook(WebhookType::Notify)]));
+        let mut ctx = RequestContext::new(get("/page"));
+        assert!(run(&notify, &mut ctx, &[&Webhooks]).await.is_none());
+
+        let blocking = state(config().with_webhooks(vec![webhook(WebhookType::Blocking)]));
+        let mut ctx = RequestContext::new(get("/page"));
+        assert!(matches!(
+            run(&blocking, &mut ctx, &[&Webhooks]).await,
+            Some(Err(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_head_is_looked_up_as_get() {
+        let mut ctx = RequestContext::new(request("HEAD", "/page", &[]));
+        run(
+            &state(config().with_serve_head_from_get(true)),
+            &mut ctx,
+            &[&HeadAsGet],
+        )
+        .await;
+        assert!(ctx.head_from_get);
+        assert_eq!(ctx.lookup_method, Method::GET);
+
+        let mut ctx = RequestContext::new(request("HEAD", "/page", &[]));
+        run(
+            &state(config().with_serve_head_from_get(false)),
+            &mut ctx,
+            &[&HeadAsGet],
+        )
+        .await;
+        assert!(!ctx.head_from_get);
+        assert_eq!(ctx.lookup_method, Method::HEAD);
+    }
+
+    #[tokio::test]
+    async fn test_filter_follows_path_rules_and_pause() {
+        let state = state(config().with_exclude_paths(vec!["/private/*".to_string()]));
+        let filtered = |uri: &str| {
+            let state = state.clone();
+            let mut ctx = RequestContext::new(get(uri));
+            async move {
+                run(&state, &mut ctx, &[&Filter]).await;
+                (ctx.should_cache, ctx.cache_reads_enabled)
+            }
+        };
+
+        assert_eq!(filtered("/page").await, (true, true));
+        assert_eq!(filtered("/private/page").await, (false, true));
+        state.set_caching_paused(true);
+        assert_eq!(filtered("/page").await, (false, false));
+    }
+
+    #[tokio::test]
+    async fn test_credentials_keep_request_from_cache() {
+        let state = state(config().with_bypass_credentialed_requests(true));
+        let mut ctx = RequestContext::new(request(
+            "GET",
+            "/page",
+            &[("authorization", "Bearer token")],
+        ));
+        run(&state, &mut ctx, &[&Filter, &Credentials]).await;
+        assert!(!ctx.should_cache);
+        assert_eq!(ctx.credential, Some(Credential::Authorization));
+        assert_eq!(ctx.credential_bypass, Some(Credential::Authorization));
+    }
+
+    #[tokio::test]
+    async fn test_should_cache_fn_overrides_credentials_but_not_bypass() {
+        let state = state(
+            config()
+                .with_bypass_credentialed_requests(true)
+                .with_bypass_header("x-bypass")
+                .with_should_cache_fn(|_| CacheDecision::Cache),
+        );
+        let decided = |headers: &'static [(&'static str, &'static str)]| {
+            let state = state.clone();
+            let mut ctx = RequestContext::new(request("GET", "/page", headers));
+            async move {
+                run(
+                    &state,
+                    &mut ctx,
+                    &[&ProxyHeaders, &Filter, &Credentials, &Decide],
+                )
+                .await;
+                (ctx.should_cache, ctx.credential_bypass)
+            }
+        };
+
+        assert_eq!(
+            decided(&[("authorization", "Bearer token")]).await,
+            (true, None)
+        );
+        assert_eq!(
+            decided(&[("authorization", "Bearer token"), ("x-bypass", "1")]).await,
+            (false, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_follows_cache_rule_and_key_fn() {
+        let ruled = state(config().with_cache_rules(vec![CacheRule::new("/page")
+            .with_ttl_secs(30)
+            .with_key_headers(vec!["x-tenant".to_string()])]));
+        let keyed = |headers: &'static [(&'static str, &'static str)]| {
+            let state = ruled.clone();
+            let mut ctx = RequestContext::new(request("GET", "/page", headers));
+            async move {
+                run(&state, &mut ctx, &[&Filter, &Key]).await;
+                ctx
+            }
+        };
+
+        let plain = keyed(&[]).await;
+        let tenant = keyed(&[("x-tenant", "a")]).await;
+        assert_eq!(tenant.entry_ttl, Some(Duration::from_secs(30)));
+        assert!(tenant.cache_key.starts_with(&plain.cache_key));
+        assert_ne!(tenant.cache_key, plain.cache_key);
+
+        let declined = state(config().with_cache_key_opt_fn(|_| None));
+        let mut ctx = RequestContext::new(get("/page"));
+        run(&declined, &mut ctx, &[&Filter, &Key]).await;
+        assert!(ctx.no_cache_key);
+        assert!(!ctx.should_cache);
+        assert!(!ctx.cache_reads_enabled);
+    }
+
+    #[tokio::test]
+    async fn test_body_key_reads_body_into_key() {
+        let state = state(config().with_body_cache_rules(vec![
+            BodyCacheRule::new("POST /search", BodyKeyKind::Hash).with_ttl_secs(10),
+        ]));
+        let search = |body: &'static str| Request::post("/search").body(Body::from(body)).unwrap();
+
+        let mut first = prepared(&state, search("{\"q\":1}")).await;
+        let second = prepared(&state, search("{\"q\":2}")).await;
+        assert!(first.cache_key.contains("#body="));
+        assert_ne!(first.cache_key, second.cache_key);
+        assert_eq!(first.entry_ttl, Some(Duration::from_secs(10)));
+        assert_eq!(first.request_body, "{\"q\":1}");
+        assert!(first.take_body().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_purge_drops_entry() {
+        let state = state(
+            config()
+                .with_purge_header("x-purge")
+                .with_purge_token("secret"),
+        );
+        let key = prepared(&state, get("/page")).await.cache_key;
+        state.cache.set(key.clone(), page(200, "old")).await;
+
+        prepared(&state, request("GET", "/page", &[("x-purge", "secret")])).await;
+        assert!(state.cache.get(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_variant_follows_learned_vary() {
+        let state = state(config().with_respect_vary(true));
+        let base = prepared(&state, get("/page")).await.cache_key;
+        state
+            .cache
+            .set_vary(&base, vec!["accept-language".to_string()]);
+
+        let english = prepared(
+            &state,
+            request("GET", "/page", &[("accept-language", "en")]),
+        )
+        .await;
+        let german = prepared(
+            &state,
+            request("GET", "/page", &[("accept-language", "de")]),
+        )
+        .await;
+        assert!(english.cache_key.starts_with(&base));
+        assert_ne!(english.cache_key, german.cache_key);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_needs_a_backend() {
+        let no_cache = || request("GET", "/page", &[("cache-control", "no-cache")]);
+        let dynamic = state(config().with_respect_client_no_cache(true));
+        assert!(prepared(&dynamic, no_cache()).await.refresh);
+        assert!(!prepared(&dynamic, get("/page")).await.refresh);
+
+        let pregenerated = state(config().with_respect_client_no_cache(true).with_proxy_mode(
+            ProxyMode::PreGenerate {
+                paths: vec![],
+                fallthrough: false,
+            },
+        ));
+        assert!(!prepared(&pregenerated, no_cache()).await.refresh);
+    }
+
+    #[tokio::test]
+    async fn test_range_tells_single_from_multiple() {
+        let ranged = |range: &'static str| {
+            let mut ctx = RequestContext::new(request("GET", "/page", &[("range", range)]));
+            async move {
+                run(&state(config()), &mut ctx, &[&Range]).await;
+                ctx.range
+            }
+        };
+
+        assert!(matches!(ranged("bytes=0-9").await, Some(Some(_))));
+        assert!(matches!(ranged("bytes=0-1,4-5").await, Some(None)));
+        let mut ctx = RequestContext::new(get("/page"));
+        run(&state(config()), &mut ctx, &[&Range]).await;
+        assert!(ctx.range.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_404_answers_head_without_body() {
+        let state = state(
+            config()
+                .with_cache_404_capacity(10)
+                .with_serve_head_from_get(true),
+        );
+        let key = prepared(&state, get("/gone")).await.cache_key;
+        state.cache.set_404(key, page(404, "gone")).await;
+
+        let mut ctx = prepared(&state, get("/gone")).await;
+        let response = run(&state, &mut ctx, &[&Lookup404]).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.extensions().get::<Outcome>(), Some(&Outcome::Hit));
+        assert_eq!(body_string(response).await, "gone");
+
+        let mut ctx = prepared(&state, request("HEAD", "/gone", &[])).await;
+        let response = run(&state, &mut ctx, &[&Lookup404]).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_string(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_prefers_main_cache_over_error_store() {
+        let state = state(config().with_cache_errors_ttl_secs(60));
+        let key = prepared(&state, get("/page")).await.cache_key;
+        let looked_up = || {
+            let state = state.clone();
+            async move {
+                let mut ctx = prepared(&state, get("/page")).await;
+                let response = run(&state, &mut ctx, &[&Lookup]).await.unwrap().unwrap();
+                (response.status(), body_string(response).await)
+            }
+        };
+
+        state.cache.set_error(key.clone(), page(503, "down")).await;
+        assert_eq!(
+            looked_up().await,
+            (StatusCode::SERVICE_UNAVAILABLE, "down".to_string())
+        );
+        state.cache.set(key, page(200, "page")).await;
+        assert_eq!(looked_up().await, (StatusCode::OK, "page".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_applies_accept_mismatch() {
+        let json = || request("GET", "/page", &[("accept", "application/json")]);
+        let seeded = |mode| {
+            let state = state(config().with_accept_mismatch(mode));
+            async move {
+                let key = prepared(&state, get("/page")).await.cache_key;
+                state.cache.set(key, page(200, "page")).await;
+                state
+            }
+        };
+
+        let reject = seeded(AcceptMismatch::Reject).await;
+        let mut ctx = prepared(&reject, json()).await;
+        assert!(matches!(
+            run(&reject, &mut ctx, &[&Lookup]).await,
+            Some(Err(StatusCode::NOT_ACCEPTABLE))
+        ));
+
+        let bypass = seeded(AcceptMismatch::Bypass).await;
+        let mut ctx = prepared(&bypass, json()).await;
+        assert!(run(&bypass, &mut ctx, &[&Lookup]).await.is_none());
+        assert!(ctx.accept_bypass);
+    }
+
+    #[tokio::test]
+    async fn test_miss_reads_body_and_picks_backend() {
+        let state = state(config().with_serve_head_from_get(true));
+        let mut ctx = prepared(
+            &state,
+            Request::post("/page").body(Body::from("form")).unwrap(),
+        )
+        .await;
+        assert!(run(&state, &mut ctx, &[&Miss]).await.is_none());
+        assert_eq!(ctx.request_body, "form");
+        assert!(ctx.backend.is_some());
+        assert_eq!(ctx.outcome, Outcome::Miss);
+
+        // A `HEAD` miss is forwarded but never stored.
+        let mut ctx = prepared(&state, request("HEAD", "/page", &[])).await;
+        assert!(run(&state, &mut ctx, &[&Miss]).await.is_none());
+        assert_eq!(ctx.outcome, Outcome::Bypass);
+        assert!(!ctx.should_cache);
+    }
+
+    #[tokio::test]
+    async fn test_miss_without_fallthrough_is_not_found() {
+        let state = state(config().with_proxy_mode(ProxyMode::PreGenerate {
+            paths: vec![],
+            fallthrough: false,
+        }));
+        let mut ctx = prepared(&state, get("/page")).await;
+        assert!(matches!(
+            run(&state, &mut ctx, &[&Miss]).await,
+            Some(Err(StatusCode::NOT_FOUND))
+        ));
+        assert!(ctx.backend.is_none());
+    }
+}
@@ -0,0 +1,1826 @@
+//! A request's way through the proxy, as one ordered list of stages.
+//!
+//! Each stage reads and fills in a [`RequestContext`] and either hands over
+//! to the next stage or ends the request early: with a cache hit, a refusal,
+//! or the backend's answer. [`PIPELINE`] is the only place the order is
+//! defined; a new feature adds a stage to it rather than a branch to
+//! `proxy_handler`. The stages up to [`Miss`] decide whether the cache may
+//! answer (see the `lookup` module); from [`Coalesce`] on they fetch
+//! upstream, decide whether the response may be stored, and store it.
+//! [`respond`] answers the client once every stage has run.
+//!
+//! The invariants the order relies on:
+//!
+//! - [`ProxyHeaders`] runs first, so the bypass and purge headers never
+//!   reach the backend, tunnels included, and a purge with the wrong token is
+//!   refused before anything else happens.
+//! - [`Upgrade`] runs before anything reads the body, so a tunnelled request
+//!   leaves the pipeline whole.
+//! - [`Webhooks`] run before any lookup, so access control holds for requests
+//!   the cache could answer, and before [`Key`], which uses the key a
+//!   `cache_key` webhook returned.
+//! - [`HeadAsGet`] runs before [`Filter`] and [`Key`], so a `HEAD` is matched
+//!   and keyed as the `GET` for the same URL.
+//! - [`Credentials`] runs after [`Filter`] and [`Decide`] after both, so
+//!   `should_cache_fn` has the last word over path rules and credentials, but
+//!   not over a client asking to bypass the cache.
+//! - [`BodyKey`] runs after [`Key`] and reads the body before any lookup, so
+//!   an entry cached by body is looked up under its full key.
+//! - [`Purge`] runs before [`Variant`], so it drops the entry with all its
+//!   variants, and before the lookups, so the request continues as a miss.
+//! - [`Refresh`] and [`Range`] run before the lookups they skip.
+//! - [`Lookup404`] runs before [`Lookup`]: the 404 store answers even
+//!   requests the path rules keep out of the main cache.
+//! - [`Miss`] runs after every lookup. It records the miss or bypass and
+//!   picks the backend, so a hit never takes a turn from the balancer.
+//! - [`Coalesce`] runs before [`Fetch`], so a follower waits for the leader's
+//!   fetch instead of starting its own. From here on the stages run on their
+//!   own task under `client_disconnect = complete_cacheable`.
+//! - [`Fetch`] runs before every stage that reads the upstream response.
+//! - [`ServeStale`] runs before [`Classify`], so a backend error answered
+//!   from a stale entry is never stored over that entry.
+//! - [`Inspect`] decodes the upstream body before anything looks inside it,
+//!   so [`Classify`] scans the decoded HTML for the 404 meta tag rather than
+//!   compressed bytes.
+//! - [`Classify`] picks the store target before [`AdmitWrite`] so a write
+//!   token is only spent on responses that would actually be stored.
+//!   [`AdmitWrite`] admits the cache write, not the request.
+//! - [`NormalizeHtml`] runs after [`AdmitWrite`], so only bodies that will be
+//!   stored are rewritten, and after [`Classify`], so the 404 meta scan sees
+//!   the page as the backend sent it.
+//! - [`Transform`] runs after [`NormalizeHtml`] and just before [`Store`], so
+//!   the `transform_response` hook sees the body that would be stored and
+//!   only runs for responses that will be.
+//! - [`Store`] builds the cached entry from the decoded body with its framing
+//!   headers (`content-encoding`, `content-length`, `transfer-encoding`)
+//!   rewritten, so nothing describing the upstream connection is stored.
+//! - [`respond`] always runs last and answers from the stored entry when one
+//!   was written, so hits and misses share the same encoding negotiation.
+
+use super::{
+    apply_cache_error_policy, authorize_upstream, build_cached_response,
+    build_cached_response_from_pairs, build_response_from_upstream, cached_response_is_allowed,
+    coalesce::{Coalesce, LeaderGuard},
+    convert_headers, convert_headers_to_pairs, hit_matches_origin,
+    lookup::{
+        AllowMethod, BodyKey, Credentials, Decide, Filter, HeadAsGet, Key, Lookup, Lookup404, Miss,
+        ProxyHeaders, Purge, Range, Refresh, Upgrade, Variant, Webhooks,
+    },
+    remove_header, serve_cached, transform_outbound,
+    write_queue::PendingWrite,
+    DisconnectGuard, ProxyState,
+};
+use crate::backend::BackendLease;
+use crate::backend_headers::BackendDirectives;
+use crate::cache::{content_digest, CacheBucket, CacheOrigin, CachedResponse};
+use crate::cache_control::CacheControl;
+use crate::compression::{decodable_accept_encoding, decode_upstream_body_async};
+use crate::credentials::Credential;
+use crate::decision_memo::NotCacheable;
+use crate::normalize::normalize_percent_encoding;
+use crate::not_found_meta::{contains_404_meta, is_html};
+use crate::outbound::OutboundRequest;
+use crate::range::ByteRange;
+use crate::stats::{Outcome, PolicyActivation};
+use crate::transform;
+use crate::vary::{self, Vary};
+use crate::{BackendErrorPolicy, ClientDisconnect, CreateProxyConfig};
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        Extensions, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+    },
+};
+use http_body_util::BodyExt;
+use hyper::body::{Body as HttpBody, Frame};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sync_wrapper::SyncWrapper;
+
+#[cfg(test)]
+thread_local! {
+    /// Upstream bodies decoded for cache or 404-meta inspection on this thread.
+    pub(super) static BODY_INSPECTIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// What a stage decides: continue with the next stage, or end the request
+/// with this result.
+pub(super) type StageFlow = ControlFlow<Result<Response<Body>, StatusCode>>;
+
+pub(super) type StageFuture<'a> = Pin<Box<dyn Future<Output = StageFlow> + Send + 'a>>;
+
+/// One step of the pipeline.
+pub(super) trait Stage: Sync {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a>;
+}
+
+/// Every stage, in order. See the module docs for why it is ordered this way.
+const PIPELINE: [&dyn Stage; 26] = [
+    &ProxyHeaders,
+    &Upgrade,
+    &AllowMethod,
+    &Webhooks,
+    &HeadAsGet,
+    &Filter,
+    &Credentials,
+    &Decide,
+    &Key,
+    &BodyKey,
+    &Purge,
+    &Variant,
+    &Refresh,
+    &Range,
+    &Lookup404,
+    &Lookup,
+    &Miss,
+    &Coalesce,
+    &Fetch,
+    &ServeStale,
+    &Inspect,
+    &Classify,
+    &AdmitWrite,
+    &NormalizeHtml,
+    &Transform,
+    &Store,
+];
+
+/// Index of [`Coalesce`] in [`PIPELINE`], where the miss path starts: the
+/// cache could not answer and the request goes to the backend.
+const MISS_PATH_START: usize = 17;
+
+/// Run the pipeline for one request.
+pub(super) async fn run(
+    state: &Arc<ProxyState>,
+    mut ctx: RequestContext,
+) -> Result<Response<Body>, StatusCode> {
+    if let ControlFlow::Break(result) =
+        run_stages(state, &mut ctx, &PIPELINE[..MISS_PATH_START]).await
+    {
+        return result;
+    }
+
+    let complete = state.config.client_disconnect == ClientDisconnect::CompleteCacheable
+        && ctx.should_cache
+        && ctx.cache_reads_enabled;
+    let outcome = ctx.outcome;
+    let method = ctx.method.clone();
+    let path = ctx.path.clone();
+    let mut guard = DisconnectGuard {
+        state,
+        method: method.as_str(),
+        path: &path,
+        completing: complete,
+        armed: true,
+    };
+    let mut result = if complete {
+        // A spawned task is not cancelled when the client goes away and
+        // hyper drops this future, so the response still reaches the cache.
+        let task_state = state.clone();
+        match tokio::spawn(async move { run_miss_path(&task_state, ctx).await }).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    } else {
+        run_miss_path(state, ctx).await
+    };
+    guard.disarm();
+    if let Ok(response) = &mut result {
+        state.mark_cache_status(response, outcome, None);
+    }
+    result
+}
+
+/// Run the stages from [`MISS_PATH_START`] on, then answer the client.
+async fn run_miss_path(
+    state: &Arc<ProxyState>,
+    mut ctx: RequestContext,
+) -> Result<Response<Body>, StatusCode> {
+    if let ControlFlow::Break(result) =
+        run_stages(state, &mut ctx, &PIPELINE[MISS_PATH_START..]).await
+    {
+        return result;
+    }
+    respond(state, ctx).await
+}
+
+async fn run_stages(
+    state: &Arc<ProxyState>,
+    ctx: &mut RequestContext,
+    stages: &[&dyn Stage],
+) -> StageFlow {
+    for stage in stages {
+        if let ControlFlow::Break(result) = stage.run(state, ctx).await {
+            tracing::debug!(
+                method = ctx.method.as_str(),
+                path = ctx.path,
+                stage = stage.name(),
+                "proxy request left the pipeline early"
+            );
+            return ControlFlow::Break(result);
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Where an upstream response will be stored, if anywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum StoreTarget {
+    Main,
+    NotFound,
+    /// The short-TTL error store; 5xx responses never reach the main cache
+    /// while error caching is enabled.
+    Error,
+}
+
+/// The upstream response as read by [`Fetch`].
+pub(super) struct Upstream {
+    pub(super) status: u16,
+    pub(super) headers: reqwest::header::HeaderMap,
+    /// The body exactly as received, still in the upstream encoding.
+    pub(super) body: Vec<u8>,
+    /// Time from sending the request until the response headers arrived.
+    pub(super) ttfb: Duration,
+    /// Time from the headers until the body was fully read.
+    pub(super) body_time: Duration,
+    /// The `X-Phantom-*` headers, already removed from `headers`.
+    pub(super) directives: BackendDirectives,
+}
+
+/// Everything the pipeline knows about one request.
+///
+/// The request fields are filled in from the request; the rest starts empty
+/// and is filled in by the stages in order.
+pub(super) struct RequestContext {
+    pub(super) request_started: Instant,
+    pub(super) method: Method,
+    /// The request's URI as received, for requests handed on whole.
+    pub(super) uri: Uri,
+    pub(super) version: Version,
+    pub(super) extensions: Extensions,
+    /// Normalized, so every spelling of a URL matches the same patterns,
+    /// shares one cache key, and reaches the backend as valid ASCII.
+    pub(super) path: String,
+    pub(super) path_and_query: String,
+    /// Without the bypass and purge headers once [`ProxyHeaders`] ran.
+    pub(super) request_headers: HeaderMap,
+    /// The body until [`BodyKey`] or [`Miss`] reads it into `request_body`;
+    /// wrapped so the context can be shared while a stage awaits.
+    unread_body: Option<SyncWrapper<Body>>,
+    pub(super) request_body: Bytes,
+
+    /// Set by [`ProxyHeaders`]: the client asked to bypass the cache.
+    pub(super) bypass_requested: bool,
+    /// Set by [`ProxyHeaders`]: the client asked, with the right token, to
+    /// drop the entry.
+    pub(super) purge_requested: bool,
+    /// Set by [`Webhooks`]: the key a `cache_key` webhook returned.
+    pub(super) cache_key_override: Option<String>,
+    /// Set by [`HeadAsGet`]: a `HEAD` answered from the `GET`'s entry.
+    pub(super) head_from_get: bool,
+    /// The method rules, key functions and entries are looked up with; the
+    /// request's own unless [`HeadAsGet`] made it `GET`.
+    pub(super) lookup_method: Method,
+    /// Set by [`Filter`]: caching is paused.
+    pub(super) paused: bool,
+    /// Set by [`Credentials`].
+    pub(super) credential: Option<Credential>,
+    /// Set by [`Credentials`] and narrowed by [`Decide`]: the credential that,
+    /// rather than the path, keeps the request away from the cache.
+    pub(super) credential_bypass: Option<Credential>,
+    /// Set by [`Key`]: the key function declined or panicked, so the request
+    /// bypasses the cache entirely.
+    pub(super) no_cache_key: bool,
+    pub(super) cache_key: String,
+    /// The request may be stored: set by [`Filter`], narrowed by the stages
+    /// up to [`BodyKey`], and cleared by [`Miss`] for responses that stream
+    /// through.
+    pub(super) should_cache: bool,
+    /// The cache may be read: caching is not paused, the cache strategy is
+    /// not `none` and there is a key. Cleared by [`Miss`] like `should_cache`.
+    pub(super) cache_reads_enabled: bool,
+    /// Set by [`Refresh`]: the client asked for a fresh copy.
+    pub(super) refresh: bool,
+    /// Set by [`Range`]: the `Range` header, `Some(None)` when it asks for
+    /// several ranges or cannot be parsed.
+    pub(super) range: Option<Option<ByteRange>>,
+    /// Set by the lookups when a cached entry exists but the client's
+    /// `Accept` rules it out and `accept_mismatch = bypass`: the backend
+    /// answers and nothing is stored.
+    pub(super) accept_bypass: bool,
+    /// Set by [`Miss`]: what the cache status header reports for the miss path.
+    pub(super) outcome: Outcome,
+    /// Lifetime of the main-cache entry, set by [`Key`] from a cache rule,
+    /// by [`BodyKey`] for requests cached by body, and by [`Classify`] from
+    /// the backend's `X-Phantom-TTL` or `Cache-Control`.
+    pub(super) entry_ttl: Option<Duration>,
+    /// Set by [`Miss`] and pinned for the rest of the request, so a
+    /// concurrent swap lets it finish on the client it started with.
+    pub(super) backend: Option<BackendLease>,
+
+    /// Set by [`Fetch`].
+    pub(super) upstream: Option<Upstream>,
+    /// Set by [`Inspect`]: the content type is allowed by the cache strategy.
+    pub(super) response_is_cacheable: bool,
+    /// Set by [`Inspect`] when the body was decoded for storing or scanning.
+    pub(super) normalized_body: Option<Vec<u8>>,
+    /// Set by [`Classify`], cleared by [`AdmitWrite`] when throttled.
+    pub(super) store: Option<StoreTarget>,
+    /// Set by [`NormalizeHtml`]: digest of the normalized body.
+    pub(super) content_digest: Option<String>,
+    /// Set by [`Transform`]: the status and headers `transform_response`
+    /// returned, stored instead of the upstream ones.
+    pub(super) transformed_head: Option<(u16, Vec<(String, String)>)>,
+    /// Set by [`Store`] once the entry has been written, or queued to be.
+    pub(super) cached: Option<CachedResponse>,
+    /// Held while this request leads a coalesced miss; a queued write takes
+    /// it along so followers wait for the entry.
+    pub(super) in_flight: Option<LeaderGuard>,
+}
+
+impl RequestContext {
+    pub(super) fn new(req: Request<Body>) -> Self {
+        let (parts, body) = req.into_parts();
+        let path = normalize_percent_encoding(parts.uri.path()).into_owned();
+        let path_and_query = match parts.uri.query() {
+            Some(query) => format!("{}?{}", path, normalize_percent_encoding(query)),
+            None => path.clone(),
+        };
+        Self {
+            request_started: Instant::now(),
+            lookup_method: parts.method.clone(),
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            extensions: parts.extensions,
+            path,
+            path_and_query,
+            request_headers: parts.headers,
+            unread_body: Some(SyncWrapper::new(body)),
+            request_body: Bytes::new(),
+            bypass_requested: false,
+            purge_requested: false,
+            cache_key_override: None,
+            head_from_get: false,
+            paused: false,
+            credential: None,
+            credential_bypass: None,
+            no_cache_key: false,
+            cache_key: String::new(),
+            should_cache: false,
+            cache_reads_enabled: false,
+            refresh: false,
+            range: None,
+            accept_bypass: false,
+            outcome: Outcome::Bypass,
+            entry_ttl: None,
+            backend: None,
+            upstream: None,
+            response_is_cacheable: false,
+            normalized_body: None,
+            store: None,
+            content_digest: None,
+            transformed_head: None,
+            cached: None,
+            in_flight: None,
+        }
+    }
+
+    /// The normalized query string; empty without one.
+    pub(super) fn query(&self) -> &str {
+        self.path_and_query
+            .split_once('?')
+            .map_or("", |(_, query)| query)
+    }
+
+    /// The request as the key functions and hooks see it.
+    pub(super) fn request_info(&self) -> crate::RequestInfo<'_> {
+        crate::RequestInfo {
+            method: self.lookup_method.as_str(),
+            path: &self.path,
+            query: self.query(),
+            headers: &self.request_headers,
+        }
+    }
+
+    /// The request put back together, for handing it on whole.
+    pub(super) fn take_request(&mut self) -> Request<Body> {
+        let mut req = Request::new(self.take_body().unwrap_or_default());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = std::mem::take(&mut self.request_headers);
+        *req.extensions_mut() = std::mem::take(&mut self.extensions);
+        req
+    }
+
+    /// Take back a request from [`take_request`](Self::take_request) that
+    /// continues through the pipeline.
+    pub(super) fn restore_request(&mut self, req: Request<Body>) {
+        let (parts, body) = req.into_parts();
+        self.request_headers = parts.headers;
+        self.extensions = parts.extensions;
+        self.unread_body = Some(SyncWrapper::new(body));
+    }
+
+    /// The body, unless a stage already read it.
+    pub(super) fn take_body(&mut self) -> Option<Body> {
+        self.unread_body.take().map(SyncWrapper::into_inner)
+    }
+
+    fn backend(&self) -> &BackendLease {
+        self.backend
+            .as_ref()
+            .expect("Miss picks the backend before the miss path runs")
+    }
+
+    fn upstream(&self) -> &Upstream {
+        self.upstream
+            .as_ref()
+            .expect("Fetch runs before any stage reading the upstream response")
+    }
+
+    fn passthrough(&mut self) -> Response<Body> {
+        let upstream = self
+            .upstream
+            .take()
+            .expect("Fetch runs before any stage reading the upstream response");
+        build_response_from_upstream(upstream.status, &upstream.headers, upstream.body)
+    }
+}
+
+/// Forward the request to the pinned backend and read the whole response.
+///
+/// Responses with trailers end the request here: they are forwarded with
+/// their trailers and never reach the later stages. So do responses no cache
+/// could take (see [`can_stream`]), whose bodies are forwarded as they
+/// arrive instead of being read into memory first.
+pub(super) struct Fetch;
+
+impl Stage for Fetch {
+    fn name(&self) -> &'static str {
+        "fetch"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            #[cfg(feature = "cassette")]
+            if let Some(cassette) = state
+                .config
+                .cassette
+                .as_ref()
+                .filter(|cassette| cassette.mode() == crate::cassette::CassetteMode::Replay)
+            {
+                return replay_from_cassette(cassette, state, ctx);
+            }
+
+            if !ctx.backend().is_up() {
+                // Only picked when every backend failed its health check.
+                tracing::debug!(
+                    "No healthy backend for {}",
+                    state.redactor().target(&ctx.path_and_query)
+                );
+                return match serve_stale(state, ctx).await {
+                    Some(response) => ControlFlow::Break(Ok(response)),
+                    None => ControlFlow::Break(Err(StatusCode::SERVICE_UNAVAILABLE)),
+                };
+            }
+            let upstream_started = Instant::now();
+            tracing::trace!(
+                method = ctx.method.as_str(),
+                target = %state.redactor().target(&ctx.path_and_query),
+                headers = ?state.redactor().headers(&ctx.request_headers),
+                "forwarding request to backend"
+            );
+
+            let mut headers = convert_headers(&ctx.request_headers, state.config.preserve_host);
+            if ctx.should_cache && ctx.cache_reads_enabled {
+                narrow_accept_encoding(&mut headers);
+            }
+            if let Err(response) = authorize_upstream(state, &mut headers).await {
+                return ControlFlow::Break(Ok(response));
+            }
+            let mut outbound = OutboundRequest::new(
+                ctx.method.clone(),
+                ctx.path_and_query.as_str(),
+                headers,
+                ctx.backend().url(),
+            );
+            if let Err(status) = transform_outbound(state, &mut outbound) {
+                return ControlFlow::Break(Err(status));
+            }
+            let target_url = outbound.url();
+            let origin_replaced = outbound.origin_replaced();
+            let (method, _, headers) = outbound.into_parts();
+            let request_body = std::mem::take(&mut ctx.request_body);
+            let response = match ctx
+                .backend()
+                .client()
+                .request(method, &target_url)
+                .headers(headers)
+                .body(request_body.clone())
+                .send()
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    // A replaced origin is not the picked backend's fault.
+                    if !origin_replaced {
+                        state.backend().report_failure(ctx.backend());
+                    }
+                    state.emit(|events| events.on_backend_error(&ctx.path, &e));
+                    let status = fetch_error_status(&e);
+                    tracing::error!(
+                        "Failed to fetch {} from backend: {}",
+                        state.redactor().target(&ctx.path_and_query),
+                        e.without_url()
+                    );
+                    return match serve_stale(state, ctx).await {
+                        Some(response) => ControlFlow::Break(Ok(response)),
+                        None => ControlFlow::Break(Err(status)),
+                    };
+                }
+            };
+            let ttfb = upstream_started.elapsed();
+            state.stats.record_backend_ttfb(ttfb);
+            tracing::debug!(
+                method = ctx.method.as_str(),
+                path = ctx.path,
+                ttfb_ms = ttfb.as_millis(),
+                "proxy request received upstream response headers"
+            );
+
+            let status = response.status().as_u16();
+            #[cfg(feature = "cassette")]
+            let recorded_headers = response.headers().clone();
+            // Never stored nor sent on, whatever happens to the response.
+            let mut headers = response.headers().clone();
+            let directives = BackendDirectives::take(&mut headers);
+
+            // A cached entry has nowhere to keep trailer fields, so responses
+            // that announce them are streamed through untouched and never stored.
+            if headers.contains_key(reqwest::header::TRAILER) {
+                tracing::debug!(
+                    method = ctx.method.as_str(),
+                    path = ctx.path,
+                    "upstream response announces trailers, streaming it uncached"
+                );
+                let response = Response::<reqwest::Body>::from(response);
+                return ControlFlow::Break(Ok(stream_with_trailers(
+                    status,
+                    &headers,
+                    Body::new(response.into_body()),
+                )));
+            }
+
+            // An event stream never ends: reading it whole would hang, and
+            // a stored snapshot of it would be wrong for everyone after.
+            if is_endless_stream(&state.config, &headers) {
+                tracing::debug!(
+                    method = ctx.method.as_str(),
+                    path = ctx.path,
+                    "upstream response is a stream, passing it through uncached"
+                );
+                let response = Response::<reqwest::Body>::from(response);
+                return ControlFlow::Break(Ok(stream_upstream(
+                    status,
+                    &headers,
+                    Body::new(response.into_body()),
+                )));
+            }
+
+            if can_stream(state, ctx, status, &headers) {
+                tracing::debug!(
+                    method = ctx.method.as_str(),
+                    path = ctx.path,
+                    "upstream response cannot be cached, streaming it"
+                );
+                let response = Response::<reqwest::Body>::from(response);
+                return ControlFlow::Break(Ok(stream_upstream(
+                    status,
+                    &headers,
+                    Body::new(response.into_body()),
+                )));
+            }
+
+            // A body past `max_cacheable_response_bytes` is not buffered: one
+            // announced as too long is streamed from the start, one that
+            // grows too long from where reading stopped.
+            let limit = state.config.max_cacheable_response_bytes;
+            let declared = headers
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if limit > 0 && declared.is_some_and(|len| len > limit as u64) {
+                tracing::debug!(
+                    method = ctx.method.as_str(),
+                    path = ctx.path,
+                    "upstream response exceeds max_cacheable_response_bytes, streaming it uncached"
+                );
+                let response = Response::<reqwest::Body>::from(response);
+                return ControlFlow::Break(Ok(stream_upstream(
+                    status,
+                    &headers,
+                    Body::new(response.into_body()),
+                )));
+            }
+
+            let body_started = Instant::now();
+            let response = Response::<reqwest::Body>::from(response);
+            let collected = match read_upstream_body(response.into_body(), limit).await {
+                Ok(ReadBody::Complete(collected)) => collected,
+                Ok(ReadBody::Oversized(body)) => {
+                    tracing::debug!(
+                        method = ctx.method.as_str(),
+                        path = ctx.path,
+                        "upstream response grew past max_cacheable_response_bytes, streaming it uncached"
+                    );
+                    return ControlFlow::Break(Ok(stream_upstream(
+                        status,
+                        &headers,
+                        Body::new(body),
+                    )));
+                }
+                Err(e) => {
+                    // As above, a replaced origin is not the picked backend's fault.
+                    if !origin_replaced {
+                        state.backend().report_failure(ctx.backend());
+                    }
+                    let status = fetch_error_status(&e);
+                    tracing::error!("Failed to read response body: {}", e.without_url());
+                    return match serve_stale(state, ctx).await {
+                        Some(response) => ControlFlow::Break(Ok(response)),
+                        None => ControlFlow::Break(Err(status)),
+                    };
+                }
+            };
+            let body_time = body_started.elapsed();
+            state.stats.record_backend_body(body_time);
+            tracing::debug!(
+                method = ctx.method.as_str(),
+                path = ctx.path,
+                ttfb_ms = ttfb.as_millis(),
+                body_ms = body_time.as_millis(),
+                "proxy request read upstream response body"
+            );
+            if collected.has_trailers() {
+                // Trailers sent without a `Trailer` header: still forward them
+                // rather than dropping them, and still skip the cache.
+                tracing::debug!(
+                    method = ctx.method.as_str(),
+                    path = ctx.path,
+                    "upstream response carried unannounced trailers, passing it through uncached"
+                );
+                return ControlFlow::Break(Ok(stream_with_trailers(
+                    status,
+                    &headers,
+                    Body::new(collected),
+                )));
+            }
+            let body = collected.into_data();
+
+            #[cfg(feature = "cassette")]
+            if let Some(cassette) = &state.config.cassette {
+                record_to_cassette(
+                    cassette,
+                    state,
+                    ctx,
+                    &request_body,
+                    status,
+                    &recorded_headers,
+                    &body,
+                )
+                .await;
+            }
+
+            ctx.upstream = Some(Upstream {
+                status,
+                headers,
+                body,
+                ttfb,
+                body_time,
+                directives,
+            });
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Only ask the backend for encodings the cache can store. Whatever it picks
+/// from them is still one the client accepts, for when the response is
+/// passed through instead.
+fn narrow_accept_encoding(headers: &mut HeaderMap) {
+    let Some(value) = headers.get(axum::http::header::ACCEPT_ENCODING) else {
+        return;
+    };
+    let narrowed = value.to_str().ok().and_then(decodable_accept_encoding);
+    match narrowed.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        Some(value) => {
+            headers.insert(axum::http::header::ACCEPT_ENCODING, value);
+        }
+        None => {
+            headers.remove(axum::http::header::ACCEPT_ENCODING);
+        }
+    }
+}
+
+/// Answer a backend error status from a stale entry, when
+/// `backend_error_policy` and `stale_on_error_status` allow it and one exists.
+pub(super) struct ServeStale;
+
+impl Stage for ServeStale {
+    fn name(&self) -> &'static str {
+        "serve_stale"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if !state.config.stale_on_error_status
+                || !matches!(ctx.upstream().status, 500 | 502 | 503 | 504)
+            {
+                return ControlFlow::Continue(());
+            }
+            match serve_stale(state, ctx).await {
+                Some(response) => ControlFlow::Break(Ok(response)),
+                None => ControlFlow::Continue(()),
+            }
+        })
+    }
+}
+
+/// Apply `backend_error_policy` after the backend failed a request: the
+/// stale entry to answer with, or `None` to pass the failure on. Requests
+/// that could not be cached are not counted.
+async fn serve_stale(state: &ProxyState, ctx: &RequestContext) -> Option<Response<Body>> {
+    if !ctx.should_cache || !ctx.cache_reads_enabled {
+        return None;
+    }
+    let stale = match state.config.backend_error_policy {
+        BackendErrorPolicy::Fail => None,
+        BackendErrorPolicy::StaleIfAvailable => {
+            match state.cache.try_get_stale(&ctx.cache_key).await {
+                Ok(stale) => stale,
+                Err(error) => {
+                    tracing::warn!("No stale entry to fall back on: {}", error);
+                    None
+                }
+            }
+        }
+    };
+    let method = ctx.method.as_str();
+    let max_age = Duration::from_secs(state.config.stale_max_age_secs);
+    let stale = match stale {
+        Some(stale)
+            if stale.age().is_none_or(|age| age <= max_age)
+                && hit_matches_origin(state, &stale, method, &ctx.path, &ctx.cache_key).await
+                && cached_response_is_allowed(&state.config.cache_strategy, &stale) =>
+        {
+            stale
+        }
+        _ => {
+            state
+                .stats
+                .record_policy(PolicyActivation::BackendErrorFail);
+            return None;
+        }
+    };
+
+    tracing::warn!(
+        "Backend failed for {} {}, serving stale cached entry",
+        method,
+        state.redactor().target(&ctx.cache_key)
+    );
+    state
+        .stats
+        .record_policy(PolicyActivation::BackendErrorStale);
+    let age = stale.age();
+    let mut response = serve_cached(state, &ctx.cache_key, stale, &ctx.request_headers)
+        .await
+        .ok()?;
+    state.mark_stale(&mut response, age);
+    Some(response)
+}
+
+/// 504 for a backend that ran out of `request_timeout`, 502 for any other
+/// failure.
+fn fetch_error_status(error: &reqwest::Error) -> StatusCode {
+    if error.is_timeout() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+/// Answer the fetch from a replay cassette instead of the backend.
+#[cfg(feature = "cassette")]
+fn replay_from_cassette(
+    cassette: &crate::cassette::Cassette,
+    state: &ProxyState,
+    ctx: &mut RequestContext,
+) -> StageFlow {
+    let target = state.redactor().target(&ctx.path_and_query);
+    let Some(recorded) = cassette.replay_response(ctx.method.as_str(), &target) else {
+        tracing::error!(
+            "No interaction for {} {} in cassette '{}'",
+            ctx.method,
+            target,
+            cassette.path().display()
+        );
+        return ControlFlow::Break(Err(StatusCode::BAD_GATEWAY));
+    };
+    let body = match recorded.body.to_bytes() {
+        Ok(body) => body,
+        Err(error) => {
+            tracing::error!(
+                "Cassette '{}' has an unreadable body for {} {}: {:#}",
+                cassette.path().display(),
+                ctx.method,
+                target,
+                error
+            );
+            return ControlFlow::Break(Err(StatusCode::BAD_GATEWAY));
+        }
+    };
+    tracing::debug!(
+        method = ctx.method.as_str(),
+        path = ctx.path,
+        "proxy request answered from cassette"
+    );
+    let mut headers = crate::cassette::to_header_map(&recorded.headers);
+    let directives = BackendDirectives::take(&mut headers);
+    ctx.upstream = Some(Upstream {
+        status: recorded.status,
+        headers,
+        body,
+        ttfb: Duration::ZERO,
+        body_time: Duration::ZERO,
+        directives,
+    });
+    ControlFlow::Continue(())
+}
+
+/// Append the exchange to a recording cassette, redacted like log output.
+#[cfg(feature = "cassette")]
+async fn record_to_cassette(
+    cassette: &crate::cassette::Cassette,
+    state: &ProxyState,
+    ctx: &RequestContext,
+    request_body: &[u8],
+    status: u16,
+    headers: &reqwest::header::HeaderMap,
+    body: &[u8],
+) {
+    use crate::cassette::{
+        header_pairs, redact_headers, Interaction, RecordedBody, RecordedRequest, RecordedResponse,
+    };
+
+    let redactor = state.redactor();
+    let interaction = Interaction {
+        request: RecordedRequest {
+            method: ctx.method.to_string(),
+            target: redactor.target(&ctx.path_and_query).into_owned(),
+            headers: redact_headers(redactor, header_pairs(&ctx.request_headers)),
+            body: RecordedBody::new(request_body),
+        },
+        response: RecordedResponse {
+            status,
+            headers: redact_headers(redactor, header_pairs(headers)),
+            body: RecordedBody::new(body),
+        },
+    };
+    if let Err(error) = cassette.record_interaction(&interaction).await {
+        tracing::warn!(
+            "Failed to record {} {} to cassette '{}': {:#}",
+            ctx.method,
+            interaction.request.target,
+            cassette.path().display(),
+            error
+        );
+    }
+}
+
+/// An upstream body read by [`read_upstream_body`].
+enum ReadBody<B> {
+    /// Read to the end.
+    Complete(ResumedBody<B>),
+    /// Longer than the limit: the frames read so far, then the rest.
+    Oversized(ResumedBody<B>),
+}
+
+/// A body that replays frames already read from it, then continues with
+/// what is left.
+struct ResumedBody<B> {
+    read: VecDeque<Frame<Bytes>>,
+    rest: Option<B>,
+}
+
+impl<B> ResumedBody<B> {
+    fn has_trailers(&self) -> bool {
+        self.read.iter().any(Frame::is_trailers)
+    }
+
+    /// The data read, joined.
+    fn into_data(self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for frame in &self.read {
+            if let Some(chunk) = frame.data_ref() {
+                data.extend_from_slice(chunk);
+            }
+        }
+        data
+    }
+}
+
+impl<B> HttpBody for ResumedBody<B>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        if let Some(frame) = self.read.pop_front() {
+            return std::task::Poll::Ready(Some(Ok(frame)));
+        }
+        match self.rest.as_mut() {
+            Some(rest) => Pin::new(rest).poll_frame(cx),
+            None => std::task::Poll::Ready(None),
+        }
+    }
+}
+
+/// Read `body` to the end, or until its data passes `limit` bytes (`0`: no
+/// limit).
+async fn read_upstream_body<B>(mut body: B, limit: usize) -> Result<ReadBody<B>, B::Error>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+{
+    let mut read = VecDeque::new();
+    let mut len = 0;
+    while let Some(frame) = body.frame().await {
+        let frame = frame?;
+        len += frame.data_ref().map_or(0, Bytes::len);
+        read.push_back(frame);
+        if limit > 0 && len > limit {
+            return Ok(ReadBody::Oversized(ResumedBody {
+                read,
+                rest: Some(body),
+            }));
+        }
+    }
+    Ok(ReadBody::Complete(ResumedBody { read, rest: None }))
+}
+
+/// Whether the response is a stream that must be forwarded as it arrives:
+/// one of `streaming_content_types`, or a chunked body of unknown length
+/// whose content type `cache_strategy` would not store anyway.
+fn is_endless_stream(config: &CreateProxyConfig, headers: &reqwest::header::HeaderMap) -> bool {
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if transform::content_type_matches(&config.streaming_content_types, content_type) {
+        return true;
+    }
+    let chunked = headers
+        .get_all(reqwest::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("chunked"));
+    // The page may carry the phantom-404 tag and have to be answered as a 404.
+    let scanned = config.use_404_meta && is_html(content_type);
+    chunked
+        && !scanned
+        && !headers.contains_key(reqwest::header::CONTENT_LENGTH)
+        && !config.cache_strategy.allows_content_type(content_type)
+}
+
+/// Whether nothing could store this response, so its body need not be
+/// buffered. Paths that are not cacheable only reach the 404 cache, and only
+/// when the status, or the 404 meta tag in a body of a cacheable type, could
+/// make them a 404.
+fn can_stream(
+    state: &ProxyState,
+    ctx: &RequestContext,
+    status: u16,
+    headers: &reqwest::header::HeaderMap,
+) -> bool {
+    let config = &state.config;
+    #[cfg(feature = "cassette")]
+    if config.cassette.is_some() {
+        // Recording needs the whole body.
+        return false;
+    }
+    if !ctx.cache_reads_enabled {
+        return true;
+    }
+    if ctx.should_cache {
+        return false;
+    }
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    // The page may carry the phantom-404 tag and have to be answered as a 404.
+    if config.use_404_meta && is_html(content_type) {
+        return false;
+    }
+    !(status == 404
+        && config.cache_404_capacity > 0
+        && config.cache_strategy.allows_content_type(content_type))
+}
+
+/// Build a pass-through response that forwards `body` as it arrives.
+fn stream_upstream(
+    status: u16,
+    upstream_headers: &reqwest::header::HeaderMap,
+    body: Body,
+) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+    for (name, value) in upstream_headers {
+        if name != reqwest::header::TRANSFER_ENCODING {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+/// Build a pass-through response whose body carries its own trailer frames.
+/// The upstream framing is dropped so the client connection chunks the body
+/// and can send the trailers after it.
+fn stream_with_trailers(
+    status: u16,
+    upstream_headers: &reqwest::header::HeaderMap,
+    body: Body,
+) -> Response<Body> {
+    let mut response = stream_upstream(status, upstream_headers, body);
+    response
+        .headers_mut()
+        .remove(reqwest::header::CONTENT_LENGTH);
+    response
+}
+
+/// Check the content type and decode the body when it will be stored or
+/// scanned, consulting the decision memo so repeat "not cacheable" responses
+/// skip decoding entirely.
+pub(super) struct Inspect;
+
+impl Stage for Inspect {
+    fn name(&self) -> &'static str {
+        "inspect"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let config = &state.config;
+            let upstream = ctx.upstream();
+            let content_type = upstream
+                .headers
+                .get(axum::http::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            let response_is_cacheable = config.cache_strategy.allows_content_type(content_type);
+            let content_encoding = upstream
+                .headers
+                .get(axum::http::header::CONTENT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string());
+
+            let cache_considered =
+                ctx.cache_reads_enabled && (ctx.should_cache || config.cache_404_capacity > 0);
+            let decision_memo = state.cache.decision_memo();
+            let remembered = if cache_considered {
+                decision_memo.lookup(&ctx.cache_key)
+            } else {
+                None
+            };
+            if cache_considered && remembered.is_none() && !response_is_cacheable {
+                decision_memo.remember(&ctx.cache_key, NotCacheable::ContentType);
+            }
+            let should_try_cache =
+                cache_considered && response_is_cacheable && remembered.is_none();
+
+            // The meta scan decides the status, so it is never skipped.
+            let needs_decode = should_try_cache || (config.use_404_meta && is_html(content_type));
+            let body = needs_decode.then(|| upstream.body.clone());
+
+            ctx.response_is_cacheable = response_is_cacheable;
+            let Some(body) = body else {
+                return ControlFlow::Continue(());
+            };
+
+            #[cfg(test)]
+            BODY_INSPECTIONS.with(|count| count.set(count.get() + 1));
+            match decode_upstream_body_async(body, content_encoding).await {
+                Ok(body) => ctx.normalized_body = Some(body),
+                Err(error) => {
+                    tracing::warn!(
+                        "Skipping cache compression for {} {} due to unsupported upstream encoding: {}",
+                        ctx.method,
+                        ctx.path,
+                        error
+                    );
+                    if should_try_cache {
+                        decision_memo.remember(&ctx.cache_key, NotCacheable::UndecodableBody);
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Decide whether the response is a 404 (by status or meta tag) or a cacheable
+/// error, and which cache, if any, it belongs in, following the backend's
+/// `X-Phantom-*` headers and, when `respect_cache_control` is set, its
+/// `Cache-Control`. Responses that set a
+/// cookie are not stored unless `skip_set_cookie_responses` is off, and with
+/// `respect_vary` the response's `Vary` picks the key it is stored under.
+pub(super) struct Classify;
+
+/// Move the store target to the variant of the key the response's `Vary`
+/// selects for this request, or drop it for `Vary: *`. Later lookups for the
+/// key pick their variant the same way.
+fn apply_vary(state: &ProxyState, ctx: &mut RequestContext) {
+    let vary = Vary::parse(
+        ctx.upstream()
+            .headers
+            .get_all(reqwest::header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok()),
+    );
+    let key = vary::base_key(&ctx.cache_key).to_string();
+    match vary {
+        Vary::Any => {
+            tracing::debug!(
+                "Not caching {} {}: the response varies on more than request headers",
+                ctx.method,
+                state.redactor().target(&key)
+            );
+            ctx.store = None;
+        }
+        Vary::Headers(names) => {
+            ctx.cache_key = vary::variant_key(&key, &names, &ctx.request_headers);
+            state.cache.set_vary(&key, names);
+        }
+    }
+}
+
+/// Drop the store target of a response the backend marked `no-store` or
+/// `private`, and bound the entry's lifetime by its freshness, less the time
+/// it already spent in caches upstream.
+fn apply_cache_control(state: &ProxyState, ctx: &mut RequestContext) {
+    let headers = &ctx.upstream().headers;
+    let directives = CacheControl::parse(
+        headers
+            .get_all(reqwest::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok()),
+    );
+    if !directives.allows_store() {
+        tracing::debug!(
+            "Not caching {} {}: the backend sent Cache-Control {:?}",
+            ctx.method,
+            state.redactor().target(&ctx.cache_key),
+            directives
+        );
+        ctx.store = None;
+        return;
+    }
+    let Some(freshness) = directives.freshness() else {
+        return;
+    };
+    let upstream_age = headers
+        .get(reqwest::header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs);
+    let ttl = freshness.saturating_sub(upstream_age);
+    ctx.entry_ttl = Some(ctx.entry_ttl.map_or(ttl, |rule_ttl| rule_ttl.min(ttl)));
+}
+
+impl Stage for Classify {
+    fn name(&self) -> &'static str {
+        "classify"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let config = &state.config;
+            let upstream = ctx.upstream();
+            let content_type = upstream
+                .headers
+                .get(axum::http::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            let mut is_404 = upstream.status == 404;
+            if !is_404 && config.use_404_meta && is_html(content_type) {
+                if let Some(body) = ctx.normalized_body.as_deref() {
+                    is_404 = contains_404_meta(body, config.meta_404_scan_bytes);
+                }
+                if is_404 {
+                    tracing::debug!(
+                        "{} {} carries the phantom-404 meta tag, answering with 404",
+                        ctx.method,
+                        ctx.path
+                    );
+                    // Both the stored entry and the passthrough use this status.
+                    if let Some(upstream) = ctx.upstream.as_mut() {
+                        upstream.status = 404;
+                    }
+                }
+            }
+
+            let storable = ctx.response_is_cacheable
+                && ctx.cache_reads_enabled
+                && ctx.normalized_body.is_some();
+            ctx.store = if !storable {
+                None
+            } else if is_404 {
+                (config.cache_404_capacity > 0).then_some(StoreTarget::NotFound)
+            } else if ctx.upstream().status >= 500 && state.cache.error_store_enabled() {
+                ctx.should_cache.then_some(StoreTarget::Error)
+            } else {
+                ctx.should_cache.then_some(StoreTarget::Main)
+            };
+            if config.skip_set_cookie_responses
+                && ctx.store.is_some()
+                && ctx
+                    .upstream()
+                    .headers
+                    .contains_key(reqwest::header::SET_COOKIE)
+            {
+                tracing::debug!(
+                    "Not caching {} {}: the response sets a cookie",
+                    ctx.method,
+                    state.redactor().target(&ctx.cache_key)
+                );
+                ctx.store = None;
+            }
+            let directives = &ctx.upstream().directives;
+            if !directives.tags.is_empty() {
+                tracing::trace!(
+                    tags = ?directives.tags,
+                    "{} {} carries X-Phantom-Tags, which nothing uses yet",
+                    ctx.method,
+                    state.redactor().target(&ctx.cache_key)
+                );
+            }
+            if ctx.store.is_some() && !directives.allows_store() {
+                tracing::debug!(
+                    "Not caching {} {}: the backend sent {:?}",
+                    ctx.method,
+                    state.redactor().target(&ctx.cache_key),
+                    directives
+                );
+                ctx.store = None;
+            }
+            if config.respect_vary && ctx.store.is_some() {
+                apply_vary(state, ctx);
+            }
+            // The backend's own TTL wins over rule TTLs and `Cache-Control`.
+            match ctx.upstream().directives.ttl {
+                Some(ttl) if ctx.store.is_some() => ctx.entry_ttl = Some(ttl),
+                None if config.respect_cache_control && ctx.store.is_some() => {
+                    apply_cache_control(state, ctx)
+                }
+                _ => {}
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Apply the cache write ceiling; throttled responses are served unstored.
+pub(super) struct AdmitWrite;
+
+impl Stage for AdmitWrite {
+    fn name(&self) -> &'static str {
+        "admit_write"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            if ctx.store.is_some()
+                && state
+                    .write_limiter
+                    .as_ref()
+                    .is_some_and(|limiter| !limiter.try_acquire())
+            {
+                ctx.store = None;
+            }
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Rewrite the decoded body of an HTML response about to be stored with the
+/// configured normalization rules, and record its digest.
+pub(super) struct NormalizeHtml;
+
+impl Stage for NormalizeHtml {
+    fn name(&self) -> &'static str {
+        "normalize_html"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let config = &state.config;
+            let Some(normalizer) = config.html_normalizer.as_ref() else {
+                return ControlFlow::Continue(());
+            };
+            if ctx.store.is_none() {
+                return ControlFlow::Continue(());
+            }
+            let is_html = ctx
+                .upstream()
+                .headers
+                .get(axum::http::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.trim_start().starts_with("text/html"));
+            let paths = &state.html_normalize_paths;
+            let path_matches =
+                paths.is_empty() || paths.matches(ctx.method.as_str(), &ctx.path, ctx.query());
+            if !is_html || !path_matches {
+                return ControlFlow::Continue(());
+            }
+
+            let body = ctx
+                .normalized_body
+                .as_mut()
+                .expect("Classify only picks a store target for decoded bodies");
+            if let Cow::Owned(normalized) = normalizer.apply(body) {
+                *body = normalized;
+            }
+            ctx.content_digest = Some(content_digest(body));
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Pass a response about to be stored through the `transform_response` hook
+/// when its content type is one of `transform_content_types`.
+pub(super) struct Transform;
+
+impl Stage for Transform {
+    fn name(&self) -> &'static str {
+        "transform"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let config = &state.config;
+            let Some(hook) = config.transform_response.as_deref() else {
+                return ControlFlow::Continue(());
+            };
+            let upstream = ctx.upstream();
+            let content_type = upstream
+                .headers
+                .get(axum::http::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            if ctx.store.is_none()
+                || !transform::content_type_matches(&config.transform_content_types, content_type)
+            {
+                return ControlFlow::Continue(());
+            }
+
+            let mut headers = convert_headers_to_pairs(&upstream.headers);
+            let status = upstream.status;
+            for name in ["content-encoding", "content-length", "transfer-encoding"] {
+                remove_header(&mut headers, name);
+            }
+            let body = ctx
+                .normalized_body
+                .take()
+                .expect("Classify only picks a store target for decoded bodies");
+            let decoded = CachedResponse {
+                body: body.into(),
+                headers,
+                status,
+                content_encoding: None,
+                origin: None,
+                content_digest: None,
+                stored_at: None,
+            };
+            let req_info = crate::RequestInfo {
+                method: ctx.method.as_str(),
+                path: &ctx.path,
+                query: ctx.query(),
+                headers: &ctx.request_headers,
+            };
+            let Some(transformed) = transform::call_hook(hook, &req_info, decoded) else {
+                tracing::warn!(
+                    "transform_response panicked for {} {} — not caching it",
+                    ctx.method,
+                    ctx.path
+                );
+                state.callback_panics.fetch_add(1, Ordering::Relaxed);
+                ctx.store = None;
+                return ControlFlow::Continue(());
+            };
+            if ctx.content_digest.is_some() {
+                ctx.content_digest = Some(content_digest(&transformed.body));
+            }
+            ctx.normalized_body = Some(transformed.body.to_vec());
+            ctx.transformed_head = Some((transformed.status, transformed.headers));
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Compress and write the entry, then queue it for external rendering when a
+/// render-queue pattern matches.
+pub(super) struct Store;
+
+impl Stage for Store {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a Arc<ProxyState>,
+        ctx: &'a mut RequestContext,
+    ) -> StageFuture<'a> {
+        Box::pin(async move {
+            let Some(target) = ctx.store else {
+                return ControlFlow::Continue(());
+            };
+            let method_str = ctx.method.as_str();
+            let upstream = ctx.upstream();
+            let body = ctx.normalized_body.as_deref().unwrap();
+            let compression = &state.compression;
+            let built = match &ctx.transformed_head {
+                Some((status, headers)) => {
+                    build_cached_response_from_pairs(*status, headers.clone(), body, compression)
+                        .await
+                }
+                None => {
+                    build_cached_response(upstream.status, &upstream.headers, body, compression)
+                        .await
+                }
+            };
+            let mut cached = match built {
+                Ok(cached) => cached,
+                Err(error) => {
+                    tracing::warn!(
+                        "Failed to prepare cached response for {} {}: {}",
+                        method_str,
+                        ctx.path,
+                        error
+                    );
+                    return ControlFlow::Break(Ok(ctx.passthrough()));
+                }
+            };
+            if state.config.verify_cache_origin {
+                cached.origin = Some(CacheOrigin::new(method_str, &ctx.path));
+            }
+            cached.content_digest = ctx.content_digest.clone();
+
+            let (bucket, ttl) = match target {
+                StoreTarget::Main => (CacheBucket::Standard, ctx.entry_ttl),
+                StoreTarget::NotFound => (CacheBucket::NotFound, None),
+                StoreTarget::Error => (CacheBucket::Error, None),
+            };
+            let render = match (&state.render_queue, target) {
+                (Some(queue), StoreTarget::Main)
+                    if ctx.method == Method::GET
+                        && state.render_queue_patterns.matches(
+                            method_str,
+                            &ctx.path,
+                            ctx.query(),
+                        ) =>
+                {
+                    Some(queue.clone())
+                }
+                _ => None,
+            };
+
+            if let Some(queue) = &state.write_queue {
+                let status = upstream.status;
+                // The client is answered from `cached` without waiting for
+                // the write; a full queue serves it uncached.
+                let queued = queue.push(PendingWrite {
+                    bucket,
+                    key: ctx.cache_key.clone(),
+                    log_key: state.redactor().target(&ctx.cache_key).to_string(),
+                    response: cached.clone(),
+                    ttl,
+                    render: render.map(|queue| (queue, ctx.path_and_query.clone())),
+                    in_flight: ctx.in_flight.take(),
+                });
+                if queued {
+                    state.stats.record(Outcome::Store);
+                    let size = cached.body.len();
+                    state.emit(|events| events.on_store(&ctx.cache_key, size, status));
+                }
+                ctx.cached = Some(cached);
+                return ControlFlow::Continue(());
+            }
+
+            if let Err(error) = state
+                .cache
+                .try_set_in(bucket, ctx.cache_key.clone(), cached.clone(), ttl)
+                .await
+            {
+                // Bypassing leaves `ctx.cached` empty, so the upstream
+                // response is passed through.
+                return match apply_cache_error_policy(state, error, "store", &ctx.cache_key) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(status) => ControlFlow::Break(Err(status)),
+                };
+            }
+
+            state.stats.record(Outcome::Store);
+            let size = cached.body.len();
+            state.emit(|events| events.on_store(&ctx.cache_key, size, upstream.status));
+            match target {
+                StoreTarget::NotFound => {
+                    tracing::debug!(
+                        "Cached 404 response for: {} {}",
+                        method_str,
+                        state.redactor().target(&ctx.cache_key)
+                    );
+                }
+                StoreTarget::Error => {
+                    tracing::debug!(
+                        "Cached {} error response for: {} {}",
+                        upstream.status,
+                        method_str,
+                        state.redactor().target(&ctx.cache_key)
+                    );
+                }
+                StoreTarget::Main => {
+                    tracing::debug!(
+                        "Cached response for: {} {}",
+                        method_str,
+                        state.redactor().target(&ctx.cache_key)
+                    );
+                }
+            }
+            if let Some(queue) = render {
+                if queue.enqueue(&ctx.cache_key, &ctx.path_and_query).await {
+                    tracing::debug!(
+                        "Queued {} for external rendering",
+                        state.redactor().target(&ctx.cache_key)
+                    );
+                }
+            }
+
+            ctx.cached = Some(cached);
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Answer the client: from the stored entry when one was written, otherwise
+/// with the upstream response as received.
+async fn respond(
+    state: &ProxyState,
+    mut ctx: RequestContext,
+) -> Result<Response<Body>, StatusCode> {
+    let method_str = ctx.method.as_str();
+    let (ttfb_ms, body_ms) = {
+        let upstream = ctx.upstream();
+        (upstream.ttfb.as_millis(), upstream.body_time.as_millis())
+    };
+    if let Some(cached) = ctx.cached.take() {
+        let mut response =
+            serve_cached(state, &ctx.cache_key, cached, &ctx.request_headers).await?;
+        response.extensions_mut().insert(super::StoredOnMiss);
+        tracing::debug!(
+            method = method_str,
+            path = ctx.path,
+            elapsed_ms = ctx.request_started.elapsed().as_millis(),
+            ttfb_ms,
+            body_ms,
+            "proxy request completed after upstream fetch and cache write"
+        );
+        return Ok(response);
+    }
+
+    tracing::debug!(
+        method = method_str,
+        path = ctx.path,
+        elapsed_ms = ctx.request_started.elapsed().as_millis(),
+        ttfb_ms,
+        body_ms,
+        "proxy request completed without caching"
+    );
+    Ok(ctx.passthrough())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendSlot, PoolOptions};
+    use crate::cache::{CacheHandle, CacheStore};
+    use crate::CreateProxyConfig;
+
+    fn state(config: CreateProxyConfig) -> Arc<ProxyState> {
+        let backend =
+            BackendSlot::new(config.backend_urls(), PoolOptions::from_config(&config)).unwrap();
+        Arc::new(ProxyState::new(
+            CacheStore::new(CacheHandle::new(), config.cache_404_capacity),
+            config,
+            backend,
+            super::super::build_webhook_client().unwrap(),
+        ))
+    }
+
+    fn context(state: &ProxyState, status: u16, content_type: &str, body: &str) -> RequestContext {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            reqwest::header::HeaderValue::from_str(content_type).unwrap(),
+        );
+        let mut ctx = RequestContext::new(crate::test_support::get("/page"));
+        ctx.cache_key = "GET:/page".to_string();
+        ctx.should_cache = true;
+        ctx.cache_reads_enabled = true;
+        ctx.backend = Some(state.backend.pick());
+        ctx.upstream = Some(Upstream {
+            status,
+            headers,
+            body: body.as_bytes().to_vec(),
+            ttfb: Duration::ZERO,
+            body_time: Duration::ZERO,
+            directives: BackendDirectives::default(),
+        });
+        ctx
+    }
+
+    async fn run_stages(state: &Arc<ProxyState>, ctx: &mut RequestContext, stages: &[&dyn Stage]) {
+        for stage in stages {
+            assert!(
+                stage.run(state, ctx).await.is_continue(),
+                "{}",
+                stage.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_miss_path_starts_at_coalesce() {
+        assert_eq!(PIPELINE[MISS_PATH_START].name(), "coalesce");
+        let mut names: Vec<_> = PIPELINE.iter().map(|stage| stage.name()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), PIPELINE.len());
+    }
+
+    #[tokio::test]
+    async fn test_meta_404_is_classified_from_decoded_body() {
+        let state = state(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+                .with_use_404_meta(true)
+                .with_cache_404_capacity(10),
+        );
+        let mut ctx = context(
+            &state,
+            200,
+            "text/html",
+            r#"<meta name="phantom-404" content="true">"#,
+        );
+
+        run_stages(&state, &mut ctx, &[&Inspect, &Classify]).await;
+        assert!(ctx.normalized_body.is_some());
+        assert_eq!(ctx.store, Some(StoreTarget::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_cache_control_decides_store_and_ttl() {
+        let classify = |respect: bool, cache_control: &'static str, age: Option<&'static str>| {
+            let state = state(
+                CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+                    .with_respect_cache_control(respect),
+            );
+            async move {
+                let mut ctx = context(&state, 200, "text/html", "<p>page</p>");
+                let headers = &mut ctx.upstream.as_mut().unwrap().headers;
+                headers.insert(
+                    reqwest::header::CACHE_CONTROL,
+                    reqwest::header::HeaderValue::from_static(cache_control),
+                );
+                if let Some(age) = age {
+                    headers.insert(
+                        reqwest::header::AGE,
+                        reqwest::header::HeaderValue::from_static(age),
+                    );
+                }
+                run_stages(&state, &mut ctx, &[&Inspect, &Classify]).await;
+                (ctx.store, ctx.entry_ttl)
+            }
+        };
+        let main_for = |secs: u64| (Some(StoreTarget::Main), Some(Duration::from_secs(secs)));
+
+        assert_eq!(
+            classify(true, "public, max-age=60", None).await,
+            main_for(60)
+        );
+        assert_eq!(
+            classify(true, "max-age=60, s-maxage=10", None).await,
+            main_for(10)
+        );
+        assert_eq!(classify(true, "max-age=60", Some("45")).await, main_for(15));
+        assert_eq!(classify(true, "max-age=60", Some("90")).await, main_for(0));
+        assert_eq!(classify(true, "no-cache", None).await, main_for(0));
+        assert_eq!(classify(true, "max-age=oops", None).await, main_for(0));
+        assert_eq!(
+            classify(true, "public", None).await,
+            (Some(StoreTarget::Main), None)
+        );
+        assert_eq!(classify(true, "no-store", None).await, (None, None));
+        assert_eq!(
+            classify(true, "private, max-age=60", None).await,
+            (None, None)
+        );
+        assert_eq!(
+            classify(false, "no-store", None).await,
+            (Some(StoreTarget::Main), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excluded_content_type_is_not_stored() {
+        let state = state(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+                .with_cache_strategy(crate::CacheStrategy::OnlyHtml),
+        );
+        let mut ctx = context(&state, 200, "image/png", "png");
+
+        run_stages(
+            &state,
+            &mut ctx,
+            &[&Inspect, &Classify, &AdmitWrite, &Store],
+        )
+        .await;
+        assert_eq!(ctx.normalized_body, None);
+        assert_eq!(ctx.store, None);
+        assert!(ctx.cached.is_none());
+        assert_eq!(state.cache.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_write_is_served_unstored() {
+        let state = state(
+            CreateProxyConfig::new("http://127.0.0.1:1".to_string())
+                .with_max_cache_writes_per_sec(1),
+        );
+        let mut first = context(&state, 200, "text/html", "<p>one</p>");
+        let mut second = context(&state, 200, "text/html", "<p>two</p>");
+
+        run_stages(&state, &mut first, &[&Inspect, &Classify, &AdmitWrite]).await;
+        run_stages(&state, &mut second, &[&Inspect, &Classify, &AdmitWrite]).await;
+        assert_eq!(first.store, Some(StoreTarget::Main));
+        assert_eq!(second.store, None);
+
+        let response = respond(&state, second).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            crate::test_support::body_string(response).await,
+            "<p>two</p>"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Cache writes done after the client has its response.
+//!
+//! With `cache_write_queue_capacity` set, the miss path hands the entry it
+//! built to a bounded queue and answers straight away; one writer task stores
+//! queued entries in order. A full queue drops the write, which is counted
+//! and logged, and the response is simply not cached. A write that fails is
+//! logged and counted as a `cache_error_policy` bypass: the client was
+//! answered long ago, so `fail` cannot apply.
+//!
+//! A leader's coalescing guard travels with its write, so followers waiting
+//! on the same key wake once the entry is stored rather than before.
+
+use super::coalesce::LeaderGuard;
+use crate::cache::{CacheBucket, CacheStore, CachedResponse};
+use crate::render_queue::RenderQueue;
+use crate::stats::{PolicyActivation, ProxyStats};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// An entry waiting to be stored.
+pub(super) struct PendingWrite {
+    pub(super) bucket: CacheBucket,
+    pub(super) key: String,
+    /// The key as it may be logged.
+    pub(super) log_key: String,
+    pub(super) response: CachedResponse,
+    pub(super) ttl: Option<Duration>,
+    /// Queue the entry for external rendering once stored.
+    pub(super) render: Option<(Arc<RenderQueue>, String)>,
+    /// Released once the write is done.
+    pub(super) in_flight: Option<LeaderGuard>,
+}
+
+/// Bounded queue of cache writes, consumed by [`CacheWriteQueue::run`].
+pub(super) struct CacheWriteQueue {
+    sender: mpsc::Sender<PendingWrite>,
+    dropped: AtomicU64,
+    /// Held by tests to delay every write.
+    #[cfg(test)]
+    pub(super) hold: tokio::sync::RwLock<()>,
+}
+
+impl CacheWriteQueue {
+    /// A queue of at most `capacity` writes, and the receiver to pass to
+    /// [`CacheWriteQueue::run`].
+    pub(super) fn new(capacity: usize) -> (Self, mpsc::Receiver<PendingWrite>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let queue = Self {
+            sender,
+            dropped: AtomicU64::new(0),
+            #[cfg(test)]
+            hold: tokio::sync::RwLock::new(()),
+        };
+        (queue, receiver)
+    }
+
+    /// Queue `write`, or drop it when the queue is full. Returns whether it
+    /// was queued.
+    pub(super) fn push(&self, write: PendingWrite) -> bool {
+        let write = match self.sender.try_send(write) {
+            Ok(()) => return true,
+            Err(mpsc::error::TrySendError::Full(write)) => write,
+            // The writer stopped with the proxy.
+            Err(mpsc::error::TrySendError::Closed(_)) => return false,
+        };
+        let total = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            "Cache write queue full; not storing {} ({} writes dropped so far)",
+            write.log_key,
+            total
+        );
+        false
+    }
+
+    /// Writes dropped because the queue was full.
+    pub(super) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Store queued writes. Runs until the proxy shuts down.
+    pub(super) async fn run(
+        self: Arc<Self>,
+        mut receiver: mpsc::Receiver<PendingWrite>,
+        cache: CacheStore,
+        stats: Arc<ProxyStats>,
+    ) {
+        while let Some(write) = receiver.recv().await {
+            #[cfg(test)]
+            let _hold = self.hold.read().await;
+            store(&cache, &stats, write).await;
+        }
+    }
+}
+
+async fn store(cache: &CacheStore, stats: &ProxyStats, write: PendingWrite) {
+    let PendingWrite {
+        bucket,
+        key,
+        log_key,
+        response,
+        ttl,
+        render,
+        in_flight,
+    } = write;
+    if let Err(error) = cache.try_set_in(bucket, key.clone(), response, ttl).await {
+        tracing::warn!(
+            "Cache store failed for {}: {} — response was served uncached",
+            log_key,
+            error
+        );
+        stats.record_policy(PolicyActivation::CacheErrorBypass);
+        return;
+    }
+    tracing::debug!("Stored queued cache write for {}", log_key);
+    if let Some((queue, path_and_query)) = render {
+        if queue.enqueue(&key, &path_and_query).await {
+            tracing::debug!("Queued {} for external rendering", log_key);
+        }
+    }
+    drop(in_flight);
+}
@@ -0,0 +1,89 @@
+//! Query parameters left out of cache keys.
+//!
+//! Tracking parameters such as `utm_source` or `gclid` make every link a new
+//! URL. A [`QueryParamFilter`] drops them from the query a cache key is built
+//! from, so those URLs share one entry. The request forwarded to the backend
+//! keeps its full query.
+
+use std::borrow::Cow;
+
+/// Removes query parameters before the cache key is computed. Names match
+/// case-insensitively; a parameter without `=` is matched by its whole text.
+#[derive(Clone, Debug, Default)]
+pub struct QueryParamFilter {
+    ignored: Vec<String>,
+    /// When non-empty, only these parameters are kept.
+    allowed: Vec<String>,
+}
+
+impl QueryParamFilter {
+    /// Drop the `ignored` parameters and, if `allowed` is non-empty, every
+    /// parameter it does not list.
+    pub fn new(ignored: &[String], allowed: &[String]) -> Self {
+        Self {
+            ignored: ignored
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            allowed: allowed
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether the filter leaves every query unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.ignored.is_empty() && self.allowed.is_empty()
+    }
+
+    /// `query` without the filtered parameters, keeping the others in order.
+    pub fn apply<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        if self.is_empty() || query.is_empty() {
+            return Cow::Borrowed(query);
+        }
+        let kept = |pair: &&str| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            let listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+            !listed(&self.ignored) && (self.allowed.is_empty() || listed(&self.allowed))
+        };
+        if query.split('&').all(|pair| kept(&pair)) {
+            return Cow::Borrowed(query);
+        }
+        Cow::Owned(query.split('&').filter(kept).collect::<Vec<_>>().join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ignored_params_are_removed() {
+        let filter = QueryParamFilter::new(&names(&["utm_source", "gclid"]), &[]);
+
+        assert_eq!(
+            filter.apply("id=1&utm_source=mail&sort=asc"),
+            "id=1&sort=asc"
+        );
+        assert_eq!(filter.apply("UTM_Source=x&gclid"), "");
+        assert_eq!(filter.apply("utm_sourced=1"), "utm_sourced=1");
+        assert!(matches!(filter.apply("id=1&sort=asc"), Cow::Borrowed(_)));
+        assert!(matches!(
+            QueryParamFilter::default().apply("utm_source=x"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_allowed_params_are_the_only_ones_kept() {
+        let filter = QueryParamFilter::new(&names(&["page"]), &names(&["id", "page"]));
+
+        assert_eq!(filter.apply("session=x&id=1&page=2&id=3"), "id=1&id=3");
+        assert_eq!(filter.apply("debug"), "");
+    }
+}
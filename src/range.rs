@@ -0,0 +1,149 @@
+//! Byte range requests (RFC 9110 §14).
+//!
+//! A cached body is stored whole, so a request for one byte range can be cut
+//! from it. Only a single `bytes` range is understood; multiple ranges, other
+//! units, and malformed headers parse as `None` and the request is left to
+//! the backend. `If-Range` (§13.1.5) is compared strongly against the
+//! entry's `ETag`, or exactly against its `Last-Modified`.
+
+use crate::conditional::EntityTag;
+use std::ops::RangeInclusive;
+
+/// One range of a `Range: bytes=…` header, before it is resolved against
+/// the length of a body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `first-last`, or `first-` through the end.
+    From { first: u64, last: Option<u64> },
+    /// `-length`: the last `length` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parse a `Range` header holding exactly one `bytes` range.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (unit, spec) = value.trim().split_once('=')?;
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let (first, last) = spec.trim().split_once('-')?;
+        let number = |digits: &str| {
+            (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| digits.parse::<u64>().ok())
+                .flatten()
+        };
+        if first.is_empty() {
+            return number(last).map(Self::Suffix);
+        }
+        let first = number(first)?;
+        let last = match last {
+            "" => None,
+            last => Some(number(last).filter(|&last| last >= first)?),
+        };
+        Some(Self::From { first, last })
+    }
+
+    /// The bytes this range selects from a body of `len` bytes, or `None`
+    /// when it selects none and the answer is `416`.
+    pub fn resolve(self, len: u64) -> Option<RangeInclusive<u64>> {
+        let end = len.checked_sub(1)?;
+        match self {
+            Self::From { first, last } => {
+                (first <= end).then(|| first..=last.map_or(end, |last| last.min(end)))
+            }
+            Self::Suffix(0) => None,
+            Self::Suffix(length) => Some(len.saturating_sub(length)..=end),
+        }
+    }
+}
+
+/// Whether an `If-Range` value still names the stored representation, so
+/// the range may be served from it. A weak or missing validator never does.
+pub fn if_range_matches(header: &str, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    let header = header.trim();
+    if header.starts_with('"') || header.starts_with("W/") {
+        let current = etag.and_then(EntityTag::parse);
+        return EntityTag::parse(header)
+            .zip(current)
+            .is_some_and(|(given, current)| given.strong_eq(&current));
+    }
+    let date = |value: &str| httpdate::parse_http_date(value.trim()).ok();
+    date(header).is_some_and(|given| last_modified.and_then(date) == Some(given))
+}
+
+/// The `Content-Range` of `range` within a body of `len` bytes.
+pub fn content_range(range: &RangeInclusive<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), len)
+}
+
+/// The `Content-Range` of a `416` for a body of `len` bytes.
+pub fn unsatisfied_range(len: u64) -> String {
+    format!("bytes */{}", len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let from = |first, last| Some(ByteRange::From { first, last });
+        assert_eq!(ByteRange::parse("bytes=0-1023"), from(0, Some(1023)));
+        assert_eq!(ByteRange::parse(" bytes = 5- "), from(5, None));
+        assert_eq!(ByteRange::parse("Bytes=-500"), Some(ByteRange::Suffix(500)));
+        assert_eq!(ByteRange::parse("bytes=7-7"), from(7, Some(7)));
+        // Several ranges, other units, and malformed values are not parsed.
+        for value in [
+            "bytes=0-1,5-9",
+            "bytes=0-1, -5",
+            "items=0-1",
+            "bytes=5-2",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=0-1-2",
+            "bytes 0-1",
+            "",
+        ] {
+            assert_eq!(ByteRange::parse(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let range = |value| ByteRange::parse(value).unwrap();
+        assert_eq!(range("bytes=0-3").resolve(10), Some(0..=3));
+        assert_eq!(range("bytes=4-").resolve(10), Some(4..=9));
+        assert_eq!(range("bytes=8-100").resolve(10), Some(8..=9));
+        assert_eq!(range("bytes=-3").resolve(10), Some(7..=9));
+        assert_eq!(range("bytes=-30").resolve(10), Some(0..=9));
+        assert_eq!(range("bytes=10-").resolve(10), None);
+        assert_eq!(range("bytes=-0").resolve(10), None);
+        assert_eq!(range("bytes=0-").resolve(0), None);
+        assert_eq!(range("bytes=-5").resolve(0), None);
+        assert_eq!(content_range(&(7..=9), 10), "bytes 7-9/10");
+        assert_eq!(unsatisfied_range(10), "bytes */10");
+    }
+
+    #[test]
+    fn test_if_range() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(if_range_matches(r#""v1""#, Some(r#""v1""#), None));
+        assert!(!if_range_matches(r#""v1""#, Some(r#""v2""#), None));
+        assert!(!if_range_matches(r#"W/"v1""#, Some(r#""v1""#), None));
+        assert!(!if_range_matches(r#""v1""#, Some(r#"W/"v1""#), None));
+        assert!(!if_range_matches(r#""v1""#, None, Some(date)));
+        assert!(if_range_matches(date, Some(r#""v1""#), Some(date)));
+        assert!(!if_range_matches(
+            "Sun, 06 Nov 1994 08:49:38 GMT",
+            None,
+            Some(date)
+        ));
+        assert!(!if_range_matches(date, None, None));
+        assert!(!if_range_matches(
+            "garbage",
+            Some("garbage"),
+            Some("garbage")
+        ));
+    }
+}
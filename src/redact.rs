@@ -0,0 +1,225 @@
+//! Redaction of sensitive values in what phantom-frame reports about requests.
+//!
+//! Log lines and debug output that mention a request target, a cache key, or
+//! request headers go through a [`Redactor`], which replaces the values of
+//! configured query parameters and headers with `[redacted]`. Only those
+//! reports are affected: the request forwarded to the backend and the cache
+//! key it is stored under keep their real values.
+
+use axum::http::HeaderMap;
+use std::borrow::Cow;
+use std::fmt;
+
+/// Replacement for a redacted value.
+pub const REDACTED: &str = "[redacted]";
+
+/// Query parameters redacted by default.
+pub const DEFAULT_REDACT_QUERY_PARAMS: &[&str] = &[
+    "token",
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "auth",
+    "key",
+    "api_key",
+    "apikey",
+    "secret",
+    "client_secret",
+    "password",
+    "signature",
+    "sig",
+    "email",
+];
+
+/// Headers redacted by default.
+pub const DEFAULT_REDACT_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-phantom-revalidate",
+    "x-phantom-purge",
+];
+
+/// [`DEFAULT_REDACT_QUERY_PARAMS`] as owned strings, for config defaults.
+pub fn default_redact_query_params() -> Vec<String> {
+    DEFAULT_REDACT_QUERY_PARAMS
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// [`DEFAULT_REDACT_HEADERS`] as owned strings, for config defaults.
+pub fn default_redact_headers() -> Vec<String> {
+    DEFAULT_REDACT_HEADERS
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Redacts configured query parameters and headers. Names match
+/// case-insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    query_params: Vec<String>,
+    headers: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(query_params: &[String], headers: &[String]) -> Self {
+        Self {
+            query_params: query_params
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            headers: headers
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Redact the query of anything ending in `?query`: a path, a path and
+    /// query, or a default-format cache key such as `GET:/page?token=abc`.
+    pub fn target<'a>(&self, target: &'a str) -> Cow<'a, str> {
+        match target.split_once('?') {
+            Some((before, query)) => match self.query(query) {
+                Cow::Borrowed(_) => Cow::Borrowed(target),
+                Cow::Owned(query) => Cow::Owned(format!("{}?{}", before, query)),
+            },
+            None => Cow::Borrowed(target),
+        }
+    }
+
+    /// Redact the values of sensitive parameters in a query string.
+    pub fn query<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        let sensitive = |pair: &str| {
+            pair.split_once('=').is_some_and(|(name, value)| {
+                value != REDACTED
+                    && self
+                        .query_params
+                        .iter()
+                        .any(|param| param.eq_ignore_ascii_case(name))
+            })
+        };
+        if !query.split('&').any(sensitive) {
+            return Cow::Borrowed(query);
+        }
+
+        let pairs: Vec<Cow<'_, str>> = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if sensitive(pair) => Cow::Owned(format!("{}={}", name, REDACTED)),
+                _ => Cow::Borrowed(pair),
+            })
+            .collect();
+        Cow::Owned(pairs.join("&"))
+    }
+
+    /// Whether values of the header `name` are redacted.
+    pub fn is_sensitive_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+    }
+
+    /// A `Debug` view of `headers` with sensitive values redacted.
+    pub fn headers<'a>(&'a self, headers: &'a HeaderMap) -> RedactedHeaders<'a> {
+        RedactedHeaders {
+            redactor: self,
+            headers,
+        }
+    }
+}
+
+/// Headers formatted with sensitive values redacted; see [`Redactor::headers`].
+pub struct RedactedHeaders<'a> {
+    redactor: &'a Redactor,
+    headers: &'a HeaderMap,
+}
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.headers.iter().map(|(name, value)| {
+                let value = if self.redactor.is_sensitive_header(name.as_str()) {
+                    REDACTED
+                } else {
+                    value.to_str().unwrap_or("<non-utf8>")
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_redactor() -> Redactor {
+        Redactor::new(&default_redact_query_params(), &default_redact_headers())
+    }
+
+    #[test]
+    fn test_query_values_are_redacted_by_name() {
+        let redactor = default_redactor();
+        assert_eq!(
+            redactor.query("page=2&Token=abc&email=a%40b.c&flag"),
+            "page=2&Token=[redacted]&email=[redacted]&flag"
+        );
+        assert!(matches!(
+            redactor.query("page=2&sort=asc"),
+            Cow::Borrowed(_)
+        ));
+        // Only exact names match; `tokens` and `monkey` are left alone.
+        assert_eq!(redactor.query("tokens=1&monkey=2"), "tokens=1&monkey=2");
+    }
+
+    #[test]
+    fn test_target_redacts_paths_and_cache_keys() {
+        let redactor = default_redactor();
+        assert_eq!(
+            redactor.target("GET:/login?next=/home&token=abc"),
+            "GET:/login?next=/home&token=[redacted]"
+        );
+        assert_eq!(redactor.target("/plain"), "/plain");
+        assert_eq!(redactor.target("/a?key="), "/a?key=[redacted]");
+        // Redacting twice is stable.
+        assert_eq!(
+            redactor.target(&redactor.target("/a?key=1")),
+            "/a?key=[redacted]"
+        );
+    }
+
+    #[test]
+    fn test_headers_debug_view_and_custom_lists() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("x-session", "s1".parse().unwrap());
+        headers.insert("accept", "text/html".parse().unwrap());
+
+        let output = format!("{:?}", default_redactor().headers(&headers));
+        assert!(
+            output.contains(r#""authorization": "[redacted]""#),
+            "{}",
+            output
+        );
+        assert!(output.contains(r#""x-session": "s1""#), "{}", output);
+        assert!(!output.contains("secret"), "{}", output);
+
+        let custom = Redactor::new(&["session".to_string()], &["X-Session".to_string()]);
+        let output = format!("{:?}", custom.headers(&headers));
+        assert!(output.contains("Bearer secret"), "{}", output);
+        assert!(
+            output.contains(r#""x-session": "[redacted]""#),
+            "{}",
+            output
+        );
+        assert_eq!(
+            custom.query("token=1&session=2"),
+            "token=1&session=[redacted]"
+        );
+    }
+}
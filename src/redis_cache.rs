@@ -0,0 +1,390 @@
+//! A main cache kept in Redis, so several proxies behind a load balancer
+//! share one (`redis-backend` feature).
+//!
+//! Each entry is a Redis hash under the key prefix followed by the cache key:
+//! `meta` holds the status, headers, and other metadata as JSON, and `body`
+//! the body bytes. An entry stored with a TTL gets a matching expiry, extended
+//! by the stale retention when stale entries are kept for
+//! `backend_error_policy`; lookups then check the TTL themselves.
+//!
+//! `meta` records the [`CACHE_FORMAT_VERSION`] it was written with. Entries
+//! from the previous version are read as they are, since `meta` did not
+//! change, unless the [`FormatMismatchPolicy`] is to discard them; entries
+//! from any other version are treated as missing and replaced on the next
+//! store.
+//!
+//! Only the main cache is shared. The 404 and error stores, the decision memo,
+//! and the `Vary` names learnt per key stay with each proxy.
+
+use crate::cache::{purge_matches, CacheOrigin, CachedResponse};
+use crate::compression::ContentEncoding;
+use crate::path_matcher::{Wildcard, WildcardSyntax};
+use crate::persistence::{from_unix_millis, readable_format, unix_millis, CACHE_FORMAT_VERSION};
+use crate::FormatMismatchPolicy;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::OnceCell;
+
+/// Keys fetched per `SCAN` call while purging.
+const SCAN_COUNT: usize = 500;
+
+/// A connection to the Redis server holding a proxy's main cache.
+#[derive(Clone)]
+pub struct RedisCache {
+    client: redis::Client,
+    prefix: String,
+    format_policy: FormatMismatchPolicy,
+    /// Opened on first use; reconnects on its own after that.
+    connection: Arc<OnceCell<ConnectionManager>>,
+}
+
+/// The `meta` field of an entry.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    format: u32,
+    status: u16,
+    headers: Vec<(String, String)>,
+    content_encoding: Option<String>,
+    origin: Option<(String, String)>,
+    content_digest: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    stored_at: u64,
+    expires_at: Option<u64>,
+}
+
+impl RedisCache {
+    /// A cache on the Redis server at `url` (e.g. `redis://127.0.0.1/0`),
+    /// whose keys all start with `key_prefix`. Fails only for a malformed
+    /// URL; the server is first contacted when the cache is used.
+    pub fn new(url: &str, key_prefix: impl Into<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            prefix: key_prefix.into(),
+            format_policy: FormatMismatchPolicy::default(),
+            connection: Arc::default(),
+        })
+    }
+
+    /// Set whether entries from the previous format version are read or
+    /// treated as missing.
+    pub fn with_format_mismatch_policy(mut self, policy: FormatMismatchPolicy) -> Self {
+        self.format_policy = policy;
+        self
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+    }
+
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// The entry under `key`. One whose TTL has lapsed is returned only when
+    /// `include_expired` is set.
+    pub(crate) async fn get(
+        &self,
+        key: &str,
+        include_expired: bool,
+    ) -> RedisResult<Option<CachedResponse>> {
+        let mut connection = self.connection().await?;
+        let (meta, body): (Option<Vec<u8>>, Option<Vec<u8>>) = connection
+            .hget(self.redis_key(key), &["meta", "body"])
+            .await?;
+        let (Some(meta), Some(body)) = (meta, body) else {
+            return Ok(None);
+        };
+        let meta = match serde_json::from_slice::<Meta>(&meta) {
+            Ok(meta) if readable_format(meta.format, self.format_policy) => meta,
+            Ok(meta) => {
+                tracing::debug!(
+                    "Ignoring Redis cache entry '{}' in format version {}",
+                    key,
+                    meta.format
+                );
+                return Ok(None);
+            }
+            Err(error) => {
+                tracing::warn!("Ignoring unreadable Redis cache entry '{}': {}", key, error);
+                return Ok(None);
+            }
+        };
+        if !include_expired
+            && meta
+                .expires_at
+                .is_some_and(|at| at <= unix_millis(SystemTime::now()))
+        {
+            return Ok(None);
+        }
+        Ok(Some(CachedResponse {
+            body: body.into(),
+            headers: meta.headers,
+            status: meta.status,
+            content_encoding: meta
+                .content_encoding
+                .as_deref()
+                .and_then(ContentEncoding::from_header_value),
+            origin: meta
+                .origin
+                .map(|(method, path)| CacheOrigin::new(&method, &path)),
+            content_digest: meta.content_digest,
+            stored_at: Some(from_unix_millis(meta.stored_at)),
+        }))
+    }
+
+    /// Store `response` under `key`, replacing any entry there. With a
+    /// `ttl`, Redis drops the entry once it lapses, or `retention` after.
+    pub(crate) async fn set(
+        &self,
+        key: &str,
+        response: &CachedResponse,
+        ttl: Option<Duration>,
+        retention: Option<Duration>,
+    ) -> RedisResult<()> {
+        let now = SystemTime::now();
+        let meta = Meta {
+            format: CACHE_FORMAT_VERSION,
+            status: response.status,
+            headers: response.headers.clone(),
+            content_encoding: response
+                .content_encoding
+                .map(|encoding| encoding.as_header_value().to_string()),
+            origin: response
+                .origin
+                .as_ref()
+                .map(|origin| (origin.method.clone(), origin.path.clone())),
+            content_digest: response.content_digest.clone(),
+            stored_at: unix_millis(now),
+            expires_at: ttl.map(|ttl| unix_millis(now + ttl)),
+        };
+        let meta = serde_json::to_vec(&meta).expect("cache metadata serializes");
+
+        let redis_key = self.redis_key(key);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(&redis_key)
+            .ignore()
+            .hset_multiple(
+                &redis_key,
+                &[("meta", &meta[..]), ("body", &response.body[..])],
+            )
+            .ignore();
+        if let Some(ttl) = ttl {
+            let expiry = ttl.saturating_add(retention.unwrap_or_default());
+            // Redis rejects a zero expiry; one millisecond is as good as gone.
+            let millis = expiry.as_millis().clamp(1, i64::MAX as u128) as i64;
+            pipe.pexpire(&redis_key, millis).ignore();
+        }
+        let mut connection = self.connection().await?;
+        pipe.query_async(&mut connection).await
+    }
+
+    /// Remove the entry under `key`. Returns whether there was one.
+    pub(crate) async fn remove(&self, key: &str) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        let removed: usize = connection.del(self.redis_key(key)).await?;
+        Ok(removed > 0)
+    }
+
+    /// Remove every entry, or with a `pattern` the entries a purge for it
+    /// covers (see [`CacheStore::clear_by_pattern`]). Returns how many were
+    /// removed.
+    ///
+    /// [`CacheStore::clear_by_pattern`]: crate::cache::CacheStore::clear_by_pattern
+    pub(crate) async fn remove_matching(
+        &self,
+        pattern: Option<&str>,
+        syntax: WildcardSyntax,
+    ) -> RedisResult<usize> {
+        // A single scan finds a key's variants too; `purge_matches` then
+        // applies the exact rules, which a Redis glob cannot express.
+        let glob = format!(
+            "{}{}*",
+            escape_glob(&self.prefix),
+            pattern.map(glob_for_pattern).unwrap_or_default()
+        );
+        let wildcard = pattern.map(|pattern| Wildcard::with_syntax(pattern, syntax));
+        self.remove_scanned(&glob, |key| {
+            wildcard
+                .as_ref()
+                .is_none_or(|pattern| purge_matches(key, pattern))
+        })
+        .await
+    }
+
+    /// Remove the entry under exactly `key` and its `Vary` variants. Returns
+    /// how many were removed.
+    pub(crate) async fn remove_key(&self, key: &str) -> RedisResult<usize> {
+        let removed = usize::from(self.remove(key).await?);
+        let variants = format!("{}{}{}", self.prefix, key, crate::vary::KEY_MARKER);
+        let glob = format!("{}*", escape_glob(&variants));
+        Ok(removed + self.remove_scanned(&glob, |_| true).await?)
+    }
+
+    /// Delete the keys matching the Redis `glob` whose cache key, without the
+    /// prefix, satisfies `matches`.
+    async fn remove_scanned(
+        &self,
+        glob: &str,
+        matches: impl Fn(&str) -> bool,
+    ) -> RedisResult<usize> {
+        let mut connection = self.connection().await?;
+        let mut cursor = 0u64;
+        let mut removed = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(glob)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut connection)
+                .await?;
+            let matching: Vec<&String> = keys
+                .iter()
+                .filter(|redis_key| matches(&redis_key[self.prefix.len()..]))
+                .collect();
+            if !matching.is_empty() {
+                let deleted: usize = connection.del(&matching).await?;
+                removed += deleted;
+            }
+            if next == 0 {
+                return Ok(removed);
+            }
+            cursor = next;
+        }
+    }
+}
+
+impl std::fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The connection info may carry a password.
+        f.debug_struct("RedisCache")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Escape the characters Redis glob patterns treat specially.
+fn escape_glob(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A purge pattern as a Redis glob: `*` stays a wildcard, everything else is
+/// literal.
+fn glob_for_pattern(pattern: &str) -> String {
+    pattern
+        .split('*')
+        .map(escape_glob)
+        .collect::<Vec<_>>()
+        .join("*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purge_patterns_become_redis_globs() {
+        assert_eq!(glob_for_pattern("GET:/api/*"), "GET:/api/*");
+        assert_eq!(
+            glob_for_pattern("GET:/search?q=[a]*"),
+            "GET:/search\\?q=\\[a\\]*"
+        );
+        assert_eq!(escape_glob("pf:*"), "pf:\\*");
+    }
+
+    /// Runs against the server named by `PHANTOM_FRAME_TEST_REDIS_URL` and is
+    /// skipped when it is not set. Entries are written under a fresh prefix.
+    #[tokio::test]
+    async fn test_entries_round_trip_through_redis() {
+        let Ok(url) = std::env::var("PHANTOM_FRAME_TEST_REDIS_URL") else {
+            return;
+        };
+        let prefix = format!("phantom-frame-test:{}:", unix_millis(SystemTime::now()));
+        let cache = RedisCache::new(&url, prefix).unwrap();
+        let response = CachedResponse {
+            body: bytes::Bytes::from_static(b"\x00binary\xff"),
+            headers: vec![
+                ("set-cookie".to_string(), "a=1".to_string()),
+                ("set-cookie".to_string(), "b=2".to_string()),
+            ],
+            status: 200,
+            content_encoding: Some(ContentEncoding::Gzip),
+            origin: Some(CacheOrigin::new("GET", "/page")),
+            content_digest: None,
+            stored_at: None,
+        };
+
+        cache.set("GET:/page", &response, None, None).await.unwrap();
+        let stored = cache.get("GET:/page", false).await.unwrap().unwrap();
+        assert_eq!(stored.body, response.body);
+        assert_eq!(stored.headers, response.headers);
+        assert_eq!(stored.content_encoding, Some(ContentEncoding::Gzip));
+        assert_eq!(stored.origin, response.origin);
+
+        // A lapsed TTL hides the entry; kept, it is still there when asked
+        // for stale copies, until the retention runs out too.
+        let ttl = Some(Duration::from_millis(20));
+        let retention = Some(Duration::from_millis(200));
+        cache
+            .set("GET:/short", &response, ttl, retention)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.get("GET:/short", false).await.unwrap().is_none());
+        assert!(cache.get("GET:/short", true).await.unwrap().is_some());
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(cache.get("GET:/short", true).await.unwrap().is_none());
+
+        cache
+            .set("GET:/api/a", &response, None, None)
+            .await
+            .unwrap();
+        cache
+            .set("GET:/page#vary=0123456789abcdef", &response, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache
+                .remove_matching(Some("GET:/page"), WildcardSyntax::Legacy)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(cache.get("GET:/api/a", false).await.unwrap().is_some());
+
+        // An exact key: `*` is literal, and variants go with the key.
+        cache
+            .set("GET:/api/*#vary=0123456789abcdef", &response, None, None)
+            .await
+            .unwrap();
+        assert_eq!(cache.remove_key("GET:/api/*").await.unwrap(), 1);
+        assert!(cache.get("GET:/api/a", false).await.unwrap().is_some());
+        assert_eq!(
+            cache
+                .remove_matching(None, WildcardSyntax::Legacy)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(!cache.remove("GET:/api/a").await.unwrap());
+    }
+}
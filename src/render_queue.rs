@@ -0,0 +1,428 @@
+//! Pending-render queue for deferred cache population.
+//!
+//! When a cache miss matches one of the configured `render_queue_patterns`, the
+//! proxy serves the backend response immediately and records the request here.
+//! An external renderer (e.g. headless Chrome) claims pending URLs through the
+//! control API, renders them, and submits the result via `PUT /cache/entry`,
+//! which replaces the interim cache entry and completes the job.
+//!
+//! Claims are leased: a claimed job is hidden from other workers until its
+//! lease expires, after which it becomes claimable again. While a lease is
+//! live, only its holder can complete the job.
+//!
+//! With a persistence path, changes are written to the file in the
+//! background, [`PERSIST_DEBOUNCE`] after the first of them, so a burst of
+//! misses shares one write and none of them waits on the disk.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// Default lease duration handed out by [`RenderQueue::claim`].
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60);
+
+/// Numbers lease ids across every queue in the process, so a lease on one
+/// proxy's queue never matches a job on another's.
+static LEASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How long the queue file waits after a change before it is rewritten.
+pub const PERSIST_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// A job claimed by an external render worker.
+#[derive(Clone, Debug, Serialize)]
+pub struct RenderLease {
+    /// The cache key the rendered result must be stored under.
+    pub key: String,
+    /// Path and query to render, relative to the proxied origin.
+    pub path: String,
+    /// Opaque lease identifier. Submit it back with the result to prove ownership.
+    pub lease_id: String,
+    /// Seconds until the lease expires and the job becomes claimable again.
+    pub lease_expires_in_secs: u64,
+}
+
+/// Serializable form of a pending job, used for optional on-disk persistence.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PersistedJob {
+    key: String,
+    path: String,
+    enqueued_at: u64,
+}
+
+#[derive(Debug)]
+struct PendingRender {
+    key: String,
+    path: String,
+    enqueued_at: u64,
+    lease: Option<(String, Instant)>,
+}
+
+/// Outcome of submitting a rendered result for a queued key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompleteOutcome {
+    /// The job was pending and has been removed from the queue.
+    Completed,
+    /// No job was queued for the key (the submission is still accepted as a plain seed).
+    NotQueued,
+    /// The job is currently leased, and the submission did not carry that
+    /// lease's id.
+    LeaseMismatch,
+}
+
+type Jobs = Arc<Mutex<VecDeque<PendingRender>>>;
+
+/// Bounded FIFO queue of cache keys awaiting an external render.
+pub struct RenderQueue {
+    jobs: Jobs,
+    capacity: usize,
+    file: Option<Arc<QueueFile>>,
+    dropped: AtomicU64,
+}
+
+/// The file a queue is persisted to.
+struct QueueFile {
+    path: PathBuf,
+    /// Set while a background write is pending.
+    scheduled: AtomicBool,
+    /// Held while the file is written, so two writes never share the temp
+    /// file and a newer snapshot is never overwritten by an older one.
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl RenderQueue {
+    /// Create a queue holding at most `capacity` pending jobs.
+    ///
+    /// When `persist_path` is set, previously persisted jobs are loaded from it
+    /// and every change is written back in the background.
+    pub fn new(capacity: usize, persist_path: Option<PathBuf>) -> Self {
+        let jobs = persist_path
+            .as_deref()
+            .map(load_persisted_jobs)
+            .unwrap_or_default()
+            .into_iter()
+            .take(capacity)
+            .map(|job| PendingRender {
+                key: job.key,
+                path: job.path,
+                enqueued_at: job.enqueued_at,
+                lease: None,
+            })
+            .collect();
+
+        Self {
+            jobs: Arc::new(Mutex::new(jobs)),
+            capacity,
+            file: persist_path.map(|path| {
+                Arc::new(QueueFile {
+                    path,
+                    scheduled: AtomicBool::new(false),
+                    write_lock: tokio::sync::Mutex::new(()),
+                })
+            }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `key` for rendering. Returns `false` when the key is already
+    /// pending or the queue is full.
+    pub async fn enqueue(&self, key: &str, path: &str) -> bool {
+        let accepted = {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.iter().any(|job| job.key == key) {
+                false
+            } else if jobs.len() >= self.capacity {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Render queue is full ({} jobs), not queueing '{}'",
+                    self.capacity,
+                    key
+                );
+                false
+            } else {
+                jobs.push_back(PendingRender {
+                    key: key.to_string(),
+                    path: path.to_string(),
+                    enqueued_at: unix_now(),
+                    lease: None,
+                });
+                true
+            }
+        };
+
+        if accepted {
+            self.schedule_persist();
+        }
+        accepted
+    }
+
+    /// Claim up to `limit` unleased (or lease-expired) jobs for `lease` duration.
+    pub fn claim(&self, limit: usize, lease: Duration) -> Vec<RenderLease> {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut claimed = Vec::new();
+
+        for job in jobs.iter_mut() {
+            if claimed.len() >= limit {
+                break;
+            }
+            if matches!(&job.lease, Some((_, expires)) if *expires > now) {
+                continue;
+            }
+
+            let lease_id = format!(
+                "{:x}-{:x}",
+                job.enqueued_at,
+                LEASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            job.lease = Some((lease_id.clone(), now + lease));
+            claimed.push(RenderLease {
+                key: job.key.clone(),
+                path: job.path.clone(),
+                lease_id,
+                lease_expires_in_secs: lease.as_secs(),
+            });
+        }
+
+        claimed
+    }
+
+    /// Mark the job for `key` as rendered and remove it from the queue.
+    ///
+    /// While the job has a live lease, `lease_id` must name it; otherwise any
+    /// submission completes the job.
+    pub async fn complete(&self, key: &str, lease_id: Option<&str>) -> CompleteOutcome {
+        let outcome = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let outcome = check_lease(&jobs, key, lease_id);
+            if outcome == CompleteOutcome::Completed {
+                jobs.retain(|job| job.key != key);
+            }
+            outcome
+        };
+
+        if outcome == CompleteOutcome::Completed {
+            self.schedule_persist();
+        }
+        outcome
+    }
+
+    /// What [`complete`](Self::complete) would answer for `key` and
+    /// `lease_id` right now, without changing the queue.
+    pub fn check(&self, key: &str, lease_id: Option<&str>) -> CompleteOutcome {
+        check_lease(&self.jobs.lock().unwrap(), key, lease_id)
+    }
+
+    /// Number of jobs currently pending (leased or not).
+    pub fn len(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    /// Returns `true` when no jobs are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of jobs rejected because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Write the queue file now instead of waiting for the pending
+    /// background write, e.g. before shutting down.
+    pub async fn flush(&self) {
+        if let Some(file) = &self.file {
+            file.write(&self.jobs).await;
+        }
+    }
+
+    /// Have the queue file rewritten [`PERSIST_DEBOUNCE`] from now, unless a
+    /// write is already pending, which will then include this change.
+    fn schedule_persist(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        if file.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (file, jobs) = (file.clone(), self.jobs.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(PERSIST_DEBOUNCE).await;
+            // Cleared first: a change made during the write schedules another.
+            file.scheduled.store(false, Ordering::Release);
+            file.write(&jobs).await;
+        });
+    }
+}
+
+impl QueueFile {
+    async fn write(&self, jobs: &Jobs) {
+        let _guard = self.write_lock.lock().await;
+        let snapshot: Vec<PersistedJob> = jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| PersistedJob {
+                key: job.key.clone(),
+                path: job.path.clone(),
+                enqueued_at: job.enqueued_at,
+            })
+            .collect();
+
+        let result = async {
+            let payload = serde_json::to_vec(&snapshot)?;
+            let tmp_path = self.path.with_extension("tmp");
+            tokio::fs::write(&tmp_path, payload).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(error) = result {
+            tracing::warn!(
+                "Failed to persist render queue to '{}': {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Whether a submission for `key` carrying `lease_id` may complete its job.
+fn check_lease(
+    jobs: &VecDeque<PendingRender>,
+    key: &str,
+    lease_id: Option<&str>,
+) -> CompleteOutcome {
+    let Some(job) = jobs.iter().find(|job| job.key == key) else {
+        return CompleteOutcome::NotQueued;
+    };
+    let active_lease = job
+        .lease
+        .as_ref()
+        .filter(|(_, expires)| *expires > Instant::now())
+        .map(|(id, _)| id.as_str());
+    match active_lease {
+        Some(active) if lease_id != Some(active) => CompleteOutcome::LeaseMismatch,
+        _ => CompleteOutcome::Completed,
+    }
+}
+
+fn load_persisted_jobs(path: &std::path::Path) -> Vec<PersistedJob> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(error) => {
+            tracing::warn!(
+                "Failed to read persisted render queue '{}': {}",
+                path.display(),
+                error
+            );
+            return Vec::new();
+        }
+    };
+
+    serde_json::from_slice(&content).unwrap_or_else(|error| {
+        tracing::warn!(
+            "Ignoring unreadable render queue file '{}': {}",
+            path.display(),
+            error
+        );
+        Vec::new()
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_enqueue_deduplicates_and_respects_capacity() {
+        let queue = RenderQueue::new(2, None);
+        assert!(queue.enqueue("GET:/a", "/a").await);
+        assert!(!queue.enqueue("GET:/a", "/a").await);
+        assert!(queue.enqueue("GET:/b", "/b").await);
+        assert!(!queue.enqueue("GET:/c", "/c").await);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_claim_is_exclusive_until_lease_expires() {
+        let queue = RenderQueue::new(10, None);
+        queue.enqueue("GET:/a", "/a").await;
+
+        let first = queue.claim(10, Duration::from_secs(30));
+        assert_eq!(first.len(), 1);
+        assert!(queue.claim(10, Duration::from_secs(30)).is_empty());
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        let second = queue.claim(10, Duration::from_secs(30));
+        assert_eq!(second.len(), 1);
+        assert_ne!(first[0].lease_id, second[0].lease_id);
+
+        assert_eq!(
+            queue.complete("GET:/a", Some(&first[0].lease_id)).await,
+            CompleteOutcome::LeaseMismatch
+        );
+        // A live lease cannot be bypassed by leaving the id out.
+        assert_eq!(queue.check("GET:/a", None), CompleteOutcome::LeaseMismatch);
+        assert_eq!(
+            queue.complete("GET:/a", None).await,
+            CompleteOutcome::LeaseMismatch
+        );
+        assert_eq!(
+            queue.complete("GET:/a", Some(&second[0].lease_id)).await,
+            CompleteOutcome::Completed
+        );
+        assert!(queue.is_empty());
+
+        // Without a live lease, no id is needed.
+        queue.enqueue("GET:/b", "/b").await;
+        queue.claim(10, Duration::from_secs(30));
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_eq!(
+            queue.complete("GET:/b", None).await,
+            CompleteOutcome::Completed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_persisted_queue_survives_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "phantom-frame-render-queue-{:x}.json",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let queue = RenderQueue::new(10, Some(path.clone()));
+        queue.enqueue("GET:/a", "/a").await;
+        queue.enqueue("GET:/b", "/b?x=1").await;
+        queue.complete("GET:/a", None).await;
+        // The burst is written once, in the background.
+        assert!(!path.exists());
+        tokio::time::sleep(PERSIST_DEBOUNCE * 2).await;
+        assert!(path.exists());
+
+        let restored = RenderQueue::new(10, Some(path.clone()));
+        let claimed = restored.claim(10, DEFAULT_LEASE);
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].path, "/b?x=1");
+
+        // `flush` writes without waiting.
+        queue.enqueue("GET:/c", "/c").await;
+        queue.flush().await;
+        assert_eq!(RenderQueue::new(10, Some(path.clone())).len(), 2);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
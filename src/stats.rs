@@ -0,0 +1,785 @@
+//! Request outcome counters and backend latency histograms for a single proxy.
+//!
+//! Alongside the lifetime totals, outcomes are recorded into a ring of
+//! per-minute buckets so recent hit rates can be reported after long uptimes.
+//! A bucket is reused once its minute falls out of the window; the first
+//! writer to notice the stale minute resets it.
+//!
+//! Backend fetches are timed in two parts: time to first byte (until the
+//! response headers arrive) and body time (from the headers until the body is
+//! fully read). A slow backend and a large or slowly streamed body look alike
+//! in the total but not in the split. Without the `metrics` feature only the
+//! count and sum of each are kept, enough for the means `/stats` reports,
+//! and the Prometheus rendering is left out.
+
+#[cfg(feature = "metrics")]
+use crate::cache::CacheStats;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const BUCKET: Duration = Duration::from_secs(60);
+
+/// What happened to a proxied request with respect to the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Served from the main or 404 cache.
+    Hit,
+    /// Cacheable, but not cached yet; fetched from the backend.
+    Miss,
+    /// Not cacheable (filtered path or cache strategy); proxied directly.
+    Bypass,
+    /// A backend response was written to the cache.
+    Store,
+}
+
+impl Outcome {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A `cache_error_policy` or `backend_error_policy` decision taking effect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyActivation {
+    /// The cache failed and the request carried on without it.
+    CacheErrorBypass,
+    /// The cache failed and the request was answered with `503`.
+    CacheErrorFail,
+    /// The backend failed and a stale cached entry was served.
+    BackendErrorStale,
+    /// The backend failed and the failure was passed on.
+    BackendErrorFail,
+}
+
+/// Lifetime [`PolicyActivation`] counts, as reported by `/stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PolicyCounts {
+    pub cache_error_bypass: u64,
+    pub cache_error_fail: u64,
+    pub backend_error_stale: u64,
+    pub backend_error_fail: u64,
+}
+
+/// Outcome counts over some period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct OutcomeCounts {
+    pub hits: u64,
+    pub misses: u64,
+    pub bypasses: u64,
+    pub stores: u64,
+    /// `hits / (hits + misses)`, or `None` when no cacheable requests were seen.
+    pub hit_rate: Option<f64>,
+}
+
+impl OutcomeCounts {
+    fn from_raw(raw: [u64; 4]) -> Self {
+        let [hits, misses, bypasses, stores] = raw;
+        let lookups = hits + misses;
+        Self {
+            hits,
+            misses,
+            bypasses,
+            stores,
+            hit_rate: (lookups > 0).then(|| hits as f64 / lookups as f64),
+        }
+    }
+}
+
+struct Bucket {
+    /// Minute index (since `ProxyStats::started`) this bucket currently holds.
+    minute: AtomicU64,
+    counts: [AtomicU64; 4],
+}
+
+/// Upper bounds of the latency histogram buckets, in milliseconds. A final
+/// `+Inf` bucket is implied.
+pub const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Slots of a [`LatencyHistogram`]: one per bound and `+Inf`, or just
+/// `+Inf` without the `metrics` feature.
+const LATENCY_SLOTS: usize = if cfg!(feature = "metrics") {
+    LATENCY_BUCKETS_MS.len() + 1
+} else {
+    1
+};
+
+/// Lifetime latency histogram with fixed buckets.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    /// Per-bucket (not cumulative) counts; the last slot is `+Inf`.
+    counts: [AtomicU64; LATENCY_SLOTS],
+    sum_micros: AtomicU64,
+}
+
+/// A point-in-time copy of a [`LatencyHistogram`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencySnapshot {
+    /// Cumulative counts for each bound in [`LATENCY_BUCKETS_MS`], then `+Inf`.
+    /// Without the `metrics` feature, only the `+Inf` count.
+    pub cumulative: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let index = if cfg!(feature = "metrics") {
+            let millis = elapsed.as_millis();
+            LATENCY_BUCKETS_MS
+                .iter()
+                .position(|&bound| millis <= u128::from(bound))
+                .unwrap_or(LATENCY_BUCKETS_MS.len())
+        } else {
+            0
+        };
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let cumulative: Vec<u64> = self
+            .counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect();
+        LatencySnapshot {
+            count: *cumulative.last().unwrap(),
+            cumulative,
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Count and mean of a latency histogram, as reported by `/stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    /// `None` until something has been recorded.
+    pub mean_ms: Option<f64>,
+}
+
+impl From<&LatencySnapshot> for LatencySummary {
+    fn from(snapshot: &LatencySnapshot) -> Self {
+        Self {
+            count: snapshot.count,
+            mean_ms: (snapshot.count > 0)
+                .then(|| snapshot.sum.as_secs_f64() * 1000.0 / snapshot.count as f64),
+        }
+    }
+}
+
+/// Keeps a tunnel counted in [`ProxyStats::active_tunnels`] while alive.
+pub struct TunnelGuard(Arc<ProxyStats>);
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.0.active_tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Lifetime and sliding-window outcome counters, plus backend latencies.
+pub struct ProxyStats {
+    started: Instant,
+    totals: [AtomicU64; 4],
+    buckets: Box<[Bucket]>,
+    backend_ttfb: LatencyHistogram,
+    backend_body: LatencyHistogram,
+    upstream_auth_failures: AtomicU64,
+    client_disconnects: AtomicU64,
+    coalesced_misses: AtomicU64,
+    active_tunnels: AtomicU64,
+    policy_activations: [AtomicU64; 4],
+    /// Error responses the proxy produced itself, by status code.
+    error_responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl ProxyStats {
+    /// Keep per-minute history for `window_minutes` (at least one minute).
+    pub fn new(window_minutes: usize) -> Self {
+        let buckets = (0..window_minutes.max(1))
+            .map(|_| Bucket {
+                minute: AtomicU64::new(u64::MAX),
+                counts: Default::default(),
+            })
+            .collect();
+        Self {
+            started: Instant::now(),
+            totals: Default::default(),
+            buckets,
+            backend_ttfb: LatencyHistogram::default(),
+            backend_body: LatencyHistogram::default(),
+            upstream_auth_failures: AtomicU64::new(0),
+            client_disconnects: AtomicU64::new(0),
+            coalesced_misses: AtomicU64::new(0),
+            active_tunnels: AtomicU64::new(0),
+            policy_activations: Default::default(),
+            error_responses: Mutex::default(),
+        }
+    }
+
+    /// Time since the proxy was created.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Record how long the backend took to send response headers.
+    pub fn record_backend_ttfb(&self, elapsed: Duration) {
+        self.backend_ttfb.record(elapsed);
+    }
+
+    /// Record how long reading the backend body took after the headers.
+    pub fn record_backend_body(&self, elapsed: Duration) {
+        self.backend_body.record(elapsed);
+    }
+
+    /// Time to first byte of backend responses.
+    pub fn backend_ttfb(&self) -> LatencySnapshot {
+        self.backend_ttfb.snapshot()
+    }
+
+    /// Time spent reading backend bodies after their headers arrived.
+    pub fn backend_body(&self) -> LatencySnapshot {
+        self.backend_body.snapshot()
+    }
+
+    /// Count a backend request abandoned because `upstream_auth` failed.
+    pub fn record_upstream_auth_failure(&self) {
+        self.upstream_auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upstream_auth_failures(&self) -> u64 {
+        self.upstream_auth_failures.load(Ordering::Relaxed)
+    }
+
+    /// Count a client that went away while its backend fetch was running.
+    pub fn record_client_disconnect(&self) {
+        self.client_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnects(&self) -> u64 {
+        self.client_disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Count a miss answered from the entry another request's fetch stored.
+    pub fn record_coalesced_miss(&self) {
+        self.coalesced_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn coalesced_misses(&self) -> u64 {
+        self.coalesced_misses.load(Ordering::Relaxed)
+    }
+
+    /// Count an upgrade tunnel until the returned guard is dropped.
+    pub fn track_tunnel(self: &Arc<Self>) -> TunnelGuard {
+        self.active_tunnels.fetch_add(1, Ordering::Relaxed);
+        TunnelGuard(self.clone())
+    }
+
+    /// Upgrade tunnels currently open.
+    pub fn active_tunnels(&self) -> u64 {
+        self.active_tunnels.load(Ordering::Relaxed)
+    }
+
+    /// Count a request the proxy answered with an error of its own, such as
+    /// `502` for an unreachable backend. Error responses passed on from the
+    /// backend are not counted.
+    pub fn record_error_response(&self, status: u16) {
+        *self
+            .error_responses
+            .lock()
+            .unwrap()
+            .entry(status)
+            .or_default() += 1;
+    }
+
+    /// Error responses the proxy produced itself since startup, by status.
+    pub fn error_responses(&self) -> BTreeMap<u16, u64> {
+        self.error_responses.lock().unwrap().clone()
+    }
+
+    pub fn record_policy(&self, activation: PolicyActivation) {
+        self.policy_activations[activation as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn policy_activations(&self) -> PolicyCounts {
+        let [cache_error_bypass, cache_error_fail, backend_error_stale, backend_error_fail] = self
+            .policy_activations
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed));
+        PolicyCounts {
+            cache_error_bypass,
+            cache_error_fail,
+            backend_error_stale,
+            backend_error_fail,
+        }
+    }
+
+    /// Count one request outcome.
+    pub fn record(&self, outcome: Outcome) {
+        let index = outcome.index();
+        self.totals[index].fetch_add(1, Ordering::Relaxed);
+
+        let minute = self.current_minute();
+        let bucket = &self.buckets[(minute % self.buckets.len() as u64) as usize];
+        let held = bucket.minute.load(Ordering::Acquire);
+        if held != minute
+            && bucket
+                .minute
+                .compare_exchange(held, minute, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            for count in &bucket.counts {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+        bucket.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts since the proxy started.
+    pub fn totals(&self) -> OutcomeCounts {
+        OutcomeCounts::from_raw(self.totals.each_ref().map(|c| c.load(Ordering::Relaxed)))
+    }
+
+    /// Counts for the current minute and the `minutes - 1` before it.
+    ///
+    /// Capped at the configured window length.
+    pub fn last(&self, minutes: usize) -> OutcomeCounts {
+        let now = self.current_minute();
+        let span = minutes.clamp(1, self.buckets.len()) as u64;
+        let mut raw = [0u64; 4];
+        for bucket in self.buckets.iter() {
+            let minute = bucket.minute.load(Ordering::Acquire);
+            if minute <= now && now - minute < span {
+                for (sum, count) in raw.iter_mut().zip(&bucket.counts) {
+                    *sum += count.load(Ordering::Relaxed);
+                }
+            }
+        }
+        OutcomeCounts::from_raw(raw)
+    }
+
+    fn current_minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / BUCKET.as_secs()
+    }
+}
+
+/// Trailing windows reported by `/stats` and `/metrics`, as (label, minutes).
+pub const REPORTED_WINDOWS: [(&str, usize); 3] = [("1m", 1), ("5m", 5), ("1h", 60)];
+
+/// One server's input to [`render_prometheus`].
+#[cfg(feature = "metrics")]
+pub struct ServerMetrics<'a> {
+    pub name: &'a str,
+    pub stats: &'a ProxyStats,
+    pub cache: CacheStats,
+}
+
+/// Render the counters of every server in the Prometheus text format.
+#[cfg(feature = "metrics")]
+pub fn render_prometheus(servers: &[ServerMetrics]) -> String {
+    use std::fmt::Write;
+
+    const OUTCOMES: [(&str, Outcome); 4] = [
+        ("hit", Outcome::Hit),
+        ("miss", Outcome::Miss),
+        ("bypass", Outcome::Bypass),
+        ("store", Outcome::Store),
+    ];
+    let mut out = String::new();
+
+    out.push_str("# HELP phantom_frame_requests_total Requests by cache outcome since startup.\n");
+    out.push_str("# TYPE phantom_frame_requests_total counter\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        for (label, outcome) in OUTCOMES {
+            let value = stats.totals[outcome.index()].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "phantom_frame_requests_total{{server=\"{}\",outcome=\"{}\"}} {}",
+                escape_label(server),
+                label,
+                value
+            );
+        }
+    }
+
+    out.push_str(
+        "# HELP phantom_frame_window_requests Requests by cache outcome over a trailing window.\n",
+    );
+    out.push_str("# TYPE phantom_frame_window_requests gauge\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        for (window, minutes) in REPORTED_WINDOWS {
+            let counts = stats.last(minutes);
+            for (label, value) in [
+                ("hit", counts.hits),
+                ("miss", counts.misses),
+                ("bypass", counts.bypasses),
+                ("store", counts.stores),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "phantom_frame_window_requests{{server=\"{}\",window=\"{}\",outcome=\"{}\"}} {}",
+                    escape_label(server),
+                    window,
+                    label,
+                    value
+                );
+            }
+        }
+    }
+
+    out.push_str("# HELP phantom_frame_hit_ratio Cache hit ratio over a trailing window.\n");
+    out.push_str("# TYPE phantom_frame_hit_ratio gauge\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        for (window, minutes) in REPORTED_WINDOWS {
+            if let Some(rate) = stats.last(minutes).hit_rate {
+                let _ = writeln!(
+                    out,
+                    "phantom_frame_hit_ratio{{server=\"{}\",window=\"{}\"}} {}",
+                    escape_label(server),
+                    window,
+                    rate
+                );
+            }
+        }
+    }
+
+    out.push_str(
+        "# HELP phantom_frame_policy_activations_total Cache and backend error policy decisions since startup.\n",
+    );
+    out.push_str("# TYPE phantom_frame_policy_activations_total counter\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        let counts = stats.policy_activations();
+        for (label, value) in [
+            ("cache_error_bypass", counts.cache_error_bypass),
+            ("cache_error_fail", counts.cache_error_fail),
+            ("backend_error_stale", counts.backend_error_stale),
+            ("backend_error_fail", counts.backend_error_fail),
+        ] {
+            let _ = writeln!(
+                out,
+                "phantom_frame_policy_activations_total{{server=\"{}\",policy=\"{}\"}} {}",
+                escape_label(server),
+                label,
+                value
+            );
+        }
+    }
+
+    out.push_str(
+        "# HELP phantom_frame_error_responses_total Requests the proxy answered with an error of its own, such as 502 for an unreachable backend.\n",
+    );
+    out.push_str("# TYPE phantom_frame_error_responses_total counter\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        for (status, count) in stats.error_responses() {
+            let _ = writeln!(
+                out,
+                "phantom_frame_error_responses_total{{server=\"{}\",status=\"{}\"}} {}",
+                escape_label(server),
+                status,
+                count
+            );
+        }
+    }
+
+    out.push_str("# HELP phantom_frame_active_tunnels Upgrade tunnels currently open.\n");
+    out.push_str("# TYPE phantom_frame_active_tunnels gauge\n");
+    for ServerMetrics {
+        name: server,
+        stats,
+        ..
+    } in servers
+    {
+        let _ = writeln!(
+            out,
+            "phantom_frame_active_tunnels{{server=\"{}\"}} {}",
+            escape_label(server),
+            stats.active_tunnels()
+        );
+    }
+
+    for (name, help, read) in [
+        (
+            "phantom_frame_cache_entries",
+            "Entries in each cache store.",
+            (|cache| [cache.entries, cache.not_found_entries, cache.error_entries])
+                as fn(&CacheStats) -> [usize; 3],
+        ),
+        (
+            "phantom_frame_cache_bytes",
+            "Approximate bytes held by each cache store.",
+            |cache| [cache.bytes, cache.not_found_bytes, cache.error_bytes],
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for ServerMetrics {
+            name: server,
+            cache,
+            ..
+        } in servers
+        {
+            for (store, value) in ["main", "404", "error"].into_iter().zip(read(cache)) {
+                let _ = writeln!(
+                    out,
+                    "{}{{server=\"{}\",store=\"{}\"}} {}",
+                    name,
+                    escape_label(server),
+                    store,
+                    value
+                );
+            }
+        }
+    }
+
+    out.push_str(
+        "# HELP phantom_frame_cache_budget_evictions_total Main-cache entries evicted to stay within cache_max_bytes.\n",
+    );
+    out.push_str("# TYPE phantom_frame_cache_budget_evictions_total counter\n");
+    for ServerMetrics {
+        name: server,
+        cache,
+        ..
+    } in servers
+    {
+        let _ = writeln!(
+            out,
+            "phantom_frame_cache_budget_evictions_total{{server=\"{}\"}} {}",
+            escape_label(server),
+            cache.budget_evictions
+        );
+    }
+
+    out.push_str(
+        "# HELP phantom_frame_cache_capacity_evictions_total 404 and error entries evicted at the store's capacity.\n",
+    );
+    out.push_str("# TYPE phantom_frame_cache_capacity_evictions_total counter\n");
+    for ServerMetrics {
+        name: server,
+        cache,
+        ..
+    } in servers
+    {
+        for (store, value) in [
+            ("404", cache.not_found_evictions),
+            ("error", cache.error_evictions),
+        ] {
+            let _ = writeln!(
+                out,
+                "phantom_frame_cache_capacity_evictions_total{{server=\"{}\",store=\"{}\"}} {}",
+                escape_label(server),
+                store,
+                value
+            );
+        }
+    }
+
+    for (name, help, read) in [
+        (
+            "phantom_frame_backend_ttfb_seconds",
+            "Time until backend response headers arrived.",
+            ProxyStats::backend_ttfb as fn(&ProxyStats) -> LatencySnapshot,
+        ),
+        (
+            "phantom_frame_backend_body_seconds",
+            "Time spent reading backend response bodies after the headers.",
+            ProxyStats::backend_body,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for ServerMetrics {
+            name: server,
+            stats,
+            ..
+        } in servers
+        {
+            let server = escape_label(server);
+            let snapshot = read(stats);
+            let bounds = LATENCY_BUCKETS_MS
+                .iter()
+                .map(|ms| (*ms as f64 / 1000.0).to_string())
+                .chain(std::iter::once("+Inf".to_string()));
+            for (bound, count) in bounds.zip(&snapshot.cumulative) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{server=\"{}\",le=\"{}\"}} {}",
+                    name, server, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_sum{{server=\"{}\"}} {}",
+                name,
+                server,
+                snapshot.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{server=\"{}\"}} {}",
+                name, server, snapshot.count
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(feature = "metrics")]
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window_rotation_and_rates() {
+        let stats = ProxyStats::new(5);
+        for _ in 0..3 {
+            stats.record(Outcome::Hit);
+        }
+        stats.record(Outcome::Miss);
+
+        tokio::time::advance(BUCKET * 2).await;
+        stats.record(Outcome::Miss);
+        stats.record(Outcome::Bypass);
+
+        let last_1m = stats.last(1);
+        assert_eq!((last_1m.hits, last_1m.misses, last_1m.bypasses), (0, 1, 1));
+        assert_eq!(last_1m.hit_rate, Some(0.0));
+
+        let last_5m = stats.last(5);
+        assert_eq!((last_5m.hits, last_5m.misses), (3, 2));
+        assert_eq!(last_5m.hit_rate, Some(0.6));
+
+        // Once the first minute leaves the window its bucket is reused.
+        tokio::time::advance(BUCKET * 5).await;
+        stats.record(Outcome::Store);
+        let last_5m = stats.last(5);
+        assert_eq!((last_5m.hits, last_5m.misses, last_5m.stores), (0, 0, 1));
+        assert_eq!(last_5m.hit_rate, None);
+
+        let totals = stats.totals();
+        assert_eq!((totals.hits, totals.misses, totals.stores), (3, 2, 1));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test(start_paused = true)]
+    async fn test_prometheus_output_types_and_labels() {
+        let stats = ProxyStats::new(60);
+        stats.record(Outcome::Hit);
+        stats.record(Outcome::Miss);
+
+        stats.record_error_response(502);
+        stats.record_error_response(502);
+        let stats = Arc::new(stats);
+        let _tunnel = stats.track_tunnel();
+        let cache = CacheStats {
+            entries: 3,
+            bytes: 1200,
+            error_entries: 1,
+            ..CacheStats::default()
+        };
+
+        let text = render_prometheus(&[ServerMetrics {
+            name: "front\"end",
+            stats: &stats,
+            cache,
+        }]);
+        assert!(text.contains("# TYPE phantom_frame_requests_total counter"));
+        assert!(text.contains("# TYPE phantom_frame_hit_ratio gauge"));
+        assert!(text
+            .contains("phantom_frame_requests_total{server=\"front\\\"end\",outcome=\"hit\"} 1"));
+        assert!(text.contains("phantom_frame_hit_ratio{server=\"front\\\"end\",window=\"5m\"} 0.5"));
+        assert!(text.contains(
+            "phantom_frame_error_responses_total{server=\"front\\\"end\",status=\"502\"} 2"
+        ));
+        assert!(text.contains("phantom_frame_active_tunnels{server=\"front\\\"end\"} 1"));
+        assert!(
+            text.contains("phantom_frame_cache_entries{server=\"front\\\"end\",store=\"main\"} 3")
+        );
+        assert!(
+            text.contains("phantom_frame_cache_entries{server=\"front\\\"end\",store=\"error\"} 1")
+        );
+        assert!(
+            text.contains("phantom_frame_cache_bytes{server=\"front\\\"end\",store=\"main\"} 1200")
+        );
+    }
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        let stats = ProxyStats::new(1);
+        stats.record_backend_ttfb(Duration::from_millis(3));
+        stats.record_backend_ttfb(Duration::from_millis(40));
+        stats.record_backend_ttfb(Duration::from_secs(30));
+
+        let snapshot = stats.backend_ttfb();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.sum, Duration::from_millis(30_043));
+        assert_eq!(stats.backend_body().count, 0);
+
+        #[cfg(not(feature = "metrics"))]
+        assert_eq!(snapshot.cumulative, [3]);
+        #[cfg(feature = "metrics")]
+        {
+            // <= 5ms, <= 10ms, <= 25ms, <= 50ms, ... , +Inf
+            assert_eq!(&snapshot.cumulative[..4], &[1, 1, 1, 2]);
+            assert_eq!(snapshot.cumulative[LATENCY_BUCKETS_MS.len() - 1], 2);
+            assert_eq!(snapshot.cumulative[LATENCY_BUCKETS_MS.len()], 3);
+
+            let text = render_prometheus(&[ServerMetrics {
+                name: "web",
+                stats: &stats,
+                cache: CacheStats::default(),
+            }]);
+            assert!(text.contains("# TYPE phantom_frame_backend_ttfb_seconds histogram"));
+            assert!(text.contains(
+                "phantom_frame_backend_ttfb_seconds_bucket{server=\"web\",le=\"0.05\"} 2"
+            ));
+            assert!(text.contains(
+                "phantom_frame_backend_ttfb_seconds_bucket{server=\"web\",le=\"+Inf\"} 3"
+            ));
+            assert!(text.contains("phantom_frame_backend_ttfb_seconds_count{server=\"web\"} 3"));
+            assert!(text.contains("phantom_frame_backend_body_seconds_count{server=\"web\"} 0"));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! The HTML page served by `GET /status` on the control server.
+//!
+//! It shows the same counters as `GET /stats` for operators without a
+//! metrics stack: one section per server with its backend, cache sizes, hit
+//! rates, most-hit keys, recent purges, and open tunnels. The page has no
+//! external assets and reloads itself with a meta refresh. Keys and purge
+//! patterns are redacted like log lines.
+
+use crate::cache::Purge;
+use crate::stats::{LatencySummary, OutcomeCounts};
+use crate::ProxyHandle;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+const TEMPLATE: &str = include_str!("status_page.html");
+
+/// Seconds between automatic reloads.
+pub const REFRESH_SECS: u64 = 5;
+
+/// Keys listed under "Top keys".
+pub const TOP_KEYS: usize = 20;
+
+/// Everything the page shows for one server.
+#[derive(Clone, Debug, Default)]
+pub struct ServerStatus {
+    pub name: String,
+    pub backend: String,
+    pub backend_generation: u64,
+    pub uptime: Duration,
+    pub backend_ttfb: LatencySummary,
+    /// Cacheable requests the backend failed, served stale or not.
+    pub backend_failures: u64,
+    pub upstream_auth_failures: u64,
+    pub cache_entries: usize,
+    pub cache_bytes: usize,
+    pub cache_404_entries: usize,
+    pub cache_error_entries: usize,
+    pub last_5m: OutcomeCounts,
+    pub last_1h: OutcomeCounts,
+    pub top_keys: Vec<(String, u64)>,
+    pub recent_purges: Vec<Purge>,
+    pub active_tunnels: u64,
+}
+
+impl ServerStatus {
+    /// Read the current counters of `proxy`.
+    pub async fn collect(name: &str, proxy: &ProxyHandle) -> Self {
+        let backend = proxy.backend();
+        let stats = proxy.stats();
+        let cache = proxy.cache();
+        let redactor = proxy.redactor();
+        let policies = stats.policy_activations();
+        Self {
+            name: name.to_string(),
+            backend: backend.url().to_string(),
+            backend_generation: backend.generation(),
+            uptime: stats.uptime(),
+            backend_ttfb: LatencySummary::from(&stats.backend_ttfb()),
+            backend_failures: policies.backend_error_stale + policies.backend_error_fail,
+            upstream_auth_failures: stats.upstream_auth_failures(),
+            cache_entries: cache.size().await,
+            cache_bytes: cache.memory_usage().await,
+            cache_404_entries: cache.size_404().await,
+            cache_error_entries: cache.size_error().await,
+            last_5m: stats.last(5),
+            last_1h: stats.last(60),
+            top_keys: cache
+                .top_keys(TOP_KEYS)
+                .into_iter()
+                .map(|(key, hits)| (redactor.target(&key).into_owned(), hits))
+                .collect(),
+            recent_purges: cache
+                .recent_purges()
+                .into_iter()
+                .map(|purge| Purge {
+                    pattern: purge
+                        .pattern
+                        .map(|pattern| redactor.target(&pattern).into_owned()),
+                    ..purge
+                })
+                .collect(),
+            active_tunnels: stats.active_tunnels(),
+        }
+    }
+}
+
+/// Render the page for `servers`.
+pub fn render(servers: &[ServerStatus]) -> String {
+    let mut sections = String::new();
+    if servers.is_empty() {
+        sections.push_str("<p class=\"muted\">No servers configured.</p>\n");
+    }
+    let now = SystemTime::now();
+    for server in servers {
+        render_server(&mut sections, server, now);
+    }
+    TEMPLATE
+        .replace("{{refresh_secs}}", &REFRESH_SECS.to_string())
+        .replace("{{servers}}", &sections)
+}
+
+fn render_server(out: &mut String, s: &ServerStatus, now: SystemTime) {
+    let _ = writeln!(out, "<h2>{}</h2>", escape(&s.name));
+
+    out.push_str("<table>\n");
+    row(out, "Uptime", &format_duration(s.uptime));
+    row(
+        out,
+        "Backend",
+        &format!(
+            "<code>{}</code> (generation {})",
+            escape(&s.backend),
+            s.backend_generation
+        ),
+    );
+    let ttfb = match s.backend_ttfb.mean_ms {
+        Some(mean) => format!(
+            "{:.1} ms mean over {} responses",
+            mean, s.backend_ttfb.count
+        ),
+        None => "no responses yet".to_string(),
+    };
+    row(out, "Backend time to first byte", &ttfb);
+    row(
+        out,
+        "Failed backend fetches",
+        &s.backend_failures.to_string(),
+    );
+    row(
+        out,
+        "Upstream auth failures",
+        &s.upstream_auth_failures.to_string(),
+    );
+    row(
+        out,
+        "Cache entries",
+        &format!(
+            "{} ({}), {} not found, {} errors",
+            s.cache_entries,
+            format_bytes(s.cache_bytes),
+            s.cache_404_entries,
+            s.cache_error_entries
+        ),
+    );
+    row(out, "Active tunnels", &s.active_tunnels.to_string());
+    out.push_str("</table>\n");
+
+    out.push_str("<h3>Hit rate</h3>\n<table>\n");
+    for (label, counts) in [("Last 5 minutes", &s.last_5m), ("Last hour", &s.last_1h)] {
+        let gauge = match counts.hit_rate {
+            Some(rate) => format!(
+                "<meter min=\"0\" max=\"1\" value=\"{rate:.3}\"></meter> {:.1}%",
+                rate * 100.0
+            ),
+            None => "<span class=\"muted\">no cacheable requests</span>".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "<tr><th>{label}</th><td>{gauge}</td><td class=\"muted\">{} hits, {} misses, {} bypasses</td></tr>",
+            counts.hits, counts.misses, counts.bypasses
+        );
+    }
+    out.push_str("</table>\n");
+
+    out.push_str("<h3>Top keys</h3>\n");
+    if s.top_keys.is_empty() {
+        out.push_str("<p class=\"muted\">No cache hits yet.</p>\n");
+    } else {
+        out.push_str("<table>\n<tr><th>Key</th><th>Hits</th></tr>\n");
+        for (key, hits) in &s.top_keys {
+            let _ = writeln!(
+                out,
+                "<tr><td><code>{}</code></td><td class=\"num\">{}</td></tr>",
+                escape(key),
+                hits
+            );
+        }
+        out.push_str("</table>\n");
+    }
+
+    out.push_str("<h3>Recent purges</h3>\n");
+    if s.recent_purges.is_empty() {
+        out.push_str("<p class=\"muted\">None since startup.</p>\n");
+    } else {
+        out.push_str("<table>\n<tr><th>When</th><th>Pattern</th><th>Removed</th></tr>\n");
+        for purge in &s.recent_purges {
+            let age = now.duration_since(purge.at).unwrap_or_default();
+            let pattern = match &purge.pattern {
+                Some(pattern) => format!("<code>{}</code>", escape(pattern)),
+                None => "everything".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "<tr><td>{} ago</td><td>{}</td><td class=\"num\">{}</td></tr>",
+                format_duration(age),
+                pattern,
+                purge.removed
+            );
+        }
+        out.push_str("</table>\n");
+    }
+}
+
+fn row(out: &mut String, label: &str, value: &str) {
+    let _ = writeln!(out, "<tr><th>{label}</th><td>{value}</td></tr>");
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `3d 4h`, `2h 5m`, `7m 12s`, or `9s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) =
+        (secs / 86_400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_empty_state() {
+        let page = render(&[]);
+        assert!(page.contains("No servers configured."));
+
+        let page = render(&[ServerStatus::default()]);
+        assert!(page.contains("no responses yet"));
+        assert!(page.contains("no cacheable requests"));
+        assert!(page.contains("No cache hits yet."));
+        assert!(page.contains("None since startup."));
+        assert!(!page.contains("{{"));
+    }
+
+    #[test]
+    fn test_render_seeded_stats() {
+        let server = ServerStatus {
+            name: "web<1>".to_string(),
+            backend: "http://localhost:5173".to_string(),
+            backend_generation: 2,
+            uptime: Duration::from_secs(2 * 3600 + 5 * 60),
+            backend_ttfb: LatencySummary {
+                count: 4,
+                mean_ms: Some(12.5),
+            },
+            cache_entries: 3,
+            cache_bytes: 3 * 1024 * 1024 / 2,
+            last_5m: OutcomeCounts {
+                hits: 3,
+                misses: 1,
+                hit_rate: Some(0.75),
+                ..Default::default()
+            },
+            top_keys: vec![("GET:/a?x=<b>".to_string(), 7)],
+            recent_purges: vec![Purge {
+                at: SystemTime::now() - Duration::from_secs(90),
+                pattern: Some("GET:/blog/*".to_string()),
+                removed: 4,
+            }],
+            active_tunnels: 1,
+            ..Default::default()
+        };
+        let page = render(&[server]);
+
+        assert!(page.contains("<meta http-equiv=\"refresh\" content=\"5\">"));
+        assert!(page.contains("<h2>web&lt;1&gt;</h2>"));
+        assert!(page.contains("2h 5m"));
+        assert!(page.contains("12.5 ms mean over 4 responses"));
+        assert!(page.contains("3 (1.5 MiB)"));
+        assert!(page.contains("value=\"0.750\"></meter> 75.0%"));
+        assert!(page.contains("<code>GET:/a?x=&lt;b&gt;</code></td><td class=\"num\">7<"));
+        assert!(page.contains("1m 30s ago"));
+        assert!(page.contains("<code>GET:/blog/*</code>"));
+    }
+}
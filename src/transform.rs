@@ -0,0 +1,183 @@
+//! User hooks that rewrite responses, e.g. to inject a `<script>` tag.
+//!
+//! `transform_response` runs once on a response about to be stored, so the
+//! cached entry carries its changes. `transform_on_serve` runs on every
+//! response as it leaves the proxy, hit or miss, for per-request tweaks.
+//! Both see the decoded body, without `content-encoding` or
+//! `content-length`; the proxy compresses the result again and recomputes its
+//! length. Only responses whose `Content-Type` starts with one of
+//! `transform_content_types` (default `text/html`) are passed to them, so
+//! images and other binaries are never handed over.
+
+use crate::cache::CachedResponse;
+use crate::compression::{compress_body_async, decode_upstream_body_async, ContentEncoding};
+use crate::RequestInfo;
+use axum::{
+    body::Body,
+    http::{HeaderName, HeaderValue, Response, StatusCode},
+};
+
+/// Content types the hooks run for by default.
+pub const DEFAULT_CONTENT_TYPES: &[&str] = &["text/html"];
+
+/// [`DEFAULT_CONTENT_TYPES`] as owned strings, for config defaults.
+pub fn default_content_types() -> Vec<String> {
+    DEFAULT_CONTENT_TYPES
+        .iter()
+        .map(|content_type| content_type.to_string())
+        .collect()
+}
+
+/// Whether `content_type` starts with one of `types`, ignoring case.
+pub fn content_type_matches(types: &[String], content_type: Option<&str>) -> bool {
+    let Some(content_type) = content_type.map(str::trim_start) else {
+        return false;
+    };
+    types.iter().any(|prefix| {
+        content_type
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    })
+}
+
+/// Run `hook` on a decoded response, or return `None` if it panicked.
+pub(crate) fn call_hook(
+    hook: &(dyn Fn(&RequestInfo, CachedResponse) -> CachedResponse + Send + Sync),
+    req_info: &RequestInfo,
+    response: CachedResponse,
+) -> Option<CachedResponse> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| hook(req_info, response))).ok()
+}
+
+/// Apply a `transform_on_serve` hook to an outgoing response. Responses of
+/// other content types, without a body, or in an encoding the proxy cannot
+/// decode are returned as they are; so is the original response when `hook`
+/// panics, which `on_panic` is told about.
+pub(crate) async fn on_serve(
+    response: Response<Body>,
+    types: &[String],
+    hook: &(dyn Fn(&RequestInfo, CachedResponse) -> CachedResponse + Send + Sync),
+    req_info: &RequestInfo<'_>,
+    on_panic: impl FnOnce(),
+) -> Response<Body> {
+    let content_type = response
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let bodiless = req_info.method == "HEAD"
+        || matches!(
+            response.status(),
+            StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        );
+    // A part of a body cannot be transformed on its own.
+    let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+    if bodiless || partial || !content_type_matches(types, content_type) {
+        return response;
+    }
+    let content_encoding = response
+        .headers()
+        .get(axum::http::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let encoding = match content_encoding.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(value) if value.eq_ignore_ascii_case("identity") => None,
+        Some(value) => match ContentEncoding::from_header_value(value) {
+            Some(encoding) => Some(encoding),
+            None => return response,
+        },
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(error) => {
+            tracing::warn!("Failed to read response body to transform: {}", error);
+            parts.status = StatusCode::BAD_GATEWAY;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let decoded = match decode_upstream_body_async(body.to_vec(), content_encoding).await {
+        Ok(decoded) => decoded,
+        Err(error) => {
+            tracing::warn!("Not transforming a response it cannot decode: {}", error);
+            return Response::from_parts(parts, Body::from(body));
+        }
+    };
+    let headers = parts
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            *name != axum::http::header::CONTENT_ENCODING
+                && *name != axum::http::header::CONTENT_LENGTH
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let decoded = CachedResponse {
+        body: decoded.into(),
+        headers,
+        status: parts.status.as_u16(),
+        content_encoding: None,
+        origin: None,
+        content_digest: None,
+        stored_at: None,
+    };
+    let Some(transformed) = call_hook(hook, req_info, decoded) else {
+        on_panic();
+        return Response::from_parts(parts, Body::from(body));
+    };
+
+    let body = match encoding {
+        Some(encoding) => match compress_body_async(transformed.body.to_vec(), encoding).await {
+            Ok(compressed) => compressed.into(),
+            Err(error) => {
+                tracing::warn!("Failed to compress a transformed response: {}", error);
+                return Response::from_parts(parts, Body::from(body));
+            }
+        },
+        None => transformed.body,
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::from_u16(transformed.status).unwrap_or(parts.status);
+    *response.extensions_mut() = parts.extensions;
+    let headers = response.headers_mut();
+    for (name, value) in &transformed.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    if let Some(encoding) = encoding {
+        headers.insert(
+            axum::http::header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_header_value()),
+        );
+    }
+    headers.insert(axum::http::header::CONTENT_LENGTH, body.len().into());
+    *response.body_mut() = Body::from(body);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type_matches() {
+        let types = default_content_types();
+        assert!(content_type_matches(&types, Some("text/html")));
+        assert!(content_type_matches(
+            &types,
+            Some("Text/HTML; charset=utf-8")
+        ));
+        assert!(!content_type_matches(&types, Some("text/plain")));
+        assert!(!content_type_matches(&types, Some("image/png")));
+        assert!(!content_type_matches(&types, None));
+        assert!(content_type_matches(
+            &["application/".to_string()],
+            Some("application/json")
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+//! Credentials attached to every request sent to the backend.
+//!
+//! An [`UpstreamAuth`] provider is asked for a header value before each
+//! backend request — cache misses, bypasses, snapshot fetches, and upgrade
+//! handshakes — and the value replaces any header of the same name sent by
+//! the client. [`ClientCredentials`] implements the OAuth2 client-credentials
+//! grant and reuses its token until shortly before it expires.
+//!
+//! Values are marked sensitive and never logged. They are only added to the
+//! outgoing request, so they cannot end up in cached entries or cassettes.
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::{header, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Future returned by [`UpstreamAuth::header`].
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<HeaderValue>> + Send + 'a>>;
+
+/// Supplies the credential header for backend requests.
+pub trait UpstreamAuth: Send + Sync {
+    /// The header value to send, e.g. `Bearer <token>`. Called once per
+    /// backend request, so implementations should cache what they fetch.
+    fn header(&self) -> AuthFuture<'_>;
+
+    /// The header the value is sent in (default: `Authorization`).
+    fn header_name(&self) -> HeaderName {
+        header::AUTHORIZATION
+    }
+}
+
+/// `[server.NAME.upstream_auth]` configuration.
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamAuthConfig {
+    /// OAuth2 client-credentials grant (RFC 6749 §4.4).
+    ClientCredentials {
+        token_url: String,
+        client_id: String,
+        client_secret: String,
+        #[serde(default)]
+        scope: Option<String>,
+        /// Fetch a new token this long before the current one expires
+        /// (default: 60).
+        #[serde(default = "default_refresh_before_expiry_secs")]
+        refresh_before_expiry_secs: u64,
+    },
+}
+
+fn default_refresh_before_expiry_secs() -> u64 {
+    60
+}
+
+impl std::fmt::Debug for UpstreamAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientCredentials {
+                token_url,
+                client_id,
+                scope,
+                ..
+            } => f
+                .debug_struct("ClientCredentials")
+                .field("token_url", token_url)
+                .field("client_id", client_id)
+                .field("scope", scope)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+impl UpstreamAuthConfig {
+    /// Build the provider this configuration describes.
+    pub fn build(&self) -> Result<ClientCredentials> {
+        match self {
+            Self::ClientCredentials {
+                token_url,
+                client_id,
+                client_secret,
+                scope,
+                refresh_before_expiry_secs,
+            } => Ok(ClientCredentials::new(token_url, client_id, client_secret)?
+                .with_scope(scope.clone())
+                .with_refresh_before_expiry(Duration::from_secs(*refresh_before_expiry_secs))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    token_type: Option<String>,
+    /// Seconds; tokens without it are reused until a request fails.
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+struct CachedToken {
+    value: HeaderValue,
+    refresh_at: Option<Instant>,
+}
+
+/// OAuth2 client-credentials provider. Sends `Authorization: Bearer <token>`.
+pub struct ClientCredentials {
+    client: reqwest::Client,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    refresh_before_expiry: Duration,
+    /// Held across a refresh so concurrent requests wait for one fetch.
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl std::fmt::Debug for ClientCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCredentials")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientCredentials {
+    pub fn new(
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("failed to build token endpoint HTTP client")?;
+        Ok(Self {
+            client,
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scope: None,
+            refresh_before_expiry: Duration::from_secs(default_refresh_before_expiry_secs()),
+            token: Mutex::new(None),
+        })
+    }
+
+    pub fn with_scope(mut self, scope: Option<String>) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn with_refresh_before_expiry(mut self, margin: Duration) -> Self {
+        self.refresh_before_expiry = margin;
+        self
+    }
+
+    async fn fetch_token(&self) -> Result<CachedToken> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let response = self
+            .client
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| anyhow!("token request failed: {}", e.without_url()))?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("token endpoint answered {}", status);
+        }
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| anyhow!("invalid token response: {}", e.without_url()))?;
+        if let Some(token_type) = &token.token_type {
+            if !token_type.eq_ignore_ascii_case("bearer") {
+                bail!("unsupported token type '{}'", token_type);
+            }
+        }
+
+        let mut value = HeaderValue::try_from(format!("Bearer {}", token.access_token))
+            .context("access token is not a valid header value")?;
+        value.set_sensitive(true);
+        let refresh_at = token.expires_in.map(|secs| {
+            Instant::now() + Duration::from_secs(secs).saturating_sub(self.refresh_before_expiry)
+        });
+        tracing::debug!(
+            expires_in = token.expires_in,
+            "fetched upstream access token from '{}'",
+            self.token_url
+        );
+        Ok(CachedToken { value, refresh_at })
+    }
+}
+
+impl UpstreamAuth for ClientCredentials {
+    fn header(&self) -> AuthFuture<'_> {
+        Box::pin(async move {
+            let mut token = self.token.lock().await;
+            let fresh = token.as_ref().is_some_and(|token| {
+                token
+                    .refresh_at
+                    .is_none_or(|refresh_at| Instant::now() < refresh_at)
+            });
+            if !fresh {
+                *token = Some(self.fetch_token().await?);
+            }
+            Ok(token.as_ref().unwrap().value.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::spawn_backend;
+    use axum::{routing::post, Form, Json, Router};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Token endpoint issuing `token-0`, `token-1`, ... valid for 120 s.
+    async fn token_endpoint(issued: Arc<AtomicUsize>) -> String {
+        let url = spawn_backend(Router::new().route(
+            "/token",
+            post(move |Form(form): Form<HashMap<String, String>>| {
+                let issued = issued.clone();
+                async move {
+                    assert_eq!(form["grant_type"], "client_credentials");
+                    assert_eq!(form["client_secret"], "shh");
+                    let n = issued.fetch_add(1, Ordering::SeqCst);
+                    Json(serde_json::json!({
+                        "access_token": format!("token-{n}"),
+                        "token_type": "Bearer",
+                        "expires_in": 120,
+                    }))
+                }
+            }),
+        ))
+        .await;
+        format!("{url}/token")
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials_reuses_token_until_near_expiry() {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let auth = ClientCredentials::new(token_endpoint(issued.clone()).await, "id", "shh")
+            .unwrap()
+            .with_refresh_before_expiry(Duration::from_secs(30));
+
+        assert_eq!(auth.header().await.unwrap(), "Bearer token-0");
+        assert_eq!(auth.header().await.unwrap(), "Bearer token-0");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        // Pretend 90 s have passed: within the refresh margin of expiry.
+        auth.token.lock().await.as_mut().unwrap().refresh_at = Some(Instant::now());
+        let value = auth.header().await.unwrap();
+        assert_eq!(value, "Bearer token-1");
+        assert!(value.is_sensitive());
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint_errors_are_reported() {
+        let url = spawn_backend(Router::new().route(
+            "/token",
+            post(|| async { axum::http::StatusCode::UNAUTHORIZED }),
+        ))
+        .await;
+        let auth = ClientCredentials::new(format!("{url}/token"), "id", "wrong").unwrap();
+
+        let error = auth.header().await.unwrap_err();
+        assert!(error.to_string().contains("401"), "{}", error);
+        assert!(!format!("{:?}", auth).contains("wrong"));
+    }
+}
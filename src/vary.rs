@@ -0,0 +1,117 @@
+//! Backend `Vary` headers (RFC 9110 §12.5.5) and the cache keys they select.
+//!
+//! A response that varies on request headers is stored once per combination
+//! of their values, under its cache key followed by [`KEY_MARKER`] and a
+//! digest of those values. `Accept-Encoding` is left out: the proxy stores
+//! bodies decoded and negotiates the encoding with each client itself.
+
+use axum::http::HeaderMap;
+
+/// Separates a cache key from the digest of the request headers it varies on.
+pub const KEY_MARKER: &str = "#vary=";
+
+/// Hex digits of the header digest kept in a variant key.
+const DIGEST_LEN: usize = 16;
+
+/// What a response's `Vary` says about the requests it may answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Vary {
+    /// `*`: something other than request headers decides, so no stored copy
+    /// can be reused.
+    Any,
+    /// Lowercase names of the request headers that select the response,
+    /// sorted and without duplicates. Empty when it does not vary.
+    Headers(Vec<String>),
+}
+
+impl Vary {
+    /// Parse every `Vary` field line of a response.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut names = Vec::new();
+        for name in values.into_iter().flat_map(|value| value.split(',')) {
+            let name = name.trim();
+            if name == "*" {
+                return Self::Any;
+            }
+            if !name.is_empty() && !name.eq_ignore_ascii_case("accept-encoding") {
+                names.push(name.to_ascii_lowercase());
+            }
+        }
+        names.sort();
+        names.dedup();
+        Self::Headers(names)
+    }
+}
+
+/// The key of the variant of `key` selected by `request_headers`, or `key`
+/// itself when `names` is empty. A missing header and an empty one select
+/// different variants; several field lines are joined with `, `.
+pub fn variant_key(key: &str, names: &[String], request_headers: &HeaderMap) -> String {
+    if names.is_empty() {
+        return key.to_string();
+    }
+    let mut selected = Vec::new();
+    for name in names {
+        selected.extend_from_slice(name.as_bytes());
+        let values: Vec<_> = request_headers
+            .get_all(name.as_str())
+            .iter()
+            .map(|value| value.as_bytes().trim_ascii())
+            .collect();
+        if !values.is_empty() {
+            selected.push(b'=');
+            selected.extend_from_slice(&values.join(&b", "[..]));
+        }
+        // Header values cannot contain NUL, so names and values cannot run
+        // into each other.
+        selected.push(0);
+    }
+    let digest = crate::cache::content_digest(&selected);
+    format!("{key}{KEY_MARKER}{}", &digest[..DIGEST_LEN])
+}
+
+/// `key` without the variant part, if it has one.
+pub fn base_key(key: &str) -> &str {
+    key.split_once(KEY_MARKER).map_or(key, |(base, _)| base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Vary::parse(["*"]), Vary::Any);
+        assert_eq!(Vary::parse(["Accept-Language, *"]), Vary::Any);
+        assert_eq!(
+            Vary::parse(["Accept-Language, accept-encoding", "Cookie,accept-language"]),
+            Vary::Headers(vec!["accept-language".to_string(), "cookie".to_string()])
+        );
+        assert_eq!(Vary::parse(["Accept-Encoding"]), Vary::Headers(vec![]));
+        assert_eq!(Vary::parse([" , "]), Vary::Headers(vec![]));
+    }
+
+    #[test]
+    fn test_variant_keys() {
+        let names = ["accept-language".to_string()];
+        let key = |value: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert("accept-language", HeaderValue::from_static(value));
+            }
+            variant_key("GET:/page", &names, &headers)
+        };
+
+        assert_eq!(
+            variant_key("GET:/page", &[], &HeaderMap::new()),
+            "GET:/page"
+        );
+        assert!(key(Some("en")).starts_with("GET:/page#vary="));
+        assert_eq!(key(Some("en")), key(Some(" en ")));
+        assert_ne!(key(Some("en")), key(Some("de")));
+        assert_ne!(key(None), key(Some("")));
+        assert_eq!(base_key(&key(Some("en"))), "GET:/page");
+        assert_eq!(base_key("GET:/page"), "GET:/page");
+    }
+}
@@ -0,0 +1,188 @@
+//! Cache warming: request a list of paths through a proxy ahead of traffic
+//! so the first visitors get cache hits.
+//!
+//! Each path goes through the proxy's own request handling, as a client
+//! without cookies would send it, so `cache_key_fn`, cache key webhooks,
+//! `cache_rules`, and `Vary` apply exactly as they do for live traffic.
+//! Paths that are not cacheable by `cache_rules` or `include_paths` /
+//! `exclude_paths` are skipped without a backend request. Warm requests
+//! count in [`ProxyHandle::stats`] like any other request.
+//!
+//! [`ProxyHandle::stats`]: crate::ProxyHandle::stats
+
+use crate::normalize::normalize_percent_encoding;
+use crate::proxy::{handle_request, ProxyState, StoredOnMiss};
+use crate::stats::Outcome;
+use axum::body::Body;
+use axum::http::{Method, Request, Uri};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Paths fetched at once when no concurrency is configured.
+pub const DEFAULT_WARM_CONCURRENCY: usize = 4;
+
+/// What warming one path did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmOutcome {
+    /// Fetched from the backend and stored.
+    Stored,
+    /// Served from an existing cache entry; the backend was not asked.
+    AlreadyCached,
+    /// Fetched, but the response was not cacheable (status, `Cache-Control`,
+    /// cache rules, or the write limit).
+    NotStored,
+    /// Not cacheable by `cache_rules` or `include_paths` / `exclude_paths`;
+    /// not requested.
+    Excluded,
+    /// The target could not be parsed or the proxy answered with an error.
+    Failed,
+}
+
+/// The report for one warmed path.
+#[derive(Clone, Debug, Serialize)]
+pub struct WarmResult {
+    /// The path as given, including any method prefix.
+    pub path: String,
+    pub outcome: WarmOutcome,
+    /// Status the proxy answered with; absent when no request was made.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl WarmResult {
+    fn new(path: String, outcome: WarmOutcome, status: Option<u16>) -> Self {
+        Self {
+            path,
+            outcome,
+            status,
+            error: None,
+        }
+    }
+
+    fn failed(path: String, status: Option<u16>, error: impl Into<String>) -> Self {
+        Self {
+            error: Some(error.into()),
+            ..Self::new(path, WarmOutcome::Failed, status)
+        }
+    }
+}
+
+/// Split `"HEAD /about?x=1"` or `"/about"` (GET) into method and URI, the
+/// same method-prefix syntax `include_paths` uses.
+fn parse_target(target: &str) -> Result<(Method, Uri), String> {
+    let target = target.trim();
+    let (method, path) = match target.split_once(char::is_whitespace) {
+        Some((method, path)) => {
+            let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method '{}'", method))?;
+            (method, path.trim_start())
+        }
+        None => (Method::GET, target),
+    };
+    if !path.starts_with('/') {
+        return Err(format!("path '{}' must start with '/'", path));
+    }
+    let uri = path
+        .parse::<Uri>()
+        .map_err(|err| format!("invalid path '{}': {}", path, err))?;
+    Ok((method, uri))
+}
+
+async fn warm_one(state: Arc<ProxyState>, target: String) -> WarmResult {
+    let (method, uri) = match parse_target(&target) {
+        Ok(parsed) => parsed,
+        Err(err) => return WarmResult::failed(target, None, err),
+    };
+    let path = normalize_percent_encoding(uri.path());
+    let query = normalize_percent_encoding(uri.query().unwrap_or(""));
+    if !state.should_cache(method.as_str(), &path, &query) {
+        return WarmResult::new(target, WarmOutcome::Excluded, None);
+    }
+
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())
+        .expect("method and URI are already validated");
+    let response = match handle_request(state, request).await {
+        Ok(response) => response,
+        Err(status) => {
+            return WarmResult::failed(
+                target,
+                Some(status.as_u16()),
+                "proxy answered with an error",
+            )
+        }
+    };
+
+    let status = response.status().as_u16();
+    let outcome = if response.extensions().get::<StoredOnMiss>().is_some() {
+        WarmOutcome::Stored
+    } else if response.extensions().get::<Outcome>() == Some(&Outcome::Hit) {
+        WarmOutcome::AlreadyCached
+    } else {
+        WarmOutcome::NotStored
+    };
+    // A streamed body finishes (and its cache write lands) only when read.
+    if let Err(err) = axum::body::to_bytes(response.into_body(), usize::MAX).await {
+        return WarmResult::failed(target, Some(status), err.to_string());
+    }
+    WarmResult::new(target, outcome, Some(status))
+}
+
+/// Warm `paths` with at most `concurrency` requests in flight, returning one
+/// result per path in the order given.
+pub(crate) async fn warm(
+    state: Arc<ProxyState>,
+    paths: Vec<String>,
+    concurrency: usize,
+) -> Vec<WarmResult> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, path) in paths.iter().enumerate() {
+        let (state, permits, path) = (state.clone(), permits.clone(), path.clone());
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            (index, warm_one(state, path).await)
+        });
+    }
+
+    let mut results: Vec<Option<WarmResult>> = vec![None; paths.len()];
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, result)) => results[index] = Some(result),
+            Err(err) => tracing::error!("Cache warm task failed: {}", err),
+        }
+    }
+    results
+        .into_iter()
+        .zip(paths)
+        .map(|(result, path)| {
+            result.unwrap_or_else(|| WarmResult::failed(path, None, "warm task panicked"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target_accepts_method_prefix() {
+        let (method, uri) = parse_target("/about?x=1").unwrap();
+        assert_eq!(method, Method::GET);
+        assert_eq!(uri, "/about?x=1");
+
+        let (method, uri) = parse_target("head  /about").unwrap();
+        assert_eq!(method, Method::HEAD);
+        assert_eq!(uri, "/about");
+
+        assert!(parse_target("about").is_err());
+        assert!(parse_target("GET about").is_err());
+    }
+}
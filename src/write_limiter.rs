@@ -0,0 +1,83 @@
+//! Global ceiling on cache inserts per second.
+//!
+//! A client requesting many unique URLs forces one backend fetch and one cache
+//! insert per URL, which churns the cache for everyone else. Once the ceiling
+//! is reached within the current one-second window, responses are still served
+//! to the client but are no longer stored.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Fixed-window counter limiting how many cache writes are admitted per second.
+pub struct CacheWriteLimiter {
+    max_per_window: u32,
+    window: Mutex<(Instant, u32)>,
+    throttled: AtomicU64,
+}
+
+impl CacheWriteLimiter {
+    /// Admit at most `max_per_sec` cache writes per second.
+    pub fn new(max_per_sec: u32) -> Self {
+        Self {
+            max_per_window: max_per_sec,
+            window: Mutex::new((Instant::now(), 0)),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns `true` when a cache write may proceed.
+    ///
+    /// Rejections are counted; only the first rejection in each window is
+    /// logged so a sustained burst does not flood the log.
+    pub fn try_acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= WINDOW {
+            *window = (now, 0);
+        }
+
+        if window.1 < self.max_per_window {
+            window.1 += 1;
+            return true;
+        }
+
+        let first_in_window = window.1 == self.max_per_window;
+        window.1 = window.1.saturating_add(1);
+        drop(window);
+
+        let total = self.throttled.fetch_add(1, Ordering::Relaxed) + 1;
+        if first_in_window {
+            tracing::warn!(
+                "Cache write limit of {}/s reached; serving responses without storing them ({} writes skipped so far)",
+                self.max_per_window,
+                total
+            );
+        }
+        false
+    }
+
+    /// Total number of cache writes skipped because the ceiling was reached.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiter_resets_each_window() {
+        let limiter = CacheWriteLimiter::new(3);
+        let admitted = (0..10).filter(|_| limiter.try_acquire()).count();
+        assert_eq!(admitted, 3);
+        assert_eq!(limiter.throttled(), 7);
+
+        tokio::time::advance(WINDOW).await;
+        assert!(limiter.try_acquire());
+    }
+}
@@ -0,0 +1,64 @@
+//! Checks a proxy configuration against recorded backend traffic.
+//!
+//! `tests/fixtures/blog.cassette.jsonl` was captured with a server running in
+//! cassette record mode. Replaying it needs no backend, so the cache key
+//! function and path rules below are exercised fully offline.
+//!
+//! Run with `cargo test --features cassette`.
+#![cfg(feature = "cassette")]
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use phantom_frame::cassette::Cassette;
+use phantom_frame::{create_proxy_handle, CreateProxyConfig};
+use tower::ServiceExt;
+
+async fn get(app: &axum::Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn blog_pages_share_an_entry_regardless_of_tracking_params() {
+    let cassette = Cassette::replay(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/blog.cassette.jsonl"
+    ))
+    .unwrap();
+    // The backend URL is never contacted in replay mode.
+    let config = CreateProxyConfig::new("http://backend.invalid".to_string())
+        .with_include_paths(vec!["GET /blog/*".to_string()])
+        .with_cache_key_fn(|request| format!("{}:{}", request.method, request.path))
+        .with_cassette(cassette);
+    let (app, handle) = create_proxy_handle(config);
+
+    let (status, body) = get(&app, "/blog/hello?utm_source=news").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("<h1>Hello</h1>"));
+    assert!(handle.cache().get("GET:/blog/hello").await.is_some());
+
+    // Answered from the cache: the cassette has no recording for this query.
+    let (status, body) = get(&app, "/blog/hello?utm_source=social").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("<h1>Hello</h1>"));
+
+    let (status, _) = get(&app, "/blog/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(handle.cache().get_404("GET:/blog/missing").await.is_some());
+
+    // Recorded with its token redacted; any token value replays it, and
+    // the path is outside `include_paths`, so nothing is cached.
+    let (status, body) = get(&app, "/account?token=live-secret").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"user":"demo"}"#);
+    assert!(handle.cache().get("GET:/account").await.is_none());
+
+    // Requests the cassette does not know about fail instead of reaching out.
+    let (status, _) = get(&app, "/blog/unrecorded").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}